@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use vulkano::device::{Device, DeviceExtensions, Features, Queue};
+use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice, QueueFamily};
+
+// The first compute or graphics queue of the first device, `None` on machines
+// without a Vulkan device. Each bench only uses some of these.
+#[allow(dead_code)]
+pub fn compute_queue() -> Option<Arc<Queue>> {
+	first_queue(|q| q.supports_compute())
+}
+
+#[allow(dead_code)]
+pub fn graphics_queue() -> Option<Arc<Queue>> {
+	first_queue(|q| q.supports_graphics())
+}
+
+fn first_queue(filter: impl Fn(&QueueFamily) -> bool) -> Option<Arc<Queue>> {
+	let instance = Instance::new(None, &InstanceExtensions::none(), None).ok()?;
+	let physical = PhysicalDevice::enumerate(&instance).next()?;
+	let queue_family = physical.queue_families().find(|q| filter(q))?;
+	let (_, mut queues) = Device::new(
+		physical,
+		&Features::none(),
+		&DeviceExtensions::none(),
+		[(queue_family, 0.5)].iter().cloned(),
+	)
+	.ok()?;
+	queues.next()
+}
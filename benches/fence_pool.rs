@@ -0,0 +1,42 @@
+// Fence per frame against GpuFencePool, on empty submissions so that the
+// fence creation is most of the time measured.
+// Run with `cargo bench --bench fence_pool`
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use vulkano::sync::{self, GpuFuture};
+
+use vulkano_start::sync::GpuFencePool;
+
+mod common;
+
+fn fence_per_frame(c: &mut Criterion) {
+	let queue = match common::compute_queue() {
+		Some(queue) => queue,
+		None => {
+			eprintln!("No Vulkan device available, skipping");
+			return;
+		}
+	};
+	let device = queue.device().clone();
+
+	let mut group = c.benchmark_group("frame_fence");
+	group.bench_function("then_signal_fence_and_flush", |b| {
+		b.iter(|| {
+			sync::now(device.clone())
+				.then_signal_fence_and_flush()
+				.unwrap()
+				.wait(None)
+				.unwrap()
+		})
+	});
+
+	let mut pool = GpuFencePool::new(device.clone()).unwrap();
+	group.bench_function("GpuFencePool::signal_after", |b| {
+		// Dropping the future waits on its fence
+		b.iter(|| drop(pool.signal_after(&queue, sync::now(device.clone())).unwrap()))
+	});
+	group.finish();
+}
+
+criterion_group!(benches, fence_per_frame);
+criterion_main!(benches);
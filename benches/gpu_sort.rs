@@ -0,0 +1,81 @@
+// GpuRadixSort against Vec::sort_unstable on 10 000 transparent objects. The
+// GPU time includes the upload and the readback.
+// Run with `cargo bench --bench gpu_sort`
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
+use vulkano::sync::GpuFuture;
+
+use vulkano_start::gpu_sort::{back_to_front_key, GpuRadixSort};
+
+mod common;
+
+const OBJECTS: usize = 10_000;
+
+// Pseudo random depths, the same on every run
+fn keys() -> Vec<u32> {
+	let mut state = 0x2545_f491u32;
+	(0..OBJECTS)
+		.map(|id| {
+			state ^= state << 13;
+			state ^= state >> 17;
+			state ^= state << 5;
+			back_to_front_key((state % 10_000) as f32 * 0.01, id as u16)
+		})
+		.collect()
+}
+
+fn sort(c: &mut Criterion) {
+	let keys = keys();
+
+	let mut group = c.benchmark_group("sort_10000");
+	group.bench_function("Vec::sort_unstable", |b| {
+		b.iter(|| {
+			let mut sorted = keys.clone();
+			sorted.sort_unstable();
+			sorted
+		})
+	});
+
+	let queue = match common::compute_queue() {
+		Some(queue) => queue,
+		None => {
+			eprintln!("No Vulkan device available, skipping");
+			return;
+		}
+	};
+	let device = queue.device().clone();
+	let sort = GpuRadixSort::new(device.clone(), OBJECTS).unwrap();
+	let readback = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		BufferUsage::transfer_destination(),
+		false,
+		(0..OBJECTS).map(|_| 0u32),
+	)
+	.unwrap();
+	let mut builder = AutoCommandBufferBuilder::new(device, queue.family()).unwrap();
+	sort.upload(&mut builder, &keys).unwrap();
+	sort.record(&mut builder, keys.len()).unwrap();
+	builder.copy_buffer(sort.indices(), readback).unwrap();
+	let command_buffer = Arc::new(builder.build().unwrap());
+
+	group.bench_function("GpuRadixSort", |b| {
+		b.iter(|| {
+			command_buffer
+				.clone()
+				.execute(queue.clone())
+				.unwrap()
+				.then_signal_fence_and_flush()
+				.unwrap()
+				.wait(None)
+				.unwrap()
+		})
+	});
+	group.finish();
+}
+
+criterion_group!(benches, sort);
+criterion_main!(benches);
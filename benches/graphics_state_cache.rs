@@ -0,0 +1,145 @@
+// Records 1000 objects sharing the same mesh and pipeline straight into the
+// builder and through GraphicsStateCache, and reports how many binds the cache
+// skipped. Nothing is submitted, only the recording is measured.
+// Run with `cargo bench --bench graphics_state_cache`
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::AttachmentImage;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+
+use vulkano_start::commands::GraphicsStateCache;
+use vulkano_start::glsl_shaders::{fs, vs};
+use vulkano_start::Vertex;
+
+mod common;
+
+const OBJECTS: usize = 1000;
+
+fn record_objects(c: &mut Criterion) {
+	let queue = match common::graphics_queue() {
+		Some(queue) => queue,
+		None => {
+			eprintln!("No Vulkan device available, skipping");
+			return;
+		}
+	};
+	let device = queue.device().clone();
+
+	let render_pass = Arc::new(
+		vulkano::single_pass_renderpass!(
+			device.clone(),
+			attachments: {
+				color: {
+					load: Clear,
+					store: Store,
+					format: Format::R8G8B8A8Unorm,
+					samples: 1,
+				}
+			},
+			pass: {
+				color: [color],
+				depth_stencil: {}
+			}
+		)
+		.unwrap(),
+	) as Arc<dyn RenderPassAbstract + Send + Sync>;
+	let image = AttachmentImage::new(device.clone(), [64, 64], Format::R8G8B8A8Unorm).unwrap();
+	let framebuffer = Arc::new(
+		Framebuffer::start(render_pass.clone())
+			.add(ImageView::new(image).unwrap())
+			.unwrap()
+			.build()
+			.unwrap(),
+	) as Arc<dyn FramebufferAbstract + Send + Sync>;
+
+	let vs = vs::Shader::load(device.clone()).unwrap();
+	let fs = fs::Shader::load(device.clone()).unwrap();
+	let pipeline = Arc::new(
+		GraphicsPipeline::start()
+			.vertex_input_single_buffer::<Vertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), fs::SpecializationConstants::default())
+			.render_pass(Subpass::from(render_pass, 0).unwrap())
+			.build(device.clone())
+			.unwrap(),
+	) as Arc<dyn GraphicsPipelineAbstract + Send + Sync>;
+	let dynamic_state = DynamicState {
+		viewports: Some(vec![Viewport {
+			origin: [0.0, 0.0],
+			dimensions: [64.0, 64.0],
+			depth_range: 0.0..1.0,
+		}]),
+		..DynamicState::none()
+	};
+	let vertex_buffer = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		BufferUsage::vertex_buffer(),
+		false,
+		[[-0.5, -0.5], [0.0, 0.5], [0.5, -0.25]]
+			.iter()
+			.map(|&position| Vertex { position }),
+	)
+	.unwrap() as Arc<dyn BufferAccess + Send + Sync>;
+
+	let begin = || {
+		let mut builder =
+			AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap();
+		builder
+			.begin_render_pass(framebuffer.clone(), SubpassContents::Inline, vec![[0.0, 0.0, 0.0, 1.0].into()])
+			.unwrap();
+		builder
+	};
+
+	let mut group = c.benchmark_group("record_1000_objects");
+	group.bench_function("AutoCommandBufferBuilder::draw", |b| {
+		b.iter(|| {
+			let mut builder = begin();
+			for _ in 0..OBJECTS {
+				builder
+					.draw(pipeline.clone(), &dynamic_state, vec![vertex_buffer.clone()], (), (), vec![])
+					.unwrap();
+			}
+			builder.end_render_pass().unwrap();
+			builder.build().unwrap()
+		})
+	});
+	group.bench_function("GraphicsStateCache::draw", |b| {
+		b.iter(|| {
+			let mut builder = begin();
+			let mut cache = GraphicsStateCache::new(&mut builder);
+			for _ in 0..OBJECTS {
+				cache.draw(pipeline.clone(), &dynamic_state, vertex_buffer.clone(), (), ()).unwrap();
+			}
+			builder.end_render_pass().unwrap();
+			builder.build().unwrap()
+		})
+	});
+	group.finish();
+
+	let mut builder = begin();
+	let mut cache = GraphicsStateCache::new(&mut builder);
+	for _ in 0..OBJECTS {
+		cache.draw(pipeline.clone(), &dynamic_state, vertex_buffer.clone(), (), ()).unwrap();
+	}
+	let stats = cache.stats();
+	println!(
+		"{} draws: {} pipeline binds, {} vertex buffer binds, {} redundant binds skipped",
+		stats.draws,
+		stats.pipeline_binds,
+		stats.vertex_buffer_binds,
+		stats.redundant_binds_skipped()
+	);
+}
+
+criterion_group!(benches, record_objects);
+criterion_main!(benches);
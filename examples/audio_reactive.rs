@@ -0,0 +1,358 @@
+// The rotating triangle driven by the microphone: a capture thread computes
+// the RMS volume of every audio buffer and publishes it through an atomic, the
+// render loop smooths it and uses it to speed up the rotation and shift the
+// color of the triangle, both given to the shaders as push constants.
+//
+// Run with `cargo run --example audio_reactive --features audio`
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::device::{Device, DeviceExtensions};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageAccess, ImageUsage, SwapchainImage};
+use vulkano::instance::{Instance, PhysicalDevice};
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::swapchain::{
+	AcquireError, ColorSpace, FullscreenExclusive, PresentMode, SurfaceTransform, Swapchain, SwapchainCreationError,
+};
+use vulkano::sync::{FlushError, GpuFuture};
+
+use vulkano_win::VkSurfaceBuild;
+
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::{Window, WindowBuilder};
+
+// The amplitude is shared as a 16.16 fixed point number, atomics have no f32
+const AMPLITUDE_ONE: f32 = 65536.0;
+// Fraction of the distance to the latest amplitude covered per 60th of a second
+const SMOOTHING: f32 = 0.15;
+// In radians per second, the amplitude being in [0, 1]
+const BASE_SPEED: f32 = 0.5;
+const AMPLITUDE_SPEED: f32 = 12.0;
+// Microphones rarely get past this RMS, it maps to the full effect
+const AMPLITUDE_GAIN: f32 = 4.0;
+
+mod vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(push_constant) uniform PushConstants {
+				vec4 color;
+				float angle;
+			} pc;
+
+			const float RADIUS = 0.5;
+			const float ANGLE_OFFSET = 2.09439510239;
+
+			void main() {
+				float angle = pc.angle + float(gl_VertexIndex) * ANGLE_OFFSET;
+				gl_Position = vec4(cos(angle) * RADIUS, sin(angle) * RADIUS, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(push_constant) uniform PushConstants {
+				vec4 color;
+				float angle;
+			} pc;
+
+			void main() {
+				f_color = pc.color;
+			}
+		"
+	}
+}
+
+fn rms_to_fixed_point<T: Copy>(samples: &[T], to_f32: impl Fn(T) -> f32) -> u32 {
+	if samples.is_empty() {
+		return 0;
+	}
+	let sum: f32 = samples.iter().map(|&sample| to_f32(sample).powi(2)).sum();
+	let rms = (sum / samples.len() as f32).sqrt();
+	(rms.min(1.0) * AMPLITUDE_ONE) as u32
+}
+
+// Captures the default input device on a background thread. The stream is
+// kept alive by the parked thread and its callback stores the RMS of every
+// buffer into `amplitude`, without locking.
+fn spawn_audio_capture(amplitude: Arc<AtomicU32>) {
+	std::thread::spawn(move || {
+		let host = cpal::default_host();
+		let device = match host.default_input_device() {
+			Some(device) => device,
+			None => {
+				eprintln!("No audio input device, the triangle won't react");
+				return;
+			}
+		};
+		let config = device.default_input_config().unwrap();
+		println!("Capturing audio from {}", device.name().unwrap_or_default());
+
+		let on_error = |e| eprintln!("Audio capture error: {}", e);
+		let stream = match config.sample_format() {
+			cpal::SampleFormat::F32 => device.build_input_stream(
+				&config.into(),
+				move |data: &[f32], _: &cpal::InputCallbackInfo| {
+					amplitude.store(rms_to_fixed_point(data, |s| s), Ordering::Relaxed);
+				},
+				on_error,
+			),
+			cpal::SampleFormat::I16 => device.build_input_stream(
+				&config.into(),
+				move |data: &[i16], _: &cpal::InputCallbackInfo| {
+					amplitude.store(rms_to_fixed_point(data, |s| s as f32 / i16::MAX as f32), Ordering::Relaxed);
+				},
+				on_error,
+			),
+			cpal::SampleFormat::U16 => device.build_input_stream(
+				&config.into(),
+				move |data: &[u16], _: &cpal::InputCallbackInfo| {
+					let to_f32 = |s: u16| s as f32 / u16::MAX as f32 * 2.0 - 1.0;
+					amplitude.store(rms_to_fixed_point(data, to_f32), Ordering::Relaxed);
+				},
+				on_error,
+			),
+		}
+		.unwrap();
+		stream.play().unwrap();
+
+		loop {
+			std::thread::park();
+		}
+	});
+}
+
+fn window_size_dependent_setup(
+	images: &[Arc<SwapchainImage<Window>>],
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	dynamic_state: &mut DynamicState,
+) -> Vec<Arc<dyn FramebufferAbstract + Send + Sync>> {
+	let dimensions = images[0].dimensions();
+	dynamic_state.viewports = Some(vec![Viewport {
+		origin: [0.0, 0.0],
+		dimensions: [dimensions.width() as f32, dimensions.height() as f32],
+		depth_range: 0.0..1.0,
+	}]);
+	images
+		.iter()
+		.map(|image| {
+			Arc::new(
+				Framebuffer::start(render_pass.clone())
+					.add(ImageView::new(image.clone()).unwrap())
+					.unwrap()
+					.build()
+					.unwrap(),
+			) as Arc<dyn FramebufferAbstract + Send + Sync>
+		})
+		.collect()
+}
+
+fn main() {
+	let amplitude = Arc::new(AtomicU32::new(0));
+	spawn_audio_capture(amplitude.clone());
+
+	let instance = Instance::new(None, &vulkano_win::required_extensions(), None).unwrap();
+	let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+	let event_loop = EventLoop::new();
+	let surface = WindowBuilder::new()
+		.with_title("Audio reactive triangle")
+		.build_vk_surface(&event_loop, instance.clone())
+		.unwrap();
+
+	let queue_family = physical
+		.queue_families()
+		.find(|&q| q.supports_graphics() && surface.is_supported(q).unwrap_or(false))
+		.unwrap();
+	let device_ext = DeviceExtensions {
+		khr_swapchain: true,
+		..DeviceExtensions::none()
+	};
+	let (device, mut queues) = Device::new(
+		physical,
+		physical.supported_features(),
+		&device_ext,
+		[(queue_family, 0.5)].iter().cloned(),
+	)
+	.unwrap();
+	let queue = queues.next().unwrap();
+
+	let caps = surface.capabilities(physical).unwrap();
+	let (mut swapchain, images) = Swapchain::new(
+		device.clone(),
+		surface.clone(),
+		caps.min_image_count,
+		caps.supported_formats[0].0,
+		surface.window().inner_size().into(),
+		1,
+		ImageUsage::color_attachment(),
+		&queue,
+		SurfaceTransform::Identity,
+		caps.supported_composite_alpha.iter().next().unwrap(),
+		PresentMode::Fifo,
+		FullscreenExclusive::Default,
+		true,
+		ColorSpace::SrgbNonLinear,
+	)
+	.unwrap();
+
+	let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+		vulkano::single_pass_renderpass!(
+			device.clone(),
+			attachments: {
+				color: {
+					load: Clear,
+					store: Store,
+					format: swapchain.format(),
+					samples: 1,
+				}
+			},
+			pass: {
+				color: [color],
+				depth_stencil: {}
+			}
+		)
+		.unwrap(),
+	);
+
+	let vs = vs::Shader::load(device.clone()).unwrap();
+	let fs = fs::Shader::load(device.clone()).unwrap();
+	let pipeline = Arc::new(
+		GraphicsPipeline::start()
+			.vertex_input(BufferlessDefinition {})
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+			.build(device.clone())
+			.unwrap(),
+	);
+
+	let mut dynamic_state = DynamicState::none();
+	let mut framebuffers = window_size_dependent_setup(&images, render_pass.clone(), &mut dynamic_state);
+	let mut recreate_swapchain = false;
+	let mut previous_frame_end = Some(vulkano::sync::now(device.clone()).boxed());
+
+	let mut last_frame = Instant::now();
+	let mut smoothed = 0.0f32;
+	let mut angle = 0.0f32;
+
+	event_loop.run(move |event, _, control_flow| match event {
+		Event::WindowEvent {
+			event: WindowEvent::CloseRequested,
+			..
+		} => {
+			*control_flow = ControlFlow::Exit;
+		}
+		Event::WindowEvent {
+			event: WindowEvent::Resized(_),
+			..
+		} => {
+			recreate_swapchain = true;
+		}
+		Event::RedrawEventsCleared => {
+			previous_frame_end.as_mut().unwrap().cleanup_finished();
+
+			if recreate_swapchain {
+				let dimensions: [u32; 2] = surface.window().inner_size().into();
+				let (new_swapchain, new_images) = match swapchain.recreate_with_dimensions(dimensions) {
+					Ok(r) => r,
+					Err(SwapchainCreationError::UnsupportedDimensions) => return,
+					Err(e) => panic!("Failed to recreate the swapchain: {:?}", e),
+				};
+				swapchain = new_swapchain;
+				framebuffers = window_size_dependent_setup(&new_images, render_pass.clone(), &mut dynamic_state);
+				recreate_swapchain = false;
+			}
+
+			let (image_num, suboptimal, acquire_future) =
+				match vulkano::swapchain::acquire_next_image(swapchain.clone(), None) {
+					Ok(r) => r,
+					Err(AcquireError::OutOfDate) => {
+						recreate_swapchain = true;
+						return;
+					}
+					Err(e) => panic!("Failed to acquire the next image: {:?}", e),
+				};
+			recreate_swapchain |= suboptimal;
+
+			// The smoothing is framerate independent, `SMOOTHING` applies at 60 FPS
+			let now = Instant::now();
+			let dt = (now - last_frame).as_secs_f32();
+			last_frame = now;
+			let target = (amplitude.load(Ordering::Relaxed) as f32 / AMPLITUDE_ONE * AMPLITUDE_GAIN).min(1.0);
+			let factor = 1.0 - (1.0 - SMOOTHING).powf(dt * 60.0);
+			smoothed += (target - smoothed) * factor;
+			angle = (angle + dt * (BASE_SPEED + smoothed * AMPLITUDE_SPEED)).rem_euclid(std::f32::consts::PI * 2.0);
+
+			// From the red of the static triangle to yellow when loud
+			let push_constants = vs::ty::PushConstants {
+				color: [1.0, smoothed, smoothed * 0.2, 1.0],
+				angle,
+			};
+
+			let mut builder =
+				AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap();
+			builder
+				.begin_render_pass(
+					framebuffers[image_num].clone(),
+					SubpassContents::Inline,
+					vec![[0.0, 0.0, 1.0, 1.0].into()],
+				)
+				.unwrap()
+				.draw(
+					pipeline.clone(),
+					&dynamic_state,
+					BufferlessVertices {
+						vertices: 3,
+						instances: 1,
+					},
+					(),
+					push_constants,
+					vec![],
+				)
+				.unwrap()
+				.end_render_pass()
+				.unwrap();
+			let command_buffer = builder.build().unwrap();
+
+			let future = previous_frame_end
+				.take()
+				.unwrap()
+				.join(acquire_future)
+				.then_execute(queue.clone(), command_buffer)
+				.unwrap()
+				.then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
+				.then_signal_fence_and_flush();
+			previous_frame_end = match future {
+				Ok(future) => Some(future.boxed()),
+				Err(FlushError::OutOfDate) => {
+					recreate_swapchain = true;
+					Some(vulkano::sync::now(device.clone()).boxed())
+				}
+				Err(e) => panic!("Failed to flush the frame: {:?}", e),
+			};
+		}
+		_ => (),
+	});
+}
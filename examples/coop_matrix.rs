@@ -0,0 +1,308 @@
+// Multiplies two 16x16 matrices with the cooperative matrix operations of
+// VK_KHR_cooperative_matrix, which run on the tensor / matrix cores of the
+// GPU, and checks D = A * B + C against the same product computed on the CPU.
+// Devices without the extension, or a shaderc too old to know
+// GL_KHR_cooperative_matrix, skip the GPU path.
+//
+// Run with `cargo run --example coop_matrix`
+
+use std::ffi::{CStr, CString};
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
+use vulkano::descriptor::descriptor::{DescriptorBufferDesc, DescriptorDesc, DescriptorDescTy, ShaderStages};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::pipeline_layout::{PipelineLayoutDesc, PipelineLayoutDescPcRange};
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, Queue, RawDeviceExtensions};
+use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+use vulkano::pipeline::shader::ShaderModule;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::sync::GpuFuture;
+
+// The only size every implementation of the extension supports for
+// float16 A and B with a float32 accumulator
+const SIZE: usize = 16;
+const COOPERATIVE_MATRIX: &str = "VK_KHR_cooperative_matrix";
+const SHADER_FLOAT16: &str = "VK_KHR_shader_float16_int8";
+
+// A and B hold two float16 per uint, the row stride is in uints
+const SHADER: &str = "
+	#version 450
+	#extension GL_KHR_cooperative_matrix : require
+	#extension GL_KHR_memory_scope_semantics : require
+	#extension GL_EXT_shader_explicit_arithmetic_types_float16 : require
+
+	// One subgroup with a subgroup size of 64, two computing the same
+	// product with a subgroup size of 32
+	layout(local_size_x = 64) in;
+
+	layout(set = 0, binding = 0) readonly buffer A {
+		uint data[];
+	} a;
+	layout(set = 0, binding = 1) readonly buffer B {
+		uint data[];
+	} b;
+	layout(set = 0, binding = 2) readonly buffer C {
+		float data[];
+	} c;
+	layout(set = 0, binding = 3) writeonly buffer D {
+		float data[];
+	} d;
+
+	void main() {
+		coopmat<float16_t, gl_ScopeSubgroup, 16, 16, gl_MatrixUseA> mat_a;
+		coopmat<float16_t, gl_ScopeSubgroup, 16, 16, gl_MatrixUseB> mat_b;
+		coopmat<float, gl_ScopeSubgroup, 16, 16, gl_MatrixUseAccumulator> mat_c;
+
+		coopMatLoad(mat_a, a.data, 0, 8, gl_CooperativeMatrixLayoutRowMajor);
+		coopMatLoad(mat_b, b.data, 0, 8, gl_CooperativeMatrixLayoutRowMajor);
+		coopMatLoad(mat_c, c.data, 0, 16, gl_CooperativeMatrixLayoutRowMajor);
+		mat_c = coopMatMulAdd(mat_a, mat_b, mat_c);
+		coopMatStore(mat_c, d.data, 0, 16, gl_CooperativeMatrixLayoutRowMajor);
+	}
+";
+
+// The four storage buffers of SHADER, the module is compiled at runtime so
+// the layout vulkano_shaders would generate is written by hand
+#[derive(Clone, Copy)]
+struct CoopMatrixLayout;
+
+unsafe impl PipelineLayoutDesc for CoopMatrixLayout {
+	fn num_sets(&self) -> usize {
+		1
+	}
+
+	fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
+		if set == 0 {
+			Some(4)
+		} else {
+			None
+		}
+	}
+
+	fn descriptor(&self, set: usize, binding: usize) -> Option<DescriptorDesc> {
+		if set != 0 || binding >= 4 {
+			return None;
+		}
+		Some(DescriptorDesc {
+			ty: DescriptorDescTy::Buffer(DescriptorBufferDesc {
+				dynamic: Some(false),
+				storage: true,
+			}),
+			array_count: 1,
+			stages: ShaderStages::compute(),
+			readonly: binding < 3,
+		})
+	}
+
+	fn num_push_constants_ranges(&self) -> usize {
+		0
+	}
+
+	fn push_constants_range(&self, _num: usize) -> Option<PipelineLayoutDescPcRange> {
+		None
+	}
+}
+
+fn has_extension(physical: PhysicalDevice, name: &str) -> bool {
+	RawDeviceExtensions::supported_by_device(physical)
+		.iter()
+		.any(|extension| extension.to_bytes() == name.as_bytes())
+}
+
+fn compile() -> Result<Vec<u32>, String> {
+	let mut compiler = shaderc::Compiler::new().ok_or("failed to initialize shaderc")?;
+	let mut options = shaderc::CompileOptions::new().ok_or("failed to create the shaderc compile options")?;
+	options.set_target_env(shaderc::TargetEnv::Vulkan, shaderc::EnvVersion::Vulkan1_1 as u32);
+	options.set_target_spirv(shaderc::SpirvVersion::V1_3);
+	let artifact = compiler
+		.compile_into_spirv(SHADER, shaderc::ShaderKind::Compute, "coop_matrix.comp", "main", Some(&options))
+		.map_err(|e| e.to_string())?;
+	Ok(artifact.as_binary().to_vec())
+}
+
+// Exact for the small integers of the example, which are normal float16
+// numbers: only the exponent needs rebiasing, the low mantissa bits are 0
+fn f16_bits(value: f32) -> u16 {
+	if value == 0.0 {
+		return 0;
+	}
+	let bits = value.to_bits();
+	let sign = (bits >> 16) & 0x8000;
+	let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+	assert!(exponent > 0 && exponent < 31, "{} isn't a normal float16", value);
+	let mantissa = (bits >> 13) & 0x3ff;
+	(sign | (exponent as u32) << 10 | mantissa) as u16
+}
+
+// Two float16 per uint, the first in the low half
+fn pack_f16(matrix: &[f32]) -> Vec<u32> {
+	matrix
+		.chunks(2)
+		.map(|pair| f16_bits(pair[0]) as u32 | (f16_bits(pair[1]) as u32) << 16)
+		.collect()
+}
+
+fn multiply_add_cpu(a: &[f32], b: &[f32], c: &[f32]) -> Vec<f32> {
+	let mut d = c.to_vec();
+	for row in 0..SIZE {
+		for column in 0..SIZE {
+			d[row * SIZE + column] += (0..SIZE).map(|k| a[row * SIZE + k] * b[k * SIZE + column]).sum::<f32>();
+		}
+	}
+	d
+}
+
+struct CoopMatrixDemo {
+	device: Arc<Device>,
+	queue: Arc<Queue>,
+	pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+}
+
+impl CoopMatrixDemo {
+	// Err explains why the device can't run the demo
+	fn new(physical: PhysicalDevice) -> Result<CoopMatrixDemo, String> {
+		if !has_extension(physical, COOPERATIVE_MATRIX) {
+			return Err(format!("{} isn't supported", COOPERATIVE_MATRIX));
+		}
+		if physical.extended_properties().subgroup_size().is_none() {
+			return Err("the device doesn't support Vulkan 1.1".to_string());
+		}
+		let spirv = compile().map_err(|e| format!("the shader doesn't compile: {}", e))?;
+
+		let queue_family = physical
+			.queue_families()
+			.find(|&q| q.supports_compute())
+			.ok_or("no compute queue")?;
+		let mut extensions = RawDeviceExtensions::new(vec![CString::new(COOPERATIVE_MATRIX).unwrap()]);
+		// Core in Vulkan 1.2, an extension before
+		if has_extension(physical, SHADER_FLOAT16) {
+			extensions = extensions.union(&RawDeviceExtensions::new(vec![CString::new(SHADER_FLOAT16).unwrap()]));
+		}
+		// Enables shaderFloat16 and the 16 bit storage features. vulkano 0.22
+		// has no VkPhysicalDeviceCooperativeMatrixFeaturesKHR to chain, the
+		// cooperativeMatrix feature, which every driver exposing the extension
+		// supports, is left to the driver: the validation layer reports it.
+		let (device, mut queues) = Device::new(
+			physical,
+			physical.supported_features(),
+			extensions,
+			[(queue_family, 0.5)].iter().cloned(),
+		)
+		.map_err(|e| e.to_string())?;
+		let queue = queues.next().ok_or("no queue")?;
+
+		// Safe as long as the SPIR-V is valid, which shaderc guarantees
+		let module = unsafe { ShaderModule::from_words(device.clone(), &spirv) }.map_err(|e| e.to_string())?;
+		let main = CStr::from_bytes_with_nul(b"main\0").unwrap();
+		let entry_point = unsafe { module.compute_entry_point::<(), _>(main, CoopMatrixLayout) };
+		// Fails on the devices without a float16 16x16x16 subgroup product
+		let pipeline = ComputePipeline::new(device.clone(), &entry_point, &(), None)
+			.map_err(|e| format!("the pipeline can't be created: {}", e))?;
+
+		Ok(CoopMatrixDemo {
+			device,
+			queue,
+			pipeline: Arc::new(pipeline),
+		})
+	}
+
+	fn multiply_add(&self, a: &[f32], b: &[f32], c: &[f32]) -> Vec<f32> {
+		let usage = BufferUsage {
+			storage_buffer: true,
+			..BufferUsage::none()
+		};
+		let buffer =
+			|data: Vec<u32>| CpuAccessibleBuffer::from_iter(self.device.clone(), usage, false, data.into_iter()).unwrap();
+		let a = buffer(pack_f16(a));
+		let b = buffer(pack_f16(b));
+		let c = CpuAccessibleBuffer::from_iter(
+			self.device.clone(),
+			usage,
+			false,
+			c.iter().cloned(),
+		)
+		.unwrap();
+		let d = CpuAccessibleBuffer::from_iter(
+			self.device.clone(),
+			usage,
+			false,
+			(0..SIZE * SIZE).map(|_| 0.0f32),
+		)
+		.unwrap();
+
+		let layout = self.pipeline.descriptor_set_layout(0).unwrap();
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(a)
+				.unwrap()
+				.add_buffer(b)
+				.unwrap()
+				.add_buffer(c)
+				.unwrap()
+				.add_buffer(d.clone())
+				.unwrap()
+				.build()
+				.unwrap(),
+		);
+
+		let mut builder = AutoCommandBufferBuilder::new(self.device.clone(), self.queue.family()).unwrap();
+		builder
+			.dispatch([1, 1, 1], self.pipeline.clone(), set, (), vec![])
+			.unwrap();
+		let command_buffer = builder.build().unwrap();
+		command_buffer
+			.execute(self.queue.clone())
+			.unwrap()
+			.then_signal_fence_and_flush()
+			.unwrap()
+			.wait(None)
+			.unwrap();
+
+		let result = d.read().unwrap();
+		result.to_vec()
+	}
+}
+
+fn main() {
+	let instance = match Instance::new(None, &InstanceExtensions::none(), None) {
+		Ok(instance) => instance,
+		Err(e) => {
+			println!("No Vulkan instance, skipping: {}", e);
+			return;
+		}
+	};
+
+	let mut demo = None;
+	for physical in PhysicalDevice::enumerate(&instance) {
+		match CoopMatrixDemo::new(physical) {
+			Ok(d) => {
+				println!("Using device: {} (type: {:?})", physical.name(), physical.ty());
+				demo = Some(d);
+				break;
+			}
+			Err(reason) => println!("Skipping {}: {}", physical.name(), reason),
+		}
+	}
+	let demo = match demo {
+		Some(demo) => demo,
+		None => {
+			println!("No device can run cooperative matrix operations");
+			return;
+		}
+	};
+
+	// Small integers keep the float16 inputs and the float32 sums exact
+	let a: Vec<f32> = (0..SIZE * SIZE).map(|i| ((i * 7) % 11) as f32 - 5.0).collect();
+	let b: Vec<f32> = (0..SIZE * SIZE).map(|i| ((i * 5) % 9) as f32 - 4.0).collect();
+	let c: Vec<f32> = (0..SIZE * SIZE).map(|i| (i % 3) as f32).collect();
+
+	let expected = multiply_add_cpu(&a, &b, &c);
+	let result = demo.multiply_add(&a, &b, &c);
+	for (i, (&result, &expected)) in result.iter().zip(expected.iter()).enumerate() {
+		assert_eq!(result, expected, "Unexpected value at row {} column {}", i / SIZE, i % SIZE);
+	}
+	println!("The {}x{} cooperative matrix product matches the CPU", SIZE, SIZE);
+}
@@ -0,0 +1,221 @@
+// GPU driven compute dispatch: a first pass selects the values above a
+// threshold and writes the number of workgroups needed to process them into
+// an indirect dispatch command, the second pass is dispatched from that
+// command and only processes the selected values.
+//
+// Run with `cargo run --example indirect_compute`
+
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DispatchIndirectCommand};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, DeviceExtensions, Features};
+use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+use vulkano::pipeline::ComputePipeline;
+use vulkano::sync::GpuFuture;
+
+const ELEMENTS: u32 = 65536;
+const THRESHOLD: f32 = 0.75;
+// Must match local_size_x of both shaders
+const WORKGROUP_SIZE: u32 = 64;
+
+mod count_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 64) in;
+
+			layout(set = 0, binding = 0) readonly buffer Input {
+				float values[];
+			} input_data;
+
+			layout(set = 0, binding = 1) buffer Selection {
+				uint count;
+				uint indices[];
+			} selection;
+
+			// VkDispatchIndirectCommand, y and z are initialized to 1 on the CPU
+			layout(set = 0, binding = 2) buffer Command {
+				uint x;
+				uint y;
+				uint z;
+			} command;
+
+			layout(push_constant) uniform PushConstants {
+				float threshold;
+			} pc;
+
+			void main() {
+				uint index = gl_GlobalInvocationID.x;
+				if (index >= input_data.values.length() || input_data.values[index] <= pc.threshold) {
+					return;
+				}
+
+				uint slot = atomicAdd(selection.count, 1);
+				selection.indices[slot] = index;
+				// The first item of every workgroup adds that workgroup to the dispatch
+				if (slot % gl_WorkGroupSize.x == 0) {
+					atomicAdd(command.x, 1);
+				}
+			}
+		"
+	}
+}
+
+mod process_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 64) in;
+
+			layout(set = 0, binding = 0) buffer Data {
+				float values[];
+			} data;
+
+			layout(set = 0, binding = 1) readonly buffer Selection {
+				uint count;
+				uint indices[];
+			} selection;
+
+			void main() {
+				// The last workgroup is only partially used
+				if (gl_GlobalInvocationID.x >= selection.count) {
+					return;
+				}
+				uint index = selection.indices[gl_GlobalInvocationID.x];
+				data.values[index] = 1.0 - data.values[index];
+			}
+		"
+	}
+}
+
+fn main() {
+	let instance = Instance::new(None, &InstanceExtensions::none(), None).unwrap();
+	let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+	println!("Using device: {} (type: {:?})", physical.name(), physical.ty());
+
+	let queue_family = physical
+		.queue_families()
+		.find(|&q| q.supports_compute())
+		.unwrap();
+	let (device, mut queues) = Device::new(
+		physical,
+		&Features::none(),
+		&DeviceExtensions::none(),
+		[(queue_family, 0.5)].iter().cloned(),
+	)
+	.unwrap();
+	let queue = queues.next().unwrap();
+
+	// Deterministic values in [0, 1)
+	let initial: Vec<f32> = (0..ELEMENTS)
+		.map(|i| (i.wrapping_mul(2654435761) >> 8) as f32 / (1 << 24) as f32)
+		.collect();
+	let expected = initial.iter().filter(|&&v| v > THRESHOLD).count() as u32;
+
+	let data = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+		false,
+		initial.iter().cloned(),
+	)
+	.unwrap();
+	// Count followed by up to one index per element
+	let selection = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+		false,
+		(0..ELEMENTS + 1).map(|_| 0u32),
+	)
+	.unwrap();
+	let command = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		BufferUsage {
+			storage_buffer: true,
+			indirect_buffer: true,
+			..BufferUsage::none()
+		},
+		false,
+		std::iter::once(DispatchIndirectCommand { x: 0, y: 1, z: 1 }),
+	)
+	.unwrap();
+
+	let count_shader = count_cs::Shader::load(device.clone()).unwrap();
+	let count_pipeline = Arc::new(
+		ComputePipeline::new(device.clone(), &count_shader.main_entry_point(), &(), None).unwrap(),
+	);
+	let process_shader = process_cs::Shader::load(device.clone()).unwrap();
+	let process_pipeline = Arc::new(
+		ComputePipeline::new(device.clone(), &process_shader.main_entry_point(), &(), None).unwrap(),
+	);
+
+	let count_set = Arc::new(
+		PersistentDescriptorSet::start(count_pipeline.descriptor_set_layout(0).unwrap().clone())
+			.add_buffer(data.clone())
+			.unwrap()
+			.add_buffer(selection.clone())
+			.unwrap()
+			.add_buffer(command.clone())
+			.unwrap()
+			.build()
+			.unwrap(),
+	);
+	let process_set = Arc::new(
+		PersistentDescriptorSet::start(process_pipeline.descriptor_set_layout(0).unwrap().clone())
+			.add_buffer(data.clone())
+			.unwrap()
+			.add_buffer(selection.clone())
+			.unwrap()
+			.build()
+			.unwrap(),
+	);
+
+	// vulkano tracks the accesses of each command and inserts the pipeline
+	// barrier between the two dispatches itself: `command` is written by the
+	// compute shader (SHADER_WRITE) then read as an indirect buffer, which
+	// makes it wait on COMPUTE_SHADER with DRAW_INDIRECT / INDIRECT_COMMAND_READ
+	// as the destination scope.
+	let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap();
+	builder
+		.dispatch(
+			[ELEMENTS.div_ceil(WORKGROUP_SIZE), 1, 1],
+			count_pipeline,
+			count_set,
+			count_cs::ty::PushConstants { threshold: THRESHOLD },
+			vec![],
+		)
+		.unwrap()
+		.dispatch_indirect(command.clone(), process_pipeline, process_set, (), vec![])
+		.unwrap();
+	let command_buffer = builder.build().unwrap();
+
+	vulkano::sync::now(device.clone())
+		.then_execute(queue.clone(), command_buffer)
+		.unwrap()
+		.then_signal_fence_and_flush()
+		.unwrap()
+		.wait(None)
+		.unwrap();
+
+	let dispatched = command.read().unwrap()[0].x;
+	let selected = selection.read().unwrap()[0];
+	println!(
+		"Selected {} values (expected {}), dispatched {} workgroups",
+		selected, expected, dispatched
+	);
+	assert_eq!(selected, expected);
+	assert_eq!(dispatched, expected.div_ceil(WORKGROUP_SIZE));
+
+	let data = data.read().unwrap();
+	for (i, (&result, &value)) in data.iter().zip(initial.iter()).enumerate() {
+		let expected = if value > THRESHOLD { 1.0 - value } else { value };
+		assert_eq!(result, expected, "Unexpected value at index {}", i);
+	}
+	println!("Only the selected values were processed");
+}
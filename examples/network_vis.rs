@@ -0,0 +1,378 @@
+// Renders whatever 2D geometry a server streams over UDP, for live
+// visualizations of simulations or telemetry. Each datagram replaces the
+// geometry: a 4 byte header whose first byte selects the topology (0 for a
+// triangle list, 1 for a line strip), then up to 1024 vertices as pairs of
+// little endian f32 in normalized device coordinates. The socket never blocks,
+// the last received geometry is drawn until a new one arrives.
+//
+// Run with `cargo run --example network_vis -- --listen 127.0.0.1:9000`
+
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::device::{Device, DeviceExtensions};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageAccess, ImageUsage, SwapchainImage};
+use vulkano::instance::{Instance, PhysicalDevice};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::swapchain::{
+	AcquireError, ColorSpace, FullscreenExclusive, PresentMode, SurfaceTransform, Swapchain, SwapchainCreationError,
+};
+use vulkano::sync::{FlushError, GpuFuture};
+
+use vulkano_win::VkSurfaceBuild;
+
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::{Window, WindowBuilder};
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:9000";
+const MAX_VERTICES: usize = 1024;
+const HEADER_SIZE: usize = 4;
+const DATAGRAM_SIZE: usize = HEADER_SIZE + MAX_VERTICES * 8;
+
+const TOPOLOGY_TRIANGLE_LIST: u8 = 0;
+const TOPOLOGY_LINE_STRIP: u8 = 1;
+
+mod vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 position;
+
+			void main() {
+				gl_Position = vec4(position, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) out vec4 f_color;
+
+			void main() {
+				f_color = vec4(1.0, 0.0, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Vertex {
+	position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Topology {
+	TriangleList,
+	LineStrip,
+}
+
+// The geometry of a datagram, None if it is malformed
+fn parse_datagram(datagram: &[u8]) -> Option<(Topology, Vec<Vertex>)> {
+	if datagram.len() < HEADER_SIZE || !(datagram.len() - HEADER_SIZE).is_multiple_of(8) {
+		return None;
+	}
+	let topology = match datagram[0] {
+		TOPOLOGY_TRIANGLE_LIST => Topology::TriangleList,
+		TOPOLOGY_LINE_STRIP => Topology::LineStrip,
+		_ => return None,
+	};
+	let float = |bytes: &[u8]| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+	let vertices = datagram[HEADER_SIZE..]
+		.chunks_exact(8)
+		.take(MAX_VERTICES)
+		.map(|pair| Vertex {
+			position: [float(&pair[..4]), float(&pair[4..])],
+		})
+		.collect();
+	Some((topology, vertices))
+}
+
+struct NetworkedRenderer {
+	socket: UdpSocket,
+	buffer_pool: CpuBufferPool<Vertex>,
+	triangle_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+	line_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+	topology: Topology,
+	vertices: Vec<Vertex>,
+	datagram: Vec<u8>,
+}
+
+impl NetworkedRenderer {
+	fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		address: &str,
+	) -> NetworkedRenderer {
+		let socket = UdpSocket::bind(address).unwrap();
+		socket.set_nonblocking(true).unwrap();
+		println!("Listening for geometry on {}", socket.local_addr().unwrap());
+
+		let vs = vs::Shader::load(device.clone()).unwrap();
+		let fs = fs::Shader::load(device.clone()).unwrap();
+		let triangle_pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<Vertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.render_pass(subpass.clone())
+				.build(device.clone())
+				.unwrap(),
+		);
+		let line_pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<Vertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.line_strip()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.render_pass(subpass)
+				.build(device.clone())
+				.unwrap(),
+		);
+
+		NetworkedRenderer {
+			socket,
+			buffer_pool: CpuBufferPool::vertex_buffer(device),
+			triangle_pipeline,
+			line_pipeline,
+			topology: Topology::TriangleList,
+			vertices: Vec::new(),
+			datagram: vec![0; DATAGRAM_SIZE],
+		}
+	}
+
+	// Keeps the latest valid datagram received since the last frame
+	fn receive(&mut self) {
+		loop {
+			match self.socket.recv(&mut self.datagram) {
+				Ok(size) => match parse_datagram(&self.datagram[..size]) {
+					Some((topology, vertices)) => {
+						self.topology = topology;
+						self.vertices = vertices;
+					}
+					None => eprintln!("Ignoring a malformed datagram of {} bytes", size),
+				},
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(e) => {
+					eprintln!("Failed to receive a datagram: {}", e);
+					return;
+				}
+			}
+		}
+	}
+
+	fn draw(&self, builder: &mut AutoCommandBufferBuilder, dynamic_state: &DynamicState) {
+		if self.vertices.is_empty() {
+			return;
+		}
+		let pipeline = match self.topology {
+			Topology::TriangleList => self.triangle_pipeline.clone(),
+			Topology::LineStrip => self.line_pipeline.clone(),
+		};
+		let buffer = self.buffer_pool.chunk(self.vertices.iter().copied()).unwrap();
+		builder
+			.draw(
+				pipeline,
+				dynamic_state,
+				vec![Arc::new(buffer) as Arc<dyn BufferAccess + Send + Sync>],
+				(),
+				(),
+				vec![],
+			)
+			.unwrap();
+	}
+}
+
+fn window_size_dependent_setup(
+	images: &[Arc<SwapchainImage<Window>>],
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	dynamic_state: &mut DynamicState,
+) -> Vec<Arc<dyn FramebufferAbstract + Send + Sync>> {
+	let dimensions = images[0].dimensions();
+	dynamic_state.viewports = Some(vec![Viewport {
+		origin: [0.0, 0.0],
+		dimensions: [dimensions.width() as f32, dimensions.height() as f32],
+		depth_range: 0.0..1.0,
+	}]);
+	images
+		.iter()
+		.map(|image| {
+			Arc::new(
+				Framebuffer::start(render_pass.clone())
+					.add(ImageView::new(image.clone()).unwrap())
+					.unwrap()
+					.build()
+					.unwrap(),
+			) as Arc<dyn FramebufferAbstract + Send + Sync>
+		})
+		.collect()
+}
+
+fn main() {
+	let mut args = std::env::args().skip_while(|arg| arg != "--listen").skip(1);
+	let address = args.next().unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_owned());
+
+	let instance = Instance::new(None, &vulkano_win::required_extensions(), None).unwrap();
+	let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+	let event_loop = EventLoop::new();
+	let surface = WindowBuilder::new()
+		.with_title("Network visualization")
+		.build_vk_surface(&event_loop, instance.clone())
+		.unwrap();
+
+	let queue_family = physical
+		.queue_families()
+		.find(|&q| q.supports_graphics() && surface.is_supported(q).unwrap_or(false))
+		.unwrap();
+	let device_ext = DeviceExtensions {
+		khr_swapchain: true,
+		..DeviceExtensions::none()
+	};
+	let (device, mut queues) = Device::new(
+		physical,
+		physical.supported_features(),
+		&device_ext,
+		[(queue_family, 0.5)].iter().cloned(),
+	)
+	.unwrap();
+	let queue = queues.next().unwrap();
+
+	let caps = surface.capabilities(physical).unwrap();
+	let (mut swapchain, images) = Swapchain::new(
+		device.clone(),
+		surface.clone(),
+		caps.min_image_count,
+		caps.supported_formats[0].0,
+		surface.window().inner_size().into(),
+		1,
+		ImageUsage::color_attachment(),
+		&queue,
+		SurfaceTransform::Identity,
+		caps.supported_composite_alpha.iter().next().unwrap(),
+		PresentMode::Fifo,
+		FullscreenExclusive::Default,
+		true,
+		ColorSpace::SrgbNonLinear,
+	)
+	.unwrap();
+
+	let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+		vulkano::single_pass_renderpass!(
+			device.clone(),
+			attachments: {
+				color: {
+					load: Clear,
+					store: Store,
+					format: swapchain.format(),
+					samples: 1,
+				}
+			},
+			pass: {
+				color: [color],
+				depth_stencil: {}
+			}
+		)
+		.unwrap(),
+	);
+
+	let mut renderer = NetworkedRenderer::new(
+		device.clone(),
+		Subpass::from(render_pass.clone(), 0).unwrap(),
+		&address,
+	);
+
+	let mut dynamic_state = DynamicState::none();
+	let mut framebuffers = window_size_dependent_setup(&images, render_pass.clone(), &mut dynamic_state);
+	let mut recreate_swapchain = false;
+	let mut previous_frame_end = Some(vulkano::sync::now(device.clone()).boxed());
+
+	event_loop.run(move |event, _, control_flow| match event {
+		Event::WindowEvent {
+			event: WindowEvent::CloseRequested,
+			..
+		} => {
+			*control_flow = ControlFlow::Exit;
+		}
+		Event::WindowEvent {
+			event: WindowEvent::Resized(_),
+			..
+		} => {
+			recreate_swapchain = true;
+		}
+		Event::RedrawEventsCleared => {
+			previous_frame_end.as_mut().unwrap().cleanup_finished();
+			renderer.receive();
+
+			if recreate_swapchain {
+				let dimensions: [u32; 2] = surface.window().inner_size().into();
+				let (new_swapchain, new_images) = match swapchain.recreate_with_dimensions(dimensions) {
+					Ok(r) => r,
+					Err(SwapchainCreationError::UnsupportedDimensions) => return,
+					Err(e) => panic!("Failed to recreate the swapchain: {:?}", e),
+				};
+				swapchain = new_swapchain;
+				framebuffers = window_size_dependent_setup(&new_images, render_pass.clone(), &mut dynamic_state);
+				recreate_swapchain = false;
+			}
+
+			let (image_num, suboptimal, acquire_future) =
+				match vulkano::swapchain::acquire_next_image(swapchain.clone(), None) {
+					Ok(r) => r,
+					Err(AcquireError::OutOfDate) => {
+						recreate_swapchain = true;
+						return;
+					}
+					Err(e) => panic!("Failed to acquire the next image: {:?}", e),
+				};
+			recreate_swapchain |= suboptimal;
+
+			let mut builder =
+				AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap();
+			builder
+				.begin_render_pass(
+					framebuffers[image_num].clone(),
+					SubpassContents::Inline,
+					vec![[0.0, 0.0, 1.0, 1.0].into()],
+				)
+				.unwrap();
+			renderer.draw(&mut builder, &dynamic_state);
+			builder.end_render_pass().unwrap();
+			let command_buffer = builder.build().unwrap();
+
+			let future = previous_frame_end
+				.take()
+				.unwrap()
+				.join(acquire_future)
+				.then_execute(queue.clone(), command_buffer)
+				.unwrap()
+				.then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
+				.then_signal_fence_and_flush();
+			previous_frame_end = match future {
+				Ok(future) => Some(future.boxed()),
+				Err(FlushError::OutOfDate) => {
+					recreate_swapchain = true;
+					Some(vulkano::sync::now(device.clone()).boxed())
+				}
+				Err(e) => panic!("Failed to flush the frame: {:?}", e),
+			};
+		}
+		_ => (),
+	});
+}
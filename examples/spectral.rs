@@ -0,0 +1,454 @@
+// Chromatic dispersion through a glass sphere, which RGB rendering can't
+// simulate: the scene is rendered once per wavelength sample, with the
+// refractive index the Cauchy equation gives at 650nm, 550nm and 450nm, each
+// pass writing a single channel of an R32G32B32A32_SFLOAT image through its
+// color write mask. A second subpass composites the three samples as RGB.
+//
+// The material coefficients default to a strongly dispersive flint glass, with
+// B in square micrometers.
+// Run with `cargo run --example spectral -- --cauchy-a 1.62 --cauchy-b 0.012`
+
+use std::sync::Arc;
+
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::{Device, DeviceExtensions};
+use vulkano::format::{ClearValue, Format};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageAccess, ImageUsage, SwapchainImage};
+use vulkano::instance::{Instance, PhysicalDevice};
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::swapchain::{
+	AcquireError, ColorSpace, FullscreenExclusive, PresentMode, SurfaceTransform, Swapchain, SwapchainCreationError,
+};
+use vulkano::sync::{FlushError, GpuFuture};
+
+use vulkano_win::VkSurfaceBuild;
+
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::{Window, WindowBuilder};
+
+use vulkano_start::pipeline::BufferlessPipeline;
+
+// In nanometers, in the order of the channels they are written to
+const WAVELENGTHS: [f32; 3] = [650.0, 550.0, 450.0];
+const SPECTRAL_FORMAT: Format = Format::R32G32B32A32Sfloat;
+
+mod fullscreen_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			void main() {
+				vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+				gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod scene_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) out vec4 f_intensity;
+
+			layout(push_constant) uniform PushConstants {
+				vec2 resolution;
+				float refractive_index;
+			} pc;
+
+			// Thin bright lines on black, the fringes show best on sharp edges
+			float background(vec3 direction) {
+				vec2 grid = abs(fract(direction.xy / max(direction.z, 1e-3) * 2.0) - 0.5);
+				float line = 1.0 - smoothstep(0.01, 0.03, min(grid.x, grid.y));
+				return direction.z > 0.0 ? line : 0.0;
+			}
+
+			// Distance to the far or near side of the unit sphere, negative on a miss
+			float hit_sphere(vec3 origin, vec3 direction, bool far) {
+				float b = dot(origin, direction);
+				float h = b * b - dot(origin, origin) + 1.0;
+				if (h < 0.0) {
+					return -1.0;
+				}
+				return far ? -b + sqrt(h) : -b - sqrt(h);
+			}
+
+			void main() {
+				vec2 uv = (gl_FragCoord.xy * 2.0 - pc.resolution) / pc.resolution.y;
+				vec3 origin = vec3(0.0, 0.0, -3.0);
+				vec3 direction = normalize(vec3(uv.x, -uv.y, 1.5));
+
+				float t = hit_sphere(origin, direction, false);
+				if (t > 0.0) {
+					vec3 entry = origin + direction * t;
+					direction = refract(direction, entry, 1.0 / pc.refractive_index);
+					vec3 exit = entry + direction * hit_sphere(entry, direction, true);
+					vec3 refracted = refract(direction, -exit, pc.refractive_index);
+					// Total internal reflection
+					direction = refracted == vec3(0.0) ? reflect(direction, -exit) : refracted;
+				}
+				f_intensity = vec4(background(direction));
+			}
+		"
+	}
+}
+
+mod composite_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput spectral;
+
+			layout(location = 0) out vec4 f_color;
+
+			void main() {
+				// The samples are close enough to the primaries to be used as is
+				f_color = vec4(subpassLoad(spectral).rgb, 1.0);
+			}
+		"
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CauchyMaterial {
+	a: f32,
+	// In square micrometers
+	b: f32,
+}
+
+impl CauchyMaterial {
+	fn refractive_index(&self, wavelength_nm: f32) -> f32 {
+		let wavelength_um = wavelength_nm / 1000.0;
+		self.a + self.b / (wavelength_um * wavelength_um)
+	}
+}
+
+fn arg_value(name: &str) -> Option<f32> {
+	let mut args = std::env::args().skip_while(|arg| arg != name).skip(1);
+	args.next().map(|value| value.parse().unwrap())
+}
+
+struct SpectralRenderer {
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	// One per wavelength, only writing its channel
+	wavelength_pipelines: Vec<Arc<BufferlessPipeline>>,
+	composite_pipeline: Arc<BufferlessPipeline>,
+	material: CauchyMaterial,
+	device: Arc<Device>,
+	framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+	composite_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+	resolution: [f32; 2],
+}
+
+impl SpectralRenderer {
+	fn new(device: Arc<Device>, output_format: Format, material: CauchyMaterial) -> SpectralRenderer {
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::ordered_passes_renderpass!(
+				device.clone(),
+				attachments: {
+					spectral: {
+						load: Clear,
+						store: DontCare,
+						format: SPECTRAL_FORMAT,
+						samples: 1,
+					},
+					color: {
+						load: DontCare,
+						store: Store,
+						format: output_format,
+						samples: 1,
+					}
+				},
+				passes: [
+					{
+						color: [spectral],
+						depth_stencil: {},
+						input: []
+					},
+					{
+						color: [color],
+						depth_stencil: {},
+						input: [spectral]
+					}
+				]
+			)
+			.unwrap(),
+		);
+
+		let vs = fullscreen_vs::Shader::load(device.clone()).unwrap();
+		let scene_fs = scene_fs::Shader::load(device.clone()).unwrap();
+		let composite_fs = composite_fs::Shader::load(device.clone()).unwrap();
+
+		let wavelength_pipelines = (0..WAVELENGTHS.len())
+			.map(|channel| {
+				let blend = AttachmentBlend {
+					mask_red: channel == 0,
+					mask_green: channel == 1,
+					mask_blue: channel == 2,
+					mask_alpha: false,
+					..AttachmentBlend::pass_through()
+				};
+				Arc::new(
+					GraphicsPipeline::start()
+						.vertex_input(BufferlessDefinition {})
+						.vertex_shader(vs.main_entry_point(), ())
+						.triangle_list()
+						.viewports_dynamic_scissors_irrelevant(1)
+						.fragment_shader(scene_fs.main_entry_point(), ())
+						.blend_collective(blend)
+						.render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+						.build(device.clone())
+						.unwrap(),
+				)
+			})
+			.collect();
+		let composite_pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input(BufferlessDefinition {})
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(composite_fs.main_entry_point(), ())
+				.render_pass(Subpass::from(render_pass.clone(), 1).unwrap())
+				.build(device.clone())
+				.unwrap(),
+		);
+
+		for &wavelength in WAVELENGTHS.iter() {
+			println!("n({}nm) = {:.4}", wavelength, material.refractive_index(wavelength));
+		}
+
+		SpectralRenderer {
+			render_pass,
+			wavelength_pipelines,
+			composite_pipeline,
+			material,
+			device,
+			framebuffers: Vec::new(),
+			composite_set: None,
+			resolution: [0.0, 0.0],
+		}
+	}
+
+	// The spectral image matches the swapchain, so this runs on every resize
+	fn resize(&mut self, images: &[Arc<SwapchainImage<Window>>], dynamic_state: &mut DynamicState) {
+		let dimensions = images[0].dimensions();
+		self.resolution = [dimensions.width() as f32, dimensions.height() as f32];
+		dynamic_state.viewports = Some(vec![Viewport {
+			origin: [0.0, 0.0],
+			dimensions: self.resolution,
+			depth_range: 0.0..1.0,
+		}]);
+
+		let spectral = ImageView::new(
+			AttachmentImage::transient_input_attachment(
+				self.device.clone(),
+				[dimensions.width(), dimensions.height()],
+				SPECTRAL_FORMAT,
+			)
+			.unwrap(),
+		)
+		.unwrap();
+		let layout = self.composite_pipeline.descriptor_set_layout(0).unwrap();
+		self.composite_set = Some(Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(spectral.clone())
+				.unwrap()
+				.build()
+				.unwrap(),
+		));
+		self.framebuffers = images
+			.iter()
+			.map(|image| {
+				Arc::new(
+					Framebuffer::start(self.render_pass.clone())
+						.add(spectral.clone())
+						.unwrap()
+						.add(ImageView::new(image.clone()).unwrap())
+						.unwrap()
+						.build()
+						.unwrap(),
+				) as Arc<dyn FramebufferAbstract + Send + Sync>
+			})
+			.collect();
+	}
+
+	fn draw(&self, builder: &mut AutoCommandBufferBuilder, image_num: usize, dynamic_state: &DynamicState) {
+		builder
+			.begin_render_pass(
+				self.framebuffers[image_num].clone(),
+				SubpassContents::Inline,
+				vec![[0.0, 0.0, 0.0, 0.0].into(), ClearValue::None],
+			)
+			.unwrap();
+		for (pipeline, &wavelength) in self.wavelength_pipelines.iter().zip(WAVELENGTHS.iter()) {
+			builder
+				.draw(
+					pipeline.clone(),
+					dynamic_state,
+					BufferlessVertices {
+						vertices: 3,
+						instances: 1,
+					},
+					(),
+					scene_fs::ty::PushConstants {
+						resolution: self.resolution,
+						refractive_index: self.material.refractive_index(wavelength),
+					},
+					vec![],
+				)
+				.unwrap();
+		}
+		builder
+			.next_subpass(SubpassContents::Inline)
+			.unwrap()
+			.draw(
+				self.composite_pipeline.clone(),
+				dynamic_state,
+				BufferlessVertices {
+					vertices: 3,
+					instances: 1,
+				},
+				self.composite_set.clone().unwrap(),
+				(),
+				vec![],
+			)
+			.unwrap()
+			.end_render_pass()
+			.unwrap();
+	}
+}
+
+fn main() {
+	let material = CauchyMaterial {
+		a: arg_value("--cauchy-a").unwrap_or(1.62),
+		b: arg_value("--cauchy-b").unwrap_or(0.012),
+	};
+
+	let instance = Instance::new(None, &vulkano_win::required_extensions(), None).unwrap();
+	let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+	let event_loop = EventLoop::new();
+	let surface = WindowBuilder::new()
+		.with_title("Spectral dispersion")
+		.build_vk_surface(&event_loop, instance.clone())
+		.unwrap();
+
+	let queue_family = physical
+		.queue_families()
+		.find(|&q| q.supports_graphics() && surface.is_supported(q).unwrap_or(false))
+		.unwrap();
+	let device_ext = DeviceExtensions {
+		khr_swapchain: true,
+		..DeviceExtensions::none()
+	};
+	let (device, mut queues) = Device::new(
+		physical,
+		physical.supported_features(),
+		&device_ext,
+		[(queue_family, 0.5)].iter().cloned(),
+	)
+	.unwrap();
+	let queue = queues.next().unwrap();
+
+	let caps = surface.capabilities(physical).unwrap();
+	let (mut swapchain, images) = Swapchain::new(
+		device.clone(),
+		surface.clone(),
+		caps.min_image_count,
+		caps.supported_formats[0].0,
+		surface.window().inner_size().into(),
+		1,
+		ImageUsage::color_attachment(),
+		&queue,
+		SurfaceTransform::Identity,
+		caps.supported_composite_alpha.iter().next().unwrap(),
+		PresentMode::Fifo,
+		FullscreenExclusive::Default,
+		true,
+		ColorSpace::SrgbNonLinear,
+	)
+	.unwrap();
+
+	let mut dynamic_state = DynamicState::none();
+	let mut renderer = SpectralRenderer::new(device.clone(), swapchain.format(), material);
+	renderer.resize(&images, &mut dynamic_state);
+	let mut recreate_swapchain = false;
+	let mut previous_frame_end = Some(vulkano::sync::now(device.clone()).boxed());
+
+	event_loop.run(move |event, _, control_flow| match event {
+		Event::WindowEvent {
+			event: WindowEvent::CloseRequested,
+			..
+		} => {
+			*control_flow = ControlFlow::Exit;
+		}
+		Event::WindowEvent {
+			event: WindowEvent::Resized(_),
+			..
+		} => {
+			recreate_swapchain = true;
+		}
+		Event::RedrawEventsCleared => {
+			previous_frame_end.as_mut().unwrap().cleanup_finished();
+
+			if recreate_swapchain {
+				let dimensions: [u32; 2] = surface.window().inner_size().into();
+				let (new_swapchain, new_images) = match swapchain.recreate_with_dimensions(dimensions) {
+					Ok(r) => r,
+					Err(SwapchainCreationError::UnsupportedDimensions) => return,
+					Err(e) => panic!("Failed to recreate the swapchain: {:?}", e),
+				};
+				swapchain = new_swapchain;
+				renderer.resize(&new_images, &mut dynamic_state);
+				recreate_swapchain = false;
+			}
+
+			let (image_num, suboptimal, acquire_future) =
+				match vulkano::swapchain::acquire_next_image(swapchain.clone(), None) {
+					Ok(r) => r,
+					Err(AcquireError::OutOfDate) => {
+						recreate_swapchain = true;
+						return;
+					}
+					Err(e) => panic!("Failed to acquire the next image: {:?}", e),
+				};
+			recreate_swapchain |= suboptimal;
+
+			let mut builder =
+				AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap();
+			renderer.draw(&mut builder, image_num, &dynamic_state);
+			let command_buffer = builder.build().unwrap();
+
+			let future = previous_frame_end
+				.take()
+				.unwrap()
+				.join(acquire_future)
+				.then_execute(queue.clone(), command_buffer)
+				.unwrap()
+				.then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
+				.then_signal_fence_and_flush();
+			previous_frame_end = match future {
+				Ok(future) => Some(future.boxed()),
+				Err(FlushError::OutOfDate) => {
+					recreate_swapchain = true;
+					Some(vulkano::sync::now(device.clone()).boxed())
+				}
+				Err(e) => panic!("Failed to flush the frame: {:?}", e),
+			};
+		}
+		_ => (),
+	});
+}
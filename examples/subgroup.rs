@@ -0,0 +1,209 @@
+// Sums and finds the minimum of an array of floats on the GPU, once with
+// subgroup operations and once with a tree reduction in shared memory, and
+// compares the time both take.
+//
+// Run with `cargo run --example subgroup`
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, DeviceExtensions, Features, Queue};
+use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+use vulkano::pipeline::shader::EntryPointAbstract;
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::sync::GpuFuture;
+
+use vulkano_start::pipeline::{PipelineLayoutCache, SharedPipelineLayout};
+use vulkano_start::shaders;
+
+const WORKGROUP_SIZE: u32 = 256;
+const WORKGROUPS: u32 = 4096;
+const ELEMENTS: u32 = WORKGROUP_SIZE * WORKGROUPS;
+const ITERATIONS: u32 = 50;
+
+// vulkano-shaders can't parse the subgroup capabilities, the shader is
+// compiled at runtime for Vulkan 1.1 and its layout reflected from the SPIR-V.
+// It has the same bindings as the shared memory version and shares its layout
+// through the cache.
+const SUBGROUP_SHADER: &str = include_str!("../src/shaders/subgroup_reduce.glsl");
+
+fn subgroup_pipeline(
+	device: &Arc<Device>,
+	layouts: &mut PipelineLayoutCache,
+) -> Arc<ComputePipeline<SharedPipelineLayout>> {
+	let mut compiler = shaderc::Compiler::new().unwrap();
+	let mut options = shaderc::CompileOptions::new().unwrap();
+	options.set_target_env(shaderc::TargetEnv::Vulkan, shaderc::EnvVersion::Vulkan1_1 as u32);
+	let spirv = compiler
+		.compile_into_spirv(
+			SUBGROUP_SHADER,
+			shaderc::ShaderKind::Compute,
+			"subgroup_reduce.glsl",
+			"main",
+			Some(&options),
+		)
+		.unwrap();
+	shaders::compute_pipeline(device, spirv.as_binary(), layouts).unwrap()
+}
+
+mod shared_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 256) in;
+
+			layout(set = 0, binding = 0) readonly buffer Input {
+				float values[];
+			} input_data;
+
+			layout(set = 0, binding = 1) buffer Output {
+				vec2 partials[];
+			} output_data;
+
+			shared vec2 scratch[256];
+
+			void main() {
+				uint lane = gl_LocalInvocationID.x;
+				float value = input_data.values[gl_GlobalInvocationID.x];
+				scratch[lane] = vec2(value, value);
+				barrier();
+
+				for (uint stride = gl_WorkGroupSize.x / 2; stride > 0; stride /= 2) {
+					if (lane < stride) {
+						vec2 other = scratch[lane + stride];
+						scratch[lane] = vec2(scratch[lane].x + other.x, min(scratch[lane].y, other.y));
+					}
+					barrier();
+				}
+
+				if (lane == 0) {
+					output_data.partials[gl_WorkGroupID.x] = scratch[0];
+				}
+			}
+		"
+	}
+}
+
+// vulkano only reports the subgroup size (Vulkan 1.1 devices), the supported
+// stages and operations aren't exposed. The arithmetic operations are
+// supported by every desktop driver exposing a subgroup size, a device
+// lacking them would fail when building the pipeline.
+fn subgroup_size(physical: PhysicalDevice) -> Option<u32> {
+	*physical.extended_properties().subgroup_size()
+}
+
+fn run<P>(
+	device: &Arc<Device>,
+	queue: &Arc<Queue>,
+	pipeline: Arc<P>,
+	input: Arc<CpuAccessibleBuffer<[f32]>>,
+) -> ((f32, f32), Duration)
+where
+	P: ComputePipelineAbstract + Send + Sync + 'static,
+{
+	let output = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+		false,
+		(0..WORKGROUPS).map(|_| [0.0f32; 2]),
+	)
+	.unwrap();
+
+	let layout = pipeline.descriptor_set_layout(0).unwrap();
+	let set = Arc::new(
+		PersistentDescriptorSet::start(layout.clone())
+			.add_buffer(input)
+			.unwrap()
+			.add_buffer(output.clone())
+			.unwrap()
+			.build()
+			.unwrap(),
+	);
+
+	let mut builder = AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
+	builder
+		.dispatch([WORKGROUPS, 1, 1], pipeline, set, (), vec![])
+		.unwrap();
+	let command_buffer = Arc::new(builder.build().unwrap());
+
+	// The first submission also pays for the lazy allocations, leave it out
+	let mut elapsed = Duration::default();
+	for i in 0..=ITERATIONS {
+		let start = Instant::now();
+		command_buffer
+			.clone()
+			.execute(queue.clone())
+			.unwrap()
+			.then_signal_fence_and_flush()
+			.unwrap()
+			.wait(None)
+			.unwrap();
+		if i > 0 {
+			elapsed += start.elapsed();
+		}
+	}
+
+	let partials = output.read().unwrap();
+	let result = partials
+		.iter()
+		.fold((0.0, f32::INFINITY), |(sum, min), p| (sum + p[0], min.min(p[1])));
+	(result, elapsed / ITERATIONS)
+}
+
+fn main() {
+	let instance = Instance::new(None, &InstanceExtensions::none(), None).unwrap();
+	let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+	println!("Using device: {} (type: {:?})", physical.name(), physical.ty());
+
+	let queue_family = physical
+		.queue_families()
+		.find(|&q| q.supports_compute())
+		.unwrap();
+	let (device, mut queues) = Device::new(
+		physical,
+		&Features::none(),
+		&DeviceExtensions::none(),
+		[(queue_family, 0.5)].iter().cloned(),
+	)
+	.unwrap();
+	let queue = queues.next().unwrap();
+
+	// Small integers keep the sum exact whatever the order of the additions
+	let input = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+		false,
+		(0..ELEMENTS).map(|i| ((i * 7) % 13) as f32 - 3.0),
+	)
+	.unwrap();
+	let expected = input
+		.read()
+		.unwrap()
+		.iter()
+		.fold((0.0f64, f32::INFINITY), |(sum, min), &v| (sum + v as f64, min.min(v)));
+	println!("Expected: sum {} min {}", expected.0, expected.1);
+
+	let mut layouts = PipelineLayoutCache::new(device.clone());
+	let shared = shared_cs::Shader::load(device.clone()).unwrap();
+	let entry_point = shared.main_entry_point();
+	let layout = layouts.get_or_create(entry_point.layout()).unwrap();
+	let shared_pipeline = Arc::new(
+		ComputePipeline::with_pipeline_layout(device.clone(), &entry_point, &(), layout, None).unwrap(),
+	);
+	let ((sum, min), time) = run(&device, &queue, shared_pipeline, input.clone());
+	println!("Shared memory: sum {} min {} in {:?}", sum, min, time);
+
+	match subgroup_size(physical) {
+		Some(size) if size >= 8 => {
+			let ((sum, min), time) = run(&device, &queue, subgroup_pipeline(&device, &mut layouts), input);
+			println!("Subgroups of {}: sum {} min {} in {:?}", size, sum, min, time);
+		}
+		_ => println!("Subgroup operations aren't supported by this device, skipping"),
+	}
+}
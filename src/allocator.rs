@@ -0,0 +1,361 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::{BufferSlice, BufferUsage, DeviceLocalBuffer, TypedBufferAccess};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::{AttachmentImage, ImageUsage};
+
+use crate::error::VulkanoError;
+
+// Frames of history the pool sizes are computed from
+const PEAK_HISTORY: usize = 120;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct TransientKey {
+	format: Format,
+	dimensions: [u32; 2],
+	usage: ImageUsage,
+}
+
+impl TransientKey {
+	// `ImageUsage` doesn't implement `Hash`, the debug output is hashed instead
+	fn hash(&self) -> u64 {
+		let mut hasher = DefaultHasher::new();
+		format!("{:?}", self).hash(&mut hasher);
+		hasher.finish()
+	}
+}
+
+struct TransientPool {
+	key: TransientKey,
+	free: Vec<Arc<AttachmentImage<Format>>>,
+	acquired: Vec<Arc<AttachmentImage<Format>>>,
+	// Most images of the pool used at once during the current frame
+	frame_peak: usize,
+	peaks: Vec<usize>,
+}
+
+impl TransientPool {
+	fn required(&self) -> usize {
+		self.peaks.iter().copied().max().unwrap_or(0).max(self.frame_peak)
+	}
+}
+
+// Intermediate render targets only needed during a frame (bloom blurs, SSAO,
+// motion blur...), pooled by format, dimensions and usage. `acquire` reuses a
+// free image of the same kind when there is one, `release` gives it back as
+// soon as its pass is recorded, so the passes recorded afterwards reuse it
+// too. `end_frame` releases what is left and trims every pool to the most
+// images used at once over the last frames.
+// vulkano 0.22 can't bind several images to the same memory, the images
+// used only as attachments are created with `transient_attachment` instead,
+// which lets tiled GPUs keep them in tile memory without backing them with
+// VRAM.
+pub struct TransientResourceAllocator {
+	device: Arc<Device>,
+	pools: HashMap<u64, Vec<TransientPool>>,
+	frame: usize,
+}
+
+impl TransientResourceAllocator {
+	pub fn new(device: Arc<Device>) -> TransientResourceAllocator {
+		TransientResourceAllocator {
+			device,
+			pools: HashMap::new(),
+			frame: 0,
+		}
+	}
+
+	pub fn acquire(
+		&mut self,
+		format: Format,
+		dimensions: [u32; 2],
+		usage: ImageUsage,
+	) -> Result<Arc<AttachmentImage<Format>>, VulkanoError> {
+		// Only the attachment usages can be combined with `transient_attachment`
+		let attachment_only = ImageUsage {
+			color_attachment: false,
+			depth_stencil_attachment: false,
+			input_attachment: false,
+			transient_attachment: false,
+			..usage
+		} == ImageUsage::none();
+		let usage = ImageUsage {
+			transient_attachment: attachment_only,
+			..usage
+		};
+
+		let key = TransientKey { format, dimensions, usage };
+		let device = self.device.clone();
+		let pool = self.pool(key);
+		let image = match pool.free.pop() {
+			Some(image) => image,
+			None => {
+				trace!("Allocating a transient {:?} {}x{} image", format, dimensions[0], dimensions[1]);
+				AttachmentImage::with_usage(device, dimensions, format, usage)?
+			}
+		};
+		pool.acquired.push(image.clone());
+		pool.frame_peak = pool.frame_peak.max(pool.acquired.len());
+		Ok(image)
+	}
+
+	// Gives back an image whose last pass of the frame is recorded. The GPU
+	// executes the recorded commands in order, the next user of the image
+	// overwrites it after the passes that read it.
+	pub fn release(&mut self, image: &Arc<AttachmentImage<Format>>) {
+		for pool in self.pools.values_mut().flatten() {
+			if let Some(index) = pool.acquired.iter().position(|acquired| Arc::ptr_eq(acquired, image)) {
+				let image = pool.acquired.swap_remove(index);
+				pool.free.push(image);
+				return;
+			}
+		}
+		warn!("Released a transient image that wasn't acquired");
+	}
+
+	// Call once the frame is recorded, the images still in use are released
+	// for the next frame. Their commands must be submitted before recording
+	// those of the next frame.
+	pub fn end_frame(&mut self) {
+		let slot = self.frame % PEAK_HISTORY;
+		for pool in self.pools.values_mut().flatten() {
+			pool.free.append(&mut pool.acquired);
+			if pool.peaks.len() < PEAK_HISTORY {
+				pool.peaks.push(pool.frame_peak);
+			} else {
+				pool.peaks[slot] = pool.frame_peak;
+			}
+			pool.frame_peak = 0;
+
+			let required = pool.required();
+			if pool.free.len() > required {
+				debug!(
+					"Trimming the transient {:?} {}x{} pool to {} images",
+					pool.key.format, pool.key.dimensions[0], pool.key.dimensions[1], required
+				);
+				pool.free.truncate(required);
+			}
+		}
+		for bucket in self.pools.values_mut() {
+			bucket.retain(|pool| pool.required() > 0 || !pool.free.is_empty());
+		}
+		self.pools.retain(|_, bucket| !bucket.is_empty());
+		self.frame += 1;
+	}
+
+	// The images needed to satisfy the observed frames, i.e. the sum of every
+	// pool's largest simultaneous use
+	pub fn required_pool_size(&self) -> usize {
+		self.pools.values().flatten().map(TransientPool::required).sum()
+	}
+
+	pub fn allocated(&self) -> usize {
+		self.pools.values().flatten().map(|pool| pool.free.len() + pool.acquired.len()).sum()
+	}
+
+	fn pool(&mut self, key: TransientKey) -> &mut TransientPool {
+		let bucket = self.pools.entry(key.hash()).or_default();
+		let index = match bucket.iter().position(|pool| pool.key == key) {
+			Some(index) => index,
+			None => {
+				bucket.push(TransientPool {
+					key,
+					free: Vec::new(),
+					acquired: Vec::new(),
+					frame_peak: 0,
+					peaks: Vec::new(),
+				});
+				bucket.len() - 1
+			}
+		};
+		&mut bucket[index]
+	}
+}
+
+// A compute buffer of the frame, live from the pass `first_stage` to the pass
+// `last_stage` included, in recording order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AliasedUsage {
+	name: &'static str,
+	words: usize,
+	first_stage: usize,
+	last_stage: usize,
+}
+
+impl AliasedUsage {
+	fn overlaps(&self, other: &AliasedUsage) -> bool {
+		self.first_stage <= other.last_stage && other.first_stage <= self.last_stage
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AliasedBufferId(usize);
+
+pub type AliasedBuffer = Arc<BufferSlice<[u32], Arc<DeviceLocalBuffer<[u32]>>>>;
+
+// Transient compute buffers of the post processing passes (bloom, SSAO,
+// motion blur...), declared with the stages using them, which aren't live at
+// the same time. `allocate` groups the usages whose stage intervals don't
+// overlap into aliasing sets and backs each set with a single buffer of 32 bit
+// words as large as its largest member, `buffer` gives a usage its slice of it.
+// The command buffer orders the passes sharing a buffer, a pass may only
+// expect what an earlier stage of its own usage wrote.
+pub struct BufferAliasingPool {
+	device: Arc<Device>,
+	usages: Vec<AliasedUsage>,
+	// Set of every usage, an index in `buffers`
+	sets: Vec<usize>,
+	buffers: Vec<Arc<DeviceLocalBuffer<[u32]>>>,
+	dirty: bool,
+}
+
+impl BufferAliasingPool {
+	pub fn new(device: Arc<Device>) -> BufferAliasingPool {
+		BufferAliasingPool {
+			device,
+			usages: Vec::new(),
+			sets: Vec::new(),
+			buffers: Vec::new(),
+			dirty: false,
+		}
+	}
+
+	// Declares a buffer of `words` 32 bit values needed from `first_stage` to
+	// `last_stage`. The usages can be declared once, or again when the passes
+	// or the resolution change, followed by `allocate`.
+	pub fn declare(&mut self, name: &'static str, words: usize, first_stage: usize, last_stage: usize) -> AliasedBufferId {
+		assert!(first_stage <= last_stage, "{} is used from stage {} to stage {}", name, first_stage, last_stage);
+		self.usages.push(AliasedUsage {
+			name,
+			words: words.max(1),
+			first_stage,
+			last_stage,
+		});
+		self.dirty = true;
+		AliasedBufferId(self.usages.len() - 1)
+	}
+
+	// Forgets the usages, the ids already given out become invalid
+	pub fn clear(&mut self) {
+		self.usages.clear();
+		self.sets.clear();
+		self.dirty = true;
+	}
+
+	// Assigns the usages to aliasing sets and creates their buffers, keeping
+	// the previous buffers which are still large enough
+	pub fn allocate(&mut self) -> Result<(), VulkanoError> {
+		if !self.dirty {
+			return Ok(());
+		}
+		let (sets, sizes) = alias_sets(&self.usages);
+
+		let mut previous = std::mem::take(&mut self.buffers);
+		// Largest sets first so they get the largest previous buffers
+		let mut order: Vec<usize> = (0..sizes.len()).collect();
+		order.sort_by_key(|&set| std::cmp::Reverse(sizes[set]));
+		previous.sort_by_key(|buffer| std::cmp::Reverse(buffer.len()));
+
+		let mut buffers = vec![None; sizes.len()];
+		for set in order {
+			let reused = previous.iter().position(|buffer| buffer.len() >= sizes[set]);
+			let buffer = match reused {
+				Some(index) if previous[index].len() <= sizes[set] * 2 => previous.remove(index),
+				_ => {
+					trace!("Allocating an aliased compute buffer of {} words", sizes[set]);
+					DeviceLocalBuffer::array(
+						self.device.clone(),
+						sizes[set],
+						BufferUsage {
+							storage_buffer: true,
+							transfer_source: true,
+							transfer_destination: true,
+							..BufferUsage::none()
+						},
+						self.device.active_queue_families(),
+					)?
+				}
+			};
+			buffers[set] = Some(buffer);
+		}
+		self.buffers = buffers.into_iter().flatten().collect();
+		self.sets = sets;
+		self.dirty = false;
+
+		debug!(
+			"{} transient compute buffers aliased into {}, {} bytes instead of {}",
+			self.usages.len(),
+			self.buffers.len(),
+			self.aliased_bytes(),
+			self.unaliased_bytes()
+		);
+		Ok(())
+	}
+
+	// The words of `id` in the buffer of its set, `allocate` must have been
+	// called since it was declared
+	pub fn buffer(&self, id: AliasedBufferId) -> AliasedBuffer {
+		assert!(!self.dirty, "BufferAliasingPool::allocate wasn't called after declaring the buffers");
+		let usage = &self.usages[id.0];
+		let buffer = self.buffers[self.sets[id.0]].clone();
+		Arc::new(
+			BufferSlice::from_typed_buffer_access(buffer)
+				.slice(0..usage.words)
+				.expect("aliased buffers are as large as their usages"),
+		)
+	}
+
+	// The usages sharing the memory of `id`
+	pub fn aliases(&self, id: AliasedBufferId) -> Vec<&'static str> {
+		let set = self.sets[id.0];
+		(0..self.usages.len())
+			.filter(|&usage| usage != id.0 && self.sets[usage] == set)
+			.map(|usage| self.usages[usage].name)
+			.collect()
+	}
+
+	pub fn aliased_bytes(&self) -> usize {
+		self.buffers.iter().map(|buffer| buffer.len() * 4).sum()
+	}
+
+	// What separate buffers for every usage would take
+	pub fn unaliased_bytes(&self) -> usize {
+		self.usages.iter().map(|usage| usage.words * 4).sum()
+	}
+}
+
+// Greedy interval partitioning: the usages are visited by first stage and each
+// one joins the smallest set whose members all ended before it starts, or a
+// new set. Returns the set of every usage and the words of every set.
+fn alias_sets(usages: &[AliasedUsage]) -> (Vec<usize>, Vec<usize>) {
+	let mut order: Vec<usize> = (0..usages.len()).collect();
+	order.sort_by_key(|&usage| (usages[usage].first_stage, std::cmp::Reverse(usages[usage].words)));
+
+	let mut sets = vec![0; usages.len()];
+	let mut members: Vec<Vec<usize>> = Vec::new();
+	let mut sizes: Vec<usize> = Vec::new();
+	for usage in order {
+		let candidate = (0..members.len())
+			.filter(|&set| members[set].iter().all(|&member| !usages[member].overlaps(&usages[usage])))
+			// Growing a set costs the difference, prefer the sets already large enough
+			.min_by_key(|&set| (usages[usage].words.saturating_sub(sizes[set]), sizes[set]));
+		let set = match candidate {
+			Some(set) => set,
+			None => {
+				members.push(Vec::new());
+				sizes.push(0);
+				members.len() - 1
+			}
+		};
+		members[set].push(usage);
+		sizes[set] = sizes[set].max(usages[usage].words);
+		sets[usage] = set;
+	}
+	(sets, sizes)
+}
@@ -0,0 +1,459 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::pipeline::ComputePipeline;
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::forward_plus::ForwardVertex;
+use crate::pipeline::SharedComputePipeline;
+use crate::ray_march::upload_array;
+
+// Values that can be interpolated by an `AnimationCurve`
+pub trait Lerp: Copy {
+	fn add(self, other: Self) -> Self;
+	fn scale(self, factor: f32) -> Self;
+
+	fn lerp(self, other: Self, t: f32) -> Self {
+		self.scale(1.0 - t).add(other.scale(t))
+	}
+}
+
+impl Lerp for f32 {
+	fn add(self, other: f32) -> f32 {
+		self + other
+	}
+
+	fn scale(self, factor: f32) -> f32 {
+		self * factor
+	}
+}
+
+macro_rules! impl_lerp_array {
+	($($n:expr),*) => {
+		$(
+			impl Lerp for [f32; $n] {
+				fn add(mut self, other: [f32; $n]) -> [f32; $n] {
+					for i in 0..$n {
+						self[i] += other[i];
+					}
+					self
+				}
+
+				fn scale(mut self, factor: f32) -> [f32; $n] {
+					for value in self.iter_mut() {
+						*value *= factor;
+					}
+					self
+				}
+			}
+		)*
+	};
+}
+
+impl_lerp_array!(2, 3, 4, 8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopMode {
+	// Holds the last value after the end
+	Once,
+	// Restarts from the first keyframe
+	Loop,
+	// Plays forward then backward
+	PingPong,
+}
+
+// Tangents are in value units per second
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe<T> {
+	pub time: f32,
+	pub value: T,
+	pub tangent_in: T,
+	pub tangent_out: T,
+}
+
+// Cubic Hermite spline through keyframes sorted by time. The tangents are
+// either given explicitly, or computed the Catmull-Rom way from the
+// neighbouring keyframes with `catmull_rom`.
+pub struct AnimationCurve<T> {
+	keyframes: Vec<Keyframe<T>>,
+	pub loop_mode: LoopMode,
+}
+
+impl<T: Lerp> AnimationCurve<T> {
+	// `keyframes` must not be empty
+	pub fn new(mut keyframes: Vec<Keyframe<T>>, loop_mode: LoopMode) -> AnimationCurve<T> {
+		assert!(!keyframes.is_empty(), "An animation curve needs at least one keyframe");
+		keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+		AnimationCurve { keyframes, loop_mode }
+	}
+
+	// Tangent of each inner keyframe is the slope between its neighbours, the
+	// first and last keyframes use the slope of their only segment
+	pub fn catmull_rom(points: &[(f32, T)], loop_mode: LoopMode) -> AnimationCurve<T> {
+		let slope = |a: &(f32, T), b: &(f32, T)| {
+			let dt = b.0 - a.0;
+			if dt <= 0.0 {
+				a.1.scale(0.0)
+			} else {
+				b.1.add(a.1.scale(-1.0)).scale(1.0 / dt)
+			}
+		};
+
+		let keyframes = (0..points.len())
+			.map(|i| {
+				let previous = &points[i.saturating_sub(1)];
+				let next = &points[(i + 1).min(points.len() - 1)];
+				let tangent = slope(previous, next);
+				Keyframe {
+					time: points[i].0,
+					value: points[i].1,
+					tangent_in: tangent,
+					tangent_out: tangent,
+				}
+			})
+			.collect();
+		AnimationCurve::new(keyframes, loop_mode)
+	}
+
+	pub fn keyframes(&self) -> &[Keyframe<T>] {
+		&self.keyframes
+	}
+
+	// Editing must keep the keyframes sorted by time
+	pub fn keyframes_mut(&mut self) -> &mut [Keyframe<T>] {
+		&mut self.keyframes
+	}
+
+	pub fn duration(&self) -> f32 {
+		self.keyframes[self.keyframes.len() - 1].time - self.keyframes[0].time
+	}
+
+	pub fn sample(&self, t: f32) -> T {
+		let first = &self.keyframes[0];
+		let duration = self.duration();
+		if duration <= 0.0 {
+			return first.value;
+		}
+
+		let local = t - first.time;
+		let local = match self.loop_mode {
+			LoopMode::Once => local.max(0.0).min(duration),
+			LoopMode::Loop => local.rem_euclid(duration),
+			LoopMode::PingPong => {
+				let phase = local.rem_euclid(duration * 2.0);
+				if phase > duration {
+					duration * 2.0 - phase
+				} else {
+					phase
+				}
+			}
+		};
+		let time = first.time + local;
+
+		// Index of the keyframe starting the segment containing `time`
+		let next = self
+			.keyframes
+			.iter()
+			.position(|keyframe| keyframe.time > time)
+			.unwrap_or(self.keyframes.len() - 1)
+			.max(1);
+		let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+
+		let dt = b.time - a.time;
+		if dt <= 0.0 {
+			return b.value;
+		}
+		let s = ((time - a.time) / dt).clamp(0.0, 1.0);
+		let (s2, s3) = (s * s, s * s * s);
+
+		// Hermite basis functions
+		let h00 = 2.0 * s3 - 3.0 * s2 + 1.0;
+		let h10 = s3 - 2.0 * s2 + s;
+		let h01 = -2.0 * s3 + 3.0 * s2;
+		let h11 = s3 - s2;
+
+		a.value
+			.scale(h00)
+			.add(a.tangent_out.scale(h10 * dt))
+			.add(b.value.scale(h01))
+			.add(b.tangent_in.scale(h11 * dt))
+	}
+}
+
+// Must match the size of `weights` in morph_cs
+pub const MAX_MORPH_TARGETS: usize = 8;
+
+mod morph_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+			// ForwardVertex as 6 floats, a vec3 would be padded to 16 bytes
+			layout(set = 0, binding = 0) readonly buffer Base {
+				float data[];
+			} base;
+			// The deltas of every target one after the other
+			layout(set = 0, binding = 1) readonly buffer Deltas {
+				float data[];
+			} deltas;
+			layout(set = 0, binding = 2) writeonly buffer Output {
+				float data[];
+			} result;
+
+			layout(push_constant) uniform PushConstants {
+				float weights[8];
+				uint vertex_count;
+				uint target_count;
+			} pc;
+
+			void main() {
+				uint vertex = gl_GlobalInvocationID.x;
+				if (vertex >= pc.vertex_count) {
+					return;
+				}
+
+				float blended[6];
+				for (uint i = 0; i < 6; i++) {
+					blended[i] = base.data[vertex * 6 + i];
+				}
+				for (uint target = 0; target < pc.target_count; target++) {
+					float weight = pc.weights[target];
+					if (weight == 0.0) {
+						continue;
+					}
+					uint first = (target * pc.vertex_count + vertex) * 6;
+					for (uint i = 0; i < 6; i++) {
+						blended[i] += weight * deltas.data[first + i];
+					}
+				}
+
+				vec3 normal = vec3(blended[3], blended[4], blended[5]);
+				normal = normal / max(length(normal), 1e-6);
+				for (uint i = 0; i < 3; i++) {
+					result.data[vertex * 6 + i] = blended[i];
+				}
+				result.data[vertex * 6 + 3] = normal.x;
+				result.data[vertex * 6 + 4] = normal.y;
+				result.data[vertex * 6 + 5] = normal.z;
+			}
+		"
+	}
+}
+
+// Difference between a morph target and the base mesh, vertex by vertex
+pub fn morph_deltas(base: &[ForwardVertex], target: &[ForwardVertex]) -> Vec<ForwardVertex> {
+	base.iter()
+		.zip(target)
+		.map(|(base, target)| ForwardVertex {
+			position: target.position.add(base.position.scale(-1.0)),
+			normal: target.normal.add(base.normal.scale(-1.0)),
+		})
+		.collect()
+}
+
+// Blends up to MAX_MORPH_TARGETS shapes of a mesh, shape keys or facial
+// expressions, on the GPU: `record` writes base + sum(weight_i * delta_i) of
+// every vertex to `output`, the vertex buffer to draw the mesh with, with the
+// normals renormalized.
+pub struct MorphTargetAnimator {
+	pipeline: SharedComputePipeline,
+	base: Arc<DeviceLocalBuffer<[ForwardVertex]>>,
+	deltas: Arc<DeviceLocalBuffer<[ForwardVertex]>>,
+	output: Arc<DeviceLocalBuffer<[ForwardVertex]>>,
+	vertex_count: usize,
+	target_count: usize,
+}
+
+impl MorphTargetAnimator {
+	// Records the upload of the meshes. Each of `deltas` holds as many vertices
+	// as `base`, see `morph_deltas`.
+	pub fn new(
+		device: Arc<Device>,
+		builder: &mut AutoCommandBufferBuilder,
+		base: &[ForwardVertex],
+		deltas: &[Vec<ForwardVertex>],
+	) -> Result<MorphTargetAnimator, VulkanoError> {
+		assert!(deltas.len() <= MAX_MORPH_TARGETS, "at most {} morph targets", MAX_MORPH_TARGETS);
+		assert!(deltas.iter().all(|delta| delta.len() == base.len()), "the morph targets must match the base mesh");
+
+		let shader = morph_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let mut packed: Vec<ForwardVertex> = deltas.iter().flat_map(|delta| delta.iter().cloned()).collect();
+		// Buffers can't be empty
+		if packed.is_empty() {
+			packed.push(ForwardVertex::default());
+		}
+		let output = DeviceLocalBuffer::array(
+			device.clone(),
+			base.len().max(1),
+			BufferUsage {
+				storage_buffer: true,
+				vertex_buffer: true,
+				..BufferUsage::none()
+			},
+			device.active_queue_families(),
+		)?;
+
+		Ok(MorphTargetAnimator {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			base: upload_array(&device, builder, base)?,
+			deltas: upload_array(&device, builder, &packed)?,
+			output,
+			vertex_count: base.len(),
+			target_count: deltas.len(),
+		})
+	}
+
+	pub fn target_count(&self) -> usize {
+		self.target_count
+	}
+
+	// The blended vertices of the last `record`
+	pub fn output(&self) -> Arc<DeviceLocalBuffer<[ForwardVertex]>> {
+		self.output.clone()
+	}
+
+	// Records the blend outside of any render pass, before the draws reading
+	// `output`. Weights past the number of targets are ignored.
+	pub fn record(&self, builder: &mut AutoCommandBufferBuilder, weights: [f32; MAX_MORPH_TARGETS]) -> Result<(), VulkanoError> {
+		if self.vertex_count == 0 {
+			return Ok(());
+		}
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(self.base.clone())?
+				.add_buffer(self.deltas.clone())?
+				.add_buffer(self.output.clone())?
+				.build()?,
+		);
+		debug_label!(builder, "MorphTargetAnimator", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[(self.vertex_count as u32).div_ceil(64), 1, 1],
+			self.pipeline.clone(),
+			set,
+			morph_cs::ty::PushConstants {
+				weights,
+				vertex_count: self.vertex_count as u32,
+				target_count: self.target_count as u32,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+}
+
+// Plays morph target weights keyframed over time, interpolated linearly from
+// one keyframe to the next like glTF's LINEAR weights
+pub struct MorphAnimation {
+	curve: AnimationCurve<[f32; MAX_MORPH_TARGETS]>,
+	time: f32,
+	pub speed: f32,
+}
+
+impl MorphAnimation {
+	// `keyframes` are (time, weights) pairs and must not be empty
+	pub fn new(mut keyframes: Vec<(f32, [f32; MAX_MORPH_TARGETS])>, loop_mode: LoopMode) -> MorphAnimation {
+		keyframes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+		// Hermite segments whose tangents are the slope of the segment are lines
+		let slope = |a: &(f32, [f32; MAX_MORPH_TARGETS]), b: &(f32, [f32; MAX_MORPH_TARGETS])| {
+			let dt = b.0 - a.0;
+			if dt <= 0.0 {
+				[0.0; MAX_MORPH_TARGETS]
+			} else {
+				b.1.add(a.1.scale(-1.0)).scale(1.0 / dt)
+			}
+		};
+		let last = keyframes.len().saturating_sub(1);
+		let keyframes = (0..keyframes.len())
+			.map(|i| Keyframe {
+				time: keyframes[i].0,
+				value: keyframes[i].1,
+				tangent_in: slope(&keyframes[i.saturating_sub(1)], &keyframes[i]),
+				tangent_out: slope(&keyframes[i], &keyframes[(i + 1).min(last)]),
+			})
+			.collect();
+		MorphAnimation {
+			curve: AnimationCurve::new(keyframes, loop_mode),
+			time: 0.0,
+			speed: 1.0,
+		}
+	}
+
+	pub fn time(&self) -> f32 {
+		self.time
+	}
+
+	pub fn reset(&mut self) {
+		self.time = 0.0;
+	}
+
+	pub fn weights(&self) -> [f32; MAX_MORPH_TARGETS] {
+		self.curve.sample(self.time)
+	}
+
+	// Moves the playback forward by `dt` seconds and returns the weights there
+	pub fn advance(&mut self, dt: f32) -> [f32; MAX_MORPH_TARGETS] {
+		self.time += dt * self.speed;
+		self.weights()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn linear() -> AnimationCurve<f32> {
+		AnimationCurve::catmull_rom(&[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0)], LoopMode::Once)
+	}
+
+	fn assert_close(a: f32, b: f32) {
+		assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+	}
+
+	#[test]
+	fn passes_through_the_keyframes() {
+		let curve = linear();
+		for &(time, value) in &[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.5, 0.5)] {
+			assert_close(curve.sample(time), value);
+		}
+	}
+
+	#[test]
+	fn loop_modes() {
+		let mut curve = linear();
+		assert_close(curve.sample(-1.0), 0.0);
+		assert_close(curve.sample(3.0), 2.0);
+		curve.loop_mode = LoopMode::Loop;
+		assert_close(curve.sample(2.5), 0.5);
+		curve.loop_mode = LoopMode::PingPong;
+		assert_close(curve.sample(2.5), 1.5);
+	}
+
+	#[test]
+	fn keyframes_are_sorted() {
+		let keyframe = |time: f32| Keyframe {
+			time,
+			value: time,
+			tangent_in: 0.0,
+			tangent_out: 0.0,
+		};
+		let curve = AnimationCurve::new(vec![keyframe(2.0), keyframe(0.0)], LoopMode::Once);
+		assert_close(curve.duration(), 2.0);
+		assert_close(curve.sample(0.0), 0.0);
+		// Zero tangents ease in and out
+		assert_close(curve.sample(1.0), 1.0);
+		assert!(curve.sample(0.1) < 0.1);
+	}
+}
@@ -0,0 +1,340 @@
+use std::iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer, TypedBufferAccess};
+use vulkano::command_buffer::pool::standard::StandardCommandPoolAlloc;
+use vulkano::command_buffer::pool::{CommandPool, CommandPoolAlloc, CommandPoolBuilderAlloc};
+use vulkano::command_buffer::submit::{SubmitAnyBuilder, SubmitCommandBufferBuilder};
+use vulkano::command_buffer::sys::{
+	Flags,
+	UnsafeCommandBuffer,
+	UnsafeCommandBufferBuilder,
+	UnsafeCommandBufferBuilderPipelineBarrier,
+};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, Kind};
+use vulkano::device::{Device, DeviceOwned, Queue};
+use vulkano::image::{ImageAccess, ImageLayout};
+use vulkano::instance::QueueFamily;
+use vulkano::sync::{AccessCheckError, AccessFlagBits, Fence, FlushError, GpuFuture, PipelineStages, Semaphore};
+use vulkano::OomError;
+
+use crate::error::VulkanoError;
+
+// Stages and accesses of the graphics queue that can read an uploaded buffer
+fn graphics_read_stages() -> PipelineStages {
+	PipelineStages {
+		vertex_input: true,
+		vertex_shader: true,
+		fragment_shader: true,
+		..PipelineStages::none()
+	}
+}
+
+fn graphics_read_access() -> AccessFlagBits {
+	AccessFlagBits {
+		vertex_attribute_read: true,
+		index_read: true,
+		uniform_read: true,
+		shader_read: true,
+		..AccessFlagBits::none()
+	}
+}
+
+// Queue family ownership transfer of a buffer with exclusive sharing.
+// The release barrier is recorded on the source queue and the acquire barrier
+// on the destination queue, the two submissions being ordered by a semaphore.
+// vulkano's `AutoCommandBufferBuilder` doesn't expose barriers, so these are
+// meant for command buffers recorded with the unsafe builders. The queue
+// family indices must be the ones `resource` is transferred between, and the
+// release must be submitted before the acquire waits on it.
+pub struct QueueOwnershipTransfer;
+
+#[allow(clippy::missing_safety_doc)]
+impl QueueOwnershipTransfer {
+	pub unsafe fn release<B>(
+		builder: &mut UnsafeCommandBufferBuilderPipelineBarrier,
+		resource: &B,
+		src_queue_family: u32,
+		dst_queue_family: u32,
+	) where
+		B: ?Sized + BufferAccess,
+	{
+		builder.add_buffer_memory_barrier(
+			resource,
+			PipelineStages {
+				transfer: true,
+				..PipelineStages::none()
+			},
+			AccessFlagBits {
+				transfer_write: true,
+				..AccessFlagBits::none()
+			},
+			// The destination scope is ignored by the releasing queue
+			PipelineStages {
+				bottom_of_pipe: true,
+				..PipelineStages::none()
+			},
+			AccessFlagBits::none(),
+			false,
+			Some((src_queue_family, dst_queue_family)),
+			0,
+			resource.size(),
+		);
+	}
+
+	pub unsafe fn acquire<B>(
+		builder: &mut UnsafeCommandBufferBuilderPipelineBarrier,
+		resource: &B,
+		src_queue_family: u32,
+		dst_queue_family: u32,
+	) where
+		B: ?Sized + BufferAccess,
+	{
+		builder.add_buffer_memory_barrier(
+			resource,
+			// The source scope is ignored by the acquiring queue
+			PipelineStages {
+				top_of_pipe: true,
+				..PipelineStages::none()
+			},
+			AccessFlagBits::none(),
+			graphics_read_stages(),
+			graphics_read_access(),
+			false,
+			Some((src_queue_family, dst_queue_family)),
+			0,
+			resource.size(),
+		);
+	}
+}
+
+// A command buffer recorded with the unsafe builder, with the pool allocation
+// it was recorded in
+struct RecordedCommands {
+	command_buffer: UnsafeCommandBuffer,
+	_alloc: StandardCommandPoolAlloc,
+}
+
+// Everything used by `commands` must outlive the returned command buffer
+unsafe fn record_once<F>(
+	device: &Arc<Device>,
+	queue_family: QueueFamily,
+	commands: F,
+) -> Result<RecordedCommands, OomError>
+where
+	F: FnOnce(&mut UnsafeCommandBufferBuilder),
+{
+	let pool = Device::standard_command_pool(device, queue_family);
+	// Allocates exactly the one command buffer asked for
+	let alloc = pool
+		.alloc(false, 1)?
+		.next()
+		.ok_or(OomError::OutOfHostMemory)?
+		.into_alloc();
+	let mut builder = UnsafeCommandBufferBuilder::new(alloc.inner(), Kind::primary(), Flags::OneTimeSubmit)?;
+	commands(&mut builder);
+	Ok(RecordedCommands {
+		command_buffer: builder.build()?,
+		_alloc: alloc,
+	})
+}
+
+// Uploads data to device local memory through a dedicated transfer queue.
+// The buffer is owned by the transfer family while it is copied to, then
+// released to the graphics family. The copy is submitted right away and
+// signals a semaphore, the returned future waits on it and acquires the buffer
+// on the graphics queue before whatever is joined after it.
+pub struct TransferUpload {
+	device: Arc<Device>,
+	transfer_queue: Arc<Queue>,
+	graphics_queue: Arc<Queue>,
+}
+
+impl TransferUpload {
+	pub fn new(device: Arc<Device>, transfer_queue: Arc<Queue>, graphics_queue: Arc<Queue>) -> TransferUpload {
+		TransferUpload {
+			device,
+			transfer_queue,
+			graphics_queue,
+		}
+	}
+
+	#[allow(clippy::type_complexity)]
+	pub fn upload<T, I>(
+		&self,
+		data: I,
+		usage: BufferUsage,
+	) -> Result<(Arc<DeviceLocalBuffer<[T]>>, Box<dyn GpuFuture>), VulkanoError>
+	where
+		T: Copy + Send + Sync + 'static,
+		I: ExactSizeIterator<Item = T>,
+	{
+		let staging = CpuAccessibleBuffer::from_iter(
+			self.device.clone(),
+			BufferUsage::transfer_source(),
+			false,
+			data,
+		)?;
+		let usage = BufferUsage {
+			transfer_destination: true,
+			..usage
+		};
+		let transfer_family = self.transfer_queue.family();
+		let graphics_family = self.graphics_queue.family();
+
+		// Both queues are in the same family, there is nothing to transfer
+		if transfer_family.id() == graphics_family.id() {
+			let buffer =
+				DeviceLocalBuffer::array(self.device.clone(), staging.len(), usage, iter::once(graphics_family))?;
+			let mut builder =
+				AutoCommandBufferBuilder::primary_one_time_submit(self.device.clone(), graphics_family)?;
+			builder.copy_buffer(staging, buffer.clone())?;
+			let future = vulkano::sync::now(self.device.clone())
+				.then_execute(self.graphics_queue.clone(), builder.build()?)?;
+			return Ok((buffer, future.boxed()));
+		}
+
+		let buffer =
+			DeviceLocalBuffer::array(self.device.clone(), staging.len(), usage, iter::once(transfer_family))?;
+		let (src, dst) = (transfer_family.id(), graphics_family.id());
+		// Safe, the buffers are kept alive by the future with the command buffers
+		let (transfer, acquire) = unsafe {
+			let transfer = record_once(&self.device, transfer_family, |builder| {
+				builder.copy_buffer(&*staging, &*buffer, iter::once((0, 0, staging.size())));
+				let mut barrier = UnsafeCommandBufferBuilderPipelineBarrier::new();
+				QueueOwnershipTransfer::release(&mut barrier, &*buffer, src, dst);
+				builder.pipeline_barrier(&barrier);
+			})?;
+			let acquire = record_once(&self.device, graphics_family, |builder| {
+				let mut barrier = UnsafeCommandBufferBuilderPipelineBarrier::new();
+				QueueOwnershipTransfer::acquire(&mut barrier, &*buffer, src, dst);
+				builder.pipeline_barrier(&barrier);
+			})?;
+			(transfer, acquire)
+		};
+
+		let semaphore = Semaphore::alloc(self.device.clone())?;
+		let transfer_done = Fence::alloc(self.device.clone())?;
+		// Safe, the semaphore and the fence are new and the command buffer was
+		// never submitted
+		unsafe {
+			let mut submit = SubmitCommandBufferBuilder::new();
+			submit.add_command_buffer(&transfer.command_buffer);
+			submit.add_signal_semaphore(&semaphore);
+			submit.set_fence_signal(&transfer_done);
+			submit.submit(&self.transfer_queue).map_err(FlushError::from)?;
+		}
+
+		let future = OwnershipTransferFuture {
+			graphics_queue: self.graphics_queue.clone(),
+			acquire,
+			_transfer: transfer,
+			semaphore,
+			transfer_done,
+			_staging: staging,
+			_buffer: buffer.clone(),
+			submitted: Mutex::new(false),
+			finished: AtomicBool::new(false),
+		};
+		Ok((buffer, future.boxed()))
+	}
+}
+
+// The graphics side of `TransferUpload`: waits on the transfer semaphore and
+// executes the acquire barrier, in the same submission as the command buffers
+// executed after it
+struct OwnershipTransferFuture {
+	graphics_queue: Arc<Queue>,
+	acquire: RecordedCommands,
+	_transfer: RecordedCommands,
+	semaphore: Semaphore,
+	transfer_done: Fence,
+	_staging: Arc<dyn BufferAccess + Send + Sync>,
+	_buffer: Arc<dyn BufferAccess + Send + Sync>,
+	submitted: Mutex<bool>,
+	finished: AtomicBool,
+}
+
+impl OwnershipTransferFuture {
+	fn acquire_submission(&self) -> SubmitCommandBufferBuilder<'_> {
+		let mut submit = SubmitCommandBufferBuilder::new();
+		// Safe, the semaphore is signaled once by the transfer submission and
+		// only waited on here
+		unsafe {
+			submit.add_wait_semaphore(&self.semaphore, graphics_read_stages());
+			submit.add_command_buffer(&self.acquire.command_buffer);
+		}
+		submit
+	}
+}
+
+unsafe impl GpuFuture for OwnershipTransferFuture {
+	fn cleanup_finished(&mut self) {}
+
+	unsafe fn build_submission(&self) -> Result<SubmitAnyBuilder<'_>, FlushError> {
+		Ok(SubmitAnyBuilder::CommandBuffer(self.acquire_submission()))
+	}
+
+	fn flush(&self) -> Result<(), FlushError> {
+		let mut submitted = self.submitted.lock().unwrap();
+		if *submitted {
+			return Ok(());
+		}
+		self.acquire_submission().submit(&self.graphics_queue)?;
+		*submitted = true;
+		Ok(())
+	}
+
+	unsafe fn signal_finished(&self) {
+		self.finished.store(true, Ordering::SeqCst);
+	}
+
+	fn queue_change_allowed(&self) -> bool {
+		false
+	}
+
+	fn queue(&self) -> Option<Arc<Queue>> {
+		Some(self.graphics_queue.clone())
+	}
+
+	// The upload isn't tracked by vulkano, the buffer is locked by the first
+	// command buffer using it like any unused resource
+	fn check_buffer_access(
+		&self,
+		_buffer: &dyn BufferAccess,
+		_exclusive: bool,
+		_queue: &Queue,
+	) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+		Err(AccessCheckError::Unknown)
+	}
+
+	fn check_image_access(
+		&self,
+		_image: &dyn ImageAccess,
+		_layout: ImageLayout,
+		_exclusive: bool,
+		_queue: &Queue,
+	) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+		Err(AccessCheckError::Unknown)
+	}
+}
+
+unsafe impl DeviceOwned for OwnershipTransferFuture {
+	fn device(&self) -> &Arc<Device> {
+		self.graphics_queue.device()
+	}
+}
+
+impl Drop for OwnershipTransferFuture {
+	fn drop(&mut self) {
+		if *self.finished.get_mut() {
+			return;
+		}
+		// Errors mean a lost device, which has nothing left to wait for
+		let _ = self
+			.flush()
+			.and_then(|()| self.graphics_queue.wait().map_err(|_| FlushError::DeviceLost));
+		let _ = self.transfer_done.wait(None);
+	}
+}
@@ -0,0 +1,358 @@
+use std::f32::consts::TAU;
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::device::Device;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::pipeline::GraphicsPipeline;
+
+use crate::error::VulkanoError;
+use crate::pipeline::SharedPipeline;
+
+// Width of the alpha falloff along the edges, in pixels
+const CANVAS_FEATHER: f32 = 1.0;
+// Length of the segments approximating arcs, in pixels
+const CANVAS_ARC_STEP: f32 = 4.0;
+const CANVAS_MAX_ARC_SEGMENTS: usize = 256;
+
+mod canvas_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			// In pixels from the top left corner
+			layout(location = 0) in vec2 position;
+			layout(location = 1) in vec4 color;
+
+			layout(location = 0) out vec4 v_color;
+
+			layout(push_constant) uniform PushConstants {
+				vec2 viewport;
+			} pc;
+
+			void main() {
+				v_color = color;
+				gl_Position = vec4(position / pc.viewport * 2.0 - 1.0, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod canvas_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec4 v_color;
+
+			layout(location = 0) out vec4 f_color;
+
+			void main() {
+				f_color = v_color;
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct CanvasVertex {
+	position: [f32; 2],
+	color: [f32; 4],
+}
+vulkano::impl_vertex!(CanvasVertex, position, color);
+
+// Immediate 2D drawing in pixels from the top left corner of the viewport.
+// Every shape is tessellated into triangles on the CPU, its edges fading to
+// transparent over CANVAS_FEATHER pixels for the anti-aliasing, and the
+// frame's triangles are drawn at once by `flush` from a single chunk.
+pub struct Gpu2dCanvas {
+	pipeline: SharedPipeline,
+	vertex_pool: CpuBufferPool<CanvasVertex>,
+	vertices: Vec<CanvasVertex>,
+}
+
+impl Gpu2dCanvas {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<Gpu2dCanvas, VulkanoError> {
+		let vs = canvas_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = canvas_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<CanvasVertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.blend_alpha_blending()
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+
+		Ok(Gpu2dCanvas {
+			pipeline,
+			vertex_pool: CpuBufferPool::vertex_buffer(device),
+			vertices: Vec::new(),
+		})
+	}
+
+	// A disc, or a ring one pixel wide when not `filled`
+	pub fn draw_circle(&mut self, center: [f32; 2], radius: f32, color: [f32; 4], filled: bool) {
+		if radius <= 0.0 {
+			return;
+		}
+		let segments = arc_segments(radius, TAU);
+		let circle = |radius: f32| -> Vec<[f32; 2]> {
+			(0..segments)
+				.map(|i| {
+					let angle = i as f32 / segments as f32 * TAU;
+					[center[0] + radius * angle.cos(), center[1] + radius * angle.sin()]
+				})
+				.collect()
+		};
+
+		if filled {
+			self.fill_polygon(&circle(radius), color);
+		} else {
+			// Opaque on the circle, transparent a pixel inside and outside of it
+			let inner = circle((radius - CANVAS_FEATHER).max(0.0));
+			let middle = circle(radius);
+			let outer = circle(radius + CANVAS_FEATHER);
+			self.strip(&inner, &middle, faded(color, 0.0), color);
+			self.strip(&middle, &outer, color, faded(color, 0.0));
+		}
+	}
+
+	// A filled rectangle, its corners rounded by arcs of `corner_radius`
+	pub fn draw_rect(&mut self, min: [f32; 2], max: [f32; 2], color: [f32; 4], corner_radius: f32) {
+		let radius = corner_radius.min((max[0] - min[0]) * 0.5).min((max[1] - min[1]) * 0.5);
+		if radius <= 0.0 {
+			self.fill_polygon(&[min, [max[0], min[1]], max, [min[0], max[1]]], color);
+			return;
+		}
+
+		// Clockwise on screen from the right of the top edge, each corner's arc
+		// starting where the one before ended
+		let corners = [
+			[max[0] - radius, min[1] + radius],
+			[max[0] - radius, max[1] - radius],
+			[min[0] + radius, max[1] - radius],
+			[min[0] + radius, min[1] + radius],
+		];
+		let segments = arc_segments(radius, TAU * 0.25);
+		let mut points = Vec::with_capacity(4 * (segments + 1));
+		for (corner, center) in corners.iter().enumerate() {
+			let start = (corner as f32 - 1.0) * TAU * 0.25;
+			for i in 0..=segments {
+				let angle = start + i as f32 / segments as f32 * TAU * 0.25;
+				points.push([center[0] + radius * angle.cos(), center[1] + radius * angle.sin()]);
+			}
+		}
+		self.fill_polygon(&points, color);
+	}
+
+	// A segment `width` pixels wide with square caps ending at `start` and
+	// `end`. Lines thinner than a pixel are drawn a pixel wide but fainter.
+	pub fn draw_line(&mut self, start: [f32; 2], end: [f32; 2], width: f32, color: [f32; 4]) {
+		let direction = normalize(sub(end, start));
+		if direction == [0.0, 0.0] || width <= 0.0 {
+			return;
+		}
+		let (half_width, color) = if width < 1.0 {
+			(0.5, faded(color, width))
+		} else {
+			(width * 0.5, color)
+		};
+		let normal = [-direction[1] * half_width, direction[0] * half_width];
+		self.fill_polygon(
+			&[add(start, normal), add(end, normal), sub(end, normal), sub(start, normal)],
+			color,
+		);
+	}
+
+	// A filled simple polygon, convex or not, of any winding
+	pub fn draw_polygon(&mut self, points: &[[f32; 2]], color: [f32; 4]) {
+		self.fill_polygon(points, color);
+	}
+
+	// Records the shapes drawn since the last flush, inside the current render
+	// pass, `dimensions` being the size of its viewport
+	pub fn flush(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		dimensions: [u32; 2],
+	) -> Result<(), VulkanoError> {
+		if self.vertices.is_empty() {
+			return Ok(());
+		}
+
+		let vertices = self.vertex_pool.chunk(self.vertices.drain(..))?;
+		let push_constants = canvas_vs::ty::PushConstants {
+			viewport: [dimensions[0] as f32, dimensions[1] as f32],
+		};
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![Arc::new(vertices) as Arc<dyn BufferAccess + Send + Sync>],
+			(),
+			push_constants,
+			vec![],
+		)?;
+		Ok(())
+	}
+
+	// The polygon shrunk by half the feather is filled with `color`, a band out
+	// to the polygon grown by half the feather fades it out
+	fn fill_polygon(&mut self, points: &[[f32; 2]], color: [f32; 4]) {
+		// Repeated points have no edge normal to offset them along
+		let mut points = points.to_vec();
+		points.dedup_by(|a, b| distance_squared(*a, *b) < 1e-6);
+		while points.len() > 1 && distance_squared(points[0], points[points.len() - 1]) < 1e-6 {
+			points.pop();
+		}
+		if points.len() < 3 {
+			return;
+		}
+		let points = &points[..];
+		let area = signed_area(points);
+		if area.abs() < 1e-6 {
+			return;
+		}
+		let orientation = area.signum();
+
+		let count = points.len();
+		let offsets: Vec<[f32; 2]> = (0..count)
+			.map(|i| {
+				let previous = points[(i + count - 1) % count];
+				let next = points[(i + 1) % count];
+				let before = outward_normal(previous, points[i], orientation);
+				let after = outward_normal(points[i], next, orientation);
+				// Miter of the two edges, limited on sharp corners
+				let average = scale(add(before, after), 0.5);
+				let length_squared = dot(average, average);
+				if length_squared > 1e-6 {
+					scale(average, (1.0 / length_squared).min(4.0) * CANVAS_FEATHER * 0.5)
+				} else {
+					[0.0, 0.0]
+				}
+			})
+			.collect();
+		let inner: Vec<[f32; 2]> = points.iter().zip(&offsets).map(|(&point, &offset)| sub(point, offset)).collect();
+		let outer: Vec<[f32; 2]> = points.iter().zip(&offsets).map(|(&point, &offset)| add(point, offset)).collect();
+
+		let vertex = |position| CanvasVertex { position, color };
+		for [a, b, c] in triangulate(points, orientation) {
+			self.vertices.extend_from_slice(&[vertex(inner[a]), vertex(inner[b]), vertex(inner[c])]);
+		}
+		self.strip(&inner, &outer, color, faded(color, 0.0));
+	}
+
+	// Quads between two closed loops of as many points
+	fn strip(&mut self, from: &[[f32; 2]], to: &[[f32; 2]], from_color: [f32; 4], to_color: [f32; 4]) {
+		let count = from.len();
+		for i in 0..count {
+			let j = (i + 1) % count;
+			let a = CanvasVertex { position: from[i], color: from_color };
+			let b = CanvasVertex { position: from[j], color: from_color };
+			let c = CanvasVertex { position: to[i], color: to_color };
+			let d = CanvasVertex { position: to[j], color: to_color };
+			self.vertices.extend_from_slice(&[a, b, c, b, d, c]);
+		}
+	}
+}
+
+// Enough segments for `angle` radians of an arc of `radius` pixels
+fn arc_segments(radius: f32, angle: f32) -> usize {
+	((radius * angle / CANVAS_ARC_STEP).ceil() as usize).clamp(3, CANVAS_MAX_ARC_SEGMENTS)
+}
+
+fn faded(color: [f32; 4], alpha: f32) -> [f32; 4] {
+	[color[0], color[1], color[2], color[3] * alpha]
+}
+
+// Positive for polygons going clockwise on screen, y pointing down
+fn signed_area(points: &[[f32; 2]]) -> f32 {
+	let count = points.len();
+	(0..count)
+		.map(|i| cross(points[i], points[(i + 1) % count]))
+		.sum::<f32>()
+		* 0.5
+}
+
+fn outward_normal(from: [f32; 2], to: [f32; 2], orientation: f32) -> [f32; 2] {
+	let direction = normalize(sub(to, from));
+	[direction[1] * orientation, -direction[0] * orientation]
+}
+
+// Ear clipping, quadratic but the canvas' polygons are small. Gives up on
+// self intersecting polygons with a fan of what's left.
+fn triangulate(points: &[[f32; 2]], orientation: f32) -> Vec<[usize; 3]> {
+	let mut remaining: Vec<usize> = (0..points.len()).collect();
+	let mut triangles = Vec::with_capacity(points.len() - 2);
+	while remaining.len() > 3 {
+		let count = remaining.len();
+		let ear = (0..count).find(|&i| {
+			let [a, b, c] = [remaining[(i + count - 1) % count], remaining[i], remaining[(i + 1) % count]];
+			let convex = cross(sub(points[b], points[a]), sub(points[c], points[b])) * orientation > 0.0;
+			convex
+				&& remaining
+					.iter()
+					.filter(|&&other| other != a && other != b && other != c)
+					.all(|&other| !in_triangle(points[other], points[a], points[b], points[c], orientation))
+		});
+		match ear {
+			Some(i) => {
+				triangles.push([remaining[(i + count - 1) % count], remaining[i], remaining[(i + 1) % count]]);
+				remaining.remove(i);
+			}
+			None => break,
+		}
+	}
+	for i in 1..remaining.len() - 1 {
+		triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+	}
+	triangles
+}
+
+fn in_triangle(point: [f32; 2], a: [f32; 2], b: [f32; 2], c: [f32; 2], orientation: f32) -> bool {
+	let side = |from: [f32; 2], to: [f32; 2]| cross(sub(to, from), sub(point, from)) * orientation;
+	side(a, b) >= 0.0 && side(b, c) >= 0.0 && side(c, a) >= 0.0
+}
+
+fn add(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+	[a[0] + b[0], a[1] + b[1]]
+}
+
+fn sub(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+	[a[0] - b[0], a[1] - b[1]]
+}
+
+fn scale(a: [f32; 2], factor: f32) -> [f32; 2] {
+	[a[0] * factor, a[1] * factor]
+}
+
+fn dot(a: [f32; 2], b: [f32; 2]) -> f32 {
+	a[0] * b[0] + a[1] * b[1]
+}
+
+fn distance_squared(a: [f32; 2], b: [f32; 2]) -> f32 {
+	dot(sub(a, b), sub(a, b))
+}
+
+fn cross(a: [f32; 2], b: [f32; 2]) -> f32 {
+	a[0] * b[1] - a[1] * b[0]
+}
+
+fn normalize(a: [f32; 2]) -> [f32; 2] {
+	let length = dot(a, a).sqrt();
+	if length > 1e-6 {
+		scale(a, 1.0 / length)
+	} else {
+		[0.0, 0.0]
+	}
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, TypedBufferAccess};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::DescriptorSetsCollection;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use crate::error::VulkanoError;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BindStats {
+	pub draws: u32,
+	pub indexed_draws: u32,
+	pub pipeline_binds: u32,
+	pub vertex_buffer_binds: u32,
+	pub index_buffer_binds: u32,
+}
+
+impl BindStats {
+	// Binds that a naive recording would have emitted but weren't needed
+	pub fn redundant_binds_skipped(&self) -> u32 {
+		(self.draws - self.pipeline_binds)
+			+ (self.draws - self.vertex_buffer_binds)
+			+ (self.indexed_draws - self.index_buffer_binds)
+	}
+}
+
+// Identifies a buffer range independently of the wrapper type holding it
+fn buffer_key(buffer: &dyn BufferAccess) -> (u64, usize) {
+	let inner = buffer.inner();
+	(inner.buffer.key(), inner.offset)
+}
+
+fn pipeline_key(pipeline: &Arc<dyn GraphicsPipelineAbstract + Send + Sync>) -> usize {
+	Arc::as_ptr(pipeline) as *const () as usize
+}
+
+// Records draws while tracking the last bound pipeline, vertex and index
+// buffers, so a bind is only requested when the state actually changes.
+// vulkano emits the bind commands lazily when recording a draw and compares
+// them with what is already bound, this keeps the same bookkeeping on our side
+// to count the binds and let callers order their draws to minimize them.
+pub struct GraphicsStateCache<'a> {
+	builder: &'a mut AutoCommandBufferBuilder,
+	last_pipeline: Option<usize>,
+	last_vertex_buffer: Option<(u64, usize)>,
+	last_index_buffer: Option<(u64, usize)>,
+	stats: BindStats,
+}
+
+impl<'a> GraphicsStateCache<'a> {
+	pub fn new(builder: &'a mut AutoCommandBufferBuilder) -> GraphicsStateCache<'a> {
+		GraphicsStateCache {
+			builder,
+			last_pipeline: None,
+			last_vertex_buffer: None,
+			last_index_buffer: None,
+			stats: BindStats::default(),
+		}
+	}
+
+	pub fn draw<S, Pc>(
+		&mut self,
+		pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+		dynamic_state: &DynamicState,
+		vertex_buffer: Arc<dyn BufferAccess + Send + Sync>,
+		sets: S,
+		push_constants: Pc,
+	) -> Result<&mut Self, VulkanoError>
+	where
+		S: DescriptorSetsCollection,
+	{
+		self.bind_pipeline(&pipeline);
+		self.bind_vertex_buffer(&*vertex_buffer);
+		self.stats.draws += 1;
+
+		self.builder.draw(
+			pipeline,
+			dynamic_state,
+			vec![vertex_buffer],
+			sets,
+			push_constants,
+			vec![],
+		)?;
+		Ok(self)
+	}
+
+	pub fn draw_indexed<Ib, I, S, Pc>(
+		&mut self,
+		pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+		dynamic_state: &DynamicState,
+		vertex_buffer: Arc<dyn BufferAccess + Send + Sync>,
+		index_buffer: Ib,
+		sets: S,
+		push_constants: Pc,
+	) -> Result<&mut Self, VulkanoError>
+	where
+		Ib: BufferAccess + TypedBufferAccess<Content = [I]> + Send + Sync + 'static,
+		I: vulkano::pipeline::input_assembly::Index + 'static,
+		S: DescriptorSetsCollection,
+	{
+		self.bind_pipeline(&pipeline);
+		self.bind_vertex_buffer(&*vertex_buffer);
+		let key = buffer_key(&index_buffer);
+		if self.last_index_buffer != Some(key) {
+			self.last_index_buffer = Some(key);
+			self.stats.index_buffer_binds += 1;
+		}
+		self.stats.draws += 1;
+		self.stats.indexed_draws += 1;
+
+		self.builder.draw_indexed(
+			pipeline,
+			dynamic_state,
+			vec![vertex_buffer],
+			index_buffer,
+			sets,
+			push_constants,
+			vec![],
+		)?;
+		Ok(self)
+	}
+
+	pub fn stats(&self) -> BindStats {
+		self.stats
+	}
+
+	fn bind_pipeline(&mut self, pipeline: &Arc<dyn GraphicsPipelineAbstract + Send + Sync>) {
+		let key = pipeline_key(pipeline);
+		if self.last_pipeline != Some(key) {
+			self.last_pipeline = Some(key);
+			self.stats.pipeline_binds += 1;
+		}
+	}
+
+	fn bind_vertex_buffer(&mut self, vertex_buffer: &dyn BufferAccess) {
+		let key = buffer_key(vertex_buffer);
+		if self.last_vertex_buffer != Some(key) {
+			self.last_vertex_buffer = Some(key);
+			self.stats.vertex_buffer_binds += 1;
+		}
+	}
+}
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::ComputePipeline;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::pipeline::SharedComputePipeline;
+
+// Must match HI_Z_MAX_LEVELS in hi_z.glsl, enough for 4096 pixels
+pub const HI_Z_MAX_LEVELS: usize = 12;
+pub const HI_Z_FORMAT: Format = Format::R32Sfloat;
+
+mod hi_z_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			// The depth buffer, or the previous level
+			layout(set = 0, binding = 0) uniform sampler2D source;
+			layout(set = 0, binding = 1, r32f) uniform writeonly image2D destination;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+
+				// Farthest depth of every source texel the pixel touches, 3 along
+				// the odd dimensions so the last texel isn't dropped
+				ivec2 source_size = textureSize(source, 0);
+				ivec2 first = pixel * source_size / size;
+				ivec2 last = ((pixel + 1) * source_size + size - 1) / size;
+				float depth = 0.0;
+				for (int y = first.y; y < last.y; y++) {
+					for (int x = first.x; x < last.x; x++) {
+						depth = max(depth, texelFetch(source, ivec2(x, y), 0).r);
+					}
+				}
+				imageStore(destination, pixel, vec4(depth));
+			}
+		"
+	}
+}
+
+// Hierarchical depth buffer for occlusion tests (Hi-Z). Once the depth
+// prepass is done, each level keeps the farthest depth of the 2x2 texels of
+// the level before, level 0 being a copy of the depth buffer, so whatever is
+// behind a texel of any level is hidden. The chain has ceil(log2(max(w, h)))
+// levels. vulkano 0.22 can't view a single mip level of an image, each level
+// is a separate image; the shaders sample them through `sample_hi_z` of
+// hi_z.glsl.
+pub struct HiZBuilder {
+	pipeline: SharedComputePipeline,
+	levels: Vec<Arc<StorageImage<Format>>>,
+	sampler: Arc<Sampler>,
+	dimensions: [u32; 2],
+	device: Arc<Device>,
+}
+
+impl HiZBuilder {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<HiZBuilder, VulkanoError> {
+		let shader = hi_z_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		Ok(HiZBuilder {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			levels: levels(&device, dimensions)?,
+			// The levels are read texel by texel, never interpolated
+			sampler: Sampler::new(
+				device.clone(),
+				Filter::Nearest,
+				Filter::Nearest,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)?,
+			dimensions,
+			device,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		self.levels = levels(&self.device, dimensions)?;
+		self.dimensions = dimensions;
+		Ok(())
+	}
+
+	pub fn level_count(&self) -> usize {
+		self.levels.len()
+	}
+
+	pub fn level(&self, level: usize) -> Arc<StorageImage<Format>> {
+		self.levels[level].clone()
+	}
+
+	// Views of HI_Z_MAX_LEVELS levels for the `hi_z` array of hi_z.glsl, the
+	// last level repeated past the end of the chain
+	pub fn views(&self) -> Result<Vec<Arc<dyn ImageViewAbstract + Send + Sync>>, VulkanoError> {
+		(0..HI_Z_MAX_LEVELS)
+			.map(|level| {
+				let image = self.levels[level.min(self.levels.len() - 1)].clone();
+				let view: Arc<dyn ImageViewAbstract + Send + Sync> = Arc::new(ImageView::new(image)?);
+				Ok(view)
+			})
+			.collect()
+	}
+
+	pub fn sampler(&self) -> Arc<Sampler> {
+		self.sampler.clone()
+	}
+
+	// Records the whole chain from `depth`, every frame after the depth
+	// prepass and outside of any render pass
+	pub fn build(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+	) -> Result<(), VulkanoError> {
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		for level in 0..self.levels.len() {
+			let source: Arc<dyn ImageViewAbstract + Send + Sync> = match level {
+				0 => depth.clone(),
+				_ => Arc::new(ImageView::new(self.levels[level - 1].clone())?),
+			};
+			let set = Arc::new(
+				PersistentDescriptorSet::start(layout.clone())
+					.add_sampled_image(source, self.sampler.clone())?
+					.add_image(ImageView::new(self.levels[level].clone())?)?
+					.build()?,
+			);
+			let [width, height] = level_dimensions(self.dimensions, level);
+			debug_label!(builder, "HiZBuilder", LABEL_COLOR_COMPUTE);
+			builder.dispatch(
+				[width.div_ceil(8), height.div_ceil(8), 1],
+				self.pipeline.clone(),
+				set,
+				(),
+				vec![],
+			)?;
+			debug_label!(builder);
+		}
+		Ok(())
+	}
+}
+
+fn level_dimensions(dimensions: [u32; 2], level: usize) -> [u32; 2] {
+	[(dimensions[0] >> level).max(1), (dimensions[1] >> level).max(1)]
+}
+
+fn levels(device: &Arc<Device>, dimensions: [u32; 2]) -> Result<Vec<Arc<StorageImage<Format>>>, VulkanoError> {
+	// ceil(log2(max(w, h))), at least the copy of the depth buffer
+	let largest = dimensions[0].max(dimensions[1]).max(1);
+	let count = ((32 - (largest - 1).leading_zeros()) as usize).clamp(1, HI_Z_MAX_LEVELS);
+	(0..count)
+		.map(|level| {
+			let [width, height] = level_dimensions(dimensions, level);
+			let image = StorageImage::with_usage(
+				device.clone(),
+				ImageDimensions::Dim2d {
+					width,
+					height,
+					array_layers: 1,
+				},
+				HI_Z_FORMAT,
+				ImageUsage {
+					storage: true,
+					sampled: true,
+					..ImageUsage::none()
+				},
+				ImageCreateFlags::none(),
+				device.active_queue_families(),
+			)?;
+			Ok(image)
+		})
+		.collect()
+}
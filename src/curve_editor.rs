@@ -0,0 +1,493 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::{BufferAccess, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::device::Device;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::pipeline::GraphicsPipeline;
+
+use winit::event::{ElementState, MouseButton, WindowEvent};
+
+use crate::animation::AnimationCurve;
+use crate::error::VulkanoError;
+use crate::pipeline::SharedPipeline;
+
+mod overlay_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 position;
+			layout(location = 1) in vec4 color;
+
+			layout(location = 0) out vec4 v_color;
+
+			void main() {
+				v_color = color;
+				gl_Position = vec4(position, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod overlay_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec4 v_color;
+
+			layout(location = 0) out vec4 f_color;
+
+			void main() {
+				f_color = v_color;
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct OverlayVertex {
+	position: [f32; 2],
+	color: [f32; 4],
+}
+vulkano::impl_vertex!(OverlayVertex, position, color);
+
+// Draws flat colored triangles given in normalized device coordinates, alpha
+// blended over the scene
+pub struct OverlayRenderer {
+	pipeline: SharedPipeline,
+	vertex_pool: CpuBufferPool<OverlayVertex>,
+}
+
+impl OverlayRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<OverlayRenderer, VulkanoError> {
+		let vs = overlay_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = overlay_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<OverlayVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.blend_alpha_blending()
+			.render_pass(subpass)
+			.build(device.clone())?;
+
+		Ok(OverlayRenderer {
+			pipeline: Arc::new(pipeline),
+			vertex_pool: CpuBufferPool::vertex_buffer(device),
+		})
+	}
+
+	// Records the triangles inside the current render pass
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		vertices: &[OverlayVertex],
+	) -> Result<(), VulkanoError> {
+		if vertices.is_empty() {
+			return Ok(());
+		}
+
+		let vertices = self.vertex_pool.chunk(vertices.iter().copied())?;
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![Arc::new(vertices) as Arc<dyn BufferAccess + Send + Sync>],
+			(),
+			(),
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+pub const CURVE_SAVE_PATH: &str = "animation_curve.toml";
+
+// Rectangles as left, top, right, bottom in normalized device coordinates
+const PANEL_BOUNDS: [f32; 4] = [-0.95, 0.4, 0.95, 0.95];
+const SAVE_BUTTON_BOUNDS: [f32; 4] = [0.82, 0.42, 0.93, 0.5];
+
+// Half size of the control point squares, and distance of the tangent handles
+// from their keyframe
+const HANDLE_SIZE: f32 = 0.015;
+const TANGENT_HANDLE_LENGTH: f32 = 0.12;
+const CURVE_SEGMENTS: usize = 128;
+const LINE_THICKNESS: f32 = 0.006;
+
+const BACKGROUND_COLOR: [f32; 4] = [0.05, 0.05, 0.08, 0.8];
+const AXIS_COLOR: [f32; 4] = [0.4, 0.4, 0.45, 1.0];
+const CURVE_COLOR: [f32; 4] = [1.0, 0.8, 0.2, 1.0];
+const KEY_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const TANGENT_COLOR: [f32; 4] = [0.3, 0.7, 1.0, 1.0];
+const ACTIVE_COLOR: [f32; 4] = [1.0, 0.3, 0.3, 1.0];
+const SAVE_BUTTON_COLOR: [f32; 4] = [0.2, 0.6, 0.3, 1.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handle {
+	Key(usize),
+	TangentIn(usize),
+	TangentOut(usize),
+}
+
+// Maps the curve space, time horizontally and value vertically, to the panel
+#[derive(Debug, Clone, Copy)]
+struct CurveView {
+	time: (f32, f32),
+	value: (f32, f32),
+}
+
+impl CurveView {
+	// Everything the keyframes and their tangent handles cover, with a margin
+	fn fit(curve: &AnimationCurve<f32>) -> CurveView {
+		let keyframes = curve.keyframes();
+		let mut time = (keyframes[0].time, keyframes[keyframes.len() - 1].time);
+		if time.1 - time.0 < 1e-3 {
+			time.1 = time.0 + 1.0;
+		}
+
+		let mut value = (f32::MAX, f32::MIN);
+		for i in 0..=CURVE_SEGMENTS {
+			let t = time.0 + (time.1 - time.0) * i as f32 / CURVE_SEGMENTS as f32;
+			let sample = curve.sample(t);
+			value = (value.0.min(sample), value.1.max(sample));
+		}
+		for keyframe in keyframes {
+			value = (value.0.min(keyframe.value), value.1.max(keyframe.value));
+		}
+		if value.1 - value.0 < 1e-3 {
+			value = (value.0 - 0.5, value.1 + 0.5);
+		}
+
+		let time_margin = (time.1 - time.0) * 0.05;
+		let value_margin = (value.1 - value.0) * 0.15;
+		CurveView {
+			time: (time.0 - time_margin, time.1 + time_margin),
+			value: (value.0 - value_margin, value.1 + value_margin),
+		}
+	}
+
+	// Panel units per curve unit, on each axis
+	fn scale(&self) -> [f32; 2] {
+		let [left, top, right, bottom] = PANEL_BOUNDS;
+		[
+			(right - left) / (self.time.1 - self.time.0),
+			(bottom - top) / (self.value.1 - self.value.0),
+		]
+	}
+
+	fn screen_position(self, time: f32, value: f32) -> [f32; 2] {
+		let [left, _, _, bottom] = PANEL_BOUNDS;
+		let [sx, sy] = self.scale();
+		// Y points down in NDC
+		[left + (time - self.time.0) * sx, bottom - (value - self.value.0) * sy]
+	}
+
+	fn curve_position(self, position: [f32; 2]) -> (f32, f32) {
+		let [left, _, _, bottom] = PANEL_BOUNDS;
+		let [sx, sy] = self.scale();
+		(self.time.0 + (position[0] - left) / sx, self.value.0 + (bottom - position[1]) / sy)
+	}
+
+	// Offset of a tangent handle from its keyframe for a slope in value units per second
+	fn tangent_offset(&self, slope: f32) -> [f32; 2] {
+		let [sx, sy] = self.scale();
+		let direction = [sx, -slope * sy];
+		let length = (direction[0] * direction[0] + direction[1] * direction[1]).sqrt();
+		[
+			direction[0] / length * TANGENT_HANDLE_LENGTH,
+			direction[1] / length * TANGENT_HANDLE_LENGTH,
+		]
+	}
+
+	// Slope of the tangent pointing along `offset`, which must point forward in time
+	fn slope(&self, offset: [f32; 2]) -> f32 {
+		let [sx, sy] = self.scale();
+		(-offset[1] / sy) / (offset[0].max(1e-4) / sx)
+	}
+}
+
+fn contains(bounds: [f32; 4], position: [f32; 2]) -> bool {
+	let [left, top, right, bottom] = bounds;
+	(left..=right).contains(&position[0]) && (top..=bottom).contains(&position[1])
+}
+
+fn push_rect(vertices: &mut Vec<OverlayVertex>, bounds: [f32; 4], color: [f32; 4]) {
+	let [left, top, right, bottom] = bounds;
+	let vertex = |x, y| OverlayVertex { position: [x, y], color };
+	vertices.extend_from_slice(&[
+		vertex(left, top),
+		vertex(right, top),
+		vertex(left, bottom),
+		vertex(right, top),
+		vertex(right, bottom),
+		vertex(left, bottom),
+	]);
+}
+
+fn push_square(vertices: &mut Vec<OverlayVertex>, center: [f32; 2], color: [f32; 4]) {
+	push_rect(
+		vertices,
+		[
+			center[0] - HANDLE_SIZE,
+			center[1] - HANDLE_SIZE,
+			center[0] + HANDLE_SIZE,
+			center[1] + HANDLE_SIZE,
+		],
+		color,
+	);
+}
+
+fn push_line(vertices: &mut Vec<OverlayVertex>, a: [f32; 2], b: [f32; 2], color: [f32; 4]) {
+	let direction = [b[0] - a[0], b[1] - a[1]];
+	let length = (direction[0] * direction[0] + direction[1] * direction[1]).sqrt();
+	if length == 0.0 {
+		return;
+	}
+	let half = LINE_THICKNESS * 0.5 / length;
+	let normal = [-direction[1] * half, direction[0] * half];
+	let vertex = |p: [f32; 2], sign: f32| OverlayVertex {
+		position: [p[0] + normal[0] * sign, p[1] + normal[1] * sign],
+		color,
+	};
+	vertices.extend_from_slice(&[
+		vertex(a, 1.0),
+		vertex(b, 1.0),
+		vertex(a, -1.0),
+		vertex(b, 1.0),
+		vertex(b, -1.0),
+		vertex(a, -1.0),
+	]);
+}
+
+// Writes the keyframes as an array of tables
+pub fn save_curve(curve: &AnimationCurve<f32>, path: &Path) -> Result<(), VulkanoError> {
+	let mut toml = String::new();
+	// Writing to a String can't fail
+	let _ = writeln!(toml, "loop_mode = \"{:?}\"", curve.loop_mode);
+	for keyframe in curve.keyframes() {
+		let _ = writeln!(toml, "\n[[keyframes]]");
+		let _ = writeln!(toml, "time = {:?}", keyframe.time);
+		let _ = writeln!(toml, "value = {:?}", keyframe.value);
+		let _ = writeln!(toml, "tangent_in = {:?}", keyframe.tangent_in);
+		let _ = writeln!(toml, "tangent_out = {:?}", keyframe.tangent_out);
+	}
+	std::fs::write(path, toml)?;
+	Ok(())
+}
+
+// Overlay plotting an animation curve, shown with `visible`. The keyframes
+// and their tangent handles can be dragged with the left mouse button, the
+// curve is modified in place so the edits show up on the next frame. The
+// green button saves the curve to `CURVE_SAVE_PATH`.
+// The overlay has no text, there is no font atlas loaded by the renderer.
+pub struct CurveEditorPanel {
+	pub visible: bool,
+	// In normalized device coordinates
+	cursor: [f32; 2],
+	dragging: Option<Handle>,
+	// The view doesn't follow the curve while dragging, the handle would move
+	// away from the cursor
+	frozen_view: Option<CurveView>,
+}
+
+impl Default for CurveEditorPanel {
+	fn default() -> CurveEditorPanel {
+		CurveEditorPanel::new()
+	}
+}
+
+impl CurveEditorPanel {
+	pub fn new() -> CurveEditorPanel {
+		CurveEditorPanel {
+			visible: false,
+			cursor: [0.0, 0.0],
+			dragging: None,
+			frozen_view: None,
+		}
+	}
+
+	fn view(&self, curve: &AnimationCurve<f32>) -> CurveView {
+		self.frozen_view.unwrap_or_else(|| CurveView::fit(curve))
+	}
+
+	// Returns true when the panel used the event
+	pub fn handle_event(
+		&mut self,
+		event: &WindowEvent,
+		window_size: [u32; 2],
+		curve: &mut AnimationCurve<f32>,
+	) -> bool {
+		if !self.visible {
+			return false;
+		}
+
+		match event {
+			WindowEvent::CursorMoved { position, .. } => {
+				self.cursor = [
+					position.x as f32 / window_size[0].max(1) as f32 * 2.0 - 1.0,
+					position.y as f32 / window_size[1].max(1) as f32 * 2.0 - 1.0,
+				];
+				match self.dragging {
+					Some(handle) => {
+						self.drag(handle, curve);
+						true
+					}
+					None => false,
+				}
+			}
+			WindowEvent::MouseInput {
+				state: ElementState::Pressed,
+				button: MouseButton::Left,
+				..
+			} => {
+				if contains(SAVE_BUTTON_BOUNDS, self.cursor) {
+					match save_curve(curve, Path::new(CURVE_SAVE_PATH)) {
+						Ok(()) => info!("Saved the animation curve to {}", CURVE_SAVE_PATH),
+						Err(e) => error!("Failed to save the animation curve: {}", e),
+					}
+					return true;
+				}
+
+				let view = self.view(curve);
+				self.dragging = self.handle_at(&view, curve, self.cursor);
+				if self.dragging.is_some() {
+					self.frozen_view = Some(view);
+				}
+				contains(PANEL_BOUNDS, self.cursor)
+			}
+			WindowEvent::MouseInput {
+				state: ElementState::Released,
+				button: MouseButton::Left,
+				..
+			} => {
+				self.frozen_view = None;
+				self.dragging.take().is_some()
+			}
+			_ => false,
+		}
+	}
+
+	fn handle_positions(view: &CurveView, curve: &AnimationCurve<f32>, index: usize) -> [[f32; 2]; 3] {
+		let keyframe = &curve.keyframes()[index];
+		let key = view.screen_position(keyframe.time, keyframe.value);
+		let tangent_in = view.tangent_offset(keyframe.tangent_in);
+		let tangent_out = view.tangent_offset(keyframe.tangent_out);
+		[
+			key,
+			[key[0] - tangent_in[0], key[1] - tangent_in[1]],
+			[key[0] + tangent_out[0], key[1] + tangent_out[1]],
+		]
+	}
+
+	fn handle_at(&self, view: &CurveView, curve: &AnimationCurve<f32>, position: [f32; 2]) -> Option<Handle> {
+		let hit = |center: [f32; 2]| {
+			(center[0] - position[0]).abs() <= HANDLE_SIZE && (center[1] - position[1]).abs() <= HANDLE_SIZE
+		};
+		// Keyframes first, a steep tangent handle can end up over its keyframe
+		(0..curve.keyframes().len())
+			.find(|&i| hit(CurveEditorPanel::handle_positions(view, curve, i)[0]))
+			.map(Handle::Key)
+			.or_else(|| {
+				(0..curve.keyframes().len()).find_map(|i| {
+					let [_, tangent_in, tangent_out] = CurveEditorPanel::handle_positions(view, curve, i);
+					if hit(tangent_in) {
+						Some(Handle::TangentIn(i))
+					} else if hit(tangent_out) {
+						Some(Handle::TangentOut(i))
+					} else {
+						None
+					}
+				})
+			})
+	}
+
+	fn drag(&self, handle: Handle, curve: &mut AnimationCurve<f32>) {
+		let view = self.view(curve);
+		match handle {
+			Handle::Key(i) => {
+				let (time, value) = view.curve_position(self.cursor);
+				let keyframes = curve.keyframes_mut();
+				// Keyframes can't cross their neighbours, they stay sorted
+				let min = if i > 0 { keyframes[i - 1].time } else { f32::MIN };
+				let max = keyframes.get(i + 1).map_or(f32::MAX, |next| next.time);
+				keyframes[i].time = time.max(min).min(max);
+				keyframes[i].value = value;
+			}
+			Handle::TangentIn(i) => {
+				let key = CurveEditorPanel::handle_positions(&view, curve, i)[0];
+				let slope = view.slope([key[0] - self.cursor[0], key[1] - self.cursor[1]]);
+				curve.keyframes_mut()[i].tangent_in = slope;
+			}
+			Handle::TangentOut(i) => {
+				let key = CurveEditorPanel::handle_positions(&view, curve, i)[0];
+				let slope = view.slope([self.cursor[0] - key[0], self.cursor[1] - key[1]]);
+				curve.keyframes_mut()[i].tangent_out = slope;
+			}
+		}
+	}
+
+	// Triangles of the panel for the current state of `curve`, empty while hidden
+	pub fn vertices(&self, curve: &AnimationCurve<f32>) -> Vec<OverlayVertex> {
+		let mut vertices = Vec::new();
+		if !self.visible {
+			return vertices;
+		}
+		let view = self.view(curve);
+		let [left, top, right, bottom] = PANEL_BOUNDS;
+
+		push_rect(&mut vertices, PANEL_BOUNDS, BACKGROUND_COLOR);
+		let zero = view.screen_position(0.0, 0.0);
+		if (top..=bottom).contains(&zero[1]) {
+			push_line(&mut vertices, [left, zero[1]], [right, zero[1]], AXIS_COLOR);
+		}
+		if (left..=right).contains(&zero[0]) {
+			push_line(&mut vertices, [zero[0], top], [zero[0], bottom], AXIS_COLOR);
+		}
+
+		let keyframes = curve.keyframes();
+		let (start, end) = (keyframes[0].time, keyframes[keyframes.len() - 1].time);
+		let points: Vec<[f32; 2]> = (0..=CURVE_SEGMENTS)
+			.map(|i| {
+				let t = start + (end - start) * i as f32 / CURVE_SEGMENTS as f32;
+				view.screen_position(t, curve.sample(t))
+			})
+			.collect();
+		for segment in points.windows(2) {
+			push_line(&mut vertices, segment[0], segment[1], CURVE_COLOR);
+		}
+
+		let color = |handle| {
+			if self.dragging == Some(handle) {
+				ACTIVE_COLOR
+			} else {
+				match handle {
+					Handle::Key(_) => KEY_COLOR,
+					_ => TANGENT_COLOR,
+				}
+			}
+		};
+		for i in 0..keyframes.len() {
+			let [key, tangent_in, tangent_out] = CurveEditorPanel::handle_positions(&view, curve, i);
+			push_line(&mut vertices, tangent_in, tangent_out, TANGENT_COLOR);
+			push_square(&mut vertices, tangent_in, color(Handle::TangentIn(i)));
+			push_square(&mut vertices, tangent_out, color(Handle::TangentOut(i)));
+			push_square(&mut vertices, key, color(Handle::Key(i)));
+		}
+
+		push_rect(&mut vertices, SAVE_BUTTON_BOUNDS, SAVE_BUTTON_COLOR);
+		vertices
+	}
+}
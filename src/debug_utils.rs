@@ -0,0 +1,591 @@
+use std::ffi::{CStr, CString};
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+#[cfg(debug_assertions)]
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+#[cfg(debug_assertions)]
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, DeviceOwned};
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, RenderPassDesc};
+use vulkano::image::ImageLayout;
+use vulkano::instance::debug::{DebugCallback, MessageSeverity, MessageType};
+use vulkano::instance::Instance;
+#[cfg(debug_assertions)]
+use vulkano::pipeline::{ComputePipeline, ComputePipelineAbstract};
+use vulkano::{VulkanHandle, VulkanObject};
+
+use crate::error::VulkanoError;
+
+// Render pass compatibility as Vulkan defines it: the attachments have the same
+// formats and sample counts, and every subpass references compatible
+// attachments. The layouts and the load / store ops don't matter.
+pub struct RenderPassCompatibilityCheck;
+
+impl RenderPassCompatibilityCheck {
+	// Panics in debug builds if a pipeline created for `pipeline_pass` can't be used
+	// inside a framebuffer created for `framebuffer_pass`
+	pub fn assert_compatible(
+		pipeline_pass: &dyn RenderPassAbstract,
+		framebuffer_pass: &dyn RenderPassAbstract,
+	) {
+		if !cfg!(debug_assertions) {
+			return;
+		}
+
+		if let Err(reason) = Self::compare(pipeline_pass, framebuffer_pass) {
+			panic!(
+				"Pipeline render pass is not compatible with the framebuffer render pass: {}",
+				reason
+			);
+		}
+	}
+
+	pub fn compare<A, B>(pipeline_pass: &A, framebuffer_pass: &B) -> Result<(), String>
+	where
+		A: ?Sized + RenderPassDesc,
+		B: ?Sized + RenderPassDesc,
+	{
+		if pipeline_pass.num_attachments() != framebuffer_pass.num_attachments() {
+			return Err(format!(
+				"attachment count differs ({} vs {})",
+				pipeline_pass.num_attachments(),
+				framebuffer_pass.num_attachments()
+			));
+		}
+
+		for num in 0..pipeline_pass.num_attachments() {
+			let (a, b) = match (pipeline_pass.attachment_desc(num), framebuffer_pass.attachment_desc(num)) {
+				(Some(a), Some(b)) => (a, b),
+				_ => return Err(format!("attachment {} has no description", num)),
+			};
+			if a.format != b.format {
+				return Err(format!(
+					"attachment {} format differs ({:?} vs {:?})",
+					num, a.format, b.format
+				));
+			}
+			if a.samples != b.samples {
+				return Err(format!(
+					"attachment {} sample count differs ({} vs {})",
+					num, a.samples, b.samples
+				));
+			}
+		}
+
+		if pipeline_pass.num_subpasses() != framebuffer_pass.num_subpasses() {
+			return Err(format!(
+				"subpass count differs ({} vs {})",
+				pipeline_pass.num_subpasses(),
+				framebuffer_pass.num_subpasses()
+			));
+		}
+
+		for num in 0..pipeline_pass.num_subpasses() {
+			let (a, b) = match (pipeline_pass.subpass_desc(num), framebuffer_pass.subpass_desc(num)) {
+				(Some(a), Some(b)) => (a, b),
+				_ => return Err(format!("subpass {} has no description", num)),
+			};
+			if referenced(pipeline_pass, &a.color_attachments) != referenced(framebuffer_pass, &b.color_attachments) {
+				return Err(format!(
+					"subpass {} color attachments differ ({:?} vs {:?})",
+					num, a.color_attachments, b.color_attachments
+				));
+			}
+			let depth_stencil_a = referenced(pipeline_pass, a.depth_stencil.as_slice());
+			let depth_stencil_b = referenced(framebuffer_pass, b.depth_stencil.as_slice());
+			if depth_stencil_a != depth_stencil_b {
+				return Err(format!(
+					"subpass {} depth/stencil attachment differs ({:?} vs {:?})",
+					num, a.depth_stencil, b.depth_stencil
+				));
+			}
+			if referenced(pipeline_pass, &a.input_attachments) != referenced(framebuffer_pass, &b.input_attachments) {
+				return Err(format!(
+					"subpass {} input attachments differ ({:?} vs {:?})",
+					num, a.input_attachments, b.input_attachments
+				));
+			}
+			if referenced(pipeline_pass, &a.resolve_attachments) != referenced(framebuffer_pass, &b.resolve_attachments) {
+				return Err(format!(
+					"subpass {} resolve attachments differ ({:?} vs {:?})",
+					num, a.resolve_attachments, b.resolve_attachments
+				));
+			}
+		}
+
+		Ok(())
+	}
+}
+
+// Format and sample count of the attachments a subpass references. References
+// are compatible when they point to compatible attachments, whatever their
+// index and layout.
+fn referenced<P>(pass: &P, references: &[(usize, ImageLayout)]) -> Vec<Option<(Format, u32)>>
+where
+	P: ?Sized + RenderPassDesc,
+{
+	references
+		.iter()
+		.map(|&(attachment, _)| {
+			pass.attachment_desc(attachment)
+				.map(|desc| (desc.format, desc.samples))
+		})
+		.collect()
+}
+
+// Gives Vulkan objects readable names in RenderDoc / Nsight captures.
+// Does nothing when VK_EXT_debug_utils isn't enabled on the instance.
+pub struct DebugNameRegistry {
+	device: Arc<Device>,
+	enabled: bool,
+}
+
+impl DebugNameRegistry {
+	pub fn new(device: Arc<Device>) -> DebugNameRegistry {
+		let enabled = device.instance().loaded_extensions().ext_debug_utils;
+		if !enabled {
+			debug!("VK_EXT_debug_utils unavailable, Vulkan objects won't be named");
+		}
+
+		DebugNameRegistry { device, enabled }
+	}
+
+	// `object` must belong to the device of the registry. Not every vulkano
+	// object is DeviceOwned (images, render passes), so it isn't checked.
+	pub fn name<T>(&self, object: &T, name: &str)
+	where
+		T: VulkanObject,
+	{
+		if !self.enabled {
+			return;
+		}
+
+		let name = match CString::new(name) {
+			Ok(name) => name,
+			Err(e) => {
+				warn!("Failed to name {:?}: {}", name, e);
+				return;
+			}
+		};
+		// Safe, T::TYPE is the type of the handle
+		let named = unsafe { self.device.set_object_name_raw(T::TYPE, object.internal_object().value(), &name) };
+		if let Err(e) = named {
+			warn!("Failed to name {:?}: {:?}", name, e);
+		}
+	}
+
+	// Names the object `<prefix>_<index>`, eg. "framebuffer_0"
+	pub fn name_indexed<T>(&self, object: &T, prefix: &str, index: usize)
+	where
+		T: VulkanObject,
+	{
+		if self.enabled {
+			self.name(object, &format!("{}_{}", prefix, index));
+		}
+	}
+}
+
+// Label colors of the render passes and the compute dispatches in captures
+pub const LABEL_COLOR_RENDER_PASS: [f32; 4] = [0.2, 0.6, 1.0, 1.0];
+pub const LABEL_COLOR_COMPUTE: [f32; 4] = [1.0, 0.6, 0.1, 1.0];
+
+// Opens a VK_EXT_debug_utils label, shown in RenderDoc / Nsight captures as a
+// group of the commands recorded until `debug_label!(builder)` closes it:
+//
+//	debug_label!(builder, "bloom", LABEL_COLOR_COMPUTE);
+//	builder.dispatch(...)?;
+//	debug_label!(builder);
+//
+// The name must be a string literal without nul bytes, checked at compile
+// time, vulkano only takes 'static names, and the color an opaque RGBA array.
+// The labels are only recorded in debug builds, release builds don't evaluate
+// the arguments.
+macro_rules! debug_label {
+	($builder:expr, $name:literal, $color:expr) => {
+		#[cfg(debug_assertions)]
+		{
+			use $crate::debug_utils::DebugLabels as _;
+			const NAME: &[u8] = concat!($name, "\0").as_bytes();
+			const _: () = assert!(!$crate::debug_utils::has_interior_nul(NAME), "debug label names can't contain a nul byte");
+			if let Ok(name) = ::std::ffi::CStr::from_bytes_with_nul(NAME) {
+				$builder.begin_debug_label(name, $color);
+			}
+		}
+		// Keeps the color's imports used without evaluating it
+		#[cfg(not(debug_assertions))]
+		let _ = || $color;
+	};
+	($builder:expr) => {
+		#[cfg(debug_assertions)]
+		{
+			use $crate::debug_utils::DebugLabels as _;
+			$builder.end_debug_label();
+		}
+	};
+}
+
+// Whether a nul terminated string has a nul before its last byte
+pub const fn has_interior_nul(bytes: &[u8]) -> bool {
+	let mut i = 0;
+	while i + 1 < bytes.len() {
+		if bytes[i] == 0 {
+			return true;
+		}
+		i += 1;
+	}
+	false
+}
+
+// Used by `debug_label!`. The labels are skipped without VK_EXT_debug_utils,
+// and when vulkano refuses them, on a compute only queue for example: a
+// missing label isn't worth failing the frame for.
+pub trait DebugLabels {
+	fn begin_debug_label(&mut self, name: &'static CStr, color: [f32; 4]);
+	fn end_debug_label(&mut self);
+}
+
+impl DebugLabels for AutoCommandBufferBuilder {
+	fn begin_debug_label(&mut self, name: &'static CStr, color: [f32; 4]) {
+		if !self.device().instance().loaded_extensions().ext_debug_utils {
+			return;
+		}
+		if let Err(e) = self.debug_marker_begin(name, color) {
+			debug!("Skipping the debug label {:?}: {}", name, e);
+		}
+	}
+
+	fn end_debug_label(&mut self) {
+		if !self.device().instance().loaded_extensions().ext_debug_utils {
+			return;
+		}
+		if let Err(e) = self.debug_marker_end() {
+			debug!("Skipping the end of a debug label: {}", e);
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Breadcrumb {
+	FrameStart = 1,
+	BeforeRenderPass,
+	AfterRenderPass,
+	FrameEnd,
+}
+
+impl Breadcrumb {
+	fn from_u32(value: u32) -> Option<Breadcrumb> {
+		match value {
+			1 => Some(Breadcrumb::FrameStart),
+			2 => Some(Breadcrumb::BeforeRenderPass),
+			3 => Some(Breadcrumb::AfterRenderPass),
+			4 => Some(Breadcrumb::FrameEnd),
+			_ => None,
+		}
+	}
+}
+
+// Leaves a trail of markers in a host visible buffer as the GPU executes the
+// command buffer, so that the last one reached can be read back after a
+// device loss.
+// vulkano doesn't expose VK_AMD_buffer_marker nor VK_NV_device_diagnostic_checkpoints,
+// so the markers are written with `update_buffer`. Transfer commands can't be
+// recorded inside a render pass, the finest granularity is around render passes.
+pub struct CrashBreadcrumb {
+	buffer: Arc<CpuAccessibleBuffer<[u32; 2]>>,
+	frame: u32,
+}
+
+impl CrashBreadcrumb {
+	pub fn new(device: Arc<Device>) -> Result<CrashBreadcrumb, VulkanoError> {
+		let usage = BufferUsage {
+			transfer_destination: true,
+			..BufferUsage::none()
+		};
+		let buffer = CpuAccessibleBuffer::from_data(device, usage, false, [0u32; 2])?;
+
+		Ok(CrashBreadcrumb { buffer, frame: 0 })
+	}
+
+	pub fn begin_frame(&mut self, builder: &mut AutoCommandBufferBuilder) -> Result<(), VulkanoError> {
+		self.frame = self.frame.wrapping_add(1);
+		self.mark(builder, Breadcrumb::FrameStart)
+	}
+
+	// Must be recorded outside of a render pass
+	pub fn mark(&self, builder: &mut AutoCommandBufferBuilder, breadcrumb: Breadcrumb) -> Result<(), VulkanoError> {
+		if cfg!(debug_assertions) {
+			builder.update_buffer(self.buffer.clone(), Box::new([self.frame, breadcrumb as u32]))?;
+		}
+		Ok(())
+	}
+
+	// Logs the last marker the GPU went through, call after a DeviceLost error
+	pub fn report(&self) {
+		match self.buffer.read() {
+			Ok(content) => {
+				let [frame, breadcrumb] = *content;
+				error!(
+					"Last GPU breadcrumb: {:?} of frame {} (CPU was recording frame {})",
+					Breadcrumb::from_u32(breadcrumb),
+					frame,
+					self.frame
+				);
+			}
+			Err(e) => error!("Failed to read back GPU breadcrumbs: {:?}", e),
+		}
+	}
+}
+
+#[cfg(debug_assertions)]
+mod hang_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) buffer Counter {
+				uint value;
+			} counter;
+
+			layout(push_constant) uniform PushConstants {
+				uint target;
+			} pc;
+
+			void main() {
+				// The counter starts at 0 and stays even, an odd target is never
+				// reached, the compiler can't prove it and remove the loop
+				while (counter.value != pc.target) {
+					counter.value += 2;
+				}
+			}
+		"
+	}
+}
+
+// Records a dispatch that never finishes, for checking the recovery from a
+// device loss by hand: the driver's GPU timeout resets the GPU and the next
+// fence wait or submission returns VK_ERROR_DEVICE_LOST.
+// Debug builds only, a release build can't be made to hang on purpose.
+#[cfg(debug_assertions)]
+pub struct GpuHang {
+	pipeline: Arc<dyn ComputePipelineAbstract + Send + Sync>,
+	counter: Arc<CpuAccessibleBuffer<u32>>,
+}
+
+#[cfg(debug_assertions)]
+impl GpuHang {
+	pub fn new(device: Arc<Device>) -> Result<GpuHang, VulkanoError> {
+		let shader = hang_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let usage = BufferUsage {
+			storage_buffer: true,
+			..BufferUsage::none()
+		};
+		let counter = CpuAccessibleBuffer::from_data(device.clone(), usage, false, 0u32)?;
+		Ok(GpuHang {
+			pipeline: Arc::new(ComputePipeline::new(device, &shader.main_entry_point(), &(), None)?),
+			counter,
+		})
+	}
+
+	pub fn record(&self, builder: &mut AutoCommandBufferBuilder) -> Result<(), VulkanoError> {
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(self.counter.clone())?
+				.build()?,
+		);
+		warn!("Recording a dispatch that hangs the GPU");
+		builder.dispatch([1, 1, 1], self.pipeline.clone(), set, hang_cs::ty::PushConstants { target: 1 }, vec![])?;
+		Ok(())
+	}
+}
+
+// Prefix of the DEBUG_PRINT messages of debug.glsl
+const PRINTF_PREFIX: &str = "[Printf]";
+
+// Forwards the validation layer messages to the log, the debugPrintfEXT output
+// of the shaders being logged at info level under the `shader` target. The
+// layer only prints when its debug printf is enabled, for example with
+// VK_LAYER_ENABLES=VK_VALIDATION_FEATURE_ENABLE_DEBUG_PRINTF_EXT.
+// Does nothing without the validation feature.
+pub struct ShaderDebugPrintf {
+	_callback: Option<DebugCallback>,
+}
+
+impl ShaderDebugPrintf {
+	pub fn new(instance: &Arc<Instance>) -> Result<ShaderDebugPrintf, VulkanoError> {
+		if !cfg!(feature = "validation") || !instance.loaded_extensions().ext_debug_utils {
+			return Ok(ShaderDebugPrintf { _callback: None });
+		}
+
+		let severity = MessageSeverity {
+			information: true,
+			..MessageSeverity::errors_and_warnings()
+		};
+		let callback = DebugCallback::new(instance, severity, MessageType::all(), |message| {
+			if message.severity.information {
+				if let Some(start) = message.description.find(PRINTF_PREFIX) {
+					let printed = message.description[start + PRINTF_PREFIX.len()..].trim();
+					info!(target: "shader", "{}", printed);
+				}
+			} else if message.severity.error {
+				error!("[{}] {}", message.layer_prefix.unwrap_or("unknown"), message.description);
+			} else {
+				warn!("[{}] {}", message.layer_prefix.unwrap_or("unknown"), message.description);
+			}
+		})?;
+		Ok(ShaderDebugPrintf {
+			_callback: Some(callback),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use vulkano::format::ClearValue;
+	use vulkano::framebuffer::{
+		AttachmentDescription,
+		LoadOp,
+		PassDependencyDescription,
+		PassDescription,
+		RenderPassDescClearValues,
+		StoreOp,
+	};
+
+	struct TestPass {
+		attachments: Vec<AttachmentDescription>,
+		subpasses: Vec<PassDescription>,
+	}
+
+	unsafe impl RenderPassDesc for TestPass {
+		fn num_attachments(&self) -> usize {
+			self.attachments.len()
+		}
+
+		fn attachment_desc(&self, num: usize) -> Option<AttachmentDescription> {
+			self.attachments.get(num).cloned()
+		}
+
+		fn num_subpasses(&self) -> usize {
+			self.subpasses.len()
+		}
+
+		fn subpass_desc(&self, num: usize) -> Option<PassDescription> {
+			self.subpasses.get(num).cloned()
+		}
+
+		fn num_dependencies(&self) -> usize {
+			0
+		}
+
+		fn dependency_desc(&self, _num: usize) -> Option<PassDependencyDescription> {
+			None
+		}
+	}
+
+	unsafe impl RenderPassDescClearValues<Vec<ClearValue>> for TestPass {
+		fn convert_clear_values(&self, values: Vec<ClearValue>) -> Box<dyn Iterator<Item = ClearValue>> {
+			Box::new(values.into_iter())
+		}
+	}
+
+	fn attachment(format: Format, samples: u32, load: LoadOp, final_layout: ImageLayout) -> AttachmentDescription {
+		AttachmentDescription {
+			format,
+			samples,
+			load,
+			store: StoreOp::Store,
+			stencil_load: LoadOp::DontCare,
+			stencil_store: StoreOp::DontCare,
+			initial_layout: ImageLayout::Undefined,
+			final_layout,
+		}
+	}
+
+	fn subpass(color: &[usize], depth_stencil: Option<usize>, layout: ImageLayout) -> PassDescription {
+		PassDescription {
+			color_attachments: color.iter().map(|&attachment| (attachment, layout)).collect(),
+			depth_stencil: depth_stencil.map(|attachment| (attachment, ImageLayout::DepthStencilAttachmentOptimal)),
+			input_attachments: Vec::new(),
+			resolve_attachments: Vec::new(),
+			preserve_attachments: Vec::new(),
+		}
+	}
+
+	// A color and a depth attachment drawn in one subpass
+	fn color_depth_pass(color_format: Format, samples: u32) -> TestPass {
+		TestPass {
+			attachments: vec![
+				attachment(color_format, samples, LoadOp::Clear, ImageLayout::PresentSrc),
+				attachment(Format::D32Sfloat, samples, LoadOp::Clear, ImageLayout::DepthStencilAttachmentOptimal),
+			],
+			subpasses: vec![subpass(&[0], Some(1), ImageLayout::ColorAttachmentOptimal)],
+		}
+	}
+
+	#[test]
+	fn identical_passes_are_compatible() {
+		let a = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		let b = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		assert_eq!(RenderPassCompatibilityCheck::compare(&a, &b), Ok(()));
+	}
+
+	#[test]
+	fn layouts_and_load_ops_dont_matter() {
+		let a = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		let mut b = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		b.attachments[0] = attachment(Format::B8G8R8A8Srgb, 1, LoadOp::Load, ImageLayout::ColorAttachmentOptimal);
+		b.subpasses[0] = subpass(&[0], Some(1), ImageLayout::General);
+		assert_eq!(RenderPassCompatibilityCheck::compare(&a, &b), Ok(()));
+	}
+
+	#[test]
+	fn attachment_format_and_samples_must_match() {
+		let a = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		assert!(RenderPassCompatibilityCheck::compare(&a, &color_depth_pass(Format::B8G8R8A8Unorm, 1)).is_err());
+		assert!(RenderPassCompatibilityCheck::compare(&a, &color_depth_pass(Format::B8G8R8A8Srgb, 4)).is_err());
+	}
+
+	#[test]
+	fn subpass_count_must_match() {
+		let a = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		let mut b = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		b.subpasses.push(subpass(&[0], None, ImageLayout::ColorAttachmentOptimal));
+		assert!(RenderPassCompatibilityCheck::compare(&a, &b).is_err());
+	}
+
+	#[test]
+	fn subpass_attachment_references_must_match() {
+		let a = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		let mut without_depth = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		without_depth.subpasses[0] = subpass(&[0], None, ImageLayout::ColorAttachmentOptimal);
+		assert!(RenderPassCompatibilityCheck::compare(&a, &without_depth).is_err());
+
+		let mut two_colors = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		two_colors.subpasses[0] = subpass(&[0, 0], Some(1), ImageLayout::ColorAttachmentOptimal);
+		assert!(RenderPassCompatibilityCheck::compare(&a, &two_colors).is_err());
+	}
+
+	#[test]
+	fn references_to_compatible_attachments_are_compatible() {
+		let mut a = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		a.attachments.push(attachment(Format::B8G8R8A8Srgb, 1, LoadOp::Clear, ImageLayout::PresentSrc));
+		let mut b = color_depth_pass(Format::B8G8R8A8Srgb, 1);
+		b.attachments.push(attachment(Format::B8G8R8A8Srgb, 1, LoadOp::Clear, ImageLayout::PresentSrc));
+		b.subpasses[0] = subpass(&[2], Some(1), ImageLayout::ColorAttachmentOptimal);
+		assert_eq!(RenderPassCompatibilityCheck::compare(&a, &b), Ok(()));
+	}
+}
@@ -0,0 +1,1135 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, ImmutableImage, MipmapsCount, StorageImage};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::glsl_shaders::fullscreen_vs;
+use crate::input::{Action, InputMapper};
+use crate::pipeline::{BufferlessPipeline, SharedComputePipeline, SharedPipeline};
+
+mod line_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec4 color;
+
+			layout(location = 0) out vec4 v_color;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+				// Towards the camera, in normalized depth units
+				float depth_bias;
+			} pc;
+
+			void main() {
+				v_color = color;
+				gl_Position = pc.view_projection * vec4(position, 1.0);
+				gl_Position.z -= pc.depth_bias * gl_Position.w;
+			}
+		"
+	}
+}
+
+mod line_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec4 v_color;
+
+			layout(location = 0) out vec4 f_color;
+
+			void main() {
+				f_color = v_color;
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct LineVertex {
+	position: [f32; 3],
+	color: [f32; 4],
+}
+vulkano::impl_vertex!(LineVertex, position, color);
+
+// Segments of the circles approximating a wire sphere
+pub const WIRE_SPHERE_SEGMENTS: usize = 16;
+
+// Batches colored world space lines during the frame and draws them in a
+// single call on `flush`. Lines ignore the depth buffer, debug geometry stays
+// visible behind the scene, unless created with `depth_tested`.
+pub struct LineRenderer {
+	pipeline: SharedPipeline,
+	vertex_pool: CpuBufferPool<LineVertex>,
+	vertices: Vec<LineVertex>,
+	// Moves the lines towards the camera, so that the depth tested lines
+	// drawn over a surface aren't hidden by it
+	pub depth_bias: f32,
+}
+
+impl LineRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<LineRenderer, VulkanoError> {
+		LineRenderer::with_depth(device, subpass, DepthStencil::disabled())
+	}
+
+	// Hidden by the scene when the subpass has a depth attachment
+	pub fn depth_tested(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<LineRenderer, VulkanoError> {
+		let depth_stencil = if subpass.has_depth() {
+			DepthStencil::simple_depth_test()
+		} else {
+			DepthStencil::disabled()
+		};
+		LineRenderer::with_depth(device, subpass, depth_stencil)
+	}
+
+	fn with_depth(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		depth_stencil: DepthStencil,
+	) -> Result<LineRenderer, VulkanoError> {
+		let vs = line_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = line_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<LineVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.line_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.depth_stencil(depth_stencil)
+			.blend_alpha_blending()
+			.render_pass(subpass)
+			.build(device.clone())?;
+
+		Ok(LineRenderer {
+			pipeline: Arc::new(pipeline),
+			vertex_pool: CpuBufferPool::vertex_buffer(device),
+			vertices: Vec::new(),
+			depth_bias: 0.0,
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.vertices.is_empty()
+	}
+
+	pub fn add_line(&mut self, start: [f32; 3], end: [f32; 3], color: [f32; 4]) {
+		self.vertices.push(LineVertex { position: start, color });
+		self.vertices.push(LineVertex { position: end, color });
+	}
+
+	// One circle around each axis
+	pub fn add_wire_sphere(&mut self, center: [f32; 3], radius: f32, color: [f32; 4]) {
+		let point = |axis: usize, angle: f32| {
+			let (sin, cos) = angle.sin_cos();
+			let mut point = center;
+			point[(axis + 1) % 3] += cos * radius;
+			point[(axis + 2) % 3] += sin * radius;
+			point
+		};
+		let step = std::f32::consts::PI * 2.0 / WIRE_SPHERE_SEGMENTS as f32;
+		for axis in 0..3 {
+			for i in 0..WIRE_SPHERE_SEGMENTS {
+				let start = point(axis, i as f32 * step);
+				let end = point(axis, (i + 1) as f32 * step);
+				self.add_line(start, end, color);
+			}
+		}
+	}
+
+	pub fn add_wire_aabb(&mut self, min: [f32; 3], max: [f32; 3], color: [f32; 4]) {
+		// Bit i of the index selects max over min on axis i
+		let corner = |index: usize| {
+			let mut corner = min;
+			for (axis, value) in corner.iter_mut().enumerate() {
+				if index & (1 << axis) != 0 {
+					*value = max[axis];
+				}
+			}
+			corner
+		};
+		for index in 0..8 {
+			for axis in 0..3 {
+				// Each edge once, from its corner closest to min
+				if index & (1 << axis) == 0 {
+					self.add_line(corner(index), corner(index | (1 << axis)), color);
+				}
+			}
+		}
+	}
+
+	// Records the lines added since the last flush inside the current render
+	// pass, then forgets them. Records nothing if no line was added.
+	pub fn flush(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		if self.vertices.is_empty() {
+			return Ok(());
+		}
+
+		let vertices = self.vertex_pool.chunk(self.vertices.drain(..))?;
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![Arc::new(vertices) as Arc<dyn BufferAccess + Send + Sync>],
+			(),
+			line_vs::ty::PushConstants {
+				view_projection,
+				depth_bias: self.depth_bias,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+// World space bounds of a scene object
+#[derive(Debug, Clone, Copy)]
+pub struct DebugBounds {
+	pub center: [f32; 3],
+	pub radius: f32,
+	pub min: [f32; 3],
+	pub max: [f32; 3],
+}
+
+impl DebugBounds {
+	// The sphere is centered on the box, not the tightest one but close enough to
+	// debug culling. `points` must not be empty.
+	pub fn from_points(points: &[[f32; 3]]) -> DebugBounds {
+		let mut min = points[0];
+		let mut max = points[0];
+		for point in points {
+			for axis in 0..3 {
+				min[axis] = min[axis].min(point[axis]);
+				max[axis] = max[axis].max(point[axis]);
+			}
+		}
+		let center = [(min[0] + max[0]) * 0.5, (min[1] + max[1]) * 0.5, (min[2] + max[2]) * 0.5];
+		let radius = points
+			.iter()
+			.map(|point| {
+				let (x, y, z) = (point[0] - center[0], point[1] - center[1], point[2] - center[2]);
+				(x * x + y * y + z * z).sqrt()
+			})
+			.fold(0.0, f32::max);
+		DebugBounds {
+			center,
+			radius,
+			min,
+			max,
+		}
+	}
+}
+
+const SPHERE_COLOR: [f32; 4] = [0.2, 1.0, 0.4, 1.0];
+const AABB_COLOR: [f32; 4] = [1.0, 0.6, 0.1, 1.0];
+
+// Draws the bounding sphere and box of each scene object, toggled by
+// Action::ToggleBounds
+pub struct DebugMeshOverlay {
+	enabled: bool,
+}
+
+impl Default for DebugMeshOverlay {
+	fn default() -> DebugMeshOverlay {
+		DebugMeshOverlay::new()
+	}
+}
+
+impl DebugMeshOverlay {
+	pub fn new() -> DebugMeshOverlay {
+		DebugMeshOverlay { enabled: false }
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	// Adds nothing to `lines` while disabled, so the line renderer doesn't
+	// write a buffer nor record a draw
+	pub fn add_bounds(&self, lines: &mut LineRenderer, objects: &[DebugBounds]) {
+		if !self.enabled {
+			return;
+		}
+		for bounds in objects {
+			lines.add_wire_sphere(bounds.center, bounds.radius, SPHERE_COLOR);
+			lines.add_wire_aabb(bounds.min, bounds.max, AABB_COLOR);
+		}
+	}
+}
+
+mod point_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec4 color;
+
+			layout(location = 0) out vec4 v_color;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+				float depth_bias;
+				// In pixels
+				float point_size;
+			} pc;
+
+			void main() {
+				v_color = color;
+				gl_Position = pc.view_projection * vec4(position, 1.0);
+				gl_Position.z -= pc.depth_bias * gl_Position.w;
+				gl_PointSize = pc.point_size;
+			}
+		"
+	}
+}
+
+mod point_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec4 v_color;
+
+			layout(location = 0) out vec4 f_color;
+
+			void main() {
+				// Round points rather than squares
+				if (length(gl_PointCoord - 0.5) > 0.5) {
+					discard;
+				}
+				f_color = v_color;
+			}
+		"
+	}
+}
+
+// Batches colored world space points during the frame and draws them in a
+// single call on `flush`, like LineRenderer. Points are hidden by the scene
+// when the subpass has a depth attachment.
+pub struct PointRenderer {
+	pipeline: SharedPipeline,
+	vertex_pool: CpuBufferPool<LineVertex>,
+	vertices: Vec<LineVertex>,
+	large_points: bool,
+	// Without the large_points feature points are always one pixel wide
+	pub point_size: f32,
+	// Moves the points towards the camera, like LineRenderer::depth_bias
+	pub depth_bias: f32,
+}
+
+impl PointRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<PointRenderer, VulkanoError> {
+		let vs = point_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = point_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let depth_stencil = if subpass.has_depth() {
+			DepthStencil::simple_depth_test()
+		} else {
+			DepthStencil::disabled()
+		};
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<LineVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.point_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.depth_stencil(depth_stencil)
+			.blend_alpha_blending()
+			.render_pass(subpass)
+			.build(device.clone())?;
+
+		Ok(PointRenderer {
+			pipeline: Arc::new(pipeline),
+			large_points: device.enabled_features().large_points,
+			vertex_pool: CpuBufferPool::vertex_buffer(device),
+			vertices: Vec::new(),
+			point_size: 6.0,
+			depth_bias: 0.0,
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.vertices.is_empty()
+	}
+
+	pub fn add_point(&mut self, position: [f32; 3], color: [f32; 4]) {
+		self.vertices.push(LineVertex { position, color });
+	}
+
+	// Records the points added since the last flush inside the current render
+	// pass, then forgets them. Records nothing if no point was added.
+	pub fn flush(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		if self.vertices.is_empty() {
+			return Ok(());
+		}
+
+		let vertices = self.vertex_pool.chunk(self.vertices.drain(..))?;
+		let point_size = if self.large_points { self.point_size } else { 1.0 };
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![Arc::new(vertices) as Arc<dyn BufferAccess + Send + Sync>],
+			(),
+			point_vs::ty::PushConstants {
+				view_projection,
+				depth_bias: self.depth_bias,
+				point_size,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+// Colors of the vertices, by index modulo the length
+const TRIANGULATION_PALETTE: [[f32; 4]; 7] = [
+	[1.0, 0.2, 0.2, 1.0],
+	[1.0, 0.6, 0.1, 1.0],
+	[1.0, 1.0, 0.2, 1.0],
+	[0.2, 1.0, 0.3, 1.0],
+	[0.2, 0.8, 1.0, 1.0],
+	[0.3, 0.3, 1.0, 1.0],
+	[0.8, 0.3, 1.0, 1.0],
+];
+const EDGE_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 0.8];
+// Of the points and edges drawn over the mesh, in normalized depth units
+pub const TRIANGULATION_DEPTH_BIAS: f32 = 1e-4;
+
+// Draws the vertices of triangle lists as points colored by their index and
+// each of their edges once as a line, toggled by Action::ToggleTriangulation.
+// The points and lines should be the depth tested kind, with
+// TRIANGULATION_DEPTH_BIAS, to float over the shaded mesh.
+pub struct DebugTriangulation {
+	enabled: bool,
+}
+
+impl Default for DebugTriangulation {
+	fn default() -> DebugTriangulation {
+		DebugTriangulation::new()
+	}
+}
+
+impl DebugTriangulation {
+	pub fn new() -> DebugTriangulation {
+		DebugTriangulation { enabled: false }
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	// `indices` hold 3 vertices per triangle. Adds nothing while disabled.
+	pub fn add_mesh(&self, points: &mut PointRenderer, lines: &mut LineRenderer, positions: &[[f32; 3]], indices: &[u32]) {
+		if !self.enabled {
+			return;
+		}
+		for (i, &position) in positions.iter().enumerate() {
+			points.add_point(position, TRIANGULATION_PALETTE[i % TRIANGULATION_PALETTE.len()]);
+		}
+
+		// The edges shared by two triangles are only drawn once
+		let mut edges = HashSet::new();
+		for triangle in indices.chunks_exact(3) {
+			for corner in 0..3 {
+				let (a, b) = (triangle[corner], triangle[(corner + 1) % 3]);
+				if edges.insert((a.min(b), a.max(b))) {
+					if let (Some(&start), Some(&end)) = (positions.get(a as usize), positions.get(b as usize)) {
+						lines.add_line(start, end, EDGE_COLOR);
+					}
+				}
+			}
+		}
+	}
+}
+
+// Side of the monitors, in histogram bins and in pixels on screen
+pub const MONITOR_SIZE: u32 = 256;
+pub const MONITOR_FORMAT: Format = Format::R8G8B8A8Unorm;
+// Only every 8th pixel of each row and column is binned
+const MONITOR_STRIDE: u32 = 8;
+// Between the panels and the edges of the window, in pixels
+const MONITOR_MARGIN: f32 = 16.0;
+const WAVEFORM_MODE: u32 = 0;
+const VECTORSCOPE_MODE: u32 = 1;
+
+mod monitor_bin_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const int MONITOR_SIZE = 256;
+			const int MONITOR_STRIDE = 8;
+
+			layout(set = 0, binding = 0) uniform sampler2D frame;
+			layout(set = 0, binding = 1) buffer Counts {
+				uint counts[];
+			};
+
+			layout(push_constant) uniform PushConstants {
+				// 0 for the waveform, 1 for the vectorscope
+				uint mode;
+			} pc;
+
+			void main() {
+				ivec2 size = textureSize(frame, 0);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy) * MONITOR_STRIDE;
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+
+				vec3 color = clamp(texelFetch(frame, pixel, 0).rgb, 0.0, 1.0);
+				float luma = dot(color, vec3(0.2126, 0.7152, 0.0722));
+				ivec2 bin;
+				if (pc.mode == 0) {
+					// Columns of the frame across, luma upwards
+					bin = ivec2(pixel.x * MONITOR_SIZE / size.x, int((1.0 - luma) * float(MONITOR_SIZE - 1) + 0.5));
+				} else {
+					// Rec. 709 Cb across and Cr upwards, grays in the center
+					vec2 chroma = vec2((color.b - luma) / 1.8556, (color.r - luma) / 1.5748);
+					bin = ivec2((vec2(0.5 + chroma.x, 0.5 - chroma.y)) * float(MONITOR_SIZE - 1) + 0.5);
+				}
+				bin = clamp(bin, ivec2(0), ivec2(MONITOR_SIZE - 1));
+				atomicAdd(counts[bin.y * MONITOR_SIZE + bin.x], 1u);
+			}
+		"
+	}
+}
+
+mod monitor_resolve_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const int MONITOR_SIZE = 256;
+
+			layout(set = 0, binding = 0) buffer Counts {
+				uint counts[];
+			};
+			layout(set = 0, binding = 1, rgba8) uniform writeonly image2D overlay;
+
+			layout(push_constant) uniform PushConstants {
+				uint mode;
+				// Count shown at full brightness
+				float reference;
+			} pc;
+
+			void main() {
+				ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+				if (texel.x >= MONITOR_SIZE || texel.y >= MONITOR_SIZE) {
+					return;
+				}
+				int index = texel.y * MONITOR_SIZE + texel.x;
+				float count = float(counts[index]);
+				// Cleared for the next frame's binning
+				counts[index] = 0;
+
+				// Log scale, the sparse values stay visible next to the dense ones
+				float density = clamp(log2(1.0 + count) / log2(1.0 + pc.reference), 0.0, 1.0);
+				vec2 uv = (vec2(texel) + 0.5) / float(MONITOR_SIZE);
+				float half_texel = 0.5 / float(MONITOR_SIZE);
+				vec3 color;
+				float graticule;
+				if (pc.mode == 0) {
+					color = vec3(0.3, 1.0, 0.4) * density;
+					// Every 10% of luma
+					float level = (1.0 - uv.y) * 10.0;
+					graticule = abs(level - round(level)) < half_texel * 10.0 ? 0.2 : 0.0;
+				} else {
+					color = vec3(density);
+					// The edge of the plot, half saturation and the axes
+					vec2 centered = uv - 0.5;
+					float radius = length(centered);
+					if (radius > 0.5) {
+						imageStore(overlay, texel, vec4(0.0));
+						return;
+					}
+					bool ring = abs(radius - 0.5 + half_texel) < half_texel || abs(radius - 0.25) < half_texel;
+					bool axis = abs(centered.x) < half_texel || abs(centered.y) < half_texel;
+					graticule = ring || axis ? 0.2 : 0.0;
+				}
+				imageStore(overlay, texel, vec4(color + graticule, 1.0));
+			}
+		"
+	}
+}
+
+mod monitor_overlay_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 0) uniform sampler2D overlay;
+
+			void main() {
+				f_color = vec4(texture(overlay, v_uv).rgb, 0.0);
+			}
+		"
+	}
+}
+
+fn monitor_overlay(device: &Arc<Device>) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width: MONITOR_SIZE,
+			height: MONITOR_SIZE,
+			array_layers: 1,
+		},
+		MONITOR_FORMAT,
+		ImageUsage {
+			storage: true,
+			sampled: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+fn monitor_counts(device: &Arc<Device>) -> Result<Arc<CpuAccessibleBuffer<[u32]>>, VulkanoError> {
+	let usage = BufferUsage {
+		storage_buffer: true,
+		..BufferUsage::none()
+	};
+	let counts = CpuAccessibleBuffer::from_iter(
+		device.clone(),
+		usage,
+		false,
+		(0..MONITOR_SIZE * MONITOR_SIZE).map(|_| 0u32),
+	)?;
+	Ok(counts)
+}
+
+// Luma of the frame per column, bottom to top
+pub struct WaveformMonitor {
+	counts: Arc<CpuAccessibleBuffer<[u32]>>,
+	overlay: Arc<StorageImage<Format>>,
+}
+
+impl WaveformMonitor {
+	pub fn new(device: &Arc<Device>) -> Result<WaveformMonitor, VulkanoError> {
+		Ok(WaveformMonitor {
+			counts: monitor_counts(device)?,
+			overlay: monitor_overlay(device)?,
+		})
+	}
+
+	pub fn overlay(&self) -> Arc<StorageImage<Format>> {
+		self.overlay.clone()
+	}
+}
+
+// Chrominance of the frame in a circular plot, hue around and saturation
+// outwards
+pub struct Vectorscope {
+	counts: Arc<CpuAccessibleBuffer<[u32]>>,
+	overlay: Arc<StorageImage<Format>>,
+}
+
+impl Vectorscope {
+	pub fn new(device: &Arc<Device>) -> Result<Vectorscope, VulkanoError> {
+		Ok(Vectorscope {
+			counts: monitor_counts(device)?,
+			overlay: monitor_overlay(device)?,
+		})
+	}
+
+	pub fn overlay(&self) -> Arc<StorageImage<Format>> {
+		self.overlay.clone()
+	}
+}
+
+// Waveform and vectorscope of the frame to validate color grading, shown as
+// MONITOR_SIZE insets in the bottom right corner, toggled with M. A compute
+// shader bins every MONITOR_STRIDE-th pixel of the frame into a histogram per
+// monitor with atomics, another one resolves it into the monitor's overlay
+// image and clears it. The overlays are added over the frame.
+pub struct ColorMonitors {
+	bin_pipeline: SharedComputePipeline,
+	resolve_pipeline: SharedComputePipeline,
+	overlay_pipeline: Arc<BufferlessPipeline>,
+	sampler: Arc<Sampler>,
+	enabled: bool,
+	pub waveform: WaveformMonitor,
+	pub vectorscope: Vectorscope,
+}
+
+impl ColorMonitors {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<ColorMonitors, VulkanoError> {
+		let bin = monitor_bin_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let resolve = monitor_resolve_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = monitor_overlay_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		// The frame shows through the black of the panels
+		let additive = AttachmentBlend {
+			enabled: true,
+			color_op: BlendOp::Add,
+			color_source: BlendFactor::One,
+			color_destination: BlendFactor::One,
+			alpha_op: BlendOp::Add,
+			alpha_source: BlendFactor::Zero,
+			alpha_destination: BlendFactor::One,
+			mask_red: true,
+			mask_green: true,
+			mask_blue: true,
+			mask_alpha: true,
+		};
+		let overlay_pipeline = GraphicsPipeline::start()
+			.vertex_input(BufferlessDefinition {})
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.depth_stencil(DepthStencil::disabled())
+			.blend_collective(additive)
+			.render_pass(subpass)
+			.build(device.clone())?;
+
+		Ok(ColorMonitors {
+			bin_pipeline: Arc::new(ComputePipeline::new(device.clone(), &bin.main_entry_point(), &(), None)?),
+			resolve_pipeline: Arc::new(ComputePipeline::new(device.clone(), &resolve.main_entry_point(), &(), None)?),
+			overlay_pipeline: Arc::new(overlay_pipeline),
+			// Texels of the monitors map to pixels one to one
+			sampler: Sampler::new(
+				device.clone(),
+				Filter::Nearest,
+				Filter::Nearest,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)?,
+			enabled: false,
+			waveform: WaveformMonitor::new(&device)?,
+			vectorscope: Vectorscope::new(&device)?,
+		})
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	pub fn handle_event(&mut self, event: &WindowEvent) {
+		if let WindowEvent::KeyboardInput {
+			input:
+				KeyboardInput {
+					state: ElementState::Pressed,
+					virtual_keycode: Some(VirtualKeyCode::M),
+					..
+				},
+			..
+		} = event
+		{
+			self.enabled = !self.enabled;
+		}
+	}
+
+	// Records the update of both monitors from `frame`, the graded image of
+	// `dimensions` before the overlays, outside of any render pass. Records
+	// nothing while disabled.
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		frame: Arc<dyn ImageViewAbstract + Send + Sync>,
+		dimensions: [u32; 2],
+	) -> Result<(), VulkanoError> {
+		if !self.enabled {
+			return Ok(());
+		}
+		let monitors = [
+			(WAVEFORM_MODE, &self.waveform.counts, &self.waveform.overlay),
+			(VECTORSCOPE_MODE, &self.vectorscope.counts, &self.vectorscope.overlay),
+		];
+		let samples = [
+			dimensions[0].div_ceil(MONITOR_STRIDE),
+			dimensions[1].div_ceil(MONITOR_STRIDE),
+		];
+		let bin_layout = self
+			.bin_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let resolve_layout = self
+			.resolve_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		for &(mode, counts, overlay) in monitors.iter() {
+			let bin_set = Arc::new(
+				PersistentDescriptorSet::start(bin_layout.clone())
+					.add_sampled_image(frame.clone(), self.sampler.clone())?
+					.add_buffer(counts.clone())?
+					.build()?,
+			);
+			debug_label!(builder, "ColorMonitors bin", LABEL_COLOR_COMPUTE);
+			builder.dispatch(
+				[samples[0].div_ceil(8), samples[1].div_ceil(8), 1],
+				self.bin_pipeline.clone(),
+				bin_set,
+				monitor_bin_cs::ty::PushConstants { mode },
+				vec![],
+			)?;
+			debug_label!(builder);
+
+			let resolve_set = Arc::new(
+				PersistentDescriptorSet::start(resolve_layout.clone())
+					.add_buffer(counts.clone())?
+					.add_image(ImageView::new(overlay.clone())?)?
+					.build()?,
+			);
+			// A monitor column, or bin row, holding all of its share of the samples
+			let reference = (samples[0] * samples[1]) as f32 / MONITOR_SIZE as f32;
+			debug_label!(builder, "ColorMonitors resolve", LABEL_COLOR_COMPUTE);
+			builder.dispatch(
+				[MONITOR_SIZE / 8, MONITOR_SIZE / 8, 1],
+				self.resolve_pipeline.clone(),
+				resolve_set,
+				monitor_resolve_cs::ty::PushConstants {
+					mode,
+					reference: reference.max(1.0),
+				},
+				vec![],
+			)?;
+			debug_label!(builder);
+		}
+		Ok(())
+	}
+
+	// Records the panels over the frame of `dimensions`, inside the render pass,
+	// the waveform left of the vectorscope. Records nothing while disabled.
+	pub fn draw(&self, builder: &mut AutoCommandBufferBuilder, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		if !self.enabled {
+			return Ok(());
+		}
+		let layout = self
+			.overlay_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let size = MONITOR_SIZE as f32;
+		let top = dimensions[1] as f32 - size - MONITOR_MARGIN;
+		let panels = [
+			(self.waveform.overlay.clone(), dimensions[0] as f32 - 2.0 * (size + MONITOR_MARGIN)),
+			(self.vectorscope.overlay.clone(), dimensions[0] as f32 - size - MONITOR_MARGIN),
+		];
+		for (overlay, left) in panels.iter() {
+			let set = Arc::new(
+				PersistentDescriptorSet::start(layout.clone())
+					.add_sampled_image(ImageView::new(overlay.clone())?, self.sampler.clone())?
+					.build()?,
+			);
+			let dynamic_state = DynamicState {
+				viewports: Some(vec![Viewport {
+					origin: [*left, top],
+					dimensions: [size, size],
+					depth_range: 0.0..1.0,
+				}]),
+				..DynamicState::none()
+			};
+			builder.draw(
+				self.overlay_pipeline.clone(),
+				&dynamic_state,
+				BufferlessVertices {
+					vertices: 3,
+					instances: 1,
+				},
+				set,
+				(),
+				vec![],
+			)?;
+		}
+		Ok(())
+	}
+}
+
+mod heat_map_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+
+			layout(location = 0) out vec4 f_color;
+
+			// R32F isn't filterable everywhere, the values are fetched
+			layout(set = 0, binding = 0, r32f) uniform readonly image2D values;
+			layout(set = 0, binding = 1) uniform sampler2D colormap;
+
+			layout(push_constant) uniform PushConstants {
+				float min_value;
+				float max_value;
+			} pc;
+
+			void main() {
+				ivec2 size = imageSize(values);
+				ivec2 texel = min(ivec2(v_uv * vec2(size)), size - 1);
+				float value = imageLoad(values, texel).r;
+				float t = clamp((value - pc.min_value) / max(pc.max_value - pc.min_value, 1e-6), 0.0, 1.0);
+				// Centers of the first and last texels at 0 and 1
+				float u = (t * 255.0 + 0.5) / 256.0;
+				f_color = vec4(texture(colormap, vec2(u, 0.5)).rgb, 1.0);
+			}
+		"
+	}
+}
+
+// Perceptually uniform colormaps for `HeatMapPass`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colormap {
+	Viridis,
+	Plasma,
+	Magma,
+}
+
+impl Colormap {
+	pub const ALL: [Colormap; 3] = [Colormap::Viridis, Colormap::Plasma, Colormap::Magma];
+
+	// 256 sRGB texels, sampled from the polynomial fits of the matplotlib maps
+	fn texels(self) -> &'static [u8] {
+		match self {
+			Colormap::Viridis => include_bytes!("colormaps/viridis.rgb"),
+			Colormap::Plasma => include_bytes!("colormaps/plasma.rgb"),
+			Colormap::Magma => include_bytes!("colormaps/magma.rgb"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeatMapLayout {
+	Fullscreen,
+	// In the bottom left corner, this fraction of the screen wide and high
+	Inset(f32),
+}
+
+const HEAT_MAP_MARGIN: f32 = 16.0;
+
+// Shows an R32F image of per pixel values, occlusion, depth, roughness, as
+// colors of a colormap, `min_value` and `max_value` mapping to both ends of
+// it. The number keys select the colormap, see `handle_input`.
+pub struct HeatMapPass {
+	pipeline: Arc<BufferlessPipeline>,
+	colormaps: Vec<Arc<ImmutableImage<Format>>>,
+	sampler: Arc<Sampler>,
+	enabled: bool,
+	pub colormap: Colormap,
+	pub layout: HeatMapLayout,
+	pub min_value: f32,
+	pub max_value: f32,
+}
+
+impl HeatMapPass {
+	pub fn new(
+		queue: Arc<Queue>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<(HeatMapPass, Box<dyn GpuFuture>), VulkanoError> {
+		let device = queue.device().clone();
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = heat_map_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input(BufferlessDefinition {})
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.depth_stencil(DepthStencil::disabled())
+			.render_pass(subpass)
+			.build(device.clone())?;
+
+		let mut colormaps = Vec::with_capacity(Colormap::ALL.len());
+		let mut future: Box<dyn GpuFuture> = vulkano::sync::now(device.clone()).boxed();
+		for colormap in Colormap::ALL.iter() {
+			let texels: Vec<u8> = colormap
+				.texels()
+				.chunks_exact(3)
+				.flat_map(|rgb| rgb.iter().copied().chain(std::iter::once(255)))
+				.collect();
+			let (image, upload) = ImmutableImage::from_iter(
+				texels.into_iter(),
+				ImageDimensions::Dim2d {
+					width: 256,
+					height: 1,
+					array_layers: 1,
+				},
+				MipmapsCount::One,
+				Format::R8G8B8A8Srgb,
+				queue.clone(),
+			)?;
+			colormaps.push(image);
+			future = future.join(upload).boxed();
+		}
+
+		let pass = HeatMapPass {
+			pipeline: Arc::new(pipeline),
+			colormaps,
+			sampler: Sampler::new(
+				device.clone(),
+				Filter::Linear,
+				Filter::Linear,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)?,
+			enabled: false,
+			colormap: Colormap::Viridis,
+			layout: HeatMapLayout::Inset(0.25),
+			min_value: 0.0,
+			max_value: 1.0,
+		};
+		Ok((pass, future))
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	// Action::ColormapViridis, ColormapPlasma and ColormapMagma, 1 to 3 by default
+	pub fn handle_input(&mut self, input: &InputMapper) {
+		let actions = [Action::ColormapViridis, Action::ColormapPlasma, Action::ColormapMagma];
+		for (action, colormap) in actions.iter().zip(Colormap::ALL.iter()) {
+			if input.was_triggered(*action) {
+				self.colormap = *colormap;
+			}
+		}
+	}
+
+	// Records the heat map of `values`, an R32F storage image, inside the
+	// render pass of the frame of `dimensions`. Records nothing while disabled.
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		values: Arc<StorageImage<Format>>,
+		dimensions: [u32; 2],
+	) -> Result<(), VulkanoError> {
+		if !self.enabled {
+			return Ok(());
+		}
+		let index = Colormap::ALL.iter().position(|&colormap| colormap == self.colormap).unwrap_or(0);
+		let layout = self.pipeline.descriptor_set_layout(0).ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(values)?)?
+				.add_sampled_image(ImageView::new(self.colormaps[index].clone())?, self.sampler.clone())?
+				.build()?,
+		);
+
+		let [width, height] = [dimensions[0] as f32, dimensions[1] as f32];
+		let viewport = match self.layout {
+			HeatMapLayout::Fullscreen => Viewport {
+				origin: [0.0, 0.0],
+				dimensions: [width, height],
+				depth_range: 0.0..1.0,
+			},
+			HeatMapLayout::Inset(fraction) => {
+				let size = [width * fraction, height * fraction];
+				Viewport {
+					origin: [HEAT_MAP_MARGIN, height - size[1] - HEAT_MAP_MARGIN],
+					dimensions: size,
+					depth_range: 0.0..1.0,
+				}
+			}
+		};
+		let dynamic_state = DynamicState {
+			viewports: Some(vec![viewport]),
+			..DynamicState::none()
+		};
+		builder.draw(
+			self.pipeline.clone(),
+			&dynamic_state,
+			BufferlessVertices {
+				vertices: 3,
+				instances: 1,
+			},
+			set,
+			heat_map_fs::ty::PushConstants {
+				min_value: self.min_value,
+				max_value: self.max_value,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,758 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::Device;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageViewAbstract;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices, OneVertexOneInstanceDefinition};
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::forward_plus::TILE_SIZE;
+use crate::glsl_shaders::fullscreen_vs;
+use crate::pipeline::{BufferlessPipeline, SharedComputePipeline, SharedPipeline};
+
+// Older decals are replaced past this count
+pub const MAX_DECALS: usize = 64;
+
+mod decal_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			// Corner of the unit cube centered on the origin
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in mat4 model;
+			layout(location = 5) in vec4 atlas_rect;
+			layout(location = 6) in float normal_strength;
+
+			layout(location = 0) flat out mat4 v_world_to_decal;
+			layout(location = 4) flat out vec4 v_atlas_rect;
+			layout(location = 5) flat out vec3 v_normal;
+			layout(location = 6) flat out float v_normal_strength;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+			} pc;
+
+			void main() {
+				v_world_to_decal = inverse(model);
+				v_atlas_rect = atlas_rect;
+				// Decals are projected along their local Y axis
+				v_normal = normalize(model[1].xyz);
+				v_normal_strength = normal_strength;
+				gl_Position = pc.view_projection * model * vec4(position, 1.0);
+			}
+		"
+	}
+}
+
+mod decal_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) flat in mat4 v_world_to_decal;
+			layout(location = 4) flat in vec4 v_atlas_rect;
+			layout(location = 5) flat in vec3 v_normal;
+			layout(location = 6) flat in float v_normal_strength;
+
+			layout(location = 0) out vec4 f_albedo;
+			// World normals encoded as normal * 0.5 + 0.5
+			layout(location = 1) out vec4 f_normal;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			layout(set = 0, binding = 1) uniform sampler2D atlas;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+			} pc;
+
+			void main() {
+				ivec2 pixel = ivec2(gl_FragCoord.xy);
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(textureSize(depth, 0));
+				float d = texelFetch(depth, pixel, 0).r;
+				vec4 world = inverse(pc.view_projection) * vec4(uv * 2.0 - 1.0, d, 1.0);
+				vec3 local = (v_world_to_decal * vec4(world.xyz / world.w, 1.0)).xyz;
+				// The scene surface behind this fragment is outside of the box
+				if (any(greaterThan(abs(local), vec3(0.5)))) {
+					discard;
+				}
+
+				vec4 color = texture(atlas, v_atlas_rect.xy + (local.xz + 0.5) * v_atlas_rect.zw);
+				f_albedo = color;
+				f_normal = vec4(v_normal * 0.5 + 0.5, color.a * v_normal_strength);
+			}
+		"
+	}
+}
+
+// Must match the shaders, each tile stores its decal count then the indices
+pub const MAX_DECALS_PER_TILE: u32 = 16;
+
+mod tile_decal_culling_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
+
+			const uint MAX_DECALS_PER_TILE = 16;
+
+			struct Decal {
+				mat4 model;
+				mat4 world_to_decal;
+				vec4 atlas_rect;
+				vec4 params;
+			};
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				// Width, height, tiles per row and decal count
+				uvec4 screen;
+			} camera;
+			layout(set = 0, binding = 1) readonly buffer Decals {
+				Decal decals[];
+			};
+			layout(set = 0, binding = 2) writeonly buffer TileDecals {
+				uint tile_decals[];
+			};
+			layout(set = 0, binding = 3) uniform sampler2D depth;
+			// Read back to log the tiles that dropped decals
+			layout(set = 0, binding = 4) buffer Overflow {
+				uint overflowing_tiles;
+				uint max_count;
+			} overflow;
+
+			shared uint min_depth;
+			shared uint max_depth;
+			shared uint decal_count;
+			shared uint decal_indices[MAX_DECALS_PER_TILE];
+
+			vec3 unproject(mat4 inverse_projection, vec2 ndc, float depth) {
+				vec4 position = inverse_projection * vec4(ndc, depth, 1.0);
+				return position.xyz / position.w;
+			}
+
+			void main() {
+				uint local_index = gl_LocalInvocationIndex;
+				if (local_index == 0) {
+					min_depth = floatBitsToUint(1.0);
+					max_depth = 0;
+					decal_count = 0;
+				}
+				barrier();
+
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x < int(camera.screen.x) && pixel.y < int(camera.screen.y)) {
+					uint bits = floatBitsToUint(texelFetch(depth, pixel, 0).r);
+					atomicMin(min_depth, bits);
+					atomicMax(max_depth, bits);
+				}
+				barrier();
+
+				// Same tile frustum as the light culling of forward_plus.rs
+				mat4 inverse_projection = inverse(camera.projection);
+				vec2 tile_size = 2.0 * vec2(gl_WorkGroupSize.xy) / vec2(camera.screen.xy);
+				vec2 tile_min = vec2(gl_WorkGroupID.xy) * tile_size - 1.0;
+				vec2 tile_max = tile_min + tile_size;
+				vec3 corners[4] = vec3[](
+					unproject(inverse_projection, tile_min, 1.0),
+					unproject(inverse_projection, vec2(tile_max.x, tile_min.y), 1.0),
+					unproject(inverse_projection, tile_max, 1.0),
+					unproject(inverse_projection, vec2(tile_min.x, tile_max.y), 1.0)
+				);
+				vec3 center = unproject(inverse_projection, (tile_min + tile_max) * 0.5, 1.0);
+				vec3 planes[4];
+				for (int i = 0; i < 4; i++) {
+					vec3 normal = normalize(cross(corners[i], corners[(i + 1) % 4]));
+					planes[i] = dot(normal, center) < 0.0 ? -normal : normal;
+				}
+				float near = -unproject(inverse_projection, vec2(0.0), uintBitsToFloat(min_depth)).z;
+				float far = -unproject(inverse_projection, vec2(0.0), uintBitsToFloat(max_depth)).z;
+
+				uint total = camera.screen.w;
+				uint invocations = gl_WorkGroupSize.x * gl_WorkGroupSize.y;
+				for (uint i = local_index; i < total; i += invocations) {
+					// The box in view space, its half extents along each axis
+					mat4 view_model = camera.view * decals[i].model;
+					vec3 box_center = view_model[3].xyz;
+					vec3 axes[3] = vec3[](view_model[0].xyz * 0.5, view_model[1].xyz * 0.5, view_model[2].xyz * 0.5);
+
+					float depth_extent = abs(axes[0].z) + abs(axes[1].z) + abs(axes[2].z);
+					bool visible = -box_center.z + depth_extent >= near && -box_center.z - depth_extent <= far;
+					for (int p = 0; p < 4 && visible; p++) {
+						float extent = abs(dot(planes[p], axes[0])) + abs(dot(planes[p], axes[1])) + abs(dot(planes[p], axes[2]));
+						visible = dot(planes[p], box_center) >= -extent;
+					}
+					if (visible) {
+						uint slot = atomicAdd(decal_count, 1);
+						if (slot < MAX_DECALS_PER_TILE) {
+							decal_indices[slot] = i;
+						}
+					}
+				}
+				barrier();
+
+				uint tile = gl_WorkGroupID.y * camera.screen.z + gl_WorkGroupID.x;
+				uint base = tile * (MAX_DECALS_PER_TILE + 1);
+				uint count = min(decal_count, MAX_DECALS_PER_TILE);
+				if (local_index == 0) {
+					tile_decals[base] = count;
+					if (decal_count > MAX_DECALS_PER_TILE) {
+						atomicAdd(overflow.overflowing_tiles, 1);
+						atomicMax(overflow.max_count, decal_count);
+					}
+				}
+				for (uint i = local_index; i < count; i += invocations) {
+					tile_decals[base + 1 + i] = decal_indices[i];
+				}
+			}
+		"
+	}
+}
+
+mod tiled_decal_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+
+			layout(location = 0) out vec4 f_albedo;
+			layout(location = 1) out vec4 f_normal;
+
+			const uint TILE_SIZE = 16;
+			const uint MAX_DECALS_PER_TILE = 16;
+
+			struct Decal {
+				mat4 model;
+				mat4 world_to_decal;
+				vec4 atlas_rect;
+				vec4 params;
+			};
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				uvec4 screen;
+			} camera;
+			layout(set = 0, binding = 1) readonly buffer Decals {
+				Decal decals[];
+			};
+			layout(set = 0, binding = 2) readonly buffer TileDecals {
+				uint tile_decals[];
+			};
+			layout(set = 0, binding = 3) uniform sampler2D depth;
+			layout(set = 0, binding = 4) uniform sampler2D atlas;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+			} pc;
+
+			void main() {
+				ivec2 pixel = ivec2(gl_FragCoord.xy);
+				float d = texelFetch(depth, pixel, 0).r;
+				vec4 world = inverse(pc.view_projection) * vec4(v_uv * 2.0 - 1.0, d, 1.0);
+				world /= world.w;
+
+				uvec2 tile = uvec2(pixel) / TILE_SIZE;
+				uint base = (tile.y * camera.screen.z + tile.x) * (MAX_DECALS_PER_TILE + 1);
+				uint count = tile_decals[base];
+
+				// Premultiplied, the decals later in the list are composed over the earlier ones
+				vec4 albedo = vec4(0.0);
+				vec4 normal = vec4(0.0);
+				for (uint i = 0; i < count; i++) {
+					Decal decal = decals[tile_decals[base + 1 + i]];
+					vec3 local = (decal.world_to_decal * world).xyz;
+					if (any(greaterThan(abs(local), vec3(0.5)))) {
+						continue;
+					}
+					vec4 color = texture(atlas, decal.atlas_rect.xy + (local.xz + 0.5) * decal.atlas_rect.zw);
+					albedo = vec4(color.rgb * color.a, color.a) + albedo * (1.0 - color.a);
+					float strength = color.a * decal.params.x;
+					vec3 encoded = normalize(decal.model[1].xyz) * 0.5 + 0.5;
+					normal = vec4(encoded * strength, strength) + normal * (1.0 - strength);
+				}
+				if (albedo.a <= 0.0 && normal.a <= 0.0) {
+					discard;
+				}
+				// Blended over the G-buffer like the decals of DecalRenderer
+				f_albedo = vec4(albedo.rgb / max(albedo.a, 1e-5), albedo.a);
+				f_normal = vec4(normal.rgb / max(normal.a, 1e-5), normal.a);
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct DecalVertex {
+	position: [f32; 3],
+}
+vulkano::impl_vertex!(DecalVertex, position);
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct DecalInstance {
+	// Maps the unit cube to the decal box, projected along its Y axis
+	pub model: [[f32; 4]; 4],
+	// Offset then size of the decal in the atlas, in UV units
+	pub atlas_rect: [f32; 4],
+	// 0 keeps the normals of the surface, 1 replaces them with the decal's
+	pub normal_strength: f32,
+}
+vulkano::impl_vertex!(DecalInstance, model, atlas_rect, normal_strength);
+
+const CUBE_INDICES: [u16; 36] = [
+	0, 2, 1, 1, 2, 3, 4, 5, 6, 5, 7, 6, 0, 1, 4, 1, 5, 4, 2, 6, 3, 3, 6, 7, 0, 4, 2, 2, 4, 6, 1, 3, 5, 3, 7, 5,
+];
+
+// Deferred decals, drawn in the G-buffer pass after the opaque geometry. Each
+// decal is a box drawn with its back faces, so it still covers the screen when
+// the camera is inside. The fragments reconstruct the world position of the
+// surface from the depth, discard it outside of the box and blend the atlas
+// texel over the albedo and the decal normal over the normals, weighted by the
+// texel alpha, so opaque texels override the material.
+pub struct DecalRenderer {
+	pipeline: SharedPipeline,
+	vertex_buffer: Arc<CpuAccessibleBuffer<[DecalVertex]>>,
+	index_buffer: Arc<CpuAccessibleBuffer<[u16]>>,
+	instance_pool: CpuBufferPool<DecalInstance>,
+	atlas: Arc<dyn ImageViewAbstract + Send + Sync>,
+	regions: HashMap<String, [f32; 4]>,
+	decals: Vec<DecalInstance>,
+	// Slot of the next decal once MAX_DECALS are alive
+	oldest: usize,
+	depth_sampler: Arc<Sampler>,
+	atlas_sampler: Arc<Sampler>,
+	pub normal_strength: f32,
+}
+
+impl DecalRenderer {
+	// `subpass` writes the albedo then the normals of the G-buffer, and must
+	// not have a depth attachment, the depth is sampled
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		atlas: Arc<dyn ImageViewAbstract + Send + Sync>,
+	) -> Result<DecalRenderer, VulkanoError> {
+		let vs = decal_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = decal_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input(OneVertexOneInstanceDefinition::<DecalVertex, DecalInstance>::new())
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.cull_mode_front()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.blend_collective(AttachmentBlend::alpha_blending())
+			.render_pass(subpass)
+			.build(device.clone())?;
+
+		let corners = (0..8).map(|i| DecalVertex {
+			position: [
+				if i & 1 != 0 { 0.5 } else { -0.5 },
+				if i & 2 != 0 { 0.5 } else { -0.5 },
+				if i & 4 != 0 { 0.5 } else { -0.5 },
+			],
+		});
+		let vertex_buffer = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::vertex_buffer(), false, corners)?;
+		let index_buffer = CpuAccessibleBuffer::from_iter(
+			device.clone(),
+			BufferUsage::index_buffer(),
+			false,
+			CUBE_INDICES.iter().copied(),
+		)?;
+
+		let sampler = |filter: Filter| {
+			Sampler::new(
+				device.clone(),
+				filter,
+				filter,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)
+		};
+		// Depth formats don't always support linear filtering
+		let depth_sampler = sampler(Filter::Nearest)?;
+		let atlas_sampler = sampler(Filter::Linear)?;
+
+		Ok(DecalRenderer {
+			pipeline: Arc::new(pipeline),
+			vertex_buffer,
+			index_buffer,
+			instance_pool: CpuBufferPool::vertex_buffer(device),
+			atlas,
+			regions: HashMap::new(),
+			decals: Vec::with_capacity(MAX_DECALS),
+			oldest: 0,
+			depth_sampler,
+			atlas_sampler,
+			normal_strength: 0.5,
+		})
+	}
+
+	// Names the rectangle of the atlas, offset then size in UV units, that
+	// `spawn_decal` uses for `key`
+	pub fn add_atlas_region(&mut self, key: &str, rect: [f32; 4]) {
+		self.regions.insert(key.to_owned(), rect);
+	}
+
+	// Places the texture of `texture_key` on the surfaces inside the box
+	// `transform` maps the unit cube to, replacing the oldest decal if there
+	// are already MAX_DECALS. Returns false if the key isn't in the atlas.
+	pub fn spawn_decal(&mut self, transform: [[f32; 4]; 4], texture_key: &str) -> bool {
+		let atlas_rect = match self.regions.get(texture_key) {
+			Some(rect) => *rect,
+			None => {
+				warn!("No decal texture named {:?} in the atlas", texture_key);
+				return false;
+			}
+		};
+		let decal = DecalInstance {
+			model: transform,
+			atlas_rect,
+			normal_strength: self.normal_strength,
+		};
+		if self.decals.len() < MAX_DECALS {
+			self.decals.push(decal);
+		} else {
+			self.decals[self.oldest] = decal;
+			self.oldest = (self.oldest + 1) % MAX_DECALS;
+		}
+		true
+	}
+
+	pub fn clear(&mut self) {
+		self.decals.clear();
+		self.oldest = 0;
+	}
+
+	// The live decals, in no particular order, for `TiledDecalCulling`
+	pub fn decals(&self) -> &[DecalInstance] {
+		&self.decals
+	}
+
+	// Records the decals inside the G-buffer subpass. `depth` is a view of the
+	// sampled depth of the opaque geometry.
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		if self.decals.is_empty() {
+			return Ok(());
+		}
+
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(depth, self.depth_sampler.clone())?
+				.add_sampled_image(self.atlas.clone(), self.atlas_sampler.clone())?
+				.build()?,
+		);
+		let instances = self.instance_pool.chunk(self.decals.iter().copied())?;
+		builder.draw_indexed(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![
+				self.vertex_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>,
+				Arc::new(instances),
+			],
+			self.index_buffer.clone(),
+			set,
+			decal_vs::ty::PushConstants { view_projection },
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+// Matches the std430 layout of the tiled shaders, only read on the GPU
+#[allow(dead_code)]
+#[derive(Default, Debug, Clone, Copy)]
+struct TiledDecal {
+	model: [[f32; 4]; 4],
+	world_to_decal: [[f32; 4]; 4],
+	atlas_rect: [f32; 4],
+	// Normal strength then padding
+	params: [f32; 4],
+}
+
+// Inverse of a column major matrix whose last row is (0, 0, 0, 1)
+fn affine_inverse(m: [[f32; 4]; 4]) -> [[f32; 4]; 4] {
+	let a = |row: usize, column: usize| m[column][row];
+	let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| a(r0, c0) * a(r1, c1) - a(r0, c1) * a(r1, c0);
+	let determinant =
+		a(0, 0) * cofactor(1, 2, 1, 2) - a(0, 1) * cofactor(1, 2, 0, 2) + a(0, 2) * cofactor(1, 2, 0, 1);
+	let inverse_determinant = if determinant.abs() > f32::EPSILON { 1.0 / determinant } else { 0.0 };
+	// Rows of the inverse of the 3x3 part, the transposed cofactor matrix
+	let rows = [
+		[cofactor(1, 2, 1, 2), -cofactor(0, 2, 1, 2), cofactor(0, 1, 1, 2)],
+		[-cofactor(1, 2, 0, 2), cofactor(0, 2, 0, 2), -cofactor(0, 1, 0, 2)],
+		[cofactor(1, 2, 0, 1), -cofactor(0, 2, 0, 1), cofactor(0, 1, 0, 1)],
+	];
+
+	let mut inverse = [[0.0; 4]; 4];
+	for (row, values) in rows.iter().enumerate() {
+		let mut translation = 0.0;
+		for (column, value) in values.iter().enumerate() {
+			inverse[column][row] = value * inverse_determinant;
+			translation -= inverse[column][row] * m[3][column];
+		}
+		inverse[3][row] = translation;
+	}
+	inverse[3][3] = 1.0;
+	inverse
+}
+
+fn tile_count([width, height]: [u32; 2]) -> [u32; 2] {
+	[width.div_ceil(TILE_SIZE), height.div_ceil(TILE_SIZE)]
+}
+
+fn tile_decal_buffer(device: &Arc<Device>, dimensions: [u32; 2]) -> Result<Arc<DeviceLocalBuffer<[u32]>>, VulkanoError> {
+	let [tiles_x, tiles_y] = tile_count(dimensions);
+	let buffer = DeviceLocalBuffer::array(
+		device.clone(),
+		(tiles_x * tiles_y * (MAX_DECALS_PER_TILE + 1)) as usize,
+		BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+		device.active_queue_families(),
+	)?;
+	Ok(buffer)
+}
+
+// Tiled deferred decals, the decal counterpart of the light culling of
+// `TiledForwardRenderer`, using the same 16x16 pixel tiles. `cull` tests the
+// box of every decal against the frustum of every tile, bounded by the depth,
+// and writes per tile decal lists of at most MAX_DECALS_PER_TILE decals, the
+// overflowing tiles being logged. `draw` is then a single full screen pass in
+// the G-buffer subpass of `DecalRenderer`, each pixel only projecting the
+// decals of its tile.
+pub struct TiledDecalCulling {
+	culling_pipeline: SharedComputePipeline,
+	apply_pipeline: Arc<BufferlessPipeline>,
+	camera_pool: CpuBufferPool<tile_decal_culling_cs::ty::Camera>,
+	decal_pool: CpuBufferPool<TiledDecal>,
+	tile_decals: Arc<DeviceLocalBuffer<[u32]>>,
+	// Overflowing tiles then the largest decal count of a tile
+	overflow: Arc<CpuAccessibleBuffer<[u32; 2]>>,
+	last_overflow: [u32; 2],
+	atlas: Arc<dyn ImageViewAbstract + Send + Sync>,
+	depth_sampler: Arc<Sampler>,
+	atlas_sampler: Arc<Sampler>,
+	// Written by `cull` for the draw of the same frame
+	apply_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+	dimensions: [u32; 2],
+	device: Arc<Device>,
+}
+
+impl TiledDecalCulling {
+	// `subpass` is laid out like the one of `DecalRenderer::new`
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		atlas: Arc<dyn ImageViewAbstract + Send + Sync>,
+		dimensions: [u32; 2],
+	) -> Result<TiledDecalCulling, VulkanoError> {
+		let culling_shader = tile_decal_culling_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let culling_pipeline: SharedComputePipeline = Arc::new(ComputePipeline::new(
+			device.clone(),
+			&culling_shader.main_entry_point(),
+			&(),
+			None,
+		)?);
+
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = tiled_decal_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let apply_pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input(BufferlessDefinition {})
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.blend_collective(AttachmentBlend::alpha_blending())
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+
+		let overflow_usage = BufferUsage {
+			storage_buffer: true,
+			transfer_destination: true,
+			..BufferUsage::none()
+		};
+		let overflow = CpuAccessibleBuffer::from_data(device.clone(), overflow_usage, false, [0u32; 2])?;
+
+		let sampler = |filter: Filter| {
+			Sampler::new(
+				device.clone(),
+				filter,
+				filter,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)
+		};
+		let depth_sampler = sampler(Filter::Nearest)?;
+		let atlas_sampler = sampler(Filter::Linear)?;
+
+		Ok(TiledDecalCulling {
+			culling_pipeline,
+			apply_pipeline,
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			decal_pool: CpuBufferPool::new(device.clone(), BufferUsage { storage_buffer: true, ..BufferUsage::none() }),
+			tile_decals: tile_decal_buffer(&device, dimensions)?,
+			overflow,
+			last_overflow: [0, 0],
+			atlas,
+			depth_sampler,
+			atlas_sampler,
+			apply_set: None,
+			dimensions,
+			device,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		if dimensions != self.dimensions {
+			self.tile_decals = tile_decal_buffer(&self.device, dimensions)?;
+			self.apply_set = None;
+			self.dimensions = dimensions;
+		}
+		Ok(())
+	}
+
+	// The counters are read back once the GPU is done with the previous cull,
+	// and only logged when they change
+	fn report_overflow(&mut self) {
+		let overflow = match self.overflow.read() {
+			Ok(content) => *content,
+			Err(_) => return,
+		};
+		if overflow != self.last_overflow && overflow[0] > 0 {
+			warn!(
+				"{} tiles had more than {} decals (up to {}), the extra decals were dropped",
+				overflow[0], MAX_DECALS_PER_TILE, overflow[1]
+			);
+		}
+		self.last_overflow = overflow;
+	}
+
+	// Records the decal culling outside of any render pass. `depth` is a view
+	// of the sampled depth of the opaque geometry, `decals` usually those of
+	// `DecalRenderer::decals`.
+	pub fn cull(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+		decals: &[DecalInstance],
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		self.report_overflow();
+		builder.update_buffer(self.overflow.clone(), Box::new([0u32; 2]))?;
+
+		let [tiles_x, tiles_y] = tile_count(self.dimensions);
+		let camera = self.camera_pool.next(tile_decal_culling_cs::ty::Camera {
+			view,
+			projection,
+			screen: [self.dimensions[0], self.dimensions[1], tiles_x, decals.len() as u32],
+		})?;
+		let tiled = decals.iter().map(|decal| TiledDecal {
+			model: decal.model,
+			world_to_decal: affine_inverse(decal.model),
+			atlas_rect: decal.atlas_rect,
+			params: [decal.normal_strength, 0.0, 0.0, 0.0],
+		});
+		// Empty buffers can't be bound, the count keeps the placeholder unused
+		let decal_buffer = if decals.is_empty() {
+			self.decal_pool.chunk(vec![TiledDecal::default()])?
+		} else {
+			self.decal_pool.chunk(tiled)?
+		};
+
+		let layout = self
+			.culling_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let culling_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera.clone())?
+				.add_buffer(decal_buffer.clone())?
+				.add_buffer(self.tile_decals.clone())?
+				.add_sampled_image(depth.clone(), self.depth_sampler.clone())?
+				.add_buffer(self.overflow.clone())?
+				.build()?,
+		);
+		debug_label!(builder, "TiledDecalCulling culling", LABEL_COLOR_COMPUTE);
+		builder.dispatch([tiles_x, tiles_y, 1], self.culling_pipeline.clone(), culling_set, (), vec![])?;
+		debug_label!(builder);
+
+		let layout = self
+			.apply_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		self.apply_set = Some(Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_buffer(decal_buffer)?
+				.add_buffer(self.tile_decals.clone())?
+				.add_sampled_image(depth, self.depth_sampler.clone())?
+				.add_sampled_image(self.atlas.clone(), self.atlas_sampler.clone())?
+				.build()?,
+		));
+		Ok(())
+	}
+
+	// Records the decals of the last `cull` inside the G-buffer subpass
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		let apply_set = self.apply_set.clone().expect("the decals must be culled before drawing");
+		builder.draw(
+			self.apply_pipeline.clone(),
+			dynamic_state,
+			BufferlessVertices {
+				vertices: 3,
+				instances: 1,
+			},
+			apply_set,
+			tiled_decal_fs::ty::PushConstants { view_projection },
+			vec![],
+		)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,3027 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool, TypedBufferAccess};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{ImageAccess, ImageCreateFlags, ImageDimensions, ImageUsage, ImmutableImage, MipmapsCount, StorageImage};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::depth_stencil::{Compare, DepthBounds, DepthStencil, Stencil, StencilOp};
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::glsl_shaders::fullscreen_vs;
+use crate::pipeline::{BufferlessPipeline, SharedComputePipeline, SharedPipeline};
+use crate::Vertex;
+
+mod outline_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 position;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 transform;
+				vec4 color;
+				float scale;
+			} pc;
+
+			void main() {
+				gl_Position = pc.transform * vec4(position * pc.scale, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod outline_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 transform;
+				vec4 color;
+				float scale;
+			} pc;
+
+			void main() {
+				f_color = pc.color;
+			}
+		"
+	}
+}
+
+const OUTLINE_STENCIL_REF: u32 = 1;
+
+// A selected mesh, drawn with its own color and outlined
+#[derive(Clone)]
+pub struct OutlineDraw {
+	pub vertex_buffer: Arc<dyn BufferAccess + Send + Sync>,
+	pub index_buffer: Arc<dyn TypedBufferAccess<Content = [u16]> + Send + Sync>,
+	pub transform: [[f32; 4]; 4],
+	pub color: [f32; 4],
+}
+
+// Selection highlight: the selected meshes are drawn while writing 1 to the
+// stencil buffer, then drawn again scaled up with a solid color where the
+// stencil isn't 1, leaving only the border visible. Both pipelines depth test
+// against the depth-stencil attachment of the subpass, so the outline is
+// hidden by the geometry in front of the selection.
+pub struct StencilOutlineEffect {
+	mask_pipeline: SharedPipeline,
+	outline_pipeline: SharedPipeline,
+	pub outline_color: [f32; 4],
+	pub scale: f32,
+}
+
+impl StencilOutlineEffect {
+	// `subpass` must have a depth-stencil attachment with a stencil aspect
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<StencilOutlineEffect, VulkanoError> {
+		debug_assert!(subpass.has_stencil(), "The outline requires a stencil attachment");
+
+		let vs = outline_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = outline_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let stencil = |compare, pass_op, write_mask| Stencil {
+			compare,
+			pass_op,
+			fail_op: StencilOp::Keep,
+			depth_fail_op: StencilOp::Keep,
+			compare_mask: Some(0xff),
+			write_mask: Some(write_mask),
+			reference: Some(OUTLINE_STENCIL_REF),
+		};
+
+		let mask_stencil = stencil(Compare::Always, StencilOp::Replace, 0xff);
+		let mask_pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<Vertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.depth_stencil(DepthStencil {
+				depth_write: true,
+				depth_compare: Compare::Less,
+				depth_bounds_test: DepthBounds::Disabled,
+				stencil_front: mask_stencil,
+				stencil_back: mask_stencil,
+			})
+			.render_pass(subpass.clone())
+			.build(device.clone())?;
+
+		// Doesn't write depth, the outline must not occlude what's drawn after it
+		let outline_stencil = stencil(Compare::NotEqual, StencilOp::Keep, 0);
+		let outline_pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<Vertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.depth_stencil(DepthStencil {
+				depth_write: false,
+				depth_compare: Compare::Less,
+				depth_bounds_test: DepthBounds::Disabled,
+				stencil_front: outline_stencil,
+				stencil_back: outline_stencil,
+			})
+			.blend_alpha_blending()
+			.render_pass(subpass)
+			.build(device)?;
+
+		Ok(StencilOutlineEffect {
+			mask_pipeline: Arc::new(mask_pipeline),
+			outline_pipeline: Arc::new(outline_pipeline),
+			outline_color: [1.0, 0.6, 0.1, 0.8],
+			scale: 1.05,
+		})
+	}
+
+	// Records the selected meshes then their outlines, inside the render pass
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		selection: &[OutlineDraw],
+	) -> Result<(), VulkanoError> {
+		for draw in selection {
+			self.record(builder, dynamic_state, &self.mask_pipeline, draw, draw.color, 1.0)?;
+		}
+		for draw in selection {
+			self.record(
+				builder,
+				dynamic_state,
+				&self.outline_pipeline,
+				draw,
+				self.outline_color,
+				self.scale,
+			)?;
+		}
+		Ok(())
+	}
+
+	fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		pipeline: &SharedPipeline,
+		draw: &OutlineDraw,
+		color: [f32; 4],
+		scale: f32,
+	) -> Result<(), VulkanoError> {
+		let push_constants = outline_vs::ty::PushConstants {
+			transform: draw.transform,
+			color,
+			scale,
+		};
+		builder.draw_indexed(
+			pipeline.clone(),
+			dynamic_state,
+			vec![draw.vertex_buffer.clone()],
+			draw.index_buffer.clone(),
+			(),
+			push_constants,
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+mod blur_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			// Pixels computed by a workgroup, along the blur direction. A
+			// constant_id of its own would be a second constant with the same id
+			layout(local_size_x_id = 0, local_size_y = 1, local_size_z = 1) in;
+			#define tile_size gl_WorkGroupSize.x
+
+			const int MAX_RADIUS = 32;
+
+			layout(set = 0, binding = 0, rgba16f) uniform readonly image2D source;
+			layout(set = 0, binding = 1, rgba16f) uniform writeonly image2D destination;
+
+			// Weights of the center and of the pixels at distance 1 to radius, packed by 4
+			layout(set = 0, binding = 2) uniform Weights {
+				vec4 weights[MAX_RADIUS / 4 + 1];
+				int radius;
+			} w;
+
+			layout(push_constant) uniform PushConstants {
+				// (1, 0) for the horizontal pass, (0, 1) for the vertical one
+				ivec2 direction;
+			} pc;
+
+			// The tile and the pixels within MAX_RADIUS on each side
+			shared vec4 tile[tile_size + 2 * MAX_RADIUS];
+
+			float weight(int i) {
+				return w.weights[i / 4][i % 4];
+			}
+
+			void main() {
+				ivec2 size = imageSize(source);
+				ivec2 along = pc.direction;
+				ivec2 across = pc.direction.yx;
+				int line_length = size.x * along.x + size.y * along.y;
+
+				int lane = int(gl_LocalInvocationID.x);
+				int start = int(gl_WorkGroupID.x * tile_size);
+				int line = int(gl_WorkGroupID.y);
+
+				for (int i = lane; i < int(tile_size) + 2 * MAX_RADIUS; i += int(tile_size)) {
+					int position = clamp(start - MAX_RADIUS + i, 0, line_length - 1);
+					tile[i] = imageLoad(source, along * position + across * line);
+				}
+				barrier();
+
+				int position = start + lane;
+				if (position >= line_length) {
+					return;
+				}
+
+				int center = lane + MAX_RADIUS;
+				vec4 sum = tile[center] * weight(0);
+				for (int i = 1; i <= w.radius; i++) {
+					sum += (tile[center - i] + tile[center + i]) * weight(i);
+				}
+				imageStore(destination, along * position + across * line, sum);
+			}
+		"
+	}
+}
+
+pub const BLUR_FORMAT: Format = Format::R16G16B16A16Sfloat;
+// Must match MAX_RADIUS in the shader
+pub const MAX_BLUR_RADIUS: usize = 32;
+pub const DEFAULT_BLUR_TILE_SIZE: u32 = 128;
+
+// Normalized weights of the center and of each distance up to 3 sigma
+pub fn gaussian_weights(sigma: f32) -> Vec<f32> {
+	let radius = ((sigma * 3.0).ceil() as usize).min(MAX_BLUR_RADIUS);
+	let mut weights: Vec<f32> = (0..=radius)
+		.map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+		.collect();
+	let total = weights[0] + 2.0 * weights[1..].iter().sum::<f32>();
+	for weight in weights.iter_mut() {
+		*weight /= total;
+	}
+	weights
+}
+
+// Separable gaussian blur of an RGBA16F storage image: a horizontal pass into
+// an intermediate image then a vertical pass into the destination. Each
+// workgroup caches the pixels of its tile and of the blur radius around it in
+// shared memory, so every pixel is only loaded once per pass.
+pub struct GaussianBlurPass {
+	pipeline: SharedComputePipeline,
+	intermediate: Arc<StorageImage<Format>>,
+	weights: Arc<CpuAccessibleBuffer<blur_cs::ty::Weights>>,
+	device: Arc<Device>,
+	dimensions: [u32; 2],
+	tile_size: u32,
+}
+
+impl GaussianBlurPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2], sigma: f32) -> Result<GaussianBlurPass, VulkanoError> {
+		GaussianBlurPass::with_tile_size(device, dimensions, sigma, DEFAULT_BLUR_TILE_SIZE)
+	}
+
+	// `tile_size` must not exceed the device's maximum workgroup size
+	pub fn with_tile_size(
+		device: Arc<Device>,
+		dimensions: [u32; 2],
+		sigma: f32,
+		tile_size: u32,
+	) -> Result<GaussianBlurPass, VulkanoError> {
+		let shader = blur_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline = Arc::new(ComputePipeline::new(
+			device.clone(),
+			&shader.main_entry_point(),
+			&blur_cs::SpecializationConstants { constant_0: tile_size },
+			None,
+		)?);
+
+		let intermediate = StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim2d {
+				width: dimensions[0],
+				height: dimensions[1],
+				array_layers: 1,
+			},
+			BLUR_FORMAT,
+			ImageUsage {
+				storage: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)?;
+
+		let weights = GaussianBlurPass::weights_buffer(&device, sigma)?;
+		Ok(GaussianBlurPass {
+			pipeline,
+			intermediate,
+			weights,
+			device,
+			dimensions,
+			tile_size,
+		})
+	}
+
+	pub fn set_sigma(&mut self, sigma: f32) -> Result<(), VulkanoError> {
+		self.weights = GaussianBlurPass::weights_buffer(&self.device, sigma)?;
+		Ok(())
+	}
+
+	fn weights_buffer(
+		device: &Arc<Device>,
+		sigma: f32,
+	) -> Result<Arc<CpuAccessibleBuffer<blur_cs::ty::Weights>>, VulkanoError> {
+		let weights = gaussian_weights(sigma);
+		let mut packed = [[0.0; 4]; MAX_BLUR_RADIUS / 4 + 1];
+		for (i, &weight) in weights.iter().enumerate() {
+			packed[i / 4][i % 4] = weight;
+		}
+		let buffer = CpuAccessibleBuffer::from_data(
+			device.clone(),
+			BufferUsage::uniform_buffer(),
+			false,
+			blur_cs::ty::Weights {
+				weights: packed,
+				radius: weights.len() as i32 - 1,
+			},
+		)?;
+		Ok(buffer)
+	}
+
+	// Records both passes, `source` and `destination` must have the dimensions the
+	// pass was created with and may be the same image
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		source: Arc<StorageImage<Format>>,
+		destination: Arc<StorageImage<Format>>,
+	) -> Result<(), VulkanoError> {
+		let [width, height] = self.dimensions;
+		self.dispatch(builder, source, self.intermediate.clone(), [1, 0], [width, height])?;
+		self.dispatch(builder, self.intermediate.clone(), destination, [0, 1], [height, width])?;
+		Ok(())
+	}
+
+	// `lines` is the length of a line along the blur direction then the line count
+	fn dispatch(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		source: Arc<StorageImage<Format>>,
+		destination: Arc<StorageImage<Format>>,
+		direction: [i32; 2],
+		lines: [u32; 2],
+	) -> Result<(), VulkanoError> {
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(source)?)?
+				.add_image(ImageView::new(destination)?)?
+				.add_buffer(self.weights.clone())?
+				.build()?,
+		);
+
+		debug_label!(builder, "GaussianBlurPass", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[lines[0].div_ceil(self.tile_size), lines[1], 1],
+			self.pipeline.clone(),
+			set,
+			blur_cs::ty::PushConstants { direction },
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+}
+
+fn clamp_to_edge_sampler(device: Arc<Device>, filter: Filter) -> Result<Arc<Sampler>, VulkanoError> {
+	let sampler = Sampler::new(
+		device,
+		filter,
+		filter,
+		MipmapMode::Nearest,
+		SamplerAddressMode::ClampToEdge,
+		SamplerAddressMode::ClampToEdge,
+		SamplerAddressMode::ClampToEdge,
+		0.0,
+		1.0,
+		0.0,
+		0.0,
+	)?;
+	Ok(sampler)
+}
+
+type SharedImageView = Arc<dyn ImageViewAbstract + Send + Sync>;
+
+fn storage_image(
+	device: &Arc<Device>,
+	dimensions: [u32; 2],
+	format: Format,
+) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width: dimensions[0],
+			height: dimensions[1],
+			array_layers: 1,
+		},
+		format,
+		ImageUsage {
+			storage: true,
+			sampled: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+mod velocity_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			layout(set = 0, binding = 1, rg16f) uniform writeonly image2D velocity;
+
+			layout(set = 0, binding = 2) uniform Matrices {
+				mat4 view_projection;
+				mat4 previous_view_projection;
+			} m;
+
+			void main() {
+				ivec2 size = imageSize(velocity);
+				if (gl_GlobalInvocationID.x >= size.x || gl_GlobalInvocationID.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(gl_GlobalInvocationID.xy) + 0.5) / vec2(size);
+
+				// World position of the pixel, then where it was on the screen last frame
+				float d = texture(depth, uv).r;
+				vec4 world = inverse(m.view_projection) * vec4(uv * 2.0 - 1.0, d, 1.0);
+				world /= world.w;
+				vec4 previous = m.previous_view_projection * world;
+				vec2 previous_uv = previous.xy / previous.w * 0.5 + 0.5;
+
+				imageStore(velocity, ivec2(gl_GlobalInvocationID.xy), vec4(uv - previous_uv, 0.0, 0.0));
+			}
+		"
+	}
+}
+
+mod motion_blur_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D color;
+			layout(set = 0, binding = 1, rg16f) uniform readonly image2D velocity;
+			layout(set = 0, binding = 2, rgba16f) uniform writeonly image2D destination;
+
+			layout(push_constant) uniform PushConstants {
+				int max_samples;
+				float velocity_scale;
+			} pc;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+				// One sample per pixel covered by the motion, centered on the pixel
+				vec2 motion = imageLoad(velocity, pixel).xy * pc.velocity_scale;
+				int samples = clamp(int(length(motion * vec2(size))), 1, pc.max_samples);
+
+				vec4 sum = vec4(0.0);
+				for (int i = 0; i < samples; i++) {
+					float t = samples == 1 ? 0.0 : float(i) / float(samples - 1) - 0.5;
+					sum += texture(color, uv + motion * t);
+				}
+				imageStore(destination, pixel, sum / float(samples));
+			}
+		"
+	}
+}
+
+pub const VELOCITY_FORMAT: Format = Format::R16G16Sfloat;
+
+// Camera motion blur: the velocity of each pixel is computed from the depth
+// buffer and the view-projection matrices of the current and previous frames,
+// then the color is averaged along that velocity.
+pub struct MotionBlurPass {
+	velocity_pipeline: SharedComputePipeline,
+	blur_pipeline: SharedComputePipeline,
+	matrices: CpuBufferPool<velocity_cs::ty::Matrices>,
+	velocity: Arc<StorageImage<Format>>,
+	depth_sampler: Arc<Sampler>,
+	color_sampler: Arc<Sampler>,
+	previous_view_projection: Option<[[f32; 4]; 4]>,
+	dimensions: [u32; 2],
+	pub max_samples: i32,
+	pub velocity_scale: f32,
+}
+
+impl MotionBlurPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<MotionBlurPass, VulkanoError> {
+		let velocity_shader = velocity_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let blur_shader = motion_blur_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		Ok(MotionBlurPass {
+			velocity_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&velocity_shader.main_entry_point(),
+				&(),
+				None,
+			)?),
+			blur_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&blur_shader.main_entry_point(),
+				&(),
+				None,
+			)?),
+			matrices: CpuBufferPool::uniform_buffer(device.clone()),
+			velocity: storage_image(&device, dimensions, VELOCITY_FORMAT)?,
+			// Depth formats don't always support linear filtering
+			depth_sampler: clamp_to_edge_sampler(device.clone(), Filter::Nearest)?,
+			color_sampler: clamp_to_edge_sampler(device, Filter::Linear)?,
+			previous_view_projection: None,
+			dimensions,
+			max_samples: 16,
+			velocity_scale: 1.0,
+		})
+	}
+
+	// The velocity of the last recorded frame, in UV units
+	pub fn velocity(&self) -> Arc<StorageImage<Format>> {
+		self.velocity.clone()
+	}
+
+	// Records both passes for a frame, outside of any render pass. `depth` and
+	// `color` are views of the frame's sampled depth and color images, the
+	// blurred color is written to `destination` (RGBA16F).
+	pub fn record(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: SharedImageView,
+		color: SharedImageView,
+		destination: Arc<StorageImage<Format>>,
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		// The first frame has no previous frame and doesn't blur
+		let previous_view_projection = self.previous_view_projection.replace(view_projection);
+		let matrices = self.matrices.next(velocity_cs::ty::Matrices {
+			view_projection,
+			previous_view_projection: previous_view_projection.unwrap_or(view_projection),
+		})?;
+
+		let groups = [self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1];
+
+		let layout = self
+			.velocity_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let velocity_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(depth, self.depth_sampler.clone())?
+				.add_image(ImageView::new(self.velocity.clone())?)?
+				.add_buffer(matrices)?
+				.build()?,
+		);
+		debug_label!(builder, "MotionBlurPass velocity", LABEL_COLOR_COMPUTE);
+		builder.dispatch(groups, self.velocity_pipeline.clone(), velocity_set, (), vec![])?;
+		debug_label!(builder);
+
+		let layout = self
+			.blur_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let blur_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(color, self.color_sampler.clone())?
+				.add_image(ImageView::new(self.velocity.clone())?)?
+				.add_image(ImageView::new(destination)?)?
+				.build()?,
+		);
+		debug_label!(builder, "MotionBlurPass blur", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			groups,
+			self.blur_pipeline.clone(),
+			blur_set,
+			motion_blur_cs::ty::PushConstants {
+				max_samples: self.max_samples,
+				velocity_scale: self.velocity_scale,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+}
+
+mod taa_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D current;
+			layout(set = 0, binding = 1) uniform sampler2D history;
+			layout(set = 0, binding = 2, rg16f) uniform readonly image2D velocity;
+			layout(set = 0, binding = 3, rgba16f) uniform writeonly image2D destination;
+
+			layout(push_constant) uniform PushConstants {
+				// Weight of the current frame, 1 discards the history
+				float alpha;
+			} pc;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+				vec4 color = texelFetch(current, pixel, 0);
+				vec3 neighborhood_min = color.rgb;
+				vec3 neighborhood_max = color.rgb;
+				for (int y = -1; y <= 1; y++) {
+					for (int x = -1; x <= 1; x++) {
+						ivec2 neighbor = clamp(pixel + ivec2(x, y), ivec2(0), size - 1);
+						vec3 sample_color = texelFetch(current, neighbor, 0).rgb;
+						neighborhood_min = min(neighborhood_min, sample_color);
+						neighborhood_max = max(neighborhood_max, sample_color);
+					}
+				}
+
+				vec2 previous_uv = uv - imageLoad(velocity, pixel).xy;
+				if (any(lessThan(previous_uv, vec2(0.0))) || any(greaterThan(previous_uv, vec2(1.0)))) {
+					imageStore(destination, pixel, color);
+					return;
+				}
+
+				// Clamping to the colors around the pixel rejects the history of
+				// surfaces that were disoccluded, which would otherwise ghost
+				vec4 previous = texture(history, previous_uv);
+				previous.rgb = clamp(previous.rgb, neighborhood_min, neighborhood_max);
+				imageStore(destination, pixel, mix(previous, color, pc.alpha));
+			}
+		"
+	}
+}
+
+// Point of the Halton low discrepancy sequence, in [0, 1)
+pub fn halton(mut index: u32, base: u32) -> f32 {
+	let mut fraction = 1.0;
+	let mut result = 0.0;
+	while index > 0 {
+		fraction /= base as f32;
+		result += fraction * (index % base) as f32;
+		index /= base;
+	}
+	result
+}
+
+// Temporal anti-aliasing: the projection is jittered by a sub-pixel offset
+// every frame and each frame is blended into an accumulation of the previous
+// ones, reprojected with the velocity buffer of `MotionBlurPass`. The
+// accumulation ping-pongs between two images.
+pub struct TaaPass {
+	pipeline: SharedComputePipeline,
+	history: [Arc<StorageImage<Format>>; 2],
+	// Index of the image holding the latest accumulation
+	latest: usize,
+	history_valid: bool,
+	sampler: Arc<Sampler>,
+	frame: u32,
+	dimensions: [u32; 2],
+	pub alpha: f32,
+	pub halton_bases: [u32; 2],
+	pub jitter_period: u32,
+}
+
+impl TaaPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<TaaPass, VulkanoError> {
+		let shader = taa_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		Ok(TaaPass {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			history: [
+				storage_image(&device, dimensions, BLUR_FORMAT)?,
+				storage_image(&device, dimensions, BLUR_FORMAT)?,
+			],
+			latest: 0,
+			history_valid: false,
+			sampler: clamp_to_edge_sampler(device, Filter::Linear)?,
+			frame: 0,
+			dimensions,
+			alpha: 0.1,
+			halton_bases: [2, 3],
+			jitter_period: 8,
+		})
+	}
+
+	// Sub-pixel offset of the current frame, in pixels in [-0.5, 0.5)
+	pub fn jitter(&self) -> [f32; 2] {
+		// The sequence starts at 1, its first point is (0, 0)
+		let index = self.frame % self.jitter_period.max(1) + 1;
+		[
+			halton(index, self.halton_bases[0]) - 0.5,
+			halton(index, self.halton_bases[1]) - 0.5,
+		]
+	}
+
+	// Offsets a column major projection matrix by the current jitter
+	pub fn jitter_projection(&self, mut projection: [[f32; 4]; 4]) -> [[f32; 4]; 4] {
+		let [x, y] = self.jitter();
+		projection[2][0] += x * 2.0 / self.dimensions[0] as f32;
+		projection[2][1] += y * 2.0 / self.dimensions[1] as f32;
+		projection
+	}
+
+	// Discards the accumulation, after a camera cut
+	pub fn reset(&mut self) {
+		self.history_valid = false;
+	}
+
+	// Records the resolve of the current frame and returns the image holding
+	// the anti-aliased result, which is also the history of the next frame
+	pub fn record(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		color: SharedImageView,
+		velocity: Arc<StorageImage<Format>>,
+	) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+		let history = self.history[self.latest].clone();
+		let destination = self.history[1 - self.latest].clone();
+		let alpha = if self.history_valid { self.alpha } else { 1.0 };
+
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(color, self.sampler.clone())?
+				.add_sampled_image(ImageView::new(history)?, self.sampler.clone())?
+				.add_image(ImageView::new(velocity)?)?
+				.add_image(ImageView::new(destination.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "TaaPass", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1],
+			self.pipeline.clone(),
+			set,
+			taa_cs::ty::PushConstants { alpha },
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		self.latest = 1 - self.latest;
+		self.history_valid = true;
+		self.frame = self.frame.wrapping_add(1);
+		Ok(destination)
+	}
+}
+
+// Levels of the min depth pyramid, must match HIZ_LEVELS in the shaders
+pub const HIZ_LEVELS: usize = 5;
+pub const HIZ_FORMAT: Format = Format::R32Sfloat;
+
+mod hiz_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			// The depth buffer, or the previous level of the pyramid
+			layout(set = 0, binding = 0) uniform sampler2D source;
+			layout(set = 0, binding = 1, r32f) uniform writeonly image2D destination;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+
+				// Nearest depth of the 2x2 texels covered by the pixel
+				ivec2 source_size = textureSize(source, 0);
+				float depth = 1.0;
+				for (int y = 0; y < 2; y++) {
+					for (int x = 0; x < 2; x++) {
+						ivec2 texel = min(pixel * 2 + ivec2(x, y), source_size - 1);
+						depth = min(depth, texelFetch(source, texel, 0).r);
+					}
+				}
+				imageStore(destination, pixel, vec4(depth));
+			}
+		"
+	}
+}
+
+mod ssr_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const int HIZ_LEVELS = 5;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			// View space normals, encoded in [0, 1]
+			layout(set = 0, binding = 1) uniform sampler2D normals;
+			layout(set = 0, binding = 2) uniform sampler2D color;
+			layout(set = 0, binding = 3) uniform sampler2D hiz[HIZ_LEVELS];
+			// Reflected color, and how much it can be trusted in alpha
+			layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D reflections;
+
+			layout(set = 0, binding = 5) uniform Matrices {
+				mat4 projection;
+			} m;
+
+			layout(push_constant) uniform PushConstants {
+				int max_steps;
+				// In view space units
+				float max_distance;
+			} pc;
+
+			// Hi-Z levels can't be indexed dynamically without enabling a device feature
+			float hiz_depth(int level, vec2 uv) {
+				switch (level) {
+					case 0: return textureLod(hiz[0], uv, 0.0).r;
+					case 1: return textureLod(hiz[1], uv, 0.0).r;
+					case 2: return textureLod(hiz[2], uv, 0.0).r;
+					case 3: return textureLod(hiz[3], uv, 0.0).r;
+					default: return textureLod(hiz[4], uv, 0.0).r;
+				}
+			}
+
+			vec3 project(vec3 view_position) {
+				vec4 clip = m.projection * vec4(view_position, 1.0);
+				clip.xyz /= clip.w;
+				return vec3(clip.xy * 0.5 + 0.5, clip.z);
+			}
+
+			void main() {
+				ivec2 size = imageSize(reflections);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+				float pixel_depth = textureLod(depth, uv, 0.0).r;
+				if (pixel_depth >= 1.0) {
+					imageStore(reflections, pixel, vec4(0.0));
+					return;
+				}
+
+				vec4 unprojected = inverse(m.projection) * vec4(uv * 2.0 - 1.0, pixel_depth, 1.0);
+				vec3 position = unprojected.xyz / unprojected.w;
+				vec3 normal = normalize(textureLod(normals, uv, 0.0).xyz * 2.0 - 1.0);
+				vec3 direction = reflect(normalize(position), normal);
+
+				// Rays towards the camera are shortened to stay in front of the near plane
+				float distance = pc.max_distance;
+				if (direction.z > 0.0) {
+					distance = min(distance, (-0.01 - position.z) / direction.z);
+				}
+				vec3 start = vec3(uv, pixel_depth);
+				vec3 ray = project(position + direction * distance) - start;
+
+				// The finest step moves by one texel of the first Hi-Z level
+				vec2 hiz_size = vec2(textureSize(hiz[0], 0));
+				float step_length = 1.0 / max(length(ray.xy * hiz_size), 1.0);
+
+				// Steps over the cells the ray passes in front of, going to coarser
+				// levels while it's unobstructed and back to finer ones near surfaces
+				float t = 0.0;
+				int level = 0;
+				bool hit = false;
+				for (int i = 0; i < pc.max_steps; i++) {
+					float next_t = t + step_length * float(1 << level);
+					vec3 next = start + ray * next_t;
+					if (next_t > 1.0 || any(lessThan(next.xy, vec2(0.0))) || any(greaterThan(next.xy, vec2(1.0)))) {
+						break;
+					}
+
+					if (next.z < hiz_depth(level, next.xy)) {
+						t = next_t;
+						level = min(level + 1, HIZ_LEVELS - 1);
+					} else if (level == 0) {
+						t = next_t;
+						hit = true;
+						break;
+					} else {
+						level--;
+					}
+				}
+
+				if (!hit) {
+					imageStore(reflections, pixel, vec4(0.0));
+					return;
+				}
+
+				// Fades out the hits close to the screen edges and far along the ray
+				vec2 hit_uv = (start + ray * t).xy;
+				vec2 edge = abs(hit_uv * 2.0 - 1.0);
+				float confidence = (1.0 - pow(max(edge.x, edge.y), 8.0)) * (1.0 - t);
+				imageStore(reflections, pixel, vec4(textureLod(color, hit_uv, 0.0).rgb, confidence));
+			}
+		"
+	}
+}
+
+mod bilateral_upsample_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			// Depth at the low resolution
+			layout(set = 0, binding = 1) uniform sampler2D low_depth;
+			layout(set = 0, binding = 2) uniform sampler2D low_color;
+			layout(set = 0, binding = 3, rgba16f) uniform writeonly image2D destination;
+
+			// How fast the weight of a low resolution texel drops with its depth difference
+			const float DEPTH_SHARPNESS = 500.0;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+				float pixel_depth = texelFetch(depth, pixel, 0).r;
+
+				ivec2 low_size = textureSize(low_color, 0);
+				vec2 low_position = uv * vec2(low_size) - 0.5;
+				ivec2 base = ivec2(floor(low_position));
+				vec2 f = fract(low_position);
+
+				// Bilinear weights, lowered for the texels of other surfaces
+				vec4 sum = vec4(0.0);
+				float total = 0.0;
+				for (int y = 0; y < 2; y++) {
+					for (int x = 0; x < 2; x++) {
+						ivec2 texel = clamp(base + ivec2(x, y), ivec2(0), low_size - 1);
+						float bilinear = (x == 0 ? 1.0 - f.x : f.x) * (y == 0 ? 1.0 - f.y : f.y);
+						float depth_difference = abs(texelFetch(low_depth, texel, 0).r - pixel_depth);
+						float weight = bilinear * exp(-depth_difference * DEPTH_SHARPNESS);
+						sum += texelFetch(low_color, texel, 0) * weight;
+						total += weight;
+					}
+				}
+				imageStore(destination, pixel, sum / max(total, 1e-5));
+			}
+		"
+	}
+}
+
+// Screen space reflections traced at half resolution. The rays are marched
+// against a pyramid of the nearest depths (Hi-Z), which lets them cross empty
+// regions in a few steps, and the result is brought back to full resolution
+// with a depth aware upsampling. The output holds the reflected color and its
+// confidence in alpha, to blend with the lit scene.
+pub struct SsrPass {
+	hiz_pipeline: SharedComputePipeline,
+	ssr_pipeline: SharedComputePipeline,
+	upsample_pipeline: SharedComputePipeline,
+	hiz: Vec<Arc<StorageImage<Format>>>,
+	half_resolution: Arc<StorageImage<Format>>,
+	output: Arc<StorageImage<Format>>,
+	matrices: CpuBufferPool<ssr_cs::ty::Matrices>,
+	sampler: Arc<Sampler>,
+	dimensions: [u32; 2],
+	pub max_steps: i32,
+	pub max_distance: f32,
+}
+
+impl SsrPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<SsrPass, VulkanoError> {
+		let half = [dimensions[0].div_ceil(2), dimensions[1].div_ceil(2)];
+		let hiz = (0..HIZ_LEVELS)
+			.map(|level| {
+				let size = [(half[0] >> level).max(1), (half[1] >> level).max(1)];
+				storage_image(&device, size, HIZ_FORMAT)
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let hiz_shader = hiz_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let ssr_shader = ssr_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let upsample_shader = bilateral_upsample_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		Ok(SsrPass {
+			hiz_pipeline: Arc::new(ComputePipeline::new(device.clone(), &hiz_shader.main_entry_point(), &(), None)?),
+			ssr_pipeline: Arc::new(ComputePipeline::new(device.clone(), &ssr_shader.main_entry_point(), &(), None)?),
+			upsample_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&upsample_shader.main_entry_point(),
+				&(),
+				None,
+			)?),
+			hiz,
+			half_resolution: storage_image(&device, half, BLUR_FORMAT)?,
+			output: storage_image(&device, dimensions, BLUR_FORMAT)?,
+			matrices: CpuBufferPool::uniform_buffer(device.clone()),
+			// Depths and normals must not be interpolated across surfaces
+			sampler: clamp_to_edge_sampler(device, Filter::Nearest)?,
+			dimensions,
+			max_steps: 64,
+			max_distance: 20.0,
+		})
+	}
+
+	// Records the Hi-Z build, the trace and the upsampling, outside of any
+	// render pass, and returns the full resolution reflections
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: SharedImageView,
+		normals: SharedImageView,
+		color: SharedImageView,
+		projection: [[f32; 4]; 4],
+	) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+		let groups = |[width, height]: [u32; 2]| [width.div_ceil(8), height.div_ceil(8), 1];
+		let size_of = |image: &Arc<StorageImage<Format>>| {
+			let dimensions = image.dimensions();
+			[dimensions.width(), dimensions.height()]
+		};
+
+		let hiz_layout = self
+			.hiz_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		for level in 0..HIZ_LEVELS {
+			let source: SharedImageView = match level {
+				0 => depth.clone(),
+				_ => Arc::new(ImageView::new(self.hiz[level - 1].clone())?),
+			};
+			let set = Arc::new(
+				PersistentDescriptorSet::start(hiz_layout.clone())
+					.add_sampled_image(source, self.sampler.clone())?
+					.add_image(ImageView::new(self.hiz[level].clone())?)?
+					.build()?,
+			);
+			debug_label!(builder, "SsrPass hiz", LABEL_COLOR_COMPUTE);
+			builder.dispatch(groups(size_of(&self.hiz[level])), self.hiz_pipeline.clone(), set, (), vec![])?;
+			debug_label!(builder);
+		}
+
+		let ssr_layout = self
+			.ssr_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		// Each descriptor changes the type of the builder, the levels can't be added in a loop
+		let hiz = |level: usize| ImageView::new(self.hiz[level].clone());
+		let set = Arc::new(
+			PersistentDescriptorSet::start(ssr_layout.clone())
+				.add_sampled_image(depth.clone(), self.sampler.clone())?
+				.add_sampled_image(normals, self.sampler.clone())?
+				.add_sampled_image(color, self.sampler.clone())?
+				.enter_array()?
+				.add_sampled_image(hiz(0)?, self.sampler.clone())?
+				.add_sampled_image(hiz(1)?, self.sampler.clone())?
+				.add_sampled_image(hiz(2)?, self.sampler.clone())?
+				.add_sampled_image(hiz(3)?, self.sampler.clone())?
+				.add_sampled_image(hiz(4)?, self.sampler.clone())?
+				.leave_array()?
+				.add_image(ImageView::new(self.half_resolution.clone())?)?
+				.add_buffer(self.matrices.next(ssr_cs::ty::Matrices { projection })?)?
+				.build()?,
+		);
+		debug_label!(builder, "SsrPass ssr", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			groups(size_of(&self.half_resolution)),
+			self.ssr_pipeline.clone(),
+			set,
+			ssr_cs::ty::PushConstants {
+				max_steps: self.max_steps,
+				max_distance: self.max_distance,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		let upsample_layout = self
+			.upsample_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(upsample_layout.clone())
+				.add_sampled_image(depth, self.sampler.clone())?
+				.add_sampled_image(ImageView::new(self.hiz[0].clone())?, self.sampler.clone())?
+				.add_sampled_image(ImageView::new(self.half_resolution.clone())?, self.sampler.clone())?
+				.add_image(ImageView::new(self.output.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "SsrPass upsample", LABEL_COLOR_COMPUTE);
+		builder.dispatch(groups(self.dimensions), self.upsample_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+
+		Ok(self.output.clone())
+	}
+}
+
+pub const AO_FORMAT: Format = Format::R32Sfloat;
+
+// Random rotations tiled over the screen: cosine and sine of the angle in RG,
+// offset of the first sample along each direction in B. The box blur covers
+// exactly one tile, which averages the rotations away.
+const ROTATION_NOISE_SIZE: u32 = 4;
+const ROTATION_NOISE: [[u8; 4]; 16] = [
+	[70, 242, 38, 255],
+	[53, 24, 18, 255],
+	[3, 99, 93, 255],
+	[247, 173, 129, 255],
+	[251, 157, 111, 255],
+	[243, 182, 23, 255],
+	[14, 186, 211, 255],
+	[218, 217, 57, 255],
+	[39, 36, 242, 255],
+	[15, 68, 101, 255],
+	[254, 109, 12, 255],
+	[208, 28, 74, 255],
+	[206, 228, 30, 255],
+	[82, 246, 208, 255],
+	[181, 243, 148, 255],
+	[46, 30, 95, 255],
+];
+
+mod hbao_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			layout(set = 0, binding = 1) uniform sampler2D rotations;
+			// Ambient visibility, 1 when nothing occludes
+			layout(set = 0, binding = 2, r32f) uniform writeonly image2D occlusion;
+
+			layout(set = 0, binding = 3) uniform Matrices {
+				mat4 projection;
+			} m;
+
+			layout(push_constant) uniform PushConstants {
+				// In view space units
+				float radius;
+				// Sine of the angle the horizons must rise above the surface
+				float bias;
+				// Number of directions
+				int sample_count;
+			} pc;
+
+			const float PI = 3.14159265359;
+			const int STEPS_PER_DIRECTION = 4;
+
+			mat4 inverse_projection;
+
+			vec3 view_position(vec2 uv) {
+				float d = textureLod(depth, uv, 0.0).r;
+				vec4 position = inverse_projection * vec4(uv * 2.0 - 1.0, d, 1.0);
+				return position.xyz / position.w;
+			}
+
+			void main() {
+				ivec2 size = imageSize(occlusion);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 texel = 1.0 / vec2(size);
+				vec2 uv = (vec2(pixel) + 0.5) * texel;
+
+				if (textureLod(depth, uv, 0.0).r >= 1.0) {
+					imageStore(occlusion, pixel, vec4(1.0));
+					return;
+				}
+
+				inverse_projection = inverse(m.projection);
+				vec3 position = view_position(uv);
+
+				// Normal from the neighbours, on the side closest to the pixel to
+				// avoid mixing depths across silhouettes
+				vec3 right = view_position(uv + vec2(texel.x, 0.0)) - position;
+				vec3 left = position - view_position(uv - vec2(texel.x, 0.0));
+				vec3 down = view_position(uv + vec2(0.0, texel.y)) - position;
+				vec3 up = position - view_position(uv - vec2(0.0, texel.y));
+				vec3 dx = dot(right, right) < dot(left, left) ? right : left;
+				vec3 dy = dot(down, down) < dot(up, up) ? down : up;
+				vec3 normal = normalize(cross(dy, dx));
+
+				// The radius projected on the screen
+				vec2 radius_uv = 0.5 * pc.radius * vec2(m.projection[0][0], m.projection[1][1]) / -position.z;
+				vec3 noise = texelFetch(rotations, pixel & 3, 0).xyz;
+				vec2 rotation = noise.xy * 2.0 - 1.0;
+				float radius_squared = pc.radius * pc.radius;
+
+				float ambient_occlusion = 0.0;
+				for (int i = 0; i < pc.sample_count; i++) {
+					float angle = 2.0 * PI * float(i) / float(pc.sample_count);
+					vec2 direction = vec2(cos(angle), sin(angle));
+					direction = vec2(
+						direction.x * rotation.x - direction.y * rotation.y,
+						direction.x * rotation.y + direction.y * rotation.x
+					);
+					vec2 step_uv = direction * radius_uv / float(STEPS_PER_DIRECTION);
+
+					// Each time the horizon rises, the part of the hemisphere between the
+					// previous and the new horizon angles is occluded
+					float horizon = pc.bias;
+					float direction_occlusion = 0.0;
+					for (int j = 0; j < STEPS_PER_DIRECTION; j++) {
+						vec2 sample_uv = uv + step_uv * (float(j) + noise.z);
+						vec3 to_sample = view_position(sample_uv) - position;
+						float distance_squared = dot(to_sample, to_sample);
+						float elevation = dot(normal, to_sample) * inversesqrt(max(distance_squared, 1e-6));
+						if (elevation > horizon) {
+							float falloff = max(0.0, 1.0 - distance_squared / radius_squared);
+							direction_occlusion += (elevation - horizon) * falloff;
+							horizon = elevation;
+						}
+					}
+					ambient_occlusion += direction_occlusion;
+				}
+
+				float visibility = clamp(1.0 - ambient_occlusion / float(max(pc.sample_count, 1)), 0.0, 1.0);
+				imageStore(occlusion, pixel, vec4(visibility));
+			}
+		"
+	}
+}
+
+mod box_blur_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D source;
+			layout(set = 0, binding = 1, r32f) uniform writeonly image2D destination;
+
+			// Same size as the rotation tile
+			const int SIZE = 4;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+
+				float sum = 0.0;
+				for (int y = 0; y < SIZE; y++) {
+					for (int x = 0; x < SIZE; x++) {
+						ivec2 texel = clamp(pixel + ivec2(x, y) - SIZE / 2, ivec2(0), size - 1);
+						sum += texelFetch(source, texel, 0).r;
+					}
+				}
+				imageStore(destination, pixel, vec4(sum / float(SIZE * SIZE)));
+			}
+		"
+	}
+}
+
+// Horizon based ambient occlusion. Several directions around each pixel are
+// walked in screen space, and the occlusion is integrated from how high the
+// reconstructed depth rises above the surface along each of them. The walk
+// directions are rotated per pixel with a tiled 4x4 noise and the result is
+// box blurred over one tile, the output is the ambient visibility the
+// lighting pass multiplies the ambient term with.
+pub struct SsaoPass {
+	hbao_pipeline: SharedComputePipeline,
+	blur_pipeline: SharedComputePipeline,
+	rotations: Arc<ImmutableImage<Format>>,
+	raw: Arc<StorageImage<Format>>,
+	output: Arc<StorageImage<Format>>,
+	matrices: CpuBufferPool<hbao_cs::ty::Matrices>,
+	sampler: Arc<Sampler>,
+	dimensions: [u32; 2],
+	pub radius: f32,
+	pub bias: f32,
+	pub sample_count: i32,
+}
+
+impl SsaoPass {
+	// The returned future uploads the rotation texture, it must be waited on
+	// before the first `record`
+	pub fn new(
+		device: Arc<Device>,
+		queue: Arc<Queue>,
+		dimensions: [u32; 2],
+	) -> Result<(SsaoPass, Box<dyn GpuFuture>), VulkanoError> {
+		let (rotations, rotations_future) = ImmutableImage::from_iter(
+			ROTATION_NOISE.iter().flatten().copied().collect::<Vec<u8>>().into_iter(),
+			ImageDimensions::Dim2d {
+				width: ROTATION_NOISE_SIZE,
+				height: ROTATION_NOISE_SIZE,
+				array_layers: 1,
+			},
+			MipmapsCount::One,
+			Format::R8G8B8A8Unorm,
+			queue,
+		)?;
+
+		let hbao_shader = hbao_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let blur_shader = box_blur_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pass = SsaoPass {
+			hbao_pipeline: Arc::new(ComputePipeline::new(device.clone(), &hbao_shader.main_entry_point(), &(), None)?),
+			blur_pipeline: Arc::new(ComputePipeline::new(device.clone(), &blur_shader.main_entry_point(), &(), None)?),
+			rotations,
+			raw: storage_image(&device, dimensions, AO_FORMAT)?,
+			output: storage_image(&device, dimensions, AO_FORMAT)?,
+			matrices: CpuBufferPool::uniform_buffer(device.clone()),
+			sampler: clamp_to_edge_sampler(device, Filter::Nearest)?,
+			dimensions,
+			radius: 0.5,
+			bias: 0.1,
+			sample_count: 8,
+		};
+		Ok((pass, rotations_future.boxed()))
+	}
+
+	// Records the occlusion and its blur outside of any render pass, and
+	// returns the blurred visibility
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: SharedImageView,
+		projection: [[f32; 4]; 4],
+	) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+		let groups = [self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1];
+
+		let hbao_layout = self
+			.hbao_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(hbao_layout.clone())
+				.add_sampled_image(depth, self.sampler.clone())?
+				.add_sampled_image(ImageView::new(self.rotations.clone())?, self.sampler.clone())?
+				.add_image(ImageView::new(self.raw.clone())?)?
+				.add_buffer(self.matrices.next(hbao_cs::ty::Matrices { projection })?)?
+				.build()?,
+		);
+		debug_label!(builder, "SsaoPass hbao", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			groups,
+			self.hbao_pipeline.clone(),
+			set,
+			hbao_cs::ty::PushConstants {
+				radius: self.radius,
+				bias: self.bias,
+				sample_count: self.sample_count,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		let blur_layout = self
+			.blur_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(blur_layout.clone())
+				.add_sampled_image(ImageView::new(self.raw.clone())?, self.sampler.clone())?
+				.add_image(ImageView::new(self.output.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "SsaoPass blur", LABEL_COLOR_COMPUTE);
+		builder.dispatch(groups, self.blur_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+
+		Ok(self.output.clone())
+	}
+}
+
+pub const FOG_NOISE_SIZE: u32 = 32;
+pub const FOG_NOISE_FORMAT: Format = Format::R32Sfloat;
+
+mod fog_noise_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 4, local_size_y = 4, local_size_z = 4) in;
+
+			layout(set = 0, binding = 0, r32f) uniform writeonly image3D noise;
+
+			// Feature points per axis, the noise tiles seamlessly
+			const int CELLS = 4;
+
+			vec3 feature_point(ivec3 cell) {
+				vec3 p = vec3(cell);
+				return fract(sin(vec3(
+					dot(p, vec3(127.1, 311.7, 74.7)),
+					dot(p, vec3(269.5, 183.3, 246.1)),
+					dot(p, vec3(113.5, 271.9, 124.6))
+				)) * 43758.5453);
+			}
+
+			void main() {
+				ivec3 size = imageSize(noise);
+				ivec3 voxel = ivec3(gl_GlobalInvocationID);
+				if (any(greaterThanEqual(voxel, size))) {
+					return;
+				}
+
+				// Inverted Worley noise: bright close to the feature points
+				vec3 position = (vec3(voxel) + 0.5) / vec3(size) * float(CELLS);
+				ivec3 cell = ivec3(floor(position));
+				float nearest = 1.0;
+				for (int z = -1; z <= 1; z++) {
+					for (int y = -1; y <= 1; y++) {
+						for (int x = -1; x <= 1; x++) {
+							ivec3 neighbor = cell + ivec3(x, y, z);
+							vec3 point = vec3(neighbor) + feature_point((neighbor + CELLS) % CELLS);
+							nearest = min(nearest, distance(position, point));
+						}
+					}
+				}
+				imageStore(noise, voxel, vec4(1.0 - nearest));
+			}
+		"
+	}
+}
+
+mod fog_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			layout(set = 0, binding = 1) uniform sampler3D noise;
+			layout(set = 0, binding = 2) uniform sampler2D history;
+			// In-scattered light in RGB, transmittance in A
+			layout(set = 0, binding = 3, rgba16f) uniform writeonly image2D fog;
+
+			layout(set = 0, binding = 4) uniform Camera {
+				mat4 view_projection;
+				mat4 previous_view_projection;
+				vec4 camera_position;
+				// Towards the light
+				vec4 light_direction;
+			} c;
+
+			layout(push_constant) uniform PushConstants {
+				vec3 scatter_color;
+				float density;
+				// Henyey-Greenstein g, positive values scatter forward
+				float anisotropy;
+				// Offset of the samples along the ray, in steps
+				float jitter;
+				// Weight of the current frame, 1 discards the history
+				float alpha;
+				int steps;
+			} pc;
+
+			const float PI = 3.14159265359;
+			// Rays through the sky stop at this distance
+			const float MAX_DISTANCE = 100.0;
+			// World units covered by one tile of the noise
+			const float NOISE_TILE = 16.0;
+
+			float henyey_greenstein(float cos_theta, float g) {
+				float g2 = g * g;
+				return (1.0 - g2) / (4.0 * PI * pow(1.0 + g2 - 2.0 * g * cos_theta, 1.5));
+			}
+
+			void main() {
+				ivec2 size = imageSize(fog);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+				float pixel_depth = textureLod(depth, uv, 0.0).r;
+				vec4 surface = inverse(c.view_projection) * vec4(uv * 2.0 - 1.0, pixel_depth, 1.0);
+				vec3 origin = c.camera_position.xyz;
+				vec3 to_surface = surface.xyz / surface.w - origin;
+				vec3 direction = normalize(to_surface);
+				float ray_length = pixel_depth >= 1.0 ? MAX_DISTANCE : min(length(to_surface), MAX_DISTANCE);
+				float step_size = ray_length / float(max(pc.steps, 1));
+
+				float phase = henyey_greenstein(dot(direction, c.light_direction.xyz), pc.anisotropy);
+
+				// Front to back: the light scattered by each step is attenuated by
+				// everything in front of it
+				vec3 scattered = vec3(0.0);
+				float transmittance = 1.0;
+				for (int i = 0; i < pc.steps; i++) {
+					vec3 position = origin + direction * (float(i) + pc.jitter) * step_size;
+					float density = pc.density * textureLod(noise, position / NOISE_TILE, 0.0).r;
+					float step_transmittance = exp(-density * step_size);
+					scattered += transmittance * (1.0 - step_transmittance) * pc.scatter_color * phase;
+					transmittance *= step_transmittance;
+				}
+				vec4 result = vec4(scattered, transmittance);
+
+				if (pc.alpha < 1.0) {
+					vec4 previous = c.previous_view_projection * vec4(origin + direction * ray_length, 1.0);
+					vec2 previous_uv = previous.xy / previous.w * 0.5 + 0.5;
+					if (all(greaterThanEqual(previous_uv, vec2(0.0))) && all(lessThanEqual(previous_uv, vec2(1.0)))) {
+						result = mix(textureLod(history, previous_uv, 0.0), result, pc.alpha);
+					}
+				}
+				imageStore(fog, pixel, result);
+			}
+		"
+	}
+}
+
+mod fog_composite_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D color;
+			layout(set = 0, binding = 1) uniform sampler2D fog;
+			layout(set = 0, binding = 2, rgba16f) uniform writeonly image2D destination;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec4 scene = texelFetch(color, pixel, 0);
+				vec4 fog_sample = texelFetch(fog, pixel, 0);
+				imageStore(destination, pixel, vec4(scene.rgb * fog_sample.a + fog_sample.rgb, scene.a));
+			}
+		"
+	}
+}
+
+// Volumetric fog lit by a single directional light. Rays are marched from the
+// camera to the depth buffer through a density modulated by a tiling 3D
+// Worley noise, which is generated once by a compute shader. With `temporal`
+// the sample offsets change every frame and the fog is blended with the
+// previous frame's, reprojected at the end of each ray, so fewer steps are
+// needed for a smooth result.
+pub struct VolumetricFogPass {
+	fog_pipeline: SharedComputePipeline,
+	composite_pipeline: SharedComputePipeline,
+	noise: Arc<StorageImage<Format>>,
+	fog: [Arc<StorageImage<Format>>; 2],
+	// Index of the image holding the latest fog
+	latest: usize,
+	history_valid: bool,
+	camera: CpuBufferPool<fog_cs::ty::Camera>,
+	previous_view_projection: Option<[[f32; 4]; 4]>,
+	noise_sampler: Arc<Sampler>,
+	sampler: Arc<Sampler>,
+	frame: u32,
+	dimensions: [u32; 2],
+	pub density: f32,
+	pub scatter_color: [f32; 3],
+	pub anisotropy: f32,
+	pub steps: i32,
+	pub temporal: bool,
+	pub temporal_alpha: f32,
+	// Normalized, towards the light
+	pub light_direction: [f32; 3],
+}
+
+impl VolumetricFogPass {
+	// The returned future generates the noise, it must be waited on before the
+	// first `record`
+	pub fn new(
+		device: Arc<Device>,
+		queue: Arc<Queue>,
+		dimensions: [u32; 2],
+	) -> Result<(VolumetricFogPass, Box<dyn GpuFuture>), VulkanoError> {
+		let noise = StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim3d {
+				width: FOG_NOISE_SIZE,
+				height: FOG_NOISE_SIZE,
+				depth: FOG_NOISE_SIZE,
+			},
+			FOG_NOISE_FORMAT,
+			ImageUsage {
+				storage: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)?;
+
+		let noise_shader = fog_noise_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let noise_pipeline = Arc::new(ComputePipeline::new(
+			device.clone(),
+			&noise_shader.main_entry_point(),
+			&(),
+			None,
+		)?);
+		let layout = noise_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(noise.clone())?)?
+				.build()?,
+		);
+		let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family())?;
+		let groups = FOG_NOISE_SIZE.div_ceil(4);
+		debug_label!(builder, "VolumetricFogPass noise", LABEL_COLOR_COMPUTE);
+		builder.dispatch([groups, groups, groups], noise_pipeline, set, (), vec![])?;
+		debug_label!(builder);
+		let noise_future = vulkano::sync::now(device.clone()).then_execute(queue, builder.build()?)?;
+
+		let fog_shader = fog_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let composite_shader = fog_composite_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pass = VolumetricFogPass {
+			fog_pipeline: Arc::new(ComputePipeline::new(device.clone(), &fog_shader.main_entry_point(), &(), None)?),
+			composite_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&composite_shader.main_entry_point(),
+				&(),
+				None,
+			)?),
+			noise,
+			fog: [
+				storage_image(&device, dimensions, BLUR_FORMAT)?,
+				storage_image(&device, dimensions, BLUR_FORMAT)?,
+			],
+			latest: 0,
+			history_valid: false,
+			camera: CpuBufferPool::uniform_buffer(device.clone()),
+			previous_view_projection: None,
+			noise_sampler: Sampler::new(
+				device.clone(),
+				Filter::Linear,
+				Filter::Linear,
+				MipmapMode::Nearest,
+				SamplerAddressMode::Repeat,
+				SamplerAddressMode::Repeat,
+				SamplerAddressMode::Repeat,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)?,
+			sampler: clamp_to_edge_sampler(device, Filter::Nearest)?,
+			frame: 0,
+			dimensions,
+			density: 0.05,
+			scatter_color: [1.0, 1.0, 1.0],
+			anisotropy: 0.3,
+			steps: 32,
+			temporal: true,
+			temporal_alpha: 0.1,
+			light_direction: [0.0, 1.0, 0.0],
+		};
+		Ok((pass, noise_future.boxed()))
+	}
+
+	// Records the ray march and the composite over `color` into `destination`
+	// (RGBA16F), outside of any render pass
+	pub fn record(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: SharedImageView,
+		color: SharedImageView,
+		destination: Arc<StorageImage<Format>>,
+		view_projection: [[f32; 4]; 4],
+		camera_position: [f32; 3],
+	) -> Result<(), VulkanoError> {
+		let previous_view_projection = self.previous_view_projection.replace(view_projection);
+		let [x, y, z] = camera_position;
+		let [lx, ly, lz] = self.light_direction;
+		let camera = self.camera.next(fog_cs::ty::Camera {
+			view_projection,
+			previous_view_projection: previous_view_projection.unwrap_or(view_projection),
+			camera_position: [x, y, z, 1.0],
+			light_direction: [lx, ly, lz, 0.0],
+		})?;
+
+		let history = self.fog[self.latest].clone();
+		let current = self.fog[1 - self.latest].clone();
+		let (alpha, jitter) = if self.temporal {
+			let alpha = if self.history_valid { self.temporal_alpha } else { 1.0 };
+			(alpha, halton(self.frame % 8 + 1, 2))
+		} else {
+			(1.0, 0.5)
+		};
+
+		let groups = [self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1];
+
+		let layout = self
+			.fog_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(depth, self.sampler.clone())?
+				.add_sampled_image(ImageView::new(self.noise.clone())?, self.noise_sampler.clone())?
+				.add_sampled_image(ImageView::new(history)?, self.sampler.clone())?
+				.add_image(ImageView::new(current.clone())?)?
+				.add_buffer(camera)?
+				.build()?,
+		);
+		debug_label!(builder, "VolumetricFogPass fog", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			groups,
+			self.fog_pipeline.clone(),
+			set,
+			fog_cs::ty::PushConstants {
+				scatter_color: self.scatter_color,
+				density: self.density,
+				anisotropy: self.anisotropy,
+				jitter,
+				alpha,
+				steps: self.steps,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		let layout = self
+			.composite_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(color, self.sampler.clone())?
+				.add_sampled_image(ImageView::new(current)?, self.sampler.clone())?
+				.add_image(ImageView::new(destination)?)?
+				.build()?,
+		);
+		debug_label!(builder, "VolumetricFogPass composite", LABEL_COLOR_COMPUTE);
+		builder.dispatch(groups, self.composite_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+
+		self.latest = 1 - self.latest;
+		self.history_valid = true;
+		self.frame = self.frame.wrapping_add(1);
+		Ok(())
+	}
+}
+
+mod flare_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 position;
+			// In [-1, 1] over the sprite
+			layout(location = 1) in vec2 uv;
+			layout(location = 2) in vec4 color;
+			layout(location = 3) in uint shape;
+
+			layout(location = 0) out vec2 v_uv;
+			layout(location = 1) out vec4 v_color;
+			layout(location = 2) flat out uint v_shape;
+
+			void main() {
+				v_uv = uv;
+				v_color = color;
+				v_shape = shape;
+				gl_Position = vec4(position, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod flare_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+			layout(location = 1) in vec4 v_color;
+			layout(location = 2) flat in uint v_shape;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(push_constant) uniform PushConstants {
+				// Relative scale difference between the red and blue channels
+				float chromatic_offset;
+			} pc;
+
+			const float PI = 3.14159265359;
+			const uint SHAPE_CIRCLE = 0;
+			const uint SHAPE_RING = 1;
+			const uint SHAPE_HEXAGON = 2;
+			const uint SHAPE_GLOW = 3;
+
+			// Intensity of the shape at a point given in polar coordinates, the
+			// distances are signed, negative inside
+			float shape_intensity(float radius, float angle) {
+				float edge = fwidth(radius) + 0.02;
+				switch (v_shape) {
+					case SHAPE_CIRCLE:
+						return 1.0 - smoothstep(-edge, edge, radius - 0.9);
+					case SHAPE_RING:
+						return 1.0 - smoothstep(-edge, edge, abs(radius - 0.8) - 0.08);
+					case SHAPE_HEXAGON: {
+						// Distance to the nearest side, the sides are PI / 3 apart
+						float side_angle = mod(angle + PI / 6.0, PI / 3.0) - PI / 6.0;
+						float distance = radius * cos(side_angle) - 0.8;
+						return 1.0 - smoothstep(-edge, edge, distance);
+					}
+					default:
+						return pow(max(1.0 - radius, 0.0), 3.0);
+				}
+			}
+
+			void main() {
+				float angle = atan(v_uv.y, v_uv.x);
+				float radius = length(v_uv);
+				// Each channel sees the shape at a slightly different size, like a lens
+				// refracting wavelengths differently
+				vec3 intensity = vec3(
+					shape_intensity(radius * (1.0 - pc.chromatic_offset), angle),
+					shape_intensity(radius, angle),
+					shape_intensity(radius * (1.0 + pc.chromatic_offset), angle)
+				);
+				f_color = vec4(v_color.rgb * intensity, v_color.a);
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct FlareVertex {
+	position: [f32; 2],
+	uv: [f32; 2],
+	color: [f32; 4],
+	shape: u32,
+}
+vulkano::impl_vertex!(FlareVertex, position, uv, color, shape);
+
+// Must match the SHAPE_ constants of the fragment shader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlareShape {
+	Circle = 0,
+	Ring = 1,
+	Hexagon = 2,
+	Glow = 3,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FlareSprite {
+	pub shape: FlareShape,
+	// Radius, in units of the viewport height
+	pub size: f32,
+	pub color: [f32; 4],
+}
+
+// Lights fade out over this distance from the screen edges, in NDC
+const FLARE_EDGE_FADE: f32 = 0.2;
+
+// Lens flares of point lights: a chain of sprites is drawn at evenly spaced
+// positions from each light, projected on the screen, to the screen center.
+// The sprites are procedural, each shape being a distance function of the
+// polar coordinates in the sprite, and are blended additively. The flare of a
+// light fades out as it gets close to the edges of the screen.
+pub struct LensFlarePass {
+	pipeline: SharedPipeline,
+	vertex_pool: CpuBufferPool<FlareVertex>,
+	vertices: Vec<FlareVertex>,
+	pub sprites: Vec<FlareSprite>,
+	pub chromatic_offset: f32,
+}
+
+impl LensFlarePass {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<LensFlarePass, VulkanoError> {
+		let vs = flare_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = flare_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		// The destination alpha is kept, flares only add light
+		let additive = AttachmentBlend {
+			enabled: true,
+			color_op: BlendOp::Add,
+			color_source: BlendFactor::SrcAlpha,
+			color_destination: BlendFactor::One,
+			alpha_op: BlendOp::Add,
+			alpha_source: BlendFactor::Zero,
+			alpha_destination: BlendFactor::One,
+			mask_red: true,
+			mask_green: true,
+			mask_blue: true,
+			mask_alpha: true,
+		};
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<FlareVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.blend_collective(additive)
+			.render_pass(subpass)
+			.build(device.clone())?;
+
+		let sprite = |shape, size, color| FlareSprite { shape, size, color };
+		Ok(LensFlarePass {
+			pipeline: Arc::new(pipeline),
+			vertex_pool: CpuBufferPool::vertex_buffer(device),
+			vertices: Vec::new(),
+			sprites: vec![
+				sprite(FlareShape::Glow, 0.3, [1.0, 0.9, 0.7, 0.6]),
+				sprite(FlareShape::Hexagon, 0.05, [0.6, 0.8, 1.0, 0.25]),
+				sprite(FlareShape::Circle, 0.03, [1.0, 0.6, 0.3, 0.3]),
+				sprite(FlareShape::Ring, 0.1, [0.5, 1.0, 0.6, 0.2]),
+				sprite(FlareShape::Hexagon, 0.08, [0.8, 0.5, 1.0, 0.2]),
+				sprite(FlareShape::Circle, 0.02, [1.0, 1.0, 1.0, 0.3]),
+			],
+			chromatic_offset: 0.05,
+		})
+	}
+
+	// Queues the flare of a light at `position` in world space, `view_projection`
+	// being column major
+	pub fn add_light(&mut self, position: [f32; 3], view_projection: [[f32; 4]; 4], aspect_ratio: f32) {
+		let m = view_projection;
+		let [x, y, z] = position;
+		let clip = |row: usize| m[0][row] * x + m[1][row] * y + m[2][row] * z + m[3][row];
+		let w = clip(3);
+		// Behind the camera
+		if w <= 0.0 {
+			return;
+		}
+		let source = [clip(0) / w, clip(1) / w];
+
+		let edge_distance = 1.0 - source[0].abs().max(source[1].abs());
+		let fade = (edge_distance / FLARE_EDGE_FADE).clamp(0.0, 1.0);
+		if fade == 0.0 {
+			return;
+		}
+
+		let count = self.sprites.len();
+		for (i, sprite) in self.sprites.iter().enumerate() {
+			let t = if count > 1 { i as f32 / (count - 1) as f32 } else { 0.0 };
+			let center = [source[0] * (1.0 - t), source[1] * (1.0 - t)];
+			let half = [sprite.size / aspect_ratio, sprite.size];
+			let color = [sprite.color[0], sprite.color[1], sprite.color[2], sprite.color[3] * fade];
+
+			let vertex = |u: f32, v: f32| FlareVertex {
+				position: [center[0] + u * half[0], center[1] + v * half[1]],
+				uv: [u, v],
+				color,
+				shape: sprite.shape as u32,
+			};
+			self.vertices.extend_from_slice(&[
+				vertex(-1.0, -1.0),
+				vertex(1.0, -1.0),
+				vertex(-1.0, 1.0),
+				vertex(1.0, -1.0),
+				vertex(1.0, 1.0),
+				vertex(-1.0, 1.0),
+			]);
+		}
+	}
+
+	// Records the flares queued since the last flush, inside the current render
+	// pass, over the scene
+	pub fn flush(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+	) -> Result<(), VulkanoError> {
+		if self.vertices.is_empty() {
+			return Ok(());
+		}
+
+		let vertices = self.vertex_pool.chunk(self.vertices.drain(..))?;
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![Arc::new(vertices) as Arc<dyn BufferAccess + Send + Sync>],
+			(),
+			flare_fs::ty::PushConstants {
+				chromatic_offset: self.chromatic_offset,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+mod chromatic_aberration_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 0) uniform sampler2D color;
+
+			layout(push_constant) uniform PushConstants {
+				// Offset of the blue channel at the screen corners, in UV units
+				float strength;
+				// Barrel distortion coefficient, 0 disables it
+				float distortion;
+			} pc;
+
+			void main() {
+				vec2 centered = v_uv * 2.0 - 1.0;
+				float radius_squared = dot(centered, centered);
+				centered *= 1.0 + pc.distortion * radius_squared;
+
+				// Red isn't moved, green and blue are pushed further out towards the edges
+				vec2 offset = centered * pc.strength * radius_squared * 0.5;
+				vec2 uv = centered * 0.5 + 0.5;
+				f_color = vec4(
+					texture(color, uv).r,
+					texture(color, uv - offset * 0.5).g,
+					texture(color, uv - offset).b,
+					texture(color, uv).a
+				);
+			}
+		"
+	}
+}
+
+// Full screen pass splitting the color channels radially, optionally with a
+// barrel distortion. While disabled the source is copied unchanged, so the
+// pass can be toggled without rebuilding the frame graph.
+pub struct ChromaticAberrationPass {
+	pipeline: Arc<BufferlessPipeline>,
+	sampler: Arc<Sampler>,
+	pub enabled: bool,
+	pub strength: f32,
+	pub distortion: f32,
+}
+
+impl ChromaticAberrationPass {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<ChromaticAberrationPass, VulkanoError> {
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = chromatic_aberration_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input(BufferlessDefinition {})
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+
+		Ok(ChromaticAberrationPass {
+			pipeline,
+			sampler: clamp_to_edge_sampler(device, Filter::Linear)?,
+			enabled: true,
+			strength: 0.01,
+			distortion: 0.0,
+		})
+	}
+
+	pub fn toggle(&mut self) {
+		self.enabled = !self.enabled;
+	}
+
+	// Records the draw of `color` over the whole framebuffer, inside the current render pass
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		color: SharedImageView,
+	) -> Result<(), VulkanoError> {
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(color, self.sampler.clone())?
+				.build()?,
+		);
+
+		let push_constants = if self.enabled {
+			chromatic_aberration_fs::ty::PushConstants {
+				strength: self.strength,
+				distortion: self.distortion,
+			}
+		} else {
+			chromatic_aberration_fs::ty::PushConstants {
+				strength: 0.0,
+				distortion: 0.0,
+			}
+		};
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			BufferlessVertices {
+				vertices: 3,
+				instances: 1,
+			},
+			set,
+			push_constants,
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+mod coc_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			// Signed radius in pixels, negative in front of the focus distance
+			layout(set = 0, binding = 1, r32f) uniform writeonly image2D coc;
+
+			layout(set = 0, binding = 2) uniform Matrices {
+				mat4 projection;
+			} m;
+
+			layout(push_constant) uniform PushConstants {
+				// In millimeters
+				float focal_length;
+				float f_stop;
+				// In world units, taken as meters
+				float focus_distance;
+				// Height of the sensor in millimeters, 24 for full frame
+				float sensor_height;
+				float max_radius;
+			} pc;
+
+			void main() {
+				ivec2 size = imageSize(coc);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+				vec4 position = inverse(m.projection) * vec4(uv * 2.0 - 1.0, texelFetch(depth, pixel, 0).r, 1.0);
+				float distance = -position.z / position.w;
+
+				// Thin lens: diameter of the image of a point at `distance` on the sensor
+				float f = pc.focal_length * 0.001;
+				float aperture = f / pc.f_stop;
+				float s = pc.focus_distance;
+				float diameter = aperture * f * (distance - s) / (distance * max(s - f, 1e-4));
+				float radius = 0.5 * diameter / (pc.sensor_height * 0.001) * float(size.y);
+				imageStore(coc, pixel, vec4(clamp(radius, -pc.max_radius, pc.max_radius)));
+			}
+		"
+	}
+}
+
+mod coc_tiles_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			// One workgroup per tile
+			layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0, r32f) uniform readonly image2D coc;
+			// Largest far and near radii of each tile
+			layout(set = 0, binding = 1, rg16f) uniform writeonly image2D tiles;
+
+			// The radii are positive so their bits sort like them
+			shared uint max_far;
+			shared uint max_near;
+
+			void main() {
+				if (gl_LocalInvocationIndex == 0) {
+					max_far = 0;
+					max_near = 0;
+				}
+				barrier();
+
+				ivec2 size = imageSize(coc);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x < size.x && pixel.y < size.y) {
+					float radius = imageLoad(coc, pixel).r;
+					atomicMax(max_far, floatBitsToUint(max(radius, 0.0)));
+					atomicMax(max_near, floatBitsToUint(max(-radius, 0.0)));
+				}
+				barrier();
+
+				if (gl_LocalInvocationIndex == 0) {
+					imageStore(tiles, ivec2(gl_WorkGroupID.xy), vec4(uintBitsToFloat(max_far), uintBitsToFloat(max_near), 0.0, 0.0));
+				}
+			}
+		"
+	}
+}
+
+mod bokeh_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			// Blades of the aperture, 0 for a circle
+			layout(constant_id = 0) const int blade_count = 6;
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D color;
+			layout(set = 0, binding = 1, r32f) uniform readonly image2D coc;
+			layout(set = 0, binding = 2, rg16f) uniform readonly image2D tiles;
+			// The far field, then the near field with its coverage in A
+			layout(set = 0, binding = 3, rgba16f) uniform writeonly image2D far_field;
+			layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D near_field;
+
+			layout(push_constant) uniform PushConstants {
+				// Rings of the kernel, ring i has 8 * i samples
+				int rings;
+			} pc;
+
+			const float PI = 3.14159265359;
+			const int TILE_SIZE = 16;
+
+			// Distance to the edge of the aperture along `angle`, 1 at the corners
+			float aperture_edge(float angle) {
+				if (blade_count < 3) {
+					return 1.0;
+				}
+				float sector = 2.0 * PI / float(blade_count);
+				return cos(PI / float(blade_count)) / cos(mod(angle, sector) - sector * 0.5);
+			}
+
+			// How much of a pixel a disc of `radius` at `distance` covers
+			float coverage(float radius, float distance) {
+				return clamp(radius - distance + 1.0, 0.0, 1.0);
+			}
+
+			void main() {
+				ivec2 size = imageSize(coc);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 texel = 1.0 / vec2(size);
+
+				// The kernel must reach the pixels of the neighbouring tiles that spill over this one
+				ivec2 tile = pixel / TILE_SIZE;
+				ivec2 tile_count = imageSize(tiles);
+				float kernel_radius = 0.0;
+				for (int y = -1; y <= 1; y++) {
+					for (int x = -1; x <= 1; x++) {
+						vec2 radii = imageLoad(tiles, clamp(tile + ivec2(x, y), ivec2(0), tile_count - 1)).rg;
+						kernel_radius = max(kernel_radius, max(radii.r, radii.g));
+					}
+				}
+
+				float center_coc = imageLoad(coc, pixel).r;
+				vec4 center = texelFetch(color, pixel, 0);
+				if (kernel_radius < 0.5) {
+					imageStore(far_field, pixel, center);
+					imageStore(near_field, pixel, vec4(0.0));
+					return;
+				}
+
+				// The center sample, the far field only gathers the pixels behind it
+				// up to its own radius so the background doesn't bleed over it
+				float center_far = max(center_coc, 0.0);
+				vec4 far_sum = vec4(center.rgb, 1.0);
+				vec4 near_sum = vec4(0.0);
+				int samples = 1;
+				for (int ring = 1; ring <= pc.rings; ring++) {
+					int ring_samples = ring * 8;
+					float ring_radius = kernel_radius * float(ring) / float(pc.rings);
+					for (int i = 0; i < ring_samples; i++) {
+						float angle = 2.0 * PI * (float(i) + 0.5 * float(ring & 1)) / float(ring_samples);
+						float distance = ring_radius * aperture_edge(angle);
+						vec2 offset = vec2(cos(angle), sin(angle)) * distance;
+						ivec2 sample_pixel = clamp(pixel + ivec2(round(offset)), ivec2(0), size - 1);
+						float sample_coc = imageLoad(coc, sample_pixel).r;
+						vec3 sample_color = texelFetch(color, sample_pixel, 0).rgb;
+
+						float far_weight = coverage(min(max(sample_coc, 0.0), center_far), distance);
+						far_sum += vec4(sample_color, 1.0) * far_weight;
+						float near_weight = coverage(max(-sample_coc, 0.0), distance);
+						near_sum += vec4(sample_color, 1.0) * near_weight;
+						samples++;
+					}
+				}
+
+				imageStore(far_field, pixel, vec4(far_sum.rgb / far_sum.a, center.a));
+				// The foreground covers the pixel as much as its samples cover the kernel
+				float near_alpha = clamp(2.0 * near_sum.a / float(samples), 0.0, 1.0);
+				vec3 near_color = near_sum.a > 0.0 ? near_sum.rgb / near_sum.a : vec3(0.0);
+				imageStore(near_field, pixel, vec4(near_color, near_alpha));
+			}
+		"
+	}
+}
+
+mod dof_composite_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D color;
+			layout(set = 0, binding = 1, r32f) uniform readonly image2D coc;
+			layout(set = 0, binding = 2, rgba16f) uniform readonly image2D far_field;
+			layout(set = 0, binding = 3, rgba16f) uniform readonly image2D near_field;
+			layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D destination;
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+
+				// The sharp pixels in focus fade into the far field as their radius grows
+				vec4 sharp = texelFetch(color, pixel, 0);
+				float far_blend = clamp(imageLoad(coc, pixel).r, 0.0, 1.0);
+				vec3 result = mix(sharp.rgb, imageLoad(far_field, pixel).rgb, far_blend);
+
+				vec4 near = imageLoad(near_field, pixel);
+				result = mix(result, near.rgb, near.a);
+				imageStore(destination, pixel, vec4(result, sharp.a));
+			}
+		"
+	}
+}
+
+pub const COC_FORMAT: Format = Format::R32Sfloat;
+const COC_TILE_FORMAT: Format = Format::R16G16Sfloat;
+// Must match the workgroup size of the tile pass
+const COC_TILE_SIZE: u32 = 16;
+
+// Shape of the bokeh, the number of blades of the aperture
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApertureShape {
+	Circle,
+	Pentagon,
+	Hexagon,
+}
+
+impl ApertureShape {
+	fn blade_count(self) -> i32 {
+		match self {
+			ApertureShape::Circle => 0,
+			ApertureShape::Pentagon => 5,
+			ApertureShape::Hexagon => 6,
+		}
+	}
+}
+
+// Thin lens depth of field. The circle of confusion of each pixel is computed
+// from its depth and the lens (focal length, f-stop, focus distance), and the
+// largest far and near radii of every 16x16 tile are kept so the gather
+// kernel is sized by the neighbouring tiles and catches the blur spilling
+// from them. The gather walks rings of samples stretched to the aperture
+// shape, a specialization constant, into a far field that doesn't bleed over
+// the pixels in front of it and a near field that does, which are then
+// composited over the sharp color.
+pub struct DofPass {
+	coc_pipeline: SharedComputePipeline,
+	tiles_pipeline: SharedComputePipeline,
+	bokeh_pipeline: SharedComputePipeline,
+	composite_pipeline: SharedComputePipeline,
+	matrices: CpuBufferPool<coc_cs::ty::Matrices>,
+	coc: Arc<StorageImage<Format>>,
+	tiles: Arc<StorageImage<Format>>,
+	far_field: Arc<StorageImage<Format>>,
+	near_field: Arc<StorageImage<Format>>,
+	sampler: Arc<Sampler>,
+	dimensions: [u32; 2],
+	// In millimeters
+	pub focal_length: f32,
+	pub f_stop: f32,
+	pub focus_distance: f32,
+	pub sensor_height: f32,
+	// In pixels, bounds the cost of the gather
+	pub max_radius: f32,
+	pub rings: i32,
+}
+
+impl DofPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2], aperture: ApertureShape) -> Result<DofPass, VulkanoError> {
+		let coc_shader = coc_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let tiles_shader = coc_tiles_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let bokeh_shader = bokeh_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let composite_shader = dof_composite_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let tile_dimensions = [
+			dimensions[0].div_ceil(COC_TILE_SIZE),
+			dimensions[1].div_ceil(COC_TILE_SIZE),
+		];
+
+		Ok(DofPass {
+			coc_pipeline: Arc::new(ComputePipeline::new(device.clone(), &coc_shader.main_entry_point(), &(), None)?),
+			tiles_pipeline: Arc::new(ComputePipeline::new(device.clone(), &tiles_shader.main_entry_point(), &(), None)?),
+			bokeh_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&bokeh_shader.main_entry_point(),
+				&bokeh_cs::SpecializationConstants {
+					blade_count: aperture.blade_count(),
+				},
+				None,
+			)?),
+			composite_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&composite_shader.main_entry_point(),
+				&(),
+				None,
+			)?),
+			matrices: CpuBufferPool::uniform_buffer(device.clone()),
+			coc: storage_image(&device, dimensions, COC_FORMAT)?,
+			tiles: storage_image(&device, tile_dimensions, COC_TILE_FORMAT)?,
+			far_field: storage_image(&device, dimensions, BLUR_FORMAT)?,
+			near_field: storage_image(&device, dimensions, BLUR_FORMAT)?,
+			sampler: clamp_to_edge_sampler(device, Filter::Nearest)?,
+			dimensions,
+			focal_length: 50.0,
+			f_stop: 2.8,
+			focus_distance: 5.0,
+			sensor_height: 24.0,
+			max_radius: 16.0,
+			rings: 4,
+		})
+	}
+
+	// The signed circle of confusion radius in pixels of the last recorded frame
+	pub fn coc(&self) -> Arc<StorageImage<Format>> {
+		self.coc.clone()
+	}
+
+	// Records the passes for a frame outside of any render pass, `depth` and
+	// `color` are views of the frame's sampled depth and color images, the
+	// result is written to `destination` (RGBA16F)
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: SharedImageView,
+		color: SharedImageView,
+		destination: Arc<StorageImage<Format>>,
+		projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		let groups = [self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1];
+
+		let layout = self
+			.coc_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(depth, self.sampler.clone())?
+				.add_image(ImageView::new(self.coc.clone())?)?
+				.add_buffer(self.matrices.next(coc_cs::ty::Matrices { projection })?)?
+				.build()?,
+		);
+		debug_label!(builder, "DofPass coc", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			groups,
+			self.coc_pipeline.clone(),
+			set,
+			coc_cs::ty::PushConstants {
+				focal_length: self.focal_length,
+				f_stop: self.f_stop,
+				focus_distance: self.focus_distance,
+				sensor_height: self.sensor_height,
+				max_radius: self.max_radius,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		let layout = self
+			.tiles_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(self.coc.clone())?)?
+				.add_image(ImageView::new(self.tiles.clone())?)?
+				.build()?,
+		);
+		let tile_groups = [
+			self.dimensions[0].div_ceil(COC_TILE_SIZE),
+			self.dimensions[1].div_ceil(COC_TILE_SIZE),
+			1,
+		];
+		debug_label!(builder, "DofPass tiles", LABEL_COLOR_COMPUTE);
+		builder.dispatch(tile_groups, self.tiles_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+
+		let layout = self
+			.bokeh_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(color.clone(), self.sampler.clone())?
+				.add_image(ImageView::new(self.coc.clone())?)?
+				.add_image(ImageView::new(self.tiles.clone())?)?
+				.add_image(ImageView::new(self.far_field.clone())?)?
+				.add_image(ImageView::new(self.near_field.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "DofPass bokeh", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			groups,
+			self.bokeh_pipeline.clone(),
+			set,
+			bokeh_cs::ty::PushConstants { rings: self.rings },
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		let layout = self
+			.composite_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(color, self.sampler.clone())?
+				.add_image(ImageView::new(self.coc.clone())?)?
+				.add_image(ImageView::new(self.far_field.clone())?)?
+				.add_image(ImageView::new(self.near_field.clone())?)?
+				.add_image(ImageView::new(destination)?)?
+				.build()?,
+		);
+		debug_label!(builder, "DofPass composite", LABEL_COLOR_COMPUTE);
+		builder.dispatch(groups, self.composite_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+		Ok(())
+	}
+}
+
+mod contact_shadow_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			// 1 when lit, 0 in contact shadow
+			layout(set = 0, binding = 1, r32f) uniform writeonly image2D shadow;
+
+			layout(set = 0, binding = 2) uniform Matrices {
+				mat4 view;
+				mat4 projection;
+				// World space, towards the light
+				vec4 light_direction;
+			} m;
+
+			layout(push_constant) uniform PushConstants {
+				// Length of the ray towards the light, in world units
+				float max_distance;
+				// Depth difference beyond which a sample is behind the occluder, not under it
+				float thickness;
+				// The pass fades out towards this distance from the camera
+				float max_camera_distance;
+				int steps;
+			} pc;
+
+			mat4 inverse_projection;
+
+			vec3 view_position(vec2 uv) {
+				vec4 position = inverse_projection * vec4(uv * 2.0 - 1.0, textureLod(depth, uv, 0.0).r, 1.0);
+				return position.xyz / position.w;
+			}
+
+			vec3 project(vec3 position) {
+				vec4 clip = m.projection * vec4(position, 1.0);
+				return vec3(clip.xy / clip.w * 0.5 + 0.5, clip.z / clip.w);
+			}
+
+			// Interleaved gradient noise, offsets the steps per pixel
+			float noise(vec2 pixel) {
+				return fract(52.9829189 * fract(dot(pixel, vec2(0.06711056, 0.00583715))));
+			}
+
+			void main() {
+				ivec2 size = imageSize(shadow);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+				inverse_projection = inverse(m.projection);
+				vec3 origin = view_position(uv);
+				float camera_distance = length(origin);
+				if (textureLod(depth, uv, 0.0).r >= 1.0 || camera_distance >= pc.max_camera_distance) {
+					imageStore(shadow, pixel, vec4(1.0));
+					return;
+				}
+
+				vec3 to_light = normalize((m.view * vec4(m.light_direction.xyz, 0.0)).xyz);
+				float step_length = pc.max_distance / float(max(pc.steps, 1));
+				float offset = noise(vec2(pixel));
+
+				float occlusion = 0.0;
+				for (int i = 0; i < pc.steps; i++) {
+					vec3 position = origin + to_light * step_length * (float(i) + offset);
+					vec3 projected = project(position);
+					if (any(lessThan(projected.xy, vec2(0.0))) || any(greaterThan(projected.xy, vec2(1.0)))) {
+						break;
+					}
+					// View space looks down -Z, positive when the scene is in front of the ray
+					float difference = view_position(projected.xy).z - position.z;
+					if (difference > 0.0 && difference < pc.thickness) {
+						// Fades out at the screen edges where the occluders may be missing
+						vec2 edge = min(projected.xy, 1.0 - projected.xy);
+						occlusion = clamp(min(edge.x, edge.y) * 20.0, 0.0, 1.0);
+						break;
+					}
+				}
+
+				float fade = 1.0 - smoothstep(0.75 * pc.max_camera_distance, pc.max_camera_distance, camera_distance);
+				imageStore(shadow, pixel, vec4(1.0 - occlusion * fade));
+			}
+		"
+	}
+}
+
+pub const CONTACT_SHADOW_FORMAT: Format = Format::R32Sfloat;
+
+// Screen space contact shadows: the view space position of each pixel is
+// reconstructed from the depth buffer, and a short ray towards the light is
+// marched in a few steps against the depth buffer. They catch the small
+// shadows where objects touch the ground that the shadow map resolution
+// misses, the lighting pass multiplies the result with the shadow map
+// visibility. Only the pixels closer than `max_camera_distance` are marched.
+pub struct ContactShadowPass {
+	pipeline: SharedComputePipeline,
+	output: Arc<StorageImage<Format>>,
+	matrices: CpuBufferPool<contact_shadow_cs::ty::Matrices>,
+	sampler: Arc<Sampler>,
+	dimensions: [u32; 2],
+	pub max_distance: f32,
+	pub thickness: f32,
+	pub max_camera_distance: f32,
+	pub steps: i32,
+}
+
+impl ContactShadowPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<ContactShadowPass, VulkanoError> {
+		let shader = contact_shadow_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		Ok(ContactShadowPass {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			output: storage_image(&device, dimensions, CONTACT_SHADOW_FORMAT)?,
+			matrices: CpuBufferPool::uniform_buffer(device.clone()),
+			sampler: clamp_to_edge_sampler(device, Filter::Nearest)?,
+			dimensions,
+			max_distance: 0.2,
+			thickness: 0.05,
+			max_camera_distance: 30.0,
+			steps: 8,
+		})
+	}
+
+	// Records the march outside of any render pass and returns the contact
+	// shadow term. `light_direction` is normalized, towards the light.
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: SharedImageView,
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+		light_direction: [f32; 3],
+	) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+		let [x, y, z] = light_direction;
+		let matrices = self.matrices.next(contact_shadow_cs::ty::Matrices {
+			view,
+			projection,
+			light_direction: [x, y, z, 0.0],
+		})?;
+
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(depth, self.sampler.clone())?
+				.add_image(ImageView::new(self.output.clone())?)?
+				.add_buffer(matrices)?
+				.build()?,
+		);
+		debug_label!(builder, "ContactShadowPass", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1],
+			self.pipeline.clone(),
+			set,
+			contact_shadow_cs::ty::PushConstants {
+				max_distance: self.max_distance,
+				thickness: self.thickness,
+				max_camera_distance: self.max_camera_distance,
+				steps: self.steps,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(self.output.clone())
+	}
+}
+
+// Samples of the separable skin kernel, must match the shader
+pub const SSS_SAMPLES: usize = 17;
+
+// The sum of three gaussians fitted to the dipole diffusion profile of skin,
+// as variance in mm^2 and weight per channel. Red scatters the farthest.
+const SKIN_PROFILE: [(f32, [f32; 3]); 3] = [
+	(0.0484, [0.2, 0.4, 0.65]),
+	(0.187, [0.3, 0.4, 0.3]),
+	(1.99, [0.5, 0.2, 0.05]),
+];
+
+// Weights per channel of the samples of the skin kernel in rgb, and their
+// offset in w, from -1 to 1 across the width of the kernel. The samples are
+// denser near the center where the profile is sharp.
+pub fn skin_kernel() -> [[f32; 4]; SSS_SAMPLES] {
+	// 3 standard deviations of the widest gaussian
+	let range = 3.0 * SKIN_PROFILE[2].0.sqrt();
+	let offsets: Vec<f32> = (0..SSS_SAMPLES)
+		.map(|i| {
+			let t = 2.0 * i as f32 / (SSS_SAMPLES - 1) as f32 - 1.0;
+			t * t.abs() * range
+		})
+		.collect();
+
+	let mut kernel = [[0.0; 4]; SSS_SAMPLES];
+	let mut totals = [0.0; 3];
+	for (i, sample) in kernel.iter_mut().enumerate() {
+		// Every sample stands for the distance halfway to its neighbours
+		let previous = if i > 0 { offsets[i - 1] } else { offsets[i] };
+		let next = if i + 1 < SSS_SAMPLES { offsets[i + 1] } else { offsets[i] };
+		let area = (next - previous) / 2.0;
+		let offset = offsets[i];
+		for (variance, weights) in SKIN_PROFILE.iter() {
+			let gaussian = (-offset * offset / (2.0 * variance)).exp() / (2.0 * std::f32::consts::PI * variance).sqrt();
+			for channel in 0..3 {
+				sample[channel] += weights[channel] * gaussian * area;
+			}
+		}
+		for channel in 0..3 {
+			totals[channel] += sample[channel];
+		}
+		sample[3] = offset / range;
+	}
+	for sample in kernel.iter_mut() {
+		for channel in 0..3 {
+			sample[channel] /= totals[channel];
+		}
+	}
+	kernel
+}
+
+mod sss_blur_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const int SSS_SAMPLES = 17;
+
+			layout(set = 0, binding = 0) uniform sampler2D depth;
+			// Scattering strength in alpha, 0 outside of the skin
+			layout(set = 0, binding = 1) uniform sampler2D mask;
+			// The diffuse lighting, or the result of the horizontal pass
+			layout(set = 0, binding = 2) uniform sampler2D source;
+			// Added back by the vertical pass
+			layout(set = 0, binding = 3) uniform sampler2D specular;
+			layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D destination;
+
+			layout(set = 0, binding = 5) uniform Kernel {
+				// Weights in rgb, offset in w
+				vec4 samples[SSS_SAMPLES];
+			} kernel;
+			layout(set = 0, binding = 6) uniform Matrices {
+				mat4 projection;
+			} m;
+
+			layout(push_constant) uniform PushConstants {
+				vec2 direction;
+				// World space half width of the kernel
+				float width;
+				// How fast the samples fall back to the center across depth differences
+				float follow_surface;
+				// 1 for the vertical pass
+				uint composite;
+			} pc;
+
+			mat4 inverse_projection;
+
+			float linear_depth(vec2 uv) {
+				vec4 position = inverse_projection * vec4(uv * 2.0 - 1.0, textureLod(depth, uv, 0.0).r, 1.0);
+				return -position.z / position.w;
+			}
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+				vec4 center = textureLod(source, uv, 0.0);
+				vec3 added = pc.composite != 0 ? textureLod(specular, uv, 0.0).rgb : vec3(0.0);
+
+				float strength = textureLod(mask, uv, 0.0).a;
+				if (strength <= 0.0) {
+					imageStore(destination, pixel, vec4(center.rgb + added, center.a));
+					return;
+				}
+
+				inverse_projection = inverse(m.projection);
+				float center_depth = linear_depth(uv);
+				// The kernel has the same world size at every distance, its screen
+				// size shrinks with 1 / depth
+				vec2 scale = pc.direction * pc.width * strength * vec2(m.projection[0][0], m.projection[1][1]) * 0.5 / center_depth;
+
+				vec3 blurred = vec3(0.0);
+				for (int i = 0; i < SSS_SAMPLES; i++) {
+					vec2 sample_uv = uv + scale * kernel.samples[i].w;
+					vec3 color = textureLod(source, sample_uv, 0.0).rgb;
+					// Samples on another surface or outside of the skin would bleed
+					// light across edges, they are replaced by the center
+					float difference = abs(linear_depth(sample_uv) - center_depth);
+					float fallback = clamp(pc.follow_surface * difference / pc.width, 0.0, 1.0);
+					if (textureLod(mask, sample_uv, 0.0).a <= 0.0) {
+						fallback = 1.0;
+					}
+					blurred += mix(color, center.rgb, fallback) * kernel.samples[i].rgb;
+				}
+				imageStore(destination, pixel, vec4(blurred + added, center.a));
+			}
+		"
+	}
+}
+
+// Screen space subsurface scattering for skin. The diffuse lighting of the
+// pixels flagged in the alpha of the mask is blurred along the screen axes
+// with the separable skin kernel of `skin_kernel`, whose red channel scatters
+// the farthest, and the vertical pass adds the specular lighting back on top.
+// The kernel has a fixed world width, which the shader projects at the depth
+// of each pixel.
+pub struct SssPass {
+	pipeline: SharedComputePipeline,
+	kernel: Arc<CpuAccessibleBuffer<sss_blur_cs::ty::Kernel>>,
+	matrices: CpuBufferPool<sss_blur_cs::ty::Matrices>,
+	intermediate: Arc<StorageImage<Format>>,
+	sampler: Arc<Sampler>,
+	depth_sampler: Arc<Sampler>,
+	dimensions: [u32; 2],
+	// Half width of the kernel in world units, how far the light travels under the skin
+	pub width: f32,
+	pub follow_surface: f32,
+}
+
+impl SssPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<SssPass, VulkanoError> {
+		let shader = sss_blur_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		Ok(SssPass {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			kernel: CpuAccessibleBuffer::from_data(
+				device.clone(),
+				BufferUsage::uniform_buffer(),
+				false,
+				sss_blur_cs::ty::Kernel { samples: skin_kernel() },
+			)?,
+			matrices: CpuBufferPool::uniform_buffer(device.clone()),
+			intermediate: storage_image(&device, dimensions, BLUR_FORMAT)?,
+			// The kernel offsets fall between pixels
+			sampler: clamp_to_edge_sampler(device.clone(), Filter::Linear)?,
+			depth_sampler: clamp_to_edge_sampler(device, Filter::Nearest)?,
+			dimensions,
+			width: 0.01,
+			follow_surface: 1.0,
+		})
+	}
+
+	// Records both passes after the lighting, outside of any render pass.
+	// `destination` receives the scattered diffuse plus the specular lighting.
+	#[allow(clippy::too_many_arguments)]
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: SharedImageView,
+		mask: SharedImageView,
+		diffuse: SharedImageView,
+		specular: SharedImageView,
+		destination: Arc<StorageImage<Format>>,
+		projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		let matrices = self.matrices.next(sss_blur_cs::ty::Matrices { projection })?;
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let groups = [self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1];
+
+		let passes: [(SharedImageView, Arc<StorageImage<Format>>, [f32; 2]); 2] = [
+			(diffuse, self.intermediate.clone(), [1.0, 0.0]),
+			(Arc::new(ImageView::new(self.intermediate.clone())?), destination, [0.0, 1.0]),
+		];
+		for (composite, (source, target, direction)) in passes.iter().cloned().enumerate() {
+			let set = Arc::new(
+				PersistentDescriptorSet::start(layout.clone())
+					.add_sampled_image(depth.clone(), self.depth_sampler.clone())?
+					.add_sampled_image(mask.clone(), self.depth_sampler.clone())?
+					.add_sampled_image(source, self.sampler.clone())?
+					.add_sampled_image(specular.clone(), self.depth_sampler.clone())?
+					.add_image(ImageView::new(target)?)?
+					.add_buffer(self.kernel.clone())?
+					.add_buffer(matrices.clone())?
+					.build()?,
+			);
+			debug_label!(builder, "SssPass", LABEL_COLOR_COMPUTE);
+			builder.dispatch(
+				groups,
+				self.pipeline.clone(),
+				set,
+				sss_blur_cs::ty::PushConstants {
+					direction,
+					width: self.width,
+					follow_surface: self.follow_surface,
+					composite: composite as u32,
+				},
+				vec![],
+			)?;
+			debug_label!(builder);
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use egui_plot::{Bar, BarChart, Legend, Line, Plot, PlotPoints};
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+// Seconds of history kept in the scrolling charts
+const HISTORY_SECONDS: f64 = 60.0;
+const PLOT_HEIGHT: f32 = 120.0;
+
+const METRICS: [&str; 3] = [
+	"Vertex shader invocations",
+	"Fragment shader invocations",
+	"Clipping primitives generated",
+];
+
+// The results of a pipeline statistics query, in the order of METRICS
+#[derive(Default, Debug, Clone, Copy)]
+pub struct PipelineStatistics {
+	pub vertex_shader_invocations: u64,
+	pub fragment_shader_invocations: u64,
+	pub clipping_primitives: u64,
+}
+
+impl PipelineStatistics {
+	fn values(&self) -> [u64; 3] {
+		[
+			self.vertex_shader_invocations,
+			self.fragment_shader_invocations,
+			self.clipping_primitives,
+		]
+	}
+}
+
+#[derive(Default)]
+struct PassHistory {
+	latest: PipelineStatistics,
+	baseline: Option<PipelineStatistics>,
+	// Seconds since the dashboard was created, then the values minus the baseline
+	samples: VecDeque<(f64, [f64; 3])>,
+}
+
+impl PassHistory {
+	fn normalized(&self) -> [f64; 3] {
+		let latest = self.latest.values();
+		let baseline = self.baseline.unwrap_or_default().values();
+		let mut values = [0.0; 3];
+		for (value, (latest, baseline)) in values.iter_mut().zip(latest.iter().zip(baseline.iter())) {
+			*value = *latest as f64 - *baseline as f64;
+		}
+		values
+	}
+}
+
+// Scrolling charts of the pipeline statistics, one per metric with a line per
+// pass, sampled once per second from the latest results given to `record`.
+// With several passes (one query pool each) the latest values are also broken
+// down per pass in bar charts.
+pub struct PipelineStatsDashboard {
+	start: Instant,
+	last_update: Option<Instant>,
+	passes: BTreeMap<String, PassHistory>,
+}
+
+impl Default for PipelineStatsDashboard {
+	fn default() -> PipelineStatsDashboard {
+		PipelineStatsDashboard::new()
+	}
+}
+
+impl PipelineStatsDashboard {
+	pub fn new() -> PipelineStatsDashboard {
+		PipelineStatsDashboard {
+			start: Instant::now(),
+			last_update: None,
+			passes: BTreeMap::new(),
+		}
+	}
+
+	// The latest results of the query pool of `pass`, usually once per frame
+	pub fn record(&mut self, pass: &str, statistics: PipelineStatistics) {
+		self.passes.entry(pass.to_owned()).or_default().latest = statistics;
+	}
+
+	// Every value is shown relative to the current reading from now on
+	pub fn reset_baseline(&mut self) {
+		for history in self.passes.values_mut() {
+			history.baseline = Some(history.latest);
+			history.samples.clear();
+		}
+	}
+
+	fn update(&mut self) {
+		let now = Instant::now();
+		if self.last_update.is_some_and(|last| now - last < UPDATE_INTERVAL) {
+			return;
+		}
+		self.last_update = Some(now);
+
+		let time = (now - self.start).as_secs_f64();
+		for history in self.passes.values_mut() {
+			let values = history.normalized();
+			history.samples.push_back((time, values));
+			while history.samples.front().is_some_and(|(t, _)| time - t > HISTORY_SECONDS) {
+				history.samples.pop_front();
+			}
+		}
+	}
+
+	pub fn ui(&mut self, ui: &mut egui::Ui) {
+		self.update();
+
+		if ui.button("Reset baseline").clicked() {
+			self.reset_baseline();
+		}
+		if self.passes.is_empty() {
+			ui.label("No pipeline statistics recorded");
+			return;
+		}
+
+		for (metric, name) in METRICS.iter().enumerate() {
+			ui.label(*name);
+			Plot::new(("pipeline_stats", metric))
+				.height(PLOT_HEIGHT)
+				.legend(Legend::default())
+				.show(ui, |plot_ui| {
+					for (pass, history) in self.passes.iter() {
+						let points: Vec<[f64; 2]> = history.samples.iter().map(|(t, values)| [*t, values[metric]]).collect();
+						plot_ui.line(Line::new(PlotPoints::from(points)).name(pass));
+					}
+				});
+
+			if self.passes.len() > 1 {
+				let bars = self
+					.passes
+					.iter()
+					.enumerate()
+					.map(|(i, (pass, history))| Bar::new(i as f64, history.normalized()[metric]).name(pass))
+					.collect();
+				Plot::new(("pipeline_stats_passes", metric))
+					.height(PLOT_HEIGHT)
+					.show(ui, |plot_ui| plot_ui.bar_chart(BarChart::new(bars).name(*name)));
+			}
+		}
+	}
+}
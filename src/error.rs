@@ -0,0 +1,218 @@
+use log::*;
+
+use thiserror::Error;
+
+use vulkano::buffer::cpu_access::ReadLockError;
+
+use vulkano::command_buffer::{
+	AutoCommandBufferBuilderContextError,
+	BeginRenderPassError,
+	BlitImageError,
+	BuildError,
+	CommandBufferExecError,
+	CopyBufferError,
+	CopyBufferImageError,
+	CopyImageError,
+	DispatchError,
+	DrawError,
+	DrawIndexedError,
+	DrawIndirectError,
+	UpdateBufferError,
+};
+use vulkano::descriptor::descriptor_set::{
+	PersistentDescriptorSetBuildError,
+	PersistentDescriptorSetError,
+};
+use vulkano::descriptor::pipeline_layout::{PipelineLayoutCreationError, RuntimePipelineDescError};
+use vulkano::device::DeviceCreationError;
+use vulkano::framebuffer::{FramebufferCreationError, RenderPassCreationError};
+use vulkano::image::view::ImageViewCreationError;
+use vulkano::image::ImageCreationError;
+use vulkano::instance::debug::DebugCallbackCreationError;
+use vulkano::instance::{InstanceCreationError, LoadingError};
+use vulkano::memory::DeviceMemoryAllocError;
+use vulkano::pipeline::{ComputePipelineCreationError, GraphicsPipelineCreationError};
+use vulkano::sampler::SamplerCreationError;
+use vulkano::swapchain::{AcquireError, CapabilitiesError, SurfaceCreationError, SwapchainCreationError};
+use vulkano::sync::FlushError;
+use vulkano::OomError;
+
+use crate::shaders::{CompileError, ReflectionError};
+
+#[derive(Debug, Error)]
+pub enum VulkanoError {
+	#[error("failed to load the .env file: {0}")]
+	Env(#[from] dotenv::Error),
+	#[error("failed to load the Vulkan library: {0}")]
+	Loading(#[from] LoadingError),
+	#[error("failed to create the Vulkan instance: {0}")]
+	InstanceCreation(#[from] InstanceCreationError),
+	#[error("failed to register the debug messenger: {0}")]
+	DebugCallback(#[from] DebugCallbackCreationError),
+	#[error("no Vulkan physical device available")]
+	NoPhysicalDevice,
+	#[error("failed to create the window: {0}")]
+	WindowCreation(#[from] winit::error::OsError),
+	#[error("failed to create the window surface: {0}")]
+	SurfaceCreation(#[from] SurfaceCreationError),
+	#[error("failed to query the surface capabilities: {0}")]
+	Capabilities(#[from] CapabilitiesError),
+	#[error("no queue family supports graphics on this surface")]
+	NoQueueFamily,
+	#[error("failed to create the logical device: {0}")]
+	DeviceCreation(#[from] DeviceCreationError),
+	#[error("the device didn't return any queue")]
+	NoQueue,
+	#[error("the surface doesn't support any composite alpha mode")]
+	NoCompositeAlpha,
+	#[error("failed to create the swapchain: {0}")]
+	SwapchainCreation(#[from] SwapchainCreationError),
+	#[error("{0}")]
+	ShaderCompile(#[from] CompileError),
+	#[error("failed to load a shader module: {0}")]
+	ShaderLoad(#[source] OomError),
+	#[error("{0}")]
+	ShaderReflection(#[from] ReflectionError),
+	#[error("failed to create the render pass: {0}")]
+	RenderPassCreation(#[from] RenderPassCreationError),
+	#[error("the render pass doesn't have the requested subpass")]
+	NoSubpass,
+	#[error("failed to build the graphics pipeline: {0}")]
+	PipelineBuild(#[from] GraphicsPipelineCreationError),
+	#[error("failed to build the compute pipeline: {0}")]
+	ComputePipelineBuild(#[from] ComputePipelineCreationError),
+	#[error("failed to create an image: {0}")]
+	ImageCreation(#[from] ImageCreationError),
+	#[error("failed to create a pipeline layout: {0}")]
+	PipelineLayoutCreation(#[from] PipelineLayoutCreationError),
+	#[error("invalid pipeline layout description: {0}")]
+	PipelineLayoutDesc(#[from] RuntimePipelineDescError),
+	#[error("failed to create an image view: {0}")]
+	ImageViewCreation(#[from] ImageViewCreationError),
+	#[error("failed to create a framebuffer: {0}")]
+	FramebufferCreation(#[from] FramebufferCreationError),
+	#[error("failed to allocate device memory: {0}")]
+	DeviceMemoryAlloc(#[from] DeviceMemoryAllocError),
+	#[error("out of memory: {0}")]
+	Oom(#[from] OomError),
+	#[error("failed to begin the render pass: {0}")]
+	BeginRenderPass(#[from] BeginRenderPassError),
+	#[error("failed to record a draw command: {0}")]
+	Draw(#[from] DrawError),
+	#[error("failed to record a dispatch command: {0}")]
+	Dispatch(#[from] DispatchError),
+	#[error("failed to record an indexed draw command: {0}")]
+	DrawIndexed(#[from] DrawIndexedError),
+	#[error("failed to record an indirect draw command: {0}")]
+	DrawIndirect(#[from] DrawIndirectError),
+	#[error("failed to copy a buffer: {0}")]
+	CopyBuffer(#[from] CopyBufferError),
+	#[error("failed to copy between a buffer and an image: {0}")]
+	CopyBufferImage(#[from] CopyBufferImageError),
+	#[error("failed to copy an image: {0}")]
+	CopyImage(#[from] CopyImageError),
+	#[error("failed to blit an image: {0}")]
+	BlitImage(#[from] BlitImageError),
+	#[error("failed to lock a buffer for reading: {0}")]
+	BufferRead(#[from] ReadLockError),
+	#[error("failed to update a buffer: {0}")]
+	UpdateBuffer(#[from] UpdateBufferError),
+	#[error("failed to record a command: {0}")]
+	CommandBufferRecord(#[from] AutoCommandBufferBuilderContextError),
+	#[error("failed to build the command buffer: {0}")]
+	CommandBufferBuild(#[from] BuildError),
+	#[error("failed to execute the command buffer: {0}")]
+	CommandBufferExec(#[from] CommandBufferExecError),
+	#[error("failed to acquire the next swapchain image: {0}")]
+	Acquire(AcquireError),
+	#[error("failed to flush the frame: {0}")]
+	Flush(FlushError),
+	#[error("the pipeline doesn't have the requested descriptor set layout")]
+	NoDescriptorSetLayout,
+	#[error("failed to add a descriptor: {0}")]
+	DescriptorSet(#[from] PersistentDescriptorSetError),
+	#[error("failed to build a descriptor set: {0}")]
+	DescriptorSetBuild(#[from] PersistentDescriptorSetBuildError),
+	#[error("the Vulkan device was lost")]
+	DeviceLost,
+	#[error("failed to decode an image: {0}")]
+	ImageDecode(#[from] image::ImageError),
+	#[error("failed to create a sampler: {0}")]
+	SamplerCreation(#[from] SamplerCreationError),
+	#[error("failed to parse JSON: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("invalid font metrics: {0}")]
+	FontMetrics(String),
+	#[error("invalid key bindings: {0}")]
+	Keybindings(String),
+	#[error("invalid font file")]
+	FontLoad,
+	#[error("the glyphs don't fit in a {0}x{0} font atlas")]
+	FontAtlasFull(u32),
+	#[error("unsupported by the device: {0}")]
+	Unsupported(&'static str),
+	#[error("I/O error: {0}")]
+	Io(#[from] std::io::Error),
+	#[cfg(feature = "serde")]
+	#[error("failed to (de)serialize the mesh cache: {0}")]
+	MeshCache(#[from] bincode::Error),
+}
+
+impl From<AcquireError> for VulkanoError {
+	fn from(e: AcquireError) -> VulkanoError {
+		match e {
+			AcquireError::DeviceLost => VulkanoError::DeviceLost,
+			e => VulkanoError::Acquire(e),
+		}
+	}
+}
+
+impl From<FlushError> for VulkanoError {
+	fn from(e: FlushError) -> VulkanoError {
+		match e {
+			FlushError::DeviceLost => VulkanoError::DeviceLost,
+			e => VulkanoError::Flush(e),
+		}
+	}
+}
+
+// Maximum number of times a lost device is recreated before giving up
+pub const MAX_RECOVERY_ATTEMPTS: u32 = 3;
+
+// Recreates the Vulkan context after a device loss, a bounded number of times
+pub struct RecoveryStrategy {
+	attempts: u32,
+}
+
+impl Default for RecoveryStrategy {
+	fn default() -> RecoveryStrategy {
+		RecoveryStrategy::new()
+	}
+}
+
+impl RecoveryStrategy {
+	pub fn new() -> RecoveryStrategy {
+		RecoveryStrategy { attempts: 0 }
+	}
+
+	// Returns None once every attempt has been used up
+	pub fn recover<T, F>(&mut self, mut init: F) -> Option<T>
+	where
+		F: FnMut() -> Result<T, VulkanoError>,
+	{
+		while self.attempts < MAX_RECOVERY_ATTEMPTS {
+			self.attempts += 1;
+			warn!(
+				"Recovering from device loss (attempt {}/{})",
+				self.attempts, MAX_RECOVERY_ATTEMPTS
+			);
+
+			match init() {
+				Ok(value) => return Some(value),
+				Err(e) => error!("Failed to recreate the Vulkan context: {}", e),
+			}
+		}
+
+		None
+	}
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, ImmutableBuffer, TypedBufferAccess};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::error::VulkanoError;
+use crate::pipeline::{RenderPipeline, SharedPipeline};
+use crate::streaming::Texture;
+
+// Leaves and grass cards below this alpha are cut out
+pub const DEFAULT_ALPHA_THRESHOLD: f32 = 0.5;
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct FoliageVertex {
+	pub position: [f32; 3],
+	pub uv: [f32; 2],
+	// Multiplies the alpha mask, to fade the cards out with the distance
+	pub alpha: f32,
+}
+vulkano::impl_vertex!(FoliageVertex, position, uv, alpha);
+
+// Alpha tested foliage that doesn't need sorting: the alpha becomes the MSAA
+// sample coverage, which smooths the cutout edges at the resolve when the
+// subpass is multisampled. Alpha to one is left disabled, the written alpha
+// is the coverage, and a single sampled subpass behaves as a plain alpha test.
+// Culling is disabled for the two sided cards.
+#[derive(RenderPipeline)]
+#[vert = "src/shaders/foliage.vert"]
+#[frag = "src/shaders/foliage.frag"]
+#[vertex = "crate::foliage::FoliageVertex"]
+#[alpha_to_coverage = true]
+#[alpha_to_one = false]
+pub struct FoliagePipeline;
+
+// How a mesh is shaded, which picks the pipeline it's drawn with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshSurface {
+	Opaque,
+	Foliage,
+}
+
+pub struct FoliageMesh {
+	pub vertices: Arc<ImmutableBuffer<[FoliageVertex]>>,
+	pub indices: Arc<ImmutableBuffer<[u16]>>,
+	// The alpha of the texture is the mask
+	pub texture: Texture,
+}
+
+pub struct FoliageRenderer {
+	pipeline: SharedPipeline,
+	sampler: Arc<Sampler>,
+	pub alpha_threshold: f32,
+}
+
+impl FoliageRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	) -> Result<FoliageRenderer, VulkanoError> {
+		let sampler = Sampler::new(
+			device.clone(),
+			Filter::Linear,
+			Filter::Linear,
+			MipmapMode::Linear,
+			SamplerAddressMode::Repeat,
+			SamplerAddressMode::Repeat,
+			SamplerAddressMode::Repeat,
+			0.0,
+			1.0,
+			0.0,
+			1000.0,
+		)?;
+		Ok(FoliageRenderer {
+			pipeline: FoliagePipeline::build(device, render_pass)?,
+			sampler,
+			alpha_threshold: DEFAULT_ALPHA_THRESHOLD,
+		})
+	}
+
+	pub fn pipeline(&self) -> SharedPipeline {
+		self.pipeline.clone()
+	}
+
+	// Records inside the render pass `new` was given
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		mesh: &FoliageMesh,
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		if mesh.indices.len() == 0 {
+			return Ok(());
+		}
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(mesh.texture.clone(), self.sampler.clone())?
+				.build()?,
+		);
+		builder.draw_indexed(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![mesh.vertices.clone() as Arc<dyn BufferAccess + Send + Sync>],
+			mesh.indices.clone(),
+			set,
+			__foliage_pipeline_shaders::vs::ty::PushConstants {
+				view_projection,
+				alpha_threshold: self.alpha_threshold,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
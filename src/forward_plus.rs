@@ -0,0 +1,429 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::Device;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageViewAbstract;
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::pipeline::{SharedComputePipeline, SharedPipeline};
+
+// Size of the screen tiles in pixels, must match the workgroup size of the culling pass
+pub const TILE_SIZE: u32 = 16;
+// Lights uploaded per frame, the others are ignored
+pub const MAX_LIGHTS: usize = 1024;
+// Must match the shaders, each tile stores its light count then the indices
+pub const MAX_LIGHTS_PER_TILE: u32 = 255;
+
+mod tile_light_culling_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
+
+			const uint MAX_LIGHTS_PER_TILE = 255;
+
+			struct PointLight {
+				vec3 position;
+				float radius;
+				vec3 color;
+				float intensity;
+			};
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				// Width, height, tiles per row and light count
+				uvec4 screen;
+			} camera;
+			layout(set = 0, binding = 1) readonly buffer Lights {
+				PointLight lights[];
+			};
+			layout(set = 0, binding = 2) writeonly buffer TileLights {
+				uint tile_lights[];
+			};
+			layout(set = 0, binding = 3) uniform sampler2D depth;
+
+			shared uint min_depth;
+			shared uint max_depth;
+			shared uint light_count;
+			shared uint light_indices[MAX_LIGHTS_PER_TILE];
+
+			vec3 unproject(mat4 inverse_projection, vec2 ndc, float depth) {
+				vec4 position = inverse_projection * vec4(ndc, depth, 1.0);
+				return position.xyz / position.w;
+			}
+
+			void main() {
+				uint local_index = gl_LocalInvocationIndex;
+				if (local_index == 0) {
+					min_depth = floatBitsToUint(1.0);
+					max_depth = 0;
+					light_count = 0;
+				}
+				barrier();
+
+				// The depth range of the tile, depths are positive so their bits sort like them
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x < int(camera.screen.x) && pixel.y < int(camera.screen.y)) {
+					uint bits = floatBitsToUint(texelFetch(depth, pixel, 0).r);
+					atomicMin(min_depth, bits);
+					atomicMax(max_depth, bits);
+				}
+				barrier();
+
+				// View space frustum of the tile, the side planes go through the camera
+				mat4 inverse_projection = inverse(camera.projection);
+				vec2 tile_size = 2.0 * vec2(gl_WorkGroupSize.xy) / vec2(camera.screen.xy);
+				vec2 tile_min = vec2(gl_WorkGroupID.xy) * tile_size - 1.0;
+				vec2 tile_max = tile_min + tile_size;
+				vec3 corners[4] = vec3[](
+					unproject(inverse_projection, tile_min, 1.0),
+					unproject(inverse_projection, vec2(tile_max.x, tile_min.y), 1.0),
+					unproject(inverse_projection, tile_max, 1.0),
+					unproject(inverse_projection, vec2(tile_min.x, tile_max.y), 1.0)
+				);
+				vec3 center = unproject(inverse_projection, (tile_min + tile_max) * 0.5, 1.0);
+				vec3 planes[4];
+				for (int i = 0; i < 4; i++) {
+					vec3 normal = normalize(cross(corners[i], corners[(i + 1) % 4]));
+					// Pointing inside, whatever the handedness of the projection
+					planes[i] = dot(normal, center) < 0.0 ? -normal : normal;
+				}
+				// Distances along the view direction, which is -Z
+				float near = -unproject(inverse_projection, vec2(0.0), uintBitsToFloat(min_depth)).z;
+				float far = -unproject(inverse_projection, vec2(0.0), uintBitsToFloat(max_depth)).z;
+
+				uint total = camera.screen.w;
+				uint invocations = gl_WorkGroupSize.x * gl_WorkGroupSize.y;
+				for (uint i = local_index; i < total; i += invocations) {
+					PointLight light = lights[i];
+					vec3 position = (camera.view * vec4(light.position, 1.0)).xyz;
+					bool visible = -position.z + light.radius >= near && -position.z - light.radius <= far;
+					for (int p = 0; p < 4 && visible; p++) {
+						visible = dot(planes[p], position) >= -light.radius;
+					}
+					if (visible) {
+						uint slot = atomicAdd(light_count, 1);
+						if (slot < MAX_LIGHTS_PER_TILE) {
+							light_indices[slot] = i;
+						}
+					}
+				}
+				barrier();
+
+				uint tile = gl_WorkGroupID.y * camera.screen.z + gl_WorkGroupID.x;
+				uint base = tile * (MAX_LIGHTS_PER_TILE + 1);
+				uint count = min(light_count, MAX_LIGHTS_PER_TILE);
+				if (local_index == 0) {
+					tile_lights[base] = count;
+				}
+				for (uint i = local_index; i < count; i += invocations) {
+					tile_lights[base + 1 + i] = light_indices[i];
+				}
+			}
+		"
+	}
+}
+
+mod forward_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+
+			layout(location = 0) out vec3 v_position;
+			layout(location = 1) out vec3 v_normal;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				uvec4 screen;
+			} camera;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+			} pc;
+
+			void main() {
+				vec4 world = pc.model * vec4(position, 1.0);
+				v_position = world.xyz;
+				v_normal = mat3(pc.model) * normal;
+				gl_Position = camera.projection * camera.view * world;
+			}
+		"
+	}
+}
+
+mod forward_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 v_position;
+			layout(location = 1) in vec3 v_normal;
+
+			layout(location = 0) out vec4 f_color;
+
+			const uint TILE_SIZE = 16;
+			const uint MAX_LIGHTS_PER_TILE = 255;
+
+			struct PointLight {
+				vec3 position;
+				float radius;
+				vec3 color;
+				float intensity;
+			};
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				uvec4 screen;
+			} camera;
+			layout(set = 0, binding = 1) readonly buffer Lights {
+				PointLight lights[];
+			};
+			layout(set = 0, binding = 2) readonly buffer TileLights {
+				uint tile_lights[];
+			};
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+			} pc;
+
+			const float AMBIENT = 0.03;
+
+			void main() {
+				uvec2 tile = uvec2(gl_FragCoord.xy) / TILE_SIZE;
+				uint base = (tile.y * camera.screen.z + tile.x) * (MAX_LIGHTS_PER_TILE + 1);
+				vec3 normal = normalize(v_normal);
+
+				vec3 radiance = vec3(AMBIENT);
+				uint count = tile_lights[base];
+				for (uint i = 0; i < count; i++) {
+					PointLight light = lights[tile_lights[base + 1 + i]];
+					vec3 to_light = light.position - v_position;
+					float distance = length(to_light);
+					// Smoothly reaches 0 at the radius the lights were culled with
+					float falloff = clamp(1.0 - pow(distance / light.radius, 4.0), 0.0, 1.0);
+					float attenuation = falloff * falloff / (distance * distance + 1.0);
+					float diffuse = max(dot(normal, to_light / max(distance, 1e-4)), 0.0);
+					radiance += light.color * light.intensity * diffuse * attenuation;
+				}
+				f_color = vec4(pc.albedo.rgb * radiance, pc.albedo.a);
+			}
+		"
+	}
+}
+
+// Matches the std430 layout of the shaders
+#[derive(Default, Debug, Clone, Copy)]
+pub struct PointLight {
+	pub position: [f32; 3],
+	// Beyond which the light doesn't contribute
+	pub radius: f32,
+	pub color: [f32; 3],
+	pub intensity: f32,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct ForwardVertex {
+	pub position: [f32; 3],
+	pub normal: [f32; 3],
+}
+vulkano::impl_vertex!(ForwardVertex, position, normal);
+
+fn tile_count([width, height]: [u32; 2]) -> [u32; 2] {
+	[width.div_ceil(TILE_SIZE), height.div_ceil(TILE_SIZE)]
+}
+
+fn tile_light_buffer(device: &Arc<Device>, dimensions: [u32; 2]) -> Result<Arc<DeviceLocalBuffer<[u32]>>, VulkanoError> {
+	let [tiles_x, tiles_y] = tile_count(dimensions);
+	let buffer = DeviceLocalBuffer::array(
+		device.clone(),
+		(tiles_x * tiles_y * (MAX_LIGHTS_PER_TILE + 1)) as usize,
+		BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+		device.active_queue_families(),
+	)?;
+	Ok(buffer)
+}
+
+// Forward+ shading. Before the render pass, `cull` splits the screen into
+// 16x16 pixel tiles and a compute pass tests every light against the frustum
+// of every tile, bounded by the depth of a depth prepass, writing per tile
+// light lists. View space looks down -Z. The forward fragment shader then only iterates the lights of
+// its tile, which keeps hundreds of small dynamic lights affordable.
+pub struct TiledForwardRenderer {
+	culling_pipeline: SharedComputePipeline,
+	shading_pipeline: SharedPipeline,
+	camera_pool: CpuBufferPool<tile_light_culling_cs::ty::Camera>,
+	light_pool: CpuBufferPool<PointLight>,
+	tile_lights: Arc<DeviceLocalBuffer<[u32]>>,
+	depth_sampler: Arc<Sampler>,
+	// Written by `cull` for the draws of the same frame
+	shading_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+	dimensions: [u32; 2],
+	device: Arc<Device>,
+}
+
+impl TiledForwardRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		dimensions: [u32; 2],
+	) -> Result<TiledForwardRenderer, VulkanoError> {
+		let culling_shader = tile_light_culling_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let culling_pipeline: SharedComputePipeline = Arc::new(ComputePipeline::new(
+			device.clone(),
+			&culling_shader.main_entry_point(),
+			&(),
+			None,
+		)?);
+
+		let vs = forward_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = forward_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let has_depth = subpass.has_depth();
+		let builder = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<ForwardVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ());
+		let builder = if has_depth {
+			builder.depth_stencil_simple_depth()
+		} else {
+			builder
+		};
+		let shading_pipeline: SharedPipeline = Arc::new(builder.render_pass(subpass).build(device.clone())?);
+
+		// Depth formats don't always support linear filtering
+		let depth_sampler = Sampler::new(
+			device.clone(),
+			Filter::Nearest,
+			Filter::Nearest,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+
+		Ok(TiledForwardRenderer {
+			culling_pipeline,
+			shading_pipeline,
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			light_pool: CpuBufferPool::new(device.clone(), BufferUsage { storage_buffer: true, ..BufferUsage::none() }),
+			tile_lights: tile_light_buffer(&device, dimensions)?,
+			depth_sampler,
+			shading_set: None,
+			dimensions,
+			device,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		if dimensions != self.dimensions {
+			self.tile_lights = tile_light_buffer(&self.device, dimensions)?;
+			self.shading_set = None;
+			self.dimensions = dimensions;
+		}
+		Ok(())
+	}
+
+	// Records the light culling outside of any render pass. `depth` is a view
+	// of the sampled depth of the frame, from a depth prepass.
+	pub fn cull(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+		lights: &[PointLight],
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+		let [tiles_x, tiles_y] = tile_count(self.dimensions);
+		let camera = self.camera_pool.next(tile_light_culling_cs::ty::Camera {
+			view,
+			projection,
+			screen: [self.dimensions[0], self.dimensions[1], tiles_x, lights.len() as u32],
+		})?;
+		// Empty buffers can't be bound, the count keeps the placeholder unused
+		let light_buffer = if lights.is_empty() {
+			self.light_pool.chunk(vec![PointLight::default()])?
+		} else {
+			self.light_pool.chunk(lights.iter().copied())?
+		};
+
+		let layout = self
+			.culling_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let culling_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera.clone())?
+				.add_buffer(light_buffer.clone())?
+				.add_buffer(self.tile_lights.clone())?
+				.add_sampled_image(depth, self.depth_sampler.clone())?
+				.build()?,
+		);
+		debug_label!(builder, "TiledForwardRenderer culling", LABEL_COLOR_COMPUTE);
+		builder.dispatch([tiles_x, tiles_y, 1], self.culling_pipeline.clone(), culling_set, (), vec![])?;
+		debug_label!(builder);
+
+		let layout = self
+			.shading_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		self.shading_set = Some(Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_buffer(light_buffer)?
+				.add_buffer(self.tile_lights.clone())?
+				.build()?,
+		));
+		Ok(())
+	}
+
+	// Records a mesh lit by the lights of the last `cull`, inside the render pass
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		vertices: Arc<CpuAccessibleBuffer<[ForwardVertex]>>,
+		indices: Arc<CpuAccessibleBuffer<[u32]>>,
+		model: [[f32; 4]; 4],
+		albedo: [f32; 4],
+	) -> Result<(), VulkanoError> {
+		let shading_set = self
+			.shading_set
+			.clone()
+			.expect("the lights must be culled before drawing");
+		builder.draw_indexed(
+			self.shading_pipeline.clone(),
+			dynamic_state,
+			vec![vertices as Arc<dyn BufferAccess + Send + Sync>],
+			indices,
+			shading_set,
+			forward_vs::ty::PushConstants { model, albedo },
+			vec![],
+		)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,748 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use log::*;
+
+use crossbeam_channel::{Receiver, Sender};
+
+use vulkano::buffer::{BufferUsage, ImmutableBuffer};
+use vulkano::device::Queue;
+use vulkano::sync::GpuFuture;
+
+use crate::error::VulkanoError;
+use crate::Vertex;
+
+#[cfg(feature = "serde")]
+pub use self::cache::MeshCache;
+
+#[cfg(feature = "serde")]
+mod cache {
+	use std::collections::hash_map::DefaultHasher;
+	use std::fs::{self, File};
+	use std::hash::{Hash, Hasher};
+	use std::io::{BufReader, BufWriter};
+	use std::path::{Path, PathBuf};
+	use std::time::UNIX_EPOCH;
+
+	use log::*;
+
+	use serde::{Deserialize, Serialize};
+
+	use crate::error::VulkanoError;
+	use crate::Vertex;
+
+	const CACHE_FILE_NAME: &str = "mesh_cache.bin";
+
+	#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+	struct SourceMetadata {
+		len: u64,
+		// Seconds and nanoseconds since the epoch
+		mtime: (u64, u32),
+	}
+
+	#[derive(Serialize, Deserialize)]
+	struct CacheHeader {
+		source_path_hash: u64,
+		source_metadata: SourceMetadata,
+		// The modification time can stay the same across quick saves on file
+		// systems with a coarse resolution, the content decides then
+		source_content_hash: u64,
+	}
+
+	#[derive(Serialize, Deserialize)]
+	struct CacheContent {
+		header: CacheHeader,
+		vertices: Vec<Vertex>,
+		indices: Vec<u16>,
+	}
+
+	// Stores the output of the last mesh parse in the app data directory, so it
+	// can be reused as long as the source file isn't modified
+	pub struct MeshCache {
+		path: PathBuf,
+	}
+
+	impl Default for MeshCache {
+		fn default() -> MeshCache {
+			MeshCache::new()
+		}
+	}
+
+	impl MeshCache {
+		pub fn new() -> MeshCache {
+			let directory = dirs::data_dir()
+				.unwrap_or_else(std::env::temp_dir)
+				.join(env!("CARGO_PKG_NAME"));
+			MeshCache::with_path(directory.join(CACHE_FILE_NAME))
+		}
+
+		pub fn with_path(path: PathBuf) -> MeshCache {
+			MeshCache { path }
+		}
+
+		// Returns the cached mesh if it was built from the current version of
+		// `source`, otherwise parses it with `parse` and updates the cache.
+		// The source is only hashed when its size or modification time changed.
+		pub fn load<F>(&self, source: &Path, parse: F) -> Result<(Vec<Vertex>, Vec<u16>), VulkanoError>
+		where
+			F: FnOnce(&Path) -> Result<(Vec<Vertex>, Vec<u16>), VulkanoError>,
+		{
+			let source_path_hash = path_hash(source);
+			let source_metadata = metadata(source)?;
+
+			let cached = match self.read() {
+				Ok(content) if content.header.source_path_hash == source_path_hash => Some(content),
+				Ok(_) => None,
+				Err(e) => {
+					debug!("Mesh cache unavailable: {}", e);
+					None
+				}
+			};
+			if let Some(content) = cached.as_ref() {
+				if content.header.source_metadata == source_metadata {
+					debug!("Loaded {} from the mesh cache", source.display());
+					return Ok((content.vertices.clone(), content.indices.clone()));
+				}
+			}
+
+			let source_content_hash = content_hash(source)?;
+			let header = CacheHeader {
+				source_path_hash,
+				source_metadata,
+				source_content_hash,
+			};
+			if let Some(mut content) = cached {
+				if content.header.source_content_hash == source_content_hash {
+					// Touched but not modified, the new metadata spares the next hash
+					debug!("Loaded {} from the mesh cache", source.display());
+					content.header = header;
+					if let Err(e) = self.write(&content) {
+						warn!("Failed to write the mesh cache: {}", e);
+					}
+					return Ok((content.vertices, content.indices));
+				}
+				debug!("Mesh cache is stale for {}", source.display());
+			}
+
+			let (vertices, indices) = parse(source)?;
+			let content = CacheContent {
+				header,
+				vertices,
+				indices,
+			};
+			if let Err(e) = self.write(&content) {
+				warn!("Failed to write the mesh cache: {}", e);
+			}
+
+			Ok((content.vertices, content.indices))
+		}
+
+		fn read(&self) -> Result<CacheContent, VulkanoError> {
+			let file = BufReader::new(File::open(&self.path)?);
+			Ok(bincode::deserialize_from(file)?)
+		}
+
+		fn write(&self, content: &CacheContent) -> Result<(), VulkanoError> {
+			if let Some(directory) = self.path.parent() {
+				fs::create_dir_all(directory)?;
+			}
+			let file = BufWriter::new(File::create(&self.path)?);
+			Ok(bincode::serialize_into(file, content)?)
+		}
+	}
+
+	fn path_hash(path: &Path) -> u64 {
+		let mut hasher = DefaultHasher::new();
+		path.hash(&mut hasher);
+		hasher.finish()
+	}
+
+	fn metadata(path: &Path) -> Result<SourceMetadata, VulkanoError> {
+		let metadata = fs::metadata(path)?;
+		let mtime = metadata
+			.modified()?
+			.duration_since(UNIX_EPOCH)
+			.map(|duration| (duration.as_secs(), duration.subsec_nanos()))
+			.unwrap_or((0, 0));
+		Ok(SourceMetadata {
+			len: metadata.len(),
+			mtime,
+		})
+	}
+
+	fn content_hash(path: &Path) -> Result<u64, VulkanoError> {
+		let mut hasher = DefaultHasher::new();
+		fs::read(path)?.hash(&mut hasher);
+		Ok(hasher.finish())
+	}
+
+	#[cfg(test)]
+	mod tests {
+		use super::*;
+
+		use std::time::{Duration, SystemTime};
+
+		fn set_mtime(path: &Path, seconds: u64) {
+			let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+			file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)).unwrap();
+		}
+
+		#[test]
+		fn rehashes_only_when_the_metadata_changes() {
+			let directory = std::env::temp_dir().join(format!("mesh_cache_test_{}", std::process::id()));
+			fs::create_dir_all(&directory).unwrap();
+			let source = directory.join("mesh.obj");
+			let cache = MeshCache::with_path(directory.join(CACHE_FILE_NAME));
+			let parse = |path: &Path| -> Result<(Vec<Vertex>, Vec<u16>), VulkanoError> {
+				let x = fs::read(path)?[0] as f32;
+				Ok((vec![Vertex { position: [x, 0.0] }], vec![0]))
+			};
+			let up_to_date = |_: &Path| -> Result<(Vec<Vertex>, Vec<u16>), VulkanoError> {
+				panic!("the cache is up to date")
+			};
+			let loaded = |result: Result<(Vec<Vertex>, Vec<u16>), VulkanoError>| result.unwrap().0[0].position[0];
+
+			fs::write(&source, b"1").unwrap();
+			set_mtime(&source, 1000);
+			assert_eq!(loaded(cache.load(&source, parse)), b'1' as f32);
+			fs::write(&source, b"2").unwrap();
+			set_mtime(&source, 1001);
+			assert_eq!(loaded(cache.load(&source, parse)), b'2' as f32);
+			assert_eq!(loaded(cache.load(&source, up_to_date)), b'2' as f32);
+
+			// Touched, the content hash matches
+			set_mtime(&source, 1002);
+			assert_eq!(loaded(cache.load(&source, up_to_date)), b'2' as f32);
+
+			// Same size and modification time, the content isn't read
+			fs::write(&source, b"3").unwrap();
+			set_mtime(&source, 1002);
+			assert_eq!(loaded(cache.load(&source, up_to_date)), b'2' as f32);
+
+			fs::remove_dir_all(&directory).unwrap();
+		}
+	}
+}
+
+// Disables the MeshPreprocessor optimizations, to compare the `--benchmark`
+// frame times of the raw and optimized meshes
+pub const SKIP_MESH_OPT_FLAG: &str = "--skip-mesh-opt";
+// Below 1.0 the overdraw optimization may not degrade the vertex cache
+// efficiency, meshoptimizer recommends 1.05
+const OVERDRAW_THRESHOLD: f32 = 1.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshHandle(usize);
+
+impl MeshHandle {
+	pub fn index(&self) -> usize {
+		self.0
+	}
+}
+
+#[derive(Clone)]
+pub struct GpuMesh {
+	pub vertices: Arc<ImmutableBuffer<[Vertex]>>,
+	pub indices: Arc<ImmutableBuffer<[u16]>>,
+	// False until the optimized buffers replace the raw ones
+	pub optimized: bool,
+}
+
+struct OptimizedMesh {
+	handle: MeshHandle,
+	vertices: Vec<Vertex>,
+	indices: Vec<u16>,
+}
+
+// Reorders the loaded meshes with meshoptimizer: the triangles for the
+// post-transform vertex cache then to reduce overdraw, and the vertices in the
+// order the indices fetch them. The raw mesh is uploaded right away and
+// drawable, the optimization runs on the rayon pool and its buffers replace
+// the raw ones once `process_uploads` uploads them.
+pub struct MeshPreprocessor {
+	queue: Arc<Queue>,
+	enabled: bool,
+	meshes: Vec<GpuMesh>,
+	sender: Sender<OptimizedMesh>,
+	receiver: Receiver<OptimizedMesh>,
+}
+
+impl MeshPreprocessor {
+	// Disabled when the application runs with SKIP_MESH_OPT_FLAG
+	pub fn new(queue: Arc<Queue>, enabled: bool) -> MeshPreprocessor {
+		let (sender, receiver) = crossbeam_channel::unbounded();
+		MeshPreprocessor {
+			queue,
+			enabled,
+			meshes: Vec::new(),
+			sender,
+			receiver,
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub fn load(
+		&mut self,
+		vertices: Vec<Vertex>,
+		indices: Vec<u16>,
+	) -> Result<(MeshHandle, Box<dyn GpuFuture>), VulkanoError> {
+		let handle = MeshHandle(self.meshes.len());
+		let (mesh, future) = upload(&self.queue, &vertices, &indices, !self.enabled)?;
+		self.meshes.push(mesh);
+
+		if self.enabled {
+			let sender = self.sender.clone();
+			rayon::spawn(move || {
+				let (vertices, indices) = optimize_mesh(&vertices, &indices);
+				// The preprocessor may have been dropped in the meantime
+				let _ = sender.send(OptimizedMesh { handle, vertices, indices });
+			});
+		}
+		Ok((handle, future))
+	}
+
+	// Uploads at most one optimized mesh per call, like TextureStreamer
+	pub fn process_uploads(&mut self) -> Result<Option<Box<dyn GpuFuture>>, VulkanoError> {
+		let optimized = match self.receiver.try_recv() {
+			Ok(optimized) => optimized,
+			Err(_) => return Ok(None),
+		};
+		let (mesh, future) = upload(&self.queue, &optimized.vertices, &optimized.indices, true)?;
+		self.meshes[optimized.handle.index()] = mesh;
+		debug!(
+			"Streamed the optimized mesh {} ({} vertices, {} triangles)",
+			optimized.handle.index(),
+			optimized.vertices.len(),
+			optimized.indices.len() / 3
+		);
+		Ok(Some(future))
+	}
+
+	pub fn get(&self, handle: MeshHandle) -> GpuMesh {
+		self.meshes[handle.index()].clone()
+	}
+}
+
+// meshoptimizer works on u32 indices and 3D positions, the result is
+// converted back to the crate's 16 bit indices
+pub fn optimize_mesh(vertices: &[Vertex], indices: &[u16]) -> (Vec<Vertex>, Vec<u16>) {
+	let indices: Vec<u32> = indices.iter().map(|&index| index as u32).collect();
+	let mut indices = meshopt::optimize_vertex_cache(&indices, vertices.len());
+
+	let positions: Vec<[f32; 3]> = vertices
+		.iter()
+		.map(|vertex| [vertex.position[0], vertex.position[1], 0.0])
+		.collect();
+	match meshopt::VertexDataAdapter::new(meshopt::typed_to_bytes(&positions), std::mem::size_of::<[f32; 3]>(), 0) {
+		Ok(adapter) => meshopt::optimize_overdraw_in_place(&indices, &adapter, OVERDRAW_THRESHOLD),
+		Err(e) => warn!("Skipping the overdraw optimization: {:?}", e),
+	}
+
+	let vertices = meshopt::optimize_vertex_fetch(&mut indices, vertices);
+	// The vertex count doesn't grow, the indices still fit
+	(vertices, indices.into_iter().map(|index| index as u16).collect())
+}
+
+fn upload(
+	queue: &Arc<Queue>,
+	vertices: &[Vertex],
+	indices: &[u16],
+	optimized: bool,
+) -> Result<(GpuMesh, Box<dyn GpuFuture>), VulkanoError> {
+	let (vertices, indices, future) = upload_buffers(queue, vertices, indices)?;
+	let mesh = GpuMesh {
+		vertices,
+		indices,
+		optimized,
+	};
+	Ok((mesh, future))
+}
+
+// ImmutableBuffer copies the data through a staging buffer, the buffers can
+// be used once the future is signaled
+#[allow(clippy::type_complexity)]
+pub fn upload_buffers<V>(
+	queue: &Arc<Queue>,
+	vertices: &[V],
+	indices: &[u16],
+) -> Result<(Arc<ImmutableBuffer<[V]>>, Arc<ImmutableBuffer<[u16]>>, Box<dyn GpuFuture>), VulkanoError>
+where
+	V: Clone + Send + Sync + 'static,
+{
+	let (vertex_buffer, vertex_future) =
+		ImmutableBuffer::from_iter(vertices.iter().cloned(), BufferUsage::vertex_buffer(), queue.clone())?;
+	let (index_buffer, index_future) =
+		ImmutableBuffer::from_iter(indices.iter().copied(), BufferUsage::index_buffer(), queue.clone())?;
+	Ok((vertex_buffer, index_buffer, vertex_future.join(index_future).boxed()))
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct MeshVertex {
+	pub position: [f32; 3],
+	pub normal: [f32; 3],
+	pub uv: [f32; 2],
+}
+vulkano::impl_vertex!(MeshVertex, position, normal, uv);
+
+// Parametric meshes centered on the origin with Y up, tessellated at any level
+// as long as the vertices fit the 16 bit indices. The triangles are counter
+// clockwise around their outward normal, like the terrain. The rows and
+// columns wrapping around the shapes duplicate their first vertices, with the
+// U coordinate at 1 instead of 0, so the textures don't wrap at the seam.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProceduralMesh {
+	// `rings` from pole to pole (at least 2), `sectors` around the Y axis (at least 3)
+	Sphere { radius: f32, rings: u32, sectors: u32 },
+	// Capped, `segments` around the Y axis (at least 3)
+	Cylinder { radius: f32, height: f32, segments: u32 },
+	// `rings` around the Y axis and `sides` around the tube (at least 3 each)
+	Torus { major_radius: f32, minor_radius: f32, rings: u32, sides: u32 },
+	// In the XZ plane facing up, `div_x` by `div_z` quads (at least 1 each)
+	Plane { width: f32, depth: f32, div_x: u32, div_z: u32 },
+}
+
+impl ProceduralMesh {
+	// The tessellation levels clamped to the smallest meshes that aren't degenerate
+	fn clamped(&self) -> ProceduralMesh {
+		match *self {
+			ProceduralMesh::Sphere { radius, rings, sectors } => ProceduralMesh::Sphere {
+				radius,
+				rings: rings.max(2),
+				sectors: sectors.max(3),
+			},
+			ProceduralMesh::Cylinder { radius, height, segments } => ProceduralMesh::Cylinder {
+				radius,
+				height,
+				segments: segments.max(3),
+			},
+			ProceduralMesh::Torus {
+				major_radius,
+				minor_radius,
+				rings,
+				sides,
+			} => ProceduralMesh::Torus {
+				major_radius,
+				minor_radius,
+				rings: rings.max(3),
+				sides: sides.max(3),
+			},
+			ProceduralMesh::Plane { width, depth, div_x, div_z } => ProceduralMesh::Plane {
+				width,
+				depth,
+				div_x: div_x.max(1),
+				div_z: div_z.max(1),
+			},
+		}
+	}
+
+	pub fn vertex_count(&self) -> usize {
+		match self.clamped() {
+			ProceduralMesh::Sphere { rings, sectors, .. } => ((rings + 1) * (sectors + 1)) as usize,
+			// The side has a top and a bottom row, each cap a center and a rim
+			ProceduralMesh::Cylinder { segments, .. } => (2 * (segments + 1) + 2 * (segments + 1)) as usize,
+			ProceduralMesh::Torus { rings, sides, .. } => ((rings + 1) * (sides + 1)) as usize,
+			ProceduralMesh::Plane { div_x, div_z, .. } => ((div_x + 1) * (div_z + 1)) as usize,
+		}
+	}
+
+	pub fn index_count(&self) -> usize {
+		match self.clamped() {
+			// The rows touching the poles have a single triangle per sector
+			ProceduralMesh::Sphere { rings, sectors, .. } => (6 * sectors * (rings - 1)) as usize,
+			ProceduralMesh::Cylinder { segments, .. } => (12 * segments) as usize,
+			ProceduralMesh::Torus { rings, sides, .. } => (6 * rings * sides) as usize,
+			ProceduralMesh::Plane { div_x, div_z, .. } => (6 * div_x * div_z) as usize,
+		}
+	}
+
+	pub fn generate(&self) -> (Vec<MeshVertex>, Vec<u16>) {
+		assert!(
+			self.vertex_count() <= u16::MAX as usize + 1,
+			"{:?} has too many vertices for 16 bit indices",
+			self
+		);
+		match self.clamped() {
+			ProceduralMesh::Sphere { radius, rings, sectors } => sphere(radius, rings, sectors),
+			ProceduralMesh::Cylinder { radius, height, segments } => cylinder(radius, height, segments),
+			ProceduralMesh::Torus {
+				major_radius,
+				minor_radius,
+				rings,
+				sides,
+			} => torus(major_radius, minor_radius, rings, sides),
+			ProceduralMesh::Plane { width, depth, div_x, div_z } => plane(width, depth, div_x, div_z),
+		}
+	}
+
+	#[allow(clippy::type_complexity)]
+	pub fn upload(
+		&self,
+		queue: &Arc<Queue>,
+	) -> Result<(Arc<ImmutableBuffer<[MeshVertex]>>, Arc<ImmutableBuffer<[u16]>>, Box<dyn GpuFuture>), VulkanoError> {
+		let (vertices, indices) = self.generate();
+		upload_buffers(queue, &vertices, &indices)
+	}
+}
+
+// Two triangles per cell of a grid of `columns + 1` vertices per row, where
+// going down a row and along a column turn counter clockwise around the normal
+fn grid_indices(rows: u32, columns: u32, indices: &mut Vec<u16>, skip_first: bool, skip_last: bool) {
+	let stride = columns + 1;
+	for row in 0..rows {
+		for column in 0..columns {
+			let a = (row * stride + column) as u16;
+			let b = a + stride as u16;
+			let c = a + 1;
+			let d = b + 1;
+			if !(skip_first && row == 0) {
+				indices.extend_from_slice(&[a, c, b]);
+			}
+			if !(skip_last && row == rows - 1) {
+				indices.extend_from_slice(&[c, d, b]);
+			}
+		}
+	}
+}
+
+fn sphere(radius: f32, rings: u32, sectors: u32) -> (Vec<MeshVertex>, Vec<u16>) {
+	let mut vertices = Vec::with_capacity(((rings + 1) * (sectors + 1)) as usize);
+	for ring in 0..=rings {
+		let v = ring as f32 / rings as f32;
+		let phi = v * PI;
+		for sector in 0..=sectors {
+			let u = sector as f32 / sectors as f32;
+			let theta = u * PI * 2.0;
+			let normal = [phi.sin() * theta.cos(), phi.cos(), phi.sin() * theta.sin()];
+			vertices.push(MeshVertex {
+				position: [normal[0] * radius, normal[1] * radius, normal[2] * radius],
+				normal,
+				uv: [u, v],
+			});
+		}
+	}
+
+	// The first triangle of the top row and the second of the bottom row
+	// would have two vertices on the pole
+	let mut indices = Vec::new();
+	grid_indices(rings, sectors, &mut indices, true, true);
+	(vertices, indices)
+}
+
+fn cylinder(radius: f32, height: f32, segments: u32) -> (Vec<MeshVertex>, Vec<u16>) {
+	let half = height / 2.0;
+	let angles: Vec<f32> = (0..=segments).map(|s| s as f32 / segments as f32 * PI * 2.0).collect();
+
+	let mut vertices = Vec::new();
+	for (s, theta) in angles.iter().enumerate() {
+		let u = s as f32 / segments as f32;
+		let normal = [theta.cos(), 0.0, theta.sin()];
+		vertices.push(MeshVertex {
+			position: [normal[0] * radius, half, normal[2] * radius],
+			normal,
+			uv: [u, 0.0],
+		});
+		vertices.push(MeshVertex {
+			position: [normal[0] * radius, -half, normal[2] * radius],
+			normal,
+			uv: [u, 1.0],
+		});
+	}
+	// The side vertices are laid out as 2 columns per row, `grid_indices`
+	// wants rows of vertices around the axis
+	let mut indices = Vec::new();
+	for s in 0..segments {
+		let a = (2 * s) as u16;
+		let (b, c, d) = (a + 1, a + 2, a + 3);
+		indices.extend_from_slice(&[a, c, b, c, d, b]);
+	}
+
+	for &(y, up) in &[(half, 1.0f32), (-half, -1.0f32)] {
+		let center = vertices.len() as u16;
+		vertices.push(MeshVertex {
+			position: [0.0, y, 0.0],
+			normal: [0.0, up, 0.0],
+			uv: [0.5, 0.5],
+		});
+		for theta in &angles[..segments as usize] {
+			vertices.push(MeshVertex {
+				position: [theta.cos() * radius, y, theta.sin() * radius],
+				normal: [0.0, up, 0.0],
+				uv: [0.5 + 0.5 * theta.cos(), 0.5 + 0.5 * theta.sin()],
+			});
+		}
+		for s in 0..segments as u16 {
+			let rim = center + 1 + s;
+			let next = center + 1 + (s + 1) % segments as u16;
+			if up > 0.0 {
+				indices.extend_from_slice(&[center, next, rim]);
+			} else {
+				indices.extend_from_slice(&[center, rim, next]);
+			}
+		}
+	}
+	(vertices, indices)
+}
+
+fn torus(major_radius: f32, minor_radius: f32, rings: u32, sides: u32) -> (Vec<MeshVertex>, Vec<u16>) {
+	let mut vertices = Vec::with_capacity(((rings + 1) * (sides + 1)) as usize);
+	for ring in 0..=rings {
+		let u = ring as f32 / rings as f32;
+		let theta = u * PI * 2.0;
+		for side in 0..=sides {
+			let v = side as f32 / sides as f32;
+			let phi = v * PI * 2.0;
+			let normal = [phi.cos() * theta.cos(), phi.sin(), phi.cos() * theta.sin()];
+			vertices.push(MeshVertex {
+				position: [
+					major_radius * theta.cos() + minor_radius * normal[0],
+					minor_radius * normal[1],
+					major_radius * theta.sin() + minor_radius * normal[2],
+				],
+				normal,
+				uv: [u, v],
+			});
+		}
+	}
+
+	// Going along a ring turns around the Y axis, like going down a row, and
+	// going along a side turns around the tube
+	let mut indices = Vec::new();
+	grid_indices(rings, sides, &mut indices, false, false);
+	(vertices, indices)
+}
+
+fn plane(width: f32, depth: f32, div_x: u32, div_z: u32) -> (Vec<MeshVertex>, Vec<u16>) {
+	let mut vertices = Vec::with_capacity(((div_x + 1) * (div_z + 1)) as usize);
+	for z in 0..=div_z {
+		let v = z as f32 / div_z as f32;
+		for x in 0..=div_x {
+			let u = x as f32 / div_x as f32;
+			vertices.push(MeshVertex {
+				position: [(u - 0.5) * width, 0.0, (v - 0.5) * depth],
+				normal: [0.0, 1.0, 0.0],
+				uv: [u, v],
+			});
+		}
+	}
+
+	// Rows along +Z and columns along +X turn counter clockwise around +Y
+	let mut indices = Vec::new();
+	let stride = div_x + 1;
+	for z in 0..div_z {
+		for x in 0..div_x {
+			let corner = (z * stride + x) as u16;
+			let below = corner + stride as u16;
+			indices.extend_from_slice(&[corner, below, corner + 1, corner + 1, below, below + 1]);
+		}
+	}
+	(vertices, indices)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn check(mesh: ProceduralMesh, vertex_count: usize, index_count: usize) {
+		let (vertices, indices) = mesh.generate();
+		assert_eq!(vertices.len(), vertex_count, "{:?}", mesh);
+		assert_eq!(indices.len(), index_count, "{:?}", mesh);
+		assert_eq!(mesh.vertex_count(), vertex_count);
+		assert_eq!(mesh.index_count(), index_count);
+		assert!(indices.iter().all(|&index| (index as usize) < vertices.len()));
+		for vertex in &vertices {
+			let length = vertex.normal.iter().map(|n| n * n).sum::<f32>().sqrt();
+			assert!((length - 1.0).abs() < 1e-4, "{:?} has a normal of length {}", mesh, length);
+		}
+	}
+
+	#[test]
+	fn sphere_counts() {
+		check(ProceduralMesh::Sphere { radius: 1.0, rings: 8, sectors: 16 }, 9 * 17, 6 * 16 * 7);
+		// Clamped to 2 rings and 3 sectors
+		check(ProceduralMesh::Sphere { radius: 1.0, rings: 0, sectors: 0 }, 3 * 4, 6 * 3);
+	}
+
+	#[test]
+	fn cylinder_counts() {
+		check(
+			ProceduralMesh::Cylinder {
+				radius: 0.5,
+				height: 2.0,
+				segments: 12,
+			},
+			2 * 13 + 2 * 13,
+			12 * 12,
+		);
+	}
+
+	#[test]
+	fn torus_counts() {
+		check(
+			ProceduralMesh::Torus {
+				major_radius: 1.0,
+				minor_radius: 0.25,
+				rings: 24,
+				sides: 8,
+			},
+			25 * 9,
+			6 * 24 * 8,
+		);
+	}
+
+	#[test]
+	fn plane_counts() {
+		check(
+			ProceduralMesh::Plane {
+				width: 4.0,
+				depth: 2.0,
+				div_x: 4,
+				div_z: 3,
+			},
+			5 * 4,
+			6 * 4 * 3,
+		);
+	}
+
+	#[test]
+	fn triangles_face_outwards() {
+		let meshes = [
+			ProceduralMesh::Sphere { radius: 2.0, rings: 6, sectors: 8 },
+			ProceduralMesh::Cylinder {
+				radius: 1.0,
+				height: 1.0,
+				segments: 8,
+			},
+			ProceduralMesh::Torus {
+				major_radius: 1.0,
+				minor_radius: 0.3,
+				rings: 8,
+				sides: 6,
+			},
+			ProceduralMesh::Plane {
+				width: 1.0,
+				depth: 1.0,
+				div_x: 2,
+				div_z: 2,
+			},
+		];
+		for mesh in &meshes {
+			let (vertices, indices) = mesh.generate();
+			for triangle in indices.chunks(3) {
+				let [a, b, c] = [0, 1, 2].map(|i| vertices[triangle[i] as usize]);
+				let ab = [0, 1, 2].map(|i| b.position[i] - a.position[i]);
+				let ac = [0, 1, 2].map(|i| c.position[i] - a.position[i]);
+				let cross = [
+					ab[1] * ac[2] - ab[2] * ac[1],
+					ab[2] * ac[0] - ab[0] * ac[2],
+					ab[0] * ac[1] - ab[1] * ac[0],
+				];
+				let normal = [0, 1, 2].map(|i| a.normal[i] + b.normal[i] + c.normal[i]);
+				let facing: f32 = (0..3).map(|i| cross[i] * normal[i]).sum();
+				assert!(facing > 0.0, "{:?} has a triangle facing inwards: {:?}", mesh, triangle);
+			}
+		}
+	}
+}
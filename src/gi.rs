@@ -0,0 +1,944 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::{LABEL_COLOR_COMPUTE, LABEL_COLOR_RENDER_PASS};
+use crate::error::VulkanoError;
+use crate::forward_plus::ForwardVertex;
+use crate::lighting::{SHProbe, SH_COEFFICIENTS};
+use crate::pipeline::{SharedComputePipeline, SharedPipeline};
+
+// Voxels along each side of the volume, must match the shaders
+pub const VOXEL_RESOLUTION: u32 = 128;
+pub const VOXEL_FORMAT: Format = Format::R8G8B8A8Unorm;
+// Levels of the anisotropic volumes, from half the resolution down to 2 voxels
+pub const ANISOTROPIC_LEVELS: usize = 6;
+// +X, -X, +Y, -Y, +Z, -Z side by side along X in each anisotropic level
+const DIRECTIONS: u32 = 6;
+
+mod voxel_clear_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 4, local_size_y = 4, local_size_z = 4) in;
+
+			layout(set = 0, binding = 0, rgba8) uniform writeonly image3D voxels;
+
+			void main() {
+				imageStore(voxels, ivec3(gl_GlobalInvocationID), vec4(0.0));
+			}
+		"
+	}
+}
+
+mod voxelize_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+
+			layout(location = 0) out vec3 v_volume;
+			layout(location = 1) out vec3 v_normal;
+
+			layout(set = 0, binding = 1) uniform Volume {
+				// Center in xyz, half the side in w
+				vec4 bounds;
+				// Towards the light
+				vec4 light_direction;
+				vec4 light_color;
+			} volume;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+				vec4 emission;
+			} pc;
+
+			void main() {
+				vec4 world = pc.model * vec4(position, 1.0);
+				// [-1, 1] over the volume
+				v_volume = (world.xyz - volume.bounds.xyz) / volume.bounds.w;
+				v_normal = mat3(pc.model) * normal;
+			}
+		"
+	}
+}
+
+mod voxelize_gs {
+	vulkano_shaders::shader! {
+		ty: "geometry",
+		src: "
+			#version 450
+
+			layout(triangles) in;
+			layout(triangle_strip, max_vertices = 3) out;
+
+			layout(location = 0) in vec3 v_volume[];
+			layout(location = 1) in vec3 v_normal[];
+
+			layout(location = 0) out vec3 g_volume;
+			layout(location = 1) out vec3 g_normal;
+
+			void main() {
+				// Projected along the axis the triangle faces most, so it covers
+				// as many voxels as possible
+				vec3 face = abs(cross(v_volume[1] - v_volume[0], v_volume[2] - v_volume[0]));
+				for (int i = 0; i < 3; i++) {
+					vec3 p = v_volume[i];
+					vec3 projected = face.x >= face.y && face.x >= face.z ? p.yzx : face.y >= face.z ? p.zxy : p;
+					g_volume = p;
+					g_normal = v_normal[i];
+					gl_Position = vec4(projected.xy, projected.z * 0.5 + 0.5, 1.0);
+					EmitVertex();
+				}
+				EndPrimitive();
+			}
+		"
+	}
+}
+
+mod voxelize_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 g_volume;
+			layout(location = 1) in vec3 g_normal;
+
+			layout(set = 0, binding = 0, rgba8) uniform writeonly image3D voxels;
+			layout(set = 0, binding = 1) uniform Volume {
+				vec4 bounds;
+				vec4 light_direction;
+				vec4 light_color;
+			} volume;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+				vec4 emission;
+			} pc;
+
+			const int RESOLUTION = 128;
+
+			void main() {
+				ivec3 voxel = ivec3((g_volume * 0.5 + 0.5) * float(RESOLUTION));
+				if (any(lessThan(voxel, ivec3(0))) || any(greaterThanEqual(voxel, ivec3(RESOLUTION)))) {
+					return;
+				}
+				// The light the surface sends back, unshadowed
+				float diffuse = max(dot(normalize(g_normal), volume.light_direction.xyz), 0.0);
+				vec3 radiance = pc.albedo.rgb * volume.light_color.rgb * diffuse + pc.emission.rgb;
+				imageStore(voxels, voxel, vec4(min(radiance, vec3(1.0)), 1.0));
+			}
+		"
+	}
+}
+
+mod anisotropic_mip_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 4, local_size_y = 4, local_size_z = 4) in;
+
+			// The voxels, or the directions of the previous level side by side along X
+			layout(set = 0, binding = 0, rgba8) uniform readonly image3D source;
+			layout(set = 0, binding = 1, rgba8) uniform writeonly image3D destination;
+
+			layout(push_constant) uniform PushConstants {
+				// Side of the destination level
+				int size;
+				// 0 when the source is the voxels
+				int anisotropic_source;
+			} pc;
+
+			const ivec3 AXES[6] = ivec3[](
+				ivec3(1, 0, 0), ivec3(-1, 0, 0),
+				ivec3(0, 1, 0), ivec3(0, -1, 0),
+				ivec3(0, 0, 1), ivec3(0, 0, -1)
+			);
+
+			vec4 load(ivec3 child, int direction) {
+				if (pc.anisotropic_source != 0) {
+					child.x += direction * pc.size * 2;
+				}
+				return imageLoad(source, child);
+			}
+
+			void main() {
+				ivec3 id = ivec3(gl_GlobalInvocationID);
+				int direction = id.x / pc.size;
+				ivec3 voxel = ivec3(id.x % pc.size, id.yz);
+				if (direction >= 6 || any(greaterThanEqual(voxel, ivec3(pc.size)))) {
+					return;
+				}
+
+				// The 4 pairs of children along the direction, blended front to back
+				ivec3 axis = AXES[direction];
+				ivec3 along = abs(axis);
+				ivec3 side_a = along.yzx;
+				ivec3 side_b = along.zxy;
+				ivec3 base = voxel * 2;
+				vec4 sum = vec4(0.0);
+				for (int a = 0; a < 2; a++) {
+					for (int b = 0; b < 2; b++) {
+						ivec3 corner = base + side_a * a + side_b * b;
+						bool forward = axis.x + axis.y + axis.z > 0;
+						vec4 near = load(forward ? corner : corner + along, direction);
+						vec4 far = load(forward ? corner + along : corner, direction);
+						vec3 color = near.rgb * near.a + far.rgb * far.a * (1.0 - near.a);
+						float alpha = near.a + far.a * (1.0 - near.a);
+						sum += vec4(alpha > 0.0 ? color / alpha : vec3(0.0), alpha);
+					}
+				}
+				imageStore(destination, ivec3(voxel.x + direction * pc.size, voxel.yz), sum * 0.25);
+			}
+		"
+	}
+}
+
+mod cone_trace_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+
+			layout(location = 0) out vec3 v_position;
+			layout(location = 1) out vec3 v_normal;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view_projection;
+				vec4 camera_position;
+			} camera;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+				float roughness;
+				float indirect_strength;
+			} pc;
+
+			void main() {
+				vec4 world = pc.model * vec4(position, 1.0);
+				v_position = world.xyz;
+				v_normal = mat3(pc.model) * normal;
+				gl_Position = camera.view_projection * world;
+			}
+		"
+	}
+}
+
+mod cone_trace_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 v_position;
+			layout(location = 1) in vec3 v_normal;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view_projection;
+				vec4 camera_position;
+			} camera;
+			layout(set = 0, binding = 1) uniform Volume {
+				vec4 bounds;
+				vec4 light_direction;
+				vec4 light_color;
+			} volume;
+			layout(set = 0, binding = 2) uniform sampler3D voxels;
+			layout(set = 0, binding = 3) uniform sampler3D anisotropic[6];
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+				float roughness;
+				float indirect_strength;
+			} pc;
+
+			const float RESOLUTION = 128.0;
+			const int LEVELS = 6;
+			// Cones stop after crossing this much of the volume
+			const float MAX_DISTANCE = 1.0;
+
+			// The indices must be constant without the descriptor indexing features
+			vec4 sample_anisotropic(int level, vec3 uvw) {
+				switch (level) {
+				case 0: return textureLod(anisotropic[0], uvw, 0.0);
+				case 1: return textureLod(anisotropic[1], uvw, 0.0);
+				case 2: return textureLod(anisotropic[2], uvw, 0.0);
+				case 3: return textureLod(anisotropic[3], uvw, 0.0);
+				case 4: return textureLod(anisotropic[4], uvw, 0.0);
+				default: return textureLod(anisotropic[5], uvw, 0.0);
+				}
+			}
+
+			// The direction of a level as seen by a cone going along `direction`,
+			// the faces weighted by how much the cone goes along their axis
+			vec4 sample_level(int level, vec3 uvw, vec3 direction) {
+				float size = RESOLUTION / float(2 << level);
+				vec3 inner = clamp(uvw, vec3(0.5 / size), vec3(1.0 - 0.5 / size));
+				vec3 weights = direction * direction;
+				ivec3 faces = ivec3(direction.x < 0.0 ? 1 : 0, direction.y < 0.0 ? 3 : 2, direction.z < 0.0 ? 5 : 4);
+				vec4 result = vec4(0.0);
+				for (int axis = 0; axis < 3; axis++) {
+					vec3 slab = vec3((float(faces[axis]) + inner.x) / 6.0, inner.yz);
+					result += sample_anisotropic(level, slab) * weights[axis];
+				}
+				return result;
+			}
+
+			vec4 sample_volume(vec3 uvw, vec3 direction, float level) {
+				if (level < 1.0) {
+					return mix(textureLod(voxels, uvw, 0.0), sample_level(0, uvw, direction), level);
+				}
+				float anisotropic_level = min(level - 1.0, float(LEVELS - 1));
+				int lower = int(anisotropic_level);
+				int upper = min(lower + 1, LEVELS - 1);
+				return mix(
+					sample_level(lower, uvw, direction),
+					sample_level(upper, uvw, direction),
+					anisotropic_level - float(lower)
+				);
+			}
+
+			// Front to back through the volume, the samples growing with the
+			// cone; `origin` and the distances are in [0, 1] volume units
+			vec3 trace_cone(vec3 origin, vec3 direction, float aperture) {
+				float voxel = 1.0 / RESOLUTION;
+				vec4 accumulated = vec4(0.0);
+				float t = voxel * 2.0;
+				while (t < MAX_DISTANCE && accumulated.a < 0.95) {
+					vec3 uvw = origin + direction * t;
+					if (any(lessThan(uvw, vec3(0.0))) || any(greaterThan(uvw, vec3(1.0)))) {
+						break;
+					}
+					float diameter = max(voxel, 2.0 * aperture * t);
+					vec4 s = sample_volume(uvw, direction, log2(diameter / voxel));
+					accumulated.rgb += (1.0 - accumulated.a) * s.a * s.rgb;
+					accumulated.a += (1.0 - accumulated.a) * s.a;
+					t += diameter * 0.5;
+				}
+				return accumulated.rgb;
+			}
+
+			void main() {
+				vec3 normal = normalize(v_normal);
+				vec3 origin = (v_position - volume.bounds.xyz) / volume.bounds.w * 0.5 + 0.5;
+				// Out of the voxels of the surface itself
+				origin += normal / RESOLUTION;
+
+				// One cone along the normal and five around it at 60 degrees
+				vec3 helper = abs(normal.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+				vec3 tangent = normalize(cross(helper, normal));
+				vec3 bitangent = cross(normal, tangent);
+				const float DIFFUSE_APERTURE = 0.577;
+				vec3 indirect = trace_cone(origin, normal, DIFFUSE_APERTURE) * 0.25;
+				for (int i = 0; i < 5; i++) {
+					float angle = 6.2831853 * float(i) / 5.0;
+					vec3 side = cos(angle) * tangent + sin(angle) * bitangent;
+					vec3 direction = normalize(normal * 0.5 + side * 0.866);
+					indirect += trace_cone(origin, direction, DIFFUSE_APERTURE) * 0.15;
+				}
+
+				vec3 to_camera = normalize(camera.camera_position.xyz - v_position);
+				vec3 reflected = reflect(-to_camera, normal);
+				vec3 specular = trace_cone(origin, reflected, max(pc.roughness, 0.05));
+
+				float diffuse = max(dot(normal, volume.light_direction.xyz), 0.0);
+				vec3 direct = volume.light_color.rgb * diffuse;
+				vec3 color = pc.albedo.rgb * (direct + indirect * pc.indirect_strength) + specular * (1.0 - pc.roughness) * pc.indirect_strength;
+				f_color = vec4(color, pc.albedo.a);
+			}
+		"
+	}
+}
+
+// A mesh voxelized into the volume
+#[derive(Clone)]
+pub struct VoxelMesh {
+	pub vertices: Arc<CpuAccessibleBuffer<[ForwardVertex]>>,
+	pub indices: Arc<CpuAccessibleBuffer<[u32]>>,
+	pub model: [[f32; 4]; 4],
+	pub albedo: [f32; 4],
+	pub emission: [f32; 3],
+}
+
+fn volume_image(device: &Arc<Device>, [width, height, depth]: [u32; 3]) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim3d { width, height, depth },
+		VOXEL_FORMAT,
+		ImageUsage {
+			storage: true,
+			sampled: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+// Voxel cone tracing. Every `rebuild_interval` frames the scene is voxelized
+// into a 128^3 volume: each triangle is rasterized along its dominant axis
+// into a render pass without attachments, and its fragments store the light
+// they send back in their voxel. A compute pass then builds the anisotropic
+// mips: each level stores, for each of the 6 axis directions, the children
+// blended front to back along that direction. The receivers trace 6 diffuse
+// cones over their hemisphere and a specular cone through the mips for their
+// indirect lighting.
+// vulkano 0.22 storage images have a single mip level, each level is its own
+// image with the 6 directions side by side along X.
+pub struct VoxelConeTracing {
+	clear_pipeline: SharedComputePipeline,
+	mip_pipeline: SharedComputePipeline,
+	voxelize_pipeline: SharedPipeline,
+	cone_trace_pipeline: SharedPipeline,
+	framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+	voxels: Arc<StorageImage<Format>>,
+	anisotropic: Vec<Arc<StorageImage<Format>>>,
+	volume_pool: CpuBufferPool<voxelize_fs::ty::Volume>,
+	camera_pool: CpuBufferPool<cone_trace_fs::ty::Camera>,
+	sampler: Arc<Sampler>,
+	dynamic_state: DynamicState,
+	// Written by `update` for the draws of the same frame
+	cone_trace_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+	frame: u32,
+	// Center of the volume and half its side, in world units
+	pub center: [f32; 3],
+	pub half_extent: f32,
+	pub rebuild_interval: u32,
+	pub indirect_strength: f32,
+	// Normalized, towards the light
+	pub light_direction: [f32; 3],
+	pub light_color: [f32; 3],
+}
+
+impl VoxelConeTracing {
+	// The receivers are drawn in `subpass`
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		center: [f32; 3],
+		half_extent: f32,
+	) -> Result<VoxelConeTracing, VulkanoError> {
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::single_pass_renderpass!(
+				device.clone(),
+				attachments: {},
+				pass: {
+					color: [],
+					depth_stencil: {}
+				}
+			)?,
+		);
+		let framebuffer = Arc::new(
+			Framebuffer::with_dimensions(render_pass.clone(), [VOXEL_RESOLUTION, VOXEL_RESOLUTION, 1]).build()?,
+		);
+
+		let voxelize_vs = voxelize_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let voxelize_gs = voxelize_gs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let voxelize_fs = voxelize_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let voxelize_pipeline: SharedPipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<ForwardVertex>()
+				.vertex_shader(voxelize_vs.main_entry_point(), ())
+				.geometry_shader(voxelize_gs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.cull_mode_disabled()
+				.fragment_shader(voxelize_fs.main_entry_point(), ())
+				.render_pass(Subpass::from(render_pass, 0).ok_or(VulkanoError::NoSubpass)?)
+				.build(device.clone())?,
+		);
+
+		let cone_trace_vs = cone_trace_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let cone_trace_fs = cone_trace_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let has_depth = subpass.has_depth();
+		let builder = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<ForwardVertex>()
+			.vertex_shader(cone_trace_vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(cone_trace_fs.main_entry_point(), ());
+		let builder = if has_depth {
+			builder.depth_stencil_simple_depth()
+		} else {
+			builder
+		};
+		let cone_trace_pipeline: SharedPipeline = Arc::new(builder.render_pass(subpass).build(device.clone())?);
+
+		let clear_shader = voxel_clear_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let mip_shader = anisotropic_mip_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let voxels = volume_image(&device, [VOXEL_RESOLUTION; 3])?;
+		let anisotropic = (0..ANISOTROPIC_LEVELS)
+			.map(|level| {
+				let size = VOXEL_RESOLUTION >> (level + 1);
+				volume_image(&device, [size * DIRECTIONS, size, size])
+			})
+			.collect::<Result<_, _>>()?;
+
+		let dynamic_state = DynamicState {
+			viewports: Some(vec![Viewport {
+				origin: [0.0, 0.0],
+				dimensions: [VOXEL_RESOLUTION as f32, VOXEL_RESOLUTION as f32],
+				depth_range: 0.0..1.0,
+			}]),
+			..DynamicState::none()
+		};
+
+		Ok(VoxelConeTracing {
+			clear_pipeline: Arc::new(ComputePipeline::new(device.clone(), &clear_shader.main_entry_point(), &(), None)?),
+			mip_pipeline: Arc::new(ComputePipeline::new(device.clone(), &mip_shader.main_entry_point(), &(), None)?),
+			voxelize_pipeline,
+			cone_trace_pipeline,
+			framebuffer,
+			voxels,
+			anisotropic,
+			volume_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			sampler: Sampler::new(
+				device,
+				Filter::Linear,
+				Filter::Linear,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)?,
+			dynamic_state,
+			cone_trace_set: None,
+			frame: 0,
+			center,
+			half_extent,
+			rebuild_interval: 4,
+			indirect_strength: 1.0,
+			light_direction: [0.0, 1.0, 0.0],
+			light_color: [1.0, 1.0, 1.0],
+		})
+	}
+
+	// Records the voxelization and the mips when the volume is due for a
+	// rebuild, outside of any render pass, and prepares the cone tracing of
+	// the frame. Returns whether the volume was rebuilt.
+	pub fn update(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		meshes: &[VoxelMesh],
+		view_projection: [[f32; 4]; 4],
+		camera_position: [f32; 3],
+	) -> Result<bool, VulkanoError> {
+		let [x, y, z] = self.center;
+		let [lx, ly, lz] = self.light_direction;
+		let [r, g, b] = self.light_color;
+		let volume = self.volume_pool.next(voxelize_fs::ty::Volume {
+			bounds: [x, y, z, self.half_extent],
+			light_direction: [lx, ly, lz, 0.0],
+			light_color: [r, g, b, 1.0],
+		})?;
+
+		let rebuild = self.frame.is_multiple_of(self.rebuild_interval.max(1));
+		self.frame = self.frame.wrapping_add(1);
+		if rebuild {
+			self.voxelize(builder, meshes, volume.clone())?;
+			self.build_mips(builder)?;
+		}
+
+		let [cx, cy, cz] = camera_position;
+		let camera = self.camera_pool.next(cone_trace_fs::ty::Camera {
+			view_projection,
+			camera_position: [cx, cy, cz, 1.0],
+		})?;
+		let layout = self
+			.cone_trace_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let level = |level: usize| ImageView::new(self.anisotropic[level].clone());
+		self.cone_trace_set = Some(Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_buffer(volume)?
+				.add_sampled_image(ImageView::new(self.voxels.clone())?, self.sampler.clone())?
+				.enter_array()?
+				.add_sampled_image(level(0)?, self.sampler.clone())?
+				.add_sampled_image(level(1)?, self.sampler.clone())?
+				.add_sampled_image(level(2)?, self.sampler.clone())?
+				.add_sampled_image(level(3)?, self.sampler.clone())?
+				.add_sampled_image(level(4)?, self.sampler.clone())?
+				.add_sampled_image(level(5)?, self.sampler.clone())?
+				.leave_array()?
+				.build()?,
+		));
+		Ok(rebuild)
+	}
+
+	fn voxelize<B>(&self, builder: &mut AutoCommandBufferBuilder, meshes: &[VoxelMesh], volume: B) -> Result<(), VulkanoError>
+	where
+		B: BufferAccess + Send + Sync + 'static,
+	{
+		let layout = self
+			.clear_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(self.voxels.clone())?)?
+				.build()?,
+		);
+		let groups = VOXEL_RESOLUTION / 4;
+		debug_label!(builder, "VoxelConeTracing clear", LABEL_COLOR_COMPUTE);
+		builder.dispatch([groups, groups, groups], self.clear_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+
+		let layout = self
+			.voxelize_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(self.voxels.clone())?)?
+				.add_buffer(volume)?
+				.build()?,
+		);
+		debug_label!(builder, "VoxelConeTracing", LABEL_COLOR_RENDER_PASS);
+		builder.begin_render_pass(self.framebuffer.clone(), SubpassContents::Inline, vec![])?;
+		for mesh in meshes {
+			let [r, g, b] = mesh.emission;
+			builder.draw_indexed(
+				self.voxelize_pipeline.clone(),
+				&self.dynamic_state,
+				vec![mesh.vertices.clone() as Arc<dyn BufferAccess + Send + Sync>],
+				mesh.indices.clone(),
+				set.clone(),
+				voxelize_vs::ty::PushConstants {
+					model: mesh.model,
+					albedo: mesh.albedo,
+					emission: [r, g, b, 0.0],
+				},
+				vec![],
+			)?;
+		}
+		builder.end_render_pass()?;
+		debug_label!(builder);
+		Ok(())
+	}
+
+	fn build_mips(&self, builder: &mut AutoCommandBufferBuilder) -> Result<(), VulkanoError> {
+		let layout = self
+			.mip_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		for level in 0..ANISOTROPIC_LEVELS {
+			let source = if level == 0 {
+				self.voxels.clone()
+			} else {
+				self.anisotropic[level - 1].clone()
+			};
+			let set = Arc::new(
+				PersistentDescriptorSet::start(layout.clone())
+					.add_image(ImageView::new(source)?)?
+					.add_image(ImageView::new(self.anisotropic[level].clone())?)?
+					.build()?,
+			);
+			let size = VOXEL_RESOLUTION >> (level + 1);
+			let groups = size.div_ceil(4);
+			debug_label!(builder, "VoxelConeTracing mip", LABEL_COLOR_COMPUTE);
+			builder.dispatch(
+				[groups * DIRECTIONS, groups, groups],
+				self.mip_pipeline.clone(),
+				set,
+				anisotropic_mip_cs::ty::PushConstants {
+					size: size as i32,
+					anisotropic_source: (level > 0) as i32,
+				},
+				vec![],
+			)?;
+			debug_label!(builder);
+		}
+		Ok(())
+	}
+
+	// Records a receiver lit directly and by the cones, inside the render pass
+	#[allow(clippy::too_many_arguments)]
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		vertices: Arc<CpuAccessibleBuffer<[ForwardVertex]>>,
+		indices: Arc<CpuAccessibleBuffer<[u32]>>,
+		model: [[f32; 4]; 4],
+		albedo: [f32; 4],
+		roughness: f32,
+	) -> Result<(), VulkanoError> {
+		let set = self
+			.cone_trace_set
+			.clone()
+			.expect("the volume must be updated before drawing");
+		builder.draw_indexed(
+			self.cone_trace_pipeline.clone(),
+			dynamic_state,
+			vec![vertices as Arc<dyn BufferAccess + Send + Sync>],
+			indices,
+			set,
+			cone_trace_vs::ty::PushConstants {
+				model,
+				albedo,
+				roughness,
+				indirect_strength: self.indirect_strength,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+// Probes along each axis of the scene bounds
+pub const PROBE_GRID_DIMENSIONS: [u32; 3] = [8, 4, 8];
+// Frames over which every probe of the grid is captured once
+pub const PROBE_UPDATE_FRAMES: usize = 64;
+pub const PROBE_GRID_FORMAT: Format = Format::R8G8B8A8Unorm;
+
+const PROBE_COUNT: usize = (PROBE_GRID_DIMENSIONS[0] * PROBE_GRID_DIMENSIONS[1] * PROBE_GRID_DIMENSIONS[2]) as usize;
+const PROBES_PER_FRAME: usize = PROBE_COUNT.div_ceil(PROBE_UPDATE_FRAMES);
+
+// A probe moved to the grid positions in turn, waiting for the readback of
+// the probe it last captured
+struct ProbeWorker {
+	probe: SHProbe,
+	pending: Option<usize>,
+}
+
+// Ambient lighting varying over the scene from a grid of SH probes spread
+// over its bounds. A few probes are captured each frame, cycling through the
+// grid so that it is entirely refreshed every PROBE_UPDATE_FRAMES frames, and
+// the probes invalidated by a geometry change go first. Keeping a cube map
+// per probe would take hundreds of megabytes, so a small pool of `SHProbe`s
+// is moved from position to position, each read back a frame later when its
+// commands are done. The coefficients of the grid are uploaded to a 3D image,
+// 9 RGBA8 texels per probe, which src/shaders/probe_grid.glsl interpolates
+// per fragment.
+pub struct ProbeGrid {
+	workers: Vec<ProbeWorker>,
+	coefficients: Vec<Option<[[f32; 3]; SH_COEFFICIENTS]>>,
+	image: Arc<StorageImage<Format>>,
+	// Probes to capture before resuming the cycle
+	invalidated: VecDeque<usize>,
+	next: usize,
+	// The range the coefficients are encoded over in the image
+	range: f32,
+	dirty: bool,
+	device: Arc<Device>,
+	pub bounds_min: [f32; 3],
+	pub bounds_max: [f32; 3],
+}
+
+impl ProbeGrid {
+	pub fn new(device: Arc<Device>, bounds_min: [f32; 3], bounds_max: [f32; 3]) -> Result<ProbeGrid, VulkanoError> {
+		// Two frames of probes, those read back and those captured
+		let workers = (0..2 * PROBES_PER_FRAME)
+			.map(|_| {
+				Ok(ProbeWorker {
+					probe: SHProbe::new(device.clone(), bounds_min)?,
+					pending: None,
+				})
+			})
+			.collect::<Result<_, VulkanoError>>()?;
+		let [x, y, z] = PROBE_GRID_DIMENSIONS;
+		let image = StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim3d {
+				width: x * SH_COEFFICIENTS as u32,
+				height: y,
+				depth: z,
+			},
+			PROBE_GRID_FORMAT,
+			ImageUsage {
+				transfer_destination: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)?;
+
+		Ok(ProbeGrid {
+			workers,
+			coefficients: vec![None; PROBE_COUNT],
+			image,
+			invalidated: VecDeque::new(),
+			next: 0,
+			range: 1.0,
+			dirty: true,
+			device,
+			bounds_min,
+			bounds_max,
+		})
+	}
+
+	// The probes are on the grid points, the outer ones on the bounds
+	pub fn probe_position(&self, index: usize) -> [f32; 3] {
+		let [width, height, _] = PROBE_GRID_DIMENSIONS;
+		let cell = [
+			index as u32 % width,
+			index as u32 / width % height,
+			index as u32 / (width * height),
+		];
+		let mut position = [0.0; 3];
+		for axis in 0..3 {
+			let t = cell[axis] as f32 / (PROBE_GRID_DIMENSIONS[axis] - 1) as f32;
+			position[axis] = self.bounds_min[axis] + (self.bounds_max[axis] - self.bounds_min[axis]) * t;
+		}
+		position
+	}
+
+	// Captures the probes within one cell of the changed geometry first, they
+	// keep their previous lighting until then
+	pub fn invalidate(&mut self, changed_min: [f32; 3], changed_max: [f32; 3]) {
+		for index in 0..PROBE_COUNT {
+			let position = self.probe_position(index);
+			let near = (0..3).all(|axis| {
+				let spacing = (self.bounds_max[axis] - self.bounds_min[axis]) / (PROBE_GRID_DIMENSIONS[axis] - 1) as f32;
+				position[axis] >= changed_min[axis] - spacing && position[axis] <= changed_max[axis] + spacing
+			});
+			if near && !self.invalidated.contains(&index) {
+				self.invalidated.push_back(index);
+			}
+		}
+	}
+
+	pub fn invalidate_all(&mut self) {
+		self.invalidated = (0..PROBE_COUNT).collect();
+	}
+
+	// Reads back the probes captured by the previous calls, uploads the grid
+	// when it changed and records the next captures, outside of any render
+	// pass. `draw_scene` is called like for `SHProbe::capture`. The commands of
+	// the previous call must have been submitted.
+	pub fn update<F>(&mut self, builder: &mut AutoCommandBufferBuilder, mut draw_scene: F) -> Result<(), VulkanoError>
+	where
+		F: FnMut(&mut AutoCommandBufferBuilder, &DynamicState, [[f32; 4]; 4]) -> Result<(), VulkanoError>,
+	{
+		for worker in self.workers.iter_mut() {
+			let index = match worker.pending {
+				Some(index) => index,
+				None => continue,
+			};
+			match worker.probe.coefficients() {
+				Ok(coefficients) => {
+					self.coefficients[index] = Some(coefficients);
+					worker.pending = None;
+					self.dirty = true;
+				}
+				// Still being computed, tried again next frame
+				Err(VulkanoError::BufferRead(_)) => {}
+				Err(error) => return Err(error),
+			}
+		}
+
+		if self.dirty {
+			self.upload(builder)?;
+			self.dirty = false;
+		}
+
+		let free: Vec<usize> = (0..self.workers.len())
+			.filter(|&worker| self.workers[worker].pending.is_none())
+			.take(PROBES_PER_FRAME)
+			.collect();
+		for worker_index in free {
+			let index = match self.invalidated.pop_front() {
+				Some(index) => index,
+				None => {
+					let index = self.next;
+					self.next = (self.next + 1) % PROBE_COUNT;
+					index
+				}
+			};
+			let position = self.probe_position(index);
+			let worker = &mut self.workers[worker_index];
+			worker.probe.set_position(position);
+			worker.probe.capture(builder, &mut draw_scene)?;
+			worker.pending = Some(index);
+		}
+		Ok(())
+	}
+
+	fn upload(&mut self, builder: &mut AutoCommandBufferBuilder) -> Result<(), VulkanoError> {
+		self.range = self
+			.coefficients
+			.iter()
+			.flatten()
+			.flat_map(|coefficients| coefficients.iter().flatten())
+			.fold(1e-4f32, |range, value| range.max(value.abs()));
+
+		let encode = |value: f32| ((value / self.range * 0.5 + 0.5).clamp(0.0, 1.0) * 255.0).round() as u8;
+		// The probes are laid out like the texels of the image, X first
+		let mut texels = Vec::with_capacity(PROBE_COUNT * SH_COEFFICIENTS * 4);
+		for probe in self.coefficients.iter() {
+			for coefficient in 0..SH_COEFFICIENTS {
+				match probe {
+					Some(coefficients) => {
+						let [r, g, b] = coefficients[coefficient];
+						texels.extend_from_slice(&[encode(r), encode(g), encode(b), 255]);
+					}
+					None => texels.extend_from_slice(&[128, 128, 128, 0]),
+				}
+			}
+		}
+		let staging = CpuAccessibleBuffer::from_iter(
+			self.device.clone(),
+			BufferUsage::transfer_source(),
+			false,
+			texels.into_iter(),
+		)?;
+		builder.copy_buffer_to_image(staging, self.image.clone())?;
+		Ok(())
+	}
+
+	// The coefficients of the grid, to sample with probe_grid.glsl
+	pub fn image(&self) -> Arc<StorageImage<Format>> {
+		self.image.clone()
+	}
+
+	// The bounds, the encoding range and the dimensions packed for the uniform
+	// block of probe_grid.glsl
+	pub fn uniform(&self) -> [[f32; 4]; 3] {
+		let [x, y, z] = self.bounds_min;
+		let [mx, my, mz] = self.bounds_max;
+		let [width, height, depth] = PROBE_GRID_DIMENSIONS;
+		[
+			[x, y, z, self.range],
+			[mx, my, mz, 0.0],
+			[width as f32, height as f32, depth as f32, 0.0],
+		]
+	}
+}
@@ -21,8 +21,70 @@ pub mod fs {
 
 			layout(location = 0) out vec4 f_color;
 
+			// One of the TRANSFER_* constants of vulk_utils, matching the swapchain format and color space
+			layout(constant_id = 0) const uint transfer_function = 0;
+
+			// Brightness of the SDR white on HDR displays, as recommended by BT.2408
+			const float SDR_WHITE_NITS = 203.0;
+
+			// BT.709 to BT.2020 primaries, columns
+			const mat3 BT709_TO_BT2020 = mat3(
+				0.6274, 0.0691, 0.0164,
+				0.3293, 0.9195, 0.0880,
+				0.0433, 0.0114, 0.8956
+			);
+
+			// SMPTE ST 2084 inverse EOTF, of a luminance normalized to 10000 nits
+			vec3 pq(vec3 luminance) {
+				const float m1 = 0.1593017578125;
+				const float m2 = 78.84375;
+				const float c1 = 0.8359375;
+				const float c2 = 18.8515625;
+				const float c3 = 18.6875;
+				vec3 p = pow(max(luminance, 0.0), vec3(m1));
+				return pow((c1 + c2 * p) / (1.0 + c3 * p), vec3(m2));
+			}
+
+			// sRGB inverse EOTF, for the formats without the hardware encoding
+			vec3 srgb(vec3 color) {
+				color = clamp(color, 0.0, 1.0);
+				return mix(
+					color * 12.92,
+					1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055,
+					greaterThan(color, vec3(0.0031308))
+				);
+			}
+
+			vec3 encode(vec3 color) {
+				if (transfer_function == 2) {
+					return pq(BT709_TO_BT2020 * color * (SDR_WHITE_NITS / 10000.0));
+				}
+				if (transfer_function == 1) {
+					return srgb(color);
+				}
+				// scRGB and the *Srgb formats are written as is
+				return color;
+			}
+
+			void main() {
+				f_color = vec4(encode(vec3(1.0, 0.0, 0.0)), 1.0);
+			}
+		"
+	}
+}
+
+// Covers the screen with a single triangle, to be used with `BufferlessDefinition`
+pub mod fullscreen_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) out vec2 v_uv;
+
 			void main() {
-				f_color = vec4(1.0, 0.0, 0.0, 1.0);
+				v_uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+				gl_Position = vec4(v_uv * 2.0 - 1.0, 0.0, 1.0);
 			}
 		"
 	}
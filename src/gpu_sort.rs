@@ -0,0 +1,520 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::pipeline::ComputePipeline;
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::pipeline::SharedComputePipeline;
+use crate::transparency::sortable_bits;
+
+// Must match local_size_x and KEYS_PER_THREAD of the shaders
+const SORT_WORKGROUP_SIZE: usize = 256;
+const SORT_KEYS_PER_THREAD: usize = 4;
+const SORT_BLOCK: usize = SORT_WORKGROUP_SIZE * SORT_KEYS_PER_THREAD;
+// 8 bits per pass
+const SORT_RADIX: usize = 256;
+const SORT_PASSES: usize = 4;
+// Object ids are the low 16 bits of the keys
+pub const MAX_SORT_KEYS: usize = 1 << 16;
+
+mod radix_histogram_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			#define KEYS_PER_THREAD 4
+
+			layout(local_size_x = 256, local_size_y = 1, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) readonly buffer Source {
+				uint keys[];
+			} source;
+			// Count of each digit in each block, digit major
+			layout(set = 0, binding = 1) writeonly buffer Histograms {
+				uint counts[];
+			} histograms;
+
+			layout(push_constant) uniform PushConstants {
+				uint count;
+				uint shift;
+			} pc;
+
+			shared uint counts[256];
+
+			void main() {
+				uint lane = gl_LocalInvocationID.x;
+				counts[lane] = 0;
+				barrier();
+
+				uint first = gl_WorkGroupID.x * gl_WorkGroupSize.x * KEYS_PER_THREAD;
+				for (uint i = 0; i < KEYS_PER_THREAD; i++) {
+					uint index = first + i * gl_WorkGroupSize.x + lane;
+					if (index < pc.count) {
+						atomicAdd(counts[(source.keys[index] >> pc.shift) & 0xff], 1);
+					}
+				}
+				barrier();
+
+				histograms.counts[lane * gl_NumWorkGroups.x + gl_WorkGroupID.x] = counts[lane];
+			}
+		"
+	}
+}
+
+mod radix_scan_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			// One invocation per digit, in a single workgroup
+			layout(local_size_x = 256, local_size_y = 1, local_size_z = 1) in;
+
+			// Counts in, exclusive prefix sums out: where the keys of each digit
+			// of each block go
+			layout(set = 0, binding = 0) buffer Histograms {
+				uint counts[];
+			} histograms;
+
+			layout(push_constant) uniform PushConstants {
+				uint block_count;
+			} pc;
+
+			shared uint sums[256];
+
+			void main() {
+				uint lane = gl_LocalInvocationID.x;
+				uint first = lane * pc.block_count;
+				uint total = 0;
+				for (uint i = 0; i < pc.block_count; i++) {
+					total += histograms.counts[first + i];
+				}
+
+				// Inclusive scan of the digit totals, the keys of the digits
+				// before this one are what precedes this digit's total
+				sums[lane] = total;
+				barrier();
+				for (uint stride = 1; stride < gl_WorkGroupSize.x; stride *= 2) {
+					uint previous = lane >= stride ? sums[lane - stride] : 0;
+					barrier();
+					sums[lane] += previous;
+					barrier();
+				}
+				uint offset = sums[lane] - total;
+
+				for (uint i = 0; i < pc.block_count; i++) {
+					uint count = histograms.counts[first + i];
+					histograms.counts[first + i] = offset;
+					offset += count;
+				}
+			}
+		"
+	}
+}
+
+mod radix_scatter_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			#define KEYS_PER_THREAD 4
+			#define NO_DIGIT 256
+
+			layout(local_size_x = 256, local_size_y = 1, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) readonly buffer Source {
+				uint keys[];
+			} source;
+			layout(set = 0, binding = 1) writeonly buffer Destination {
+				uint keys[];
+			} destination;
+			layout(set = 0, binding = 2) readonly buffer Histograms {
+				uint offsets[];
+			} histograms;
+			layout(set = 0, binding = 3) writeonly buffer Indices {
+				uint ids[];
+			} indices;
+
+			layout(push_constant) uniform PushConstants {
+				uint count;
+				uint shift;
+				// The last pass also writes the object ids
+				uint last;
+			} pc;
+
+			shared uint offsets[256];
+			shared uint digits[256];
+
+			void main() {
+				uint lane = gl_LocalInvocationID.x;
+				offsets[lane] = histograms.offsets[lane * gl_NumWorkGroups.x + gl_WorkGroupID.x];
+
+				uint first = gl_WorkGroupID.x * gl_WorkGroupSize.x * KEYS_PER_THREAD;
+				for (uint i = 0; i < KEYS_PER_THREAD; i++) {
+					uint index = first + i * gl_WorkGroupSize.x + lane;
+					uint key = index < pc.count ? source.keys[index] : 0;
+					uint digit = index < pc.count ? (key >> pc.shift) & 0xff : NO_DIGIT;
+					digits[lane] = digit;
+					barrier();
+
+					// The keys of the same digit earlier in the block go first, which
+					// keeps the sort stable from one pass to the next
+					uint rank = 0;
+					for (uint j = 0; j < lane; j++) {
+						rank += uint(digits[j] == digit);
+					}
+					if (digit != NO_DIGIT) {
+						uint target = offsets[digit] + rank;
+						destination.keys[target] = key;
+						if (pc.last != 0) {
+							indices.ids[target] = key & 0xffff;
+						}
+					}
+					barrier();
+
+					if (digit != NO_DIGIT) {
+						atomicAdd(offsets[digit], 1);
+					}
+					barrier();
+				}
+			}
+		"
+	}
+}
+
+// Sort key of an object for `GpuRadixSort`, the farthest first. The high 16
+// bits of the depth keep its order to a relative precision of 1/128; among
+// equal depths the lowest object id comes first.
+pub fn back_to_front_key(depth: f32, object_id: u16) -> u32 {
+	(!sortable_bits(depth) & 0xffff_0000) | object_id as u32
+}
+
+// Sorts packed (depth_key << 16 | object_id) keys on the GPU with an LSD
+// radix sort of 4 passes of 8 bits. Each pass counts the digits of every block
+// of SORT_BLOCK keys, scans the counts in shared memory into where each
+// block's keys go, and scatters the keys there. The last pass also
+// writes the object ids in order to `indices`, for the transparency pass to
+// look up the object of each instance with `ids[gl_InstanceIndex]`.
+//
+// vulkano-shaders can't parse the subgroup capabilities, so the scan doesn't
+// use subgroup arithmetic. Whatever writes the keys, `upload` or a compute
+// shader, goes through `keys()`.
+pub struct GpuRadixSort {
+	histogram_pipeline: SharedComputePipeline,
+	scan_pipeline: SharedComputePipeline,
+	scatter_pipeline: SharedComputePipeline,
+	// Ping-ponged between the passes, an even number of passes leaves the
+	// sorted keys in the first one
+	keys: [Arc<DeviceLocalBuffer<[u32]>>; 2],
+	histograms: Arc<DeviceLocalBuffer<[u32]>>,
+	indices: Arc<DeviceLocalBuffer<[u32]>>,
+	capacity: usize,
+	device: Arc<Device>,
+}
+
+impl GpuRadixSort {
+	pub fn new(device: Arc<Device>, capacity: usize) -> Result<GpuRadixSort, VulkanoError> {
+		if capacity > MAX_SORT_KEYS {
+			return Err(VulkanoError::Unsupported("sorting more than 65536 keys"));
+		}
+
+		let histogram = radix_histogram_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let scan = radix_scan_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let scatter = radix_scatter_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let capacity = capacity.max(1);
+		let block_count = capacity.div_ceil(SORT_BLOCK);
+
+		Ok(GpuRadixSort {
+			histogram_pipeline: Arc::new(ComputePipeline::new(device.clone(), &histogram.main_entry_point(), &(), None)?),
+			scan_pipeline: Arc::new(ComputePipeline::new(device.clone(), &scan.main_entry_point(), &(), None)?),
+			scatter_pipeline: Arc::new(ComputePipeline::new(device.clone(), &scatter.main_entry_point(), &(), None)?),
+			keys: [sort_buffer(&device, capacity)?, sort_buffer(&device, capacity)?],
+			histograms: sort_buffer(&device, SORT_RADIX * block_count)?,
+			indices: sort_buffer(&device, capacity)?,
+			capacity,
+			device,
+		})
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	// The unsorted keys, then the sorted keys once recorded
+	pub fn keys(&self) -> Arc<DeviceLocalBuffer<[u32]>> {
+		self.keys[0].clone()
+	}
+
+	// Object ids in sorted order, the low 16 bits of the sorted keys
+	pub fn indices(&self) -> Arc<DeviceLocalBuffer<[u32]>> {
+		self.indices.clone()
+	}
+
+	// Records the copy of `keys` to the first `keys.len()` keys to sort
+	pub fn upload(&self, builder: &mut AutoCommandBufferBuilder, keys: &[u32]) -> Result<(), VulkanoError> {
+		assert!(keys.len() <= self.capacity, "more keys than the sort's capacity");
+		if keys.is_empty() {
+			return Ok(());
+		}
+		let staging =
+			CpuAccessibleBuffer::from_iter(self.device.clone(), BufferUsage::transfer_source(), false, keys.iter().copied())?;
+		builder.copy_buffer(staging, self.keys[0].clone())?;
+		Ok(())
+	}
+
+	// Records the sort of the first `count` keys, outside of any render pass,
+	// and returns the buffer of the sorted object ids
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		count: usize,
+	) -> Result<Arc<DeviceLocalBuffer<[u32]>>, VulkanoError> {
+		assert!(count <= self.capacity, "more keys than the sort's capacity");
+		if count == 0 {
+			return Ok(self.indices.clone());
+		}
+
+		let block_count = count.div_ceil(SORT_BLOCK) as u32;
+		let histogram_layout = self
+			.histogram_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let scan_layout = self.scan_pipeline.descriptor_set_layout(0).ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let scatter_layout = self
+			.scatter_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let scan_set = Arc::new(
+			PersistentDescriptorSet::start(scan_layout.clone())
+				.add_buffer(self.histograms.clone())?
+				.build()?,
+		);
+
+		for pass in 0..SORT_PASSES {
+			let source = self.keys[pass % 2].clone();
+			let destination = self.keys[(pass + 1) % 2].clone();
+			let shift = (pass * 8) as u32;
+
+			let histogram_set = Arc::new(
+				PersistentDescriptorSet::start(histogram_layout.clone())
+					.add_buffer(source.clone())?
+					.add_buffer(self.histograms.clone())?
+					.build()?,
+			);
+			let histogram_constants = radix_histogram_cs::ty::PushConstants {
+				count: count as u32,
+				shift,
+			};
+			debug_label!(builder, "GpuRadixSort histogram", LABEL_COLOR_COMPUTE);
+			builder.dispatch(
+				[block_count, 1, 1],
+				self.histogram_pipeline.clone(),
+				histogram_set,
+				histogram_constants,
+				vec![],
+			)?;
+			debug_label!(builder);
+
+			let scan_constants = radix_scan_cs::ty::PushConstants { block_count };
+			debug_label!(builder, "GpuRadixSort scan", LABEL_COLOR_COMPUTE);
+			builder.dispatch([1, 1, 1], self.scan_pipeline.clone(), scan_set.clone(), scan_constants, vec![])?;
+			debug_label!(builder);
+
+			let scatter_set = Arc::new(
+				PersistentDescriptorSet::start(scatter_layout.clone())
+					.add_buffer(source)?
+					.add_buffer(destination)?
+					.add_buffer(self.histograms.clone())?
+					.add_buffer(self.indices.clone())?
+					.build()?,
+			);
+			let scatter_constants = radix_scatter_cs::ty::PushConstants {
+				count: count as u32,
+				shift,
+				last: (pass == SORT_PASSES - 1) as u32,
+			};
+			debug_label!(builder, "GpuRadixSort scatter", LABEL_COLOR_COMPUTE);
+			builder.dispatch(
+				[block_count, 1, 1],
+				self.scatter_pipeline.clone(),
+				scatter_set,
+				scatter_constants,
+				vec![],
+			)?;
+			debug_label!(builder);
+		}
+		Ok(self.indices.clone())
+	}
+}
+
+// Also copied from and to, to upload the keys or read the results back
+fn sort_buffer(device: &Arc<Device>, len: usize) -> Result<Arc<DeviceLocalBuffer<[u32]>>, VulkanoError> {
+	let buffer = DeviceLocalBuffer::array(
+		device.clone(),
+		len,
+		BufferUsage {
+			storage_buffer: true,
+			transfer_source: true,
+			transfer_destination: true,
+			..BufferUsage::none()
+		},
+		device.active_queue_families(),
+	)?;
+	Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+	use vulkano::command_buffer::CommandBuffer;
+	use vulkano::device::{DeviceExtensions, Features, Queue};
+	use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+	use vulkano::sync::GpuFuture;
+
+	use super::*;
+
+	const OBJECTS: usize = 10_000;
+
+	fn compute_queue() -> Arc<Queue> {
+		let instance = Instance::new(None, &InstanceExtensions::none(), None).unwrap();
+		let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+		let queue_family = physical.queue_families().find(|&q| q.supports_compute()).unwrap();
+		let (_, mut queues) = Device::new(
+			physical,
+			&Features::none(),
+			&DeviceExtensions::none(),
+			[(queue_family, 0.5)].iter().cloned(),
+		)
+		.unwrap();
+		queues.next().unwrap()
+	}
+
+	// Pseudo random depths, the same on every run
+	fn keys() -> Vec<u32> {
+		let mut state = 0x2545_f491u32;
+		(0..OBJECTS)
+			.map(|id| {
+				state ^= state << 13;
+				state ^= state >> 17;
+				state ^= state << 5;
+				back_to_front_key((state % 10_000) as f32 * 0.01, id as u16)
+			})
+			.collect()
+	}
+
+	// What the GPU sort should read back: the object ids of the sorted keys
+	fn expected_indices(keys: &[u32]) -> Vec<u32> {
+		let mut sorted = keys.to_vec();
+		sorted.sort();
+		sorted.iter().map(|key| key & 0xffff).collect()
+	}
+
+	// The passes of the shaders on the CPU, block by block: the histograms
+	// digit major, their exclusive scan, then the scatter in block order
+	fn radix_sort_on_cpu(keys: &[u32]) -> Vec<u32> {
+		let block_count = keys.len().div_ceil(SORT_BLOCK);
+		let mut source = keys.to_vec();
+		let mut destination = vec![0; keys.len()];
+		let mut indices = vec![0; keys.len()];
+		for pass in 0..SORT_PASSES {
+			let shift = pass * 8;
+			let digit = |key: u32| (key >> shift) as usize & 0xff;
+
+			let mut histograms = vec![0; SORT_RADIX * block_count];
+			for (block, chunk) in source.chunks(SORT_BLOCK).enumerate() {
+				for &key in chunk {
+					histograms[digit(key) * block_count + block] += 1;
+				}
+			}
+			let mut offset = 0;
+			for count in histograms.iter_mut() {
+				let next = offset + *count;
+				*count = offset;
+				offset = next;
+			}
+
+			for (block, chunk) in source.chunks(SORT_BLOCK).enumerate() {
+				for &key in chunk {
+					let target = &mut histograms[digit(key) * block_count + block];
+					destination[*target] = key;
+					if pass == SORT_PASSES - 1 {
+						indices[*target] = key & 0xffff;
+					}
+					*target += 1;
+				}
+			}
+			std::mem::swap(&mut source, &mut destination);
+		}
+		indices
+	}
+
+	#[test]
+	fn keys_sort_back_to_front() {
+		let mut keys = [back_to_front_key(1.0, 0),
+			back_to_front_key(100.0, 1),
+			back_to_front_key(-2.0, 2),
+			back_to_front_key(100.0, 3)];
+		keys.sort_unstable();
+		let ids: Vec<u32> = keys.iter().map(|key| key & 0xffff).collect();
+		assert_eq!(ids, [1, 3, 0, 2]);
+	}
+
+	// Checks the readback the GPU test compares against without a device
+	#[test]
+	fn radix_passes_match_sort() {
+		let keys = keys();
+		assert_eq!(radix_sort_on_cpu(&keys), expected_indices(&keys));
+		// A partial last block
+		assert_eq!(radix_sort_on_cpu(&keys[..SORT_BLOCK + 17]), expected_indices(&keys[..SORT_BLOCK + 17]));
+	}
+
+	// The GPU tests are run with `cargo test -- --ignored`
+	#[test]
+	#[ignore = "needs a Vulkan device"]
+	fn rejects_too_many_keys() {
+		let queue = compute_queue();
+		match GpuRadixSort::new(queue.device().clone(), MAX_SORT_KEYS + 1) {
+			Err(VulkanoError::Unsupported(_)) => {}
+			other => panic!("Expected Unsupported, got {:?}", other.err()),
+		}
+	}
+
+	// benches/gpu_sort.rs times the same sort against Vec::sort_unstable
+	#[test]
+	#[ignore = "needs a Vulkan device"]
+	fn gpu_sort_matches_sort() {
+		let queue = compute_queue();
+		let device = queue.device().clone();
+		let sort = GpuRadixSort::new(device.clone(), OBJECTS).unwrap();
+		let keys = keys();
+
+		let mut builder = AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
+		sort.upload(&mut builder, &keys).unwrap();
+		sort.record(&mut builder, keys.len()).unwrap();
+		let readback = CpuAccessibleBuffer::from_iter(
+			device,
+			BufferUsage::transfer_destination(),
+			false,
+			(0..OBJECTS).map(|_| 0u32),
+		)
+		.unwrap();
+		builder.copy_buffer(sort.indices(), readback.clone()).unwrap();
+		builder
+			.build()
+			.unwrap()
+			.execute(queue)
+			.unwrap()
+			.then_signal_fence_and_flush()
+			.unwrap()
+			.wait(None)
+			.unwrap();
+
+		assert_eq!(&readback.read().unwrap()[..], &expected_indices(&keys)[..]);
+	}
+}
@@ -0,0 +1,442 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use log::*;
+
+use winit::event::{ElementState, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+
+use crate::error::VulkanoError;
+
+pub const KEYBINDINGS_PATH: &str = "keybindings.toml";
+// Scroll in pixels worth one line of the wheel axis
+const PIXELS_PER_LINE: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+	MoveForward,
+	MoveBackward,
+	MoveLeft,
+	MoveRight,
+	MoveUp,
+	MoveDown,
+	// Mouse motion in pixels while looking around
+	LookHorizontal,
+	LookVertical,
+	Zoom,
+	ToggleWireframe,
+	ToggleBounds,
+	ToggleTriangulation,
+	ToggleCurveEditor,
+	ToggleColorMonitors,
+	// Colormap of the heat map
+	ColormapViridis,
+	ColormapPlasma,
+	ColormapMagma,
+	Screenshot,
+	Undo,
+	Redo,
+}
+
+// Names of the actions in the bindings file
+const ACTIONS: [(&str, Action); 20] = [
+	("MoveForward", Action::MoveForward),
+	("MoveBackward", Action::MoveBackward),
+	("MoveLeft", Action::MoveLeft),
+	("MoveRight", Action::MoveRight),
+	("MoveUp", Action::MoveUp),
+	("MoveDown", Action::MoveDown),
+	("LookHorizontal", Action::LookHorizontal),
+	("LookVertical", Action::LookVertical),
+	("Zoom", Action::Zoom),
+	("ToggleWireframe", Action::ToggleWireframe),
+	("ToggleBounds", Action::ToggleBounds),
+	("ToggleTriangulation", Action::ToggleTriangulation),
+	("ToggleCurveEditor", Action::ToggleCurveEditor),
+	("ToggleColorMonitors", Action::ToggleColorMonitors),
+	("ColormapViridis", Action::ColormapViridis),
+	("ColormapPlasma", Action::ColormapPlasma),
+	("ColormapMagma", Action::ColormapMagma),
+	("Screenshot", Action::Screenshot),
+	("Undo", Action::Undo),
+	("Redo", Action::Redo),
+];
+
+const DEFAULT_BINDINGS: [(Action, &[&str]); 20] = [
+	(Action::MoveForward, &["W", "Up"]),
+	(Action::MoveBackward, &["S", "Down"]),
+	(Action::MoveLeft, &["A", "Left"]),
+	(Action::MoveRight, &["D", "Right"]),
+	(Action::MoveUp, &["E", "Space"]),
+	(Action::MoveDown, &["Q", "LShift"]),
+	(Action::LookHorizontal, &["MouseX"]),
+	(Action::LookVertical, &["MouseY"]),
+	(Action::Zoom, &["Wheel"]),
+	(Action::ToggleWireframe, &["F"]),
+	(Action::ToggleBounds, &["B"]),
+	(Action::ToggleTriangulation, &["T"]),
+	(Action::ToggleCurveEditor, &["F2"]),
+	(Action::ToggleColorMonitors, &["M"]),
+	(Action::ColormapViridis, &["Key1"]),
+	(Action::ColormapPlasma, &["Key2"]),
+	(Action::ColormapMagma, &["Key3"]),
+	(Action::Screenshot, &["F12"]),
+	(Action::Undo, &["Ctrl+Z"]),
+	(Action::Redo, &["Ctrl+Y", "Ctrl+Shift+Z"]),
+];
+
+// Names of the keys in the bindings file, as winit spells them
+const KEYS: [(&str, VirtualKeyCode); 78] = [
+	("A", VirtualKeyCode::A),
+	("B", VirtualKeyCode::B),
+	("C", VirtualKeyCode::C),
+	("D", VirtualKeyCode::D),
+	("E", VirtualKeyCode::E),
+	("F", VirtualKeyCode::F),
+	("G", VirtualKeyCode::G),
+	("H", VirtualKeyCode::H),
+	("I", VirtualKeyCode::I),
+	("J", VirtualKeyCode::J),
+	("K", VirtualKeyCode::K),
+	("L", VirtualKeyCode::L),
+	("M", VirtualKeyCode::M),
+	("N", VirtualKeyCode::N),
+	("O", VirtualKeyCode::O),
+	("P", VirtualKeyCode::P),
+	("Q", VirtualKeyCode::Q),
+	("R", VirtualKeyCode::R),
+	("S", VirtualKeyCode::S),
+	("T", VirtualKeyCode::T),
+	("U", VirtualKeyCode::U),
+	("V", VirtualKeyCode::V),
+	("W", VirtualKeyCode::W),
+	("X", VirtualKeyCode::X),
+	("Y", VirtualKeyCode::Y),
+	("Z", VirtualKeyCode::Z),
+	("Key0", VirtualKeyCode::Key0),
+	("Key1", VirtualKeyCode::Key1),
+	("Key2", VirtualKeyCode::Key2),
+	("Key3", VirtualKeyCode::Key3),
+	("Key4", VirtualKeyCode::Key4),
+	("Key5", VirtualKeyCode::Key5),
+	("Key6", VirtualKeyCode::Key6),
+	("Key7", VirtualKeyCode::Key7),
+	("Key8", VirtualKeyCode::Key8),
+	("Key9", VirtualKeyCode::Key9),
+	("F1", VirtualKeyCode::F1),
+	("F2", VirtualKeyCode::F2),
+	("F3", VirtualKeyCode::F3),
+	("F4", VirtualKeyCode::F4),
+	("F5", VirtualKeyCode::F5),
+	("F6", VirtualKeyCode::F6),
+	("F7", VirtualKeyCode::F7),
+	("F8", VirtualKeyCode::F8),
+	("F9", VirtualKeyCode::F9),
+	("F10", VirtualKeyCode::F10),
+	("F11", VirtualKeyCode::F11),
+	("F12", VirtualKeyCode::F12),
+	("Escape", VirtualKeyCode::Escape),
+	("Tab", VirtualKeyCode::Tab),
+	("Space", VirtualKeyCode::Space),
+	("Return", VirtualKeyCode::Return),
+	("Back", VirtualKeyCode::Back),
+	("Delete", VirtualKeyCode::Delete),
+	("Insert", VirtualKeyCode::Insert),
+	("Home", VirtualKeyCode::Home),
+	("End", VirtualKeyCode::End),
+	("PageUp", VirtualKeyCode::PageUp),
+	("PageDown", VirtualKeyCode::PageDown),
+	("Up", VirtualKeyCode::Up),
+	("Down", VirtualKeyCode::Down),
+	("Left", VirtualKeyCode::Left),
+	("Right", VirtualKeyCode::Right),
+	("LShift", VirtualKeyCode::LShift),
+	("RShift", VirtualKeyCode::RShift),
+	("LControl", VirtualKeyCode::LControl),
+	("RControl", VirtualKeyCode::RControl),
+	("LAlt", VirtualKeyCode::LAlt),
+	("RAlt", VirtualKeyCode::RAlt),
+	("Minus", VirtualKeyCode::Minus),
+	("Equals", VirtualKeyCode::Equals),
+	("Comma", VirtualKeyCode::Comma),
+	("Period", VirtualKeyCode::Period),
+	("Slash", VirtualKeyCode::Slash),
+	("Backslash", VirtualKeyCode::Backslash),
+	("Semicolon", VirtualKeyCode::Semicolon),
+	("LBracket", VirtualKeyCode::LBracket),
+	("RBracket", VirtualKeyCode::RBracket),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Input {
+	Key(VirtualKeyCode),
+	Button(MouseButton),
+	// Analog, reset every frame
+	MouseX,
+	MouseY,
+	Wheel,
+}
+
+// An input with the modifiers that must be held along it, e.g. Ctrl+Z
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Binding {
+	input: Input,
+	modifiers: ModifiersState,
+}
+
+impl Binding {
+	// "Ctrl+Shift+Z", "MouseLeft", "Wheel"...
+	fn parse(text: &str) -> Result<Binding, VulkanoError> {
+		let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+		let name = parts.pop().unwrap_or("");
+		let mut modifiers = ModifiersState::empty();
+		for modifier in parts {
+			modifiers |= match modifier {
+				"Ctrl" => ModifiersState::CTRL,
+				"Shift" => ModifiersState::SHIFT,
+				"Alt" => ModifiersState::ALT,
+				"Logo" => ModifiersState::LOGO,
+				_ => return Err(VulkanoError::Keybindings(format!("unknown modifier `{}` in `{}`", modifier, text))),
+			};
+		}
+		let input = match name {
+			"MouseLeft" => Input::Button(MouseButton::Left),
+			"MouseRight" => Input::Button(MouseButton::Right),
+			"MouseMiddle" => Input::Button(MouseButton::Middle),
+			"MouseX" => Input::MouseX,
+			"MouseY" => Input::MouseY,
+			"Wheel" => Input::Wheel,
+			_ => match KEYS.iter().find(|(key, _)| *key == name) {
+				Some(&(_, key)) => Input::Key(key),
+				None => return Err(VulkanoError::Keybindings(format!("unknown key `{}` in `{}`", name, text))),
+			},
+		};
+		Ok(Binding { input, modifiers })
+	}
+
+	// The modifiers of the binding are held, and no other one than Shift, so
+	// Ctrl+Z doesn't trigger a plain Z while moving with Shift held still does
+	fn modifiers_match(&self, held: ModifiersState) -> bool {
+		let extra = held - self.modifiers;
+		held.contains(self.modifiers) && !extra.intersects(ModifiersState::CTRL | ModifiersState::ALT | ModifiersState::LOGO)
+	}
+}
+
+// Values of the `Action = "Binding"` and `Action = ["Binding", ...]` lines of
+// the bindings file, a TOML subset. Comments and table headers are skipped.
+fn parse_bindings(text: &str) -> Result<Vec<(Action, Vec<Binding>)>, VulkanoError> {
+	let mut actions = Vec::new();
+	for (number, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap_or("").trim();
+		if line.is_empty() || line.starts_with('[') {
+			continue;
+		}
+		let error = |message: &str| VulkanoError::Keybindings(format!("line {}: {}", number + 1, message));
+		let mut sides = line.splitn(2, '=');
+		let name = sides.next().unwrap_or("").trim();
+		let value = sides.next().ok_or_else(|| error("expected `Action = \"Binding\"`"))?.trim();
+		let action = ACTIONS
+			.iter()
+			.find(|(action, _)| *action == name)
+			.map(|&(_, action)| action)
+			.ok_or_else(|| error(&format!("unknown action `{}`", name)))?;
+
+		let value = value.strip_prefix('[').map_or(Some(value), |list| list.strip_suffix(']'));
+		let value = value.ok_or_else(|| error("unterminated array"))?;
+		let mut bindings = Vec::new();
+		for item in value.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+			let item = item
+				.strip_prefix('"')
+				.and_then(|item| item.strip_suffix('"'))
+				.ok_or_else(|| error("bindings must be quoted strings"))?;
+			bindings.push(Binding::parse(item)?);
+		}
+		actions.push((action, bindings));
+	}
+	Ok(actions)
+}
+
+// Maps the physical keys, buttons and mouse motion of the window events to
+// logical actions, so the event loop doesn't compare key codes. The bindings
+// come from DEFAULT_BINDINGS, overridden per action by `keybindings.toml`:
+//
+//     ToggleWireframe = "F"
+//     MoveForward = ["W", "Up"]
+//     Undo = "Ctrl+Z"
+//
+// An empty array unbinds the action. Call `end_frame` after the frame
+// consumed the input.
+pub struct InputMapper {
+	bindings: HashMap<Action, Vec<Binding>>,
+	keys: HashSet<VirtualKeyCode>,
+	buttons: HashSet<MouseButton>,
+	modifiers: ModifiersState,
+	// Actions whose binding went down since the last `end_frame`
+	triggered: HashSet<Action>,
+	mouse_delta: [f32; 2],
+	wheel: f32,
+	cursor: Option<[f64; 2]>,
+}
+
+impl Default for InputMapper {
+	fn default() -> InputMapper {
+		InputMapper::new()
+	}
+}
+
+impl InputMapper {
+	pub fn new() -> InputMapper {
+		let bindings = DEFAULT_BINDINGS
+			.iter()
+			.map(|&(action, bindings)| {
+				// The defaults are known to parse
+				let bindings = bindings.iter().filter_map(|binding| Binding::parse(binding).ok()).collect();
+				(action, bindings)
+			})
+			.collect();
+		InputMapper {
+			bindings,
+			keys: HashSet::new(),
+			buttons: HashSet::new(),
+			modifiers: ModifiersState::empty(),
+			triggered: HashSet::new(),
+			mouse_delta: [0.0, 0.0],
+			wheel: 0.0,
+			cursor: None,
+		}
+	}
+
+	// The defaults when the file doesn't exist
+	pub fn load(path: &Path) -> Result<InputMapper, VulkanoError> {
+		let mut mapper = InputMapper::new();
+		if !path.exists() {
+			return Ok(mapper);
+		}
+		let overrides = parse_bindings(&fs::read_to_string(path)?)?;
+		info!("Loaded {} key bindings from {}", overrides.len(), path.display());
+		mapper.bindings.extend(overrides);
+		Ok(mapper)
+	}
+
+	// Replaces the bindings of `action`, from strings like "Ctrl+Z"
+	pub fn bind(&mut self, action: Action, bindings: &[&str]) -> Result<(), VulkanoError> {
+		let bindings = bindings.iter().map(|binding| Binding::parse(binding)).collect::<Result<_, _>>()?;
+		self.bindings.insert(action, bindings);
+		Ok(())
+	}
+
+	pub fn handle_event(&mut self, event: &WindowEvent) {
+		match event {
+			WindowEvent::KeyboardInput {
+				input:
+					KeyboardInput {
+						state,
+						virtual_keycode: Some(key),
+						..
+					},
+				..
+			} => match state {
+				// Key repeats don't trigger again
+				ElementState::Pressed if self.keys.insert(*key) => self.trigger(Input::Key(*key)),
+				ElementState::Pressed => (),
+				ElementState::Released => {
+					self.keys.remove(key);
+				}
+			},
+			WindowEvent::MouseInput { state, button, .. } => match state {
+				ElementState::Pressed => {
+					self.buttons.insert(*button);
+					self.trigger(Input::Button(*button));
+				}
+				ElementState::Released => {
+					self.buttons.remove(button);
+				}
+			},
+			WindowEvent::ModifiersChanged(modifiers) => self.modifiers = *modifiers,
+			WindowEvent::CursorMoved { position, .. } => {
+				if let Some([x, y]) = self.cursor {
+					self.mouse_delta[0] += (position.x - x) as f32;
+					self.mouse_delta[1] += (position.y - y) as f32;
+				}
+				self.cursor = Some([position.x, position.y]);
+			}
+			WindowEvent::CursorLeft { .. } => self.cursor = None,
+			WindowEvent::MouseWheel { delta, .. } => {
+				self.wheel += match delta {
+					MouseScrollDelta::LineDelta(_, y) => *y,
+					MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+				}
+			}
+			// The releases happen in another window
+			WindowEvent::Focused(false) => {
+				self.keys.clear();
+				self.buttons.clear();
+				self.modifiers = ModifiersState::empty();
+			}
+			_ => (),
+		}
+	}
+
+	// Only the bindings with the most modifiers trigger, Ctrl+Shift+Z doesn't
+	// trigger Ctrl+Z too
+	fn trigger(&mut self, input: Input) {
+		let modifiers = self.modifiers;
+		let matches: Vec<(Action, u32)> = self
+			.bindings
+			.iter()
+			.flat_map(|(&action, bindings)| {
+				bindings
+					.iter()
+					.filter(move |binding| binding.input == input && binding.modifiers_match(modifiers))
+					.map(move |binding| (action, binding.modifiers.bits().count_ones()))
+			})
+			.collect();
+		let most = matches.iter().map(|&(_, count)| count).max().unwrap_or(0);
+		for (action, count) in matches {
+			if count == most {
+				self.triggered.insert(action);
+			}
+		}
+	}
+
+	fn binding_value(&self, binding: &Binding) -> f32 {
+		if !binding.modifiers_match(self.modifiers) {
+			return 0.0;
+		}
+		match binding.input {
+			Input::Key(key) => self.keys.contains(&key) as u32 as f32,
+			Input::Button(button) => self.buttons.contains(&button) as u32 as f32,
+			Input::MouseX => self.mouse_delta[0],
+			Input::MouseY => self.mouse_delta[1],
+			Input::Wheel => self.wheel,
+		}
+	}
+
+	// Any binding of `action` is held
+	pub fn is_pressed(&self, action: Action) -> bool {
+		self.axis(action) != 0.0
+	}
+
+	// A binding of `action` went down since the last `end_frame`, for toggles
+	pub fn was_triggered(&self, action: Action) -> bool {
+		self.triggered.contains(&action)
+	}
+
+	// 0 or 1 for the keys and buttons, the motion in pixels or the scroll in
+	// lines since the last `end_frame` for the mouse axes, the largest of the
+	// bindings of `action`
+	pub fn axis(&self, action: Action) -> f32 {
+		self.bindings.get(&action).map_or(0.0, |bindings| {
+			bindings
+				.iter()
+				.map(|binding| self.binding_value(binding))
+				.fold(0.0, |largest: f32, value| if value.abs() > largest.abs() { value } else { largest })
+		})
+	}
+
+	pub fn end_frame(&mut self) {
+		self.triggered.clear();
+		self.mouse_delta = [0.0, 0.0];
+		self.wheel = 0.0;
+	}
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, CpuBufferPool, TypedBufferAccess};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::DescriptorSetsCollection;
+use vulkano::device::Device;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use crate::error::VulkanoError;
+
+// Per instance vertex attributes, bound as the second vertex buffer of a
+// pipeline using `OneVertexOneInstanceDefinition`
+#[derive(Default, Debug, Clone, Copy)]
+pub struct InstanceData {
+	pub model: [[f32; 4]; 4],
+	pub object_id: u32,
+}
+vulkano::impl_vertex!(InstanceData, model, object_id);
+
+// Slot index and the generation of the slot when the instance was spawned, so
+// a handle to a despawned instance doesn't alias the instance reusing its slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InstanceHandle {
+	index: u32,
+	generation: u32,
+}
+
+struct Slot {
+	instance: Option<InstanceData>,
+	generation: u32,
+}
+
+// Dynamic set of instances, uploaded every frame as a tightly packed buffer
+// of the live instances. Despawned slots are kept on a free list and reused
+// by the next spawns.
+pub struct InstancePool {
+	slots: Vec<Slot>,
+	free: Vec<u32>,
+	live: usize,
+	buffer_pool: CpuBufferPool<InstanceData>,
+}
+
+impl InstancePool {
+	pub fn new(device: Arc<Device>) -> InstancePool {
+		InstancePool {
+			slots: Vec::new(),
+			free: Vec::new(),
+			live: 0,
+			buffer_pool: CpuBufferPool::vertex_buffer(device),
+		}
+	}
+
+	pub fn spawn(&mut self, transform: [[f32; 4]; 4]) -> InstanceHandle {
+		let index = match self.free.pop() {
+			Some(index) => index,
+			None => {
+				self.slots.push(Slot {
+					instance: None,
+					generation: 0,
+				});
+				self.slots.len() as u32 - 1
+			}
+		};
+		let slot = &mut self.slots[index as usize];
+		slot.instance = Some(InstanceData {
+			model: transform,
+			object_id: index,
+		});
+		self.live += 1;
+		InstanceHandle {
+			index,
+			generation: slot.generation,
+		}
+	}
+
+	// Does nothing if the instance was already despawned
+	pub fn despawn(&mut self, handle: InstanceHandle) {
+		if let Some(slot) = self.slot_mut(handle) {
+			slot.instance = None;
+			slot.generation = slot.generation.wrapping_add(1);
+			self.free.push(handle.index);
+			self.live -= 1;
+		}
+	}
+
+	pub fn set_transform(&mut self, handle: InstanceHandle, transform: [[f32; 4]; 4]) {
+		if let Some(instance) = self.slot_mut(handle).and_then(|slot| slot.instance.as_mut()) {
+			instance.model = transform;
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.live
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.live == 0
+	}
+
+	fn slot_mut(&mut self, handle: InstanceHandle) -> Option<&mut Slot> {
+		self.slots
+			.get_mut(handle.index as usize)
+			.filter(|slot| slot.generation == handle.generation && slot.instance.is_some())
+	}
+
+	// Copies the live instances into a chunk sized to their count
+	pub fn upload(&self) -> Result<Option<Arc<dyn BufferAccess + Send + Sync>>, VulkanoError> {
+		if self.is_empty() {
+			return Ok(None);
+		}
+		let instances: Vec<_> = self.slots.iter().filter_map(|slot| slot.instance).collect();
+		Ok(Some(Arc::new(self.buffer_pool.chunk(instances)?)))
+	}
+
+	// Draws the mesh once per live instance. vulkano takes the index count
+	// from `index_buffer` and the instance count from the instance buffer.
+	#[allow(clippy::too_many_arguments)]
+	pub fn draw<Ib, I, S, Pc>(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+		dynamic_state: &DynamicState,
+		vertex_buffer: Arc<dyn BufferAccess + Send + Sync>,
+		index_buffer: Ib,
+		sets: S,
+		push_constants: Pc,
+	) -> Result<(), VulkanoError>
+	where
+		Ib: BufferAccess + TypedBufferAccess<Content = [I]> + Send + Sync + 'static,
+		I: vulkano::pipeline::input_assembly::Index + 'static,
+		S: DescriptorSetsCollection,
+	{
+		let instance_buffer = match self.upload()? {
+			Some(buffer) => buffer,
+			None => return Ok(()),
+		};
+		builder.draw_indexed(
+			pipeline,
+			dynamic_state,
+			vec![vertex_buffer, instance_buffer],
+			index_buffer,
+			sets,
+			push_constants,
+			vec![],
+		)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,59 @@
+pub mod glsl_shaders;
+pub mod win_utils;
+pub mod vulk_utils;
+pub mod streaming;
+#[macro_use]
+pub mod debug_utils;
+pub mod debug_views;
+pub mod input;
+#[cfg(feature = "physics")]
+pub mod physics_debug;
+pub mod timing;
+pub mod sync;
+pub mod swapchain;
+pub mod pipeline;
+pub mod shaders;
+pub mod error;
+pub mod renderer;
+pub mod geometry;
+pub mod text;
+pub mod canvas;
+pub mod commands;
+pub mod barriers;
+pub mod video;
+pub mod transparency;
+pub mod foliage;
+pub mod gpu_sort;
+pub mod effects;
+pub mod instances;
+pub mod testing;
+pub mod animation;
+pub mod sky;
+pub mod curve_editor;
+pub mod water;
+pub mod terrain;
+pub mod forward_plus;
+pub mod reflection;
+pub mod decals;
+pub mod lighting;
+pub mod volumetric;
+pub mod allocator;
+pub mod shadow;
+pub mod meshlets;
+pub mod gi;
+pub mod restir;
+pub mod culling;
+pub mod picking;
+pub mod ray_march;
+pub mod path_tracer;
+pub mod stats;
+pub mod progressive;
+#[cfg(feature = "egui")]
+pub mod egui_integration;
+
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vertex {
+	pub position: [f32; 2],
+}
+vulkano::impl_vertex!(Vertex, position);
@@ -0,0 +1,208 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::pipeline::SharedComputePipeline;
+use crate::reflection::ReflectionCapture;
+
+// Irradiance is low frequency, low resolution faces are enough
+pub const SH_FACE_SIZE: u32 = 128;
+pub const SH_COEFFICIENTS: usize = 9;
+
+mod sh_project_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			// One workgroup per face, each invocation sums a block of texels
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform samplerCube cube;
+			// The coefficients of each face, summed on the CPU
+			layout(set = 0, binding = 1) writeonly buffer FaceCoefficients {
+				vec4 face_coefficients[6 * 9];
+			};
+
+			shared vec3 partial[64][9];
+
+			// Direction through (u, v) of a face, in [-1, 1], in the cube map convention
+			vec3 face_direction(uint face, vec2 uv) {
+				switch (face) {
+				case 0: return vec3(1.0, -uv.y, -uv.x);
+				case 1: return vec3(-1.0, -uv.y, uv.x);
+				case 2: return vec3(uv.x, 1.0, uv.y);
+				case 3: return vec3(uv.x, -1.0, -uv.y);
+				case 4: return vec3(uv.x, -uv.y, 1.0);
+				default: return vec3(-uv.x, -uv.y, -1.0);
+				}
+			}
+
+			void main() {
+				uint face = gl_WorkGroupID.z;
+				uint local_index = gl_LocalInvocationIndex;
+				int size = textureSize(cube, 0).x;
+				int block = size / int(gl_WorkGroupSize.x);
+
+				vec3 sums[9];
+				for (int i = 0; i < 9; i++) {
+					sums[i] = vec3(0.0);
+				}
+				ivec2 origin = ivec2(gl_LocalInvocationID.xy) * block;
+				for (int y = 0; y < block; y++) {
+					for (int x = 0; x < block; x++) {
+						vec2 uv = (vec2(origin + ivec2(x, y)) + 0.5) / float(size) * 2.0 - 1.0;
+						// Solid angle of the texel
+						float weight = 4.0 / (float(size * size) * pow(1.0 + dot(uv, uv), 1.5));
+						vec3 d = normalize(face_direction(face, uv));
+						vec3 radiance = textureLod(cube, d, 0.0).rgb * weight;
+
+						// The first 9 real spherical harmonics
+						sums[0] += radiance * 0.282095;
+						sums[1] += radiance * 0.488603 * d.y;
+						sums[2] += radiance * 0.488603 * d.z;
+						sums[3] += radiance * 0.488603 * d.x;
+						sums[4] += radiance * 1.092548 * d.x * d.y;
+						sums[5] += radiance * 1.092548 * d.y * d.z;
+						sums[6] += radiance * 0.315392 * (3.0 * d.z * d.z - 1.0);
+						sums[7] += radiance * 1.092548 * d.x * d.z;
+						sums[8] += radiance * 0.546274 * (d.x * d.x - d.y * d.y);
+					}
+				}
+				for (int i = 0; i < 9; i++) {
+					partial[local_index][i] = sums[i];
+				}
+				barrier();
+
+				for (uint stride = 32; stride > 0; stride /= 2) {
+					if (local_index < stride) {
+						for (int i = 0; i < 9; i++) {
+							partial[local_index][i] += partial[local_index + stride][i];
+						}
+					}
+					barrier();
+				}
+				if (local_index < 9) {
+					face_coefficients[face * 9 + local_index] = vec4(partial[0][local_index], 0.0);
+				}
+			}
+		"
+	}
+}
+
+// Irradiance at a point as 9 spherical harmonics coefficients, for cheap
+// diffuse ambient lighting. `capture` renders a cube map of the scene around
+// `position` with a `ReflectionCapture` and projects it on the spherical
+// harmonics with a compute pass, the coefficients being read back once the
+// GPU is done. src/shaders/sh.glsl evaluates them in fragment shaders.
+pub struct SHProbe {
+	capture: ReflectionCapture,
+	projection_pipeline: SharedComputePipeline,
+	face_coefficients: Arc<CpuAccessibleBuffer<[[f32; 4]; 6 * SH_COEFFICIENTS]>>,
+	sampler: Arc<Sampler>,
+}
+
+impl SHProbe {
+	pub fn new(device: Arc<Device>, position: [f32; 3]) -> Result<SHProbe, VulkanoError> {
+		// The influence radius is only used when blending reflections
+		let capture = ReflectionCapture::new(device.clone(), position, 0.0, SH_FACE_SIZE)?;
+
+		let shader = sh_project_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let projection_pipeline: SharedComputePipeline =
+			Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?);
+
+		let face_coefficients = CpuAccessibleBuffer::from_data(
+			device.clone(),
+			BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+			true,
+			[[0.0f32; 4]; 6 * SH_COEFFICIENTS],
+		)?;
+
+		let sampler = Sampler::new(
+			device,
+			Filter::Nearest,
+			Filter::Nearest,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+
+		Ok(SHProbe {
+			capture,
+			projection_pipeline,
+			face_coefficients,
+			sampler,
+		})
+	}
+
+	pub fn position(&self) -> [f32; 3] {
+		self.capture.position
+	}
+
+	// Takes effect at the next capture
+	pub fn set_position(&mut self, position: [f32; 3]) {
+		self.capture.position = position;
+	}
+
+	// Records the cube map and its projection outside of any render pass,
+	// `draw_scene` being called like for `ReflectionCapture::capture`
+	pub fn capture<F>(&self, builder: &mut AutoCommandBufferBuilder, draw_scene: F) -> Result<(), VulkanoError>
+	where
+		F: FnMut(&mut AutoCommandBufferBuilder, &DynamicState, [[f32; 4]; 4]) -> Result<(), VulkanoError>,
+	{
+		self.capture.capture(builder, draw_scene)?;
+
+		let layout = self
+			.projection_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let cube = ImageView::new(self.capture.levels()[0].clone())?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(cube, self.sampler.clone())?
+				.add_buffer(self.face_coefficients.clone())?
+				.build()?,
+		);
+		debug_label!(builder, "SHProbe projection", LABEL_COLOR_COMPUTE);
+		builder.dispatch([1, 1, 6], self.projection_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+		Ok(())
+	}
+
+	// The coefficients of the last capture, fails while the GPU is still computing them
+	pub fn coefficients(&self) -> Result<[[f32; 3]; SH_COEFFICIENTS], VulkanoError> {
+		let faces = self.face_coefficients.read()?;
+		let mut coefficients = [[0.0; 3]; SH_COEFFICIENTS];
+		for face in faces.chunks(SH_COEFFICIENTS) {
+			for (coefficient, value) in coefficients.iter_mut().zip(face) {
+				for channel in 0..3 {
+					coefficient[channel] += value[channel];
+				}
+			}
+		}
+		Ok(coefficients)
+	}
+
+	// The coefficients padded to vec4s, for the uniform block of sh.glsl
+	pub fn uniform(&self) -> Result<[[f32; 4]; SH_COEFFICIENTS], VulkanoError> {
+		let mut uniform = [[0.0; 4]; SH_COEFFICIENTS];
+		for (padded, [r, g, b]) in uniform.iter_mut().zip(self.coefficients()?.iter().copied()) {
+			*padded = [r, g, b, 0.0];
+		}
+		Ok(uniform)
+	}
+}
@@ -1,98 +1,76 @@
-use log::*;
+use std::path::Path;
 
-use vulkano::buffer::CpuBufferPool;
-use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
-use vulkano::framebuffer::Subpass;
-use vulkano::pipeline::GraphicsPipeline;
-use vulkano::swapchain;
-use vulkano::swapchain::{
-	AcquireError,
-	SwapchainCreationError,
-};
-use vulkano::sync;
-use vulkano::sync::{FlushError, GpuFuture};
+use log::*;
 
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use vulkano::swapchain::PresentMode;
 
-mod glsl_shaders;
-mod win_utils;
-mod vulk_utils;
+use vulkano_start::win_utils::create_window;
+use vulkano_start::curve_editor::CurveEditorPanel;
+use vulkano_start::debug_views::{DebugMeshOverlay, DebugTriangulation};
+use vulkano_start::input::{Action, InputMapper, KEYBINDINGS_PATH};
+use vulkano_start::timing::{BenchmarkMode, DeltaTime};
+use vulkano_start::error::{RecoveryStrategy, VulkanoError};
+use vulkano_start::geometry::SKIP_MESH_OPT_FLAG;
+use vulkano_start::renderer::{triangle_rotation, Renderer};
 
-use crate::glsl_shaders::*;
-use crate::win_utils::window_size_dependent_setup;
-use crate::vulk_utils::init_vlk;
-
-#[derive(Default, Debug, Clone)]
-struct Vertex {
-	position: [f32; 2],
-}
-vulkano::impl_vertex!(Vertex, position);
-
-fn main() {
-	dotenv::dotenv().expect("Failed to load .env file");
+fn main() -> Result<(), VulkanoError> {
+	dotenv::dotenv()?;
 	env_logger::builder().format_timestamp(None).init();
 
 	let event_loop = EventLoop::new();
+	let window = create_window(&event_loop)?;
+
+	// Manual check of the device loss recovery, debug builds only: `--hang-gpu`
+	// hangs the GPU on frame HANG_GPU_FRAME. Once the driver's timeout resets
+	// the GPU the log shows "Recovering from device loss (attempt 1/3)" and the
+	// triangle spins again, without a panic.
+	#[cfg(debug_assertions)]
+	const HANG_GPU_FRAME: u32 = 120;
+	// Frames left before the hang
+	#[cfg(debug_assertions)]
+	let mut hang_gpu_in = if std::env::args().any(|arg| arg == "--hang-gpu") {
+		Some(HANG_GPU_FRAME)
+	} else {
+		None
+	};
 
-	let (surface, mut swapchain, images, queue, device) = init_vlk(&event_loop);
-
-	info!("Vulkan init ended succesifully");
-
-	// Vertex Buffer Pool
-	let buffer_pool: CpuBufferPool<Vertex> = CpuBufferPool::vertex_buffer(device.clone());
+	// Renders a fixed number of frames without vsync and prints their timings
+	let mut benchmark = if std::env::args().any(|arg| arg == "--benchmark") {
+		Some(BenchmarkMode::new())
+	} else {
+		None
+	};
 
-	let vs = vs::Shader::load(device.clone()).unwrap();
-	let fs = fs::Shader::load(device.clone()).unwrap();
+	let optimize_meshes = !std::env::args().any(|arg| arg == SKIP_MESH_OPT_FLAG);
 
-	let render_pass = Arc::new(
-		vulkano::single_pass_renderpass!(
-			device.clone(),
-			attachments: {
-				color: {
-					load: Clear,
-					store: Store,
-					format: swapchain.format(),
-					samples: 1,
-				}
-			},
-			pass: {
-				color: [color],
-				depth_stencil: {}
-			}
-		)
-		.unwrap(),
-	);
-
-	let pipeline = Arc::new(
-		GraphicsPipeline::start()
-			.vertex_input_single_buffer()
-			.vertex_shader(vs.main_entry_point(), ())
-			.triangle_list()
-			.viewports_dynamic_scissors_irrelevant(1)
-			.fragment_shader(fs.main_entry_point(), ())
-			.render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
-			.build(device.clone())
-			.unwrap(),
-	);
-
-	let mut dynamic_state = DynamicState {
-		line_width: None,
-		viewports: None,
-		scissors: None,
-		compare_mask: None,
-		write_mask: None,
-		reference: None,
-	};
-	let mut framebuffers =
-		window_size_dependent_setup(&images, render_pass.clone(), &mut dynamic_state);
-	let mut recreate_swapchain = false;
-	let mut previous_frame_end = Some(sync::now(device.clone()).boxed());
+	let mut renderer = Some(Renderer::new(window.clone(), optimize_meshes)?);
+	if let Some(renderer) = renderer.as_mut() {
+		if benchmark.is_some() && !renderer.force_present_mode(PresentMode::Immediate)? {
+			warn!("Benchmarking with the default present mode, the timings include vsync");
+		}
+	}
+	let mut recovery = RecoveryStrategy::new();
+	let mut delta_time = DeltaTime::new();
+	let mut elapsed: f32 = 0.0;
+
+	let mut rotation = triangle_rotation();
+	// F2 shows an editor for the rotation curve
+	let mut curve_editor = CurveEditorPanel::new();
+	// B shows the bounds of the scene objects
+	let mut debug_mesh_overlay = DebugMeshOverlay::new();
+	// T shows the vertices and edges of the scene's triangles
+	let mut debug_triangulation = DebugTriangulation::new();
+	let mut input = InputMapper::load(Path::new(KEYBINDINGS_PATH))?;
 
 	event_loop.run(move |event, _, control_flow| {
+		if benchmark.is_some() {
+			// Don't wait for events between frames
+			*control_flow = ControlFlow::Poll;
+		}
+
 		match event {
 			Event::WindowEvent {
 				event: WindowEvent::CloseRequested,
@@ -103,118 +81,78 @@ fn main() {
 			Event::WindowEvent {
 				event: WindowEvent::Resized(_),
 				..
+			}
+			| Event::WindowEvent {
+				event: WindowEvent::ScaleFactorChanged { .. },
+				..
 			} => {
-				recreate_swapchain = true;
+				if let Some(renderer) = renderer.as_mut() {
+					renderer.resized();
+				}
+			}
+			Event::WindowEvent { event, .. } => {
+				input.handle_event(&event);
+				curve_editor.handle_event(&event, window.inner_size().into(), &mut rotation);
 			}
 			Event::RedrawEventsCleared => {
-				previous_frame_end.as_mut().unwrap().cleanup_finished();
-
-				if recreate_swapchain {
-					let dimensions: [u32; 2] = surface.window().inner_size().into();
-					let (new_swapchain, new_images) =
-						match swapchain.recreate_with_dimensions(dimensions) {
-							Ok(r) => r,
-							Err(SwapchainCreationError::UnsupportedDimensions) => return,
-							Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
-						};
-
-					swapchain = new_swapchain;
-					framebuffers = window_size_dependent_setup(
-						&new_images,
-						render_pass.clone(),
-						&mut dynamic_state,
-					);
-					recreate_swapchain = false;
+				if input.was_triggered(Action::ToggleCurveEditor) {
+					curve_editor.visible = !curve_editor.visible;
 				}
-
-				let (image_num, suboptimal, acquire_future) =
-					match swapchain::acquire_next_image(swapchain.clone(), None) {
-						Ok(r) => r,
-						Err(AcquireError::OutOfDate) => {
-							recreate_swapchain = true;
-							return;
-						}
-						Err(e) => panic!("Failed to acquire next image: {:?}", e),
-					};
-
-				if suboptimal {
-					recreate_swapchain = true;
+				if input.was_triggered(Action::ToggleBounds) {
+					debug_mesh_overlay.set_enabled(!debug_mesh_overlay.is_enabled());
 				}
-
-				let clear_values = vec![[0.0, 0.0, 1.0, 1.0].into()];
-
-				// Rotate once (PI*2) every 5 seconds
-				let elapsed = SystemTime::now()
-					.duration_since(UNIX_EPOCH)
-					.unwrap()
-					.as_secs_f64();
-				const DURATION: f64 = 5.0;
-				let remainder = elapsed.rem_euclid(DURATION);
-				let delta = (remainder / DURATION) as f32;
-				let angle = delta * std::f32::consts::PI * 2.0;
-				const RADIUS: f32 = 0.5;
-				// 120Degree offset in radians
-				const ANGLE_OFFSET: f32 = (std::f32::consts::PI * 2.0) / 3.0;
-				// Calculate vertices
-				let data = [
-					Vertex {
-						position: [angle.cos() * RADIUS, angle.sin() * RADIUS],
-					},
-					Vertex {
-						position: [
-							(angle + ANGLE_OFFSET).cos() * RADIUS,
-							(angle + ANGLE_OFFSET).sin() * RADIUS,
-						],
-					},
-					Vertex {
-						position: [
-							(angle - ANGLE_OFFSET).cos() * RADIUS,
-							(angle - ANGLE_OFFSET).sin() * RADIUS,
-						],
-					},
-				];
-
-				// Allocate a new chunk from buffer_pool
-				let buffer = buffer_pool.chunk(data.to_vec()).unwrap();
-				let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
-					device.clone(),
-					queue.family(),
-				)
-				.unwrap();
-				builder
-					.begin_render_pass(
-						framebuffers[image_num].clone(),
-						SubpassContents::Inline,
-						clear_values,
-					)
-					.unwrap()
-					// Draw our buffer
-					.draw(pipeline.clone(), &dynamic_state, buffer, (), (), vec![])
-					.unwrap()
-					.end_render_pass()
-					.unwrap();
-				let command_buffer = builder.build().unwrap();
-
-				let future = previous_frame_end
-					.take()
-					.unwrap()
-					.join(acquire_future)
-					.then_execute(queue.clone(), command_buffer)
-					.unwrap()
-					.then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
-					.then_signal_fence_and_flush();
-
-				match future {
-					Ok(future) => {
-						previous_frame_end = Some(Box::new(future) as Box<_>);
+				if input.was_triggered(Action::ToggleTriangulation) {
+					debug_triangulation.set_enabled(!debug_triangulation.is_enabled());
+				}
+				input.end_frame();
+
+				// The editor can change the duration
+				elapsed = (elapsed + delta_time.tick()).rem_euclid(rotation.duration().max(f32::EPSILON));
+				let angle = rotation.sample(elapsed);
+				let overlay = curve_editor.vertices(&rotation);
+
+				let result = match renderer.as_mut() {
+					Some(renderer) => {
+						#[cfg(debug_assertions)]
+						{
+							let hang_gpu = hang_gpu_in == Some(0);
+							hang_gpu_in = hang_gpu_in.and_then(|frames| frames.checked_sub(1));
+							if hang_gpu {
+								if let Err(e) = renderer.hang_gpu() {
+									error!("Failed to set up the GPU hang: {}", e);
+								}
+							}
+						}
+						renderer.render_frame(angle, &overlay, &debug_mesh_overlay, &debug_triangulation)
 					}
-					Err(FlushError::OutOfDate) => {
-						recreate_swapchain = true;
-						previous_frame_end = Some(Box::new(sync::now(device.clone())) as Box<_>);
+					None => return,
+				};
+
+				match result {
+					Ok(()) => {
+						if let Some(benchmark) = benchmark.as_mut() {
+							if benchmark.frame_completed() {
+								// Runs with and without SKIP_MESH_OPT_FLAG are compared by this key
+								let mut summary = benchmark.summary();
+								summary["mesh_optimization"] = serde_json::json!(optimize_meshes);
+								println!("{}", summary);
+								*control_flow = ControlFlow::Exit;
+							}
+						}
+					}
+					Err(VulkanoError::DeviceLost) => {
+						// Drop every GPU resource before creating a new context,
+						// render_frame has already leaked the frames in flight
+						drop(renderer.take());
+						renderer = recovery.recover(|| Renderer::new(window.clone(), optimize_meshes));
+						if renderer.is_none() {
+							error!("Giving up after repeated device losses");
+							*control_flow = ControlFlow::Exit;
+						}
 					}
 					Err(e) => {
-						println!("Failed to flush future: {:?}", e);
-						previous_frame_end = Some(Box::new(sync::now(device.clone())) as Box<_>);
+						error!("Failed to render frame: {}", e);
+						*control_flow = ControlFlow::Exit;
 					}
 				}
 			}
@@ -0,0 +1,718 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DrawIndirectCommand, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::Device;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageViewAbstract;
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+
+use crate::culling::HiZBuilder;
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::forward_plus::ForwardVertex;
+use crate::pipeline::{BufferlessPipeline, SharedComputePipeline};
+
+// Must match the shaders
+pub const MAX_MESHLET_TRIANGLES: usize = 128;
+// The local indices of a triangle are packed in the bytes of a u32
+pub const MAX_MESHLET_VERTICES: usize = 64;
+mod meshlet_cull_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+
+			#define HI_Z_BINDING 4
+			#include \"hi_z.glsl\"
+
+			// One invocation per meshlet
+			layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+			const uint MAX_MESHLET_TRIANGLES = 128;
+
+			struct Meshlet {
+				// Bounding sphere in model space
+				vec4 bounds;
+				// Vertex offset and count, triangle offset and count
+				uvec4 ranges;
+			};
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				// Those the depth pyramid was rendered with
+				mat4 previous_view;
+				mat4 previous_projection;
+				mat4 model;
+				// Viewport height in pixels, smallest rendered meshlet diameter in
+				// pixels, 1 to test the depth pyramid
+				vec4 screen;
+				// Meshlet count
+				uvec4 counts;
+			} camera;
+			layout(set = 0, binding = 1) readonly buffer Meshlets {
+				Meshlet meshlets[];
+			};
+			layout(set = 0, binding = 2) writeonly buffer Visible {
+				uint visible[];
+			};
+			// VkDrawIndirectCommand, the vertex count is reset to 0 before the pass
+			layout(set = 0, binding = 3) buffer Command {
+				uint vertex_count;
+				uint instance_count;
+				uint first_vertex;
+				uint first_instance;
+			} command;
+			bool in_frustum(vec3 center, float radius) {
+				// The planes from the rows of the view projection, with Vulkan's [0, 1] depth
+				mat4 m = transpose(camera.projection * camera.view);
+				vec4 planes[6] = vec4[](m[3] + m[0], m[3] - m[0], m[3] + m[1], m[3] - m[1], m[2], m[3] - m[2]);
+				for (int i = 0; i < 6; i++) {
+					vec4 plane = planes[i] / length(planes[i].xyz);
+					if (dot(plane.xyz, center) + plane.w < -radius) {
+						return false;
+					}
+				}
+				return true;
+			}
+
+			// Whether the box around the sphere, seen from the previous frame, is
+			// behind the farthest depth of the pixels it covers
+			bool occluded(vec3 center, float radius) {
+				mat4 view_projection = camera.previous_projection * camera.previous_view;
+				vec2 uv_min = vec2(1.0);
+				vec2 uv_max = vec2(0.0);
+				float nearest = 1.0;
+				for (int i = 0; i < 8; i++) {
+					vec3 corner = center + radius * vec3(i & 1, (i >> 1) & 1, (i >> 2) & 1) * 2.0 - radius;
+					vec4 clip = view_projection * vec4(corner, 1.0);
+					if (clip.w <= 0.0) {
+						return false;
+					}
+					vec3 ndc = clip.xyz / clip.w;
+					vec2 uv = ndc.xy * 0.5 + 0.5;
+					uv_min = min(uv_min, uv);
+					uv_max = max(uv_max, uv);
+					nearest = min(nearest, ndc.z);
+				}
+				uv_min = clamp(uv_min, 0.0, 1.0);
+				uv_max = clamp(uv_max, 0.0, 1.0);
+
+				// The level where the box covers at most 2x2 texels
+				vec2 size = (uv_max - uv_min) * vec2(hi_z_size());
+				float lod = log2(max(max(size.x, size.y), 1.0));
+				float farthest = max(
+					max(sample_hi_z(uv_min, lod), sample_hi_z(vec2(uv_max.x, uv_min.y), lod)),
+					max(sample_hi_z(vec2(uv_min.x, uv_max.y), lod), sample_hi_z(uv_max, lod))
+				);
+				return nearest > farthest;
+			}
+
+			void main() {
+				uint index = gl_GlobalInvocationID.x;
+				if (index >= camera.counts.x) {
+					return;
+				}
+				Meshlet meshlet = meshlets[index];
+
+				vec3 center = (camera.model * vec4(meshlet.bounds.xyz, 1.0)).xyz;
+				float scale = max(length(camera.model[0].xyz), max(length(camera.model[1].xyz), length(camera.model[2].xyz)));
+				float radius = meshlet.bounds.w * scale;
+				if (!in_frustum(center, radius)) {
+					return;
+				}
+
+				// Meshlets covering less than a few pixels are dropped like a coarser LOD would
+				float distance = -(camera.view * vec4(center, 1.0)).z;
+				float diameter = 2.0 * radius * camera.projection[1][1] / max(distance, 1e-4) * 0.5 * camera.screen.x;
+				if (distance > radius && diameter < camera.screen.y) {
+					return;
+				}
+				if (camera.screen.z != 0.0 && occluded(center, radius)) {
+					return;
+				}
+
+				// Every visible meshlet adds a full range of vertices to the draw
+				uint slot = atomicAdd(command.vertex_count, MAX_MESHLET_TRIANGLES * 3) / (MAX_MESHLET_TRIANGLES * 3);
+				visible[slot] = index;
+			}
+		"
+	}
+}
+
+mod meshlet_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			const uint MAX_MESHLET_TRIANGLES = 128;
+
+			struct Meshlet {
+				vec4 bounds;
+				uvec4 ranges;
+			};
+
+			struct MeshletVertex {
+				vec4 position;
+				vec4 normal;
+			};
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				mat4 model;
+			} camera;
+			layout(set = 0, binding = 1) readonly buffer Meshlets {
+				Meshlet meshlets[];
+			};
+			layout(set = 0, binding = 2) readonly buffer Vertices {
+				MeshletVertex vertices[];
+			};
+			// Indices in `vertices` of the vertices of each meshlet
+			layout(set = 0, binding = 3) readonly buffer MeshletVertices {
+				uint meshlet_vertices[];
+			};
+			// The local indices of each triangle in the first three bytes
+			layout(set = 0, binding = 4) readonly buffer MeshletTriangles {
+				uint meshlet_triangles[];
+			};
+			// The meshlets left by the culling
+			layout(set = 0, binding = 5) readonly buffer Visible {
+				uint visible[];
+			};
+
+			layout(location = 0) out vec3 v_normal;
+			layout(location = 1) flat out uint v_meshlet;
+
+			void main() {
+				// Each visible meshlet owns a range of vertex indices, like a mesh shader workgroup
+				uint meshlet_index = visible[uint(gl_VertexIndex) / (MAX_MESHLET_TRIANGLES * 3)];
+				uint corner = uint(gl_VertexIndex) % (MAX_MESHLET_TRIANGLES * 3);
+				Meshlet meshlet = meshlets[meshlet_index];
+				v_meshlet = meshlet_index;
+				// The triangles past the meshlet's are degenerate and clipped
+				if (corner / 3 >= meshlet.ranges.w) {
+					v_normal = vec3(0.0);
+					gl_Position = vec4(0.0);
+					return;
+				}
+
+				uint triangle = meshlet_triangles[meshlet.ranges.z + corner / 3];
+				uint local_index = (triangle >> (8 * (corner % 3))) & 0xFF;
+				MeshletVertex vertex = vertices[meshlet_vertices[meshlet.ranges.x + local_index]];
+
+				v_normal = mat3(camera.model) * vertex.normal.xyz;
+				gl_Position = camera.projection * camera.view * camera.model * vec4(vertex.position.xyz, 1.0);
+			}
+		"
+	}
+}
+
+mod meshlet_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 v_normal;
+			layout(location = 1) flat in uint v_meshlet;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(push_constant) uniform PushConstants {
+				// Towards the light
+				vec4 light_direction;
+				// Colors every meshlet differently when not 0
+				uint show_meshlets;
+			} pc;
+
+			vec3 meshlet_color(uint index) {
+				uint hash = index * 2654435761u;
+				return vec3(hash & 0xFF, (hash >> 8) & 0xFF, (hash >> 16) & 0xFF) / 255.0;
+			}
+
+			void main() {
+				vec3 albedo = pc.show_meshlets != 0 ? meshlet_color(v_meshlet) : vec3(0.8);
+				float diffuse = max(dot(normalize(v_normal), pc.light_direction.xyz), 0.0);
+				f_color = vec4(albedo * (0.1 + diffuse), 1.0);
+			}
+		"
+	}
+}
+
+// Matches the std430 layout of the shaders
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Meshlet {
+	// Center and radius of the bounding sphere
+	pub bounds: [f32; 4],
+	// Offset and count in `meshlet_vertices`, then in `meshlet_triangles`
+	pub ranges: [u32; 4],
+}
+
+// Only read on the GPU
+#[allow(dead_code)]
+#[derive(Default, Debug, Clone, Copy)]
+struct MeshletVertex {
+	position: [f32; 4],
+	normal: [f32; 4],
+}
+
+// A mesh split into meshlets of at most MAX_MESHLET_TRIANGLES triangles using
+// at most MAX_MESHLET_VERTICES vertices, in flat arrays
+pub struct MeshletMesh {
+	pub vertices: Vec<ForwardVertex>,
+	pub meshlet_vertices: Vec<u32>,
+	// The local indices of each triangle packed in the low bytes
+	pub meshlet_triangles: Vec<u32>,
+	pub meshlets: Vec<Meshlet>,
+}
+
+impl MeshletMesh {
+	// Greedy, like meshoptimizer's builder: the next triangle is the unused one
+	// sharing the most vertices with the current meshlet, which keeps the
+	// meshlets compact, and a new meshlet starts when either limit is reached
+	pub fn build(vertices: &[ForwardVertex], indices: &[u32]) -> MeshletMesh {
+		let triangle_count = indices.len() / 3;
+		let mut vertex_triangles: HashMap<u32, Vec<usize>> = HashMap::new();
+		for triangle in 0..triangle_count {
+			for &vertex in &indices[triangle * 3..triangle * 3 + 3] {
+				vertex_triangles.entry(vertex).or_default().push(triangle);
+			}
+		}
+
+		let mut mesh = MeshletMesh {
+			vertices: vertices.to_vec(),
+			meshlet_vertices: Vec::new(),
+			meshlet_triangles: Vec::new(),
+			meshlets: Vec::new(),
+		};
+		let mut used = vec![false; triangle_count];
+		// Local index of the vertices of the current meshlet
+		let mut local: HashMap<u32, u32> = HashMap::new();
+		let mut triangles: Vec<usize> = Vec::new();
+		let mut next_unused = 0;
+
+		loop {
+			let candidate = local
+				.keys()
+				.flat_map(|vertex| vertex_triangles[vertex].iter().copied())
+				.filter(|&triangle| !used[triangle])
+				.max_by_key(|&triangle| {
+					let shared = indices[triangle * 3..triangle * 3 + 3]
+						.iter()
+						.filter(|vertex| local.contains_key(vertex))
+						.count();
+					// The lowest index on ties, for a deterministic result
+					(shared, std::cmp::Reverse(triangle))
+				});
+			let triangle = match candidate {
+				Some(triangle) => triangle,
+				None => {
+					while next_unused < triangle_count && used[next_unused] {
+						next_unused += 1;
+					}
+					if next_unused == triangle_count {
+						break;
+					}
+					next_unused
+				}
+			};
+
+			let corners = &indices[triangle * 3..triangle * 3 + 3];
+			let new_vertices = corners.iter().filter(|vertex| !local.contains_key(vertex)).count();
+			if local.len() + new_vertices > MAX_MESHLET_VERTICES || triangles.len() == MAX_MESHLET_TRIANGLES {
+				mesh.flush(&mut local, &mut triangles, indices);
+				continue;
+			}
+			for &vertex in corners {
+				let next = local.len() as u32;
+				local.entry(vertex).or_insert(next);
+			}
+			triangles.push(triangle);
+			used[triangle] = true;
+		}
+		mesh.flush(&mut local, &mut triangles, indices);
+		mesh
+	}
+
+	fn flush(&mut self, local: &mut HashMap<u32, u32>, triangles: &mut Vec<usize>, indices: &[u32]) {
+		if triangles.is_empty() {
+			return;
+		}
+		let vertex_offset = self.meshlet_vertices.len() as u32;
+		let triangle_offset = self.meshlet_triangles.len() as u32;
+
+		let mut ordered = vec![0; local.len()];
+		for (&vertex, &index) in local.iter() {
+			ordered[index as usize] = vertex;
+		}
+		for &triangle in triangles.iter() {
+			let packed = indices[triangle * 3..triangle * 3 + 3]
+				.iter()
+				.enumerate()
+				.fold(0, |packed, (corner, vertex)| packed | local[vertex] << (8 * corner));
+			self.meshlet_triangles.push(packed);
+		}
+
+		// Bounding sphere centered on the bounding box
+		let positions: Vec<[f32; 3]> = ordered.iter().map(|&vertex| self.vertices[vertex as usize].position).collect();
+		let mut min = [f32::MAX; 3];
+		let mut max = [f32::MIN; 3];
+		for position in &positions {
+			for axis in 0..3 {
+				min[axis] = min[axis].min(position[axis]);
+				max[axis] = max[axis].max(position[axis]);
+			}
+		}
+		let center = [(min[0] + max[0]) * 0.5, (min[1] + max[1]) * 0.5, (min[2] + max[2]) * 0.5];
+		let radius = positions
+			.iter()
+			.map(|p| ((p[0] - center[0]).powi(2) + (p[1] - center[1]).powi(2) + (p[2] - center[2]).powi(2)).sqrt())
+			.fold(0.0, f32::max);
+
+		self.meshlets.push(Meshlet {
+			bounds: [center[0], center[1], center[2], radius],
+			ranges: [vertex_offset, ordered.len() as u32, triangle_offset, triangles.len() as u32],
+		});
+		self.meshlet_vertices.extend(ordered);
+		local.clear();
+		triangles.clear();
+	}
+}
+
+// Meshlet culling in the spirit of a task shader. Every meshlet is tested
+// against the frustum and a minimum screen size and, with `hiz_culling`,
+// against the farthest depths of the previous frame, and the survivors are
+// compacted into `visible`. vulkano 0.22 has neither draw_mesh_tasks_indirect
+// nor indirect count draws, so a single indirect draw stands in: every
+// surviving meshlet adds MAX_MESHLET_TRIANGLES * 3 vertices to its vertex
+// count with an atomic, and its slot in `visible` is that count before the
+// addition.
+pub struct MeshletCullingPass {
+	pipeline: SharedComputePipeline,
+	camera_pool: CpuBufferPool<meshlet_cull_cs::ty::Camera>,
+	meshlets: Arc<CpuAccessibleBuffer<[Meshlet]>>,
+	visible: Arc<DeviceLocalBuffer<[u32]>>,
+	command: Arc<DeviceLocalBuffer<[DrawIndirectCommand]>>,
+	// Copied over `command` before each cull
+	empty_command: Arc<CpuAccessibleBuffer<[DrawIndirectCommand]>>,
+	hiz: HiZBuilder,
+	// The matrices of the last `cull`, and of the frame the pyramid was built for
+	matrices: ([[f32; 4]; 4], [[f32; 4]; 4]),
+	previous_matrices: ([[f32; 4]; 4], [[f32; 4]; 4]),
+	hiz_valid: bool,
+	meshlet_count: u32,
+	// Diameter in pixels under which a meshlet is culled
+	pub min_screen_size: f32,
+	pub hiz_culling: bool,
+}
+
+impl MeshletCullingPass {
+	pub fn new(
+		device: Arc<Device>,
+		meshlets: Arc<CpuAccessibleBuffer<[Meshlet]>>,
+		meshlet_count: u32,
+		dimensions: [u32; 2],
+	) -> Result<MeshletCullingPass, VulkanoError> {
+		let shader = meshlet_cull_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let empty_command = DrawIndirectCommand {
+			vertex_count: 0,
+			instance_count: 1,
+			first_vertex: 0,
+			first_instance: 0,
+		};
+		let identity = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+
+		Ok(MeshletCullingPass {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			meshlets,
+			visible: DeviceLocalBuffer::array(
+				device.clone(),
+				meshlet_count.max(1) as usize,
+				BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+				device.active_queue_families(),
+			)?,
+			command: DeviceLocalBuffer::array(
+				device.clone(),
+				1,
+				BufferUsage {
+					storage_buffer: true,
+					indirect_buffer: true,
+					transfer_destination: true,
+					..BufferUsage::none()
+				},
+				device.active_queue_families(),
+			)?,
+			empty_command: CpuAccessibleBuffer::from_iter(
+				device.clone(),
+				BufferUsage::transfer_source(),
+				false,
+				Some(empty_command).into_iter(),
+			)?,
+			hiz: HiZBuilder::new(device.clone(), dimensions)?,
+			matrices: (identity, identity),
+			previous_matrices: (identity, identity),
+			hiz_valid: false,
+			meshlet_count,
+			min_screen_size: 2.0,
+			hiz_culling: true,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		self.hiz.resize(dimensions)?;
+		self.hiz_valid = false;
+		Ok(())
+	}
+
+	// Records the pyramid of the farthest depths of the frame, once its depth
+	// buffer is complete and outside of any render pass. The next `cull` tests
+	// the meshlets against it with the matrices of the last `cull`.
+	pub fn build_hiz(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+	) -> Result<(), VulkanoError> {
+		self.hiz.build(builder, depth)?;
+		self.previous_matrices = self.matrices;
+		self.hiz_valid = true;
+		Ok(())
+	}
+
+	// Records the culling of the meshlets for the frame, outside of any render pass
+	pub fn cull(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+		model: [[f32; 4]; 4],
+		viewport_height: f32,
+	) -> Result<(), VulkanoError> {
+		let (previous_view, previous_projection) = self.previous_matrices;
+		let test_hiz = self.hiz_culling && self.hiz_valid;
+		let camera = self.camera_pool.next(meshlet_cull_cs::ty::Camera {
+			view,
+			projection,
+			previous_view,
+			previous_projection,
+			model,
+			screen: [viewport_height, self.min_screen_size, test_hiz as u32 as f32, 0.0],
+			counts: [self.meshlet_count, 0, 0, 0],
+		})?;
+		self.matrices = (view, projection);
+
+		builder.copy_buffer(self.empty_command.clone(), self.command.clone())?;
+
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		// Each descriptor changes the type of the builder, the levels can't be added in a loop
+		let hiz = self.hiz.views()?;
+		let sampler = self.hiz.sampler();
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_buffer(self.meshlets.clone())?
+				.add_buffer(self.visible.clone())?
+				.add_buffer(self.command.clone())?
+				.enter_array()?
+				.add_sampled_image(hiz[0].clone(), sampler.clone())?
+				.add_sampled_image(hiz[1].clone(), sampler.clone())?
+				.add_sampled_image(hiz[2].clone(), sampler.clone())?
+				.add_sampled_image(hiz[3].clone(), sampler.clone())?
+				.add_sampled_image(hiz[4].clone(), sampler.clone())?
+				.add_sampled_image(hiz[5].clone(), sampler.clone())?
+				.add_sampled_image(hiz[6].clone(), sampler.clone())?
+				.add_sampled_image(hiz[7].clone(), sampler.clone())?
+				.add_sampled_image(hiz[8].clone(), sampler.clone())?
+				.add_sampled_image(hiz[9].clone(), sampler.clone())?
+				.add_sampled_image(hiz[10].clone(), sampler.clone())?
+				.add_sampled_image(hiz[11].clone(), sampler.clone())?
+				.leave_array()?
+				.build()?,
+		);
+		debug_label!(builder, "MeshletCullingPass", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[self.meshlet_count.div_ceil(64), 1, 1],
+			self.pipeline.clone(),
+			set,
+			(),
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+
+	// The indices of the meshlets left by the last `cull`
+	pub fn visible(&self) -> Arc<DeviceLocalBuffer<[u32]>> {
+		self.visible.clone()
+	}
+
+	// The draw of every visible meshlet, MAX_MESHLET_TRIANGLES * 3 vertices each
+	pub fn command(&self) -> Arc<DeviceLocalBuffer<[DrawIndirectCommand]>> {
+		self.command.clone()
+	}
+}
+
+// Meshlet rendering in the spirit of Nanite's clusters. vulkano 0.22 has no
+// mesh or task shaders, so both stages are emulated: a MeshletCullingPass
+// plays the task shader and the vertex shader plays the mesh shader,
+// fetching the triangles of the visible meshlets from the flat arrays with
+// the vertex index.
+pub struct MeshletRenderer {
+	culling: MeshletCullingPass,
+	draw_pipeline: Arc<BufferlessPipeline>,
+	camera_pool: CpuBufferPool<meshlet_vs::ty::Camera>,
+	meshlets: Arc<CpuAccessibleBuffer<[Meshlet]>>,
+	vertices: Arc<CpuAccessibleBuffer<[MeshletVertex]>>,
+	meshlet_vertices: Arc<CpuAccessibleBuffer<[u32]>>,
+	meshlet_triangles: Arc<CpuAccessibleBuffer<[u32]>>,
+	// Written by `cull` for the draw of the same frame
+	draw_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+	meshlet_count: u32,
+	pub show_meshlets: bool,
+	// Normalized, towards the light
+	pub light_direction: [f32; 3],
+}
+
+impl MeshletRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		mesh: &MeshletMesh,
+		dimensions: [u32; 2],
+	) -> Result<MeshletRenderer, VulkanoError> {
+		let vs = meshlet_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = meshlet_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let has_depth = subpass.has_depth();
+		let builder = GraphicsPipeline::start()
+			.vertex_input(BufferlessDefinition {})
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ());
+		let builder = if has_depth {
+			builder.depth_stencil_simple_depth()
+		} else {
+			builder
+		};
+		let draw_pipeline = Arc::new(builder.render_pass(subpass).build(device.clone())?);
+
+		let storage = |data: Vec<u32>| CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage { storage_buffer: true, ..BufferUsage::none() }, false, data.into_iter());
+		let vertices = mesh.vertices.iter().map(|vertex| {
+			let [x, y, z] = vertex.position;
+			let [nx, ny, nz] = vertex.normal;
+			MeshletVertex {
+				position: [x, y, z, 1.0],
+				normal: [nx, ny, nz, 0.0],
+			}
+		});
+		let meshlet_count = mesh.meshlets.len() as u32;
+		let meshlets = CpuAccessibleBuffer::from_iter(
+			device.clone(),
+			BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+			false,
+			mesh.meshlets.iter().copied(),
+		)?;
+
+		Ok(MeshletRenderer {
+			culling: MeshletCullingPass::new(device.clone(), meshlets.clone(), meshlet_count, dimensions)?,
+			draw_pipeline,
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			meshlets,
+			vertices: CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage { storage_buffer: true, ..BufferUsage::none() }, false, vertices)?,
+			// Empty buffers can't be created, an empty mesh keeps one unused element
+			meshlet_vertices: storage(mesh.meshlet_vertices.iter().copied().chain(Some(0)).collect())?,
+			meshlet_triangles: storage(mesh.meshlet_triangles.iter().copied().chain(Some(0)).collect())?,
+			draw_set: None,
+			meshlet_count,
+			show_meshlets: true,
+			light_direction: [0.0, 1.0, 0.0],
+		})
+	}
+
+	pub fn meshlet_count(&self) -> u32 {
+		self.meshlet_count
+	}
+
+	pub fn culling(&mut self) -> &mut MeshletCullingPass {
+		&mut self.culling
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		self.culling.resize(dimensions)
+	}
+
+	// See MeshletCullingPass::build_hiz
+	pub fn build_hiz(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+	) -> Result<(), VulkanoError> {
+		self.culling.build_hiz(builder, depth)
+	}
+
+	// Records the culling of the meshlets for the frame, outside of any render pass
+	pub fn cull(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+		model: [[f32; 4]; 4],
+		viewport_height: f32,
+	) -> Result<(), VulkanoError> {
+		self.culling.cull(builder, view, projection, model, viewport_height)?;
+
+		let camera = self.camera_pool.next(meshlet_vs::ty::Camera { view, projection, model })?;
+		let layout = self
+			.draw_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		self.draw_set = Some(Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_buffer(self.meshlets.clone())?
+				.add_buffer(self.vertices.clone())?
+				.add_buffer(self.meshlet_vertices.clone())?
+				.add_buffer(self.meshlet_triangles.clone())?
+				.add_buffer(self.culling.visible())?
+				.build()?,
+		));
+		Ok(())
+	}
+
+	// Records the meshlets left by the last `cull`, inside the render pass
+	pub fn draw(&self, builder: &mut AutoCommandBufferBuilder, dynamic_state: &DynamicState) -> Result<(), VulkanoError> {
+		if self.meshlet_count == 0 {
+			return Ok(());
+		}
+		let draw_set = self.draw_set.clone().expect("the meshlets must be culled before drawing");
+		let [x, y, z] = self.light_direction;
+		builder.draw_indirect(
+			self.draw_pipeline.clone(),
+			dynamic_state,
+			BufferlessVertices {
+				vertices: self.meshlet_count as usize * MAX_MESHLET_TRIANGLES * 3,
+				instances: 1,
+			},
+			self.culling.command(),
+			draw_set,
+			meshlet_fs::ty::PushConstants {
+				light_direction: [x, y, z, 0.0],
+				show_meshlets: self.show_meshlets as u32,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
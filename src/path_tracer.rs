@@ -0,0 +1,661 @@
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::{CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::ComputePipeline;
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::forward_plus::ForwardVertex;
+use crate::pipeline::SharedComputePipeline;
+use crate::ray_march::{device_local_array, upload_array, Bvh, BvhNode, BvhTriangle};
+
+// Mean radiance of the samples in rgb
+pub const PATH_TRACER_FORMAT: Format = Format::R32G32B32A32Sfloat;
+// Must match MAX_PATH_DEPTH of the shader
+pub const MAX_PATH_DEPTH: u32 = 8;
+
+mod path_trace_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+
+			#include \"bvh.glsl\"
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const int MAX_PATH_DEPTH = 8;
+			const float PI = 3.14159265359;
+			// Offset of the rays leaving a surface, against self intersections
+			const float RAY_EPSILON = 1e-3;
+
+			struct Material {
+				// Mirrors have a w of 1
+				vec4 albedo;
+				// Radiance in rgb, both sides emit, and in w the density in
+				// area measure of sampling a point of the triangle as a light
+				vec4 emission;
+			};
+
+			struct Light {
+				uint triangle;
+				// Of the powers, up to this light included
+				float cdf;
+			};
+
+			layout(set = 0, binding = 2) readonly buffer Materials {
+				Material materials[];
+			};
+			layout(set = 0, binding = 3) readonly buffer Lights {
+				Light lights[];
+			};
+			layout(set = 0, binding = 4) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				// Node count, light count and index of the sample
+				uvec4 counts;
+				// Vertices past the camera and past the light of the subpaths
+				uvec4 depths;
+			} camera;
+			layout(set = 0, binding = 5, rgba32f) uniform image2D output_image;
+
+			struct Vertex {
+				vec3 position;
+				// Geometric normal as wound, 0 for the camera
+				vec3 normal;
+				// Throughput from the start of the subpath
+				vec3 beta;
+				// -1 for the camera
+				int triangle;
+				bool delta;
+				// Densities in area measure of sampling the vertex from the
+				// previous vertex of its subpath, and from the next one
+				float pdf_fwd;
+				float pdf_rev;
+			};
+
+			Vertex camera_path[MAX_PATH_DEPTH + 1];
+			Vertex light_path[MAX_PATH_DEPTH + 1];
+
+			// PCG hash, the state is advanced at each call
+			uint rng_state;
+
+			float random() {
+				rng_state = rng_state * 747796405u + 2891336453u;
+				uint word = ((rng_state >> ((rng_state >> 28u) + 4u)) ^ rng_state) * 277803737u;
+				return float((word >> 22u) ^ word) / 4294967296.0;
+			}
+
+			vec3 cosine_sample(vec3 normal) {
+				float r = sqrt(random());
+				float phi = 2.0 * PI * random();
+				vec3 tangent = normalize(cross(abs(normal.x) > 0.5 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0), normal));
+				vec3 bitangent = cross(normal, tangent);
+				return normalize(tangent * r * cos(phi) + bitangent * r * sin(phi) + normal * sqrt(max(1.0 - r * r, 0.0)));
+			}
+
+			bool is_mirror(int triangle) {
+				return materials[triangle].albedo.w > 0.5;
+			}
+
+			// Two-sided Lambert, the mirrors only reflect along the direction
+			// they sample
+			vec3 bsdf(Vertex v, vec3 wo, vec3 wi) {
+				if (v.triangle < 0 || is_mirror(v.triangle) || dot(wo, v.normal) * dot(wi, v.normal) <= 0.0) {
+					return vec3(0.0);
+				}
+				return materials[v.triangle].albedo.rgb / PI;
+			}
+
+			// Solid angle density of sampling `wi` from `wo`
+			float bsdf_pdf(Vertex v, vec3 wo, vec3 wi) {
+				if (v.triangle < 0 || is_mirror(v.triangle) || dot(wo, v.normal) * dot(wi, v.normal) <= 0.0) {
+					return 0.0;
+				}
+				return abs(dot(wi, v.normal)) / PI;
+			}
+
+			// Emitters sample a cosine weighted direction on a random side
+			float light_pdf_direction(Vertex light, vec3 direction) {
+				return abs(dot(light.normal, direction)) / (2.0 * PI);
+			}
+
+			// A solid angle density at `from` converted to an area density at `to`
+			float to_area(float pdf, vec3 from, Vertex to) {
+				vec3 w = to.position - from;
+				float squared = dot(w, w);
+				return pdf * abs(dot(to.normal, w)) / (squared * sqrt(squared));
+			}
+
+			float geometry(Vertex a, Vertex b) {
+				vec3 w = b.position - a.position;
+				float squared = dot(w, w);
+				return abs(dot(a.normal, w)) * abs(dot(b.normal, w)) / (squared * squared);
+			}
+
+			bool visible(vec3 from, vec3 to) {
+				vec3 w = to - from;
+				float distance = length(w);
+				float hit_distance;
+				return bvh_trace(from + w / distance * RAY_EPSILON, w / distance, distance - 2.0 * RAY_EPSILON, camera.counts.x, hit_distance) < 0;
+			}
+
+			// A point on an emitter picked in proportion to their powers
+			Vertex sample_light() {
+				float u = random();
+				uint low = 0;
+				uint high = camera.counts.y - 1;
+				while (low < high) {
+					uint middle = (low + high) / 2;
+					if (lights[middle].cdf < u) {
+						low = middle + 1;
+					} else {
+						high = middle;
+					}
+				}
+				int triangle = int(lights[low].triangle);
+				BvhTriangle corners = bvh_triangles[triangle];
+				float s = sqrt(random());
+				float v = random();
+
+				Vertex light;
+				light.position = corners.v0.xyz * (1.0 - s) + corners.v1.xyz * s * (1.0 - v) + corners.v2.xyz * s * v;
+				light.normal = bvh_triangle_normal(triangle);
+				light.triangle = triangle;
+				light.delta = false;
+				light.pdf_fwd = materials[triangle].emission.w;
+				light.pdf_rev = 0.0;
+				light.beta = materials[triangle].emission.rgb / light.pdf_fwd;
+				return light;
+			}
+
+			Vertex path_vertex(bool on_camera, int index) {
+				return on_camera ? camera_path[index] : light_path[index];
+			}
+
+			void set_path_vertex(bool on_camera, int index, Vertex v) {
+				if (on_camera) {
+					camera_path[index] = v;
+				} else {
+					light_path[index] = v;
+				}
+			}
+
+			// Extends the subpath ending at vertex `count - 1` along `direction`,
+			// sampled with the solid angle density `pdf`, up to `max_count`
+			// vertices. Returns the vertex count.
+			int random_walk(bool on_camera, int count, int max_count, vec3 direction, vec3 beta, float pdf) {
+				while (count < max_count) {
+					Vertex previous = path_vertex(on_camera, count - 1);
+					float distance;
+					int hit = bvh_trace(previous.position + direction * RAY_EPSILON, direction, 1e30, camera.counts.x, distance);
+					if (hit < 0) {
+						break;
+					}
+
+					Vertex v;
+					v.position = previous.position + direction * (distance + RAY_EPSILON);
+					v.normal = bvh_triangle_normal(hit);
+					v.beta = beta;
+					v.triangle = hit;
+					v.delta = is_mirror(hit);
+					v.pdf_fwd = to_area(pdf, previous.position, v);
+					v.pdf_rev = 0.0;
+					set_path_vertex(on_camera, count++, v);
+					if (count == max_count) {
+						break;
+					}
+
+					vec3 wo = -direction;
+					float pdf_rev = 0.0;
+					if (v.delta) {
+						direction = reflect(direction, v.normal);
+						// The densities of specular bounces stay 0, which the MIS
+						// weights skip
+						pdf = 0.0;
+					} else {
+						direction = cosine_sample(faceforward(v.normal, direction, v.normal));
+						pdf = bsdf_pdf(v, wo, direction);
+						pdf_rev = bsdf_pdf(v, direction, wo);
+						if (pdf == 0.0) {
+							break;
+						}
+					}
+					// f cos / pdf of both the mirrors and the cosine sampled Lambert
+					beta *= materials[hit].albedo.rgb;
+					if (all(equal(beta, vec3(0.0)))) {
+						break;
+					}
+					previous.pdf_rev = to_area(pdf_rev, v.position, previous);
+					set_path_vertex(on_camera, count - 2, previous);
+				}
+				return count;
+			}
+
+			float remap(float pdf) {
+				return pdf != 0.0 ? pdf : 1.0;
+			}
+
+			// Balance heuristic of the strategy with s light and t camera
+			// vertices, against every other strategy making the same path
+			// except those connecting to the camera. The densities of the
+			// endpoints and of their predecessors as reached from the other
+			// subpath are passed in, with the density of the light sampled when
+			// s is 1.
+			float mis_weight(int s, int t, float z_rev_last, float z_rev_previous, float y_rev_last, float y_rev_previous, float sampled_pdf) {
+				float sum = 0.0;
+				float ratio = 1.0;
+				for (int i = t - 1; i > 1; i--) {
+					float rev = i == t - 1 ? z_rev_last : i == t - 2 ? z_rev_previous : camera_path[i].pdf_rev;
+					ratio *= remap(rev) / remap(camera_path[i].pdf_fwd);
+					bool delta = i != t - 1 && camera_path[i].delta;
+					if (!delta && !camera_path[i - 1].delta) {
+						sum += ratio;
+					}
+				}
+				ratio = 1.0;
+				for (int i = s - 1; i >= 0; i--) {
+					float rev = i == s - 1 ? y_rev_last : i == s - 2 ? y_rev_previous : light_path[i].pdf_rev;
+					float fwd = s == 1 ? sampled_pdf : light_path[i].pdf_fwd;
+					ratio *= remap(rev) / remap(fwd);
+					bool delta = i != s - 1 && light_path[i].delta;
+					bool delta_previous = i > 0 && light_path[i - 1].delta;
+					if (!delta && !delta_previous) {
+						sum += ratio;
+					}
+				}
+				return 1.0 / (1.0 + sum);
+			}
+
+			// Weighted contribution of the strategy with s light and t camera
+			// vertices, t being at least 2
+			vec3 connect(int s, int t) {
+				Vertex z = camera_path[t - 1];
+				Vertex z_previous = camera_path[t - 2];
+				vec3 wz = normalize(z_previous.position - z.position);
+
+				// The camera subpath hit an emitter
+				if (s == 0) {
+					vec3 emission = materials[z.triangle].emission.rgb;
+					if (all(equal(emission, vec3(0.0)))) {
+						return vec3(0.0);
+					}
+					float z_rev_last = materials[z.triangle].emission.w;
+					float z_rev_previous = to_area(light_pdf_direction(z, wz), z.position, z_previous);
+					return z.beta * emission * mis_weight(0, t, z_rev_last, z_rev_previous, 0.0, 0.0, 0.0);
+				}
+				if (z.delta) {
+					return vec3(0.0);
+				}
+
+				// Next event estimation with a new point on an emitter
+				if (s == 1) {
+					Vertex light = sample_light();
+					vec3 d = normalize(light.position - z.position);
+					vec3 contribution = z.beta * bsdf(z, wz, d) * geometry(z, light) * light.beta;
+					if (all(equal(contribution, vec3(0.0))) || !visible(z.position, light.position)) {
+						return vec3(0.0);
+					}
+					float z_rev_last = to_area(light_pdf_direction(light, -d), light.position, z);
+					float z_rev_previous = to_area(bsdf_pdf(z, d, wz), z.position, z_previous);
+					float y_rev_last = to_area(bsdf_pdf(z, wz, d), z.position, light);
+					return contribution * mis_weight(1, t, z_rev_last, z_rev_previous, y_rev_last, 0.0, light.pdf_fwd);
+				}
+
+				Vertex y = light_path[s - 1];
+				Vertex y_previous = light_path[s - 2];
+				if (y.delta) {
+					return vec3(0.0);
+				}
+				vec3 wy = normalize(y_previous.position - y.position);
+				vec3 d = normalize(z.position - y.position);
+				vec3 contribution = y.beta * bsdf(y, wy, d) * geometry(y, z) * bsdf(z, wz, -d) * z.beta;
+				if (all(equal(contribution, vec3(0.0))) || !visible(y.position, z.position)) {
+					return vec3(0.0);
+				}
+				float z_rev_last = to_area(bsdf_pdf(y, wy, d), y.position, z);
+				float z_rev_previous = to_area(bsdf_pdf(z, -d, wz), z.position, z_previous);
+				float y_rev_last = to_area(bsdf_pdf(z, wz, -d), z.position, y);
+				float y_rev_previous = to_area(bsdf_pdf(y, d, wy), y.position, y_previous);
+				return contribution * mis_weight(s, t, z_rev_last, z_rev_previous, y_rev_last, y_rev_previous, 0.0);
+			}
+
+			void main() {
+				ivec2 size = imageSize(output_image);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				rng_state = uint(pixel.x) * 1973u + uint(pixel.y) * 9277u + camera.counts.z * 26699u;
+				int camera_depth = min(int(camera.depths.x), MAX_PATH_DEPTH);
+				int light_depth = min(int(camera.depths.y), MAX_PATH_DEPTH);
+
+				// Jittered inside the pixel, which antialiases the mean
+				vec2 uv = (vec2(pixel) + vec2(random(), random())) / vec2(size);
+				mat4 inverse_view = inverse(camera.view);
+				vec4 target = inverse(camera.projection) * vec4(uv * 2.0 - 1.0, 1.0, 1.0);
+				vec3 direction = normalize((inverse_view * vec4(normalize(target.xyz / target.w), 0.0)).xyz);
+
+				// The density of the pinhole only matters to the strategies
+				// connecting to the camera, which aren't traced
+				camera_path[0].position = inverse_view[3].xyz;
+				camera_path[0].normal = vec3(0.0);
+				camera_path[0].beta = vec3(1.0);
+				camera_path[0].triangle = -1;
+				camera_path[0].delta = false;
+				camera_path[0].pdf_fwd = 1.0;
+				camera_path[0].pdf_rev = 0.0;
+				int camera_count = random_walk(true, 1, camera_depth + 1, direction, vec3(1.0), 1.0);
+
+				int light_count = 0;
+				if (camera.counts.y > 0) {
+					Vertex light = sample_light();
+					light_path[0] = light;
+					vec3 side = random() < 0.5 ? light.normal : -light.normal;
+					vec3 light_direction = cosine_sample(side);
+					float pdf = light_pdf_direction(light, light_direction);
+					vec3 beta = light.beta * abs(dot(light.normal, light_direction)) / pdf;
+					light_count = random_walk(false, 1, light_depth + 1, light_direction, beta, pdf);
+				}
+
+				// Rays escaping the scene see black
+				vec3 radiance = vec3(0.0);
+				for (int t = 2; t <= camera_count; t++) {
+					for (int s = 0; s <= light_count; s++) {
+						radiance += connect(s, t);
+					}
+				}
+				if (any(isnan(radiance)) || any(isinf(radiance))) {
+					radiance = vec3(0.0);
+				}
+
+				// Running mean of the samples
+				float n = float(camera.counts.z);
+				vec3 mean = n == 0.0 ? radiance : mix(imageLoad(output_image, pixel).rgb, radiance, 1.0 / (n + 1.0));
+				imageStore(output_image, pixel, vec4(mean, 1.0));
+			}
+		"
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PathTracerMaterial {
+	pub albedo: [f32; 3],
+	// Radiance of both sides, black for surfaces that don't emit
+	pub emission: [f32; 3],
+	// Perfect mirror tinted by the albedo, else Lambertian
+	pub mirror: bool,
+}
+
+// Geometry in world space
+pub struct PathTracerMesh<'a> {
+	pub vertices: &'a [ForwardVertex],
+	pub indices: &'a [u32],
+	pub material: PathTracerMaterial,
+}
+
+// Matches the std430 layout of the shader
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+struct TracedMaterial {
+	albedo: [f32; 4],
+	emission: [f32; 4],
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+struct TracedLight {
+	triangle: u32,
+	cdf: f32,
+}
+
+// Ground truth for the real-time approximations. A compute shader traces
+// bidirectional paths (BDPT) through the BVH of the scene: every sample builds
+// a camera subpath and a light subpath starting on an emissive triangle, then
+// connects their every prefix, hits of the emitters and next event estimation
+// included. The balance heuristic weights each strategy against the others
+// making the same path. Vulkano 0.22 exposes no float atomics, so the light
+// tracing strategies connecting to the camera, which splat to any pixel, are
+// left out of the sum and of the weights alike. Each `record` adds one sample
+// per pixel to the running mean of the output until `target_samples`, it
+// restarts when the camera moves or the scene changes.
+#[allow(clippy::type_complexity)]
+pub struct BidirectionalPathTracer {
+	pipeline: SharedComputePipeline,
+	camera_pool: CpuBufferPool<path_trace_cs::ty::Camera>,
+	nodes: Arc<DeviceLocalBuffer<[BvhNode]>>,
+	triangles: Arc<DeviceLocalBuffer<[BvhTriangle]>>,
+	materials: Arc<DeviceLocalBuffer<[TracedMaterial]>>,
+	lights: Arc<DeviceLocalBuffer<[TracedLight]>>,
+	node_count: u32,
+	light_count: u32,
+	output: Arc<StorageImage<Format>>,
+	sample_count: u32,
+	last_camera: Option<([[f32; 4]; 4], [[f32; 4]; 4])>,
+	dimensions: [u32; 2],
+	device: Arc<Device>,
+	pub target_samples: u32,
+	// Vertices past the camera and past the light, up to MAX_PATH_DEPTH
+	pub camera_depth: u32,
+	pub light_depth: u32,
+}
+
+impl BidirectionalPathTracer {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<BidirectionalPathTracer, VulkanoError> {
+		let shader = path_trace_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		Ok(BidirectionalPathTracer {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			// Empty buffers can't be created, the counts stay 0 until the scene is set
+			nodes: device_local_array(&device, 1)?,
+			triangles: device_local_array(&device, 1)?,
+			materials: device_local_array(&device, 1)?,
+			lights: device_local_array(&device, 1)?,
+			node_count: 0,
+			light_count: 0,
+			output: output_image(&device, dimensions)?,
+			sample_count: 0,
+			last_camera: None,
+			dimensions,
+			device,
+			target_samples: 1024,
+			camera_depth: 5,
+			light_depth: 5,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		self.output = output_image(&self.device, dimensions)?;
+		self.dimensions = dimensions;
+		self.reset();
+		Ok(())
+	}
+
+	// Rebuilds the BVH and the lights of the scene and records their upload,
+	// outside of any render pass
+	pub fn set_scene(&mut self, builder: &mut AutoCommandBufferBuilder, meshes: &[PathTracerMesh]) -> Result<(), VulkanoError> {
+		let mut vertices = Vec::new();
+		let mut indices = Vec::new();
+		let mut triangle_materials = Vec::new();
+		for mesh in meshes {
+			let offset = vertices.len() as u32;
+			vertices.extend_from_slice(mesh.vertices);
+			indices.extend(mesh.indices.iter().map(|index| index + offset));
+			triangle_materials.extend(std::iter::repeat_n(mesh.material, mesh.indices.len() / 3));
+		}
+		self.reset();
+
+		let bvh = Bvh::build(&vertices, &indices);
+		if bvh.nodes.is_empty() {
+			self.node_count = 0;
+			self.light_count = 0;
+			return Ok(());
+		}
+
+		// The emitters are picked in proportion to their power, luminance times area
+		let powers: Vec<f32> = bvh
+			.order()
+			.iter()
+			.map(|&triangle| {
+				let [r, g, b] = triangle_materials[triangle].emission;
+				let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+				luminance * triangle_area(&vertices, &indices[triangle * 3..triangle * 3 + 3])
+			})
+			.collect();
+		let total_power: f32 = powers.iter().sum();
+
+		let mut materials = Vec::with_capacity(powers.len());
+		let mut lights = Vec::new();
+		let mut cdf = 0.0;
+		for (slot, &triangle) in bvh.order().iter().enumerate() {
+			let material = triangle_materials[triangle];
+			let mut pdf = 0.0;
+			if powers[slot] > 0.0 {
+				cdf += powers[slot] / total_power;
+				lights.push(TracedLight {
+					triangle: slot as u32,
+					cdf,
+				});
+				// Chance of picking the triangle over its area
+				pdf = powers[slot] / total_power / triangle_area(&vertices, &indices[triangle * 3..triangle * 3 + 3]);
+			}
+			let [r, g, b] = material.albedo;
+			let [er, eg, eb] = material.emission;
+			materials.push(TracedMaterial {
+				albedo: [r, g, b, if material.mirror { 1.0 } else { 0.0 }],
+				emission: [er, eg, eb, pdf],
+			});
+		}
+		// Rounding must not leave the last light out of the search
+		if let Some(last) = lights.last_mut() {
+			last.cdf = 1.0;
+		}
+		debug!("Path tracing {} triangles with {} emissive ones", bvh.triangle_count(), lights.len());
+
+		let (nodes, triangles) = bvh.upload(&self.device, builder)?;
+		self.nodes = nodes;
+		self.triangles = triangles;
+		self.materials = upload_array(&self.device, builder, &materials)?;
+		if !lights.is_empty() {
+			self.lights = upload_array(&self.device, builder, &lights)?;
+		}
+		self.node_count = bvh.nodes.len() as u32;
+		self.light_count = lights.len() as u32;
+		Ok(())
+	}
+
+	// Discards the samples, when something the tracer can't see changed
+	pub fn reset(&mut self) {
+		self.sample_count = 0;
+	}
+
+	pub fn sample_count(&self) -> u32 {
+		self.sample_count
+	}
+
+	pub fn is_complete(&self) -> bool {
+		self.sample_count >= self.target_samples
+	}
+
+	// Records one more sample per pixel, outside of any render pass, unless
+	// `target_samples` are already accumulated for this camera. Returns
+	// whether a sample was recorded.
+	pub fn record(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+	) -> Result<bool, VulkanoError> {
+		if self.last_camera != Some((view, projection)) {
+			self.last_camera = Some((view, projection));
+			self.reset();
+		}
+		if self.is_complete() {
+			return Ok(false);
+		}
+
+		let camera = self.camera_pool.next(path_trace_cs::ty::Camera {
+			view,
+			projection,
+			counts: [self.node_count, self.light_count, self.sample_count, 0],
+			depths: [self.camera_depth.min(MAX_PATH_DEPTH), self.light_depth.min(MAX_PATH_DEPTH), 0, 0],
+		})?;
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(self.nodes.clone())?
+				.add_buffer(self.triangles.clone())?
+				.add_buffer(self.materials.clone())?
+				.add_buffer(self.lights.clone())?
+				.add_buffer(camera)?
+				.add_image(ImageView::new(self.output.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "BidirectionalPathTracer", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1],
+			self.pipeline.clone(),
+			set,
+			(),
+			vec![],
+		)?;
+		debug_label!(builder);
+		self.sample_count += 1;
+		if self.is_complete() {
+			info!("Path traced the reference image with {} samples", self.sample_count);
+		}
+		Ok(true)
+	}
+
+	// Mean radiance of the samples so far
+	pub fn output(&self) -> Arc<StorageImage<Format>> {
+		self.output.clone()
+	}
+}
+
+fn triangle_area(vertices: &[ForwardVertex], triangle: &[u32]) -> f32 {
+	let [a, b, c] = [
+		vertices[triangle[0] as usize].position,
+		vertices[triangle[1] as usize].position,
+		vertices[triangle[2] as usize].position,
+	];
+	let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+	let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+	let cross = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+	0.5 * (cross[0] * cross[0] + cross[1] * cross[1] + cross[2] * cross[2]).sqrt()
+}
+
+fn output_image(device: &Arc<Device>, [width, height]: [u32; 2]) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width,
+			height,
+			array_layers: 1,
+		},
+		PATH_TRACER_FORMAT,
+		ImageUsage {
+			storage: true,
+			sampled: true,
+			transfer_source: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
@@ -0,0 +1,82 @@
+use rapier3d::dynamics::{ImpulseJointSet, MultibodyJointSet, RigidBodySet};
+use rapier3d::geometry::{ColliderSet, NarrowPhase};
+use rapier3d::math::{Point, Real};
+use rapier3d::pipeline::{DebugRenderBackend, DebugRenderObject, DebugRenderPipeline};
+
+use crate::debug_views::LineRenderer;
+
+// Rapier's debug colors are hue in degrees, saturation, lightness and alpha
+fn hsla_to_rgba([hue, saturation, lightness, alpha]: [f32; 4]) -> [f32; 4] {
+	let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+	let sector = hue.rem_euclid(360.0) / 60.0;
+	let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+	let (r, g, b) = match sector as u32 {
+		0 => (chroma, x, 0.0),
+		1 => (x, chroma, 0.0),
+		2 => (0.0, chroma, x),
+		3 => (0.0, x, chroma),
+		4 => (x, 0.0, chroma),
+		_ => (chroma, 0.0, x),
+	};
+	let m = lightness - chroma * 0.5;
+	[r + m, g + m, b + m, alpha]
+}
+
+// Forwards the segments Rapier generates for the collision shapes, joints and
+// contacts to the line renderer
+pub struct PhysicsDebugDraw<'a> {
+	lines: &'a mut LineRenderer,
+}
+
+impl<'a> PhysicsDebugDraw<'a> {
+	pub fn new(lines: &'a mut LineRenderer) -> PhysicsDebugDraw<'a> {
+		PhysicsDebugDraw { lines }
+	}
+}
+
+impl<'a> DebugRenderBackend for PhysicsDebugDraw<'a> {
+	fn draw_line(&mut self, _object: DebugRenderObject, a: Point<Real>, b: Point<Real>, color: [f32; 4]) {
+		self.lines.add_line([a.x, a.y, a.z], [b.x, b.y, b.z], hsla_to_rgba(color));
+	}
+}
+
+// The Rapier state the debug view needs, the simulation steps it elsewhere
+pub struct PhysicsWorld {
+	pub bodies: RigidBodySet,
+	pub colliders: ColliderSet,
+	pub impulse_joints: ImpulseJointSet,
+	pub multibody_joints: MultibodyJointSet,
+	pub narrow_phase: NarrowPhase,
+	debug_pipeline: DebugRenderPipeline,
+}
+
+impl Default for PhysicsWorld {
+	fn default() -> PhysicsWorld {
+		PhysicsWorld::new()
+	}
+}
+
+impl PhysicsWorld {
+	pub fn new() -> PhysicsWorld {
+		PhysicsWorld {
+			bodies: RigidBodySet::new(),
+			colliders: ColliderSet::new(),
+			impulse_joints: ImpulseJointSet::new(),
+			multibody_joints: MultibodyJointSet::new(),
+			narrow_phase: NarrowPhase::new(),
+			debug_pipeline: DebugRenderPipeline::default(),
+		}
+	}
+
+	// Adds the lines of this frame to `lines`, to be called before flushing it
+	pub fn debug_render(&mut self, lines: &mut LineRenderer) {
+		let mut backend = PhysicsDebugDraw::new(lines);
+		self.debug_pipeline.render(
+			&mut backend,
+			&self.bodies,
+			&self.colliders,
+			&self.impulse_joints,
+			&self.multibody_joints,
+		);
+	}
+}
@@ -0,0 +1,418 @@
+use std::sync::Arc;
+
+use crossbeam_channel::{Receiver, Sender};
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageUsage};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+
+use crate::debug_utils::LABEL_COLOR_RENDER_PASS;
+use crate::error::VulkanoError;
+use crate::forward_plus::ForwardVertex;
+use crate::pipeline::SharedPipeline;
+
+pub const PICKING_FORMAT: Format = Format::R32Uint;
+// The depth is copied to a color attachment, vulkano can't copy the depth
+// aspect of a depth buffer
+const PICKING_DEPTH_COPY_FORMAT: Format = Format::R32Sfloat;
+const PICKING_DEPTH_FORMAT: Format = Format::D32Sfloat;
+// Id of the background
+pub const NO_OBJECT: u32 = u32::MAX;
+
+mod picking_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view_projection;
+			} camera;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				uint object_id;
+			} pc;
+
+			void main() {
+				gl_Position = camera.view_projection * pc.model * vec4(position, 1.0);
+			}
+		"
+	}
+}
+
+mod picking_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) out uint f_id;
+			layout(location = 1) out float f_depth;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				uint object_id;
+			} pc;
+
+			void main() {
+				f_id = pc.object_id;
+				f_depth = gl_FragCoord.z;
+			}
+		"
+	}
+}
+
+// A mesh that can be picked, `object_id` being what `PickEvent` reports
+#[derive(Clone)]
+pub struct PickableMesh {
+	pub vertices: Arc<CpuAccessibleBuffer<[ForwardVertex]>>,
+	pub indices: Arc<CpuAccessibleBuffer<[u32]>>,
+	pub model: [[f32; 4]; 4],
+	pub object_id: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PickEvent {
+	// NO_OBJECT over the background
+	pub object_id: u32,
+	// Of the surface under the cursor, or of the point of the far plane under
+	// it over the background
+	pub world_pos: [f32; 3],
+}
+
+// Cursor and camera of a pick whose readback is in flight
+struct PendingPick {
+	cursor: [u32; 2],
+	inverse_view_projection: [[f32; 4]; 4],
+	// The frame of the pick may not be submitted yet when `poll` is first
+	// called, the buffers would still hold the previous pick
+	waited: bool,
+}
+
+// Finds the object under the cursor. `pick` draws the id of every object to an
+// offscreen R32Uint image, along with the depth, and copies the texel under
+// the cursor to the CPU. The result is read once the GPU is done with the
+// frame: `poll`, called once per frame after `cleanup_finished`, returns the
+// event and sends it to the `events` channel.
+pub struct PickingPass {
+	framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	ids: Arc<AttachmentImage<Format>>,
+	depths: Arc<AttachmentImage<Format>>,
+	pipeline: SharedPipeline,
+	camera_pool: CpuBufferPool<picking_vs::ty::Camera>,
+	id_readback: Arc<CpuAccessibleBuffer<[u32]>>,
+	depth_readback: Arc<CpuAccessibleBuffer<[f32]>>,
+	pending: Option<PendingPick>,
+	sender: Sender<PickEvent>,
+	receiver: Receiver<PickEvent>,
+	dynamic_state: DynamicState,
+	dimensions: [u32; 2],
+	device: Arc<Device>,
+}
+
+impl PickingPass {
+	// `dimensions` are those of the swapchain, the cursor positions being in
+	// physical pixels
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<PickingPass, VulkanoError> {
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::single_pass_renderpass!(
+				device.clone(),
+				attachments: {
+					ids: {
+						load: Clear,
+						store: Store,
+						format: PICKING_FORMAT,
+						samples: 1,
+					},
+					depths: {
+						load: Clear,
+						store: Store,
+						format: PICKING_DEPTH_COPY_FORMAT,
+						samples: 1,
+					},
+					depth: {
+						load: Clear,
+						store: DontCare,
+						format: PICKING_DEPTH_FORMAT,
+						samples: 1,
+					}
+				},
+				pass: {
+					color: [ids, depths],
+					depth_stencil: {depth}
+				}
+			)?,
+		);
+
+		let vs = picking_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = picking_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline: SharedPipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<ForwardVertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.depth_stencil_simple_depth()
+				.render_pass(Subpass::from(render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)?)
+				.build(device.clone())?,
+		);
+
+		let (framebuffer, ids, depths) = targets(&device, &render_pass, dimensions)?;
+		let (sender, receiver) = crossbeam_channel::unbounded();
+
+		Ok(PickingPass {
+			framebuffer,
+			render_pass,
+			ids,
+			depths,
+			pipeline,
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			id_readback: CpuAccessibleBuffer::from_iter(
+				device.clone(),
+				BufferUsage::transfer_destination(),
+				true,
+				[NO_OBJECT].iter().cloned(),
+			)?,
+			depth_readback: CpuAccessibleBuffer::from_iter(
+				device.clone(),
+				BufferUsage::transfer_destination(),
+				true,
+				[1.0f32].iter().cloned(),
+			)?,
+			pending: None,
+			sender,
+			receiver,
+			dynamic_state: dynamic_state(dimensions),
+			dimensions,
+			device,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		let (framebuffer, ids, depths) = targets(&self.device, &self.render_pass, dimensions)?;
+		self.framebuffer = framebuffer;
+		self.ids = ids;
+		self.depths = depths;
+		self.dynamic_state = dynamic_state(dimensions);
+		self.dimensions = dimensions;
+		Ok(())
+	}
+
+	// Receives every event `poll` returns
+	pub fn events(&self) -> Receiver<PickEvent> {
+		self.receiver.clone()
+	}
+
+	pub fn is_pending(&self) -> bool {
+		self.pending.is_some()
+	}
+
+	// Records the ids of `objects` and the copy of the texel under `cursor`,
+	// outside of any render pass. Does nothing and returns false while the
+	// previous pick is in flight or when the cursor is outside of the window.
+	pub fn pick(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		objects: &[PickableMesh],
+		view_projection: [[f32; 4]; 4],
+		cursor: [u32; 2],
+	) -> Result<bool, VulkanoError> {
+		if self.pending.is_some() || cursor[0] >= self.dimensions[0] || cursor[1] >= self.dimensions[1] {
+			return Ok(false);
+		}
+		// A degenerate camera can't tell where the cursor points
+		let inverse_view_projection = match inverse(view_projection) {
+			Some(inverse) => inverse,
+			None => return Ok(false),
+		};
+
+		debug_label!(builder, "PickingPass", LABEL_COLOR_RENDER_PASS);
+		builder.begin_render_pass(
+			self.framebuffer.clone(),
+			SubpassContents::Inline,
+			vec![[NO_OBJECT, 0, 0, 0].into(), [1.0, 0.0, 0.0, 0.0].into(), 1f32.into()],
+		)?;
+		if !objects.is_empty() {
+			let camera = self.camera_pool.next(picking_vs::ty::Camera { view_projection })?;
+			let layout = self.pipeline.descriptor_set_layout(0).ok_or(VulkanoError::NoDescriptorSetLayout)?;
+			let set = Arc::new(PersistentDescriptorSet::start(layout.clone()).add_buffer(camera)?.build()?);
+			for object in objects {
+				builder.draw_indexed(
+					self.pipeline.clone(),
+					&self.dynamic_state,
+					vec![object.vertices.clone() as Arc<dyn BufferAccess + Send + Sync>],
+					object.indices.clone(),
+					set.clone(),
+					picking_vs::ty::PushConstants {
+						model: object.model,
+						object_id: object.object_id,
+					},
+					vec![],
+				)?;
+			}
+		}
+		builder.end_render_pass()?;
+		debug_label!(builder);
+
+		let [x, y] = cursor;
+		builder.copy_image_to_buffer_dimensions(self.ids.clone(), self.id_readback.clone(), [x, y, 0], [1, 1, 1], 0, 1, 0)?;
+		builder.copy_image_to_buffer_dimensions(
+			self.depths.clone(),
+			self.depth_readback.clone(),
+			[x, y, 0],
+			[1, 1, 1],
+			0,
+			1,
+			0,
+		)?;
+
+		self.pending = Some(PendingPick {
+			cursor,
+			inverse_view_projection,
+			waited: false,
+		});
+		Ok(true)
+	}
+
+	// The result of the pick in flight, once the GPU has written it, at the
+	// earliest the frame after the pick. The readback buffers stay locked
+	// until the frame's fence is signaled.
+	pub fn poll(&mut self) -> Option<PickEvent> {
+		let pending = self.pending.as_mut()?;
+		if !pending.waited {
+			pending.waited = true;
+			return None;
+		}
+		let object_id = match self.id_readback.read() {
+			Ok(id) => id[0],
+			Err(_) => return None,
+		};
+		let depth = match self.depth_readback.read() {
+			Ok(depth) => depth[0],
+			Err(_) => return None,
+		};
+
+		let [width, height] = self.dimensions;
+		let ndc = [
+			(pending.cursor[0] as f32 + 0.5) / width as f32 * 2.0 - 1.0,
+			(pending.cursor[1] as f32 + 0.5) / height as f32 * 2.0 - 1.0,
+			depth,
+			1.0,
+		];
+		let m = pending.inverse_view_projection;
+		let world: Vec<f32> = (0..4)
+			.map(|row| (0..4).map(|column| m[column][row] * ndc[column]).sum())
+			.collect();
+		let event = PickEvent {
+			object_id,
+			world_pos: [world[0] / world[3], world[1] / world[3], world[2] / world[3]],
+		};
+
+		self.pending = None;
+		// Nobody listening isn't an error, the event is returned anyway
+		let _ = self.sender.send(event);
+		Some(event)
+	}
+}
+
+#[allow(clippy::type_complexity)]
+fn targets(
+	device: &Arc<Device>,
+	render_pass: &Arc<dyn RenderPassAbstract + Send + Sync>,
+	dimensions: [u32; 2],
+) -> Result<
+	(
+		Arc<dyn FramebufferAbstract + Send + Sync>,
+		Arc<AttachmentImage<Format>>,
+		Arc<AttachmentImage<Format>>,
+	),
+	VulkanoError,
+> {
+	let usage = ImageUsage {
+		color_attachment: true,
+		transfer_source: true,
+		..ImageUsage::none()
+	};
+	let ids = AttachmentImage::with_usage(device.clone(), dimensions, PICKING_FORMAT, usage)?;
+	let depths = AttachmentImage::with_usage(device.clone(), dimensions, PICKING_DEPTH_COPY_FORMAT, usage)?;
+	let depth = AttachmentImage::transient(device.clone(), dimensions, PICKING_DEPTH_FORMAT)?;
+	let framebuffer = Arc::new(
+		Framebuffer::start(render_pass.clone())
+			.add(ImageView::new(ids.clone())?)?
+			.add(ImageView::new(depths.clone())?)?
+			.add(ImageView::new(depth)?)?
+			.build()?,
+	);
+	Ok((framebuffer, ids, depths))
+}
+
+fn dynamic_state(dimensions: [u32; 2]) -> DynamicState {
+	DynamicState {
+		viewports: Some(vec![Viewport {
+			origin: [0.0, 0.0],
+			dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+			depth_range: 0.0..1.0,
+		}]),
+		..DynamicState::none()
+	}
+}
+
+// Gauss-Jordan elimination with partial pivoting, None for singular matrices
+fn inverse(m: [[f32; 4]; 4]) -> Option<[[f32; 4]; 4]> {
+	let mut a = m;
+	let mut inverse = [[0.0; 4]; 4];
+	for (i, column) in inverse.iter_mut().enumerate() {
+		column[i] = 1.0;
+	}
+	// Columns of `a` are eliminated like rows, which inverts the transpose
+	// and transposes it back
+	for i in 0..4 {
+		let pivot = (i..4).max_by(|&x, &y| {
+			a[x][i].abs().partial_cmp(&a[y][i].abs()).unwrap_or(std::cmp::Ordering::Equal)
+		})?;
+		if a[pivot][i].abs() < 1e-12 {
+			return None;
+		}
+		a.swap(i, pivot);
+		inverse.swap(i, pivot);
+		let scale = 1.0 / a[i][i];
+		let (row, inverse_row) = (scaled(a[i], scale), scaled(inverse[i], scale));
+		for j in 0..4 {
+			let factor = if j == i { 1.0 } else { a[j][i] };
+			a[j] = subtract_scaled(a[j], row, factor, j == i);
+			inverse[j] = subtract_scaled(inverse[j], inverse_row, factor, j == i);
+		}
+	}
+	Some(inverse)
+}
+
+fn scaled(row: [f32; 4], scale: f32) -> [f32; 4] {
+	[row[0] * scale, row[1] * scale, row[2] * scale, row[3] * scale]
+}
+
+// `row` itself once normalized as the pivot row, `row - factor * pivot` otherwise
+fn subtract_scaled(row: [f32; 4], pivot: [f32; 4], factor: f32, is_pivot: bool) -> [f32; 4] {
+	if is_pivot {
+		return pivot;
+	}
+	[
+		row[0] - factor * pivot[0],
+		row[1] - factor * pivot[1],
+		row[2] - factor * pivot[2],
+		row[3] - factor * pivot[3],
+	]
+}
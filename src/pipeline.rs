@@ -0,0 +1,542 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::*;
+
+use arc_swap::{ArcSwap, Guard};
+
+use crate::debug_utils::RenderPassCompatibilityCheck;
+use crate::error::VulkanoError;
+use crate::shaders::{compile_glsl, compile_glsl_with_defines, ShaderKind};
+use crate::sync::is_signaled;
+
+use vulkano::descriptor::descriptor::{DescriptorDesc, ShaderStages};
+use vulkano::descriptor::pipeline_layout::{
+	PipelineLayout,
+	PipelineLayoutDesc,
+	PipelineLayoutDescPcRange,
+	RuntimePipelineDesc,
+};
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::input_assembly::PrimitiveTopology;
+use vulkano::pipeline::shader::ShaderModule;
+use vulkano::pipeline::vertex::BufferlessDefinition;
+use vulkano::pipeline::{ComputePipelineAbstract, GraphicsPipeline, GraphicsPipelineAbstract, GraphicsPipelineBuilder};
+use vulkano::sync::Fence;
+
+// #[derive(RenderPipeline)] generates `build` and `descriptor` from the shader
+// paths and the fixed function state, see vulkano_start_macros
+pub use vulkano_start_macros::RenderPipeline;
+
+pub type SharedPipeline = Arc<dyn GraphicsPipelineAbstract + Send + Sync>;
+
+pub type SharedComputePipeline = Arc<dyn ComputePipelineAbstract + Send + Sync>;
+
+// Pipeline without vertex input, drawn with `BufferlessVertices` (fullscreen passes)
+pub type BufferlessPipeline = GraphicsPipeline<
+	BufferlessDefinition,
+	Box<dyn PipelineLayoutAbstract + Send + Sync>,
+	Arc<dyn RenderPassAbstract + Send + Sync>,
+>;
+
+// Holds the pipeline used by the render loop and lets another thread (shader
+// hot reload) replace it. A new pipeline only becomes current at the start of
+// the next frame, and the previous one is kept alive until the fence of the
+// last frame that may have recorded it has signaled. Every pipeline is checked
+// against the render pass of the framebuffers it's drawn into when it's handed
+// over.
+pub struct PipelineHotSwap {
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	current: ArcSwap<SharedPipeline>,
+	pending: Mutex<Option<SharedPipeline>>,
+	// Fence of the last frame submitted before the swap, none if no frame was
+	#[allow(clippy::type_complexity)]
+	retired: Mutex<VecDeque<(Option<Arc<Fence>>, Arc<SharedPipeline>)>>,
+	last_fence: Mutex<Option<Arc<Fence>>>,
+	frame: AtomicU64,
+}
+
+impl PipelineHotSwap {
+	pub fn new(pipeline: SharedPipeline, render_pass: Arc<dyn RenderPassAbstract + Send + Sync>) -> PipelineHotSwap {
+		RenderPassCompatibilityCheck::assert_compatible(&*pipeline, &*render_pass);
+		PipelineHotSwap {
+			render_pass,
+			current: ArcSwap::from_pointee(pipeline),
+			pending: Mutex::new(None),
+			retired: Mutex::new(VecDeque::new()),
+			last_fence: Mutex::new(None),
+			frame: AtomicU64::new(0),
+		}
+	}
+
+	// The pipeline to record the current frame with
+	pub fn get(&self) -> Guard<Arc<SharedPipeline>> {
+		self.current.load()
+	}
+
+	// Schedules `pipeline` to replace the current one at the next frame boundary
+	pub fn swap(&self, pipeline: SharedPipeline) {
+		RenderPassCompatibilityCheck::assert_compatible(&*pipeline, &*self.render_pass);
+		*self.pending.lock().unwrap() = Some(pipeline);
+	}
+
+	// Call at the start of each frame, before `get`
+	pub fn begin_frame(&self) {
+		let frame = self.frame.fetch_add(1, Ordering::SeqCst) + 1;
+
+		// Frames complete in order, so do the pipelines they recorded
+		let mut retired = self.retired.lock().unwrap();
+		while let Some((fence, _)) = retired.front() {
+			match fence.as_ref().map(|fence| is_signaled(fence)) {
+				None | Some(Ok(true)) => {}
+				// On a lost device the pipeline may still be in use, it's leaked
+				Some(Ok(false)) | Some(Err(_)) => break,
+			}
+			retired.pop_front();
+		}
+
+		if let Some(pipeline) = self.pending.lock().unwrap().take() {
+			let previous = self.current.swap(Arc::new(pipeline));
+			let fence = self.last_fence.lock().unwrap().clone();
+			retired.push_back((fence, previous));
+			debug!("Swapped graphics pipeline on frame {}", frame);
+		}
+	}
+
+	// Call once the frame is submitted with the fence signaled after it
+	pub fn end_frame(&self, fence: Arc<Fence>) {
+		*self.last_fence.lock().unwrap() = Some(fence);
+	}
+}
+
+pub type SharedPipelineLayout = Arc<dyn PipelineLayoutAbstract + Send + Sync>;
+
+// Descriptors of every set followed by the push constant ranges, two layouts
+// with the same key are compatible
+#[derive(PartialEq)]
+struct LayoutKey {
+	sets: Vec<Vec<Option<DescriptorDesc>>>,
+	push_constants: Vec<(usize, usize, ShaderStages)>,
+}
+
+impl LayoutKey {
+	fn new<D: PipelineLayoutDesc + ?Sized>(desc: &D) -> LayoutKey {
+		let sets = (0..desc.num_sets())
+			.map(|set| {
+				let bindings = desc.num_bindings_in_set(set).unwrap_or(0);
+				(0..bindings).map(|binding| desc.descriptor(set, binding)).collect()
+			})
+			.collect();
+		let push_constants = (0..desc.num_push_constants_ranges())
+			.filter_map(|i| desc.push_constants_range(i))
+			.map(|range| (range.offset, range.size, range.stages))
+			.collect();
+		LayoutKey { sets, push_constants }
+	}
+
+	// What Vulkan creates the layout from: the type, count and stages of every
+	// binding and the push constant ranges. The image and buffer details of the
+	// vulkano descriptions only matter to the equality check.
+	fn hash(&self) -> u64 {
+		let mut hasher = DefaultHasher::new();
+		for bindings in &self.sets {
+			bindings.len().hash(&mut hasher);
+			for descriptor in bindings {
+				match descriptor {
+					Some(descriptor) => {
+						(descriptor.ty.ty() as u32).hash(&mut hasher);
+						descriptor.array_count.hash(&mut hasher);
+						stage_bits(descriptor.stages).hash(&mut hasher);
+					}
+					None => u32::MAX.hash(&mut hasher),
+				}
+			}
+		}
+		for &(offset, size, stages) in &self.push_constants {
+			(offset, size, stage_bits(stages)).hash(&mut hasher);
+		}
+		hasher.finish()
+	}
+
+	fn to_desc(&self) -> Result<RuntimePipelineDesc, VulkanoError> {
+		let push_constants = self
+			.push_constants
+			.iter()
+			.map(|&(offset, size, stages)| PipelineLayoutDescPcRange { offset, size, stages });
+		Ok(RuntimePipelineDesc::new(self.sets.clone(), push_constants)?)
+	}
+}
+
+fn stage_bits(stages: ShaderStages) -> u8 {
+	[
+		stages.vertex,
+		stages.tessellation_control,
+		stages.tessellation_evaluation,
+		stages.geometry,
+		stages.fragment,
+		stages.compute,
+	]
+	.iter()
+	.enumerate()
+	.fold(0, |bits, (i, &stage)| bits | (stage as u8) << i)
+}
+
+// Shares one pipeline layout between the pipelines whose shaders declare the
+// same descriptor sets and push constants, e.g. the opaque, alpha tested and
+// transparent variants of a material. The layout is passed to the builder with
+// `with_pipeline_layout`, descriptor sets created from it can be bound with
+// every pipeline sharing it.
+pub struct PipelineLayoutCache {
+	device: Arc<Device>,
+	layouts: HashMap<u64, Vec<(LayoutKey, SharedPipelineLayout)>>,
+}
+
+impl PipelineLayoutCache {
+	pub fn new(device: Arc<Device>) -> PipelineLayoutCache {
+		PipelineLayoutCache {
+			device,
+			layouts: HashMap::new(),
+		}
+	}
+
+	// `desc` is usually the union of the layouts of the pipeline's entry points
+	pub fn get_or_create<D>(&mut self, desc: &D) -> Result<SharedPipelineLayout, VulkanoError>
+	where
+		D: PipelineLayoutDesc + ?Sized,
+	{
+		let key = LayoutKey::new(desc);
+		let bucket = self.layouts.entry(key.hash()).or_default();
+		if let Some((_, layout)) = bucket.iter().find(|(k, _)| *k == key) {
+			return Ok(layout.clone());
+		}
+
+		let layout: SharedPipelineLayout = Arc::new(PipelineLayout::new(self.device.clone(), key.to_desc()?)?);
+		debug!("Created pipeline layout with {} descriptor sets", key.sets.len());
+		bucket.push((key, layout.clone()));
+		Ok(layout)
+	}
+}
+
+// Every permutation of an uber shader's features. The features are `#define`d
+// by name when enabled in a variant, the variants are built upfront for every
+// subset of the features enabled at creation and looked up by bitmask, bit i
+// standing for the i-th feature. Features disabled at creation are never
+// defined and keep their bit, so masks don't depend on the enabled features.
+pub struct PipelineVariantCache {
+	features: Vec<String>,
+	variants: HashMap<u64, SharedPipeline>,
+}
+
+impl PipelineVariantCache {
+	// `build` creates the pipeline of a variant from the compiled shader and
+	// the variant's mask, runtime shader modules need the caller's interface
+	// definitions to become entry points
+	pub fn new<F>(
+		base_shader: &Path,
+		kind: ShaderKind,
+		features: &[(&str, bool)],
+		mut build: F,
+	) -> Result<PipelineVariantCache, VulkanoError>
+	where
+		F: FnMut(&[u32], u64) -> Result<SharedPipeline, VulkanoError>,
+	{
+		assert!(features.len() <= 64, "A variant mask holds at most 64 features");
+		let source = std::fs::read_to_string(base_shader)?;
+		let name = base_shader.to_string_lossy();
+
+		let enabled = features
+			.iter()
+			.enumerate()
+			.filter(|(_, (_, enabled))| *enabled)
+			.fold(0u64, |mask, (i, _)| mask | 1 << i);
+
+		// Walks the subsets of `enabled`, from all of it down to the empty one
+		let mut variants = HashMap::new();
+		let mut mask = enabled;
+		loop {
+			let defines: Vec<&str> = features
+				.iter()
+				.enumerate()
+				.filter(|(i, _)| mask & 1 << i != 0)
+				.map(|(_, (feature, _))| *feature)
+				.collect();
+			let spirv = compile_glsl_with_defines(&source, kind, &name, &defines)?;
+			variants.insert(mask, build(&spirv, mask)?);
+			debug!("Built the {} variant [{}]", name, defines.join(", "));
+
+			if mask == 0 {
+				break;
+			}
+			mask = (mask - 1) & enabled;
+		}
+
+		Ok(PipelineVariantCache {
+			features: features.iter().map(|(feature, _)| feature.to_string()).collect(),
+			variants,
+		})
+	}
+
+	// Unknown features are ignored
+	pub fn mask(&self, active: &[&str]) -> u64 {
+		self.features
+			.iter()
+			.enumerate()
+			.filter(|(_, feature)| active.contains(&feature.as_str()))
+			.fold(0, |mask, (i, _)| mask | 1 << i)
+	}
+
+	// None if the mask has features that were disabled at creation
+	pub fn get(&self, mask: u64) -> Option<SharedPipeline> {
+		self.variants.get(&mask).cloned()
+	}
+
+	pub fn get_for(&self, active: &[&str]) -> Option<SharedPipeline> {
+		self.get(self.mask(active))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PipelineTopology {
+	PointList,
+	LineList,
+	LineStrip,
+	TriangleList,
+	TriangleStrip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PipelineCullMode {
+	None,
+	Front,
+	Back,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PipelineBlend {
+	Opaque,
+	Alpha,
+}
+
+// The parameters of a graphics pipeline other than its vertex input and render
+// pass, kept so that the pipeline can be rebuilt identically with one of its
+// shader stages replaced
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PipelineDescriptor {
+	pub vertex_shader: PathBuf,
+	pub fragment_shader: PathBuf,
+	pub topology: PipelineTopology,
+	pub cull_mode: PipelineCullMode,
+	pub wireframe: bool,
+	pub blend: PipelineBlend,
+	pub depth_test: bool,
+	// Multisampled transparency without sorting: the fragment alpha becomes
+	// the sample coverage, and alpha to one (which needs the alpha_to_one
+	// feature) then writes an alpha of 1
+	pub alpha_to_coverage: bool,
+	pub alpha_to_one: bool,
+}
+
+impl PipelineDescriptor {
+	// Sets the fixed function state of `builder`, the shaders, vertex input and
+	// render pass are left to the caller
+	#[allow(clippy::type_complexity)]
+	pub fn apply<Vdef, Vs, Vss, Tcs, Tcss, Tes, Tess, Gs, Gss, Fs, Fss, Rp>(
+		&self,
+		builder: GraphicsPipelineBuilder<Vdef, Vs, Vss, Tcs, Tcss, Tes, Tess, Gs, Gss, Fs, Fss, Rp>,
+	) -> GraphicsPipelineBuilder<Vdef, Vs, Vss, Tcs, Tcss, Tes, Tess, Gs, Gss, Fs, Fss, Rp> {
+		let topology = match self.topology {
+			PipelineTopology::PointList => PrimitiveTopology::PointList,
+			PipelineTopology::LineList => PrimitiveTopology::LineList,
+			PipelineTopology::LineStrip => PrimitiveTopology::LineStrip,
+			PipelineTopology::TriangleList => PrimitiveTopology::TriangleList,
+			PipelineTopology::TriangleStrip => PrimitiveTopology::TriangleStrip,
+		};
+		let mut builder = builder.primitive_topology(topology).viewports_dynamic_scissors_irrelevant(1);
+		builder = match self.cull_mode {
+			PipelineCullMode::None => builder.cull_mode_disabled(),
+			PipelineCullMode::Front => builder.cull_mode_front(),
+			PipelineCullMode::Back => builder.cull_mode_back(),
+		};
+		if self.wireframe {
+			builder = builder.polygon_mode_line();
+		}
+		builder = match self.blend {
+			PipelineBlend::Opaque => builder.blend_pass_through(),
+			PipelineBlend::Alpha => builder.blend_collective(AttachmentBlend::alpha_blending()),
+		};
+		if self.depth_test {
+			builder = builder.depth_stencil_simple_depth();
+		}
+		builder = if self.alpha_to_coverage {
+			builder.alpha_to_coverage_enabled()
+		} else {
+			builder.alpha_to_coverage_disabled()
+		};
+		builder = if self.alpha_to_one {
+			builder.alpha_to_one_enabled()
+		} else {
+			builder.alpha_to_one_disabled()
+		};
+		builder
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderStage {
+	Vertex,
+	Fragment,
+}
+
+struct PatchedStage {
+	hash: u64,
+	module: Arc<ShaderModule>,
+}
+
+fn hash_file(path: &Path) -> Result<(u64, String), VulkanoError> {
+	let source = std::fs::read_to_string(path)?;
+	let mut hasher = DefaultHasher::new();
+	source.hash(&mut hasher);
+	Ok((hasher.finish(), source))
+}
+
+// Rebuilds the pipeline of a `PipelineDescriptor` when its shader files
+// change, recompiling only the stages whose file hash changed and reusing the
+// module of the others. `build` turns the modules into entry points, which
+// needs the caller's interface definitions, and applies the descriptor to
+// `GraphicsPipeline::start()`. The new pipeline goes through a
+// `PipelineHotSwap`, so the render loop picks it up at the next frame.
+pub struct ShaderHotPatch<F> {
+	device: Arc<Device>,
+	descriptor: PipelineDescriptor,
+	vertex: PatchedStage,
+	fragment: PatchedStage,
+	build: F,
+}
+
+impl<F> ShaderHotPatch<F>
+where
+	F: FnMut(&PipelineDescriptor, &Arc<ShaderModule>, &Arc<ShaderModule>) -> Result<SharedPipeline, VulkanoError>,
+{
+	// Also builds the initial pipeline
+	pub fn new(
+		device: Arc<Device>,
+		descriptor: PipelineDescriptor,
+		mut build: F,
+	) -> Result<(ShaderHotPatch<F>, SharedPipeline), VulkanoError> {
+		let vertex = Self::compile(&device, &descriptor.vertex_shader, ShaderKind::Vertex)?;
+		let fragment = Self::compile(&device, &descriptor.fragment_shader, ShaderKind::Fragment)?;
+		let pipeline = build(&descriptor, &vertex.module, &fragment.module)?;
+		let patch = ShaderHotPatch {
+			device,
+			descriptor,
+			vertex,
+			fragment,
+			build,
+		};
+		Ok((patch, pipeline))
+	}
+
+	fn compile(device: &Arc<Device>, path: &Path, kind: ShaderKind) -> Result<PatchedStage, VulkanoError> {
+		let (hash, source) = hash_file(path)?;
+		let spirv = compile_glsl(&source, kind, &path.to_string_lossy())?;
+		// Safe as long as the SPIR-V is valid, which shaderc guarantees
+		let module = unsafe { ShaderModule::from_words(device.clone(), &spirv) }.map_err(VulkanoError::ShaderLoad)?;
+		Ok(PatchedStage { hash, module })
+	}
+
+	pub fn descriptor(&self) -> &PipelineDescriptor {
+		&self.descriptor
+	}
+
+	// Recompiles the stages whose file changed and submits the rebuilt pipeline
+	// to `swap`. Returns the replaced stages, empty if nothing changed.
+	pub fn patch(&mut self, swap: &PipelineHotSwap) -> Result<Vec<ShaderStage>, VulkanoError> {
+		let mut changed = Vec::new();
+		let stages = [
+			(ShaderStage::Vertex, ShaderKind::Vertex, &self.descriptor.vertex_shader),
+			(ShaderStage::Fragment, ShaderKind::Fragment, &self.descriptor.fragment_shader),
+		];
+		for (stage, kind, path) in stages.iter() {
+			let current = match stage {
+				ShaderStage::Vertex => &self.vertex,
+				ShaderStage::Fragment => &self.fragment,
+			};
+			if hash_file(path)?.0 != current.hash {
+				let patched = Self::compile(&self.device, path, *kind)?;
+				match stage {
+					ShaderStage::Vertex => self.vertex = patched,
+					ShaderStage::Fragment => self.fragment = patched,
+				}
+				changed.push(*stage);
+			}
+		}
+		if changed.is_empty() {
+			return Ok(changed);
+		}
+
+		let pipeline = (self.build)(&self.descriptor, &self.vertex.module, &self.fragment.module)?;
+		swap.swap(pipeline);
+		debug!("Hot patched the {:?} stage(s) of {}", changed, self.descriptor.fragment_shader.display());
+		Ok(changed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use vulkano::descriptor::descriptor::{DescriptorBufferDesc, DescriptorDescTy};
+
+	fn buffer(storage: bool) -> Option<DescriptorDesc> {
+		Some(DescriptorDesc {
+			ty: DescriptorDescTy::Buffer(DescriptorBufferDesc {
+				dynamic: Some(false),
+				storage,
+			}),
+			array_count: 1,
+			stages: ShaderStages {
+				compute: true,
+				..ShaderStages::none()
+			},
+			readonly: !storage,
+		})
+	}
+
+	fn key(sets: Vec<Vec<Option<DescriptorDesc>>>) -> LayoutKey {
+		LayoutKey::new(&RuntimePipelineDesc::new(sets, None).unwrap())
+	}
+
+	#[test]
+	fn identical_layouts_share_a_key() {
+		let a = key(vec![vec![buffer(false), buffer(true)]]);
+		let b = key(vec![vec![buffer(false), buffer(true)]]);
+		assert!(a == b);
+		assert_eq!(a.hash(), b.hash());
+	}
+
+	#[test]
+	fn different_bindings_hash_differently() {
+		let a = key(vec![vec![buffer(false), buffer(true)]]);
+		let swapped = key(vec![vec![buffer(true), buffer(false)]]);
+		let split = key(vec![vec![buffer(false)], vec![buffer(true)]]);
+		assert_ne!(a.hash(), swapped.hash());
+		assert_ne!(a.hash(), split.hash());
+	}
+
+	#[test]
+	fn key_round_trips_through_the_runtime_desc() {
+		let a = key(vec![vec![buffer(false), None, buffer(true)]]);
+		assert!(LayoutKey::new(&a.to_desc().unwrap()) == a);
+	}
+}
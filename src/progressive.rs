@@ -0,0 +1,374 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::*;
+
+use image::hdr::HDREncoder;
+use image::Rgb;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::glsl_shaders::fullscreen_vs;
+use crate::pipeline::{BufferlessPipeline, SharedComputePipeline};
+
+const ACCUMULATION_FORMAT: Format = Format::R32G32B32A32Sfloat;
+// The variance estimate is meaningless before this
+const MIN_CONVERGENCE_SAMPLES: u32 = 16;
+
+mod accumulate_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0) uniform sampler2D sample_image;
+			// Sum of the samples
+			layout(set = 0, binding = 1, rgba32f) uniform image2D accumulation;
+			// Sum of the luminances and of their squares
+			layout(set = 0, binding = 2, rgba32f) uniform image2D moments;
+			layout(set = 0, binding = 3) buffer Convergence {
+				uint unconverged_pixels;
+			} convergence;
+
+			layout(push_constant) uniform PushConstants {
+				// Discards the previous samples, when the scene or camera changed
+				uint reset;
+				// Including this one
+				uint sample_count;
+				float convergence_threshold;
+			} pc;
+
+			void main() {
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (any(greaterThanEqual(pixel, imageSize(accumulation)))) {
+					return;
+				}
+
+				vec3 color = texelFetch(sample_image, pixel, 0).rgb;
+				float luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+				vec4 sum = pc.reset != 0 ? vec4(0.0) : imageLoad(accumulation, pixel);
+				vec4 moment = pc.reset != 0 ? vec4(0.0) : imageLoad(moments, pixel);
+				sum += vec4(color, 1.0);
+				moment += vec4(luminance, luminance * luminance, 0.0, 0.0);
+				imageStore(accumulation, pixel, sum);
+				imageStore(moments, pixel, moment);
+
+				// Variance of the mean of the luminance
+				float n = float(pc.sample_count);
+				float mean = moment.x / n;
+				float variance = max(moment.y / n - mean * mean, 0.0) / n;
+				if (variance > pc.convergence_threshold) {
+					atomicAdd(convergence.unconverged_pixels, 1);
+				}
+			}
+		"
+	}
+}
+
+mod progressive_composite_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 0, rgba32f) uniform readonly image2D accumulation;
+
+			layout(push_constant) uniform PushConstants {
+				uint frame_count;
+			} pc;
+
+			void main() {
+				vec3 sum = imageLoad(accumulation, ivec2(gl_FragCoord.xy)).rgb;
+				f_color = vec4(sum / float(max(pc.frame_count, 1)), 1.0);
+			}
+		"
+	}
+}
+
+fn accumulation_image(device: &Arc<Device>, [width, height]: [u32; 2]) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width,
+			height,
+			array_layers: 1,
+		},
+		ACCUMULATION_FORMAT,
+		ImageUsage {
+			storage: true,
+			transfer_source: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+// Element `index` of the Halton sequence of `base`, in [0, 1)
+fn halton(mut index: u32, base: u32) -> f32 {
+	let mut result = 0.0;
+	let mut fraction = 1.0;
+	while index > 0 {
+		fraction /= base as f32;
+		result += fraction * (index % base) as f32;
+		index /= base;
+	}
+	result
+}
+
+// Progressive refinement of a still image, path tracer style. Every frame the
+// caller renders a sample of the scene with the projection offset by `jitter`,
+// and `accumulate` adds it to a float accumulation image with a compute pass,
+// which also tracks the variance of every pixel. The composite divides the sum
+// by the frame count. The accumulation restarts after `mark_dirty`, and stops
+// once `target_samples` are accumulated or every pixel's variance went below
+// `convergence_threshold`, the result then being exported to `export_path` as
+// a Radiance HDR file.
+pub struct ProgressiveRenderer {
+	accumulate_pipeline: SharedComputePipeline,
+	composite_pipeline: Arc<BufferlessPipeline>,
+	accumulation: Arc<StorageImage<Format>>,
+	moments: Arc<StorageImage<Format>>,
+	convergence: Arc<CpuAccessibleBuffer<[u32; 1]>>,
+	export_buffer: Arc<CpuAccessibleBuffer<[[f32; 4]]>>,
+	sampler: Arc<Sampler>,
+	frame_count: u32,
+	dirty: bool,
+	converged: bool,
+	exported: bool,
+	export_pending: bool,
+	dimensions: [u32; 2],
+	pub target_samples: u32,
+	pub convergence_threshold: f32,
+	pub export_path: Option<PathBuf>,
+}
+
+impl ProgressiveRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		dimensions: [u32; 2],
+		target_samples: u32,
+		convergence_threshold: f32,
+	) -> Result<ProgressiveRenderer, VulkanoError> {
+		let shader = accumulate_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let accumulate_pipeline: SharedComputePipeline =
+			Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?);
+
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = progressive_composite_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let composite_pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input(BufferlessDefinition {})
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+
+		let convergence_usage = BufferUsage {
+			storage_buffer: true,
+			transfer_destination: true,
+			..BufferUsage::none()
+		};
+		let convergence = CpuAccessibleBuffer::from_data(device.clone(), convergence_usage, false, [0u32; 1])?;
+		let export_buffer = CpuAccessibleBuffer::from_iter(
+			device.clone(),
+			BufferUsage::transfer_destination(),
+			true,
+			(0..dimensions[0] as usize * dimensions[1] as usize).map(|_| [0.0f32; 4]),
+		)?;
+
+		let sampler = Sampler::new(
+			device.clone(),
+			Filter::Nearest,
+			Filter::Nearest,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+
+		Ok(ProgressiveRenderer {
+			accumulate_pipeline,
+			composite_pipeline,
+			accumulation: accumulation_image(&device, dimensions)?,
+			moments: accumulation_image(&device, dimensions)?,
+			convergence,
+			export_buffer,
+			sampler,
+			frame_count: 0,
+			dirty: true,
+			converged: false,
+			exported: false,
+			export_pending: false,
+			dimensions,
+			target_samples,
+			convergence_threshold,
+			export_path: None,
+		})
+	}
+
+	// The camera or the scene changed, the samples accumulated so far are discarded
+	pub fn mark_dirty(&mut self) {
+		self.dirty = true;
+		self.frame_count = 0;
+		self.converged = false;
+		self.exported = false;
+		self.export_pending = false;
+	}
+
+	pub fn frame_count(&self) -> u32 {
+		self.frame_count
+	}
+
+	pub fn is_converged(&self) -> bool {
+		self.converged || self.frame_count >= self.target_samples
+	}
+
+	// Subpixel offset of the next sample in [-0.5, 0.5) pixels, to add to the
+	// projection so the samples cover the whole pixel
+	pub fn jitter(&self) -> [f32; 2] {
+		let index = self.frame_count + 1;
+		[halton(index, 2) - 0.5, halton(index, 3) - 0.5]
+	}
+
+	// Call once per frame before `accumulate`, once the previous frame's GPU
+	// work is done: checks the convergence of the last accumulation and writes
+	// the export when it's ready
+	pub fn poll(&mut self) -> Result<(), VulkanoError> {
+		if !self.converged && self.frame_count >= MIN_CONVERGENCE_SAMPLES {
+			if let Ok(content) = self.convergence.read() {
+				if content[0] == 0 {
+					info!("Progressive rendering converged after {} samples", self.frame_count);
+					self.converged = true;
+				}
+			}
+		}
+
+		if self.export_pending {
+			let path = match &self.export_path {
+				Some(path) => path.clone(),
+				None => {
+					self.export_pending = false;
+					return Ok(());
+				}
+			};
+			let pixels = match self.export_buffer.read() {
+				Ok(content) => {
+					let samples = self.frame_count.max(1) as f32;
+					content
+						.iter()
+						.map(|texel| Rgb([texel[0] / samples, texel[1] / samples, texel[2] / samples]))
+						.collect::<Vec<_>>()
+				}
+				// Still being copied
+				Err(_) => return Ok(()),
+			};
+			self.export_pending = false;
+			let [width, height] = self.dimensions;
+			HDREncoder::new(BufWriter::new(File::create(&path)?)).encode(&pixels, width as usize, height as usize)?;
+			info!("Exported the {} samples render to {}", self.frame_count, path.display());
+		}
+		Ok(())
+	}
+
+	// Adds `sample`, the render of the jittered frame, to the accumulation,
+	// outside of any render pass. Returns false once converged, the first call
+	// after that recording the copy of the export.
+	pub fn accumulate(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		sample: Arc<dyn ImageViewAbstract + Send + Sync>,
+	) -> Result<bool, VulkanoError> {
+		if self.is_converged() {
+			if !self.exported && self.export_path.is_some() {
+				builder.copy_image_to_buffer(self.accumulation.clone(), self.export_buffer.clone())?;
+				self.exported = true;
+				self.export_pending = true;
+			}
+			return Ok(false);
+		}
+
+		builder.update_buffer(self.convergence.clone(), Box::new([0u32; 1]))?;
+		let layout = self
+			.accumulate_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(sample, self.sampler.clone())?
+				.add_image(ImageView::new(self.accumulation.clone())?)?
+				.add_image(ImageView::new(self.moments.clone())?)?
+				.add_buffer(self.convergence.clone())?
+				.build()?,
+		);
+		let push_constants = accumulate_cs::ty::PushConstants {
+			reset: self.dirty as u32,
+			sample_count: self.frame_count + 1,
+			convergence_threshold: self.convergence_threshold,
+		};
+		let [width, height] = self.dimensions;
+		debug_label!(builder, "ProgressiveRenderer accumulate", LABEL_COLOR_COMPUTE);
+		builder.dispatch([width.div_ceil(8), height.div_ceil(8), 1], self.accumulate_pipeline.clone(), set, push_constants, vec![])?;
+		debug_label!(builder);
+		self.dirty = false;
+		self.frame_count += 1;
+		Ok(true)
+	}
+
+	// Records the average of the samples over the whole framebuffer, inside the render pass
+	pub fn draw(&self, builder: &mut AutoCommandBufferBuilder, dynamic_state: &DynamicState) -> Result<(), VulkanoError> {
+		let layout = self
+			.composite_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(self.accumulation.clone())?)?
+				.build()?,
+		);
+		builder.draw(
+			self.composite_pipeline.clone(),
+			dynamic_state,
+			BufferlessVertices {
+				vertices: 3,
+				instances: 1,
+			},
+			set,
+			progressive_composite_fs::ty::PushConstants {
+				frame_count: self.frame_count,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
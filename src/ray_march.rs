@@ -0,0 +1,547 @@
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::ComputePipeline;
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::forward_plus::ForwardVertex;
+use crate::pipeline::SharedComputePipeline;
+
+// Normal in xyz and hit distance in w, 0 normal on a miss
+pub const HIT_FORMAT: Format = Format::R32G32B32A32Sfloat;
+pub const RAY_DEPTH_FORMAT: Format = Format::R16G16B16A16Sfloat;
+// Candidate split planes per axis of the binned SAH
+const SAH_BINS: usize = 12;
+// Leaves are split down to this many triangles when the SAH allows it
+const MAX_LEAF_TRIANGLES: usize = 2;
+// Must match the traversal stack of bvh.glsl
+const MAX_BVH_DEPTH: usize = 32;
+
+mod ray_trace_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+
+			#include \"bvh.glsl\"
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 2) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				// Node count
+				uvec4 counts;
+				// Distance of the far plane of the visualization
+				vec4 params;
+			} camera;
+			layout(set = 0, binding = 3, rgba32f) uniform writeonly image2D hits;
+			layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D depth_view;
+
+			void main() {
+				ivec2 size = imageSize(hits);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+				mat4 inverse_view = inverse(camera.view);
+				vec4 target = inverse(camera.projection) * vec4(uv * 2.0 - 1.0, 1.0, 1.0);
+				vec3 direction = normalize((inverse_view * vec4(normalize(target.xyz / target.w), 0.0)).xyz);
+				vec3 origin = inverse_view[3].xyz;
+
+				float closest;
+				int hit = bvh_trace(origin, direction, 1e30, camera.counts.x, closest);
+				if (hit < 0) {
+					imageStore(hits, pixel, vec4(0.0, 0.0, 0.0, camera.params.x));
+					imageStore(depth_view, pixel, vec4(0.0, 0.0, 0.0, 1.0));
+					return;
+				}
+				vec3 normal = bvh_triangle_normal(hit);
+				// Towards the camera whatever the winding
+				normal = faceforward(normal, direction, normal);
+				imageStore(hits, pixel, vec4(normal, closest));
+				float brightness = 1.0 - clamp(closest / camera.params.x, 0.0, 1.0);
+				imageStore(depth_view, pixel, vec4(vec3(brightness), 1.0));
+			}
+		"
+	}
+}
+
+// Matches the std430 layout of the shader
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BvhNode {
+	pub min: [f32; 3],
+	// First child of an inner node, the second is next to it, or first triangle of a leaf
+	pub left_or_first: u32,
+	pub max: [f32; 3],
+	// 0 for inner nodes
+	pub count: u32,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BvhTriangle {
+	v0: [f32; 4],
+	v1: [f32; 4],
+	v2: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Aabb {
+	min: [f32; 3],
+	max: [f32; 3],
+}
+
+impl Aabb {
+	fn empty() -> Aabb {
+		Aabb {
+			min: [f32::MAX; 3],
+			max: [f32::MIN; 3],
+		}
+	}
+
+	fn grow(&mut self, point: [f32; 3]) {
+		for (axis, &value) in point.iter().enumerate() {
+			self.min[axis] = self.min[axis].min(value);
+			self.max[axis] = self.max[axis].max(value);
+		}
+	}
+
+	fn merge(&mut self, other: &Aabb) {
+		// The empty bins would stretch the bounds to infinity
+		if other.min[0] > other.max[0] {
+			return;
+		}
+		self.grow(other.min);
+		self.grow(other.max);
+	}
+
+	fn area(&self) -> f32 {
+		let [x, y, z] = [self.max[0] - self.min[0], self.max[1] - self.min[1], self.max[2] - self.min[2]];
+		if x < 0.0 {
+			return 0.0;
+		}
+		2.0 * (x * y + y * z + z * x)
+	}
+}
+
+// Bounding volume hierarchy over the triangles of a mesh, flattened depth
+// first with the children of each node next to each other
+pub struct Bvh {
+	pub nodes: Vec<BvhNode>,
+	triangles: Vec<BvhTriangle>,
+	// Index in the mesh of each triangle, in the order of the leaves
+	order: Vec<usize>,
+}
+
+impl Bvh {
+	// Splits the nodes top-down with the surface area heuristic, evaluated at
+	// SAH_BINS planes along each axis of the centroids' bounds
+	pub fn build(vertices: &[ForwardVertex], indices: &[u32]) -> Bvh {
+		let corners: Vec<[[f32; 3]; 3]> = indices
+			.chunks_exact(3)
+			.map(|triangle| {
+				[
+					vertices[triangle[0] as usize].position,
+					vertices[triangle[1] as usize].position,
+					vertices[triangle[2] as usize].position,
+				]
+			})
+			.collect();
+		let centroids: Vec<[f32; 3]> = corners
+			.iter()
+			.map(|[a, b, c]| [(a[0] + b[0] + c[0]) / 3.0, (a[1] + b[1] + c[1]) / 3.0, (a[2] + b[2] + c[2]) / 3.0])
+			.collect();
+		let bounds_of = |triangle: usize| {
+			let mut bounds = Aabb::empty();
+			for &corner in corners[triangle].iter() {
+				bounds.grow(corner);
+			}
+			bounds
+		};
+
+		let mut order: Vec<usize> = (0..corners.len()).collect();
+		// Nodes as (first, count, left child), with the bounds computed afterwards
+		let mut nodes = Vec::new();
+		// Nodes to split with their depth
+		let mut stack = Vec::new();
+		if !order.is_empty() {
+			nodes.push((0, order.len(), usize::MAX));
+			stack.push((0, 0));
+		}
+		while let Some((index, level)) = stack.pop() {
+			let (first, count, _) = nodes[index];
+			if count <= MAX_LEAF_TRIANGLES || level + 1 >= MAX_BVH_DEPTH {
+				continue;
+			}
+			let triangles = &mut order[first..first + count];
+			let mut bounds = Aabb::empty();
+			let mut centroid_bounds = Aabb::empty();
+			for &triangle in triangles.iter() {
+				bounds.merge(&bounds_of(triangle));
+				centroid_bounds.grow(centroids[triangle]);
+			}
+
+			let bin_of = |axis: usize, triangle: usize| {
+				let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+				let bin = ((centroids[triangle][axis] - centroid_bounds.min[axis]) / extent * SAH_BINS as f32) as usize;
+				bin.min(SAH_BINS - 1)
+			};
+			let mut best: Option<(usize, usize, f32)> = None;
+			for axis in 0..3 {
+				if centroid_bounds.max[axis] - centroid_bounds.min[axis] <= 0.0 {
+					continue;
+				}
+				let mut bins = [(Aabb::empty(), 0usize); SAH_BINS];
+				for &triangle in triangles.iter() {
+					let bin = &mut bins[bin_of(axis, triangle)];
+					bin.0.merge(&bounds_of(triangle));
+					bin.1 += 1;
+				}
+				// The costs of the left sides swept forward, added to the right sides swept back
+				let mut left_costs = [0.0; SAH_BINS];
+				let mut left = (Aabb::empty(), 0);
+				for (bin, cost) in bins.iter().zip(left_costs.iter_mut()) {
+					left.0.merge(&bin.0);
+					left.1 += bin.1;
+					*cost = left.0.area() * left.1 as f32;
+				}
+				let mut right = (Aabb::empty(), 0);
+				for split in (0..SAH_BINS - 1).rev() {
+					right.0.merge(&bins[split + 1].0);
+					right.1 += bins[split + 1].1;
+					// The traversal of a node costs about as much as a triangle test
+					let cost = 1.0 + (left_costs[split] + right.0.area() * right.1 as f32) / bounds.area().max(f32::EPSILON);
+					if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+						best = Some((axis, split, cost));
+					}
+				}
+			}
+
+			let (axis, split) = match best {
+				Some((axis, split, cost)) if cost < count as f32 => (axis, split),
+				_ => continue,
+			};
+			let (mut left_count, mut right_start) = (0, count);
+			while left_count < right_start {
+				if bin_of(axis, triangles[left_count]) <= split {
+					left_count += 1;
+				} else {
+					right_start -= 1;
+					triangles.swap(left_count, right_start);
+				}
+			}
+			if left_count == 0 || left_count == count {
+				continue;
+			}
+
+			let left = nodes.len();
+			nodes.push((first, left_count, usize::MAX));
+			nodes.push((first + left_count, count - left_count, usize::MAX));
+			nodes[index].2 = left;
+			stack.push((left, level + 1));
+			stack.push((left + 1, level + 1));
+		}
+
+		let nodes = nodes
+			.iter()
+			.map(|&(first, count, left)| {
+				let mut bounds = Aabb::empty();
+				for &triangle in order[first..first + count].iter() {
+					bounds.merge(&bounds_of(triangle));
+				}
+				let leaf = left == usize::MAX;
+				BvhNode {
+					min: bounds.min,
+					left_or_first: if leaf { first as u32 } else { left as u32 },
+					max: bounds.max,
+					count: if leaf { count as u32 } else { 0 },
+				}
+			})
+			.collect();
+		let triangles = order
+			.iter()
+			.map(|&triangle| {
+				let [a, b, c] = corners[triangle];
+				BvhTriangle {
+					v0: [a[0], a[1], a[2], 1.0],
+					v1: [b[0], b[1], b[2], 1.0],
+					v2: [c[0], c[1], c[2], 1.0],
+				}
+			})
+			.collect();
+		Bvh {
+			nodes,
+			triangles,
+			order,
+		}
+	}
+
+	pub fn triangle_count(&self) -> usize {
+		self.triangles.len()
+	}
+
+	// Triangles are reordered along the leaves, the per triangle data of the
+	// shaders must follow this order
+	pub fn order(&self) -> &[usize] {
+		&self.order
+	}
+
+	// Records the copy of the nodes and the triangles to the buffers bvh.glsl
+	// traverses, outside of any render pass. The hierarchy must not be empty.
+	#[allow(clippy::type_complexity)]
+	pub fn upload(
+		&self,
+		device: &Arc<Device>,
+		builder: &mut AutoCommandBufferBuilder,
+	) -> Result<(Arc<DeviceLocalBuffer<[BvhNode]>>, Arc<DeviceLocalBuffer<[BvhTriangle]>>), VulkanoError> {
+		let nodes = upload_array(device, builder, &self.nodes)?;
+		let triangles = upload_array(device, builder, &self.triangles)?;
+		Ok((nodes, triangles))
+	}
+}
+
+// Software ray tracing until hardware ray tracing is available. The BVH of
+// the geometry is built on the CPU whenever it changes and uploaded to device
+// local buffers, then a compute shader traces a primary ray per pixel through
+// it and tests the triangles of the leaves with Möller–Trumbore. It writes the
+// normal and the distance of the hits, and a grayscale view of the distances.
+pub struct RealTimeRayMarcher {
+	pipeline: SharedComputePipeline,
+	camera_pool: CpuBufferPool<ray_trace_cs::ty::Camera>,
+	nodes: Arc<DeviceLocalBuffer<[BvhNode]>>,
+	triangles: Arc<DeviceLocalBuffer<[BvhTriangle]>>,
+	node_count: u32,
+	hits: Arc<StorageImage<Format>>,
+	depth_view: Arc<StorageImage<Format>>,
+	dimensions: [u32; 2],
+	device: Arc<Device>,
+	// Distance shown black in the depth view
+	pub max_distance: f32,
+}
+
+impl RealTimeRayMarcher {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<RealTimeRayMarcher, VulkanoError> {
+		let shader = ray_trace_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let (hits, depth_view) = output_images(&device, dimensions)?;
+
+		Ok(RealTimeRayMarcher {
+			pipeline: Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?),
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			// Empty buffers can't be created, the node count stays 0 until the geometry is set
+			nodes: device_local_array(&device, 1)?,
+			triangles: device_local_array(&device, 1)?,
+			node_count: 0,
+			hits,
+			depth_view,
+			dimensions,
+			device,
+			max_distance: 100.0,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		let (hits, depth_view) = output_images(&self.device, dimensions)?;
+		self.hits = hits;
+		self.depth_view = depth_view;
+		self.dimensions = dimensions;
+		Ok(())
+	}
+
+	// Rebuilds the BVH of the geometry and records its upload, outside of any
+	// render pass. Call it again whenever the geometry changes.
+	pub fn set_geometry(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		vertices: &[ForwardVertex],
+		indices: &[u32],
+	) -> Result<(), VulkanoError> {
+		let bvh = Bvh::build(vertices, indices);
+		debug!("Built a BVH of {} nodes over {} triangles", bvh.nodes.len(), bvh.triangle_count());
+		if bvh.nodes.is_empty() {
+			self.node_count = 0;
+			return Ok(());
+		}
+
+		let (nodes, triangles) = bvh.upload(&self.device, builder)?;
+		self.nodes = nodes;
+		self.triangles = triangles;
+		self.node_count = bvh.nodes.len() as u32;
+		Ok(())
+	}
+
+	// Records the trace of the primary rays, outside of any render pass
+	pub fn trace(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		let camera = self.camera_pool.next(ray_trace_cs::ty::Camera {
+			view,
+			projection,
+			counts: [self.node_count, 0, 0, 0],
+			params: [self.max_distance, 0.0, 0.0, 0.0],
+		})?;
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(self.nodes.clone())?
+				.add_buffer(self.triangles.clone())?
+				.add_buffer(camera)?
+				.add_image(ImageView::new(self.hits.clone())?)?
+				.add_image(ImageView::new(self.depth_view.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "RealTimeRayMarcher", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1],
+			self.pipeline.clone(),
+			set,
+			(),
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+
+	// Normal and distance of the hits of the last `trace`
+	pub fn hits(&self) -> Arc<StorageImage<Format>> {
+		self.hits.clone()
+	}
+
+	// The distances of the last `trace` in grayscale, white near the camera
+	pub fn depth_view(&self) -> Arc<StorageImage<Format>> {
+		self.depth_view.clone()
+	}
+}
+
+// Storage buffer filled by a copy
+pub fn device_local_array<T>(device: &Arc<Device>, len: usize) -> Result<Arc<DeviceLocalBuffer<[T]>>, VulkanoError>
+where
+	T: Send + Sync + 'static,
+{
+	let buffer = DeviceLocalBuffer::array(
+		device.clone(),
+		len,
+		BufferUsage {
+			storage_buffer: true,
+			transfer_destination: true,
+			..BufferUsage::none()
+		},
+		device.active_queue_families(),
+	)?;
+	Ok(buffer)
+}
+
+#[allow(clippy::type_complexity)]
+fn output_images(
+	device: &Arc<Device>,
+	dimensions: [u32; 2],
+) -> Result<(Arc<StorageImage<Format>>, Arc<StorageImage<Format>>), VulkanoError> {
+	let image = |format| {
+		StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim2d {
+				width: dimensions[0],
+				height: dimensions[1],
+				array_layers: 1,
+			},
+			format,
+			ImageUsage {
+				storage: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)
+	};
+	Ok((image(HIT_FORMAT)?, image(RAY_DEPTH_FORMAT)?))
+}
+
+// Records the copy of `data` to a new storage buffer, which must not be empty
+pub fn upload_array<T>(
+	device: &Arc<Device>,
+	builder: &mut AutoCommandBufferBuilder,
+	data: &[T],
+) -> Result<Arc<DeviceLocalBuffer<[T]>>, VulkanoError>
+where
+	T: Copy + Send + Sync + 'static,
+{
+	let staging = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::transfer_source(), false, data.iter().copied())?;
+	let buffer = device_local_array(device, data.len())?;
+	builder.copy_buffer(staging, buffer.clone())?;
+	Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// A row of separate triangles along x
+	fn row(count: usize) -> (Vec<ForwardVertex>, Vec<u32>) {
+		let vertices = (0..count)
+			.flat_map(|i| {
+				let x = i as f32 * 2.0;
+				vec![[x, 0.0, 0.0], [x + 1.0, 0.0, 0.0], [x, 1.0, 0.0]]
+			})
+			.map(|position| ForwardVertex {
+				position,
+				normal: [0.0, 0.0, 1.0],
+			})
+			.collect();
+		(vertices, (0..count as u32 * 3).collect())
+	}
+
+	fn contains(outer: &BvhNode, inner: &BvhNode) -> bool {
+		(0..3).all(|axis| outer.min[axis] <= inner.min[axis] && inner.max[axis] <= outer.max[axis])
+	}
+
+	#[test]
+	fn leaves_cover_every_triangle_once() {
+		let (vertices, indices) = row(100);
+		let bvh = Bvh::build(&vertices, &indices);
+		assert_eq!(bvh.triangle_count(), 100);
+
+		let mut covered = vec![0; 100];
+		for node in &bvh.nodes {
+			if node.count == 0 {
+				let left = &bvh.nodes[node.left_or_first as usize];
+				let right = &bvh.nodes[node.left_or_first as usize + 1];
+				assert!(contains(node, left) && contains(node, right));
+			} else {
+				assert!(node.count as usize <= MAX_LEAF_TRIANGLES);
+				let first = node.left_or_first as usize;
+				for slot in &mut covered[first..first + node.count as usize] {
+					*slot += 1;
+				}
+			}
+		}
+		assert!(covered.iter().all(|&count| count == 1));
+	}
+
+	#[test]
+	fn empty_mesh_has_no_nodes() {
+		let bvh = Bvh::build(&[], &[]);
+		assert!(bvh.nodes.is_empty());
+		assert_eq!(bvh.triangle_count(), 0);
+	}
+}
@@ -0,0 +1,340 @@
+use std::sync::Arc;
+
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::{PersistentDescriptorSet, UnsafeDescriptorSetLayout};
+use vulkano::descriptor::DescriptorSet;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_RENDER_PASS;
+use crate::error::VulkanoError;
+
+pub const CAPTURE_FORMAT: Format = Format::R16G16B16A16Sfloat;
+const CAPTURE_DEPTH_FORMAT: Format = Format::D16Unorm;
+// Must match src/shaders/reflection.glsl
+pub const REFLECTION_MIP_LEVELS: usize = 4;
+pub const MAX_BLENDED_CAPTURES: usize = 3;
+
+const CAPTURE_NEAR: f32 = 0.05;
+const CAPTURE_FAR: f32 = 500.0;
+
+// Looking direction and up vector of the faces, in the +X -X +Y -Y +Z -Z
+// layer order. Rendered without flipping Y, rows go down the face like the
+// cube map texel coordinates.
+const FACES: [([f32; 3], [f32; 3]); 6] = [
+	([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+	([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+	([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+	([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+	([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+	([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+];
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+	a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+	[a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+// Column major, 90 degrees of field of view and square, depth from 0 to 1
+fn face_view_projection(position: [f32; 3], face: usize) -> [[f32; 4]; 4] {
+	let (forward, up) = FACES[face];
+	let side = cross(forward, up);
+	let up = cross(side, forward);
+	let view = [
+		[side[0], up[0], -forward[0], 0.0],
+		[side[1], up[1], -forward[1], 0.0],
+		[side[2], up[2], -forward[2], 0.0],
+		[-dot(side, position), -dot(up, position), dot(forward, position), 1.0],
+	];
+
+	let a = CAPTURE_FAR / (CAPTURE_NEAR - CAPTURE_FAR);
+	let b = CAPTURE_NEAR * CAPTURE_FAR / (CAPTURE_NEAR - CAPTURE_FAR);
+	let projection = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, a, -1.0], [0.0, 0.0, b, 0.0]];
+
+	let mut result = [[0.0; 4]; 4];
+	for (column, view_column) in result.iter_mut().zip(view.iter()) {
+		for (row, value) in column.iter_mut().enumerate() {
+			*value = (0..4).map(|k| projection[k][row] * view_column[k]).sum();
+		}
+	}
+	result
+}
+
+fn cube_level(device: &Arc<Device>, size: u32) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width: size,
+			height: size,
+			array_layers: 6,
+		},
+		CAPTURE_FORMAT,
+		ImageUsage {
+			sampled: true,
+			transfer_source: true,
+			transfer_destination: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags {
+			cube_compatible: true,
+			..ImageCreateFlags::none()
+		},
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+// A cube map of the scene seen from `position`, for the reflections of the
+// nearby geometry a static environment map misses. Storage images have a single
+// mip level, so each level of the chain is its own cube map, sampled by
+// roughness in src/shaders/reflection.glsl. Captures are only rendered when
+// `capture` is called, static scenes need a single one.
+pub struct ReflectionCapture {
+	pub position: [f32; 3],
+	// Distance from `position` at which the capture stops contributing
+	pub influence_radius: f32,
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+	face: Arc<AttachmentImage<Format>>,
+	levels: Vec<Arc<StorageImage<Format>>>,
+	dynamic_state: DynamicState,
+	face_size: u32,
+}
+
+impl ReflectionCapture {
+	// `face_size` must be a multiple of 2^(REFLECTION_MIP_LEVELS - 1)
+	pub fn new(
+		device: Arc<Device>,
+		position: [f32; 3],
+		influence_radius: f32,
+		face_size: u32,
+	) -> Result<ReflectionCapture, VulkanoError> {
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::single_pass_renderpass!(
+				device.clone(),
+				attachments: {
+					color: {
+						load: Clear,
+						store: Store,
+						format: CAPTURE_FORMAT,
+						samples: 1,
+					},
+					depth: {
+						load: Clear,
+						store: DontCare,
+						format: CAPTURE_DEPTH_FORMAT,
+						samples: 1,
+					}
+				},
+				pass: {
+					color: [color],
+					depth_stencil: {depth}
+				}
+			)?,
+		);
+
+		let dimensions = [face_size, face_size];
+		let face = AttachmentImage::with_usage(
+			device.clone(),
+			dimensions,
+			CAPTURE_FORMAT,
+			ImageUsage {
+				color_attachment: true,
+				transfer_source: true,
+				..ImageUsage::none()
+			},
+		)?;
+		let depth = AttachmentImage::transient(device.clone(), dimensions, CAPTURE_DEPTH_FORMAT)?;
+		let framebuffer = Arc::new(
+			Framebuffer::start(render_pass.clone())
+				.add(ImageView::new(face.clone())?)?
+				.add(ImageView::new(depth)?)?
+				.build()?,
+		);
+
+		let levels = (0..REFLECTION_MIP_LEVELS)
+			.map(|level| cube_level(&device, (face_size >> level).max(1)))
+			.collect::<Result<_, _>>()?;
+
+		let dynamic_state = DynamicState {
+			viewports: Some(vec![Viewport {
+				origin: [0.0, 0.0],
+				dimensions: [face_size as f32, face_size as f32],
+				depth_range: 0.0..1.0,
+			}]),
+			..DynamicState::none()
+		};
+
+		Ok(ReflectionCapture {
+			position,
+			influence_radius,
+			render_pass,
+			framebuffer,
+			face,
+			levels,
+			dynamic_state,
+			face_size,
+		})
+	}
+
+	// The scene pipelines drawn by `capture` must be built for this subpass
+	pub fn subpass(&self) -> Result<Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>, VulkanoError> {
+		Subpass::from(self.render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)
+	}
+
+	// The mip levels, each a six layer cube compatible image
+	pub fn levels(&self) -> &[Arc<StorageImage<Format>>] {
+		&self.levels
+	}
+
+	// Records the six faces and the mip chain, outside of any render pass.
+	// `draw_scene` records the scene inside the render pass of a face with the
+	// given view projection matrix.
+	pub fn capture<F>(&self, builder: &mut AutoCommandBufferBuilder, mut draw_scene: F) -> Result<(), VulkanoError>
+	where
+		F: FnMut(&mut AutoCommandBufferBuilder, &DynamicState, [[f32; 4]; 4]) -> Result<(), VulkanoError>,
+	{
+		let size = self.face_size as i32;
+		for face in 0..FACES.len() {
+			debug_label!(builder, "ReflectionCapture", LABEL_COLOR_RENDER_PASS);
+			builder.begin_render_pass(
+				self.framebuffer.clone(),
+				SubpassContents::Inline,
+				vec![[0.0, 0.0, 0.0, 1.0].into(), 1f32.into()],
+			)?;
+			draw_scene(builder, &self.dynamic_state, face_view_projection(self.position, face))?;
+			builder.end_render_pass()?;
+			debug_label!(builder);
+			builder.blit_image(
+				self.face.clone(),
+				[0, 0, 0],
+				[size, size, 1],
+				0,
+				0,
+				self.levels[0].clone(),
+				[0, 0, 0],
+				[size, size, 1],
+				face as u32,
+				0,
+				1,
+				Filter::Nearest,
+			)?;
+		}
+
+		for level in 1..self.levels.len() {
+			let source = (size >> (level - 1)).max(1);
+			let destination = (size >> level).max(1);
+			builder.blit_image(
+				self.levels[level - 1].clone(),
+				[0, 0, 0],
+				[source, source, 1],
+				0,
+				0,
+				self.levels[level].clone(),
+				[0, 0, 0],
+				[destination, destination, 1],
+				0,
+				0,
+				FACES.len() as u32,
+				Filter::Linear,
+			)?;
+		}
+		Ok(())
+	}
+}
+
+// std140 layout of ReflectionCaptures in src/shaders/reflection.glsl
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ReflectionCapturesUniform {
+	spheres: [[f32; 4]; MAX_BLENDED_CAPTURES],
+	count: u32,
+	_padding: [u32; 3],
+}
+
+// Binds up to MAX_BLENDED_CAPTURES captures for the shaders including
+// src/shaders/reflection.glsl, which blend them per fragment by distance
+pub struct ReflectionBlender {
+	uniforms: CpuBufferPool<ReflectionCapturesUniform>,
+	sampler: Arc<Sampler>,
+}
+
+impl ReflectionBlender {
+	pub fn new(device: Arc<Device>) -> Result<ReflectionBlender, VulkanoError> {
+		let sampler = Sampler::new(
+			device.clone(),
+			Filter::Linear,
+			Filter::Linear,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+		Ok(ReflectionBlender {
+			uniforms: CpuBufferPool::uniform_buffer(device),
+			sampler,
+		})
+	}
+
+	// A descriptor set for `layout`, the REFLECTION_SET of a pipeline. The
+	// captures past MAX_BLENDED_CAPTURES are ignored, `captures` must not be empty.
+	pub fn descriptor_set(
+		&self,
+		layout: Arc<UnsafeDescriptorSetLayout>,
+		captures: &[&ReflectionCapture],
+	) -> Result<Arc<dyn DescriptorSet + Send + Sync>, VulkanoError> {
+		assert!(!captures.is_empty(), "Blending reflections needs at least one capture");
+		let captures = &captures[..captures.len().min(MAX_BLENDED_CAPTURES)];
+
+		let mut spheres = [[0.0; 4]; MAX_BLENDED_CAPTURES];
+		for (sphere, capture) in spheres.iter_mut().zip(captures) {
+			let [x, y, z] = capture.position;
+			*sphere = [x, y, z, capture.influence_radius];
+		}
+		let uniform = self.uniforms.next(ReflectionCapturesUniform {
+			spheres,
+			count: captures.len() as u32,
+			_padding: [0; 3],
+		})?;
+
+		// Every element of the array must be written, the unused ones repeat the
+		// first capture. Six layer cube compatible images are viewed as cube maps.
+		let views = (0..MAX_BLENDED_CAPTURES)
+			.flat_map(|index| captures.get(index).unwrap_or(&captures[0]).levels.iter())
+			.map(|level| ImageView::new(level.clone()))
+			.collect::<Result<Vec<_>, _>>()?;
+		let sampler = &self.sampler;
+		// Each descriptor changes the type of the builder, they can't be added in a loop
+		let set = PersistentDescriptorSet::start(layout)
+			.add_buffer(uniform)?
+			.enter_array()?
+			.add_sampled_image(views[0].clone(), sampler.clone())?
+			.add_sampled_image(views[1].clone(), sampler.clone())?
+			.add_sampled_image(views[2].clone(), sampler.clone())?
+			.add_sampled_image(views[3].clone(), sampler.clone())?
+			.add_sampled_image(views[4].clone(), sampler.clone())?
+			.add_sampled_image(views[5].clone(), sampler.clone())?
+			.add_sampled_image(views[6].clone(), sampler.clone())?
+			.add_sampled_image(views[7].clone(), sampler.clone())?
+			.add_sampled_image(views[8].clone(), sampler.clone())?
+			.add_sampled_image(views[9].clone(), sampler.clone())?
+			.add_sampled_image(views[10].clone(), sampler.clone())?
+			.add_sampled_image(views[11].clone(), sampler.clone())?
+			.leave_array()?
+			.build()?;
+		Ok(Arc::new(set))
+	}
+}
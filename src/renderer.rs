@@ -0,0 +1,526 @@
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::{BufferAccess, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer, DynamicState, SubpassContents};
+use vulkano::descriptor::pipeline_layout::PipelineLayoutDesc;
+use vulkano::device::{Device, Queue};
+use vulkano::framebuffer::{FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::pipeline::shader::EntryPointAbstract;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::swapchain::{AcquireError, ColorSpace, PresentMode, Surface, Swapchain, SwapchainCreationError};
+use vulkano::sync::{FlushError, GpuFuture};
+
+use winit::window::Window;
+
+use crate::animation::{AnimationCurve, Keyframe, LoopMode};
+use crate::curve_editor::{OverlayRenderer, OverlayVertex};
+#[cfg(debug_assertions)]
+use crate::debug_utils::GpuHang;
+use crate::debug_utils::{Breadcrumb, CrashBreadcrumb, DebugNameRegistry, ShaderDebugPrintf, LABEL_COLOR_RENDER_PASS};
+use crate::debug_views::{
+	DebugBounds, DebugMeshOverlay, DebugTriangulation, LineRenderer, PointRenderer, TRIANGULATION_DEPTH_BIAS,
+};
+use crate::error::VulkanoError;
+use crate::foliage::{FoliageRenderer, MeshSurface};
+use crate::geometry::MeshPreprocessor;
+use crate::glsl_shaders::*;
+use crate::pipeline::{PipelineHotSwap, PipelineLayoutCache, SharedPipeline};
+use crate::streaming::TextureStreamer;
+use crate::swapchain::{refresh_period_ms, PresentModeAdaptor, SwapchainMonitor, SwapchainResizeDebouncer};
+use crate::sync::{FrameSemaphores, GpuFencePool, SemaphorePool};
+use crate::transparency::OitPass;
+use crate::vulk_utils::{init_vlk, recreate_with_present_mode, transfer_function};
+use crate::win_utils::{window_size_dependent_setup, FramebufferCache, WindowSize};
+use crate::Vertex;
+
+// Rotation of the triangle in radians after a number of seconds: once (PI*2)
+// every 5 seconds, easing in and out of each turn
+pub fn triangle_rotation() -> AnimationCurve<f32> {
+	const DURATION: f32 = 5.0;
+	AnimationCurve::new(
+		vec![
+			Keyframe {
+				time: 0.0,
+				value: 0.0,
+				tangent_in: 0.0,
+				tangent_out: 0.0,
+			},
+			Keyframe {
+				time: DURATION,
+				value: std::f32::consts::PI * 2.0,
+				tangent_in: 0.0,
+				tangent_out: 0.0,
+			},
+		],
+		LoopMode::Loop,
+	)
+}
+
+// Vertices of the triangle rotated by `angle` radians
+pub fn triangle_vertices(angle: f32) -> [Vertex; 3] {
+	const RADIUS: f32 = 0.5;
+	// 120Degree offset in radians
+	const ANGLE_OFFSET: f32 = (std::f32::consts::PI * 2.0) / 3.0;
+	[
+		Vertex {
+			position: [angle.cos() * RADIUS, angle.sin() * RADIUS],
+		},
+		Vertex {
+			position: [
+				(angle + ANGLE_OFFSET).cos() * RADIUS,
+				(angle + ANGLE_OFFSET).sin() * RADIUS,
+			],
+		},
+		Vertex {
+			position: [
+				(angle - ANGLE_OFFSET).cos() * RADIUS,
+				(angle - ANGLE_OFFSET).sin() * RADIUS,
+			],
+		},
+	]
+}
+
+// Every GPU resource of the application.
+// Fields are dropped in declaration order: pending GPU work first, then the
+// objects depending on the device, and the device and surface last.
+pub struct Renderer {
+	previous_frame_end: Box<dyn GpuFuture>,
+	framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+	framebuffer_cache: FramebufferCache,
+	pipeline_swap: PipelineHotSwap,
+	foliage: FoliageRenderer,
+	fence_pool: GpuFencePool,
+	semaphore_pool: SemaphorePool,
+	render_finished: FrameSemaphores,
+	frame: usize,
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	buffer_pool: CpuBufferPool<Vertex>,
+	overlay: OverlayRenderer,
+	lines: LineRenderer,
+	points: PointRenderer,
+	triangulation_lines: LineRenderer,
+	oit: OitPass,
+	texture_streamer: TextureStreamer,
+	mesh_preprocessor: MeshPreprocessor,
+	crash_breadcrumb: CrashBreadcrumb,
+	#[cfg(debug_assertions)]
+	gpu_hang: Option<GpuHang>,
+	dynamic_state: DynamicState,
+	swapchain_monitor: SwapchainMonitor,
+	resize_debouncer: SwapchainResizeDebouncer,
+	present_mode_adaptor: PresentModeAdaptor,
+	pending_present_mode: Option<PresentMode>,
+	forced_present_mode: Option<PresentMode>,
+	swapchain: Arc<Swapchain<Arc<Window>>>,
+	color_space: ColorSpace,
+	window_size: WindowSize,
+	debug_names: DebugNameRegistry,
+	_shader_printf: ShaderDebugPrintf,
+	queue: Arc<Queue>,
+	device: Arc<Device>,
+	surface: Arc<Surface<Arc<Window>>>,
+}
+
+impl Renderer {
+	// `optimize_meshes` enables the MeshPreprocessor optimizations
+	pub fn new(window: Arc<Window>, optimize_meshes: bool) -> Result<Renderer, VulkanoError> {
+		let (surface, swapchain, images, queue, device, color_space) = init_vlk(window)?;
+		let shader_printf = ShaderDebugPrintf::new(device.instance())?;
+
+		info!("Vulkan init ended succesifully");
+
+		// Vertex Buffer Pool
+		let buffer_pool: CpuBufferPool<Vertex> = CpuBufferPool::vertex_buffer(device.clone());
+
+		let vs = vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::single_pass_renderpass!(
+				device.clone(),
+				attachments: {
+					color: {
+						load: Clear,
+						store: Store,
+						format: swapchain.format(),
+						samples: 1,
+					}
+				},
+				pass: {
+					color: [color],
+					depth_stencil: {}
+				}
+			)?,
+		);
+
+		let debug_names = DebugNameRegistry::new(device.clone());
+		debug_names.name(&render_pass.inner(), "main_render_pass");
+
+		let mut layout_cache = PipelineLayoutCache::new(device.clone());
+		let layout_desc = vs
+			.main_entry_point()
+			.layout()
+			.clone()
+			.union(fs.main_entry_point().layout().clone());
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<Vertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(
+				fs.main_entry_point(),
+				fs::SpecializationConstants {
+					transfer_function: transfer_function(swapchain.format(), color_space),
+				},
+			)
+			.render_pass(Subpass::from(render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)?)
+			.with_pipeline_layout(device.clone(), layout_cache.get_or_create(&layout_desc)?)?;
+		debug_names.name(&pipeline, "triangle_pipeline");
+		let pipeline_swap = PipelineHotSwap::new(Arc::new(pipeline), render_pass.clone());
+		let foliage = FoliageRenderer::new(device.clone(), render_pass.clone())?;
+		let overlay = OverlayRenderer::new(
+			device.clone(),
+			Subpass::from(render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)?,
+		)?;
+		let lines = LineRenderer::new(
+			device.clone(),
+			Subpass::from(render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)?,
+		)?;
+		let mut points = PointRenderer::new(
+			device.clone(),
+			Subpass::from(render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)?,
+		)?;
+		points.depth_bias = TRIANGULATION_DEPTH_BIAS;
+		let mut triangulation_lines = LineRenderer::depth_tested(
+			device.clone(),
+			Subpass::from(render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)?,
+		)?;
+		triangulation_lines.depth_bias = TRIANGULATION_DEPTH_BIAS;
+
+		let mut dynamic_state = DynamicState {
+			line_width: None,
+			viewports: None,
+			scissors: None,
+			compare_mask: None,
+			write_mask: None,
+			reference: None,
+		};
+		let mut framebuffer_cache = FramebufferCache::new();
+		let mut oit = OitPass::new(device.clone(), swapchain.format())?;
+		let framebuffers = window_size_dependent_setup(
+			&images,
+			render_pass.clone(),
+			&mut dynamic_state,
+			&mut framebuffer_cache,
+			&debug_names,
+			&mut oit,
+		)?;
+		let (texture_streamer, placeholder_future) = TextureStreamer::new(queue.clone())?;
+		let mesh_preprocessor = MeshPreprocessor::new(queue.clone(), optimize_meshes);
+		let previous_frame_end = vulkano::sync::now(device.clone()).join(placeholder_future).boxed();
+		let fence_pool = GpuFencePool::new(device.clone())?.with_timer();
+		let semaphore_pool = SemaphorePool::new(device.clone());
+		let render_finished = FrameSemaphores::new(images.len());
+		let crash_breadcrumb = CrashBreadcrumb::new(device.clone())?;
+
+		let caps = surface.capabilities(device.physical_device())?;
+		let present_mode_adaptor = PresentModeAdaptor::new(
+			refresh_period_ms(surface.window()),
+			caps.present_modes.mailbox,
+		);
+
+		Ok(Renderer {
+			previous_frame_end,
+			framebuffers,
+			framebuffer_cache,
+			pipeline_swap,
+			foliage,
+			fence_pool,
+			semaphore_pool,
+			render_finished,
+			frame: 0,
+			render_pass,
+			buffer_pool,
+			overlay,
+			lines,
+			points,
+			triangulation_lines,
+			oit,
+			texture_streamer,
+			mesh_preprocessor,
+			crash_breadcrumb,
+			#[cfg(debug_assertions)]
+			gpu_hang: None,
+			dynamic_state,
+			swapchain_monitor: SwapchainMonitor::new(),
+			resize_debouncer: SwapchainResizeDebouncer::new(),
+			present_mode_adaptor,
+			pending_present_mode: None,
+			forced_present_mode: None,
+			window_size: WindowSize::of(surface.window()),
+			swapchain,
+			color_space,
+			debug_names,
+			_shader_printf: shader_printf,
+			queue,
+			device,
+			surface,
+		})
+	}
+
+	// The pipeline the meshes of `surface` are drawn with
+	pub fn pipeline_for(&self, surface: MeshSurface) -> SharedPipeline {
+		match surface {
+			MeshSurface::Opaque => (**self.pipeline_swap.get()).clone(),
+			MeshSurface::Foliage => self.foliage.pipeline(),
+		}
+	}
+
+	pub fn foliage(&mut self) -> &mut FoliageRenderer {
+		&mut self.foliage
+	}
+
+	pub fn mesh_preprocessor(&mut self) -> &mut MeshPreprocessor {
+		&mut self.mesh_preprocessor
+	}
+
+	// Scale factor of the monitor the window is on, 2.0 on Retina displays
+	pub fn high_dpi_scale(&self) -> f64 {
+		self.window_size.scale_factor
+	}
+
+	// Size of the swapchain in points, for the UI layout
+	pub fn logical_size(&self) -> [f32; 2] {
+		self.window_size.logical_size
+	}
+
+	// Also called when the scale factor changes, the physical size changes with it
+	pub fn resized(&mut self) {
+		self.resize_debouncer.resized();
+		self.swapchain_monitor.invalidate();
+	}
+
+	// Makes the next frame hang the GPU, see GpuHang
+	#[cfg(debug_assertions)]
+	pub fn hang_gpu(&mut self) -> Result<(), VulkanoError> {
+		self.gpu_hang = Some(GpuHang::new(self.device.clone())?);
+		Ok(())
+	}
+
+	// Recreates the swapchain with `mode` and stops adapting the present mode to
+	// the frame times. Returns false if the surface doesn't support `mode`.
+	pub fn force_present_mode(&mut self, mode: PresentMode) -> Result<bool, VulkanoError> {
+		let caps = self.surface.capabilities(self.device.physical_device())?;
+		if !caps.present_modes.supports(mode) {
+			warn!("{:?} present mode isn't supported", mode);
+			return Ok(false);
+		}
+		self.forced_present_mode = Some(mode);
+		self.pending_present_mode = Some(mode);
+		self.swapchain_monitor.invalidate();
+		Ok(true)
+	}
+
+	// Draws the triangle rotated by `angle` radians
+	// `overlay` is drawn over the scene, in normalized device coordinates.
+	// After a DeviceLost error the renderer must be dropped and a new one created
+	pub fn render_frame(
+		&mut self,
+		angle: f32,
+		overlay: &[OverlayVertex],
+		debug_mesh_overlay: &DebugMeshOverlay,
+		debug_triangulation: &DebugTriangulation,
+	) -> Result<(), VulkanoError> {
+		let result = self.record_frame(angle, overlay, debug_mesh_overlay, debug_triangulation);
+		if let Err(VulkanoError::DeviceLost) = result {
+			self.abandon_frames_in_flight();
+		}
+		result
+	}
+
+	// vulkano's fence futures wait on their fence when dropped and panic when
+	// the wait fails, which it always does on a lost device. The frames in
+	// flight are leaked instead, so that the renderer can be dropped.
+	fn abandon_frames_in_flight(&mut self) {
+		let in_flight = std::mem::replace(
+			&mut self.previous_frame_end,
+			vulkano::sync::now(self.device.clone()).boxed(),
+		);
+		std::mem::forget(in_flight);
+	}
+
+	fn record_frame(
+		&mut self,
+		angle: f32,
+		overlay: &[OverlayVertex],
+		debug_mesh_overlay: &DebugMeshOverlay,
+		debug_triangulation: &DebugTriangulation,
+	) -> Result<(), VulkanoError> {
+		self.previous_frame_end.cleanup_finished();
+
+		if let Some(upload_future) = self.texture_streamer.process_uploads()? {
+			let previous = std::mem::replace(
+				&mut self.previous_frame_end,
+				vulkano::sync::now(self.device.clone()).boxed(),
+			);
+			self.previous_frame_end = previous.join(upload_future).boxed();
+		}
+		if let Some(upload_future) = self.mesh_preprocessor.process_uploads()? {
+			let previous = std::mem::replace(
+				&mut self.previous_frame_end,
+				vulkano::sync::now(self.device.clone()).boxed(),
+			);
+			self.previous_frame_end = previous.join(upload_future).boxed();
+		}
+
+		if self.swapchain_monitor.begin_frame() && self.resize_debouncer.settled() {
+			let window_size = WindowSize::of(self.surface.window());
+			let dimensions = window_size.physical_size;
+			let recreated = match self.pending_present_mode {
+				Some(mode) => {
+					recreate_with_present_mode(&self.swapchain, &self.queue, dimensions, mode, self.color_space)
+				}
+				None => self.swapchain.recreate_with_dimensions(dimensions),
+			};
+			let (new_swapchain, new_images) =
+				match recreated {
+					Ok(r) => r,
+					Err(SwapchainCreationError::UnsupportedDimensions) => return Ok(()),
+					Err(e) => return Err(e.into()),
+				};
+
+			self.swapchain = new_swapchain;
+			self.window_size = window_size;
+			self.framebuffers = window_size_dependent_setup(
+				&new_images,
+				self.render_pass.clone(),
+				&mut self.dynamic_state,
+				&mut self.framebuffer_cache,
+				&self.debug_names,
+				&mut self.oit,
+			)?;
+			self.swapchain_monitor.recreated();
+			self.pending_present_mode = None;
+		}
+
+		let (image_num, suboptimal, acquire_future) =
+			match vulkano::swapchain::acquire_next_image(self.swapchain.clone(), None) {
+				Ok(r) => r,
+				Err(AcquireError::OutOfDate) => {
+					self.swapchain_monitor.invalidate();
+					return Ok(());
+				}
+				Err(e) => return Err(e.into()),
+			};
+
+		if suboptimal {
+			self.swapchain_monitor.report_suboptimal();
+		}
+
+		self.pipeline_swap.begin_frame();
+		let pipeline: SharedPipeline = (**self.pipeline_swap.get()).clone();
+
+		let clear_values = vec![[0.0, 0.0, 1.0, 1.0].into()];
+
+		let data = triangle_vertices(angle);
+		if debug_mesh_overlay.is_enabled() || debug_triangulation.is_enabled() {
+			let points: Vec<[f32; 3]> = data.iter().map(|v| [v.position[0], v.position[1], 0.0]).collect();
+			debug_mesh_overlay.add_bounds(&mut self.lines, &[DebugBounds::from_points(&points)]);
+			debug_triangulation.add_mesh(&mut self.points, &mut self.triangulation_lines, &points, &[0, 1, 2]);
+		}
+
+		// Allocate a new chunk from buffer_pool
+		let buffer = self.buffer_pool.chunk(data.to_vec())?;
+		let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+			self.device.clone(),
+			self.queue.family(),
+		)?;
+		self.crash_breadcrumb.begin_frame(&mut builder)?;
+		#[cfg(debug_assertions)]
+		if let Some(gpu_hang) = self.gpu_hang.take() {
+			gpu_hang.record(&mut builder)?;
+		}
+		self.crash_breadcrumb.mark(&mut builder, Breadcrumb::BeforeRenderPass)?;
+		debug_label!(builder, "main_render_pass", LABEL_COLOR_RENDER_PASS);
+		builder
+			.begin_render_pass(
+				self.framebuffers[image_num].clone(),
+				SubpassContents::Inline,
+				clear_values,
+			)?
+			// Draw our buffer
+			.draw(
+				pipeline,
+				&self.dynamic_state,
+				vec![Arc::new(buffer) as Arc<dyn BufferAccess + Send + Sync>],
+				(),
+				(),
+				vec![],
+			)?;
+		// The triangle is already in normalized device coordinates
+		const IDENTITY: [[f32; 4]; 4] = [
+			[1.0, 0.0, 0.0, 0.0],
+			[0.0, 1.0, 0.0, 0.0],
+			[0.0, 0.0, 1.0, 0.0],
+			[0.0, 0.0, 0.0, 1.0],
+		];
+		self.triangulation_lines.flush(&mut builder, &self.dynamic_state, IDENTITY)?;
+		self.points.flush(&mut builder, &self.dynamic_state, IDENTITY)?;
+		self.lines.flush(&mut builder, &self.dynamic_state, IDENTITY)?;
+		self.overlay.draw(&mut builder, &self.dynamic_state, overlay)?;
+		builder.end_render_pass()?;
+		debug_label!(builder);
+		self.crash_breadcrumb.mark(&mut builder, Breadcrumb::AfterRenderPass)?;
+		self.crash_breadcrumb.mark(&mut builder, Breadcrumb::FrameEnd)?;
+		let command_buffer = builder.build()?;
+		self.debug_names.name(command_buffer.inner(), "frame_command_buffer");
+
+		let previous = std::mem::replace(
+			&mut self.previous_frame_end,
+			vulkano::sync::now(self.device.clone()).boxed(),
+		);
+		let future = previous
+			.join(acquire_future)
+			.then_execute(self.queue.clone(), command_buffer)?;
+		// The present waits on the pooled semaphore signaled after the draw
+		let future = self
+			.render_finished
+			.signal_after(self.frame, &mut self.semaphore_pool, future)?
+			.then_swapchain_present(self.queue.clone(), self.swapchain.clone(), image_num);
+		self.frame = self.frame.wrapping_add(1);
+
+		match self.fence_pool.signal_after(&self.queue, future) {
+			Ok(future) => {
+				self.pipeline_swap.end_frame(future.fence().clone());
+				self.previous_frame_end = future.boxed();
+			}
+			Err(FlushError::OutOfDate) => {
+				self.swapchain_monitor.invalidate();
+			}
+			Err(FlushError::DeviceLost) => {
+				self.crash_breadcrumb.report();
+				return Err(VulkanoError::DeviceLost);
+			}
+			Err(e) => {
+				error!("Failed to flush future: {}", e);
+			}
+		}
+
+		// The GPU time of the frames, the CPU only records and submits them
+		if let Some(timer) = self.fence_pool.timer() {
+			for frame_ms in timer.drain() {
+				if self.forced_present_mode.is_some() {
+					continue;
+				}
+				if let Some(mode) = self.present_mode_adaptor.record_frame_time(frame_ms) {
+					self.pending_present_mode = Some(mode);
+					self.swapchain_monitor.invalidate();
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,465 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::ComputePipeline;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::forward_plus::PointLight;
+use crate::pipeline::SharedComputePipeline;
+
+// Light index, W, M and depth of each pixel's reservoir
+pub const RESERVOIR_FORMAT: Format = Format::R32G32B32A32Sfloat;
+pub const RESTIR_FORMAT: Format = Format::R16G16B16A16Sfloat;
+// Must match the spatial shader
+pub const SPATIAL_NEIGHBOURS: usize = 5;
+
+mod restir_initial_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+
+			#include \"restir.glsl\"
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			// In UV units, from the previous frame to this one
+			layout(set = 0, binding = 5, rg16f) uniform readonly image2D velocity;
+			// The final reservoirs of the previous frame
+			layout(set = 0, binding = 6, rgba32f) uniform readonly image2D history;
+			layout(set = 0, binding = 7, rgba32f) uniform writeonly image2D reservoirs;
+
+			void main() {
+				ivec2 size = imageSize(reservoirs);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+				if (textureLod(depth, uv, 0.0).r >= 1.0 || camera.counts.x == 0) {
+					imageStore(reservoirs, pixel, pack_reservoir(Reservoir(NO_LIGHT, 0.0, 0.0, 0.0)));
+					return;
+				}
+				seed(pixel, 0u);
+
+				vec3 position = view_position(uv);
+				vec3 normal = view_normal(uv);
+
+				// Resampled importance sampling of candidates drawn by power, each
+				// a reservoir of one light weighted by the inverse of its pdf
+				Resampling resampling = start_resampling();
+				for (uint i = 0; i < camera.counts.z; i++) {
+					float pdf;
+					uint light = sample_light(pdf);
+					float W = pdf > 0.0 ? 1.0 / pdf : 0.0;
+					combine(resampling, Reservoir(light, W, 1.0, 0.0), target_function(position, normal, light), random());
+				}
+				Reservoir reservoir = finish_resampling(resampling, -position.z);
+
+				// Temporal reuse of the reprojected reservoir of the previous frame
+				vec2 previous_uv = uv - imageLoad(velocity, pixel).xy;
+				if (camera.counts.w != 0 && all(greaterThanEqual(previous_uv, vec2(0.0))) && all(lessThan(previous_uv, vec2(1.0)))) {
+					Reservoir previous = unpack_reservoir(imageLoad(history, ivec2(previous_uv * vec2(size))));
+					if (abs(previous.depth - reservoir.depth) < 0.1 * reservoir.depth) {
+						// The history is capped so the reservoirs keep adapting to changes
+						previous.M = min(previous.M, camera.params.y * reservoir.M);
+						Resampling temporal = start_resampling();
+						combine(temporal, reservoir, resampling.target, random());
+						combine(temporal, previous, target_function(position, normal, previous.light), random());
+						reservoir = finish_resampling(temporal, reservoir.depth);
+					}
+				}
+
+				imageStore(reservoirs, pixel, pack_reservoir(reservoir));
+			}
+		"
+	}
+}
+
+mod restir_spatial_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+
+			#include \"restir.glsl\"
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const int SPATIAL_NEIGHBOURS = 5;
+
+			layout(set = 0, binding = 5) uniform sampler2D albedo;
+			layout(set = 0, binding = 6, rgba32f) uniform readonly image2D reservoirs;
+			// Reused by the temporal resampling of the next frame
+			layout(set = 0, binding = 7, rgba32f) uniform writeonly image2D history;
+			layout(set = 0, binding = 8, rgba16f) uniform writeonly image2D radiance;
+
+			vec3 project(vec3 position) {
+				vec4 clip = camera.projection * vec4(position, 1.0);
+				return vec3(clip.xy / clip.w * 0.5 + 0.5, clip.z / clip.w);
+			}
+
+			// Marches the depth buffer towards the light, the only geometry
+			// available without ray tracing
+			float visibility(vec3 origin, vec3 normal, uint index) {
+				vec3 to_light = (camera.view * vec4(lights[index].position, 1.0)).xyz - origin;
+				float distance = min(length(to_light), camera.params.w);
+				vec3 direction = normalize(to_light);
+				origin += normal * 0.01;
+				const int STEPS = 16;
+				for (int i = 1; i <= STEPS; i++) {
+					vec3 position = origin + direction * distance * (float(i) - random()) / float(STEPS);
+					vec3 projected = project(position);
+					if (any(lessThan(projected.xy, vec2(0.0))) || any(greaterThan(projected.xy, vec2(1.0)))) {
+						break;
+					}
+					// View space looks down -Z, positive when the scene is in front of the ray
+					float difference = view_position(projected.xy).z - position.z;
+					if (difference > 0.0 && difference < camera.params.z) {
+						return 0.0;
+					}
+				}
+				return 1.0;
+			}
+
+			void main() {
+				ivec2 size = imageSize(radiance);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+				Reservoir center = unpack_reservoir(imageLoad(reservoirs, pixel));
+				if (center.M == 0.0) {
+					imageStore(history, pixel, pack_reservoir(center));
+					imageStore(radiance, pixel, vec4(0.0));
+					return;
+				}
+				seed(pixel, 1u);
+
+				vec3 position = view_position(uv);
+				vec3 normal = view_normal(uv);
+
+				Resampling resampling = start_resampling();
+				combine(resampling, center, target_function(position, normal, center.light), random());
+				for (int i = 0; i < SPATIAL_NEIGHBOURS; i++) {
+					// Uniformly in a disk around the pixel
+					float angle = random() * 6.2831853;
+					float radius = sqrt(random()) * camera.params.x;
+					ivec2 neighbour_pixel = pixel + ivec2(round(vec2(cos(angle), sin(angle)) * radius));
+					if (any(lessThan(neighbour_pixel, ivec2(0))) || any(greaterThanEqual(neighbour_pixel, size)) || neighbour_pixel == pixel) {
+						continue;
+					}
+					Reservoir neighbour = unpack_reservoir(imageLoad(reservoirs, neighbour_pixel));
+					vec2 neighbour_uv = (vec2(neighbour_pixel) + 0.5) / vec2(size);
+					// Reservoirs across geometric edges would bias the lighting
+					if (neighbour.M == 0.0 || abs(neighbour.depth - center.depth) > 0.1 * center.depth || dot(view_normal(neighbour_uv), normal) < 0.9) {
+						continue;
+					}
+					combine(resampling, neighbour, target_function(position, normal, neighbour.light), random());
+				}
+				Reservoir reservoir = finish_resampling(resampling, center.depth);
+
+				vec3 color = vec3(0.0);
+				if (reservoir.light != NO_LIGHT) {
+					// An occluded sample is not reused by the next frames either
+					reservoir.W *= visibility(position, normal, reservoir.light);
+					vec3 diffuse_albedo = textureLod(albedo, uv, 0.0).rgb / 3.14159265;
+					color = diffuse_albedo * light_irradiance(position, normal, reservoir.light) * reservoir.W;
+				}
+
+				imageStore(history, pixel, pack_reservoir(reservoir));
+				imageStore(radiance, pixel, vec4(color, 1.0));
+			}
+		"
+	}
+}
+
+// Matches the std430 layout of the shaders
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+struct AliasEntry {
+	threshold: f32,
+	alias: u32,
+	pdf: f32,
+}
+
+// Vose's alias method: each bucket keeps its own light with `threshold`,
+// else its alias, which selects every light in constant time with a
+// probability proportional to its power.
+fn alias_table(lights: &[PointLight]) -> Vec<AliasEntry> {
+	let powers = lights
+		.iter()
+		.map(|light| {
+			let [r, g, b] = light.color;
+			(0.2126 * r + 0.7152 * g + 0.0722 * b) * light.intensity
+		})
+		.collect::<Vec<_>>();
+	let total: f32 = powers.iter().sum();
+	let count = lights.len();
+	// Lights without power are still drawn uniformly rather than never
+	let probabilities = powers
+		.iter()
+		.map(|power| if total > 0.0 { power / total } else { 1.0 / count as f32 })
+		.collect::<Vec<_>>();
+
+	let mut table = probabilities
+		.iter()
+		.enumerate()
+		.map(|(index, &pdf)| AliasEntry {
+			threshold: 1.0,
+			alias: index as u32,
+			pdf,
+		})
+		.collect::<Vec<_>>();
+	let mut scaled = probabilities.iter().map(|pdf| pdf * count as f32).collect::<Vec<_>>();
+	let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..count).partition(|&index| scaled[index] < 1.0);
+	while let (Some(&under), Some(&over)) = (small.last(), large.last()) {
+		small.pop();
+		table[under].threshold = scaled[under];
+		table[under].alias = over as u32;
+		scaled[over] -= 1.0 - scaled[under];
+		if scaled[over] < 1.0 {
+			large.pop();
+			small.push(over);
+		}
+	}
+	// What is left is 1 up to the rounding errors
+	table
+}
+
+// Direct lighting from many lights with reservoir-based spatiotemporal
+// importance resampling (ReSTIR). Each pixel draws `candidates` lights from
+// an alias table built on their power and keeps one with a reservoir, weighted
+// by its unshadowed irradiance. The reservoir is combined with the reprojected
+// one of the previous frame, then with those of SPATIAL_NEIGHBOURS pixels
+// around it, and only the light finally selected is tested for visibility and
+// shaded. vulkano 0.22 has no ray tracing, the visibility is marched in the
+// depth buffer like the contact shadows.
+pub struct ReStirPass {
+	initial_pipeline: SharedComputePipeline,
+	spatial_pipeline: SharedComputePipeline,
+	// The uniform blocks of both shaders are the same
+	camera_pool: CpuBufferPool<restir_initial_cs::ty::Camera>,
+	lights: Arc<CpuAccessibleBuffer<[PointLight]>>,
+	alias_table: Arc<CpuAccessibleBuffer<[AliasEntry]>>,
+	light_count: u32,
+	reservoirs: Arc<StorageImage<Format>>,
+	// The final reservoirs of this frame and the last, swapped every frame
+	history: [Arc<StorageImage<Format>>; 2],
+	radiance: Arc<StorageImage<Format>>,
+	sampler: Arc<Sampler>,
+	frame: u32,
+	history_valid: bool,
+	dimensions: [u32; 2],
+	device: Arc<Device>,
+	// Lights drawn for each pixel, M
+	pub candidates: u32,
+	pub spatial_radius: f32,
+	// Most candidates the previous reservoir stands for, relative to `candidates`
+	pub max_history: f32,
+	pub temporal: bool,
+	// Of the visibility test, in view space units
+	pub shadow_thickness: f32,
+	pub shadow_distance: f32,
+}
+
+impl ReStirPass {
+	pub fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<ReStirPass, VulkanoError> {
+		let initial_shader = restir_initial_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let spatial_shader = restir_spatial_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let (lights, alias_table) = light_buffers(&device, &[])?;
+		let (reservoirs, history, radiance) = images(&device, dimensions)?;
+
+		Ok(ReStirPass {
+			initial_pipeline: Arc::new(ComputePipeline::new(device.clone(), &initial_shader.main_entry_point(), &(), None)?),
+			spatial_pipeline: Arc::new(ComputePipeline::new(device.clone(), &spatial_shader.main_entry_point(), &(), None)?),
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			lights,
+			alias_table,
+			light_count: 0,
+			reservoirs,
+			history,
+			radiance,
+			// Depths and normals must not be interpolated across surfaces
+			sampler: Sampler::new(
+				device.clone(),
+				Filter::Nearest,
+				Filter::Nearest,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)?,
+			frame: 0,
+			history_valid: false,
+			dimensions,
+			device,
+			candidates: 32,
+			spatial_radius: 30.0,
+			max_history: 20.0,
+			temporal: true,
+			shadow_thickness: 0.2,
+			shadow_distance: 5.0,
+		})
+	}
+
+	pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), VulkanoError> {
+		let (reservoirs, history, radiance) = images(&self.device, dimensions)?;
+		self.reservoirs = reservoirs;
+		self.history = history;
+		self.radiance = radiance;
+		self.dimensions = dimensions;
+		self.history_valid = false;
+		Ok(())
+	}
+
+	// Replaces the lights and rebuilds their alias table. The light indices of
+	// the history change meaning, it is discarded.
+	pub fn set_lights(&mut self, lights: &[PointLight]) -> Result<(), VulkanoError> {
+		let (buffer, alias_table) = light_buffers(&self.device, lights)?;
+		self.lights = buffer;
+		self.alias_table = alias_table;
+		self.light_count = lights.len() as u32;
+		self.history_valid = false;
+		Ok(())
+	}
+
+	pub fn light_count(&self) -> u32 {
+		self.light_count
+	}
+
+	// Records the resampling and the shading, outside of any render pass, and
+	// returns the direct lighting of the frame. `velocity` is the one of
+	// `MotionBlurPass`, `albedo` the diffuse color of the G-buffer.
+	#[allow(clippy::too_many_arguments)]
+	pub fn record(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+		normals: Arc<dyn ImageViewAbstract + Send + Sync>,
+		albedo: Arc<dyn ImageViewAbstract + Send + Sync>,
+		velocity: Arc<StorageImage<Format>>,
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+	) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+		let camera = self.camera_pool.next(restir_initial_cs::ty::Camera {
+			view,
+			projection,
+			counts: [
+				self.light_count,
+				self.frame,
+				self.candidates.max(1),
+				(self.temporal && self.history_valid) as u32,
+			],
+			params: [self.spatial_radius, self.max_history, self.shadow_thickness, self.shadow_distance],
+		})?;
+		let previous = self.history[(self.frame % 2) as usize].clone();
+		let current = self.history[(self.frame % 2) as usize ^ 1].clone();
+		let groups = [self.dimensions[0].div_ceil(8), self.dimensions[1].div_ceil(8), 1];
+
+		let layout = self
+			.initial_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(self.lights.clone())?
+				.add_buffer(self.alias_table.clone())?
+				.add_buffer(camera.clone())?
+				.add_sampled_image(depth.clone(), self.sampler.clone())?
+				.add_sampled_image(normals.clone(), self.sampler.clone())?
+				.add_image(ImageView::new(velocity)?)?
+				.add_image(ImageView::new(previous)?)?
+				.add_image(ImageView::new(self.reservoirs.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "ReStirPass initial", LABEL_COLOR_COMPUTE);
+		builder.dispatch(groups, self.initial_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+
+		let layout = self
+			.spatial_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(self.lights.clone())?
+				.add_buffer(self.alias_table.clone())?
+				.add_buffer(camera)?
+				.add_sampled_image(depth, self.sampler.clone())?
+				.add_sampled_image(normals, self.sampler.clone())?
+				.add_sampled_image(albedo, self.sampler.clone())?
+				.add_image(ImageView::new(self.reservoirs.clone())?)?
+				.add_image(ImageView::new(current)?)?
+				.add_image(ImageView::new(self.radiance.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "ReStirPass spatial", LABEL_COLOR_COMPUTE);
+		builder.dispatch(groups, self.spatial_pipeline.clone(), set, (), vec![])?;
+		debug_label!(builder);
+
+		self.frame = self.frame.wrapping_add(1);
+		self.history_valid = true;
+		Ok(self.radiance.clone())
+	}
+}
+
+type LightBuffers = (Arc<CpuAccessibleBuffer<[PointLight]>>, Arc<CpuAccessibleBuffer<[AliasEntry]>>);
+
+fn light_buffers(device: &Arc<Device>, lights: &[PointLight]) -> Result<LightBuffers, VulkanoError> {
+	// Empty buffers can't be created, a dark light stands in and the light count stays 0
+	let lights = if lights.is_empty() {
+		vec![PointLight::default()]
+	} else {
+		lights.to_vec()
+	};
+	let table = alias_table(&lights);
+	Ok((
+		CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage { storage_buffer: true, ..BufferUsage::none() }, false, lights.into_iter())?,
+		CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage { storage_buffer: true, ..BufferUsage::none() }, false, table.into_iter())?,
+	))
+}
+
+type ReStirImages = (Arc<StorageImage<Format>>, [Arc<StorageImage<Format>>; 2], Arc<StorageImage<Format>>);
+
+fn images(device: &Arc<Device>, dimensions: [u32; 2]) -> Result<ReStirImages, VulkanoError> {
+	let image = |format| {
+		StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim2d {
+				width: dimensions[0],
+				height: dimensions[1],
+				array_layers: 1,
+			},
+			format,
+			ImageUsage {
+				storage: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)
+	};
+	Ok((
+		image(RESERVOIR_FORMAT)?,
+		[image(RESERVOIR_FORMAT)?, image(RESERVOIR_FORMAT)?],
+		image(RESTIR_FORMAT)?,
+	))
+}
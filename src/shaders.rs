@@ -0,0 +1,939 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crossbeam_channel::{Receiver, TryRecvError};
+
+use rspirv::dr::{Instruction, Module, Operand};
+use rspirv::spirv::{Decoration, Dim, ExecutionModel, Op, StorageClass};
+
+use vulkano::descriptor::descriptor::{
+	DescriptorBufferDesc,
+	DescriptorDesc,
+	DescriptorDescTy,
+	DescriptorImageDesc,
+	DescriptorImageDescArray,
+	DescriptorImageDescDimensions,
+	ShaderStages,
+};
+use vulkano::descriptor::pipeline_layout::{PipelineLayoutDesc, PipelineLayoutDescPcRange};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::pipeline::shader::ShaderModule;
+use vulkano::pipeline::ComputePipeline;
+
+use shaderc::{IncludeType, ResolvedInclude};
+pub use shaderc::ShaderKind;
+
+use crate::error::VulkanoError;
+use crate::pipeline::{PipelineLayoutCache, SharedPipelineLayout};
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+	pub message: String,
+}
+
+impl fmt::Display for CompileError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Shader compilation failed: {}", self.message)
+	}
+}
+
+impl std::error::Error for CompileError {}
+
+impl From<shaderc::Error> for CompileError {
+	fn from(e: shaderc::Error) -> CompileError {
+		CompileError {
+			message: e.to_string(),
+		}
+	}
+}
+
+pub fn compile_glsl(source: &str, kind: ShaderKind, name: &str) -> Result<Vec<u32>, CompileError> {
+	let mut compiler = shaderc::Compiler::new().ok_or_else(|| CompileError {
+		message: "failed to initialize shaderc".to_string(),
+	})?;
+	let artifact = compiler.compile_into_spirv(source, kind, name, "main", None)?;
+	Ok(artifact.as_binary().to_vec())
+}
+
+// Compiles `source` with each of `defines` defined (without a value), for
+// shaders whose features are toggled with `#ifdef`
+pub fn compile_glsl_with_defines(
+	source: &str,
+	kind: ShaderKind,
+	name: &str,
+	defines: &[&str],
+) -> Result<Vec<u32>, CompileError> {
+	let mut compiler = shaderc::Compiler::new().ok_or_else(|| CompileError {
+		message: "failed to initialize shaderc".to_string(),
+	})?;
+	let mut options = shaderc::CompileOptions::new().ok_or_else(|| CompileError {
+		message: "failed to create the shaderc compile options".to_string(),
+	})?;
+	for define in defines {
+		options.add_macro_definition(define, None);
+	}
+	let artifact = compiler.compile_into_spirv(source, kind, name, "main", Some(&options))?;
+	Ok(artifact.as_binary().to_vec())
+}
+
+// A compilation running in the background
+pub struct ShaderFuture {
+	receiver: Receiver<Result<Vec<u32>, CompileError>>,
+}
+
+impl ShaderFuture {
+	// Doesn't block, returns None while the compilation is still in progress
+	pub fn poll_result(&self) -> Option<Result<Vec<u32>, CompileError>> {
+		match self.receiver.try_recv() {
+			Ok(result) => Some(result),
+			Err(TryRecvError::Empty) => None,
+			Err(TryRecvError::Disconnected) => Some(Err(CompileError {
+				message: "compilation task was dropped".to_string(),
+			})),
+		}
+	}
+}
+
+// Compiles GLSL to SPIR-V on the rayon thread pool, so the render loop keeps
+// running with the old shader until the new one is ready
+pub struct AsyncShaderCompiler;
+
+impl Default for AsyncShaderCompiler {
+	fn default() -> AsyncShaderCompiler {
+		AsyncShaderCompiler::new()
+	}
+}
+
+impl AsyncShaderCompiler {
+	pub fn new() -> AsyncShaderCompiler {
+		AsyncShaderCompiler
+	}
+
+	pub fn compile_request(&self, source: String, kind: ShaderKind) -> ShaderFuture {
+		let (sender, receiver) = crossbeam_channel::bounded(1);
+		rayon::spawn(move || {
+			let _ = sender.send(compile_glsl(&source, kind, "async_shader"));
+		});
+		ShaderFuture { receiver }
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct ReflectionError {
+	pub message: String,
+}
+
+impl fmt::Display for ReflectionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Shader reflection failed: {}", self.message)
+	}
+}
+
+impl std::error::Error for ReflectionError {}
+
+fn reflection_error<T>(message: impl Into<String>) -> Result<T, ReflectionError> {
+	Err(ReflectionError {
+		message: message.into(),
+	})
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VertexInputAttribute {
+	pub location: u32,
+	pub format: Format,
+	pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptorBinding {
+	pub set: u32,
+	pub binding: u32,
+	pub desc: DescriptorDesc,
+	pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PushConstantRange {
+	pub offset: usize,
+	pub size: usize,
+	pub stages: ShaderStages,
+}
+
+// Resources used by a shader as declared in its SPIR-V. The interfaces of the
+// stages of a pipeline are merged with `union`, the result implements
+// `PipelineLayoutDesc`: `PipelineLayoutCache::get_or_create` turns it into the
+// layout passed to the pipeline builders, instead of a layout written by hand.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShaderInterface {
+	pub vertex_inputs: Vec<VertexInputAttribute>,
+	pub descriptor_bindings: Vec<DescriptorBinding>,
+	pub push_constant_ranges: Vec<PushConstantRange>,
+}
+
+impl ShaderInterface {
+	// Vertex inputs are only kept from `self`, the shader that owns them
+	pub fn union(mut self, other: &ShaderInterface) -> ShaderInterface {
+		for binding in &other.descriptor_bindings {
+			match self
+				.descriptor_bindings
+				.iter_mut()
+				.find(|b| b.set == binding.set && b.binding == binding.binding)
+			{
+				Some(existing) => {
+					existing.desc.stages = existing.desc.stages | binding.desc.stages;
+					existing.desc.readonly &= binding.desc.readonly;
+				}
+				None => self.descriptor_bindings.push(binding.clone()),
+			}
+		}
+		self.descriptor_bindings.sort_by_key(|b| (b.set, b.binding));
+
+		for range in &other.push_constant_ranges {
+			match self
+				.push_constant_ranges
+				.iter_mut()
+				.find(|r| r.offset == range.offset && r.size == range.size)
+			{
+				Some(existing) => existing.stages = existing.stages | range.stages,
+				None => self.push_constant_ranges.push(*range),
+			}
+		}
+		self
+	}
+
+	fn binding(&self, set: usize, binding: usize) -> Option<&DescriptorBinding> {
+		self.descriptor_bindings
+			.iter()
+			.find(|b| b.set as usize == set && b.binding as usize == binding)
+	}
+}
+
+unsafe impl PipelineLayoutDesc for ShaderInterface {
+	fn num_sets(&self) -> usize {
+		self.descriptor_bindings
+			.iter()
+			.map(|b| b.set as usize + 1)
+			.max()
+			.unwrap_or(0)
+	}
+
+	fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
+		if set >= self.num_sets() {
+			return None;
+		}
+		Some(
+			self.descriptor_bindings
+				.iter()
+				.filter(|b| b.set as usize == set)
+				.map(|b| b.binding as usize + 1)
+				.max()
+				.unwrap_or(0),
+		)
+	}
+
+	fn descriptor(&self, set: usize, binding: usize) -> Option<DescriptorDesc> {
+		self.binding(set, binding).map(|b| b.desc.clone())
+	}
+
+	fn num_push_constants_ranges(&self) -> usize {
+		self.push_constant_ranges.len()
+	}
+
+	fn push_constants_range(&self, num: usize) -> Option<PipelineLayoutDescPcRange> {
+		self.push_constant_ranges.get(num).map(|range| PipelineLayoutDescPcRange {
+			offset: range.offset,
+			size: range.size,
+			stages: range.stages,
+		})
+	}
+}
+
+// The type instructions of a module, indexed by result id
+#[allow(clippy::type_complexity)]
+struct SpirvTypes<'a> {
+	types: HashMap<u32, &'a Instruction>,
+	decorations: HashMap<u32, Vec<(Decoration, Option<u32>)>>,
+	member_decorations: HashMap<(u32, u32), Vec<(Decoration, Option<u32>)>>,
+	names: HashMap<u32, String>,
+}
+
+fn id_operand(inst: &Instruction, index: usize) -> Result<u32, ReflectionError> {
+	match inst.operands.get(index) {
+		Some(Operand::IdRef(id)) => Ok(*id),
+		_ => reflection_error(format!("expected an id as operand {} of {:?}", index, inst.class.opcode)),
+	}
+}
+
+fn literal_operand(inst: &Instruction, index: usize) -> Result<u32, ReflectionError> {
+	match inst.operands.get(index) {
+		Some(Operand::LiteralInt32(value)) => Ok(*value),
+		_ => reflection_error(format!("expected a literal as operand {} of {:?}", index, inst.class.opcode)),
+	}
+}
+
+fn decoration_operands(operands: &[Operand]) -> Option<(Decoration, Option<u32>)> {
+	match operands {
+		[Operand::Decoration(decoration), Operand::LiteralInt32(value), ..] => Some((*decoration, Some(*value))),
+		[Operand::Decoration(decoration), ..] => Some((*decoration, None)),
+		_ => None,
+	}
+}
+
+impl<'a> SpirvTypes<'a> {
+	fn new(module: &'a Module) -> SpirvTypes<'a> {
+		let mut types = SpirvTypes {
+			types: HashMap::new(),
+			decorations: HashMap::new(),
+			member_decorations: HashMap::new(),
+			names: HashMap::new(),
+		};
+
+		for inst in &module.types_global_values {
+			if let Some(id) = inst.result_id {
+				types.types.insert(id, inst);
+			}
+		}
+		for inst in &module.annotations {
+			match (inst.class.opcode, inst.operands.as_slice()) {
+				(Op::Decorate, [Operand::IdRef(target), rest @ ..]) => {
+					if let Some(decoration) = decoration_operands(rest) {
+						types.decorations.entry(*target).or_default().push(decoration);
+					}
+				}
+				(Op::MemberDecorate, [Operand::IdRef(target), Operand::LiteralInt32(member), rest @ ..]) => {
+					if let Some(decoration) = decoration_operands(rest) {
+						types
+							.member_decorations
+							.entry((*target, *member))
+							.or_default()
+							.push(decoration);
+					}
+				}
+				_ => {}
+			}
+		}
+		for inst in &module.debugs {
+			if let (Op::Name, [Operand::IdRef(target), Operand::LiteralString(name)]) =
+				(inst.class.opcode, inst.operands.as_slice())
+			{
+				types.names.insert(*target, name.clone());
+			}
+		}
+		types
+	}
+
+	fn get(&self, id: u32) -> Result<&'a Instruction, ReflectionError> {
+		match self.types.get(&id) {
+			Some(inst) => Ok(inst),
+			None => reflection_error(format!("unknown type id {}", id)),
+		}
+	}
+
+	fn decoration(&self, id: u32, decoration: Decoration) -> Option<Option<u32>> {
+		self.decorations
+			.get(&id)?
+			.iter()
+			.find(|(d, _)| *d == decoration)
+			.map(|&(_, value)| value)
+	}
+
+	fn member_decoration(&self, id: u32, member: u32, decoration: Decoration) -> Option<Option<u32>> {
+		self.member_decorations
+			.get(&(id, member))?
+			.iter()
+			.find(|(d, _)| *d == decoration)
+			.map(|&(_, value)| value)
+	}
+
+	fn constant(&self, id: u32) -> Result<u32, ReflectionError> {
+		let inst = self.get(id)?;
+		match inst.class.opcode {
+			Op::Constant => literal_operand(inst, 0),
+			_ => reflection_error("array lengths must be constants"),
+		}
+	}
+
+	// Format of a vertex input, and the number of locations it takes
+	fn input_format(&self, id: u32) -> Result<(Format, u32), ReflectionError> {
+		let inst = self.get(id)?;
+		let (component, count) = match inst.class.opcode {
+			Op::TypeVector => (self.get(id_operand(inst, 0)?)?, literal_operand(inst, 1)?),
+			Op::TypeMatrix => {
+				let (format, _) = self.input_format(id_operand(inst, 0)?)?;
+				return Ok((format, literal_operand(inst, 1)?));
+			}
+			_ => (inst, 1),
+		};
+
+		use Format::*;
+		let formats = match (component.class.opcode, component.operands.as_slice()) {
+			(Op::TypeFloat, [Operand::LiteralInt32(32)]) => [R32Sfloat, R32G32Sfloat, R32G32B32Sfloat, R32G32B32A32Sfloat],
+			(Op::TypeInt, [Operand::LiteralInt32(32), Operand::LiteralInt32(1)]) => [R32Sint, R32G32Sint, R32G32B32Sint, R32G32B32A32Sint],
+			(Op::TypeInt, [Operand::LiteralInt32(32), Operand::LiteralInt32(0)]) => [R32Uint, R32G32Uint, R32G32B32Uint, R32G32B32A32Uint],
+			_ => return reflection_error("only 32 bit vertex inputs are supported"),
+		};
+		match formats.get(count as usize - 1) {
+			Some(&format) => Ok((format, 1)),
+			None => reflection_error(format!("invalid vector size {}", count)),
+		}
+	}
+
+	// Size in bytes of a type laid out with explicit offsets and strides (push constants)
+	fn size(&self, id: u32) -> Result<usize, ReflectionError> {
+		let inst = self.get(id)?;
+		match inst.class.opcode {
+			Op::TypeBool => Ok(4),
+			Op::TypeInt | Op::TypeFloat => Ok(literal_operand(inst, 0)? as usize / 8),
+			Op::TypeVector => Ok(self.size(id_operand(inst, 0)?)? * literal_operand(inst, 1)? as usize),
+			Op::TypeArray => {
+				let stride = match self.decoration(id, Decoration::ArrayStride) {
+					Some(Some(stride)) => stride as usize,
+					_ => self.size(id_operand(inst, 0)?)?,
+				};
+				Ok(stride * self.constant(id_operand(inst, 1)?)? as usize)
+			}
+			Op::TypeStruct => {
+				let mut size = 0;
+				for member in 0..inst.operands.len() {
+					let member_type = id_operand(inst, member)?;
+					let offset = self
+						.member_decoration(id, member as u32, Decoration::Offset)
+						.flatten()
+						.unwrap_or(size as u32) as usize;
+					let member_size = match self.get(member_type)?.class.opcode {
+						// Column major, each column is `MatrixStride` bytes apart
+						Op::TypeMatrix => {
+							let matrix = self.get(member_type)?;
+							let columns = literal_operand(matrix, 1)? as usize;
+							match self.member_decoration(id, member as u32, Decoration::MatrixStride) {
+								Some(Some(stride)) => stride as usize * columns,
+								_ => self.size(id_operand(matrix, 0)?)? * columns,
+							}
+						}
+						_ => self.size(member_type)?,
+					};
+					size = size.max(offset + member_size);
+				}
+				Ok(size)
+			}
+			opcode => reflection_error(format!("can't compute the size of {:?}", opcode)),
+		}
+	}
+
+	// Descriptor of a uniform variable whose pointee type is `id`
+	fn descriptor_desc(
+		&self,
+		id: u32,
+		storage_class: StorageClass,
+		stages: ShaderStages,
+	) -> Result<DescriptorDesc, ReflectionError> {
+		let mut inst = self.get(id)?;
+		let mut type_id = id;
+		let mut array_count = 1;
+		match inst.class.opcode {
+			Op::TypeArray => {
+				array_count = self.constant(id_operand(inst, 1)?)?;
+				type_id = id_operand(inst, 0)?;
+				inst = self.get(type_id)?;
+			}
+			Op::TypeRuntimeArray => return reflection_error("runtime descriptor arrays aren't supported"),
+			_ => {}
+		}
+
+		let buffer = |storage| {
+			DescriptorDescTy::Buffer(DescriptorBufferDesc {
+				dynamic: Some(false),
+				storage,
+			})
+		};
+		let (ty, readonly) = match inst.class.opcode {
+			Op::TypeSampler => (DescriptorDescTy::Sampler, true),
+			Op::TypeSampledImage => (
+				DescriptorDescTy::CombinedImageSampler(self.image_desc(self.get(id_operand(inst, 0)?)?)?),
+				true,
+			),
+			Op::TypeImage => {
+				let desc = self.image_desc(inst)?;
+				let readonly = desc.sampled;
+				(DescriptorDescTy::Image(desc), readonly)
+			}
+			Op::TypeStruct if storage_class == StorageClass::StorageBuffer => (buffer(true), false),
+			Op::TypeStruct if self.decoration(type_id, Decoration::BufferBlock).is_some() => (buffer(true), false),
+			Op::TypeStruct => (buffer(false), true),
+			opcode => return reflection_error(format!("unsupported descriptor type {:?}", opcode)),
+		};
+
+		Ok(DescriptorDesc {
+			ty,
+			array_count,
+			stages,
+			readonly,
+		})
+	}
+
+	fn image_desc(&self, inst: &Instruction) -> Result<DescriptorImageDesc, ReflectionError> {
+		let dimensions = match inst.operands.get(1) {
+			Some(Operand::Dim(Dim::Dim1D)) => DescriptorImageDescDimensions::OneDimensional,
+			Some(Operand::Dim(Dim::Dim2D)) => DescriptorImageDescDimensions::TwoDimensional,
+			Some(Operand::Dim(Dim::Dim3D)) => DescriptorImageDescDimensions::ThreeDimensional,
+			Some(Operand::Dim(Dim::DimCube)) => DescriptorImageDescDimensions::Cube,
+			_ => return reflection_error("unsupported image dimensions"),
+		};
+		let array_layers = match literal_operand(inst, 3)? {
+			0 => DescriptorImageDescArray::NonArrayed,
+			_ => DescriptorImageDescArray::Arrayed { max_layers: None },
+		};
+		Ok(DescriptorImageDesc {
+			// 1: sampled image, 2: storage image
+			sampled: literal_operand(inst, 5)? == 1,
+			dimensions,
+			format: None,
+			multisampled: literal_operand(inst, 4)? == 1,
+			array_layers,
+		})
+	}
+}
+
+fn stages_of(model: ExecutionModel) -> ShaderStages {
+	let mut stages = ShaderStages::none();
+	match model {
+		ExecutionModel::Vertex => stages.vertex = true,
+		ExecutionModel::TessellationControl => stages.tessellation_control = true,
+		ExecutionModel::TessellationEvaluation => stages.tessellation_evaluation = true,
+		ExecutionModel::Geometry => stages.geometry = true,
+		ExecutionModel::Fragment => stages.fragment = true,
+		ExecutionModel::GLCompute => stages.compute = true,
+		_ => {}
+	}
+	stages
+}
+
+// Extracts the interface of the `main` entry point of a SPIR-V module
+pub fn reflect(spirv: &[u32]) -> Result<ShaderInterface, ReflectionError> {
+	let module = match rspirv::dr::load_words(spirv) {
+		Ok(module) => module,
+		Err(e) => return reflection_error(format!("invalid SPIR-V: {:?}", e)),
+	};
+	let types = SpirvTypes::new(&module);
+
+	let entry_point = module
+		.entry_points
+		.iter()
+		.find(|inst| matches!(inst.operands.get(2), Some(Operand::LiteralString(name)) if name == "main"));
+	let (stages, interface): (ShaderStages, Vec<u32>) = match entry_point {
+		Some(inst) => match inst.operands.as_slice() {
+			[Operand::ExecutionModel(model), _, _, interface @ ..] => (
+				stages_of(*model),
+				interface
+					.iter()
+					.filter_map(|operand| match operand {
+						Operand::IdRef(id) => Some(*id),
+						_ => None,
+					})
+					.collect(),
+			),
+			_ => return reflection_error("malformed entry point"),
+		},
+		None => return reflection_error("no `main` entry point"),
+	};
+
+	let mut reflected = ShaderInterface::default();
+	for inst in &module.types_global_values {
+		let (id, storage_class) = match (inst.class.opcode, inst.result_id, inst.operands.first()) {
+			(Op::Variable, Some(id), Some(Operand::StorageClass(storage_class))) => (id, *storage_class),
+			_ => continue,
+		};
+		let pointer = types.get(inst.result_type.unwrap_or(0))?;
+		let pointee = id_operand(pointer, 1)?;
+
+		match storage_class {
+			StorageClass::Input if stages.vertex && interface.contains(&id) => {
+				if types.decoration(id, Decoration::BuiltIn).is_some() {
+					continue;
+				}
+				let location = match types.decoration(id, Decoration::Location) {
+					Some(Some(location)) => location,
+					_ => return reflection_error("vertex input without a location"),
+				};
+				let (format, locations) = types.input_format(pointee)?;
+				for i in 0..locations {
+					reflected.vertex_inputs.push(VertexInputAttribute {
+						location: location + i,
+						format,
+						name: types.names.get(&id).cloned(),
+					});
+				}
+			}
+			StorageClass::Uniform | StorageClass::UniformConstant | StorageClass::StorageBuffer => {
+				let (set, binding) = match (
+					types.decoration(id, Decoration::DescriptorSet),
+					types.decoration(id, Decoration::Binding),
+				) {
+					(Some(Some(set)), Some(Some(binding))) => (set, binding),
+					_ => return reflection_error("uniform without a descriptor set or binding"),
+				};
+				reflected.descriptor_bindings.push(DescriptorBinding {
+					set,
+					binding,
+					desc: types.descriptor_desc(pointee, storage_class, stages)?,
+					name: types.names.get(&id).cloned(),
+				});
+			}
+			StorageClass::PushConstant => {
+				reflected.push_constant_ranges.push(PushConstantRange {
+					offset: 0,
+					size: types.size(pointee)?,
+					stages,
+				});
+			}
+			_ => {}
+		}
+	}
+
+	reflected.vertex_inputs.sort_by_key(|input| input.location);
+	reflected.descriptor_bindings.sort_by_key(|b| (b.set, b.binding));
+	Ok(reflected)
+}
+
+// A compute pipeline from SPIR-V compiled at runtime, e.g. by
+// `AsyncShaderCompiler`. Its layout is reflected from the module and shared
+// through `layouts` with the pipelines declaring the same resources.
+pub fn compute_pipeline(
+	device: &Arc<Device>,
+	spirv: &[u32],
+	layouts: &mut PipelineLayoutCache,
+) -> Result<Arc<ComputePipeline<SharedPipelineLayout>>, VulkanoError> {
+	let interface = reflect(spirv)?;
+	let layout = layouts.get_or_create(&interface)?;
+	// Safe, `reflect` checked that the module has a `main` entry point and the
+	// layout is the one it declares
+	unsafe {
+		let module = ShaderModule::from_words(device.clone(), spirv).map_err(VulkanoError::ShaderLoad)?;
+		let main = CStr::from_bytes_with_nul_unchecked(b"main\0");
+		let entry_point = module.compute_entry_point::<(), _>(main, interface);
+		Ok(Arc::new(ComputePipeline::with_pipeline_layout(
+			device.clone(),
+			&entry_point,
+			&(),
+			layout,
+			None,
+		)?))
+	}
+}
+
+fn shader_extension(kind: ShaderKind) -> &'static str {
+	match kind {
+		ShaderKind::Vertex => "vert",
+		ShaderKind::Fragment => "frag",
+		ShaderKind::Compute => "comp",
+		ShaderKind::Geometry => "geom",
+		ShaderKind::TessControl => "tesc",
+		ShaderKind::TessEvaluation => "tese",
+		_ => "glsl",
+	}
+}
+
+struct LoadedShader {
+	module: Arc<ShaderModule>,
+	// The shader file and every file it includes
+	dependencies: Vec<PathBuf>,
+}
+
+// Shaders loaded by name from `<shader_dir>/<name>.<vert|frag|...>`, compiled
+// with shaderc so they can `#include "file.glsl"` from `shader_include_dir`
+// (quoted includes are first looked up next to the including file). The
+// SPIR-V is cached by the hash of the preprocessed source, so reloading a
+// shader whose includes didn't change in a meaningful way doesn't recompile it.
+pub struct ShaderLibrary {
+	device: Arc<Device>,
+	compiler: shaderc::Compiler,
+	shader_dir: PathBuf,
+	shader_include_dir: PathBuf,
+	spirv_cache: HashMap<u64, Arc<Vec<u32>>>,
+	modules: HashMap<(String, &'static str), LoadedShader>,
+}
+
+impl ShaderLibrary {
+	pub fn new(
+		device: Arc<Device>,
+		shader_dir: impl Into<PathBuf>,
+		shader_include_dir: impl Into<PathBuf>,
+	) -> Result<ShaderLibrary, CompileError> {
+		let compiler = shaderc::Compiler::new().ok_or_else(|| CompileError {
+			message: "failed to initialize shaderc".to_string(),
+		})?;
+		Ok(ShaderLibrary {
+			device,
+			compiler,
+			shader_dir: shader_dir.into(),
+			shader_include_dir: shader_include_dir.into(),
+			spirv_cache: HashMap::new(),
+			modules: HashMap::new(),
+		})
+	}
+
+	pub fn get_vertex_module(&mut self, name: &str) -> Result<Arc<ShaderModule>, VulkanoError> {
+		self.get_module(name, ShaderKind::Vertex)
+	}
+
+	pub fn get_fragment_module(&mut self, name: &str) -> Result<Arc<ShaderModule>, VulkanoError> {
+		self.get_module(name, ShaderKind::Fragment)
+	}
+
+	pub fn get_module(&mut self, name: &str, kind: ShaderKind) -> Result<Arc<ShaderModule>, VulkanoError> {
+		let key = (name.to_string(), shader_extension(kind));
+		if let Some(loaded) = self.modules.get(&key) {
+			return Ok(loaded.module.clone());
+		}
+
+		let loaded = self.load(name, kind)?;
+		let module = loaded.module.clone();
+		self.modules.insert(key, loaded);
+		Ok(module)
+	}
+
+	fn load(&mut self, name: &str, kind: ShaderKind) -> Result<LoadedShader, VulkanoError> {
+		let path = self.shader_dir.join(format!("{}.{}", name, shader_extension(kind)));
+		let source = std::fs::read_to_string(&path)?;
+
+		// Filled by the include callback, which can't borrow the library mutably
+		let includes = RefCell::new(Vec::new());
+		let include_dir = self.shader_include_dir.clone();
+		let mut options = shaderc::CompileOptions::new().ok_or_else(|| CompileError {
+			message: "failed to create the shaderc compile options".to_string(),
+		})?;
+		// Turns on the DEBUG_PRINT of debug.glsl
+		if cfg!(debug_assertions) {
+			options.add_macro_definition("DEBUG", None);
+		}
+		options.set_include_callback(|requested, include_type, requesting, _depth| {
+			let relative = match include_type {
+				IncludeType::Relative => Path::new(requesting).parent().map(|dir| dir.join(requested)),
+				IncludeType::Standard => None,
+			};
+			let resolved = relative
+				.filter(|path| path.is_file())
+				.unwrap_or_else(|| include_dir.join(requested));
+			let content = std::fs::read_to_string(&resolved)
+				.map_err(|e| format!("can't include {}: {}", resolved.display(), e))?;
+			includes.borrow_mut().push(resolved.clone());
+			Ok(ResolvedInclude {
+				resolved_name: resolved.to_string_lossy().into_owned(),
+				content,
+			})
+		});
+
+		let path_name = path.to_string_lossy();
+		let expanded = self
+			.compiler
+			.preprocess(&source, &path_name, "main", Some(&options))
+			.map_err(CompileError::from)?
+			.as_text();
+
+		let mut hasher = DefaultHasher::new();
+		shader_extension(kind).hash(&mut hasher);
+		expanded.hash(&mut hasher);
+		let hash = hasher.finish();
+
+		let spirv = match self.spirv_cache.get(&hash) {
+			Some(spirv) => spirv.clone(),
+			None => {
+				// The includes were already expanded
+				let artifact = self
+					.compiler
+					.compile_into_spirv(&expanded, kind, &path_name, "main", None)
+					.map_err(CompileError::from)?;
+				let spirv = Arc::new(artifact.as_binary().to_vec());
+				self.spirv_cache.insert(hash, spirv.clone());
+				spirv
+			}
+		};
+
+		// Safe as long as the SPIR-V is valid, which shaderc guarantees
+		let module =
+			unsafe { ShaderModule::from_words(self.device.clone(), &spirv) }.map_err(VulkanoError::ShaderLoad)?;
+
+		// Releases the callback's borrow of `includes`
+		drop(options);
+		let mut dependencies = vec![path.clone()];
+		dependencies.extend(includes.into_inner());
+		Ok(LoadedShader { module, dependencies })
+	}
+
+	// For the shader hot reload: forgets the modules depending on `changed`,
+	// and returns their names so the pipelines using them can be rebuilt and
+	// swapped in (the next `get_*_module` recompiles them)
+	pub fn invalidate(&mut self, changed: &Path) -> Vec<String> {
+		let changed = changed.canonicalize().unwrap_or_else(|_| changed.to_path_buf());
+		let is_changed = |path: &PathBuf| path.canonicalize().is_ok_and(|path| path == changed);
+
+		let stale: Vec<_> = self
+			.modules
+			.iter()
+			.filter(|(_, loaded)| loaded.dependencies.iter().any(is_changed))
+			.map(|(key, _)| key.clone())
+			.collect();
+		for key in &stale {
+			self.modules.remove(key);
+		}
+
+		let mut names: Vec<String> = stale.into_iter().map(|(name, _)| name).collect();
+		names.sort();
+		names.dedup();
+		names
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const VERTEX: &str = "
+		#version 450
+
+		layout(location = 0) in vec2 position;
+		layout(location = 1) in vec3 color;
+		layout(location = 2) in mat2 transform;
+
+		layout(set = 0, binding = 0) uniform Camera {
+			mat4 view_proj;
+		} camera;
+
+		layout(push_constant) uniform Object {
+			mat4 model;
+			vec4 tint;
+		} object;
+
+		layout(location = 0) out vec3 v_color;
+
+		void main() {
+			v_color = color * object.tint.rgb;
+			gl_Position = camera.view_proj * object.model * vec4(transform * position, 0.0, 1.0);
+		}
+	";
+
+	const FRAGMENT: &str = "
+		#version 450
+
+		layout(location = 0) in vec3 v_color;
+
+		layout(set = 0, binding = 1) uniform sampler2D albedo;
+
+		layout(set = 1, binding = 0) readonly buffer Lights {
+			vec4 lights[];
+		};
+
+		layout(push_constant) uniform Object {
+			mat4 model;
+			vec4 tint;
+		} object;
+
+		layout(location = 0) out vec4 f_color;
+
+		void main() {
+			f_color = texture(albedo, v_color.xy) * object.tint + lights[0];
+		}
+	";
+
+	fn stages(vertex: bool, fragment: bool) -> ShaderStages {
+		ShaderStages {
+			vertex,
+			fragment,
+			..ShaderStages::none()
+		}
+	}
+
+	fn reflect_glsl(source: &str, kind: ShaderKind) -> ShaderInterface {
+		reflect(&compile_glsl(source, kind, "fixture").unwrap()).unwrap()
+	}
+
+	#[test]
+	fn reflects_vertex_inputs() {
+		let interface = reflect_glsl(VERTEX, ShaderKind::Vertex);
+		let inputs: Vec<_> = interface.vertex_inputs.iter().map(|i| (i.location, i.format)).collect();
+		// A mat2 takes one location per column
+		assert_eq!(
+			inputs,
+			[
+				(0, Format::R32G32Sfloat),
+				(1, Format::R32G32B32Sfloat),
+				(2, Format::R32G32Sfloat),
+				(3, Format::R32G32Sfloat),
+			]
+		);
+		assert!(reflect_glsl(FRAGMENT, ShaderKind::Fragment).vertex_inputs.is_empty());
+	}
+
+	#[test]
+	fn reflects_descriptor_bindings() {
+		let vertex = reflect_glsl(VERTEX, ShaderKind::Vertex);
+		assert_eq!(vertex.descriptor_bindings.len(), 1);
+		let camera = &vertex.descriptor_bindings[0];
+		assert_eq!((camera.set, camera.binding), (0, 0));
+		assert_eq!(
+			camera.desc.ty,
+			DescriptorDescTy::Buffer(DescriptorBufferDesc {
+				dynamic: Some(false),
+				storage: false,
+			})
+		);
+		assert_eq!(camera.desc.stages, stages(true, false));
+
+		let fragment = reflect_glsl(FRAGMENT, ShaderKind::Fragment);
+		let bindings: Vec<_> = fragment.descriptor_bindings.iter().map(|b| (b.set, b.binding)).collect();
+		assert_eq!(bindings, [(0, 1), (1, 0)]);
+		match &fragment.descriptor_bindings[0].desc.ty {
+			DescriptorDescTy::CombinedImageSampler(image) => {
+				assert!(image.sampled);
+				assert_eq!(image.dimensions, DescriptorImageDescDimensions::TwoDimensional);
+			}
+			ty => panic!("albedo reflected as {:?}", ty),
+		}
+		assert_eq!(
+			fragment.descriptor_bindings[1].desc.ty,
+			DescriptorDescTy::Buffer(DescriptorBufferDesc {
+				dynamic: Some(false),
+				storage: true,
+			})
+		);
+	}
+
+	#[test]
+	fn reflects_push_constant_ranges() {
+		let vertex = reflect_glsl(VERTEX, ShaderKind::Vertex);
+		assert_eq!(
+			vertex.push_constant_ranges,
+			[PushConstantRange {
+				offset: 0,
+				size: 80,
+				stages: stages(true, false),
+			}]
+		);
+	}
+
+	#[test]
+	fn union_is_the_pipeline_layout() {
+		let layout = reflect_glsl(VERTEX, ShaderKind::Vertex).union(&reflect_glsl(FRAGMENT, ShaderKind::Fragment));
+		assert_eq!(layout.num_sets(), 2);
+		assert_eq!(layout.num_bindings_in_set(0), Some(2));
+		assert_eq!(layout.num_bindings_in_set(1), Some(1));
+		assert_eq!(layout.descriptor(0, 0).unwrap().stages, stages(true, false));
+		assert_eq!(layout.descriptor(0, 1).unwrap().stages, stages(false, true));
+		assert_eq!(layout.num_push_constants_ranges(), 1);
+		let range = layout.push_constants_range(0).unwrap();
+		assert_eq!((range.offset, range.size), (0, 80));
+		assert_eq!(range.stages, stages(true, true));
+	}
+
+	#[test]
+	fn rejects_invalid_spirv() {
+		assert!(reflect(&[0x0723_0203, 0, 0]).is_err());
+	}
+}
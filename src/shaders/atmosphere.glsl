@@ -0,0 +1,100 @@
+// Shared by the shaders of the precomputed atmosphere. Distances are in meters,
+// positions are relative to the center of the planet.
+
+layout(set = 0, binding = 0) uniform Atmosphere {
+	// RGB scattering at sea level, scale height in W
+	vec4 rayleigh;
+	// Scattering and extinction at sea level, scale height, Cornette-Shanks g
+	vec4 mie;
+	// RGB absorption at the peak density, altitude of the peak in W
+	vec4 ozone;
+	// Ground radius, top of the atmosphere radius, ozone layer half width
+	vec4 radii;
+} atmosphere;
+
+const float PI = 3.14159265359;
+
+struct Medium {
+	vec3 rayleigh_scattering;
+	float mie_scattering;
+	vec3 scattering;
+	vec3 extinction;
+};
+
+Medium medium(float altitude) {
+	float rayleigh_density = exp(-max(altitude, 0.0) / atmosphere.rayleigh.w);
+	float mie_density = exp(-max(altitude, 0.0) / atmosphere.mie.z);
+	float ozone_density = max(0.0, 1.0 - abs(altitude - atmosphere.ozone.w) / atmosphere.radii.z);
+
+	Medium m;
+	m.rayleigh_scattering = atmosphere.rayleigh.rgb * rayleigh_density;
+	m.mie_scattering = atmosphere.mie.x * mie_density;
+	m.scattering = m.rayleigh_scattering + vec3(m.mie_scattering);
+	m.extinction = m.rayleigh_scattering + vec3(atmosphere.mie.y * mie_density) + atmosphere.ozone.rgb * ozone_density;
+	return m;
+}
+
+float atmosphere_height() {
+	return atmosphere.radii.y - atmosphere.radii.x;
+}
+
+// Distance to the nearest intersection in front of the origin with the sphere
+// of `radius`, or -1
+float ray_sphere(vec3 origin, vec3 direction, float radius) {
+	float b = dot(origin, direction);
+	float c = dot(origin, origin) - radius * radius;
+	float discriminant = b * b - c;
+	if (discriminant < 0.0) {
+		return -1.0;
+	}
+	float root = sqrt(discriminant);
+	float near = -b - root;
+	float far = -b + root;
+	return near > 0.0 ? near : (far > 0.0 ? far : -1.0);
+}
+
+// The ray marches end on the ground, or leave the atmosphere
+float ray_length(vec3 origin, vec3 direction) {
+	float ground = ray_sphere(origin, direction, atmosphere.radii.x);
+	return ground > 0.0 ? ground : max(ray_sphere(origin, direction, atmosphere.radii.y), 0.0);
+}
+
+// The transmittance LUT is indexed by the cosine of the zenith angle and the
+// altitude, with more texels close to the ground
+vec2 transmittance_uv(float altitude, float cos_zenith) {
+	return vec2(cos_zenith * 0.5 + 0.5, sqrt(clamp(altitude / atmosphere_height(), 0.0, 1.0)));
+}
+
+vec3 sample_transmittance(sampler2D lut, vec3 position, vec3 direction) {
+	float radius = length(position);
+	float cos_zenith = dot(position / radius, direction);
+	return texture(lut, transmittance_uv(radius - atmosphere.radii.x, cos_zenith)).rgb;
+}
+
+// The multiple scattering LUT is indexed by the cosine of the sun zenith angle and the altitude
+vec2 multiple_scattering_uv(float altitude, float cos_sun_zenith) {
+	return vec2(cos_sun_zenith * 0.5 + 0.5, clamp(altitude / atmosphere_height(), 0.0, 1.0));
+}
+
+float rayleigh_phase(float mu) {
+	return 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+}
+
+float mie_phase(float mu, float g) {
+	float g2 = g * g;
+	return 3.0 / (8.0 * PI) * (1.0 - g2) * (1.0 + mu * mu) / ((2.0 + g2) * pow(1.0 + g2 - 2.0 * g * mu, 1.5));
+}
+
+// The sky view LUT is indexed by the azimuth relative to the sun, in [0, PI]
+// since the sky is symmetric, and the elevation, with more texels around the
+// horizon where the sky changes the fastest
+vec2 sky_view_uv(float azimuth, float elevation) {
+	float v = 0.5 + 0.5 * sign(elevation) * sqrt(abs(elevation) / (PI * 0.5));
+	return vec2(azimuth / PI, v);
+}
+
+void sky_view_angles(vec2 uv, out float azimuth, out float elevation) {
+	azimuth = uv.x * PI;
+	float l = uv.y * 2.0 - 1.0;
+	elevation = sign(l) * l * l * PI * 0.5;
+}
@@ -0,0 +1,117 @@
+// Traversal of a `Bvh` uploaded by `Bvh::upload`. Define BVH_SET before
+// including to bind its buffers to another set than 0, their bindings being
+// BVH_BINDING and the next one (0 and 1 by default).
+
+#ifndef BVH_SET
+#define BVH_SET 0
+#endif
+#ifndef BVH_BINDING
+#define BVH_BINDING 0
+#endif
+
+const int MAX_BVH_DEPTH = 32;
+
+struct BvhNode {
+	vec3 min;
+	// First child for inner nodes, the second is next to it, first triangle for leaves
+	uint left_or_first;
+	vec3 max;
+	// 0 for inner nodes
+	uint count;
+};
+
+struct BvhTriangle {
+	vec4 v0;
+	vec4 v1;
+	vec4 v2;
+};
+
+layout(set = BVH_SET, binding = BVH_BINDING) readonly buffer BvhNodes {
+	BvhNode bvh_nodes[];
+};
+layout(set = BVH_SET, binding = BVH_BINDING + 1) readonly buffer BvhTriangles {
+	BvhTriangle bvh_triangles[];
+};
+
+// Distance to the box if the ray enters it before `closest`, else -1
+float bvh_intersect_box(vec3 origin, vec3 inverse_direction, vec3 box_min, vec3 box_max, float closest) {
+	vec3 t0 = (box_min - origin) * inverse_direction;
+	vec3 t1 = (box_max - origin) * inverse_direction;
+	vec3 near = min(t0, t1);
+	vec3 far = max(t0, t1);
+	float enter = max(max(near.x, near.y), max(near.z, 0.0));
+	float exit = min(min(far.x, far.y), far.z);
+	return enter <= exit && enter < closest ? enter : -1.0;
+}
+
+// Möller–Trumbore, the distance along the ray or -1 when missed
+float bvh_intersect_triangle(vec3 origin, vec3 direction, BvhTriangle triangle) {
+	vec3 edge1 = triangle.v1.xyz - triangle.v0.xyz;
+	vec3 edge2 = triangle.v2.xyz - triangle.v0.xyz;
+	vec3 p = cross(direction, edge2);
+	float determinant = dot(edge1, p);
+	if (abs(determinant) < 1e-8) {
+		return -1.0;
+	}
+	float inverse_determinant = 1.0 / determinant;
+	vec3 s = origin - triangle.v0.xyz;
+	float u = dot(s, p) * inverse_determinant;
+	if (u < 0.0 || u > 1.0) {
+		return -1.0;
+	}
+	vec3 q = cross(s, edge1);
+	float v = dot(direction, q) * inverse_determinant;
+	if (v < 0.0 || u + v > 1.0) {
+		return -1.0;
+	}
+	float t = dot(edge2, q) * inverse_determinant;
+	return t > 1e-5 ? t : -1.0;
+}
+
+// The closest triangle hit before `max_distance`, -1 when none, with
+// `node_count` nodes in the hierarchy
+int bvh_trace(vec3 origin, vec3 direction, float max_distance, uint node_count, out float distance) {
+	vec3 inverse_direction = 1.0 / direction;
+	distance = max_distance;
+	int hit = -1;
+	uint stack[MAX_BVH_DEPTH];
+	int top = 0;
+	if (node_count > 0 && bvh_intersect_box(origin, inverse_direction, bvh_nodes[0].min, bvh_nodes[0].max, distance) >= 0.0) {
+		stack[top++] = 0;
+	}
+	while (top > 0) {
+		BvhNode node = bvh_nodes[stack[--top]];
+		if (node.count > 0) {
+			for (uint i = node.left_or_first; i < node.left_or_first + node.count; i++) {
+				float t = bvh_intersect_triangle(origin, direction, bvh_triangles[i]);
+				if (t > 0.0 && t < distance) {
+					distance = t;
+					hit = int(i);
+				}
+			}
+			continue;
+		}
+
+		// The nearest child is pushed last to be visited first
+		uint left = node.left_or_first;
+		float left_distance = bvh_intersect_box(origin, inverse_direction, bvh_nodes[left].min, bvh_nodes[left].max, distance);
+		float right_distance = bvh_intersect_box(origin, inverse_direction, bvh_nodes[left + 1].min, bvh_nodes[left + 1].max, distance);
+		bool left_first = left_distance >= 0.0 && (right_distance < 0.0 || left_distance <= right_distance);
+		uint near_child = left_first ? left : left + 1;
+		uint far_child = left_first ? left + 1 : left;
+		float far_distance = left_first ? right_distance : left_distance;
+		float near_distance = left_first ? left_distance : right_distance;
+		if (far_distance >= 0.0 && top < MAX_BVH_DEPTH) {
+			stack[top++] = far_child;
+		}
+		if (near_distance >= 0.0 && top < MAX_BVH_DEPTH) {
+			stack[top++] = near_child;
+		}
+	}
+	return hit;
+}
+
+vec3 bvh_triangle_normal(int triangle) {
+	BvhTriangle corners = bvh_triangles[triangle];
+	return normalize(cross(corners.v1.xyz - corners.v0.xyz, corners.v2.xyz - corners.v0.xyz));
+}
@@ -0,0 +1,56 @@
+// Soft shadows of the capsules of a `CapsuleShadowPass` under a directional
+// light. Define CAPSULE_SET and CAPSULE_BINDING before including to bind them
+// elsewhere than set 0, binding 1.
+
+#ifndef CAPSULE_SET
+#define CAPSULE_SET 0
+#endif
+#ifndef CAPSULE_BINDING
+#define CAPSULE_BINDING 1
+#endif
+
+const uint MAX_CAPSULES = 16;
+
+layout(set = CAPSULE_SET, binding = CAPSULE_BINDING) uniform Capsules {
+	// Start in xyz and radius in w
+	vec4 starts[MAX_CAPSULES];
+	vec4 ends[MAX_CAPSULES];
+	// Towards the light in xyz, tangent of its angular radius in w
+	vec4 light;
+	// Capsule count in x, then the start offset along the normal as bits in y
+	uvec4 count;
+} capsules;
+
+// Fraction of the light reaching `position`. The capsule axes are tested
+// against the ray towards the light, the penumbra widening with the distance
+// along the ray like the cone of an area light.
+float capsule_visibility(vec3 position, vec3 normal) {
+	vec3 l = capsules.light.xyz;
+	float tan_angle = capsules.light.w;
+	// The fragments of the capsules themselves would shadow their lit side
+	vec3 origin = position + normal * uintBitsToFloat(capsules.count.y);
+
+	float visibility = 1.0;
+	for (uint i = 0; i < min(capsules.count.x, MAX_CAPSULES); i++) {
+		vec3 a = capsules.starts[i].xyz;
+		vec3 ba = capsules.ends[i].xyz - a;
+		float radius = capsules.starts[i].w;
+
+		// Closest point of the axis to the ray
+		vec3 w = origin - a;
+		float b = dot(l, ba);
+		float c = dot(ba, ba);
+		float denominator = c - b * b;
+		float s = denominator > 1e-6 ? clamp((dot(ba, w) - b * dot(l, w)) / denominator, 0.0, 1.0) : 0.0;
+		vec3 closest = a + ba * s;
+
+		float t = dot(closest - origin, l);
+		if (t <= 0.0) {
+			continue;
+		}
+		float distance = length(origin + l * t - closest) - radius;
+		float cone = max(t * tan_angle, 1e-3);
+		visibility *= smoothstep(-cone, cone, distance);
+	}
+	return visibility;
+}
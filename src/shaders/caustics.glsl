@@ -0,0 +1,28 @@
+// Light focused by the water of a `CausticsPass` on the floor under it.
+// Define CAUSTICS_SET before including to bind the map and its uniform block to
+// another set than 2.
+
+#ifndef CAUSTICS_SET
+#define CAUSTICS_SET 2
+#endif
+
+layout(set = CAUSTICS_SET, binding = 0) uniform Caustics {
+	// World XZ of the corner of the map, its size and the height of the floor
+	vec4 floor;
+	// Color times intensity of the light in rgb, as in CausticsPass::uniform
+	vec4 light;
+} caustics;
+layout(set = CAUSTICS_SET, binding = 1) uniform sampler2D caustics_map;
+
+// Irradiance to add to the lighting at `position` on top of the unfocused
+// light, which the direct lighting already accounts for. The map only holds
+// the floor, receivers above it fade out.
+vec3 caustics_light(vec3 position, vec3 normal) {
+	vec2 uv = (position.xz - caustics.floor.xy) / caustics.floor.z;
+	if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0)))) {
+		return vec3(0.0);
+	}
+	float fade = clamp(1.0 - abs(position.y - caustics.floor.w), 0.0, 1.0) * max(normal.y, 0.0);
+	vec3 focused = textureLod(caustics_map, uv, 0.0).rgb - caustics.light.rgb;
+	return max(focused, vec3(0.0)) * fade;
+}
@@ -0,0 +1,10 @@
+// DEBUG_PRINT(value) prints a float or vec4 with debugPrintfEXT, logged by
+// ShaderDebugPrintf with the validation feature. DEBUG is defined by the
+// ShaderLibrary in debug builds, the prints compile to nothing in release.
+
+#ifdef DEBUG
+#extension GL_EXT_debug_printf : enable
+#define DEBUG_PRINT(value) debugPrintfEXT("[Printf] %v4f", vec4(value))
+#else
+#define DEBUG_PRINT(value)
+#endif
@@ -0,0 +1,27 @@
+#version 450
+
+layout(location = 0) in vec2 v_uv;
+layout(location = 1) in float v_alpha;
+
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D alpha_mask;
+
+layout(push_constant) uniform PushConstants {
+	mat4 view_projection;
+	float alpha_threshold;
+} pc;
+
+void main() {
+	vec4 texel = texture(alpha_mask, v_uv);
+	float alpha = texel.a * v_alpha;
+
+	// Sharpens the alpha into a one pixel wide ramp centered on the threshold,
+	// alpha to coverage turns the ramp into partially covered pixels the MSAA
+	// resolve smooths, instead of the blurry edges of the raw texture alpha
+	float coverage = (alpha - pc.alpha_threshold) / max(fwidth(alpha), 1e-4) + 0.5;
+	if (coverage <= 0.0) {
+		discard;
+	}
+	f_color = vec4(texel.rgb, clamp(coverage, 0.0, 1.0));
+}
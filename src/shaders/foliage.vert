@@ -0,0 +1,20 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in float alpha;
+
+layout(location = 0) out vec2 v_uv;
+layout(location = 1) out float v_alpha;
+
+// Shared with foliage.frag
+layout(push_constant) uniform PushConstants {
+	mat4 view_projection;
+	float alpha_threshold;
+} pc;
+
+void main() {
+	v_uv = uv;
+	v_alpha = alpha;
+	gl_Position = pc.view_projection * vec4(position, 1.0);
+}
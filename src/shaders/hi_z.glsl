@@ -0,0 +1,43 @@
+// The levels of a `HiZBuilder`, the farthest depth of the texels each texel
+// covers. Define HI_Z_SET and HI_Z_BINDING before including to bind them
+// elsewhere than set 0, binding 0.
+
+#ifndef HI_Z_SET
+#define HI_Z_SET 0
+#endif
+#ifndef HI_Z_BINDING
+#define HI_Z_BINDING 0
+#endif
+
+// The levels past the last of the chain are bound to the last one
+const int HI_Z_MAX_LEVELS = 12;
+
+layout(set = HI_Z_SET, binding = HI_Z_BINDING) uniform sampler2D hi_z[HI_Z_MAX_LEVELS];
+
+// The indices must be constant without the descriptor indexing features
+float hi_z_level(int level, vec2 uv) {
+	switch (level) {
+		case 0: return textureLod(hi_z[0], uv, 0.0).r;
+		case 1: return textureLod(hi_z[1], uv, 0.0).r;
+		case 2: return textureLod(hi_z[2], uv, 0.0).r;
+		case 3: return textureLod(hi_z[3], uv, 0.0).r;
+		case 4: return textureLod(hi_z[4], uv, 0.0).r;
+		case 5: return textureLod(hi_z[5], uv, 0.0).r;
+		case 6: return textureLod(hi_z[6], uv, 0.0).r;
+		case 7: return textureLod(hi_z[7], uv, 0.0).r;
+		case 8: return textureLod(hi_z[8], uv, 0.0).r;
+		case 9: return textureLod(hi_z[9], uv, 0.0).r;
+		case 10: return textureLod(hi_z[10], uv, 0.0).r;
+		default: return textureLod(hi_z[11], uv, 0.0).r;
+	}
+}
+
+// Farthest depth around `uv` at `lod`. A fractional LOD is rounded up to the
+// coarser level, which covers more texels and stays conservative.
+float sample_hi_z(vec2 uv, float lod) {
+	return hi_z_level(clamp(int(ceil(lod)), 0, HI_Z_MAX_LEVELS - 1), uv);
+}
+
+ivec2 hi_z_size() {
+	return textureSize(hi_z[0], 0);
+}
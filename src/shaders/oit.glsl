@@ -0,0 +1,21 @@
+// Outputs of the transparent geometry drawn in the accumulation subpass of
+// `OitPass`, weighted blended order-independent transparency.
+
+layout(location = 0) out vec4 oit_accum;
+layout(location = 1) out float oit_accum_weight;
+layout(location = 2) out float oit_revealage;
+
+// Equation 10 of McGuire and Bavoil, `view_depth` being the distance to the
+// camera along the view axis
+float oit_weight(float view_depth, float alpha) {
+	float z = abs(view_depth);
+	return alpha * clamp(10.0 / (1e-5 + pow(z / 5.0, 2.0) + pow(z / 200.0, 6.0)), 1e-2, 3e3);
+}
+
+// `color` isn't premultiplied
+void oit_output(vec4 color, float view_depth) {
+	float w = oit_weight(view_depth, color.a);
+	oit_accum = vec4(color.rgb * color.a * w, 0.0);
+	oit_accum_weight = color.a * w;
+	oit_revealage = color.a;
+}
@@ -0,0 +1,64 @@
+// Ambient irradiance interpolated between the probes of a `ProbeGrid`. Define
+// PROBE_GRID_SET before including to bind the grid to another set than 2.
+
+#ifndef PROBE_GRID_SET
+#define PROBE_GRID_SET 2
+#endif
+
+#define SH_NO_PROBE
+#include "sh.glsl"
+
+layout(set = PROBE_GRID_SET, binding = 0) uniform ProbeGrid {
+	// Corner of the scene bounds in xyz, range of the encoded coefficients in w
+	vec4 bounds_min;
+	// Opposite corner in xyz
+	vec4 bounds_max;
+	// Probes along each axis in xyz, as in ProbeGrid::uniform
+	vec4 dimensions;
+} probe_grid;
+// The 9 coefficients of each probe side by side along X, encoded in [0, 1]
+// over [-range, range], alpha 0 for the probes not captured yet
+layout(set = PROBE_GRID_SET, binding = 1) uniform sampler3D probe_coefficients;
+
+// Irradiance around `normal` at `position` divided by pi, to multiply by the
+// albedo. The coefficients of the 8 probes around `position` are blended
+// trilinearly, the probes without data left out.
+vec3 probe_grid_irradiance(vec3 position, vec3 normal) {
+	ivec3 dimensions = ivec3(probe_grid.dimensions.xyz);
+	vec3 cell = clamp(
+		(position - probe_grid.bounds_min.xyz) / (probe_grid.bounds_max.xyz - probe_grid.bounds_min.xyz),
+		0.0,
+		1.0
+	) * vec3(dimensions - 1);
+	ivec3 base = min(ivec3(floor(cell)), dimensions - 2);
+	vec3 t = cell - vec3(base);
+	float range = probe_grid.bounds_min.w;
+
+	vec3 coefficients[9];
+	for (int i = 0; i < 9; i++) {
+		coefficients[i] = vec3(0.0);
+	}
+	float total = 0.0;
+	for (int corner = 0; corner < 8; corner++) {
+		ivec3 offset = ivec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+		ivec3 probe = clamp(base + offset, ivec3(0), dimensions - 1);
+		vec3 weights = mix(1.0 - t, t, vec3(offset));
+		float weight = weights.x * weights.y * weights.z;
+		for (int i = 0; i < 9; i++) {
+			vec4 texel = texelFetch(probe_coefficients, ivec3(probe.x * 9 + i, probe.y, probe.z), 0);
+			// Every coefficient of a probe has the same alpha
+			if (i == 0) {
+				weight *= texel.a;
+			}
+			coefficients[i] += (texel.rgb * 2.0 - 1.0) * range * weight;
+		}
+		total += weight;
+	}
+	if (total <= 0.0) {
+		return vec3(0.0);
+	}
+	for (int i = 0; i < 9; i++) {
+		coefficients[i] /= total;
+	}
+	return sh_evaluate(coefficients, normal);
+}
@@ -0,0 +1,62 @@
+// Blends the local reflection captures of `ReflectionCapture` by distance.
+// Define REFLECTION_SET before including to bind them to another set than 1.
+
+#ifndef REFLECTION_SET
+#define REFLECTION_SET 1
+#endif
+
+// Must match MAX_BLENDED_CAPTURES and REFLECTION_MIP_LEVELS in reflection.rs
+const int MAX_BLENDED_CAPTURES = 3;
+const int REFLECTION_MIP_LEVELS = 4;
+
+layout(set = REFLECTION_SET, binding = 0) uniform ReflectionCaptures {
+	// World position of the capture, influence radius in W
+	vec4 spheres[MAX_BLENDED_CAPTURES];
+	uint count;
+} reflection_captures;
+
+// The mip levels of every capture, capture major
+layout(set = REFLECTION_SET, binding = 1) uniform samplerCube reflection_levels[MAX_BLENDED_CAPTURES * REFLECTION_MIP_LEVELS];
+
+// The level is per fragment, the sampler array can only be indexed by constants
+vec3 sample_reflection_level(int capture, int level, vec3 direction) {
+	int base = capture * REFLECTION_MIP_LEVELS;
+	switch (level) {
+	case 0:
+		return texture(reflection_levels[base], direction).rgb;
+	case 1:
+		return texture(reflection_levels[base + 1], direction).rgb;
+	case 2:
+		return texture(reflection_levels[base + 2], direction).rgb;
+	default:
+		return texture(reflection_levels[base + 3], direction).rgb;
+	}
+}
+
+// Radiance reflected towards `direction` at `position`, blurrier with the
+// roughness. The alpha is the total weight of the captures, 0 outside of all
+// of them, for the caller to fall back on the sky.
+vec4 sample_reflections(vec3 position, vec3 direction, float roughness) {
+	float lod = clamp(roughness, 0.0, 1.0) * float(REFLECTION_MIP_LEVELS - 1);
+	int level = int(floor(lod));
+	float blend = lod - float(level);
+
+	vec3 radiance = vec3(0.0);
+	float total = 0.0;
+	for (int i = 0; i < MAX_BLENDED_CAPTURES; i++) {
+		if (uint(i) >= reflection_captures.count) {
+			break;
+		}
+		vec4 sphere = reflection_captures.spheres[i];
+		float weight = clamp(1.0 - distance(position, sphere.xyz) / sphere.w, 0.0, 1.0);
+		// Fades out smoothly at the edge of the influence
+		weight = weight * weight * (3.0 - 2.0 * weight);
+		if (weight > 0.0) {
+			vec3 low = sample_reflection_level(i, level, direction);
+			vec3 high = sample_reflection_level(i, min(level + 1, REFLECTION_MIP_LEVELS - 1), direction);
+			radiance += mix(low, high, blend) * weight;
+			total += weight;
+		}
+	}
+	return total > 0.0 ? vec4(radiance / total, min(total, 1.0)) : vec4(0.0);
+}
@@ -0,0 +1,134 @@
+// Shared by the passes of `ReStirPass`: the lights and their alias table, the
+// camera, the G-buffer, and the reservoirs. A reservoir is stored in the four
+// channels of an RGBA32F texel: the selected light as bits, its unbiased
+// contribution weight W, the number of candidates M it stands for, and the
+// view space depth of its pixel to reject reuse across surfaces.
+
+struct PointLight {
+	vec3 position;
+	float radius;
+	vec3 color;
+	float intensity;
+};
+
+struct AliasEntry {
+	// Probability to keep the bucket's own light rather than its alias
+	float threshold;
+	uint alias;
+	// Probability to select the bucket's light, proportional to its power
+	float pdf;
+};
+
+layout(set = 0, binding = 0) readonly buffer Lights {
+	PointLight lights[];
+};
+layout(set = 0, binding = 1) readonly buffer AliasTable {
+	AliasEntry alias_table[];
+};
+layout(set = 0, binding = 2) uniform Camera {
+	mat4 view;
+	mat4 projection;
+	// Light count, frame index, candidates per pixel, 1 when the history is valid
+	uvec4 counts;
+	// Spatial radius in pixels, history length cap relative to the candidates,
+	// visibility thickness and ray length
+	vec4 params;
+} camera;
+layout(set = 0, binding = 3) uniform sampler2D depth;
+// View space normals, encoded in [0, 1]
+layout(set = 0, binding = 4) uniform sampler2D normals;
+
+const uint NO_LIGHT = 0xFFFFFFFFu;
+
+struct Reservoir {
+	uint light;
+	float W;
+	float M;
+	float depth;
+};
+
+Reservoir unpack_reservoir(vec4 texel) {
+	return Reservoir(floatBitsToUint(texel.x), texel.y, texel.z, texel.w);
+}
+
+vec4 pack_reservoir(Reservoir reservoir) {
+	return vec4(uintBitsToFloat(reservoir.light), reservoir.W, reservoir.M, reservoir.depth);
+}
+
+// The streaming state while reservoirs are combined
+struct Resampling {
+	uint light;
+	// Target function of the selected light at the pixel
+	float target;
+	float weight_sum;
+	float M;
+};
+
+Resampling start_resampling() {
+	return Resampling(NO_LIGHT, 0.0, 0.0, 0.0);
+}
+
+// Adds `reservoir`, whose light evaluates to `target` at the pixel
+void combine(inout Resampling resampling, Reservoir reservoir, float target, float random) {
+	float weight = target * reservoir.W * reservoir.M;
+	resampling.weight_sum += weight;
+	resampling.M += reservoir.M;
+	if (weight > 0.0 && random * resampling.weight_sum < weight) {
+		resampling.light = reservoir.light;
+		resampling.target = target;
+	}
+}
+
+Reservoir finish_resampling(Resampling resampling, float depth) {
+	float W = resampling.target > 0.0 ? resampling.weight_sum / (resampling.M * resampling.target) : 0.0;
+	return Reservoir(resampling.light, W, resampling.M, depth);
+}
+
+// PCG hash, the state is advanced at each call
+uint rng_state;
+
+void seed(ivec2 pixel, uint salt) {
+	rng_state = uint(pixel.x) * 1973u + uint(pixel.y) * 9277u + camera.counts.y * 26699u + salt * 104729u;
+}
+
+float random() {
+	rng_state = rng_state * 747796405u + 2891336453u;
+	uint word = ((rng_state >> ((rng_state >> 28u) + 4u)) ^ rng_state) * 277803737u;
+	return float((word >> 22u) ^ word) / 4294967296.0;
+}
+
+uint sample_light(out float pdf) {
+	uint count = camera.counts.x;
+	uint bucket = min(uint(random() * float(count)), count - 1);
+	AliasEntry entry = alias_table[bucket];
+	uint light = random() < entry.threshold ? bucket : entry.alias;
+	pdf = alias_table[light].pdf;
+	return light;
+}
+
+vec3 view_position(vec2 uv) {
+	vec4 position = inverse(camera.projection) * vec4(uv * 2.0 - 1.0, textureLod(depth, uv, 0.0).r, 1.0);
+	return position.xyz / position.w;
+}
+
+vec3 view_normal(vec2 uv) {
+	return normalize(textureLod(normals, uv, 0.0).xyz * 2.0 - 1.0);
+}
+
+// Unshadowed irradiance from the light, with the attenuation of the forward renderer
+vec3 light_irradiance(vec3 position, vec3 normal, uint index) {
+	PointLight light = lights[index];
+	vec3 to_light = (camera.view * vec4(light.position, 1.0)).xyz - position;
+	float distance = length(to_light);
+	float falloff = clamp(1.0 - pow(distance / light.radius, 4.0), 0.0, 1.0);
+	float attenuation = falloff * falloff / (distance * distance + 1.0);
+	float diffuse = max(dot(normal, to_light / max(distance, 1e-4)), 0.0);
+	return light.color * light.intensity * diffuse * attenuation;
+}
+
+float target_function(vec3 position, vec3 normal, uint light) {
+	if (light >= camera.counts.x) {
+		return 0.0;
+	}
+	return dot(light_irradiance(position, normal, light), vec3(0.2126, 0.7152, 0.0722));
+}
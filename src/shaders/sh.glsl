@@ -0,0 +1,44 @@
+// Diffuse ambient lighting from the 9 spherical harmonics coefficients of an
+// `SHProbe`. Define SH_SET before including to bind them to another set than 2,
+// or SH_NO_PROBE to only get `sh_evaluate` for coefficients from elsewhere.
+
+#ifndef SH_NO_PROBE
+#ifndef SH_SET
+#define SH_SET 2
+#endif
+
+layout(set = SH_SET, binding = 0) uniform SHProbe {
+	// RGB in xyz, in the order of SHProbe::coefficients
+	vec4 coefficients[9];
+} sh_probe;
+#endif
+
+// Irradiance around `normal` divided by pi, to multiply by the albedo.
+// Ramamoorthi and Hanrahan's formula, the bands convolved with the cosine lobe.
+vec3 sh_evaluate(vec3 coefficients[9], vec3 normal) {
+	const float A0 = 3.141593;
+	const float A1 = 2.094395;
+	const float A2 = 0.785398;
+	vec3 n = normal;
+	vec3 irradiance =
+		coefficients[0] * 0.282095 * A0
+		+ coefficients[1] * 0.488603 * n.y * A1
+		+ coefficients[2] * 0.488603 * n.z * A1
+		+ coefficients[3] * 0.488603 * n.x * A1
+		+ coefficients[4] * 1.092548 * n.x * n.y * A2
+		+ coefficients[5] * 1.092548 * n.y * n.z * A2
+		+ coefficients[6] * 0.315392 * (3.0 * n.z * n.z - 1.0) * A2
+		+ coefficients[7] * 1.092548 * n.x * n.z * A2
+		+ coefficients[8] * 0.546274 * (n.x * n.x - n.y * n.y) * A2;
+	return max(irradiance, vec3(0.0)) / 3.141593;
+}
+
+#ifndef SH_NO_PROBE
+vec3 sh_irradiance(vec3 normal) {
+	vec3 coefficients[9];
+	for (int i = 0; i < 9; i++) {
+		coefficients[i] = sh_probe.coefficients[i].rgb;
+	}
+	return sh_evaluate(coefficients, normal);
+}
+#endif
@@ -0,0 +1,39 @@
+#version 450
+#extension GL_KHR_shader_subgroup_basic : enable
+#extension GL_KHR_shader_subgroup_arithmetic : enable
+
+layout(local_size_x = 256) in;
+
+layout(set = 0, binding = 0) readonly buffer Input {
+	float values[];
+} input_data;
+
+// One partial result per workgroup, combined on the CPU
+layout(set = 0, binding = 1) buffer Output {
+	vec2 partials[];
+} output_data;
+
+// Enough room for the smallest subgroup size (8 lanes)
+shared vec2 subgroup_results[32];
+
+void main() {
+	float value = input_data.values[gl_GlobalInvocationID.x];
+
+	// x: sum, y: min
+	vec2 reduced = vec2(subgroupAdd(value), subgroupMin(value));
+	if (subgroupElect()) {
+		subgroup_results[gl_SubgroupID] = reduced;
+	}
+
+	barrier();
+
+	if (gl_SubgroupID == 0) {
+		vec2 partial = gl_SubgroupInvocationID < gl_NumSubgroups
+			? subgroup_results[gl_SubgroupInvocationID]
+			: vec2(0.0, 1.0 / 0.0);
+		partial = vec2(subgroupAdd(partial.x), subgroupMin(partial.y));
+		if (subgroupElect()) {
+			output_data.partials[gl_WorkGroupID.x] = partial;
+		}
+	}
+}
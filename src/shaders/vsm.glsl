@@ -0,0 +1,21 @@
+// Variance shadow map lookup. `moments` holds the depth and the squared depth
+// seen from the light, blurred, `light_clip` the receiver in the light's clip
+// space. Returns the Chebyshev upper bound of the lit fraction.
+// `min_variance` hides the acne of flat receivers, `bleed_reduction` cuts the
+// lowest bounds off to hide the light bleeding through overlapping casters.
+float vsm_visibility(sampler2D moments, vec4 light_clip, float min_variance, float bleed_reduction) {
+	vec3 ndc = light_clip.xyz / light_clip.w;
+	vec2 uv = ndc.xy * 0.5 + 0.5;
+	if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || ndc.z > 1.0) {
+		return 1.0;
+	}
+
+	vec2 m = texture(moments, uv).rg;
+	if (ndc.z <= m.x) {
+		return 1.0;
+	}
+	float variance = max(m.y - m.x * m.x, min_variance);
+	float d = ndc.z - m.x;
+	float p_max = variance / (variance + d * d);
+	return clamp((p_max - bleed_reduction) / (1.0 - bleed_reduction), 0.0, 1.0);
+}
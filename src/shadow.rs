@@ -0,0 +1,717 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageAccess, ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::{LABEL_COLOR_COMPUTE, LABEL_COLOR_RENDER_PASS};
+use crate::effects::{gaussian_weights, MAX_BLUR_RADIUS};
+use crate::error::VulkanoError;
+use crate::forward_plus::ForwardVertex;
+use crate::pipeline::{SharedComputePipeline, SharedPipeline};
+
+pub const VSM_FORMAT: Format = Format::R32G32Sfloat;
+const VSM_DEPTH_FORMAT: Format = Format::D16Unorm;
+
+mod moments_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 light_view_projection;
+				mat4 model;
+			} pc;
+
+			void main() {
+				gl_Position = pc.light_view_projection * pc.model * vec4(position, 1.0);
+			}
+		"
+	}
+}
+
+mod moments_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) out vec2 f_moments;
+
+			void main() {
+				float depth = gl_FragCoord.z;
+				// The second moment is biased by the slope of the depth over the pixel
+				float dx = dFdx(depth);
+				float dy = dFdy(depth);
+				f_moments = vec2(depth, depth * depth + 0.25 * (dx * dx + dy * dy));
+			}
+		"
+	}
+}
+
+mod moments_blur_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const int MAX_RADIUS = 32;
+
+			layout(set = 0, binding = 0, rg32f) uniform readonly image2D source;
+			layout(set = 0, binding = 1, rg32f) uniform writeonly image2D destination;
+
+			// Weights of the center and of the pixels at distance 1 to radius, packed by 4
+			layout(set = 0, binding = 2) uniform Weights {
+				vec4 weights[MAX_RADIUS / 4 + 1];
+				int radius;
+			} w;
+
+			layout(push_constant) uniform PushConstants {
+				// (1, 0) for the horizontal pass, (0, 1) for the vertical one
+				ivec2 direction;
+			} pc;
+
+			float weight(int i) {
+				return w.weights[i / 4][i % 4];
+			}
+
+			void main() {
+				ivec2 size = imageSize(source);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+
+				vec2 sum = imageLoad(source, pixel).rg * weight(0);
+				for (int i = 1; i <= w.radius; i++) {
+					vec2 before = imageLoad(source, clamp(pixel - pc.direction * i, ivec2(0), size - 1)).rg;
+					vec2 after = imageLoad(source, clamp(pixel + pc.direction * i, ivec2(0), size - 1)).rg;
+					sum += (before + after) * weight(i);
+				}
+				imageStore(destination, pixel, vec4(sum, 0.0, 0.0));
+			}
+		"
+	}
+}
+
+mod receiver_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+
+			layout(location = 0) out vec3 v_normal;
+			layout(location = 1) out vec4 v_light_clip;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view_projection;
+				mat4 light_view_projection;
+				// Towards the light
+				vec4 light_direction;
+			} camera;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+				float min_variance;
+				float bleed_reduction;
+			} pc;
+
+			void main() {
+				vec4 world = pc.model * vec4(position, 1.0);
+				v_normal = mat3(pc.model) * normal;
+				v_light_clip = camera.light_view_projection * world;
+				gl_Position = camera.view_projection * world;
+			}
+		"
+	}
+}
+
+mod receiver_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+
+			#include \"vsm.glsl\"
+
+			layout(location = 0) in vec3 v_normal;
+			layout(location = 1) in vec4 v_light_clip;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view_projection;
+				mat4 light_view_projection;
+				vec4 light_direction;
+			} camera;
+			layout(set = 0, binding = 1) uniform sampler2D shadow_map;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+				float min_variance;
+				float bleed_reduction;
+			} pc;
+
+			const float AMBIENT = 0.03;
+
+			void main() {
+				float diffuse = max(dot(normalize(v_normal), camera.light_direction.xyz), 0.0);
+				float visibility = vsm_visibility(shadow_map, v_light_clip, pc.min_variance, pc.bleed_reduction);
+				f_color = vec4(pc.albedo.rgb * (AMBIENT + diffuse * visibility), pc.albedo.a);
+			}
+		"
+	}
+}
+
+// A mesh drawn into the shadow map
+#[derive(Clone)]
+pub struct ShadowCaster {
+	pub vertices: Arc<CpuAccessibleBuffer<[ForwardVertex]>>,
+	pub indices: Arc<CpuAccessibleBuffer<[u32]>>,
+	pub model: [[f32; 4]; 4],
+}
+
+// Variance shadow map of a directional light. `render` draws the casters'
+// depth and squared depth from the light into an RG32F map and blurs it with
+// a separable gaussian, `draw` then lights the receivers with the Chebyshev
+// upper bound of the fraction of light reaching them, which gives filtered,
+// soft penumbrae instead of the hard edges of a depth comparison.
+pub struct VsmShadowMap {
+	framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+	moments: Arc<AttachmentImage<Format>>,
+	intermediate: Arc<StorageImage<Format>>,
+	moments_pipeline: SharedPipeline,
+	blur_pipeline: SharedComputePipeline,
+	receiver_pipeline: SharedPipeline,
+	weights: Arc<CpuAccessibleBuffer<moments_blur_cs::ty::Weights>>,
+	camera_pool: CpuBufferPool<receiver_vs::ty::Camera>,
+	sampler: Arc<Sampler>,
+	dynamic_state: DynamicState,
+	// Written by `render` for the draws of the same frame
+	receiver_set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+	size: u32,
+	device: Arc<Device>,
+	pub min_variance: f32,
+	// In [0, 1), higher values darken the penumbrae but hide more bleeding
+	pub bleed_reduction: f32,
+}
+
+impl VsmShadowMap {
+	// The receivers are drawn in `subpass`
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		size: u32,
+		sigma: f32,
+	) -> Result<VsmShadowMap, VulkanoError> {
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::single_pass_renderpass!(
+				device.clone(),
+				attachments: {
+					moments: {
+						load: Clear,
+						store: Store,
+						format: VSM_FORMAT,
+						samples: 1,
+					},
+					depth: {
+						load: Clear,
+						store: DontCare,
+						format: VSM_DEPTH_FORMAT,
+						samples: 1,
+					}
+				},
+				pass: {
+					color: [moments],
+					depth_stencil: {depth}
+				}
+			)?,
+		);
+
+		let dimensions = [size, size];
+		let moments = AttachmentImage::with_usage(
+			device.clone(),
+			dimensions,
+			VSM_FORMAT,
+			ImageUsage {
+				color_attachment: true,
+				storage: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+		)?;
+		let depth = AttachmentImage::transient(device.clone(), dimensions, VSM_DEPTH_FORMAT)?;
+		let framebuffer = Arc::new(
+			Framebuffer::start(render_pass.clone())
+				.add(ImageView::new(moments.clone())?)?
+				.add(ImageView::new(depth)?)?
+				.build()?,
+		);
+		let intermediate = StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim2d {
+				width: size,
+				height: size,
+				array_layers: 1,
+			},
+			VSM_FORMAT,
+			ImageUsage {
+				storage: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)?;
+
+		let moments_vs = moments_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let moments_fs = moments_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let moments_pipeline: SharedPipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<ForwardVertex>()
+				.vertex_shader(moments_vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(moments_fs.main_entry_point(), ())
+				.depth_stencil_simple_depth()
+				.render_pass(Subpass::from(render_pass, 0).ok_or(VulkanoError::NoSubpass)?)
+				.build(device.clone())?,
+		);
+
+		let receiver_vs = receiver_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let receiver_fs = receiver_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let has_depth = subpass.has_depth();
+		let builder = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<ForwardVertex>()
+			.vertex_shader(receiver_vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(receiver_fs.main_entry_point(), ());
+		let builder = if has_depth {
+			builder.depth_stencil_simple_depth()
+		} else {
+			builder
+		};
+		let receiver_pipeline: SharedPipeline = Arc::new(builder.render_pass(subpass).build(device.clone())?);
+
+		let blur_shader = moments_blur_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let blur_pipeline: SharedComputePipeline =
+			Arc::new(ComputePipeline::new(device.clone(), &blur_shader.main_entry_point(), &(), None)?);
+
+		// Filtering the moments is what makes the penumbrae soft
+		let sampler = Sampler::new(
+			device.clone(),
+			Filter::Linear,
+			Filter::Linear,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+
+		let dynamic_state = DynamicState {
+			viewports: Some(vec![Viewport {
+				origin: [0.0, 0.0],
+				dimensions: [size as f32, size as f32],
+				depth_range: 0.0..1.0,
+			}]),
+			..DynamicState::none()
+		};
+
+		Ok(VsmShadowMap {
+			framebuffer,
+			moments,
+			intermediate,
+			moments_pipeline,
+			blur_pipeline,
+			receiver_pipeline,
+			weights: VsmShadowMap::weights_buffer(&device, sigma)?,
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			sampler,
+			dynamic_state,
+			receiver_set: None,
+			size,
+			device,
+			min_variance: 0.00002,
+			bleed_reduction: 0.2,
+		})
+	}
+
+	pub fn set_sigma(&mut self, sigma: f32) -> Result<(), VulkanoError> {
+		self.weights = VsmShadowMap::weights_buffer(&self.device, sigma)?;
+		Ok(())
+	}
+
+	fn weights_buffer(
+		device: &Arc<Device>,
+		sigma: f32,
+	) -> Result<Arc<CpuAccessibleBuffer<moments_blur_cs::ty::Weights>>, VulkanoError> {
+		let weights = gaussian_weights(sigma);
+		let mut packed = [[0.0; 4]; MAX_BLUR_RADIUS / 4 + 1];
+		for (i, &weight) in weights.iter().enumerate() {
+			packed[i / 4][i % 4] = weight;
+		}
+		let buffer = CpuAccessibleBuffer::from_data(
+			device.clone(),
+			BufferUsage::uniform_buffer(),
+			false,
+			moments_blur_cs::ty::Weights {
+				weights: packed,
+				radius: weights.len() as i32 - 1,
+			},
+		)?;
+		Ok(buffer)
+	}
+
+	// The blurred moments of the last `render`
+	pub fn moments(&self) -> Arc<AttachmentImage<Format>> {
+		self.moments.clone()
+	}
+
+	// Records the casters and the blur outside of any render pass.
+	// `light_direction` points towards the light.
+	pub fn render(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		casters: &[ShadowCaster],
+		light_view_projection: [[f32; 4]; 4],
+		light_direction: [f32; 3],
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		// Nothing in front of the far plane, every receiver is lit
+		debug_label!(builder, "VsmShadowMap", LABEL_COLOR_RENDER_PASS);
+		builder.begin_render_pass(
+			self.framebuffer.clone(),
+			SubpassContents::Inline,
+			vec![[1.0, 1.0, 0.0, 0.0].into(), 1f32.into()],
+		)?;
+		for caster in casters {
+			builder.draw_indexed(
+				self.moments_pipeline.clone(),
+				&self.dynamic_state,
+				vec![caster.vertices.clone() as Arc<dyn BufferAccess + Send + Sync>],
+				caster.indices.clone(),
+				(),
+				moments_vs::ty::PushConstants {
+					light_view_projection,
+					model: caster.model,
+				},
+				vec![],
+			)?;
+		}
+		builder.end_render_pass()?;
+		debug_label!(builder);
+
+		self.blur(builder, self.moments.clone(), self.intermediate.clone(), [1, 0])?;
+		self.blur(builder, self.intermediate.clone(), self.moments.clone(), [0, 1])?;
+
+		let [x, y, z] = light_direction;
+		let camera = self.camera_pool.next(receiver_vs::ty::Camera {
+			view_projection,
+			light_view_projection,
+			light_direction: [x, y, z, 0.0],
+		})?;
+		let layout = self
+			.receiver_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		self.receiver_set = Some(Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_sampled_image(ImageView::new(self.moments.clone())?, self.sampler.clone())?
+				.build()?,
+		));
+		Ok(())
+	}
+
+	fn blur<S, D>(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		source: Arc<S>,
+		destination: Arc<D>,
+		direction: [i32; 2],
+	) -> Result<(), VulkanoError>
+	where
+		S: ImageAccess + Send + Sync + 'static,
+		D: ImageAccess + Send + Sync + 'static,
+	{
+		let layout = self
+			.blur_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(source)?)?
+				.add_image(ImageView::new(destination)?)?
+				.add_buffer(self.weights.clone())?
+				.build()?,
+		);
+		let groups = self.size.div_ceil(8);
+		debug_label!(builder, "VsmShadowMap blur", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[groups, groups, 1],
+			self.blur_pipeline.clone(),
+			set,
+			moments_blur_cs::ty::PushConstants { direction },
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+
+	// Records a receiver lit by the light of the last `render`, inside the render pass
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		vertices: Arc<CpuAccessibleBuffer<[ForwardVertex]>>,
+		indices: Arc<CpuAccessibleBuffer<[u32]>>,
+		model: [[f32; 4]; 4],
+		albedo: [f32; 4],
+	) -> Result<(), VulkanoError> {
+		let receiver_set = self
+			.receiver_set
+			.clone()
+			.expect("the shadow map must be rendered before drawing");
+		builder.draw_indexed(
+			self.receiver_pipeline.clone(),
+			dynamic_state,
+			vec![vertices as Arc<dyn BufferAccess + Send + Sync>],
+			indices,
+			receiver_set,
+			receiver_vs::ty::PushConstants {
+				model,
+				albedo,
+				min_variance: self.min_variance,
+				bleed_reduction: self.bleed_reduction,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+// Must match MAX_CAPSULES in src/shaders/capsule_shadow.glsl
+pub const MAX_CAPSULES: usize = 16;
+
+mod capsule_receiver_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+
+			layout(location = 0) out vec3 v_position;
+			layout(location = 1) out vec3 v_normal;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view_projection;
+			} camera;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+			} pc;
+
+			void main() {
+				vec4 world = pc.model * vec4(position, 1.0);
+				v_position = world.xyz;
+				v_normal = mat3(pc.model) * normal;
+				gl_Position = camera.view_projection * world;
+			}
+		"
+	}
+}
+
+mod capsule_receiver_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+
+			#include \"capsule_shadow.glsl\"
+
+			layout(location = 0) in vec3 v_position;
+			layout(location = 1) in vec3 v_normal;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 model;
+				vec4 albedo;
+			} pc;
+
+			const float AMBIENT = 0.03;
+
+			void main() {
+				vec3 normal = normalize(v_normal);
+				float diffuse = max(dot(normal, capsules.light.xyz), 0.0);
+				float visibility = capsule_visibility(v_position, normal);
+				f_color = vec4(pc.albedo.rgb * (AMBIENT + diffuse * visibility), pc.albedo.a);
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Capsule {
+	pub start: [f32; 3],
+	pub end: [f32; 3],
+	pub radius: f32,
+}
+
+impl Capsule {
+	// One capsule from every joint to its parent, `joints` being the world
+	// positions of a posed skeleton and `parents` the index of each joint's
+	// parent, `None` for the roots
+	pub fn along_bones(joints: &[[f32; 3]], parents: &[Option<usize>], radius: f32) -> Vec<Capsule> {
+		joints
+			.iter()
+			.zip(parents)
+			.filter_map(|(&end, parent)| {
+				parent.map(|parent| Capsule {
+					start: joints[parent],
+					end,
+					radius,
+				})
+			})
+			.collect()
+	}
+}
+
+// Cheap soft self-shadowing of characters: up to 16 capsules approximating
+// the limbs are uploaded every frame in a uniform buffer, and the receivers'
+// fragment shader tests the ray towards the light against each of them
+// analytically (src/shaders/capsule_shadow.glsl), multiplying the diffuse
+// term by the visibility. No shadow map is rendered.
+pub struct CapsuleShadowPass {
+	pipeline: SharedPipeline,
+	camera_pool: CpuBufferPool<capsule_receiver_vs::ty::Camera>,
+	capsule_pool: CpuBufferPool<capsule_receiver_fs::ty::Capsules>,
+	// Written by `update` for the draws of the same frame
+	set: Option<Arc<dyn DescriptorSet + Send + Sync>>,
+	// Tangent of the angular radius of the light, wider lights give wider penumbrae
+	pub light_size: f32,
+	// Along the normal, keeps the fragments on a capsule out of it
+	pub normal_offset: f32,
+}
+
+impl CapsuleShadowPass {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<CapsuleShadowPass, VulkanoError> {
+		let vs = capsule_receiver_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = capsule_receiver_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let has_depth = subpass.has_depth();
+		let builder = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<ForwardVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ());
+		let builder = if has_depth {
+			builder.depth_stencil_simple_depth()
+		} else {
+			builder
+		};
+		let pipeline: SharedPipeline = Arc::new(builder.render_pass(subpass).build(device.clone())?);
+
+		Ok(CapsuleShadowPass {
+			pipeline,
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			capsule_pool: CpuBufferPool::uniform_buffer(device),
+			set: None,
+			light_size: 0.1,
+			normal_offset: 0.02,
+		})
+	}
+
+	// Uploads the capsules of the frame, the ones past MAX_CAPSULES are
+	// ignored. `light_direction` is normalized, towards the light.
+	pub fn update(
+		&mut self,
+		capsules: &[Capsule],
+		view_projection: [[f32; 4]; 4],
+		light_direction: [f32; 3],
+	) -> Result<(), VulkanoError> {
+		let capsules = &capsules[..capsules.len().min(MAX_CAPSULES)];
+		let mut starts = [[0.0; 4]; MAX_CAPSULES];
+		let mut ends = [[0.0; 4]; MAX_CAPSULES];
+		for (i, capsule) in capsules.iter().enumerate() {
+			let [x, y, z] = capsule.start;
+			starts[i] = [x, y, z, capsule.radius];
+			let [x, y, z] = capsule.end;
+			ends[i] = [x, y, z, 0.0];
+		}
+		let [x, y, z] = light_direction;
+		let capsule_buffer = self.capsule_pool.next(capsule_receiver_fs::ty::Capsules {
+			starts,
+			ends,
+			light: [x, y, z, self.light_size],
+			count: [capsules.len() as u32, self.normal_offset.to_bits(), 0, 0],
+		})?;
+		let camera = self.camera_pool.next(capsule_receiver_vs::ty::Camera { view_projection })?;
+
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		self.set = Some(Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_buffer(capsule_buffer)?
+				.build()?,
+		));
+		Ok(())
+	}
+
+	// Records a receiver shadowed by the capsules of the last `update`, inside the render pass
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		vertices: Arc<CpuAccessibleBuffer<[ForwardVertex]>>,
+		indices: Arc<CpuAccessibleBuffer<[u32]>>,
+		model: [[f32; 4]; 4],
+		albedo: [f32; 4],
+	) -> Result<(), VulkanoError> {
+		let set = self.set.clone().expect("the capsules must be updated before drawing");
+		builder.draw_indexed(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![vertices as Arc<dyn BufferAccess + Send + Sync>],
+			indices,
+			set,
+			capsule_receiver_vs::ty::PushConstants { model, albedo },
+			vec![],
+		)?;
+		Ok(())
+	}
+}
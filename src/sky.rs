@@ -0,0 +1,979 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::create_dir_all;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+
+use log::*;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::glsl_shaders::fullscreen_vs;
+use crate::pipeline::{BufferlessPipeline, SharedComputePipeline};
+
+mod sky_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			// The six faces of the cube map, in the +X -X +Y -Y +Z -Z order
+			layout(set = 0, binding = 0, rgba16f) uniform writeonly image2DArray sky;
+
+			layout(push_constant) uniform PushConstants {
+				vec3 sun_direction;
+				float sun_intensity;
+			} pc;
+
+			const float PI = 3.14159265359;
+
+			// Distances in meters
+			const float EARTH_RADIUS = 6360e3;
+			const float ATMOSPHERE_RADIUS = 6420e3;
+			const float RAYLEIGH_SCALE_HEIGHT = 8e3;
+			// Scattering coefficients at sea level for 680, 550 and 440nm
+			const vec3 RAYLEIGH_BETA = vec3(5.8e-6, 13.5e-6, 33.1e-6);
+			// The ozone layer absorbs red and green, which keeps the sky blue at dusk
+			const vec3 OZONE_BETA = vec3(0.650e-6, 1.881e-6, 0.085e-6);
+			const float OZONE_CENTER = 25e3;
+			const float OZONE_WIDTH = 15e3;
+
+			const int VIEW_SAMPLES = 16;
+			const int LIGHT_SAMPLES = 8;
+
+			vec3 face_direction(uint face, vec2 uv) {
+				uv = uv * 2.0 - 1.0;
+				switch (face) {
+					case 0: return vec3(1.0, -uv.y, -uv.x);
+					case 1: return vec3(-1.0, -uv.y, uv.x);
+					case 2: return vec3(uv.x, 1.0, uv.y);
+					case 3: return vec3(uv.x, -1.0, -uv.y);
+					case 4: return vec3(uv.x, -uv.y, 1.0);
+					default: return vec3(-uv.x, -uv.y, -1.0);
+				}
+			}
+
+			// Distance to the exit of the atmosphere, from inside it
+			float atmosphere_exit(vec3 origin, vec3 direction) {
+				float b = dot(origin, direction);
+				float c = dot(origin, origin) - ATMOSPHERE_RADIUS * ATMOSPHERE_RADIUS;
+				return -b + sqrt(max(b * b - c, 0.0));
+			}
+
+			// Relative densities of the air (x) and of the ozone (y) at an altitude
+			vec2 density(float altitude) {
+				float rayleigh = exp(-altitude / RAYLEIGH_SCALE_HEIGHT);
+				float ozone = max(0.0, 1.0 - abs(altitude - OZONE_CENTER) / OZONE_WIDTH);
+				return vec2(rayleigh, ozone);
+			}
+
+			vec3 extinction(vec2 optical_depth) {
+				return RAYLEIGH_BETA * optical_depth.x + OZONE_BETA * optical_depth.y;
+			}
+
+			vec3 sky_color(vec3 direction) {
+				vec3 origin = vec3(0.0, EARTH_RADIUS + 1.0, 0.0);
+				float ray_length = atmosphere_exit(origin, direction);
+				float step_size = ray_length / float(VIEW_SAMPLES);
+
+				vec2 view_depth = vec2(0.0);
+				vec3 scattered = vec3(0.0);
+				for (int i = 0; i < VIEW_SAMPLES; i++) {
+					vec3 position = origin + direction * (float(i) + 0.5) * step_size;
+					float altitude = length(position) - EARTH_RADIUS;
+					vec2 local_density = density(altitude) * step_size;
+					view_depth += local_density;
+
+					// Light from the sun reaching the sample, unless the earth is in the way
+					float light_length = atmosphere_exit(position, pc.sun_direction);
+					float light_step = light_length / float(LIGHT_SAMPLES);
+					vec2 light_depth = vec2(0.0);
+					bool shadowed = false;
+					for (int j = 0; j < LIGHT_SAMPLES; j++) {
+						vec3 light_position = position + pc.sun_direction * (float(j) + 0.5) * light_step;
+						float light_altitude = length(light_position) - EARTH_RADIUS;
+						if (light_altitude < 0.0) {
+							shadowed = true;
+							break;
+						}
+						light_depth += density(light_altitude) * light_step;
+					}
+					if (shadowed) {
+						continue;
+					}
+
+					vec3 transmittance = exp(-extinction(view_depth + light_depth));
+					scattered += transmittance * local_density.x;
+				}
+
+				float mu = dot(direction, pc.sun_direction);
+				float rayleigh_phase = 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+				return pc.sun_intensity * rayleigh_phase * RAYLEIGH_BETA * scattered;
+			}
+
+			void main() {
+				ivec3 size = imageSize(sky);
+				if (gl_GlobalInvocationID.x >= size.x || gl_GlobalInvocationID.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(gl_GlobalInvocationID.xy) + 0.5) / vec2(size.xy);
+				vec3 direction = normalize(face_direction(gl_GlobalInvocationID.z, uv));
+				imageStore(sky, ivec3(gl_GlobalInvocationID), vec4(sky_color(direction), 1.0));
+			}
+		"
+	}
+}
+
+pub const SKY_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+// Sun directions closer than this are considered unchanged (cosine of the angle)
+const SUN_DIRECTION_EPSILON: f32 = 0.99999;
+
+// Sky radiance for every view direction, stored in a cube map. The compute pass
+// integrates the single Rayleigh scattering along each view ray and only runs
+// again when the sun has moved.
+// The sky is smooth enough that the cube map is used without any additional
+// filtering, bilinear sampling of a 128 texel face doesn't show any banding.
+pub struct AtmosphericSky {
+	pipeline: SharedComputePipeline,
+	cube_map: Arc<StorageImage<Format>>,
+	face_size: u32,
+	sun_intensity: f32,
+	last_sun_direction: Option<[f32; 3]>,
+}
+
+impl AtmosphericSky {
+	pub fn new(device: Arc<Device>, face_size: u32) -> Result<AtmosphericSky, VulkanoError> {
+		if !SKY_FORMAT
+			.properties(device.physical_device())
+			.optimal_tiling_features
+			.storage_image
+		{
+			return Err(VulkanoError::Unsupported("RGBA16F storage images"));
+		}
+
+		let shader = sky_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline = Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?);
+
+		let cube_map = StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim2d {
+				width: face_size,
+				height: face_size,
+				array_layers: 6,
+			},
+			SKY_FORMAT,
+			ImageUsage {
+				storage: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags {
+				cube_compatible: true,
+				..ImageCreateFlags::none()
+			},
+			device.active_queue_families(),
+		)?;
+
+		Ok(AtmosphericSky {
+			pipeline,
+			cube_map,
+			face_size,
+			sun_intensity: 22.0,
+			last_sun_direction: None,
+		})
+	}
+
+	// The six faces as layers, to be viewed as a cube map for sampling
+	pub fn cube_map(&self) -> Arc<StorageImage<Format>> {
+		self.cube_map.clone()
+	}
+
+	// Records the sky computation if `sun_direction` (normalized, pointing
+	// towards the sun) changed since the last update, outside of any render pass
+	pub fn update(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		sun_direction: [f32; 3],
+	) -> Result<bool, VulkanoError> {
+		if let Some(last) = self.last_sun_direction {
+			let cos = last[0] * sun_direction[0] + last[1] * sun_direction[1] + last[2] * sun_direction[2];
+			if cos > SUN_DIRECTION_EPSILON {
+				return Ok(false);
+			}
+		}
+
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(self.cube_map.clone())?)?
+				.build()?,
+		);
+
+		let groups = self.face_size.div_ceil(8);
+		debug_label!(builder, "AtmosphericSky", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[groups, groups, 6],
+			self.pipeline.clone(),
+			set,
+			sky_cs::ty::PushConstants {
+				sun_direction,
+				sun_intensity: self.sun_intensity,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+		self.last_sun_direction = Some(sun_direction);
+		Ok(true)
+	}
+}
+
+// Moves the sun along a circle tilted by the latitude, one turn per day.
+// At time 0 the sun rises in the east (+X), it is highest at a quarter of the day.
+pub struct SunController {
+	pub day_length: f32,
+	pub latitude: f32,
+	time: f32,
+}
+
+impl SunController {
+	// `day_length` in seconds, `latitude` in radians
+	pub fn new(day_length: f32, latitude: f32) -> SunController {
+		SunController {
+			day_length,
+			latitude,
+			time: 0.0,
+		}
+	}
+
+	pub fn advance(&mut self, dt: f32) {
+		self.time = (self.time + dt).rem_euclid(self.day_length);
+	}
+
+	pub fn set_time_of_day(&mut self, fraction: f32) {
+		self.time = fraction.rem_euclid(1.0) * self.day_length;
+	}
+
+	pub fn direction(&self) -> [f32; 3] {
+		let angle = self.time / self.day_length * std::f32::consts::PI * 2.0;
+		let (sin, cos) = angle.sin_cos();
+		// The sun path crosses the zenith at the equator
+		[cos, sin * self.latitude.cos(), sin * self.latitude.sin()]
+	}
+}
+
+mod transmittance_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+			#extension GL_GOOGLE_include_directive : require
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			#include \"atmosphere.glsl\"
+
+			layout(set = 0, binding = 1, rgba16f) uniform writeonly image2D transmittance;
+
+			const int STEPS = 40;
+
+			void main() {
+				ivec2 size = imageSize(transmittance);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+				float cos_zenith = uv.x * 2.0 - 1.0;
+				float altitude = uv.y * uv.y * atmosphere_height();
+
+				// Up to the top of the atmosphere, the ground shadow is handled by the users
+				vec3 origin = vec3(0.0, atmosphere.radii.x + altitude, 0.0);
+				vec3 direction = vec3(sqrt(max(1.0 - cos_zenith * cos_zenith, 0.0)), cos_zenith, 0.0);
+				float step_size = max(ray_sphere(origin, direction, atmosphere.radii.y), 0.0) / float(STEPS);
+
+				vec3 optical_depth = vec3(0.0);
+				for (int i = 0; i < STEPS; i++) {
+					vec3 position = origin + direction * (float(i) + 0.5) * step_size;
+					optical_depth += medium(length(position) - atmosphere.radii.x).extinction * step_size;
+				}
+				imageStore(transmittance, pixel, vec4(exp(-optical_depth), 1.0));
+			}
+		"
+	}
+}
+
+mod multiple_scattering_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+			#extension GL_GOOGLE_include_directive : require
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			#include \"atmosphere.glsl\"
+
+			layout(set = 0, binding = 1) uniform sampler2D transmittance_lut;
+			layout(set = 0, binding = 2, rgba16f) uniform writeonly image2D multiple_scattering;
+
+			// Directions on the sphere, squared
+			const int DIRECTIONS = 8;
+			const int STEPS = 20;
+
+			void main() {
+				ivec2 size = imageSize(multiple_scattering);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+				float cos_sun_zenith = uv.x * 2.0 - 1.0;
+				float altitude = uv.y * atmosphere_height();
+
+				vec3 origin = vec3(0.0, atmosphere.radii.x + altitude, 0.0);
+				vec3 sun = vec3(sqrt(max(1.0 - cos_sun_zenith * cos_sun_zenith, 0.0)), cos_sun_zenith, 0.0);
+				const float ISOTROPIC_PHASE = 1.0 / (4.0 * PI);
+
+				// Second order scattering reaching the point from every direction
+				// (luminance) and the fraction of light scattered again towards it
+				// (transfer), assuming an isotropic phase. Higher orders are the
+				// geometric series of the transfer, as in Hillaire 2020.
+				vec3 luminance = vec3(0.0);
+				vec3 transfer = vec3(0.0);
+				for (int i = 0; i < DIRECTIONS; i++) {
+					for (int j = 0; j < DIRECTIONS; j++) {
+						float cos_theta = 1.0 - 2.0 * (float(i) + 0.5) / float(DIRECTIONS);
+						float sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+						float phi = 2.0 * PI * (float(j) + 0.5) / float(DIRECTIONS);
+						vec3 direction = vec3(sin_theta * cos(phi), cos_theta, sin_theta * sin(phi));
+
+						float step_size = ray_length(origin, direction) / float(STEPS);
+						vec3 throughput = vec3(1.0);
+						for (int s = 0; s < STEPS; s++) {
+							vec3 position = origin + direction * (float(s) + 0.5) * step_size;
+							Medium m = medium(length(position) - atmosphere.radii.x);
+							vec3 step_transmittance = exp(-m.extinction * step_size);
+							// The scattering integrated analytically over the step
+							vec3 integral = (1.0 - step_transmittance) / max(m.extinction, vec3(1e-9));
+
+							float lit = ray_sphere(position, sun, atmosphere.radii.x) > 0.0 ? 0.0 : 1.0;
+							vec3 sun_light = sample_transmittance(transmittance_lut, position, sun) * lit;
+							luminance += throughput * m.scattering * sun_light * ISOTROPIC_PHASE * integral;
+							transfer += throughput * m.scattering * integral;
+							throughput *= step_transmittance;
+						}
+					}
+				}
+				// Uniform sphere samples, the isotropic phase cancels the solid angle
+				float samples = float(DIRECTIONS * DIRECTIONS);
+				luminance /= samples;
+				transfer /= samples;
+
+				imageStore(multiple_scattering, pixel, vec4(luminance / (1.0 - transfer), 1.0));
+			}
+		"
+	}
+}
+
+mod sky_view_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+			#extension GL_GOOGLE_include_directive : require
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			#include \"atmosphere.glsl\"
+
+			layout(set = 0, binding = 1) uniform sampler2D transmittance_lut;
+			layout(set = 0, binding = 2) uniform sampler2D multiple_scattering_lut;
+			layout(set = 0, binding = 3, rgba16f) uniform writeonly image2D sky_view;
+
+			layout(push_constant) uniform PushConstants {
+				// Elevation of the sun, in radians
+				float sun_elevation;
+				float camera_altitude;
+			} pc;
+
+			const int STEPS = 30;
+
+			void main() {
+				ivec2 size = imageSize(sky_view);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				float azimuth;
+				float elevation;
+				sky_view_angles((vec2(pixel) + 0.5) / vec2(size), azimuth, elevation);
+
+				// The sun is at azimuth 0
+				vec3 origin = vec3(0.0, atmosphere.radii.x + pc.camera_altitude, 0.0);
+				vec3 direction = vec3(cos(elevation) * cos(azimuth), sin(elevation), cos(elevation) * sin(azimuth));
+				vec3 sun = vec3(cos(pc.sun_elevation), sin(pc.sun_elevation), 0.0);
+				float mu = dot(direction, sun);
+				float rayleigh = rayleigh_phase(mu);
+				float mie = mie_phase(mu, atmosphere.mie.w);
+
+				float step_size = ray_length(origin, direction) / float(STEPS);
+				vec3 luminance = vec3(0.0);
+				vec3 throughput = vec3(1.0);
+				for (int i = 0; i < STEPS; i++) {
+					vec3 position = origin + direction * (float(i) + 0.5) * step_size;
+					float radius = length(position);
+					float altitude = radius - atmosphere.radii.x;
+					Medium m = medium(altitude);
+					vec3 step_transmittance = exp(-m.extinction * step_size);
+					vec3 integral = (1.0 - step_transmittance) / max(m.extinction, vec3(1e-9));
+
+					float lit = ray_sphere(position, sun, atmosphere.radii.x) > 0.0 ? 0.0 : 1.0;
+					vec3 sun_light = sample_transmittance(transmittance_lut, position, sun) * lit;
+					vec3 single = (m.rayleigh_scattering * rayleigh + m.mie_scattering * mie) * sun_light;
+					vec2 ms_uv = multiple_scattering_uv(altitude, dot(position / radius, sun));
+					vec3 multiple = texture(multiple_scattering_lut, ms_uv).rgb * m.scattering;
+
+					luminance += throughput * (single + multiple) * integral;
+					throughput *= step_transmittance;
+				}
+				imageStore(sky_view, pixel, vec4(luminance, 1.0));
+			}
+		"
+	}
+}
+
+mod precomputed_sky_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		include: ["src/shaders"],
+		src: "
+			#version 450
+			#extension GL_GOOGLE_include_directive : require
+
+			layout(location = 0) in vec2 v_uv;
+
+			layout(location = 0) out vec4 f_color;
+
+			#include \"atmosphere.glsl\"
+
+			layout(set = 0, binding = 1) uniform sampler2D transmittance_lut;
+			layout(set = 0, binding = 2) uniform sampler2D sky_view_lut;
+
+			layout(push_constant) uniform PushConstants {
+				// Of the view matrix without its translation
+				mat4 inverse_view_projection;
+				// Towards the sun, intensity in W
+				vec4 sun;
+				float camera_altitude;
+				float exposure;
+			} pc;
+
+			const float SUN_ANGULAR_RADIUS = 0.0047;
+			const float SUN_DISK_INTENSITY = 20.0;
+
+			void main() {
+				vec4 far = pc.inverse_view_projection * vec4(v_uv * 2.0 - 1.0, 1.0, 1.0);
+				vec3 direction = normalize(far.xyz / far.w);
+				vec3 sun = normalize(pc.sun.xyz);
+
+				float elevation = asin(clamp(direction.y, -1.0, 1.0));
+				vec2 horizontal = direction.xz / max(length(direction.xz), 1e-5);
+				vec2 sun_horizontal = sun.xz / max(length(sun.xz), 1e-5);
+				float azimuth = acos(clamp(dot(horizontal, sun_horizontal), -1.0, 1.0));
+				vec3 radiance = texture(sky_view_lut, sky_view_uv(azimuth, elevation)).rgb * pc.sun.w;
+
+				vec3 origin = vec3(0.0, atmosphere.radii.x + pc.camera_altitude, 0.0);
+				if (dot(direction, sun) > cos(SUN_ANGULAR_RADIUS) && ray_sphere(origin, direction, atmosphere.radii.x) < 0.0) {
+					radiance += sample_transmittance(transmittance_lut, origin, direction) * SUN_DISK_INTENSITY * pc.sun.w;
+				}
+
+				// The swapchain isn't HDR
+				f_color = vec4(1.0 - exp(-radiance * pc.exposure), 1.0);
+			}
+		"
+	}
+}
+
+pub const LUT_FORMAT: Format = Format::R16G16B16A16Sfloat;
+const LUT_TEXEL_SIZE: usize = 8;
+pub const TRANSMITTANCE_LUT_SIZE: [u32; 2] = [256, 64];
+pub const MULTIPLE_SCATTERING_LUT_SIZE: [u32; 2] = [32, 32];
+pub const SKY_VIEW_LUT_SIZE: [u32; 2] = [200, 100];
+
+// Bumped when the precomputation changes, to discard the cached LUTs
+const LUT_VERSION: u32 = 1;
+
+// Earth's atmosphere by default, distances in meters
+#[derive(Debug, Clone, Copy)]
+pub struct AtmosphereParameters {
+	pub ground_radius: f32,
+	pub top_radius: f32,
+	pub rayleigh_scattering: [f32; 3],
+	pub rayleigh_scale_height: f32,
+	pub mie_scattering: f32,
+	pub mie_extinction: f32,
+	pub mie_scale_height: f32,
+	pub mie_g: f32,
+	pub ozone_absorption: [f32; 3],
+	pub ozone_center: f32,
+	pub ozone_half_width: f32,
+}
+
+impl Default for AtmosphereParameters {
+	fn default() -> AtmosphereParameters {
+		AtmosphereParameters {
+			ground_radius: 6360e3,
+			top_radius: 6460e3,
+			rayleigh_scattering: [5.802e-6, 13.558e-6, 33.1e-6],
+			rayleigh_scale_height: 8e3,
+			mie_scattering: 3.996e-6,
+			mie_extinction: 4.44e-6,
+			mie_scale_height: 1.2e3,
+			mie_g: 0.8,
+			ozone_absorption: [0.65e-6, 1.881e-6, 0.085e-6],
+			ozone_center: 25e3,
+			ozone_half_width: 15e3,
+		}
+	}
+}
+
+impl AtmosphereParameters {
+	// Identifies the LUTs computed from these parameters
+	pub fn hash(&self) -> u64 {
+		let mut hasher = DefaultHasher::new();
+		LUT_VERSION.hash(&mut hasher);
+		let [r, g, b] = self.rayleigh_scattering;
+		let [or, og, ob] = self.ozone_absorption;
+		for value in &[
+			self.ground_radius,
+			self.top_radius,
+			r,
+			g,
+			b,
+			self.rayleigh_scale_height,
+			self.mie_scattering,
+			self.mie_extinction,
+			self.mie_scale_height,
+			self.mie_g,
+			or,
+			og,
+			ob,
+			self.ozone_center,
+			self.ozone_half_width,
+		] {
+			value.to_bits().hash(&mut hasher);
+		}
+		hasher.finish()
+	}
+
+	fn uniform(&self) -> transmittance_cs::ty::Atmosphere {
+		let [r, g, b] = self.rayleigh_scattering;
+		let [or, og, ob] = self.ozone_absorption;
+		transmittance_cs::ty::Atmosphere {
+			rayleigh: [r, g, b, self.rayleigh_scale_height],
+			mie: [self.mie_scattering, self.mie_extinction, self.mie_scale_height, self.mie_g],
+			ozone: [or, og, ob, self.ozone_center],
+			radii: [self.ground_radius, self.top_radius, self.ozone_half_width, 0.0],
+		}
+	}
+}
+
+const KTX_IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'1', b'1', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+const KTX_ENDIANNESS: u32 = 0x0403_0201;
+const GL_HALF_FLOAT: u32 = 0x140B;
+const GL_RGBA: u32 = 0x1908;
+const GL_RGBA16F: u32 = 0x881A;
+const KTX_HASH_KEY: &str = "atmosphere_hash";
+
+// KTX 1 with a single RGBA16F level, the parameter hash in the key/value data
+fn write_ktx(path: &Path, size: [u32; 2], texels: &[u8], hash: u64) -> std::io::Result<()> {
+	let mut key_value = format!("{}\0{}\0", KTX_HASH_KEY, hash).into_bytes();
+	let entry_size = key_value.len() as u32;
+	while key_value.len() % 4 != 0 {
+		key_value.push(0);
+	}
+
+	let mut file = Vec::with_capacity(64 + 4 + key_value.len() + 4 + texels.len());
+	file.extend_from_slice(&KTX_IDENTIFIER);
+	let header = [
+		KTX_ENDIANNESS,
+		GL_HALF_FLOAT,
+		2,
+		GL_RGBA,
+		GL_RGBA16F,
+		GL_RGBA,
+		size[0],
+		size[1],
+		0,
+		0,
+		1,
+		1,
+		4 + key_value.len() as u32,
+	];
+	for value in &header {
+		file.extend_from_slice(&value.to_le_bytes());
+	}
+	file.extend_from_slice(&entry_size.to_le_bytes());
+	file.extend_from_slice(&key_value);
+	file.extend_from_slice(&(texels.len() as u32).to_le_bytes());
+	file.extend_from_slice(texels);
+	std::fs::write(path, file)
+}
+
+// The texels of a file written by `write_ktx` with the same size and hash
+fn read_ktx(path: &Path, size: [u32; 2], hash: u64) -> Option<Vec<u8>> {
+	let file = std::fs::read(path).ok()?;
+	let word = |offset: usize| {
+		file.get(offset..offset + 4)
+			.map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	};
+	if file.get(..12)? != KTX_IDENTIFIER
+		|| word(12)? != KTX_ENDIANNESS
+		|| word(28)? != GL_RGBA16F
+		|| [word(36)?, word(40)?] != size
+	{
+		return None;
+	}
+
+	let key_value_end = 64 + word(60)? as usize;
+	let mut offset = 64;
+	let mut matches = false;
+	while offset + 4 <= key_value_end {
+		let entry_size = word(offset)? as usize;
+		let entry = file.get(offset + 4..offset + 4 + entry_size)?;
+		let mut parts = entry.split(|&byte| byte == 0);
+		if parts.next() == Some(KTX_HASH_KEY.as_bytes()) {
+			matches = parts.next() == Some(hash.to_string().as_bytes());
+		}
+		offset += 4 + entry_size.div_ceil(4) * 4;
+	}
+	if !matches {
+		return None;
+	}
+
+	let image_size = word(key_value_end)? as usize;
+	let expected = (size[0] * size[1]) as usize * LUT_TEXEL_SIZE;
+	if image_size != expected {
+		return None;
+	}
+	file.get(key_value_end + 4..key_value_end + 4 + image_size)
+		.map(|texels| texels.to_vec())
+}
+
+fn lut_image(device: &Arc<Device>, size: [u32; 2]) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width: size[0],
+			height: size[1],
+			array_layers: 1,
+		},
+		LUT_FORMAT,
+		ImageUsage {
+			storage: true,
+			sampled: true,
+			transfer_source: true,
+			transfer_destination: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+// Sky from lookup tables, following Hillaire's "A Scalable and Production
+// Ready Sky and Atmosphere Rendering Technique" (2020), which builds on
+// Bruneton's precomputed scattering. The transmittance and multiple scattering
+// LUTs only depend on the atmosphere, they are computed once and cached in
+// `cache_dir` as KTX files, tagged with the hash of the parameters. The sky
+// view LUT depends on the sun, it is small and recomputed when the sun moves.
+// The full screen pass then costs one texture fetch per pixel.
+pub struct PrecomputedAtmosphere {
+	sky_view_pipeline: SharedComputePipeline,
+	sky_view_set: Arc<dyn DescriptorSet + Send + Sync>,
+	sky_pipeline: Arc<BufferlessPipeline>,
+	sky_set: Arc<dyn DescriptorSet + Send + Sync>,
+	transmittance: Arc<StorageImage<Format>>,
+	multiple_scattering: Arc<StorageImage<Format>>,
+	last_sun_direction: Option<[f32; 3]>,
+	pub camera_altitude: f32,
+	pub sun_intensity: f32,
+	pub exposure: f32,
+}
+
+impl PrecomputedAtmosphere {
+	// Computing the LUTs waits for the GPU, to write them to the cache. The
+	// returned future uploads them when they were loaded from the cache instead.
+	pub fn new(
+		device: Arc<Device>,
+		queue: Arc<Queue>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		parameters: AtmosphereParameters,
+		cache_dir: &Path,
+	) -> Result<(PrecomputedAtmosphere, Box<dyn GpuFuture>), VulkanoError> {
+		let atmosphere =
+			CpuAccessibleBuffer::from_data(device.clone(), BufferUsage::uniform_buffer(), false, parameters.uniform())?;
+		let transmittance = lut_image(&device, TRANSMITTANCE_LUT_SIZE)?;
+		let multiple_scattering = lut_image(&device, MULTIPLE_SCATTERING_LUT_SIZE)?;
+		let sky_view = lut_image(&device, SKY_VIEW_LUT_SIZE)?;
+		let sampler = Sampler::new(
+			device.clone(),
+			Filter::Linear,
+			Filter::Linear,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+
+		let hash = parameters.hash();
+		let transmittance_path = cache_dir.join("transmittance.ktx");
+		let multiple_scattering_path = cache_dir.join("multiple_scattering.ktx");
+		let cached = (
+			read_ktx(&transmittance_path, TRANSMITTANCE_LUT_SIZE, hash),
+			read_ktx(&multiple_scattering_path, MULTIPLE_SCATTERING_LUT_SIZE, hash),
+		);
+
+		let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family())?;
+		let future = if let (Some(transmittance_texels), Some(multiple_scattering_texels)) = cached {
+			debug!("Loaded the atmosphere LUTs from {}", cache_dir.display());
+			let mut upload = |texels: Vec<u8>, image: Arc<StorageImage<Format>>| -> Result<(), VulkanoError> {
+				let staging = CpuAccessibleBuffer::from_iter(
+					device.clone(),
+					BufferUsage::transfer_source(),
+					false,
+					texels.into_iter(),
+				)?;
+				builder.copy_buffer_to_image(staging, image)?;
+				Ok(())
+			};
+			upload(transmittance_texels, transmittance.clone())?;
+			upload(multiple_scattering_texels, multiple_scattering.clone())?;
+			vulkano::sync::now(device.clone())
+				.then_execute(queue, builder.build()?)?
+				.boxed()
+		} else {
+			info!("Precomputing the atmosphere LUTs");
+			let groups = |[width, height]: [u32; 2]| [width.div_ceil(8), height.div_ceil(8), 1];
+
+			let shader = transmittance_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+			let pipeline = Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?);
+			let layout = pipeline
+				.descriptor_set_layout(0)
+				.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+			let set = Arc::new(
+				PersistentDescriptorSet::start(layout.clone())
+					.add_buffer(atmosphere.clone())?
+					.add_image(ImageView::new(transmittance.clone())?)?
+					.build()?,
+			);
+			debug_label!(builder, "PrecomputedAtmosphere transmittance", LABEL_COLOR_COMPUTE);
+			builder.dispatch(groups(TRANSMITTANCE_LUT_SIZE), pipeline, set, (), vec![])?;
+			debug_label!(builder);
+
+			let shader = multiple_scattering_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+			let pipeline = Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?);
+			let layout = pipeline
+				.descriptor_set_layout(0)
+				.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+			let set = Arc::new(
+				PersistentDescriptorSet::start(layout.clone())
+					.add_buffer(atmosphere.clone())?
+					.add_sampled_image(ImageView::new(transmittance.clone())?, sampler.clone())?
+					.add_image(ImageView::new(multiple_scattering.clone())?)?
+					.build()?,
+			);
+			debug_label!(builder, "PrecomputedAtmosphere multiple_scattering", LABEL_COLOR_COMPUTE);
+			builder.dispatch(groups(MULTIPLE_SCATTERING_LUT_SIZE), pipeline, set, (), vec![])?;
+			debug_label!(builder);
+
+			let readback = |[width, height]: [u32; 2]| {
+				CpuAccessibleBuffer::from_iter(
+					device.clone(),
+					BufferUsage::transfer_destination(),
+					true,
+					(0..width as usize * height as usize * LUT_TEXEL_SIZE).map(|_| 0u8),
+				)
+			};
+			let transmittance_readback = readback(TRANSMITTANCE_LUT_SIZE)?;
+			let multiple_scattering_readback = readback(MULTIPLE_SCATTERING_LUT_SIZE)?;
+			builder
+				.copy_image_to_buffer(transmittance.clone(), transmittance_readback.clone())?
+				.copy_image_to_buffer(multiple_scattering.clone(), multiple_scattering_readback.clone())?;
+
+			vulkano::sync::now(device.clone())
+				.then_execute(queue, builder.build()?)?
+				.then_signal_fence_and_flush()?
+				.wait(None)?;
+
+			let written = create_dir_all(cache_dir)
+				.and_then(|_| {
+					write_ktx(
+						&transmittance_path,
+						TRANSMITTANCE_LUT_SIZE,
+						&transmittance_readback.read().expect("read after the GPU finished"),
+						hash,
+					)
+				})
+				.and_then(|_| {
+					write_ktx(
+						&multiple_scattering_path,
+						MULTIPLE_SCATTERING_LUT_SIZE,
+						&multiple_scattering_readback.read().expect("read after the GPU finished"),
+						hash,
+					)
+				});
+			if let Err(e) = written {
+				warn!("Failed to cache the atmosphere LUTs: {}", e);
+			}
+			vulkano::sync::now(device.clone()).boxed()
+		};
+
+		let shader = sky_view_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let sky_view_pipeline: SharedComputePipeline =
+			Arc::new(ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)?);
+		let layout = sky_view_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let sky_view_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(atmosphere.clone())?
+				.add_sampled_image(ImageView::new(transmittance.clone())?, sampler.clone())?
+				.add_sampled_image(ImageView::new(multiple_scattering.clone())?, sampler.clone())?
+				.add_image(ImageView::new(sky_view.clone())?)?
+				.build()?,
+		);
+
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = precomputed_sky_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let sky_pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input(BufferlessDefinition {})
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+		let layout = sky_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let sky_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(atmosphere)?
+				.add_sampled_image(ImageView::new(transmittance.clone())?, sampler.clone())?
+				.add_sampled_image(ImageView::new(sky_view)?, sampler)?
+				.build()?,
+		);
+
+		let sky = PrecomputedAtmosphere {
+			sky_view_pipeline,
+			sky_view_set,
+			sky_pipeline,
+			sky_set,
+			transmittance,
+			multiple_scattering,
+			last_sun_direction: None,
+			camera_altitude: 200.0,
+			sun_intensity: 20.0,
+			exposure: 1.0,
+		};
+		Ok((sky, future))
+	}
+
+	pub fn transmittance_lut(&self) -> Arc<StorageImage<Format>> {
+		self.transmittance.clone()
+	}
+
+	pub fn multiple_scattering_lut(&self) -> Arc<StorageImage<Format>> {
+		self.multiple_scattering.clone()
+	}
+
+	// Records the sky view LUT if `sun_direction` (normalized, pointing towards
+	// the sun) changed since the last update, outside of any render pass
+	pub fn update(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		sun_direction: [f32; 3],
+	) -> Result<bool, VulkanoError> {
+		if let Some(last) = self.last_sun_direction {
+			let cos = last[0] * sun_direction[0] + last[1] * sun_direction[1] + last[2] * sun_direction[2];
+			if cos > SUN_DIRECTION_EPSILON {
+				return Ok(false);
+			}
+		}
+
+		let [width, height] = SKY_VIEW_LUT_SIZE;
+		debug_label!(builder, "PrecomputedAtmosphere sky_view", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[width.div_ceil(8), height.div_ceil(8), 1],
+			self.sky_view_pipeline.clone(),
+			self.sky_view_set.clone(),
+			sky_view_cs::ty::PushConstants {
+				sun_elevation: sun_direction[1].clamp(-1.0, 1.0).asin(),
+				camera_altitude: self.camera_altitude,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+		self.last_sun_direction = Some(sun_direction);
+		Ok(true)
+	}
+
+	// Records the sky over the whole framebuffer inside the current render
+	// pass, before the scene. `inverse_view_projection` must not contain the
+	// camera translation.
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		inverse_view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		let [x, y, z] = self.last_sun_direction.unwrap_or([0.0, 1.0, 0.0]);
+		builder.draw(
+			self.sky_pipeline.clone(),
+			dynamic_state,
+			BufferlessVertices {
+				vertices: 3,
+				instances: 1,
+			},
+			self.sky_set.clone(),
+			precomputed_sky_fs::ty::PushConstants {
+				inverse_view_projection,
+				sun: [x, y, z, self.sun_intensity],
+				camera_altitude: self.camera_altitude,
+				exposure: self.exposure,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,175 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use vulkano::device::RawDeviceExtensions;
+use vulkano::instance::{Instance, MemoryHeap, PhysicalDevice};
+
+use crate::text::SdfTextRenderer;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct HeapStats {
+	pub index: u32,
+	// Derived from the heap flags, DEVICE_LOCAL or HOST
+	pub name: String,
+	pub size: u64,
+	// From VK_EXT_memory_budget, None without the extension
+	pub budget: Option<u64>,
+	pub usage: Option<u64>,
+}
+
+fn heap_name(heap: &MemoryHeap) -> String {
+	// vulkano doesn't expose the MULTI_INSTANCE flag
+	if heap.is_device_local() { "DEVICE_LOCAL" } else { "HOST" }.to_owned()
+}
+
+// Polls the memory budget of every heap of the physical device, the latest
+// values being shared through `heaps` for the overlay to read.
+pub struct MemoryBudgetTracker {
+	instance: Arc<Instance>,
+	physical_index: usize,
+	budget_supported: bool,
+	heaps: Arc<RwLock<Vec<HeapStats>>>,
+}
+
+impl MemoryBudgetTracker {
+	pub fn new(physical: PhysicalDevice) -> MemoryBudgetTracker {
+		// Not part of vulkano's DeviceExtensions, looked up by name
+		let budget_supported = RawDeviceExtensions::supported_by_device(physical)
+			.iter()
+			.any(|name| name.to_bytes() == b"VK_EXT_memory_budget");
+		if !budget_supported {
+			info!("VK_EXT_memory_budget unavailable, the heap usage won't be displayed");
+		}
+		let tracker = MemoryBudgetTracker {
+			instance: physical.instance().clone(),
+			physical_index: physical.index(),
+			budget_supported,
+			heaps: Arc::new(RwLock::new(Vec::new())),
+		};
+		tracker.poll();
+		tracker
+	}
+
+	pub fn heaps(&self) -> Arc<RwLock<Vec<HeapStats>>> {
+		self.heaps.clone()
+	}
+
+	pub fn poll(&self) {
+		let physical = match PhysicalDevice::from_index(&self.instance, self.physical_index) {
+			Some(physical) => physical,
+			None => return,
+		};
+		let heaps = physical
+			.memory_heaps()
+			.map(|heap| {
+				let (budget, usage) = if self.budget_supported {
+					heap_budget(&physical, heap.id())
+				} else {
+					(None, None)
+				};
+				HeapStats {
+					index: heap.id(),
+					name: heap_name(&heap),
+					size: heap.size() as u64,
+					budget,
+					usage,
+				}
+			})
+			.collect();
+		*self.heaps.write().unwrap() = heaps;
+	}
+
+	// Polls every `interval` on a background thread, for as long as the
+	// application runs
+	pub fn spawn(self, interval: Duration) -> Arc<RwLock<Vec<HeapStats>>> {
+		let heaps = self.heaps();
+		thread::spawn(move || loop {
+			thread::sleep(interval);
+			self.poll();
+		});
+		heaps
+	}
+}
+
+// The budget and usage come from VkPhysicalDeviceMemoryBudgetPropertiesEXT,
+// chained to vkGetPhysicalDeviceMemoryProperties2. Vulkano doesn't expose that
+// query yet, so the usage stays unknown and the overlay shows n/a.
+fn heap_budget(_physical: &PhysicalDevice, _heap: u32) -> (Option<u64>, Option<u64>) {
+	(None, None)
+}
+
+fn format_bytes(bytes: u64) -> String {
+	const MB: u64 = 1024 * 1024;
+	const GB: u64 = 1024 * MB;
+	if bytes >= GB {
+		format!("{} GB", (bytes + GB / 2) / GB)
+	} else {
+		format!("{} MB", (bytes + MB / 2) / MB)
+	}
+}
+
+fn heap_line(heap: &HeapStats) -> String {
+	let prefix = format!("Heap {} ({})", heap.index, heap.name);
+	match heap.usage {
+		Some(usage) => {
+			let budget = heap.budget.unwrap_or(heap.size).max(1);
+			format!(
+				"{}: {} / {} ({}%)",
+				prefix,
+				format_bytes(usage),
+				format_bytes(budget),
+				usage * 100 / budget
+			)
+		}
+		None => format!("{}: n/a", prefix),
+	}
+}
+
+// Lines of the render statistics overlay, the heap lines being refreshed from
+// the tracker once per second
+pub struct StatsOverlay {
+	heaps: Arc<RwLock<Vec<HeapStats>>>,
+	lines: Vec<String>,
+	last_refresh: Option<Instant>,
+}
+
+impl StatsOverlay {
+	pub fn new(heaps: Arc<RwLock<Vec<HeapStats>>>) -> StatsOverlay {
+		StatsOverlay {
+			heaps,
+			lines: Vec::new(),
+			last_refresh: None,
+		}
+	}
+
+	pub fn update(&mut self) {
+		let now = Instant::now();
+		if self.last_refresh.is_some_and(|last| now - last < REFRESH_INTERVAL) {
+			return;
+		}
+		self.last_refresh = Some(now);
+
+		let heaps = self.heaps.read().unwrap();
+		self.lines = if heaps.is_empty() {
+			vec!["Heaps: n/a".to_owned()]
+		} else {
+			heaps.iter().map(heap_line).collect()
+		};
+	}
+
+	pub fn lines(&self) -> &[String] {
+		&self.lines
+	}
+
+	// Queues the lines from (`x`, `y`) downwards, in normalized device coordinates
+	pub fn draw(&self, text: &mut SdfTextRenderer, x: f32, y: f32, size: f32, color: [f32; 4]) {
+		for (i, line) in self.lines.iter().enumerate() {
+			text.draw_text(line, x, y + i as f32 * size * 1.2, size, color);
+		}
+	}
+}
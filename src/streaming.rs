@@ -0,0 +1,514 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::*;
+
+use crossbeam_channel::{Receiver, Sender};
+
+use vulkano::buffer::{BufferAccess, ImmutableBuffer, TypedBufferAccess};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageDimensions, ImmutableImage, MipmapsCount};
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::sync::GpuFuture;
+
+use crate::error::VulkanoError;
+use crate::geometry::{upload_buffers, MeshVertex};
+use crate::pipeline::SharedPipeline;
+
+pub type Texture = Arc<ImageView<Arc<ImmutableImage<Format>>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureHandle(usize);
+
+impl TextureHandle {
+	pub fn index(&self) -> usize {
+		self.0
+	}
+}
+
+struct DecodedTexture {
+	handle: TextureHandle,
+	path: PathBuf,
+	width: u32,
+	height: u32,
+	pixels: Vec<u8>,
+}
+
+pub struct TextureStreamer {
+	queue: Arc<Queue>,
+	placeholder: Texture,
+	textures: Vec<Texture>,
+	sender: Sender<DecodedTexture>,
+	receiver: Receiver<DecodedTexture>,
+}
+
+impl TextureStreamer {
+	pub fn new(queue: Arc<Queue>) -> Result<(TextureStreamer, Box<dyn GpuFuture>), VulkanoError> {
+		let (placeholder, future) = upload(&queue, 1, 1, vec![255u8; 4])?;
+		let (sender, receiver) = crossbeam_channel::unbounded();
+
+		let streamer = TextureStreamer {
+			queue,
+			placeholder,
+			textures: Vec::new(),
+			sender,
+			receiver,
+		};
+		Ok((streamer, future))
+	}
+
+	// Returns immediately, the handle points to the placeholder until the upload is done
+	pub fn request_texture(&mut self, path: &Path) -> TextureHandle {
+		let handle = TextureHandle(self.textures.len());
+		self.textures.push(self.placeholder.clone());
+
+		let sender = self.sender.clone();
+		let path = path.to_path_buf();
+		rayon::spawn(move || match image::open(&path) {
+			Ok(img) => {
+				let img = img.to_rgba();
+				let (width, height) = img.dimensions();
+				let decoded = DecodedTexture {
+					handle,
+					path,
+					width,
+					height,
+					pixels: img.into_raw(),
+				};
+				// The streamer may have been dropped in the meantime
+				let _ = sender.send(decoded);
+			}
+			Err(e) => error!("Failed to decode texture {}: {:?}", path.display(), e),
+		});
+
+		handle
+	}
+
+	// Uploads at most one decoded texture, to cap the stutter caused by streaming
+	pub fn process_uploads(&mut self) -> Result<Option<Box<dyn GpuFuture>>, VulkanoError> {
+		let decoded = match self.receiver.try_recv() {
+			Ok(decoded) => decoded,
+			Err(_) => return Ok(None),
+		};
+		let (texture, future) = upload(&self.queue, decoded.width, decoded.height, decoded.pixels)?;
+		self.textures[decoded.handle.index()] = texture;
+
+		debug!(
+			"Streamed texture {} ({}x{})",
+			decoded.path.display(),
+			decoded.width,
+			decoded.height
+		);
+		Ok(Some(future))
+	}
+
+	pub fn get(&self, handle: TextureHandle) -> Texture {
+		self.textures[handle.index()].clone()
+	}
+
+	// Every requested texture, indexed by handle, ready to be bound as a texture array
+	pub fn textures(&self) -> &[Texture] {
+		&self.textures
+	}
+}
+
+fn upload(queue: &Arc<Queue>, width: u32, height: u32, pixels: Vec<u8>) -> Result<(Texture, Box<dyn GpuFuture>), VulkanoError> {
+	let (image, future) = ImmutableImage::from_iter(
+		pixels.into_iter(),
+		ImageDimensions::Dim2d {
+			width,
+			height,
+			array_layers: 1,
+		},
+		MipmapsCount::One,
+		Format::R8G8B8A8Srgb,
+		queue.clone(),
+	)?;
+
+	Ok((ImageView::new(image)?, future.boxed()))
+}
+
+// LOD 0 is the full resolution mesh, LOD_COUNT - 1 the coarsest, always resident
+pub const LOD_COUNT: usize = 4;
+// Distance, in bounding radii, past which each of the finer LODs is replaced
+// by the next one
+const LOD_DISTANCES: [f32; LOD_COUNT - 1] = [8.0, 16.0, 32.0];
+// A camera moving further than this between two updates teleported
+pub const TELEPORT_DISTANCE: f32 = 50.0;
+
+// The LOD an object of bounding `radius` needs at `distance` from the camera
+pub fn select_lod(distance: f32, radius: f32) -> usize {
+	let distance = distance / radius.max(f32::EPSILON);
+	LOD_DISTANCES
+		.iter()
+		.position(|&limit| distance < limit)
+		.unwrap_or(LOD_COUNT - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LodObjectHandle(usize);
+
+impl LodObjectHandle {
+	pub fn index(&self) -> usize {
+		self.0
+	}
+}
+
+#[derive(Clone)]
+pub struct LodMesh {
+	pub vertices: Arc<ImmutableBuffer<[MeshVertex]>>,
+	pub indices: Arc<ImmutableBuffer<[u16]>>,
+}
+
+// Loads the vertices and indices of a LOD of an object, on a rayon thread
+pub type LodLoader = Arc<dyn Fn(LodObjectHandle, usize) -> Result<(Vec<MeshVertex>, Vec<u16>), String> + Send + Sync>;
+
+struct LodObject {
+	center: [f32; 3],
+	radius: f32,
+	lods: Vec<Option<LodMesh>>,
+	// The LOD drawn, read by the loading threads to give up on the LODs that
+	// aren't finer anymore
+	current_lod: Arc<AtomicUsize>,
+	// Drawn along `current_lod` during the crossfade frame
+	fade_from: Option<usize>,
+	pending: Option<usize>,
+}
+
+struct UploadedLod {
+	handle: LodObjectHandle,
+	lod: usize,
+	generation: usize,
+	mesh: Result<LodMesh, String>,
+}
+
+// One draw of `draws`: the fragments whose dither value falls in
+// `dither_range` are kept, the two LODs of a crossfade keep complementary halves
+pub struct LodDraw {
+	pub handle: LodObjectHandle,
+	pub lod: usize,
+	pub mesh: LodMesh,
+	pub dither_range: [f32; 2],
+}
+
+// Meshes with LOD_COUNT levels of detail, the coarsest uploaded when the
+// object is added and the finer ones loaded and uploaded on the rayon pool
+// when the camera gets close enough for `select_lod` to ask for them. Until
+// then the object is drawn with the closest resident LOD. A LOD change
+// crossfades for one frame, both LODs drawn dithered, and the LODs finer
+// than the one drawn are dropped. A teleported camera drops the fine LODs
+// right away and discards the uploads in flight, without crossfading.
+pub struct StreamingMeshLod {
+	queue: Arc<Queue>,
+	loader: LodLoader,
+	objects: Vec<LodObject>,
+	camera: Option<[f32; 3]>,
+	// Bumped on teleports, the uploads of a previous generation are dropped
+	generation: Arc<AtomicUsize>,
+	sender: Sender<UploadedLod>,
+	receiver: Receiver<UploadedLod>,
+}
+
+impl StreamingMeshLod {
+	pub fn new(queue: Arc<Queue>, loader: LodLoader) -> StreamingMeshLod {
+		let (sender, receiver) = crossbeam_channel::unbounded();
+		StreamingMeshLod {
+			queue,
+			loader,
+			objects: Vec::new(),
+			camera: None,
+			generation: Arc::new(AtomicUsize::new(0)),
+			sender,
+			receiver,
+		}
+	}
+
+	// Uploads the coarsest LOD, the object can be drawn once the future is signaled
+	pub fn add_object(
+		&mut self,
+		center: [f32; 3],
+		radius: f32,
+		coarsest: (Vec<MeshVertex>, Vec<u16>),
+	) -> Result<(LodObjectHandle, Box<dyn GpuFuture>), VulkanoError> {
+		let (vertices, indices, future) = upload_buffers(&self.queue, &coarsest.0, &coarsest.1)?;
+		let mut lods = vec![None; LOD_COUNT];
+		lods[LOD_COUNT - 1] = Some(LodMesh { vertices, indices });
+		self.objects.push(LodObject {
+			center,
+			radius,
+			lods,
+			current_lod: Arc::new(AtomicUsize::new(LOD_COUNT - 1)),
+			fade_from: None,
+			pending: None,
+		});
+		Ok((LodObjectHandle(self.objects.len() - 1), future))
+	}
+
+	pub fn current_lod(&self, handle: LodObjectHandle) -> usize {
+		self.objects[handle.index()].current_lod.load(Ordering::Acquire)
+	}
+
+	// Call once per frame before `draws`
+	pub fn update(&mut self, camera: [f32; 3]) {
+		let teleported = self
+			.camera
+			.replace(camera)
+			.is_some_and(|previous| distance(previous, camera) > TELEPORT_DISTANCE);
+		if teleported {
+			debug!("Camera teleported, dropping the fine mesh LODs");
+			self.generation.fetch_add(1, Ordering::AcqRel);
+		}
+		self.receive_uploads();
+
+		for index in 0..self.objects.len() {
+			let object = &mut self.objects[index];
+			let desired = select_lod(distance(object.center, camera), object.radius);
+			object.fade_from = None;
+			if teleported {
+				object.pending = None;
+				for lod in object.lods.iter_mut().take(desired) {
+					*lod = None;
+				}
+			}
+
+			// The closest resident LOD, finer ones first: they look better than
+			// needed until the desired one is uploaded, coarser ones pop
+			let displayed = (0..=desired)
+				.rev()
+				.chain(desired + 1..LOD_COUNT)
+				.find(|&lod| object.lods[lod].is_some())
+				.unwrap_or(LOD_COUNT - 1);
+			let previous = object.current_lod.swap(displayed, Ordering::AcqRel);
+			if previous != displayed && !teleported && object.lods[previous].is_some() {
+				object.fade_from = Some(previous);
+			}
+			// Kept for the crossfade frame, dropped at the next update
+			for lod in 0..displayed {
+				if Some(lod) != object.fade_from {
+					object.lods[lod] = None;
+				}
+			}
+
+			if object.lods[desired].is_none() && object.pending.is_none() {
+				object.pending = Some(desired);
+				self.request(LodObjectHandle(index), desired);
+			}
+		}
+	}
+
+	// The draws of the frame, two for the objects crossfading
+	pub fn draws(&self) -> Vec<LodDraw> {
+		let mut draws = Vec::new();
+		for (index, object) in self.objects.iter().enumerate() {
+			let handle = LodObjectHandle(index);
+			let current = object.current_lod.load(Ordering::Acquire);
+			let mesh = match &object.lods[current] {
+				Some(mesh) => mesh.clone(),
+				None => continue,
+			};
+			match object.fade_from.and_then(|lod| object.lods[lod].clone().map(|mesh| (lod, mesh))) {
+				Some((lod, previous)) => {
+					draws.push(LodDraw {
+						handle,
+						lod,
+						mesh: previous,
+						dither_range: [0.5, 1.0],
+					});
+					draws.push(LodDraw {
+						handle,
+						lod: current,
+						mesh,
+						dither_range: [0.0, 0.5],
+					});
+				}
+				None => draws.push(LodDraw {
+					handle,
+					lod: current,
+					mesh,
+					dither_range: [0.0, 1.0],
+				}),
+			}
+		}
+		draws
+	}
+
+	fn request(&self, handle: LodObjectHandle, lod: usize) {
+		let loader = self.loader.clone();
+		let queue = self.queue.clone();
+		let sender = self.sender.clone();
+		let current_lod = self.objects[handle.index()].current_lod.clone();
+		let generation = self.generation.clone();
+		let requested_generation = generation.load(Ordering::Acquire);
+		rayon::spawn(move || {
+			// The object may have moved away or the camera teleported meanwhile,
+			// the result is still sent to clear the request
+			let stale = || generation.load(Ordering::Acquire) != requested_generation;
+			let mesh = if stale() || current_lod.load(Ordering::Acquire) == lod {
+				Err("no longer needed".to_string())
+			} else {
+				loader(handle, lod).and_then(|(vertices, indices)| upload_and_wait(&queue, &vertices, &indices))
+			};
+			// The streamer may have been dropped in the meantime
+			let _ = sender.send(UploadedLod {
+				handle,
+				lod,
+				generation: requested_generation,
+				mesh,
+			});
+		});
+	}
+
+	fn receive_uploads(&mut self) {
+		let generation = self.generation.load(Ordering::Acquire);
+		for uploaded in self.receiver.try_iter() {
+			let object = &mut self.objects[uploaded.handle.index()];
+			if uploaded.generation != generation {
+				continue;
+			}
+			object.pending = None;
+			match uploaded.mesh {
+				Ok(mesh) => {
+					debug!("Streamed LOD {} of mesh {}", uploaded.lod, uploaded.handle.index());
+					object.lods[uploaded.lod] = Some(mesh);
+				}
+				Err(e) => debug!("LOD {} of mesh {} wasn't streamed: {}", uploaded.lod, uploaded.handle.index(), e),
+			}
+		}
+	}
+}
+
+// The Queue is synchronized internally, the loading threads submit their own
+// uploads and wait for them so the render thread only swaps the buffers
+fn upload_and_wait(queue: &Arc<Queue>, vertices: &[MeshVertex], indices: &[u16]) -> Result<LodMesh, String> {
+	let (vertices, indices, future) = upload_buffers(queue, vertices, indices).map_err(|e| e.to_string())?;
+	future
+		.then_signal_fence_and_flush()
+		.and_then(|fence| fence.wait(None))
+		.map_err(|e| e.to_string())?;
+	Ok(LodMesh { vertices, indices })
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+	((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+mod lod_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+			layout(location = 2) in vec2 uv;
+
+			layout(location = 0) out vec3 v_normal;
+
+			// Shared with lod_fs
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+				vec2 dither_range;
+			} pc;
+
+			void main() {
+				v_normal = normal;
+				gl_Position = pc.view_projection * vec4(position, 1.0);
+			}
+		"
+	}
+}
+
+mod lod_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 v_normal;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+				vec2 dither_range;
+			} pc;
+
+			// 4x4 Bayer matrix, thresholds in [0, 1)
+			const float BAYER[16] = float[](
+				0.0, 8.0, 2.0, 10.0,
+				12.0, 4.0, 14.0, 6.0,
+				3.0, 11.0, 1.0, 9.0,
+				15.0, 7.0, 13.0, 5.0
+			);
+
+			void main() {
+				ivec2 pixel = ivec2(gl_FragCoord.xy) % 4;
+				float dither = BAYER[pixel.y * 4 + pixel.x] / 16.0;
+				if (dither < pc.dither_range.x || dither >= pc.dither_range.y) {
+					discard;
+				}
+				float light = max(dot(normalize(v_normal), normalize(vec3(0.4, 1.0, 0.3))), 0.0);
+				f_color = vec4(vec3(0.15 + 0.85 * light), 1.0);
+			}
+		"
+	}
+}
+
+// Draws the LodDraws of StreamingMeshLod, in a subpass with a depth attachment
+pub struct LodMeshRenderer {
+	pipeline: SharedPipeline,
+}
+
+impl LodMeshRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<LodMeshRenderer, VulkanoError> {
+		let vs = lod_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = lod_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<MeshVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ())
+			.cull_mode_back()
+			.depth_stencil_simple_depth()
+			.render_pass(subpass)
+			.build(device)?;
+
+		Ok(LodMeshRenderer {
+			pipeline: Arc::new(pipeline),
+		})
+	}
+
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		draws: &[LodDraw],
+		view_projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		for draw in draws.iter().filter(|draw| draw.mesh.indices.len() > 0) {
+			builder.draw_indexed(
+				self.pipeline.clone(),
+				dynamic_state,
+				vec![draw.mesh.vertices.clone() as Arc<dyn BufferAccess + Send + Sync>],
+				draw.mesh.indices.clone(),
+				(),
+				lod_vs::ty::PushConstants {
+					view_projection,
+					dither_range: draw.dither_range,
+				},
+				vec![],
+			)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use vulkano::swapchain::PresentMode;
+
+use winit::window::Window;
+
+// Decides when the swapchain has to be recreated.
+// An out of date swapchain (or a resize) is recreated on the next frame, a
+// suboptimal one is kept for the frame that reported it and recreated at the
+// start of the following frame, before acquiring an image.
+pub struct SwapchainMonitor {
+	frame: u64,
+	suboptimal_frame: Option<u64>,
+	invalidated: bool,
+	recreations: u32,
+}
+
+impl Default for SwapchainMonitor {
+	fn default() -> SwapchainMonitor {
+		SwapchainMonitor::new()
+	}
+}
+
+impl SwapchainMonitor {
+	pub fn new() -> SwapchainMonitor {
+		SwapchainMonitor {
+			frame: 0,
+			suboptimal_frame: None,
+			invalidated: false,
+			recreations: 0,
+		}
+	}
+
+	// Call at the beginning of every frame, before acquiring the next image
+	pub fn begin_frame(&mut self) -> bool {
+		self.frame += 1;
+		self.should_recreate()
+	}
+
+	pub fn should_recreate(&self) -> bool {
+		self.invalidated || self.suboptimal_frame.is_some_and(|frame| self.frame > frame)
+	}
+
+	// The swapchain can't be used anymore (out of date, window resized)
+	pub fn invalidate(&mut self) {
+		self.invalidated = true;
+	}
+
+	// The acquired image is still presentable but the swapchain should be recreated
+	pub fn report_suboptimal(&mut self) {
+		if self.suboptimal_frame.is_none() {
+			debug!("Swapchain became suboptimal on frame {}", self.frame);
+			self.suboptimal_frame = Some(self.frame);
+		}
+	}
+
+	pub fn recreated(&mut self) {
+		self.invalidated = false;
+		self.suboptimal_frame = None;
+		self.recreations += 1;
+		info!("Swapchain recreated ({} recreations so far)", self.recreations);
+	}
+
+	pub fn recreation_count(&self) -> u32 {
+		self.recreations
+	}
+}
+
+// Batches the stream of resize events sent while the window is being dragged
+// into a single swapchain recreation
+pub struct SwapchainResizeDebouncer {
+	last_resize: Option<Instant>,
+	delay: Duration,
+}
+
+impl Default for SwapchainResizeDebouncer {
+	fn default() -> SwapchainResizeDebouncer {
+		SwapchainResizeDebouncer::new()
+	}
+}
+
+impl SwapchainResizeDebouncer {
+	pub fn new() -> SwapchainResizeDebouncer {
+		SwapchainResizeDebouncer::with_delay(Duration::from_millis(16))
+	}
+
+	pub fn with_delay(delay: Duration) -> SwapchainResizeDebouncer {
+		SwapchainResizeDebouncer {
+			last_resize: None,
+			delay,
+		}
+	}
+
+	pub fn resized(&mut self) {
+		self.last_resize = Some(Instant::now());
+	}
+
+	// Whether enough time went by since the last resize event to recreate the swapchain
+	pub fn settled(&self) -> bool {
+		self.last_resize
+			.is_none_or(|last_resize| last_resize.elapsed() >= self.delay)
+	}
+}
+
+// Refresh rate assumed when the monitor doesn't report its video modes
+const FALLBACK_REFRESH_RATE: u16 = 60;
+
+// The refresh period of the monitor the window is on. winit doesn't report the
+// current video mode, the fastest mode at the monitor's resolution is close
+// enough outside of exclusive fullscreen.
+pub fn refresh_period_ms(window: &Window) -> f32 {
+	let refresh_rate = window
+		.current_monitor()
+		.and_then(|monitor| {
+			let size = monitor.size();
+			monitor
+				.video_modes()
+				.filter(|mode| mode.size() == size)
+				.map(|mode| mode.refresh_rate())
+				.max()
+		})
+		.filter(|&rate| rate > 0)
+		.unwrap_or(FALLBACK_REFRESH_RATE);
+	1000.0 / refresh_rate as f32
+}
+
+// Number of frame times averaged before deciding to switch modes
+const PRESENT_MODE_WINDOW: usize = 120;
+
+// Picks Mailbox when the GPU renders frames well within the refresh period and
+// falls back to Fifo when they get close to it. The gap between the two
+// thresholds, and the frame window being restarted after every switch, keep it
+// from oscillating between the modes.
+pub struct PresentModeAdaptor {
+	refresh_period_ms: f32,
+	mailbox_supported: bool,
+	mode: PresentMode,
+	frame_times: VecDeque<f32>,
+}
+
+impl PresentModeAdaptor {
+	pub fn new(refresh_period_ms: f32, mailbox_supported: bool) -> PresentModeAdaptor {
+		PresentModeAdaptor {
+			refresh_period_ms,
+			mailbox_supported,
+			mode: PresentMode::Fifo,
+			frame_times: VecDeque::with_capacity(PRESENT_MODE_WINDOW),
+		}
+	}
+
+	pub fn mode(&self) -> PresentMode {
+		self.mode
+	}
+
+	// Returns the new present mode when the swapchain has to be recreated with it
+	pub fn record_frame_time(&mut self, frame_ms: f32) -> Option<PresentMode> {
+		if !self.mailbox_supported {
+			return None;
+		}
+
+		if self.frame_times.len() == PRESENT_MODE_WINDOW {
+			self.frame_times.pop_front();
+		}
+		self.frame_times.push_back(frame_ms);
+		if self.frame_times.len() < PRESENT_MODE_WINDOW {
+			return None;
+		}
+
+		let average = self.frame_times.iter().sum::<f32>() / self.frame_times.len() as f32;
+		let mode = match self.mode {
+			PresentMode::Fifo if average < self.refresh_period_ms * 0.8 => PresentMode::Mailbox,
+			PresentMode::Mailbox if average > self.refresh_period_ms * 0.95 => PresentMode::Fifo,
+			_ => return None,
+		};
+
+		info!(
+			"Switching present mode to {:?} (average frame time {:.2}ms)",
+			mode, average
+		);
+		self.mode = mode;
+		self.frame_times.clear();
+		Some(mode)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const REFRESH_PERIOD_MS: f32 = 1000.0 / 60.0;
+
+	fn feed(adaptor: &mut PresentModeAdaptor, frame_ms: f32) -> Option<PresentMode> {
+		(0..PRESENT_MODE_WINDOW).filter_map(|_| adaptor.record_frame_time(frame_ms)).last()
+	}
+
+	#[test]
+	fn suboptimal_is_recreated_on_the_next_frame() {
+		let mut monitor = SwapchainMonitor::new();
+		assert!(!monitor.begin_frame());
+		monitor.report_suboptimal();
+		assert!(!monitor.should_recreate());
+		assert!(monitor.begin_frame());
+		monitor.recreated();
+		assert!(!monitor.begin_frame());
+		assert_eq!(monitor.recreation_count(), 1);
+	}
+
+	#[test]
+	fn resize_settles_after_the_delay() {
+		let mut debouncer = SwapchainResizeDebouncer::with_delay(Duration::from_secs(3600));
+		assert!(debouncer.settled());
+		debouncer.resized();
+		assert!(!debouncer.settled());
+		assert!(SwapchainResizeDebouncer::with_delay(Duration::from_secs(0)).settled());
+	}
+
+	#[test]
+	fn switches_to_mailbox_then_back_to_fifo() {
+		let mut adaptor = PresentModeAdaptor::new(REFRESH_PERIOD_MS, true);
+		assert_eq!(feed(&mut adaptor, 4.0), Some(PresentMode::Mailbox));
+		// Between the thresholds, the mode is kept
+		assert_eq!(feed(&mut adaptor, 15.0), None);
+		assert_eq!(feed(&mut adaptor, 20.0), Some(PresentMode::Fifo));
+		assert_eq!(adaptor.mode(), PresentMode::Fifo);
+	}
+
+	#[test]
+	fn keeps_fifo_without_mailbox() {
+		let mut adaptor = PresentModeAdaptor::new(REFRESH_PERIOD_MS, false);
+		assert_eq!(feed(&mut adaptor, 4.0), None);
+		assert_eq!(adaptor.mode(), PresentMode::Fifo);
+	}
+}
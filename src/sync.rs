@@ -0,0 +1,559 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender, TryIter};
+
+use vulkano::buffer::BufferAccess;
+use vulkano::command_buffer::submit::{SubmitAnyBuilder, SubmitCommandBufferBuilder, SubmitSemaphoresWaitBuilder};
+use vulkano::device::{Device, DeviceOwned, Queue};
+use vulkano::image::{ImageAccess, ImageLayout};
+use vulkano::sync::{AccessCheckError, AccessFlagBits, Fence, FlushError, GpuFuture, PipelineStages, Semaphore};
+use vulkano::OomError;
+
+// Number of fences allocated up front by `GpuFencePool::new`
+pub const DEFAULT_FENCE_COUNT: usize = 8;
+
+// Recycles a fixed set of fences instead of creating a new one every frame.
+// Only fences that were submitted are tracked, a fence is handed out again
+// once it has been signaled and the future holding it has been dropped.
+pub struct GpuFencePool {
+	device: Arc<Device>,
+	free: Vec<Fence>,
+	in_flight: VecDeque<Arc<Fence>>,
+	// Submission time of every signaled fence, see `GpuFrameTimer`
+	timer: Option<GpuFrameTimer>,
+}
+
+impl GpuFencePool {
+	pub fn new(device: Arc<Device>) -> Result<GpuFencePool, OomError> {
+		GpuFencePool::with_capacity(device, DEFAULT_FENCE_COUNT)
+	}
+
+	pub fn with_capacity(device: Arc<Device>, count: usize) -> Result<GpuFencePool, OomError> {
+		let free = (0..count)
+			.map(|_| Fence::alloc(device.clone()))
+			.collect::<Result<_, _>>()?;
+
+		Ok(GpuFencePool {
+			device,
+			free,
+			in_flight: VecDeque::with_capacity(count),
+			timer: None,
+		})
+	}
+
+	// Measures the GPU time of every submission from now on
+	pub fn with_timer(mut self) -> GpuFencePool {
+		self.timer = Some(GpuFrameTimer::new());
+		self
+	}
+
+	pub fn timer(&self) -> Option<&GpuFrameTimer> {
+		self.timer.as_ref()
+	}
+
+	pub fn in_flight(&self) -> usize {
+		self.in_flight.len()
+	}
+
+	// Flushes `previous` then signals a fence of the pool once `queue` has
+	// executed everything submitted to it until then. Like vulkano's
+	// `then_signal_fence_and_flush`, except that on a lost device `previous` is
+	// leaked rather than dropped, its drop would wait on fences that fail.
+	pub fn signal_after<F>(&mut self, queue: &Arc<Queue>, previous: F) -> Result<PooledFenceFuture<F>, FlushError>
+	where
+		F: GpuFuture,
+	{
+		match self.submit_fence(queue, &previous) {
+			Ok(fence) => Ok(PooledFenceFuture {
+				device: previous.device().clone(),
+				previous: Some(previous),
+				fence,
+			}),
+			Err(FlushError::DeviceLost) => {
+				std::mem::forget(previous);
+				Err(FlushError::DeviceLost)
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	fn submit_fence<F: GpuFuture>(&mut self, queue: &Arc<Queue>, previous: &F) -> Result<Arc<Fence>, FlushError> {
+		previous.flush()?;
+		let fence = match self.free.pop() {
+			Some(fence) => fence,
+			None => self.recycle()?,
+		};
+
+		let mut submit = SubmitCommandBufferBuilder::new();
+		// Safe, the fence is unsignaled and only referenced by the pool
+		unsafe {
+			submit.set_fence_signal(&fence);
+		}
+		if let Err(e) = submit.submit(queue) {
+			// Never submitted, it can't be tracked with the others
+			self.free.push(fence);
+			return Err(e.into());
+		}
+
+		let fence = Arc::new(fence);
+		if let Some(timer) = self.timer.as_ref() {
+			timer.submitted(fence.clone());
+		}
+		self.in_flight.push_back(fence.clone());
+		Ok(fence)
+	}
+
+	// Every fence held by its future means that the caller keeps more frames
+	// in flight than the pool was sized for, a new fence is allocated then
+	fn recycle(&mut self) -> Result<Fence, FlushError> {
+		loop {
+			let mut released = None;
+			for (position, fence) in self.in_flight.iter().enumerate() {
+				if Arc::strong_count(fence) != 1 {
+					continue;
+				}
+				if is_signaled(fence)? {
+					released = Some(position);
+					break;
+				}
+			}
+			if let Some(position) = released {
+				// The pool holds the only reference left
+				if let Some(Ok(mut fence)) = self.in_flight.remove(position).map(Arc::try_unwrap) {
+					fence.reset().map_err(FlushError::OomError)?;
+					return Ok(fence);
+				}
+			}
+
+			// Every fence tracked was submitted, so waiting on the oldest
+			// released one ends
+			match self.in_flight.iter().find(|fence| Arc::strong_count(fence) == 1) {
+				Some(oldest) => oldest.wait(None)?,
+				None => return Fence::alloc(self.device.clone()).map_err(FlushError::OomError),
+			}
+		}
+	}
+}
+
+// `Fence::ready` panics on a lost device, a zero timeout wait returns the error
+pub fn is_signaled(fence: &Fence) -> Result<bool, FlushError> {
+	match fence.wait(Some(Duration::from_secs(0))) {
+		Ok(()) => Ok(true),
+		Err(vulkano::sync::FenceWaitError::Timeout) => Ok(false),
+		Err(e) => Err(e.into()),
+	}
+}
+
+// The future of `GpuFencePool::signal_after`. Once the fence is signaled the
+// previous futures are told so and dropped, which releases the resources of
+// the frame.
+pub struct PooledFenceFuture<F>
+where
+	F: GpuFuture,
+{
+	device: Arc<Device>,
+	previous: Option<F>,
+	fence: Arc<Fence>,
+}
+
+impl<F> PooledFenceFuture<F>
+where
+	F: GpuFuture,
+{
+	pub fn fence(&self) -> &Arc<Fence> {
+		&self.fence
+	}
+}
+
+unsafe impl<F> GpuFuture for PooledFenceFuture<F>
+where
+	F: GpuFuture,
+{
+	fn cleanup_finished(&mut self) {
+		let signaled = is_signaled(&self.fence).unwrap_or(false);
+		if let Some(previous) = self.previous.as_mut() {
+			if signaled {
+				// Safe, the GPU executed everything up to the fence
+				unsafe {
+					previous.signal_finished();
+				}
+				self.previous = None;
+			} else {
+				previous.cleanup_finished();
+			}
+		}
+	}
+
+	unsafe fn build_submission(&self) -> Result<SubmitAnyBuilder<'_>, FlushError> {
+		// Already submitted, the next submission follows on the same queue
+		Ok(SubmitAnyBuilder::Empty)
+	}
+
+	fn flush(&self) -> Result<(), FlushError> {
+		Ok(())
+	}
+
+	unsafe fn signal_finished(&self) {
+		if let Some(previous) = self.previous.as_ref() {
+			previous.signal_finished();
+		}
+	}
+
+	fn queue_change_allowed(&self) -> bool {
+		self.previous.is_none()
+	}
+
+	fn queue(&self) -> Option<Arc<Queue>> {
+		self.previous.as_ref().and_then(|previous| previous.queue())
+	}
+
+	fn check_buffer_access(
+		&self,
+		buffer: &dyn BufferAccess,
+		exclusive: bool,
+		queue: &Queue,
+	) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+		match self.previous.as_ref() {
+			Some(previous) => previous.check_buffer_access(buffer, exclusive, queue),
+			None => Err(AccessCheckError::Unknown),
+		}
+	}
+
+	fn check_image_access(
+		&self,
+		image: &dyn ImageAccess,
+		layout: ImageLayout,
+		exclusive: bool,
+		queue: &Queue,
+	) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+		match self.previous.as_ref() {
+			Some(previous) => previous.check_image_access(image, layout, exclusive, queue),
+			None => Err(AccessCheckError::Unknown),
+		}
+	}
+}
+
+unsafe impl<F> DeviceOwned for PooledFenceFuture<F>
+where
+	F: GpuFuture,
+{
+	fn device(&self) -> &Arc<Device> {
+		&self.device
+	}
+}
+
+impl<F> Drop for PooledFenceFuture<F>
+where
+	F: GpuFuture,
+{
+	fn drop(&mut self) {
+		if let Some(previous) = self.previous.take() {
+			match self.fence.wait(None) {
+				// Safe, the GPU executed everything up to the fence
+				Ok(()) => unsafe { previous.signal_finished() },
+				// Dropping the previous futures would wait on their fences again
+				Err(_) => std::mem::forget(previous),
+			}
+		}
+	}
+}
+
+// Time between the submission of a fence and its signal, measured by a thread
+// waiting on the fences in submission order so that it isn't rounded to the
+// frame the render loop polls on. It includes the wait for the swapchain image
+// and for the work queued before, the time a frame takes on the GPU when the
+// queue isn't backed up.
+pub struct GpuFrameTimer {
+	submitted: Sender<(Arc<Fence>, Instant)>,
+	measured: Receiver<f32>,
+}
+
+impl GpuFrameTimer {
+	fn new() -> GpuFrameTimer {
+		let (submitted, fences) = crossbeam_channel::unbounded::<(Arc<Fence>, Instant)>();
+		let (times, measured) = crossbeam_channel::unbounded();
+		// Ends with the timer, once the channel is disconnected
+		std::thread::spawn(move || {
+			for (fence, submitted_at) in fences {
+				if fence.wait(None).is_ok() {
+					let _ = times.send(submitted_at.elapsed().as_secs_f32() * 1000.0);
+				}
+			}
+		});
+		GpuFrameTimer { submitted, measured }
+	}
+
+	fn submitted(&self, fence: Arc<Fence>) {
+		let _ = self.submitted.send((fence, Instant::now()));
+	}
+
+	// The GPU times in milliseconds measured since the last call
+	pub fn drain(&self) -> TryIter<'_, f32> {
+		self.measured.try_iter()
+	}
+}
+
+// Recycles the binary semaphores the present of each frame waits on
+pub struct SemaphorePool {
+	device: Arc<Device>,
+	free: Vec<Arc<Semaphore>>,
+	// Handed back while a future still referenced them
+	retired: Vec<Arc<Semaphore>>,
+	live: usize,
+}
+
+impl SemaphorePool {
+	pub fn new(device: Arc<Device>) -> SemaphorePool {
+		SemaphorePool {
+			device,
+			free: Vec::new(),
+			retired: Vec::new(),
+			live: 0,
+		}
+	}
+
+	pub fn acquire(&mut self) -> Result<Arc<Semaphore>, OomError> {
+		let (released, retired) = self
+			.retired
+			.drain(..)
+			.partition::<Vec<_>, _>(|semaphore| Arc::strong_count(semaphore) == 1);
+		self.retired = retired;
+		self.free.extend(released);
+
+		let semaphore = match self.free.pop() {
+			Some(semaphore) => semaphore,
+			None => Arc::new(Semaphore::alloc(self.device.clone())?),
+		};
+		self.live += 1;
+		Ok(semaphore)
+	}
+
+	// The semaphore must not be referenced by a pending submission anymore
+	pub fn recycle(&mut self, semaphore: Arc<Semaphore>) {
+		debug_assert_eq!(
+			Arc::strong_count(&semaphore),
+			1,
+			"Recycled a semaphore that is still in use"
+		);
+		debug_assert!(self.live > 0, "Recycled a semaphore that wasn't acquired from this pool");
+
+		self.live -= 1;
+		self.free.push(semaphore);
+	}
+
+	// Like `recycle`, for a semaphore that the future of its frame may still
+	// hold. It's handed out again once that future is dropped.
+	pub fn retire(&mut self, semaphore: Arc<Semaphore>) {
+		debug_assert!(self.live > 0, "Retired a semaphore that wasn't acquired from this pool");
+
+		self.live -= 1;
+		self.retired.push(semaphore);
+	}
+
+	// Semaphores currently handed out
+	pub fn live(&self) -> usize {
+		self.live
+	}
+
+	// Semaphores allocated by the pool, handed out or not
+	pub fn allocated(&self) -> usize {
+		self.live + self.free.len() + self.retired.len()
+	}
+}
+
+// Gives each frame in flight its own semaphore slot, so a frame never waits on
+// a semaphore that the previous use of the slot hasn't consumed yet
+pub struct FrameSemaphores {
+	slots: Vec<Option<Arc<Semaphore>>>,
+}
+
+impl FrameSemaphores {
+	pub fn new(frames_in_flight: usize) -> FrameSemaphores {
+		FrameSemaphores {
+			slots: vec![None; frames_in_flight],
+		}
+	}
+
+	// Hands back the semaphore previously used by this slot and stores a fresh one
+	pub fn next(&mut self, frame: usize, pool: &mut SemaphorePool) -> Result<Arc<Semaphore>, OomError> {
+		let index = frame % self.slots.len();
+		let slot = &mut self.slots[index];
+		if let Some(previous) = slot.take() {
+			if Arc::strong_count(&previous) == 1 {
+				pool.recycle(previous);
+			} else {
+				// The GPU is more frames behind than there are slots
+				pool.retire(previous);
+			}
+		}
+
+		let semaphore = pool.acquire()?;
+		*slot = Some(semaphore.clone());
+		Ok(semaphore)
+	}
+
+	// Signals the semaphore of `frame` once `previous` has executed, for the
+	// present to wait on. `then_swapchain_present` on a command buffer only
+	// submits it before presenting, which doesn't order the present after the
+	// rendering.
+	pub fn signal_after<F>(
+		&mut self,
+		frame: usize,
+		pool: &mut SemaphorePool,
+		previous: F,
+	) -> Result<PooledSemaphoreFuture<F>, OomError>
+	where
+		F: GpuFuture,
+	{
+		Ok(PooledSemaphoreFuture {
+			previous: Some(previous),
+			semaphore: self.next(frame, pool)?,
+			wait_submitted: Mutex::new(false),
+			finished: AtomicBool::new(false),
+		})
+	}
+}
+
+// vulkano's `SemaphoreSignalFuture` with a semaphore of the pool. The
+// semaphore is held until the future is dropped, which tells
+// `FrameSemaphores` whether it can be recycled.
+pub struct PooledSemaphoreFuture<F>
+where
+	F: GpuFuture,
+{
+	previous: Option<F>,
+	semaphore: Arc<Semaphore>,
+	wait_submitted: Mutex<bool>,
+	finished: AtomicBool,
+}
+
+impl<F> PooledSemaphoreFuture<F>
+where
+	F: GpuFuture,
+{
+	fn previous(&self) -> &F {
+		// Only taken by drop
+		self.previous.as_ref().unwrap()
+	}
+}
+
+unsafe impl<F> GpuFuture for PooledSemaphoreFuture<F>
+where
+	F: GpuFuture,
+{
+	fn cleanup_finished(&mut self) {
+		if let Some(previous) = self.previous.as_mut() {
+			previous.cleanup_finished();
+		}
+	}
+
+	unsafe fn build_submission(&self) -> Result<SubmitAnyBuilder<'_>, FlushError> {
+		self.flush()?;
+
+		let mut wait = SubmitSemaphoresWaitBuilder::new();
+		wait.add_wait_semaphore(&self.semaphore);
+		Ok(SubmitAnyBuilder::SemaphoresWait(wait))
+	}
+
+	fn flush(&self) -> Result<(), FlushError> {
+		let mut wait_submitted = self.wait_submitted.lock().unwrap();
+		if *wait_submitted {
+			return Ok(());
+		}
+
+		let queue = self.previous().queue().ok_or(FlushError::DeviceLost)?;
+		// Safe, the semaphore is unsignaled: the present that waited on it
+		// frames ago is done once its future was dropped
+		unsafe {
+			let mut submit = match self.previous().build_submission()? {
+				SubmitAnyBuilder::Empty => SubmitCommandBufferBuilder::new(),
+				SubmitAnyBuilder::SemaphoresWait(wait) => wait.into(),
+				SubmitAnyBuilder::CommandBuffer(submit) => submit,
+				SubmitAnyBuilder::QueuePresent(present) => {
+					present.submit(&queue)?;
+					SubmitCommandBufferBuilder::new()
+				}
+				SubmitAnyBuilder::BindSparse(sparse) => {
+					sparse.submit(&queue)?;
+					SubmitCommandBufferBuilder::new()
+				}
+			};
+			submit.add_signal_semaphore(&self.semaphore);
+			submit.submit(&queue)?;
+		}
+
+		*wait_submitted = true;
+		Ok(())
+	}
+
+	unsafe fn signal_finished(&self) {
+		self.finished.store(true, Ordering::SeqCst);
+		self.previous().signal_finished();
+	}
+
+	fn queue_change_allowed(&self) -> bool {
+		true
+	}
+
+	fn queue(&self) -> Option<Arc<Queue>> {
+		self.previous().queue()
+	}
+
+	fn check_buffer_access(
+		&self,
+		buffer: &dyn BufferAccess,
+		exclusive: bool,
+		queue: &Queue,
+	) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+		self.previous()
+			.check_buffer_access(buffer, exclusive, queue)
+			.map(|_| None)
+	}
+
+	fn check_image_access(
+		&self,
+		image: &dyn ImageAccess,
+		layout: ImageLayout,
+		exclusive: bool,
+		queue: &Queue,
+	) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+		self.previous()
+			.check_image_access(image, layout, exclusive, queue)
+			.map(|_| None)
+	}
+}
+
+unsafe impl<F> DeviceOwned for PooledSemaphoreFuture<F>
+where
+	F: GpuFuture,
+{
+	fn device(&self) -> &Arc<Device> {
+		self.semaphore.device()
+	}
+}
+
+impl<F> Drop for PooledSemaphoreFuture<F>
+where
+	F: GpuFuture,
+{
+	fn drop(&mut self) {
+		if *self.finished.get_mut() {
+			return;
+		}
+		let waited = self
+			.flush()
+			.and_then(|()| self.queue().ok_or(FlushError::DeviceLost))
+			.and_then(|queue| queue.wait().map_err(|_| FlushError::DeviceLost));
+		if let Some(previous) = self.previous.take() {
+			match waited {
+				// Safe, the queue is idle
+				Ok(()) => unsafe { previous.signal_finished() },
+				// Dropping the previous futures would wait on their fences again
+				Err(_) => std::mem::forget(previous),
+			}
+		}
+	}
+}
@@ -0,0 +1,263 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::device::Device;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::pipeline::GraphicsPipeline;
+
+use crate::error::VulkanoError;
+use crate::pipeline::SharedPipeline;
+
+mod terrain_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 position;
+			layout(location = 1) in vec3 normal;
+
+			layout(location = 0) out vec3 v_normal;
+			layout(location = 1) out float v_height;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+				// Towards the light
+				vec4 light_direction;
+				vec4 line_color;
+				// 0 shades the terrain, 1 draws its edges with the line color
+				uint mode;
+				// Of the edges towards the camera, in normalized depth units
+				float depth_bias;
+			} pc;
+
+			void main() {
+				v_normal = normal;
+				v_height = position.y;
+				gl_Position = pc.view_projection * vec4(position, 1.0);
+				if (pc.mode == 1) {
+					gl_Position.z -= pc.depth_bias * gl_Position.w;
+				}
+			}
+		"
+	}
+}
+
+mod terrain_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 v_normal;
+			layout(location = 1) in float v_height;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+				vec4 light_direction;
+				vec4 line_color;
+				uint mode;
+				float depth_bias;
+			} pc;
+
+			const vec3 LOW_COLOR = vec3(0.25, 0.4, 0.15);
+			const vec3 HIGH_COLOR = vec3(0.5, 0.45, 0.4);
+			const float AMBIENT = 0.2;
+
+			void main() {
+				if (pc.mode == 1) {
+					f_color = pc.line_color;
+					return;
+				}
+				vec3 albedo = mix(LOW_COLOR, HIGH_COLOR, clamp(v_height * 0.02, 0.0, 1.0));
+				float diffuse = max(dot(normalize(v_normal), pc.light_direction.xyz), 0.0);
+				f_color = vec4(albedo * (AMBIENT + diffuse), 1.0);
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct TerrainVertex {
+	position: [f32; 3],
+	normal: [f32; 3],
+}
+vulkano::impl_vertex!(TerrainVertex, position, normal);
+
+// Draws the edges of the terrain triangles over the filled terrain, to see the
+// mesh density
+#[derive(Debug, Clone, Copy)]
+pub struct WireframeOverlay {
+	pub enabled: bool,
+	pub color: [f32; 4],
+	// Pulls the edges towards the camera, to keep them above the filled triangles
+	pub depth_bias: f32,
+}
+
+impl Default for WireframeOverlay {
+	fn default() -> WireframeOverlay {
+		WireframeOverlay {
+			enabled: false,
+			color: [1.0, 1.0, 1.0, 1.0],
+			depth_bias: 1e-4,
+		}
+	}
+}
+
+// Heightmap terrain on a regular grid. The polygon mode is pipeline state, so
+// the wireframe pipeline is built with the fill one and the overlay costs a
+// second draw of the same buffers. Both pipelines share their shaders, the
+// `mode` push constant selects between shading and the line color.
+pub struct TerrainRenderer {
+	fill_pipeline: SharedPipeline,
+	wireframe_pipeline: SharedPipeline,
+	vertex_buffer: Arc<CpuAccessibleBuffer<[TerrainVertex]>>,
+	index_buffer: Arc<CpuAccessibleBuffer<[u32]>>,
+	pub wireframe_overlay: WireframeOverlay,
+}
+
+impl TerrainRenderer {
+	// `heights` are `resolution` x `resolution` samples in rows along X,
+	// `spacing` meters apart
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		heights: &[f32],
+		resolution: u32,
+		spacing: f32,
+	) -> Result<TerrainRenderer, VulkanoError> {
+		assert!(resolution >= 2, "A terrain needs at least 2x2 height samples");
+		assert_eq!(heights.len(), (resolution * resolution) as usize);
+
+		let vs = terrain_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = terrain_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let has_depth = subpass.has_depth();
+		let pipeline = |wireframe: bool| -> Result<SharedPipeline, VulkanoError> {
+			let builder = GraphicsPipeline::start()
+				.vertex_input_single_buffer::<TerrainVertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ());
+			let builder = if wireframe {
+				builder.polygon_mode_line()
+			} else {
+				builder
+			};
+			let builder = if has_depth {
+				builder.depth_stencil_simple_depth()
+			} else {
+				builder
+			};
+			Ok(Arc::new(builder.render_pass(subpass.clone()).build(device.clone())?))
+		};
+		let fill_pipeline = pipeline(false)?;
+		let wireframe_pipeline = pipeline(true)?;
+
+		let (vertices, indices) = terrain_mesh(heights, resolution, spacing);
+		let vertex_buffer = CpuAccessibleBuffer::from_iter(
+			device.clone(),
+			BufferUsage::vertex_buffer(),
+			false,
+			vertices.into_iter(),
+		)?;
+		let index_buffer =
+			CpuAccessibleBuffer::from_iter(device, BufferUsage::index_buffer(), false, indices.into_iter())?;
+
+		Ok(TerrainRenderer {
+			fill_pipeline,
+			wireframe_pipeline,
+			vertex_buffer,
+			index_buffer,
+			wireframe_overlay: WireframeOverlay::default(),
+		})
+	}
+
+	pub fn toggle_wireframe_overlay(&mut self) {
+		self.wireframe_overlay.enabled = !self.wireframe_overlay.enabled;
+	}
+
+	// Records the terrain inside the current render pass, then its edges if
+	// the wireframe overlay is enabled
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		view_projection: [[f32; 4]; 4],
+		light_direction: [f32; 3],
+	) -> Result<(), VulkanoError> {
+		let [lx, ly, lz] = light_direction;
+		let push_constants = |mode: u32| terrain_vs::ty::PushConstants {
+			view_projection,
+			light_direction: [lx, ly, lz, 0.0],
+			line_color: self.wireframe_overlay.color,
+			mode,
+			depth_bias: self.wireframe_overlay.depth_bias,
+		};
+
+		builder.draw_indexed(
+			self.fill_pipeline.clone(),
+			dynamic_state,
+			vec![self.vertex_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>],
+			self.index_buffer.clone(),
+			(),
+			push_constants(0),
+			vec![],
+		)?;
+		if self.wireframe_overlay.enabled {
+			builder.draw_indexed(
+				self.wireframe_pipeline.clone(),
+				dynamic_state,
+				vec![self.vertex_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>],
+				self.index_buffer.clone(),
+				(),
+				push_constants(1),
+				vec![],
+			)?;
+		}
+		Ok(())
+	}
+}
+
+// Grid with a corner at the origin, normals from the central differences of
+// the heights
+fn terrain_mesh(heights: &[f32], resolution: u32, spacing: f32) -> (Vec<TerrainVertex>, Vec<u32>) {
+	let height = |x: i64, z: i64| {
+		let max = resolution as i64 - 1;
+		heights[(z.max(0).min(max) * resolution as i64 + x.max(0).min(max)) as usize]
+	};
+	let vertices = (0..resolution as i64)
+		.flat_map(|z| {
+			(0..resolution as i64).map(move |x| {
+				let dx = (height(x + 1, z) - height(x - 1, z)) / (2.0 * spacing);
+				let dz = (height(x, z + 1) - height(x, z - 1)) / (2.0 * spacing);
+				let length = (dx * dx + 1.0 + dz * dz).sqrt();
+				TerrainVertex {
+					position: [x as f32 * spacing, height(x, z), z as f32 * spacing],
+					normal: [-dx / length, 1.0 / length, -dz / length],
+				}
+			})
+		})
+		.collect();
+
+	let quads = resolution - 1;
+	let indices = (0..quads)
+		.flat_map(|z| {
+			(0..quads).flat_map(move |x| {
+				let corner = z * resolution + x;
+				vec![
+					corner,
+					corner + resolution,
+					corner + 1,
+					corner + 1,
+					corner + resolution,
+					corner + resolution + 1,
+				]
+			})
+		})
+		.collect();
+	(vertices, indices)
+}
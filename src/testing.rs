@@ -0,0 +1,403 @@
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::RgbaImage;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::device::{Device, DeviceExtensions, DeviceOwned, Features, Queue};
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageAccess, ImageUsage};
+use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::sync::GpuFuture;
+
+use crate::debug_utils::LABEL_COLOR_RENDER_PASS;
+use crate::error::VulkanoError;
+use crate::glsl_shaders::{fs, vs};
+use crate::pipeline::SharedPipeline;
+use crate::renderer::triangle_vertices;
+use crate::Vertex;
+
+// Format of the offscreen target, read back as RGBA bytes
+pub const READBACK_FORMAT: Format = Format::R8G8B8A8Unorm;
+
+// Reads pixels of an RGBA8 image created with the `transfer_source` usage.
+// Every read is submitted on its own and waits for the GPU, this is meant for
+// tests, not for the render loop.
+pub struct PixelReadback {
+	queue: Arc<Queue>,
+	image: Arc<dyn ImageAccess + Send + Sync>,
+	buffer: Arc<CpuAccessibleBuffer<[u8]>>,
+}
+
+impl PixelReadback {
+	pub fn new(queue: Arc<Queue>, image: Arc<dyn ImageAccess + Send + Sync>) -> Result<PixelReadback, VulkanoError> {
+		let buffer = CpuAccessibleBuffer::from_iter(
+			queue.device().clone(),
+			BufferUsage::transfer_destination(),
+			true,
+			[0u8; 4].iter().cloned(),
+		)?;
+		Ok(PixelReadback { queue, image, buffer })
+	}
+
+	pub fn read_pixel(&self, x: u32, y: u32) -> Result<[u8; 4], VulkanoError> {
+		let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+			self.queue.device().clone(),
+			self.queue.family(),
+		)?;
+		builder.copy_image_to_buffer_dimensions(
+			self.image.clone(),
+			self.buffer.clone(),
+			[x, y, 0],
+			[1, 1, 1],
+			0,
+			1,
+			0,
+		)?;
+		let command_buffer = builder.build()?;
+
+		vulkano::sync::now(self.queue.device().clone())
+			.then_execute(self.queue.clone(), command_buffer)?
+			.then_signal_fence_and_flush()?
+			.wait(None)?;
+
+		let content = self.buffer.read()?;
+		Ok([content[0], content[1], content[2], content[3]])
+	}
+
+	// Copies the whole image, rows are tightly packed
+	pub fn read_image(&self) -> Result<RgbaImage, VulkanoError> {
+		let dimensions = self.image.dimensions();
+		let (width, height) = (dimensions.width(), dimensions.height());
+		let buffer = CpuAccessibleBuffer::from_iter(
+			self.queue.device().clone(),
+			BufferUsage::transfer_destination(),
+			true,
+			(0..width * height * 4).map(|_| 0u8),
+		)?;
+
+		let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+			self.queue.device().clone(),
+			self.queue.family(),
+		)?;
+		builder.copy_image_to_buffer(self.image.clone(), buffer.clone())?;
+		let command_buffer = builder.build()?;
+
+		vulkano::sync::now(self.queue.device().clone())
+			.then_execute(self.queue.clone(), command_buffer)?
+			.then_signal_fence_and_flush()?
+			.wait(None)?;
+
+		let content = buffer.read()?;
+		// The buffer has exactly width * height * 4 bytes
+		Ok(RgbaImage::from_raw(width, height, content.to_vec()).unwrap())
+	}
+}
+
+// Root mean square error of the channels of two images of the same size, in [0, 255]
+pub fn rmse(a: &RgbaImage, b: &RgbaImage) -> f64 {
+	// The images deref to their raw channels
+	let sum: f64 = a
+		.iter()
+		.zip(b.iter())
+		.map(|(&x, &y)| {
+			let d = x as f64 - y as f64;
+			d * d
+		})
+		.sum();
+	(sum / a.len().max(1) as f64).sqrt()
+}
+
+// Absolute difference of each channel, opaque so it shows in image viewers
+fn diff_image(a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+	let mut diff = a.clone();
+	for (pixel, other) in diff.pixels_mut().zip(b.pixels()) {
+		for channel in 0..3 {
+			pixel[channel] = (pixel[channel] as i16 - other[channel] as i16).unsigned_abs() as u8;
+		}
+		pixel[3] = 255;
+	}
+	diff
+}
+
+// Regression test rendering the scene for a number of frames with a fixed
+// time step, and comparing each frame with the golden PNG stored in
+// `tests/golden`. With `UPDATE_GOLDEN=1` the golden files are written instead,
+// a missing golden file is written too and has to be committed.
+// The scene doesn't use any randomness, the fixed time step is enough to make
+// the frames deterministic.
+pub struct GoldenImageTest {
+	name: &'static str,
+	frames: u32,
+	delta_time: f32,
+	tolerance: f64,
+}
+
+impl GoldenImageTest {
+	pub fn new(name: &'static str, frames: u32) -> GoldenImageTest {
+		GoldenImageTest {
+			name,
+			frames,
+			delta_time: 1.0 / 60.0,
+			// Leaves some room for rasterization differences between drivers
+			tolerance: 2.0,
+		}
+	}
+
+	pub fn with_tolerance(mut self, tolerance: f64) -> GoldenImageTest {
+		self.tolerance = tolerance;
+		self
+	}
+
+	pub fn with_delta_time(mut self, delta_time: f32) -> GoldenImageTest {
+		self.delta_time = delta_time;
+		self
+	}
+
+	fn golden_path(&self, frame: u32) -> PathBuf {
+		Path::new(env!("CARGO_MANIFEST_DIR"))
+			.join("tests")
+			.join("golden")
+			.join(format!("{}_frame_{:02}.png", self.name, frame))
+	}
+
+	fn diff_path(frame: u32) -> PathBuf {
+		Path::new(env!("CARGO_MANIFEST_DIR"))
+			.join("test_output")
+			.join(format!("diff_frame_{:02}.png", frame))
+	}
+
+	// Panics if a frame differs from its golden file by more than the tolerance,
+	// after writing the difference to `test_output/diff_frame_NN.png`.
+	// `angle_at` gives the rotation of the triangle after `elapsed` seconds.
+	pub fn run<F>(&self, scene: &HeadlessTriangle, angle_at: F) -> Result<(), VulkanoError>
+	where
+		F: Fn(f32) -> f32,
+	{
+		let update = std::env::var("UPDATE_GOLDEN").map(|v| v == "1").unwrap_or(false);
+		let readback = PixelReadback::new(scene.queue().clone(), scene.image())?;
+
+		let mut failures = Vec::new();
+		for frame in 0..self.frames {
+			scene.render(angle_at(frame as f32 * self.delta_time))?;
+			let image = readback.read_image()?;
+
+			let golden_path = self.golden_path(frame);
+			if update || !golden_path.exists() {
+				if !update {
+					eprintln!("Writing the missing golden file {}", golden_path.display());
+				}
+				create_dir_all(golden_path.parent().unwrap())?;
+				image.save(&golden_path)?;
+				continue;
+			}
+
+			let golden = image::open(&golden_path)?.to_rgba();
+			if golden.dimensions() != image.dimensions() {
+				failures.push(format!(
+					"frame {}: size {:?} instead of {:?}",
+					frame,
+					image.dimensions(),
+					golden.dimensions()
+				));
+				continue;
+			}
+
+			let error = rmse(&image, &golden);
+			if error > self.tolerance {
+				let diff_path = GoldenImageTest::diff_path(frame);
+				create_dir_all(diff_path.parent().unwrap())?;
+				diff_image(&image, &golden).save(&diff_path)?;
+				failures.push(format!(
+					"frame {}: RMSE {:.2} above {:.2}, see {}",
+					frame,
+					error,
+					self.tolerance,
+					diff_path.display()
+				));
+			}
+		}
+
+		assert!(failures.is_empty(), "Golden image test `{}` failed:\n{}", self.name, failures.join("\n"));
+		Ok(())
+	}
+}
+
+// The triangle scene rendered without a window into an offscreen image
+pub struct HeadlessTriangle {
+	queue: Arc<Queue>,
+	pipeline: SharedPipeline,
+	framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+	image: Arc<AttachmentImage>,
+	buffer_pool: CpuBufferPool<Vertex>,
+	dynamic_state: DynamicState,
+}
+
+impl HeadlessTriangle {
+	pub fn new(dimensions: [u32; 2]) -> Result<HeadlessTriangle, VulkanoError> {
+		let instance = Instance::new(None, &InstanceExtensions::none(), None)?;
+		let physical = PhysicalDevice::enumerate(&instance)
+			.next()
+			.ok_or(VulkanoError::NoPhysicalDevice)?;
+		let queue_family = physical
+			.queue_families()
+			.find(|&q| q.supports_graphics())
+			.ok_or(VulkanoError::NoQueueFamily)?;
+		let (device, mut queues) = Device::new(
+			physical,
+			&Features::none(),
+			&DeviceExtensions::none(),
+			[(queue_family, 0.5)].iter().cloned(),
+		)?;
+		let queue = queues.next().ok_or(VulkanoError::NoQueue)?;
+
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::single_pass_renderpass!(
+				device.clone(),
+				attachments: {
+					color: {
+						load: Clear,
+						store: Store,
+						format: READBACK_FORMAT,
+						samples: 1,
+					}
+				},
+				pass: {
+					color: [color],
+					depth_stencil: {}
+				}
+			)?,
+		);
+
+		let vs = vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<Vertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), fs::SpecializationConstants::default())
+			.render_pass(Subpass::from(render_pass.clone(), 0).ok_or(VulkanoError::NoSubpass)?)
+			.build(device.clone())?;
+
+		let image = AttachmentImage::with_usage(
+			device.clone(),
+			dimensions,
+			READBACK_FORMAT,
+			ImageUsage {
+				color_attachment: true,
+				transfer_source: true,
+				..ImageUsage::none()
+			},
+		)?;
+		let framebuffer = Arc::new(
+			Framebuffer::start(render_pass)
+				.add(ImageView::new(image.clone())?)?
+				.build()?,
+		);
+
+		let dynamic_state = DynamicState {
+			viewports: Some(vec![Viewport {
+				origin: [0.0, 0.0],
+				dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+				depth_range: 0.0..1.0,
+			}]),
+			..DynamicState::none()
+		};
+
+		Ok(HeadlessTriangle {
+			buffer_pool: CpuBufferPool::vertex_buffer(device),
+			queue,
+			pipeline: Arc::new(pipeline),
+			framebuffer,
+			image,
+			dynamic_state,
+		})
+	}
+
+	// Renders the triangle rotated by `angle` and waits for the GPU
+	pub fn render(&self, angle: f32) -> Result<(), VulkanoError> {
+		let buffer = self.buffer_pool.chunk(triangle_vertices(angle).to_vec())?;
+		let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+			self.queue.device().clone(),
+			self.queue.family(),
+		)?;
+		debug_label!(builder, "HeadlessTriangle", LABEL_COLOR_RENDER_PASS);
+		builder
+			.begin_render_pass(
+				self.framebuffer.clone(),
+				SubpassContents::Inline,
+				vec![[0.0, 0.0, 1.0, 1.0].into()],
+			)?
+			.draw(
+				self.pipeline.clone(),
+				&self.dynamic_state,
+				vec![Arc::new(buffer) as Arc<dyn BufferAccess + Send + Sync>],
+				(),
+				(),
+				vec![],
+			)?
+			.end_render_pass()?;
+		debug_label!(builder);
+		let command_buffer = builder.build()?;
+
+		vulkano::sync::now(self.queue.device().clone())
+			.then_execute(self.queue.clone(), command_buffer)?
+			.then_signal_fence_and_flush()?
+			.wait(None)?;
+		Ok(())
+	}
+
+	pub fn queue(&self) -> &Arc<Queue> {
+		&self.queue
+	}
+
+	pub fn image(&self) -> Arc<AttachmentImage> {
+		self.image.clone()
+	}
+
+	pub fn dimensions(&self) -> [u32; 2] {
+		let dimensions = self.image.dimensions();
+		[dimensions.width(), dimensions.height()]
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::renderer::triangle_rotation;
+
+	const SIZE: [u32; 2] = [64, 64];
+
+	// The GPU tests are run with `cargo test -- --ignored`
+	#[test]
+	#[ignore = "needs a Vulkan device"]
+	fn triangle_over_blue_background() {
+		let scene = HeadlessTriangle::new(SIZE).unwrap();
+		scene.render(0.0).unwrap();
+		let readback = PixelReadback::new(scene.queue().clone(), scene.image()).unwrap();
+
+		// The triangle is centered on the screen, the background shows in the corners
+		assert_eq!(readback.read_pixel(0, 0).unwrap(), [0, 0, 255, 255]);
+		assert_eq!(readback.read_pixel(SIZE[0] / 2, SIZE[1] / 2).unwrap(), [255, 0, 0, 255]);
+	}
+
+	// 8 frames a quarter of a second apart, on the eased rotation of the
+	// application. Missing golden files are written, see GoldenImageTest.
+	#[test]
+	#[ignore = "needs a Vulkan device"]
+	fn golden_triangle_rotation() {
+		let scene = HeadlessTriangle::new(SIZE).unwrap();
+		let rotation = triangle_rotation();
+		GoldenImageTest::new("triangle_rotation", 8)
+			.with_delta_time(0.25)
+			.run(&scene, |elapsed| rotation.sample(elapsed))
+			.unwrap();
+	}
+}
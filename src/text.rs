@@ -0,0 +1,971 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::*;
+
+use rusttype::{point, Font, Scale};
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuBufferPool};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, ImmutableImage, MipmapsCount, StorageImage};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+use crate::error::VulkanoError;
+
+mod sdf_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 position;
+			layout(location = 1) in vec2 uv;
+			layout(location = 2) in vec4 color;
+
+			layout(location = 0) out vec2 v_uv;
+			layout(location = 1) out vec4 v_color;
+
+			void main() {
+				v_uv = uv;
+				v_color = color;
+				gl_Position = vec4(position, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod sdf_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+			layout(location = 1) in vec4 v_color;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 0) uniform sampler2D atlas;
+
+			layout(push_constant) uniform PushConstants {
+				// Distance range the atlas was generated with, in atlas pixels
+				float px_range;
+			} pc;
+
+			float median(float r, float g, float b) {
+				return max(min(r, g), min(max(r, g), b));
+			}
+
+			void main() {
+				vec2 unit_range = vec2(pc.px_range) / vec2(textureSize(atlas, 0));
+				vec2 screen_tex_size = vec2(1.0) / fwidth(v_uv);
+				float screen_px_range = max(0.5 * dot(unit_range, screen_tex_size), 1.0);
+
+				vec3 msd = texture(atlas, v_uv).rgb;
+				float distance = screen_px_range * (median(msd.r, msd.g, msd.b) - 0.5);
+				float opacity = smoothstep(-0.5, 0.5, distance);
+
+				f_color = vec4(v_color.rgb, v_color.a * opacity);
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct TextVertex {
+	position: [f32; 2],
+	uv: [f32; 2],
+	color: [f32; 4],
+}
+vulkano::impl_vertex!(TextVertex, position, uv, color);
+
+// Bounds of a glyph, in em units for the plane and in atlas texels for the atlas
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+	left: f32,
+	bottom: f32,
+	right: f32,
+	top: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SdfGlyph {
+	advance: f32,
+	plane: Option<Bounds>,
+	atlas: Option<Bounds>,
+}
+
+struct FontMetrics {
+	px_range: f32,
+	atlas_size: [f32; 2],
+	// Whether atlas coordinates of the metrics file start at the bottom of the image
+	y_origin_bottom: bool,
+	glyphs: HashMap<char, SdfGlyph>,
+}
+
+impl FontMetrics {
+	// Parses the JSON layout written by msdf-atlas-gen
+	fn parse(json: &str) -> Result<FontMetrics, VulkanoError> {
+		let root: serde_json::Value = serde_json::from_str(json)?;
+		let atlas = &root["atlas"];
+		let number = |value: &serde_json::Value, name: &str| {
+			value[name]
+				.as_f64()
+				.map(|value| value as f32)
+				.ok_or_else(|| VulkanoError::FontMetrics(format!("missing `{}`", name)))
+		};
+		let bounds = |value: &serde_json::Value| -> Result<Option<Bounds>, VulkanoError> {
+			if value.is_null() {
+				return Ok(None);
+			}
+			Ok(Some(Bounds {
+				left: number(value, "left")?,
+				bottom: number(value, "bottom")?,
+				right: number(value, "right")?,
+				top: number(value, "top")?,
+			}))
+		};
+
+		let mut glyphs = HashMap::new();
+		let entries = root["glyphs"]
+			.as_array()
+			.ok_or_else(|| VulkanoError::FontMetrics("missing `glyphs`".to_string()))?;
+		for entry in entries {
+			let character = entry["unicode"]
+				.as_u64()
+				.and_then(|code| std::char::from_u32(code as u32))
+				.ok_or_else(|| VulkanoError::FontMetrics("invalid `unicode`".to_string()))?;
+			let glyph = SdfGlyph {
+				advance: number(entry, "advance")?,
+				plane: bounds(&entry["planeBounds"])?,
+				atlas: bounds(&entry["atlasBounds"])?,
+			};
+			glyphs.insert(character, glyph);
+		}
+
+		Ok(FontMetrics {
+			px_range: number(atlas, "distanceRange")?,
+			atlas_size: [number(atlas, "width")?, number(atlas, "height")?],
+			y_origin_bottom: atlas["yOrigin"].as_str() != Some("top"),
+			glyphs,
+		})
+	}
+}
+
+// Draws text from a multi-channel signed distance field atlas, which stays sharp
+// at any size. Every `draw_text` of a frame is batched into a single draw call.
+pub struct SdfTextRenderer {
+	pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+	descriptor_set: Arc<dyn DescriptorSet + Send + Sync>,
+	vertex_pool: CpuBufferPool<TextVertex>,
+	metrics: FontMetrics,
+	vertices: Vec<TextVertex>,
+}
+
+impl SdfTextRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		queue: Arc<Queue>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		atlas_path: &Path,
+		metrics_path: &Path,
+	) -> Result<(SdfTextRenderer, Box<dyn GpuFuture>), VulkanoError> {
+		let metrics = FontMetrics::parse(&fs::read_to_string(metrics_path)?)?;
+
+		let atlas = image::open(atlas_path)?.to_rgba();
+		let (width, height) = atlas.dimensions();
+		let (atlas, atlas_future) = ImmutableImage::from_iter(
+			atlas.into_raw().into_iter(),
+			ImageDimensions::Dim2d {
+				width,
+				height,
+				array_layers: 1,
+			},
+			MipmapsCount::One,
+			// Distances are linear values, they mustn't be sRGB decoded
+			Format::R8G8B8A8Unorm,
+			queue,
+		)?;
+		let sampler = Sampler::new(
+			device.clone(),
+			Filter::Linear,
+			Filter::Linear,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+
+		let vs = sdf_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = sdf_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<TextVertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.blend_alpha_blending()
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+
+		let layout = pipeline.descriptor_set_layout(0).ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let descriptor_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(ImageView::new(atlas)?, sampler)?
+				.build()?,
+		);
+
+		let renderer = SdfTextRenderer {
+			pipeline,
+			descriptor_set,
+			vertex_pool: CpuBufferPool::vertex_buffer(device),
+			metrics,
+			vertices: Vec::new(),
+		};
+		Ok((renderer, atlas_future.boxed()))
+	}
+
+	// Queues `text` with its baseline starting at (`x`, `y`) in normalized device
+	// coordinates, `size` being the em size in the same unit
+	pub fn draw_text(&mut self, text: &str, x: f32, y: f32, size: f32, color: [f32; 4]) {
+		let [atlas_width, atlas_height] = self.metrics.atlas_size;
+		let mut pen = x;
+
+		for character in text.chars() {
+			let glyph = match self.metrics.glyphs.get(&character) {
+				Some(glyph) => *glyph,
+				None => continue,
+			};
+
+			if let (Some(plane), Some(atlas)) = (glyph.plane, glyph.atlas) {
+				// Y points down in NDC, up in the font metrics
+				let left = pen + plane.left * size;
+				let right = pen + plane.right * size;
+				let top = y - plane.top * size;
+				let bottom = y - plane.bottom * size;
+
+				let u0 = atlas.left / atlas_width;
+				let u1 = atlas.right / atlas_width;
+				let (v0, v1) = if self.metrics.y_origin_bottom {
+					(1.0 - atlas.top / atlas_height, 1.0 - atlas.bottom / atlas_height)
+				} else {
+					(atlas.top / atlas_height, atlas.bottom / atlas_height)
+				};
+
+				let vertex = |position, uv| TextVertex { position, uv, color };
+				self.vertices.extend_from_slice(&[
+					vertex([left, top], [u0, v0]),
+					vertex([right, top], [u1, v0]),
+					vertex([left, bottom], [u0, v1]),
+					vertex([right, top], [u1, v0]),
+					vertex([right, bottom], [u1, v1]),
+					vertex([left, bottom], [u0, v1]),
+				]);
+			}
+
+			pen += glyph.advance * size;
+		}
+	}
+
+	// Records the text queued since the last flush, inside the current render pass
+	pub fn flush(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+	) -> Result<(), VulkanoError> {
+		if self.vertices.is_empty() {
+			return Ok(());
+		}
+
+		let vertices = self.vertex_pool.chunk(self.vertices.drain(..))?;
+		let push_constants = sdf_fs::ty::PushConstants {
+			px_range: self.metrics.px_range,
+		};
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![Arc::new(vertices) as Arc<dyn BufferAccess + Send + Sync>],
+			self.descriptor_set.clone(),
+			push_constants,
+			vec![],
+		)?;
+
+		Ok(())
+	}
+}
+
+// Point sizes rasterized by default
+pub const DEFAULT_FONT_SIZES: [u16; 5] = [8, 12, 16, 24, 48];
+// At 96 DPI
+const PIXELS_PER_POINT: f32 = 96.0 / 72.0;
+const MAX_FONT_ATLAS_SIZE: u32 = 4096;
+// Empty texels around each glyph so linear filtering doesn't bleed the neighbours in
+const GLYPH_PADDING: u32 = 1;
+
+// Printable ASCII and Latin-1
+fn atlas_characters() -> impl Iterator<Item = char> {
+	(0x20u8..=0x7E).chain(0xA0..=0xFF).map(char::from)
+}
+
+// Of a glyph at one size, in pixels with y pointing down
+#[derive(Debug, Clone, Copy)]
+pub struct GlyphMetrics {
+	pub advance: f32,
+	// Offset of the top left corner of the bitmap from the pen on the baseline
+	pub bearing: [f32; 2],
+	pub size: [f32; 2],
+	pub uv_min: [f32; 2],
+	pub uv_max: [f32; 2],
+}
+
+// Glyph placed by `FontAtlasGenerator::layout_text`, in pixels from the top
+// left corner of the text
+#[derive(Debug, Clone, Copy)]
+pub struct GlyphQuad {
+	pub min: [f32; 2],
+	pub max: [f32; 2],
+	pub uv_min: [f32; 2],
+	pub uv_max: [f32; 2],
+}
+
+// Bitmap of a glyph before packing
+struct RasterizedGlyph {
+	key: (char, u16),
+	advance: f32,
+	bearing: [f32; 2],
+	width: u32,
+	height: u32,
+	coverage: Vec<u8>,
+}
+
+// Positions of rectangles packed on shelves: sorted from the tallest, they
+// fill rows left to right, a new row starting above the tallest of the last
+// one when the width runs out. None when they overflow `atlas_size`.
+fn shelf_pack(sizes: &[[u32; 2]], atlas_size: u32) -> Option<Vec<[u32; 2]>> {
+	let mut order: Vec<usize> = (0..sizes.len()).collect();
+	order.sort_by_key(|&index| std::cmp::Reverse(sizes[index][1]));
+
+	let mut positions = vec![[0, 0]; sizes.len()];
+	let (mut x, mut y, mut shelf_height) = (GLYPH_PADDING, GLYPH_PADDING, 0);
+	for index in order {
+		let [width, height] = sizes[index];
+		if x + width + GLYPH_PADDING > atlas_size {
+			x = GLYPH_PADDING;
+			y += shelf_height + GLYPH_PADDING;
+			shelf_height = 0;
+		}
+		if x + width + GLYPH_PADDING > atlas_size || y + height + GLYPH_PADDING > atlas_size {
+			return None;
+		}
+		positions[index] = [x, y];
+		x += width + GLYPH_PADDING;
+		shelf_height = shelf_height.max(height);
+	}
+	Some(positions)
+}
+
+// Coverage atlas of a TTF font rasterized at startup with rusttype, for text
+// at fixed sizes without offline tooling. The glyphs of every size are shelf
+// packed into the smallest square power of two texture that holds them, an
+// R8Unorm image.
+pub struct FontAtlasGenerator {
+	font: Font<'static>,
+	atlas: Arc<ImmutableImage<Format>>,
+	atlas_size: u32,
+	glyphs: HashMap<(char, u16), GlyphMetrics>,
+}
+
+impl FontAtlasGenerator {
+	// `sizes` in points, DEFAULT_FONT_SIZES for most uses
+	pub fn new(
+		queue: Arc<Queue>,
+		font_path: &Path,
+		sizes: &[u16],
+	) -> Result<(FontAtlasGenerator, Box<dyn GpuFuture>), VulkanoError> {
+		let font = Font::try_from_vec(fs::read(font_path)?).ok_or(VulkanoError::FontLoad)?;
+
+		let mut rasterized = Vec::new();
+		for &size in sizes {
+			let scale = Scale::uniform(size as f32 * PIXELS_PER_POINT);
+			for character in atlas_characters() {
+				let glyph = font.glyph(character);
+				// Missing from the font, the .notdef box would be drawn instead
+				if glyph.id().0 == 0 {
+					continue;
+				}
+				let glyph = glyph.scaled(scale);
+				let advance = glyph.h_metrics().advance_width;
+				let glyph = glyph.positioned(point(0.0, 0.0));
+				let (bearing, width, height, coverage) = match glyph.pixel_bounding_box() {
+					Some(bounds) => {
+						let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+						let mut coverage = vec![0u8; (width * height) as usize];
+						glyph.draw(|x, y, value| {
+							coverage[(y * width + x) as usize] = (value * 255.0).round() as u8;
+						});
+						([bounds.min.x as f32, bounds.min.y as f32], width, height, coverage)
+					}
+					// Spaces
+					None => ([0.0, 0.0], 0, 0, Vec::new()),
+				};
+				rasterized.push(RasterizedGlyph {
+					key: (character, size),
+					advance,
+					bearing,
+					width,
+					height,
+					coverage,
+				});
+			}
+		}
+
+		let sizes: Vec<[u32; 2]> = rasterized.iter().map(|glyph| [glyph.width, glyph.height]).collect();
+		let mut atlas_size = 64;
+		let positions = loop {
+			if let Some(positions) = shelf_pack(&sizes, atlas_size) {
+				break positions;
+			}
+			if atlas_size >= MAX_FONT_ATLAS_SIZE {
+				return Err(VulkanoError::FontAtlasFull(MAX_FONT_ATLAS_SIZE));
+			}
+			atlas_size *= 2;
+		};
+		debug!("Packed {} glyphs in a {}x{} font atlas", rasterized.len(), atlas_size, atlas_size);
+
+		let mut pixels = vec![0u8; (atlas_size * atlas_size) as usize];
+		let mut glyphs = HashMap::new();
+		for (glyph, &[x, y]) in rasterized.iter().zip(positions.iter()) {
+			for row in 0..glyph.height {
+				let source = (row * glyph.width) as usize;
+				let destination = ((y + row) * atlas_size + x) as usize;
+				pixels[destination..destination + glyph.width as usize]
+					.copy_from_slice(&glyph.coverage[source..source + glyph.width as usize]);
+			}
+			let texel = 1.0 / atlas_size as f32;
+			glyphs.insert(
+				glyph.key,
+				GlyphMetrics {
+					advance: glyph.advance,
+					bearing: glyph.bearing,
+					size: [glyph.width as f32, glyph.height as f32],
+					uv_min: [x as f32 * texel, y as f32 * texel],
+					uv_max: [(x + glyph.width) as f32 * texel, (y + glyph.height) as f32 * texel],
+				},
+			);
+		}
+
+		let (atlas, atlas_future) = ImmutableImage::from_iter(
+			pixels.into_iter(),
+			ImageDimensions::Dim2d {
+				width: atlas_size,
+				height: atlas_size,
+				array_layers: 1,
+			},
+			MipmapsCount::One,
+			Format::R8Unorm,
+			queue,
+		)?;
+
+		let generator = FontAtlasGenerator {
+			font,
+			atlas,
+			atlas_size,
+			glyphs,
+		};
+		Ok((generator, atlas_future.boxed()))
+	}
+
+	pub fn atlas(&self) -> Arc<ImmutableImage<Format>> {
+		self.atlas.clone()
+	}
+
+	pub fn atlas_size(&self) -> u32 {
+		self.atlas_size
+	}
+
+	// None for the characters and sizes that weren't rasterized
+	pub fn glyph(&self, character: char, size: u16) -> Option<&GlyphMetrics> {
+		self.glyphs.get(&(character, size))
+	}
+
+	// Distance between the baselines of two lines, in pixels
+	pub fn line_height(&self, size: u16) -> f32 {
+		let metrics = self.font.v_metrics(Scale::uniform(size as f32 * PIXELS_PER_POINT));
+		metrics.ascent - metrics.descent + metrics.line_gap
+	}
+
+	// Width of the longest line and height of the lines of `text`, in pixels
+	pub fn measure_text(&self, text: &str, size: u16) -> [f32; 2] {
+		let mut width: f32 = 0.0;
+		let mut lines = 0;
+		for line in text.split('\n') {
+			width = width.max(self.line_width(line, size));
+			lines += 1;
+		}
+		[width, lines as f32 * self.line_height(size)]
+	}
+
+	// Quads of the visible glyphs of `text`, kerned, the first baseline one
+	// ascent below the top. Characters missing from the atlas are skipped.
+	pub fn layout_text(&self, text: &str, size: u16) -> Vec<GlyphQuad> {
+		let scale = Scale::uniform(size as f32 * PIXELS_PER_POINT);
+		let line_height = self.line_height(size);
+		let mut quads = Vec::with_capacity(text.len());
+		let mut baseline = self.font.v_metrics(scale).ascent;
+		for line in text.split('\n') {
+			let mut pen = 0.0;
+			let mut previous = None;
+			for character in line.chars() {
+				let glyph = match self.glyphs.get(&(character, size)) {
+					Some(glyph) => glyph,
+					None => continue,
+				};
+				if let Some(previous) = previous {
+					pen += self.font.pair_kerning(scale, previous, character);
+				}
+				if glyph.size[0] > 0.0 {
+					let min = [pen + glyph.bearing[0], baseline + glyph.bearing[1]];
+					quads.push(GlyphQuad {
+						min,
+						max: [min[0] + glyph.size[0], min[1] + glyph.size[1]],
+						uv_min: glyph.uv_min,
+						uv_max: glyph.uv_max,
+					});
+				}
+				pen += glyph.advance;
+				previous = Some(character);
+			}
+			baseline += line_height;
+		}
+		quads
+	}
+
+	fn line_width(&self, line: &str, size: u16) -> f32 {
+		let scale = Scale::uniform(size as f32 * PIXELS_PER_POINT);
+		let mut width = 0.0;
+		let mut previous = None;
+		for character in line.chars() {
+			if let Some(glyph) = self.glyphs.get(&(character, size)) {
+				if let Some(previous) = previous {
+					width += self.font.pair_kerning(scale, previous, character);
+				}
+				width += glyph.advance;
+				previous = Some(character);
+			}
+		}
+		width
+	}
+}
+
+// Glyphs of the dynamic atlas each take a square cell of this side
+const DYNAMIC_ATLAS_CELL: u32 = 64;
+const DYNAMIC_ATLAS_INITIAL_SIZE: u32 = 512;
+const DYNAMIC_ATLAS_MAX_SIZE: u32 = 2048;
+// Em size the glyphs are rasterized at, in pixels, lowered for the glyphs
+// that wouldn't fit their cell
+const SDF_RASTER_SIZE: f32 = 40.0;
+// Distance encoded on each side of the outlines, in pixels
+const SDF_SPREAD: u32 = 6;
+
+mod dynamic_text_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+			layout(location = 1) in vec4 v_color;
+
+			layout(location = 0) out vec4 f_color;
+
+			// 0.5 on the outline, growing inside
+			layout(set = 0, binding = 0) uniform sampler2D atlas;
+
+			void main() {
+				// In texels, the atlas grows between frames
+				float distance = texture(atlas, v_uv / vec2(textureSize(atlas, 0))).r;
+				float width = max(fwidth(distance), 1e-4);
+				float opacity = clamp((distance - 0.5) / width + 0.5, 0.0, 1.0);
+				f_color = vec4(v_color.rgb, v_color.a * opacity);
+			}
+		"
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedGlyph {
+	// None for the glyphs without outline
+	cell: Option<[u32; 2]>,
+	// Pixels per em the glyph was rasterized at, the metrics being in pixels
+	em_pixels: f32,
+	advance: f32,
+	// Offset of the top left of the patch from the pen on the baseline, y down
+	bearing: [f32; 2],
+	size: [f32; 2],
+	last_used: u64,
+}
+
+// Distances of a glyph waiting for its copy into the atlas
+struct PendingPatch {
+	cell: [u32; 2],
+	distances: Vec<u8>,
+}
+
+// Single channel SDF of a coverage bitmap, padded by SDF_SPREAD on each side
+// in a DYNAMIC_ATLAS_CELL square, by a brute force search of the closest texel
+// on the other side of the outline
+fn signed_distance_patch(coverage: &[u8], width: u32, height: u32) -> Vec<u8> {
+	let spread = SDF_SPREAD as i32;
+	let inside = |x: i32, y: i32| {
+		x >= 0 && y >= 0 && x < width as i32 && y < height as i32 && coverage[(y * width as i32 + x) as usize] >= 128
+	};
+	let mut distances = vec![0u8; (DYNAMIC_ATLAS_CELL * DYNAMIC_ATLAS_CELL) as usize];
+	for patch_y in 0..(height + 2 * SDF_SPREAD).min(DYNAMIC_ATLAS_CELL) {
+		for patch_x in 0..(width + 2 * SDF_SPREAD).min(DYNAMIC_ATLAS_CELL) {
+			let (x, y) = (patch_x as i32 - spread, patch_y as i32 - spread);
+			let state = inside(x, y);
+			let mut nearest = spread as f32;
+			for dy in -spread..=spread {
+				for dx in -spread..=spread {
+					if inside(x + dx, y + dy) != state {
+						nearest = nearest.min(((dx * dx + dy * dy) as f32).sqrt());
+					}
+				}
+			}
+			// The outline runs between the texel centers
+			let distance = if state { nearest - 0.5 } else { 0.5 - nearest };
+			let encoded = 0.5 + distance / (2.0 * SDF_SPREAD as f32);
+			distances[(patch_y * DYNAMIC_ATLAS_CELL + patch_x) as usize] = (encoded.clamp(0.0, 1.0) * 255.0).round() as u8;
+		}
+	}
+	distances
+}
+
+// Cells of an atlas of `size` that aren't in the one of `previous_size`
+fn new_cells(previous_size: u32, size: u32) -> Vec<[u32; 2]> {
+	let (previous, count) = (previous_size / DYNAMIC_ATLAS_CELL, size / DYNAMIC_ATLAS_CELL);
+	let mut cells = Vec::new();
+	for y in 0..count {
+		for x in 0..count {
+			if x >= previous || y >= previous {
+				cells.push([x, y]);
+			}
+		}
+	}
+	// Popped from the end, the cells are used in order
+	cells.reverse();
+	cells
+}
+
+fn dynamic_atlas(device: &Arc<Device>, size: u32) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width: size,
+			height: size,
+			array_layers: 1,
+		},
+		Format::R8Unorm,
+		ImageUsage {
+			transfer_source: true,
+			transfer_destination: true,
+			sampled: true,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+// Text in any font and any Unicode character, rasterized with rusttype on
+// first use instead of baked offline. Each glyph is turned into a signed
+// distance field patch at SDF_RASTER_SIZE, so a single patch serves every
+// size, and copied through a CpuBufferPool chunk into its cell of an R8
+// atlas. The atlas doubles, copying its content over, when its cells run out,
+// until DYNAMIC_ATLAS_MAX_SIZE where the least recently used glyphs are
+// evicted. Glyphs used by the current frame are never evicted, a character
+// that finds no cell isn't drawn.
+pub struct DynamicTextRenderer {
+	pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+	vertex_pool: CpuBufferPool<TextVertex>,
+	patch_pool: CpuBufferPool<u8>,
+	sampler: Arc<Sampler>,
+	font: Font<'static>,
+	atlas: Arc<StorageImage<Format>>,
+	atlas_size: u32,
+	// Once the pending growth is recorded
+	target_size: u32,
+	glyphs: HashMap<char, CachedGlyph>,
+	free_cells: Vec<[u32; 2]>,
+	pending: Vec<PendingPatch>,
+	frame: u64,
+	vertices: Vec<TextVertex>,
+	device: Arc<Device>,
+}
+
+impl DynamicTextRenderer {
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		font_path: &Path,
+	) -> Result<DynamicTextRenderer, VulkanoError> {
+		let font = Font::try_from_vec(fs::read(font_path)?).ok_or(VulkanoError::FontLoad)?;
+
+		let vs = sdf_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = dynamic_text_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<TextVertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.blend_alpha_blending()
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+
+		Ok(DynamicTextRenderer {
+			pipeline,
+			vertex_pool: CpuBufferPool::vertex_buffer(device.clone()),
+			patch_pool: CpuBufferPool::new(device.clone(), BufferUsage::transfer_source()),
+			sampler: Sampler::new(
+				device.clone(),
+				Filter::Linear,
+				Filter::Linear,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)?,
+			font,
+			atlas: dynamic_atlas(&device, DYNAMIC_ATLAS_INITIAL_SIZE)?,
+			atlas_size: DYNAMIC_ATLAS_INITIAL_SIZE,
+			target_size: DYNAMIC_ATLAS_INITIAL_SIZE,
+			glyphs: HashMap::new(),
+			free_cells: new_cells(0, DYNAMIC_ATLAS_INITIAL_SIZE),
+			pending: Vec::new(),
+			frame: 0,
+			vertices: Vec::new(),
+			device,
+		})
+	}
+
+	// Includes the growth not recorded yet
+	pub fn target_atlas_size(&self) -> u32 {
+		self.target_size
+	}
+
+	pub fn cached_glyph_count(&self) -> usize {
+		self.glyphs.len()
+	}
+
+	// Queues `text` with its baseline starting at (`x`, `y`) in normalized
+	// device coordinates, `size` being the em size in the same unit, like
+	// `SdfTextRenderer::draw_text`
+	pub fn draw_text(&mut self, text: &str, x: f32, y: f32, size: f32, color: [f32; 4]) {
+		let mut pen = x;
+		for character in text.chars() {
+			let glyph = match self.cached_glyph(character) {
+				Some(glyph) => glyph,
+				None => continue,
+			};
+			let scale = size / glyph.em_pixels;
+
+			if let Some([cell_x, cell_y]) = glyph.cell {
+				let left = pen + glyph.bearing[0] * scale;
+				let top = y + glyph.bearing[1] * scale;
+				let right = left + glyph.size[0] * scale;
+				let bottom = top + glyph.size[1] * scale;
+
+				// In texels, divided by the atlas size in the shader
+				let u0 = (cell_x * DYNAMIC_ATLAS_CELL) as f32;
+				let v0 = (cell_y * DYNAMIC_ATLAS_CELL) as f32;
+				let (u1, v1) = (u0 + glyph.size[0], v0 + glyph.size[1]);
+
+				let vertex = |position, uv| TextVertex { position, uv, color };
+				self.vertices.extend_from_slice(&[
+					vertex([left, top], [u0, v0]),
+					vertex([right, top], [u1, v0]),
+					vertex([left, bottom], [u0, v1]),
+					vertex([right, top], [u1, v0]),
+					vertex([right, bottom], [u1, v1]),
+					vertex([left, bottom], [u0, v1]),
+				]);
+			}
+
+			pen += glyph.advance * scale;
+		}
+	}
+
+	// Records the growth of the atlas and the copies of the glyphs rasterized
+	// since the last call, outside of any render pass, before `flush`
+	pub fn upload(&mut self, builder: &mut AutoCommandBufferBuilder) -> Result<(), VulkanoError> {
+		if self.target_size != self.atlas_size {
+			let atlas = dynamic_atlas(&self.device, self.target_size)?;
+			builder.copy_image(
+				self.atlas.clone(),
+				[0, 0, 0],
+				0,
+				0,
+				atlas.clone(),
+				[0, 0, 0],
+				0,
+				0,
+				[self.atlas_size, self.atlas_size, 1],
+				1,
+			)?;
+			debug!("Grew the text atlas to {}x{}", self.target_size, self.target_size);
+			self.atlas = atlas;
+			self.atlas_size = self.target_size;
+		}
+
+		for patch in self.pending.drain(..) {
+			let [x, y] = patch.cell;
+			let chunk = self.patch_pool.chunk(patch.distances)?;
+			builder.copy_buffer_to_image_dimensions(
+				chunk,
+				self.atlas.clone(),
+				[x * DYNAMIC_ATLAS_CELL, y * DYNAMIC_ATLAS_CELL, 0],
+				[DYNAMIC_ATLAS_CELL, DYNAMIC_ATLAS_CELL, 1],
+				0,
+				1,
+				0,
+			)?;
+		}
+		Ok(())
+	}
+
+	// Records the text queued since the last flush, inside the current render
+	// pass, and ends the frame of the LRU
+	pub fn flush(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+	) -> Result<(), VulkanoError> {
+		self.frame += 1;
+		if self.vertices.is_empty() {
+			return Ok(());
+		}
+
+		let layout = self.pipeline.descriptor_set_layout(0).ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(ImageView::new(self.atlas.clone())?, self.sampler.clone())?
+				.build()?,
+		);
+		let vertices = self.vertex_pool.chunk(self.vertices.drain(..))?;
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			vec![Arc::new(vertices) as Arc<dyn BufferAccess + Send + Sync>],
+			set,
+			(),
+			vec![],
+		)?;
+		Ok(())
+	}
+
+	// The glyph of `character`, rasterized and queued for upload on first use.
+	// None when the font lacks it or no cell could be freed.
+	fn cached_glyph(&mut self, character: char) -> Option<CachedGlyph> {
+		if let Some(glyph) = self.glyphs.get_mut(&character) {
+			glyph.last_used = self.frame;
+			return Some(*glyph);
+		}
+
+		let glyph = self.font.glyph(character);
+		if glyph.id().0 == 0 {
+			return None;
+		}
+		// Shrunk until the padded bitmap fits a cell
+		let mut em_pixels = SDF_RASTER_SIZE;
+		let positioned = loop {
+			let positioned = glyph.clone().scaled(Scale::uniform(em_pixels)).positioned(point(0.0, 0.0));
+			let fits = positioned.pixel_bounding_box().is_none_or(|bounds| {
+				let limit = (DYNAMIC_ATLAS_CELL - 2 * SDF_SPREAD) as i32;
+				bounds.width() <= limit && bounds.height() <= limit
+			});
+			if fits {
+				break positioned;
+			}
+			em_pixels *= 0.75;
+		};
+		let advance = positioned.unpositioned().h_metrics().advance_width;
+
+		let mut cached = CachedGlyph {
+			cell: None,
+			em_pixels,
+			advance,
+			bearing: [0.0, 0.0],
+			size: [0.0, 0.0],
+			last_used: self.frame,
+		};
+		if let Some(bounds) = positioned.pixel_bounding_box() {
+			let cell = self.allocate_cell()?;
+			let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+			let mut coverage = vec![0u8; (width * height) as usize];
+			positioned.draw(|x, y, value| {
+				coverage[(y * width + x) as usize] = (value * 255.0).round() as u8;
+			});
+			self.pending.push(PendingPatch {
+				cell,
+				distances: signed_distance_patch(&coverage, width, height),
+			});
+			let spread = SDF_SPREAD as f32;
+			cached.cell = Some(cell);
+			cached.bearing = [bounds.min.x as f32 - spread, bounds.min.y as f32 - spread];
+			cached.size = [width as f32 + 2.0 * spread, height as f32 + 2.0 * spread];
+		}
+		self.glyphs.insert(character, cached);
+		Some(cached)
+	}
+
+	fn allocate_cell(&mut self) -> Option<[u32; 2]> {
+		if let Some(cell) = self.free_cells.pop() {
+			return Some(cell);
+		}
+		if self.target_size < DYNAMIC_ATLAS_MAX_SIZE {
+			self.free_cells = new_cells(self.target_size, self.target_size * 2);
+			self.target_size *= 2;
+			return self.free_cells.pop();
+		}
+
+		let frame = self.frame;
+		let evicted = self
+			.glyphs
+			.iter()
+			.filter(|(_, glyph)| glyph.cell.is_some() && glyph.last_used < frame)
+			.min_by_key(|(_, glyph)| glyph.last_used)
+			.map(|(&character, _)| character);
+		match evicted {
+			Some(character) => self.glyphs.remove(&character).and_then(|glyph| glyph.cell),
+			None => {
+				warn!("The text atlas is full with the glyphs of this frame");
+				None
+			}
+		}
+	}
+}
@@ -0,0 +1,114 @@
+use std::time::Instant;
+
+pub struct DeltaTime {
+	last_instant: Instant,
+	max_dt: f32,
+}
+
+impl Default for DeltaTime {
+	fn default() -> DeltaTime {
+		DeltaTime::new()
+	}
+}
+
+impl DeltaTime {
+	pub fn new() -> DeltaTime {
+		DeltaTime::with_max_dt(1.0 / 20.0)
+	}
+
+	pub fn with_max_dt(max_dt: f32) -> DeltaTime {
+		DeltaTime {
+			last_instant: Instant::now(),
+			max_dt,
+		}
+	}
+
+	// Seconds since the previous tick, clamped so that hitches (debugger pauses,
+	// load spikes) don't make animations jump
+	pub fn tick(&mut self) -> f32 {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last_instant).as_secs_f32();
+		self.last_instant = now;
+		elapsed.min(self.max_dt)
+	}
+}
+
+pub const BENCHMARK_WARMUP_FRAMES: usize = 10;
+pub const BENCHMARK_FRAMES: usize = 1000;
+
+// Collects the duration of a fixed number of frames after a warm up, for the
+// `--benchmark` mode. vulkano's command buffer builder can't write timestamp
+// queries, the frames are timed from one submission to the next instead. Without
+// vsync acquiring an image waits for the GPU to release one, so this follows the
+// GPU time as long as the GPU is the bottleneck.
+pub struct BenchmarkMode {
+	last_frame: Option<Instant>,
+	warmup: usize,
+	frame_times_ms: Vec<f32>,
+	frames: usize,
+}
+
+impl Default for BenchmarkMode {
+	fn default() -> BenchmarkMode {
+		BenchmarkMode::new()
+	}
+}
+
+impl BenchmarkMode {
+	pub fn new() -> BenchmarkMode {
+		BenchmarkMode::with_frames(BENCHMARK_WARMUP_FRAMES, BENCHMARK_FRAMES)
+	}
+
+	pub fn with_frames(warmup: usize, frames: usize) -> BenchmarkMode {
+		BenchmarkMode {
+			last_frame: None,
+			warmup,
+			frame_times_ms: Vec::with_capacity(frames),
+			frames,
+		}
+	}
+
+	// Call once per completed frame, returns true once every frame was recorded
+	pub fn frame_completed(&mut self) -> bool {
+		let now = Instant::now();
+		if let Some(last_frame) = self.last_frame.replace(now) {
+			if self.warmup > 0 {
+				self.warmup -= 1;
+			} else if self.frame_times_ms.len() < self.frames {
+				self.frame_times_ms.push(now.duration_since(last_frame).as_secs_f32() * 1000.0);
+			}
+		}
+		self.frame_times_ms.len() == self.frames
+	}
+
+	pub fn summary(&self) -> serde_json::Value {
+		let mut sorted = self.frame_times_ms.clone();
+		sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+		let total_frames = sorted.len();
+		let percentile = |p: f32| match total_frames {
+			0 => 0.0,
+			n => sorted[((n as f32 * p).ceil() as usize).clamp(1, n) - 1],
+		};
+
+		serde_json::json!({
+			"min_ms": sorted.first().copied().unwrap_or(0.0),
+			"max_ms": sorted.last().copied().unwrap_or(0.0),
+			"avg_ms": sorted.iter().sum::<f32>() / total_frames.max(1) as f32,
+			"p99_ms": percentile(0.99),
+			"total_frames": total_frames,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn long_gap_is_clamped() {
+		let mut delta_time = DeltaTime::new();
+		delta_time.last_instant = Instant::now() - Duration::from_secs(10);
+		assert_eq!(delta_time.tick(), 1.0 / 20.0);
+	}
+}
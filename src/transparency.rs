@@ -0,0 +1,406 @@
+use std::sync::Arc;
+
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::Device;
+use vulkano::format::{ClearValue, Format};
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageAccess, SwapchainImage};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::GraphicsPipeline;
+
+use winit::window::Window;
+
+use crate::debug_utils::{DebugNameRegistry, LABEL_COLOR_RENDER_PASS};
+use crate::error::VulkanoError;
+use crate::glsl_shaders::fullscreen_vs;
+use crate::pipeline::BufferlessPipeline;
+
+// Above this many transparent draws the depths are sorted with a radix sort
+const RADIX_SORT_THRESHOLD: usize = 64;
+
+// Maps a float to an integer with the same ordering, negative values included
+pub fn sortable_bits(value: f32) -> u32 {
+	let bits = value.to_bits();
+	if bits & 0x8000_0000 != 0 {
+		!bits
+	} else {
+		bits | 0x8000_0000
+	}
+}
+
+// LSD radix sort of (key, index) pairs, one byte per pass
+fn radix_sort(mut keys: Vec<(u32, usize)>) -> Vec<(u32, usize)> {
+	let mut scratch = vec![(0, 0); keys.len()];
+	for shift in (0..32).step_by(8) {
+		let mut offsets = [0usize; 257];
+		for &(key, _) in &keys {
+			offsets[((key >> shift) & 0xff) as usize + 1] += 1;
+		}
+		for i in 1..offsets.len() {
+			offsets[i] += offsets[i - 1];
+		}
+		for &entry in &keys {
+			let bucket = ((entry.0 >> shift) & 0xff) as usize;
+			scratch[offsets[bucket]] = entry;
+			offsets[bucket] += 1;
+		}
+		std::mem::swap(&mut keys, &mut scratch);
+	}
+	keys
+}
+
+// Collects the draws of a frame and orders them for recording: opaque draws
+// keep their submission order, transparent draws are sorted back to front
+// along the camera's view direction so that alpha blending composes correctly.
+pub struct DrawCallSorter<T> {
+	opaque: Vec<T>,
+	transparent: Vec<(T, [f32; 3])>,
+}
+
+impl<T> Default for DrawCallSorter<T> {
+	fn default() -> DrawCallSorter<T> {
+		DrawCallSorter::new()
+	}
+}
+
+impl<T> DrawCallSorter<T> {
+	pub fn new() -> DrawCallSorter<T> {
+		DrawCallSorter {
+			opaque: Vec::new(),
+			transparent: Vec::new(),
+		}
+	}
+
+	pub fn push_opaque(&mut self, draw: T) {
+		self.opaque.push(draw);
+	}
+
+	// `position` is the world space position of the object
+	pub fn push_transparent(&mut self, draw: T, position: [f32; 3]) {
+		self.transparent.push((draw, position));
+	}
+
+	// Returns the opaque draws, to record first, and the transparent draws
+	// farthest first. `view_direction` must be normalized.
+	pub fn finish(&mut self, camera_position: [f32; 3], view_direction: [f32; 3]) -> (Vec<T>, Vec<T>) {
+		let opaque = std::mem::take(&mut self.opaque);
+		let transparent = DrawCallSorter::sort(
+			std::mem::take(&mut self.transparent),
+			camera_position,
+			view_direction,
+		);
+		(opaque, transparent)
+	}
+
+	// Sorts by descending depth along `view_direction`
+	pub fn sort(draws: Vec<(T, [f32; 3])>, camera_position: [f32; 3], view_direction: [f32; 3]) -> Vec<T> {
+		let depth = |position: &[f32; 3]| {
+			(0..3)
+				.map(|i| (position[i] - camera_position[i]) * view_direction[i])
+				.sum::<f32>()
+		};
+
+		let mut keys: Vec<(f32, usize)> = draws
+			.iter()
+			.enumerate()
+			.map(|(i, (_, position))| (depth(position), i))
+			.collect();
+
+		let order: Vec<usize> = if keys.len() > RADIX_SORT_THRESHOLD {
+			// Inverting the bits sorts the farthest first
+			let keys = keys.iter().map(|&(depth, i)| (!sortable_bits(depth), i)).collect();
+			radix_sort(keys).into_iter().map(|(_, i)| i).collect()
+		} else {
+			keys.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+			keys.into_iter().map(|(_, i)| i).collect()
+		};
+
+		let mut draws: Vec<Option<T>> = draws.into_iter().map(|(draw, _)| Some(draw)).collect();
+		order.into_iter().filter_map(|i| draws[i].take()).collect()
+	}
+}
+
+mod oit_composite_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput accum;
+			layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput accum_weight;
+			layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput revealage;
+
+			layout(location = 0) out vec4 f_color;
+
+			void main() {
+				float revealed = subpassLoad(revealage).r;
+				// Nothing transparent covers this pixel
+				if (revealed >= 1.0) {
+					discard;
+				}
+				vec3 average = subpassLoad(accum).rgb / max(subpassLoad(accum_weight).r, 1e-5);
+				// Blended over the opaque background by the alpha blending
+				f_color = vec4(average, 1.0 - revealed);
+			}
+		"
+	}
+}
+
+// Sum of color * alpha * w, R11G11B10 has no alpha channel so the sum of
+// alpha * w gets its own image
+pub const OIT_ACCUM_FORMAT: Format = Format::B10G11R11UfloatPack32;
+pub const OIT_WEIGHT_FORMAT: Format = Format::R16Sfloat;
+// Product of 1 - alpha, the fraction of the background still visible
+pub const OIT_REVEALAGE_FORMAT: Format = Format::R16Sfloat;
+
+// The images of the accumulation, recreated with the swapchain
+struct OitTargets {
+	framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+	composite_set: Arc<dyn DescriptorSet + Send + Sync>,
+}
+
+// McGuire and Bavoil's Weighted Blended Order-Independent Transparency. The
+// transparent geometry is drawn in any order in the first subpass, writing
+// `oit_output` of shaders/oit.glsl with the blending of `accumulate_blend`.
+// The second subpass divides the accumulated color by the accumulated weight
+// and blends it over the opaque image, which the render pass loads.
+// There is no depth buffer in the render pass yet, the transparent geometry
+// isn't tested against the opaque depth.
+pub struct OitPass {
+	device: Arc<Device>,
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	composite_pipeline: Arc<BufferlessPipeline>,
+	targets: Option<OitTargets>,
+}
+
+impl OitPass {
+	// `output_format` is the format of the opaque image the transparency is composited over
+	pub fn new(device: Arc<Device>, output_format: Format) -> Result<OitPass, VulkanoError> {
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(vulkano::ordered_passes_renderpass!(
+			device.clone(),
+			attachments: {
+				accum: {
+					load: Clear,
+					store: DontCare,
+					format: OIT_ACCUM_FORMAT,
+					samples: 1,
+				},
+				accum_weight: {
+					load: Clear,
+					store: DontCare,
+					format: OIT_WEIGHT_FORMAT,
+					samples: 1,
+				},
+				revealage: {
+					load: Clear,
+					store: DontCare,
+					format: OIT_REVEALAGE_FORMAT,
+					samples: 1,
+				},
+				color: {
+					load: Load,
+					store: Store,
+					format: output_format,
+					samples: 1,
+				}
+			},
+			passes: [
+				{
+					color: [accum, accum_weight, revealage],
+					depth_stencil: {},
+					input: []
+				},
+				{
+					color: [color],
+					depth_stencil: {},
+					input: [accum, accum_weight, revealage]
+				}
+			]
+		)?);
+
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = oit_composite_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let composite_pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input(BufferlessDefinition {})
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.blend_alpha_blending()
+				.render_pass(Subpass::from(render_pass.clone(), 1).ok_or(VulkanoError::NoSubpass)?)
+				.build(device.clone())?,
+		);
+
+		Ok(OitPass {
+			device,
+			render_pass,
+			composite_pipeline,
+			targets: None,
+		})
+	}
+
+	// The subpass to build the transparent pipelines for
+	pub fn accumulate_subpass(&self) -> Subpass<Arc<dyn RenderPassAbstract + Send + Sync>> {
+		Subpass::from(self.render_pass.clone(), 0).unwrap()
+	}
+
+	// For `blend_individual` of the transparent pipelines: the color and the
+	// weight are summed, the revealage multiplied by 1 - alpha
+	pub fn accumulate_blend() -> Vec<AttachmentBlend> {
+		let additive = AttachmentBlend {
+			enabled: true,
+			color_op: BlendOp::Add,
+			color_source: BlendFactor::One,
+			color_destination: BlendFactor::One,
+			alpha_op: BlendOp::Add,
+			alpha_source: BlendFactor::One,
+			alpha_destination: BlendFactor::One,
+			..AttachmentBlend::pass_through()
+		};
+		let revealage = AttachmentBlend {
+			color_source: BlendFactor::Zero,
+			color_destination: BlendFactor::OneMinusSrcColor,
+			alpha_source: BlendFactor::Zero,
+			alpha_destination: BlendFactor::OneMinusSrcAlpha,
+			..additive.clone()
+		};
+		vec![additive.clone(), additive, revealage]
+	}
+
+	// Recreates the accumulation images at the size of `images`, from `window_size_dependent_setup`
+	pub fn resize(
+		&mut self,
+		images: &[Arc<SwapchainImage<Arc<Window>>>],
+		debug_names: &DebugNameRegistry,
+	) -> Result<(), VulkanoError> {
+		let dimensions = SwapchainImage::dimensions(&images[0]);
+		let image = |format| -> Result<_, VulkanoError> {
+			let image = AttachmentImage::transient_input_attachment(self.device.clone(), dimensions, format)?;
+			Ok(ImageView::new(image)?)
+		};
+		let accum = image(OIT_ACCUM_FORMAT)?;
+		let accum_weight = image(OIT_WEIGHT_FORMAT)?;
+		let revealage = image(OIT_REVEALAGE_FORMAT)?;
+		debug_names.name(accum.image().inner().image, "oit_accum");
+		debug_names.name(accum_weight.image().inner().image, "oit_accum_weight");
+		debug_names.name(revealage.image().inner().image, "oit_revealage");
+
+		let layout = self
+			.composite_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let composite_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(accum.clone())?
+				.add_image(accum_weight.clone())?
+				.add_image(revealage.clone())?
+				.build()?,
+		);
+
+		let framebuffers = images
+			.iter()
+			.map(|image| {
+				let framebuffer = Framebuffer::start(self.render_pass.clone())
+					.add(accum.clone())?
+					.add(accum_weight.clone())?
+					.add(revealage.clone())?
+					.add(ImageView::new(image.clone())?)?
+					.build()?;
+				Ok(Arc::new(framebuffer) as Arc<dyn FramebufferAbstract + Send + Sync>)
+			})
+			.collect::<Result<_, VulkanoError>>()?;
+
+		self.targets = Some(OitTargets {
+			framebuffers,
+			composite_set,
+		});
+		Ok(())
+	}
+
+	// Records both subpasses over the swapchain image `image_num`, which must
+	// already hold the opaque scene. `draw_transparent` records the transparent
+	// draws in the accumulation subpass.
+	pub fn draw<F>(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		image_num: usize,
+		draw_transparent: F,
+	) -> Result<(), VulkanoError>
+	where
+		F: FnOnce(&mut AutoCommandBufferBuilder, &DynamicState) -> Result<(), VulkanoError>,
+	{
+		let targets = self.targets.as_ref().expect("OitPass::resize wasn't called");
+		let clear_values = vec![
+			[0.0, 0.0, 0.0, 0.0].into(),
+			[0.0, 0.0, 0.0, 0.0].into(),
+			// Everything is revealed until something is drawn
+			[1.0, 0.0, 0.0, 0.0].into(),
+			ClearValue::None,
+		];
+		debug_label!(builder, "OitPass", LABEL_COLOR_RENDER_PASS);
+		builder.begin_render_pass(targets.framebuffers[image_num].clone(), SubpassContents::Inline, clear_values)?;
+		draw_transparent(builder, dynamic_state)?;
+		builder.next_subpass(SubpassContents::Inline)?;
+		builder.draw(
+			self.composite_pipeline.clone(),
+			dynamic_state,
+			BufferlessVertices {
+				vertices: 3,
+				instances: 1,
+			},
+			targets.composite_set.clone(),
+			(),
+			vec![],
+		)?;
+		builder.end_render_pass()?;
+		debug_label!(builder);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sortable_bits_keeps_the_order() {
+		let values = [-100.0, -1.5, -0.0, 0.0, 0.25, 3.0, 1e9];
+		for pair in values.windows(2) {
+			assert!(sortable_bits(pair[0]) <= sortable_bits(pair[1]), "{:?}", pair);
+		}
+	}
+
+	// Above and below RADIX_SORT_THRESHOLD
+	#[test]
+	fn transparent_draws_farthest_first() {
+		for &count in &[8, 1000] {
+			let draws = (0..count)
+				.map(|i| {
+					let depth = ((i * 7919) % count) as f32 - 10.0;
+					(depth, [0.0, 0.0, depth])
+				})
+				.collect();
+			let sorted = DrawCallSorter::sort(draws, [0.0; 3], [0.0, 0.0, 1.0]);
+			assert_eq!(sorted.len(), count);
+			assert!(sorted.windows(2).all(|pair| pair[0] >= pair[1]), "{} draws", count);
+		}
+	}
+
+	#[test]
+	fn opaque_draws_keep_their_order() {
+		let mut sorter = DrawCallSorter::new();
+		sorter.push_opaque(1);
+		sorter.push_transparent(2, [0.0, 0.0, 1.0]);
+		sorter.push_opaque(3);
+		sorter.push_transparent(4, [0.0, 0.0, 5.0]);
+		let (opaque, transparent) = sorter.finish([0.0; 3], [0.0, 0.0, 1.0]);
+		assert_eq!(opaque, [1, 3]);
+		assert_eq!(transparent, [4, 2]);
+	}
+}
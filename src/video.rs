@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use log::*;
+
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::{RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::instance::PhysicalDevice;
+use vulkano::pipeline::vertex::{BufferlessDefinition, BufferlessVertices};
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::error::VulkanoError;
+use crate::glsl_shaders::fullscreen_vs;
+use crate::pipeline::BufferlessPipeline;
+
+mod yuv_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 0) uniform sampler2D y_plane;
+			layout(set = 0, binding = 1) uniform sampler2D uv_plane;
+
+			void main() {
+				// BT.709, limited range
+				float y = (texture(y_plane, v_uv).r - 16.0 / 255.0) * (255.0 / 219.0);
+				vec2 cbcr = (texture(uv_plane, v_uv).rg - 128.0 / 255.0) * (255.0 / 224.0);
+
+				vec3 rgb = vec3(
+					y + 1.5748 * cbcr.y,
+					y - 0.1873 * cbcr.x - 0.4681 * cbcr.y,
+					y + 1.8556 * cbcr.x
+				);
+				// The swapchain format is sRGB, the hardware encodes the linear output
+				f_color = vec4(pow(clamp(rgb, 0.0, 1.0), vec3(2.2)), 1.0);
+			}
+		"
+	}
+}
+
+// Uploads NV12 frames (a full resolution Y plane followed by a half resolution
+// interleaved UV plane) into one sampled image per plane
+#[allow(clippy::type_complexity)]
+pub struct VideoFrameUploader {
+	device: Arc<Device>,
+	staging: CpuBufferPool<u8>,
+	planes: Option<(Arc<StorageImage<Format>>, Arc<StorageImage<Format>>)>,
+	dimensions: [u32; 2],
+}
+
+impl VideoFrameUploader {
+	pub fn new(device: Arc<Device>) -> VideoFrameUploader {
+		VideoFrameUploader {
+			staging: CpuBufferPool::upload(device.clone()),
+			device,
+			planes: None,
+			dimensions: [0, 0],
+		}
+	}
+
+	// Records the copy of a frame, outside of any render pass
+	pub fn upload(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		y_plane: &[u8],
+		uv_plane: &[u8],
+		width: u32,
+		height: u32,
+	) -> Result<(), VulkanoError> {
+		debug_assert_eq!(y_plane.len(), (width * height) as usize);
+		debug_assert_eq!(uv_plane.len(), (width / 2 * height / 2 * 2) as usize);
+
+		if self.planes.is_none() || self.dimensions != [width, height] {
+			let y_image = self.plane_image(width, height, Format::R8Unorm)?;
+			let uv_image = self.plane_image(width / 2, height / 2, Format::R8G8Unorm)?;
+			self.planes = Some((y_image, uv_image));
+			self.dimensions = [width, height];
+		}
+		let (y_image, uv_image) = self.planes.clone().unwrap();
+
+		builder
+			.copy_buffer_to_image(self.staging.chunk(y_plane.iter().cloned())?, y_image)?
+			.copy_buffer_to_image(self.staging.chunk(uv_plane.iter().cloned())?, uv_image)?;
+		Ok(())
+	}
+
+	fn plane_image(&self, width: u32, height: u32, format: Format) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+		let usage = ImageUsage {
+			sampled: true,
+			transfer_destination: true,
+			..ImageUsage::none()
+		};
+		let image = StorageImage::with_usage(
+			self.device.clone(),
+			ImageDimensions::Dim2d {
+				width,
+				height,
+				array_layers: 1,
+			},
+			format,
+			usage,
+			ImageCreateFlags::none(),
+			self.device.active_queue_families(),
+		)?;
+		Ok(image)
+	}
+}
+
+// Draws the last uploaded video frame over the whole framebuffer.
+// vulkano doesn't support VK_KHR_sampler_ycbcr_conversion nor multi-planar
+// formats, the planes are sampled separately and converted in the shader.
+pub struct YuvSampler {
+	pipeline: Arc<BufferlessPipeline>,
+	sampler: Arc<Sampler>,
+}
+
+impl YuvSampler {
+	// Whether the plane formats can be sampled with linear filtering
+	pub fn is_supported(physical: PhysicalDevice) -> bool {
+		[Format::R8Unorm, Format::R8G8Unorm].iter().all(|format| {
+			let features = format.properties(physical).optimal_tiling_features;
+			features.sampled_image && features.sampled_image_filter_linear
+		})
+	}
+
+	pub fn new(
+		device: Arc<Device>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+	) -> Result<YuvSampler, VulkanoError> {
+		if !YuvSampler::is_supported(device.physical_device()) {
+			warn!("The device can't sample R8/R8G8 images, video frames can't be displayed");
+			return Err(VulkanoError::Unsupported("sampling YUV planes"));
+		}
+
+		let vs = fullscreen_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = yuv_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let pipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input(BufferlessDefinition {})
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.render_pass(subpass)
+				.build(device.clone())?,
+		);
+
+		let sampler = Sampler::new(
+			device,
+			Filter::Linear,
+			Filter::Linear,
+			MipmapMode::Nearest,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			SamplerAddressMode::ClampToEdge,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+
+		Ok(YuvSampler { pipeline, sampler })
+	}
+
+	// Records the draw inside the current render pass, does nothing until a frame was uploaded
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		frame: &VideoFrameUploader,
+	) -> Result<(), VulkanoError> {
+		let (y_image, uv_image) = match frame.planes.clone() {
+			Some(planes) => planes,
+			None => return Ok(()),
+		};
+
+		let layout = self
+			.pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(ImageView::new(y_image)?, self.sampler.clone())?
+				.add_sampled_image(ImageView::new(uv_image)?, self.sampler.clone())?
+				.build()?,
+		);
+
+		builder.draw(
+			self.pipeline.clone(),
+			dynamic_state,
+			BufferlessVertices {
+				vertices: 3,
+				instances: 1,
+			},
+			set,
+			(),
+			vec![],
+		)?;
+		Ok(())
+	}
+}
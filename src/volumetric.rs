@@ -0,0 +1,407 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{ImageAccess, ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::ComputePipeline;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+
+use crate::debug_utils::LABEL_COLOR_COMPUTE;
+use crate::error::VulkanoError;
+use crate::forward_plus::{PointLight, MAX_LIGHTS};
+use crate::pipeline::SharedComputePipeline;
+
+// Clusters along the screen width, height and the depth, must match the shaders
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+// Must match the shaders, each cluster stores its light count then the indices
+pub const MAX_LIGHTS_PER_CLUSTER: u32 = 31;
+const RADIANCE_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+mod cluster_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			// One invocation per cluster
+			layout(local_size_x = 4, local_size_y = 3, local_size_z = 4) in;
+
+			const uvec3 GRID = uvec3(16, 9, 24);
+			const uint MAX_LIGHTS_PER_CLUSTER = 31;
+			// Ray march steps through the depth of each cluster
+			const int STEPS = 4;
+			const float PI = 3.14159265359;
+
+			struct PointLight {
+				vec3 position;
+				float radius;
+				vec3 color;
+				float intensity;
+			};
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				// Near and far distances covered by the clusters
+				vec4 depth_range;
+				// Grid size and light count
+				uvec4 grid;
+			} camera;
+			layout(set = 0, binding = 1) readonly buffer Lights {
+				PointLight lights[];
+			};
+			layout(set = 0, binding = 2) writeonly buffer ClusterLights {
+				uint cluster_lights[];
+			};
+			// In-scattered light of each cluster in RGB, its transmittance in A
+			layout(set = 0, binding = 3, rgba16f) uniform writeonly image3D radiance;
+
+			layout(push_constant) uniform PushConstants {
+				vec3 scatter_color;
+				float density;
+				// Density decreases exponentially above y = 0
+				float height_falloff;
+				// Henyey-Greenstein g, positive values scatter forward
+				float anisotropy;
+			} pc;
+
+			vec3 unproject(mat4 inverse_projection, vec2 ndc) {
+				vec4 position = inverse_projection * vec4(ndc, 1.0, 1.0);
+				return position.xyz / position.w;
+			}
+
+			// Distance of the start of a depth slice, exponential so the clusters
+			// are roughly cubic
+			float slice_depth(float slice) {
+				return camera.depth_range.x * pow(camera.depth_range.y / camera.depth_range.x, slice / float(GRID.z));
+			}
+
+			float henyey_greenstein(float cos_theta, float g) {
+				float g2 = g * g;
+				return (1.0 - g2) / (4.0 * PI * pow(1.0 + g2 - 2.0 * g * cos_theta, 1.5));
+			}
+
+			void main() {
+				uvec3 cluster = gl_GlobalInvocationID;
+				if (any(greaterThanEqual(cluster, GRID))) {
+					return;
+				}
+
+				// View space bounds of the cluster, view space looks down -Z
+				mat4 inverse_projection = inverse(camera.projection);
+				mat4 inverse_view = inverse(camera.view);
+				vec2 tile_size = 2.0 / vec2(GRID.xy);
+				vec2 tile_min = vec2(cluster.xy) * tile_size - 1.0;
+				vec2 tile_max = tile_min + tile_size;
+				float near = slice_depth(float(cluster.z));
+				float far = slice_depth(float(cluster.z + 1));
+				vec3 corners[4] = vec3[](
+					unproject(inverse_projection, tile_min),
+					unproject(inverse_projection, vec2(tile_max.x, tile_min.y)),
+					unproject(inverse_projection, tile_max),
+					unproject(inverse_projection, vec2(tile_min.x, tile_max.y))
+				);
+				vec3 bounds_min = vec3(1e30);
+				vec3 bounds_max = vec3(-1e30);
+				for (int i = 0; i < 4; i++) {
+					// Scaled onto the near and far planes of the cluster
+					vec3 direction = corners[i] / -corners[i].z;
+					bounds_min = min(bounds_min, min(direction * near, direction * far));
+					bounds_max = max(bounds_max, max(direction * near, direction * far));
+				}
+
+				uint indices[MAX_LIGHTS_PER_CLUSTER];
+				uint count = 0;
+				for (uint i = 0; i < camera.grid.w && count < MAX_LIGHTS_PER_CLUSTER; i++) {
+					vec3 position = (camera.view * vec4(lights[i].position, 1.0)).xyz;
+					vec3 closest = clamp(position, bounds_min, bounds_max);
+					vec3 offset = position - closest;
+					if (dot(offset, offset) <= lights[i].radius * lights[i].radius) {
+						indices[count] = i;
+						count++;
+					}
+				}
+				uint base = ((cluster.z * GRID.y + cluster.y) * GRID.x + cluster.x) * (MAX_LIGHTS_PER_CLUSTER + 1);
+				cluster_lights[base] = count;
+				for (uint i = 0; i < count; i++) {
+					cluster_lights[base + 1 + i] = indices[i];
+				}
+
+				// Front to back along the ray through the center of the cluster
+				vec3 direction = normalize(unproject(inverse_projection, (tile_min + tile_max) * 0.5));
+				float t_near = near / -direction.z;
+				float t_far = far / -direction.z;
+				float step_size = (t_far - t_near) / float(STEPS);
+				vec3 scattered = vec3(0.0);
+				float transmittance = 1.0;
+				for (int step = 0; step < STEPS; step++) {
+					vec3 position = direction * (t_near + (float(step) + 0.5) * step_size);
+					float height = (inverse_view * vec4(position, 1.0)).y;
+					float density = pc.density * exp(-max(height, 0.0) * pc.height_falloff);
+
+					vec3 in_scattered = vec3(0.0);
+					for (uint i = 0; i < count; i++) {
+						PointLight light = lights[indices[i]];
+						vec3 to_light = (camera.view * vec4(light.position, 1.0)).xyz - position;
+						float distance = length(to_light);
+						// Same falloff as the forward shading
+						float falloff = clamp(1.0 - pow(distance / light.radius, 4.0), 0.0, 1.0);
+						float attenuation = falloff * falloff / (distance * distance + 1.0);
+						float phase = henyey_greenstein(dot(-direction, to_light / max(distance, 1e-4)), pc.anisotropy);
+						in_scattered += light.color * light.intensity * attenuation * phase;
+					}
+
+					float step_transmittance = exp(-density * step_size);
+					scattered += transmittance * (1.0 - step_transmittance) * pc.scatter_color * in_scattered;
+					transmittance *= step_transmittance;
+				}
+				imageStore(radiance, ivec3(cluster), vec4(scattered, transmittance));
+			}
+		"
+	}
+}
+
+mod composite_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			const uint SLICES = 24;
+
+			layout(set = 0, binding = 0) uniform Camera {
+				mat4 view;
+				mat4 projection;
+				vec4 depth_range;
+				uvec4 grid;
+			} camera;
+			layout(set = 0, binding = 1) uniform sampler3D radiance;
+			layout(set = 0, binding = 2) uniform sampler2D depth;
+			layout(set = 0, binding = 3) uniform sampler2D color;
+			layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D destination;
+
+			float slice_depth(float slice) {
+				return camera.depth_range.x * pow(camera.depth_range.y / camera.depth_range.x, slice / float(SLICES));
+			}
+
+			void main() {
+				ivec2 size = imageSize(destination);
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				if (pixel.x >= size.x || pixel.y >= size.y) {
+					return;
+				}
+				vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+				vec4 view_position = inverse(camera.projection) * vec4(uv * 2.0 - 1.0, texelFetch(depth, pixel, 0).r, 1.0);
+				float distance = -view_position.z / view_position.w;
+
+				// Slices behind the surface don't contribute, the one it is in partially
+				vec3 scattered = vec3(0.0);
+				float transmittance = 1.0;
+				for (uint slice = 0; slice < SLICES; slice++) {
+					float near = slice_depth(float(slice));
+					if (distance <= near) {
+						break;
+					}
+					float coverage = clamp((distance - near) / (slice_depth(float(slice + 1)) - near), 0.0, 1.0);
+					vec4 cluster = textureLod(radiance, vec3(uv, (float(slice) + 0.5) / float(SLICES)), 0.0);
+					scattered += transmittance * cluster.rgb * coverage;
+					transmittance *= pow(cluster.a, coverage);
+				}
+
+				vec4 scene = texelFetch(color, pixel, 0);
+				imageStore(destination, pixel, vec4(scene.rgb * transmittance + scattered, scene.a));
+			}
+		"
+	}
+}
+
+// Volumetric lighting from the point lights of the forward+ renderer. The
+// view frustum is split into 16x9x24 clusters (froxels), the depth slices
+// growing exponentially between `near` and `far`. Each frame a compute pass
+// rebuilds the cluster bounds from the projection, assigns the lights to the
+// clusters and marches the center ray of each cluster through a height fog,
+// writing the in-scattered light and the transmittance of the clusters to a 3D
+// image. The composite then accumulates the slices in front of every pixel
+// over the scene color.
+pub struct ClusteredVolumetricPass {
+	cluster_pipeline: SharedComputePipeline,
+	composite_pipeline: SharedComputePipeline,
+	camera_pool: CpuBufferPool<cluster_cs::ty::Camera>,
+	light_pool: CpuBufferPool<PointLight>,
+	cluster_lights: Arc<DeviceLocalBuffer<[u32]>>,
+	radiance: Arc<StorageImage<Format>>,
+	radiance_sampler: Arc<Sampler>,
+	sampler: Arc<Sampler>,
+	// Distances covered by the clusters, usually those of the projection
+	pub near: f32,
+	pub far: f32,
+	pub density: f32,
+	pub height_falloff: f32,
+	pub scatter_color: [f32; 3],
+	pub anisotropy: f32,
+}
+
+impl ClusteredVolumetricPass {
+	pub fn new(device: Arc<Device>, near: f32, far: f32) -> Result<ClusteredVolumetricPass, VulkanoError> {
+		let [width, height, depth] = CLUSTER_GRID;
+		let radiance = StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim3d { width, height, depth },
+			RADIANCE_FORMAT,
+			ImageUsage {
+				storage: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)?;
+		let cluster_lights = DeviceLocalBuffer::array(
+			device.clone(),
+			(width * height * depth * (MAX_LIGHTS_PER_CLUSTER + 1)) as usize,
+			BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+			device.active_queue_families(),
+		)?;
+
+		let cluster_shader = cluster_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let composite_shader = composite_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+
+		let sampler = |filter| {
+			Sampler::new(
+				device.clone(),
+				filter,
+				filter,
+				MipmapMode::Nearest,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				SamplerAddressMode::ClampToEdge,
+				0.0,
+				1.0,
+				0.0,
+				0.0,
+			)
+		};
+
+		Ok(ClusteredVolumetricPass {
+			cluster_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&cluster_shader.main_entry_point(),
+				&(),
+				None,
+			)?),
+			composite_pipeline: Arc::new(ComputePipeline::new(
+				device.clone(),
+				&composite_shader.main_entry_point(),
+				&(),
+				None,
+			)?),
+			camera_pool: CpuBufferPool::uniform_buffer(device.clone()),
+			light_pool: CpuBufferPool::new(device.clone(), BufferUsage { storage_buffer: true, ..BufferUsage::none() }),
+			cluster_lights,
+			radiance,
+			// The clusters are much larger than pixels, their radiance is interpolated
+			radiance_sampler: sampler(Filter::Linear)?,
+			sampler: sampler(Filter::Nearest)?,
+			near,
+			far,
+			density: 0.02,
+			height_falloff: 0.1,
+			scatter_color: [1.0, 1.0, 1.0],
+			anisotropy: 0.2,
+		})
+	}
+
+	// The light count then the light indices of each cluster, written by `record`
+	pub fn cluster_lights(&self) -> Arc<DeviceLocalBuffer<[u32]>> {
+		self.cluster_lights.clone()
+	}
+
+	// Records the clustering and the composite over `color` into `destination`
+	// (RGBA16F), outside of any render pass
+	#[allow(clippy::too_many_arguments)]
+	pub fn record(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		depth: Arc<dyn ImageViewAbstract + Send + Sync>,
+		color: Arc<dyn ImageViewAbstract + Send + Sync>,
+		destination: Arc<StorageImage<Format>>,
+		lights: &[PointLight],
+		view: [[f32; 4]; 4],
+		projection: [[f32; 4]; 4],
+	) -> Result<(), VulkanoError> {
+		let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+		let [x, y, z] = CLUSTER_GRID;
+		let camera = self.camera_pool.next(cluster_cs::ty::Camera {
+			view,
+			projection,
+			depth_range: [self.near, self.far, 0.0, 0.0],
+			grid: [x, y, z, lights.len() as u32],
+		})?;
+		// Empty buffers can't be bound, the count keeps the placeholder unused
+		let light_buffer = if lights.is_empty() {
+			self.light_pool.chunk(vec![PointLight::default()])?
+		} else {
+			self.light_pool.chunk(lights.iter().copied())?
+		};
+
+		let layout = self
+			.cluster_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera.clone())?
+				.add_buffer(light_buffer)?
+				.add_buffer(self.cluster_lights.clone())?
+				.add_image(ImageView::new(self.radiance.clone())?)?
+				.build()?,
+		);
+		debug_label!(builder, "ClusteredVolumetricPass cluster", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[x / 4, y / 3, z / 4],
+			self.cluster_pipeline.clone(),
+			set,
+			cluster_cs::ty::PushConstants {
+				scatter_color: self.scatter_color,
+				density: self.density,
+				height_falloff: self.height_falloff,
+				anisotropy: self.anisotropy,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		let [width, height] = destination.dimensions().width_height();
+		let layout = self
+			.composite_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_buffer(camera)?
+				.add_sampled_image(ImageView::new(self.radiance.clone())?, self.radiance_sampler.clone())?
+				.add_sampled_image(depth, self.sampler.clone())?
+				.add_sampled_image(color, self.sampler.clone())?
+				.add_image(ImageView::new(destination)?)?
+				.build()?,
+		);
+		debug_label!(builder, "ClusteredVolumetricPass composite", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[width.div_ceil(8), height.div_ceil(8), 1],
+			self.composite_pipeline.clone(),
+			set,
+			(),
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+}
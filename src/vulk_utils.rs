@@ -1,17 +1,19 @@
 use std::sync::Arc;
-use std::alloc::Layout;
+#[cfg(feature = "validation")]
+use std::ffi::CString;
 
 use log::*;
 
-use winit::window::WindowBuilder;
-use winit::event_loop::EventLoop;
+use winit::window::Window;
 
 use vulkano::instance::{
 	Instance,
+	InstanceExtensions,
 	PhysicalDevice,
 };
 use vulkano::swapchain::{
 	Swapchain,
+	SwapchainCreationError,
 	Surface,
 	ColorSpace,
 	FullscreenExclusive,
@@ -20,32 +22,127 @@ use vulkano::swapchain::{
 };
 use vulkano::device::{
 	Device,
+	DeviceOwned,
 	DeviceExtensions,
 	Queue,
+	RawDeviceExtensions,
 };
+use vulkano::format::Format;
 use vulkano::image::{
 	SwapchainImage,
 	ImageUsage,
 };
-use vulkano::pipeline::GraphicsPipeline;
-use vulkano::framebuffer::Subpass;
-use vulkano::descriptor::PipelineLayoutAbstract;
-use vulkano::pipeline::vertex::SingleBufferDefinition;
 
-use vulkano_win::VkSurfaceBuild;
+use crate::error::VulkanoError;
+use crate::win_utils::WindowSize;
 
-use crate::glsl_shaders::*;
+#[cfg(feature = "validation")]
+const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
 
-pub fn init_vlk(event_loop: &EventLoop<()>) -> (
-	Arc<Surface<winit::window::Window>>,
-	Arc<Swapchain<winit::window::Window>>,
-	Vec<Arc<SwapchainImage<winit::window::Window>>>,
+// Values of the `transfer_function` specialization constant of the fragment
+// shader. Linear colors are written as is, for scRGB and for the formats the
+// hardware encodes to sRGB.
+pub const TRANSFER_LINEAR: u32 = 0;
+pub const TRANSFER_SRGB: u32 = 1;
+pub const TRANSFER_PQ: u32 = 2;
+
+// In order of preference. scRGB comes first since it needs no encoding and
+// keeps the SDR content as is, then HDR10 and the 8 bit formats the blending
+// happens in sRGB with and without the hardware encoding.
+const SURFACE_FORMAT_PRIORITY: [(Format, ColorSpace); 4] = [
+	(Format::R16G16B16A16Sfloat, ColorSpace::ExtendedSrgbLinear),
+	(Format::A2B10G10R10UnormPack32, ColorSpace::Hdr10St2084),
+	(Format::B8G8R8A8Srgb, ColorSpace::SrgbNonLinear),
+	(Format::B8G8R8A8Unorm, ColorSpace::SrgbNonLinear),
+];
+
+// Picks the first pair of the priority list the surface supports
+pub struct SwapchainFormatNegotiator {
+	priority: Vec<(Format, ColorSpace)>,
+}
+
+impl SwapchainFormatNegotiator {
+	// The HDR formats are skipped with `FORCE_SDR=1`
+	pub fn from_env() -> SwapchainFormatNegotiator {
+		let force_sdr = std::env::var("FORCE_SDR").map(|v| v == "1").unwrap_or(false);
+		SwapchainFormatNegotiator::with_priority(&SURFACE_FORMAT_PRIORITY, force_sdr)
+	}
+
+	pub fn with_priority(priority: &[(Format, ColorSpace)], force_sdr: bool) -> SwapchainFormatNegotiator {
+		SwapchainFormatNegotiator {
+			priority: priority
+				.iter()
+				.copied()
+				.filter(|&(_, color_space)| !force_sdr || color_space == ColorSpace::SrgbNonLinear)
+				.collect(),
+		}
+	}
+
+	// Falls back to the first sRGB pair, then to the first format, when the
+	// surface supports none of the list
+	pub fn negotiate(&self, supported: &[(Format, ColorSpace)]) -> (Format, ColorSpace) {
+		let selected = self
+			.priority
+			.iter()
+			.copied()
+			.find(|pair| supported.contains(pair))
+			.or_else(|| {
+				supported
+					.iter()
+					.copied()
+					.find(|&(_, color_space)| color_space == ColorSpace::SrgbNonLinear)
+			})
+			.unwrap_or((supported[0].0, ColorSpace::SrgbNonLinear));
+		info!("Using the surface format {:?} {:?}", selected.0, selected.1);
+		selected
+	}
+}
+
+// How the fragment shader must encode its output for a swapchain of `format`
+// in `color_space`
+pub fn transfer_function(format: Format, color_space: ColorSpace) -> u32 {
+	match color_space {
+		ColorSpace::Hdr10St2084 => TRANSFER_PQ,
+		ColorSpace::ExtendedSrgbLinear => TRANSFER_LINEAR,
+		_ if is_srgb(format) => TRANSFER_LINEAR,
+		_ => TRANSFER_SRGB,
+	}
+}
+
+fn is_srgb(format: Format) -> bool {
+	matches!(
+		format,
+		Format::R8G8B8A8Srgb | Format::B8G8R8A8Srgb | Format::A8B8G8R8SrgbPack32
+	)
+}
+
+// The window is shared so that a new Vulkan context can be created on it after a device loss
+#[allow(clippy::type_complexity)]
+pub fn init_vlk(window: Arc<Window>) -> Result<(
+	Arc<Surface<Arc<Window>>>,
+	Arc<Swapchain<Arc<Window>>>,
+	Vec<Arc<SwapchainImage<Arc<Window>>>>,
 	Arc<Queue>,
-	Arc<Device>
-	) {
+	Arc<Device>,
+	ColorSpace
+	), VulkanoError> {
 	let required_extensions = vulkano_win::required_extensions();
-	let instance = Instance::new(None, &required_extensions, None).unwrap();
-	let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+	let supported_extensions = InstanceExtensions::supported_by_core()?;
+	let extensions = InstanceExtensions {
+		// Used to name objects for graphics debuggers
+		ext_debug_utils: supported_extensions.ext_debug_utils,
+		// Exposes the HDR color spaces of the surface
+		ext_swapchain_colorspace: supported_extensions.ext_swapchain_colorspace,
+		..required_extensions
+	};
+	#[cfg(feature = "validation")]
+	let layers = vec![VALIDATION_LAYER];
+	#[cfg(not(feature = "validation"))]
+	let layers = Vec::<&str>::new();
+	let instance = Instance::new(None, &extensions, layers)?;
+	let physical = PhysicalDevice::enumerate(&instance)
+		.next()
+		.ok_or(VulkanoError::NoPhysicalDevice)?;
 
 	info!(
 		"Using device: {} (type: {:?})",
@@ -53,33 +150,47 @@ pub fn init_vlk(event_loop: &EventLoop<()>) -> (
 		physical.ty()
 	);
 
-	let surface = WindowBuilder::new()
-		.build_vk_surface(event_loop, instance.clone())
-		.unwrap();
+	let surface = vulkano_win::create_vk_surface(window, instance.clone())?;
 
 	let queue_family = physical
 		.queue_families()
 		.find(|&q| q.supports_graphics() && surface.is_supported(q).unwrap_or(false))
-		.unwrap();
+		.ok_or(VulkanoError::NoQueueFamily)?;
 
 	let device_ext = DeviceExtensions {
 		khr_swapchain: true,
 		..DeviceExtensions::none()
 	};
+	#[allow(unused_mut)]
+	let mut raw_device_ext = RawDeviceExtensions::from(&device_ext);
+	// Lets the shaders call debugPrintfEXT, not part of vulkano's DeviceExtensions
+	#[cfg(feature = "validation")]
+	{
+		let non_semantic_info = RawDeviceExtensions::new(vec![CString::new("VK_KHR_shader_non_semantic_info").unwrap()]);
+		raw_device_ext = raw_device_ext.union(&non_semantic_info);
+	}
 	let (device, mut queues) = Device::new(
 		physical,
 		physical.supported_features(),
-		&device_ext,
+		raw_device_ext,
 		[(queue_family, 0.5)].iter().cloned(),
-	)
-	.unwrap();
+	)?;
 
-	let queue = queues.next().unwrap();
+	let queue = queues.next().ok_or(VulkanoError::NoQueue)?;
 
-	let caps = surface.capabilities(physical).unwrap();
-	let alpha = caps.supported_composite_alpha.iter().next().unwrap();
-	let format = caps.supported_formats[0].0;
-	let dimensions: [u32; 2] = surface.window().inner_size().into();
+	let caps = surface.capabilities(physical)?;
+	let alpha = caps
+		.supported_composite_alpha
+		.iter()
+		.next()
+		.ok_or(VulkanoError::NoCompositeAlpha)?;
+	let (format, color_space) = SwapchainFormatNegotiator::from_env().negotiate(&caps.supported_formats);
+	let window_size = WindowSize::of(surface.window());
+	info!(
+		"Window size: {:?} pixels, {:?} points, scale factor {}",
+		window_size.physical_size, window_size.logical_size, window_size.scale_factor
+	);
+	let dimensions = window_size.physical_size;
 
 	let (swapchain, images) = {
 		Swapchain::new(
@@ -96,10 +207,96 @@ pub fn init_vlk(event_loop: &EventLoop<()>) -> (
 			PresentMode::Fifo,
 			FullscreenExclusive::Default,
 			true,
-			ColorSpace::SrgbNonLinear,
-		)
-		.unwrap()
+			color_space,
+		)?
 	};
 
-	return (surface, swapchain, images, queue, device);
+	Ok((surface, swapchain, images, queue, device, color_space))
+}
+
+// Recreates the swapchain with the same parameters but a different present mode,
+// `color_space` is the one the swapchain was created with
+#[allow(clippy::type_complexity)]
+pub fn recreate_with_present_mode(
+	swapchain: &Arc<Swapchain<Arc<Window>>>,
+	queue: &Arc<Queue>,
+	dimensions: [u32; 2],
+	mode: PresentMode,
+	color_space: ColorSpace,
+) -> Result<(
+	Arc<Swapchain<Arc<Window>>>,
+	Vec<Arc<SwapchainImage<Arc<Window>>>>
+	), SwapchainCreationError> {
+	Swapchain::with_old_swapchain(
+		swapchain.device().clone(),
+		swapchain.surface().clone(),
+		swapchain.num_images(),
+		swapchain.format(),
+		dimensions,
+		1,
+		ImageUsage::color_attachment(),
+		queue,
+		SurfaceTransform::Identity,
+		swapchain.composite_alpha(),
+		mode,
+		FullscreenExclusive::Default,
+		true,
+		color_space,
+		swapchain.clone(),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SRGB_UNORM: (Format, ColorSpace) = (Format::B8G8R8A8Unorm, ColorSpace::SrgbNonLinear);
+	const SRGB: (Format, ColorSpace) = (Format::B8G8R8A8Srgb, ColorSpace::SrgbNonLinear);
+	const SCRGB: (Format, ColorSpace) = (Format::R16G16B16A16Sfloat, ColorSpace::ExtendedSrgbLinear);
+	const HDR10: (Format, ColorSpace) = (Format::A2B10G10R10UnormPack32, ColorSpace::Hdr10St2084);
+
+	fn negotiator() -> SwapchainFormatNegotiator {
+		SwapchainFormatNegotiator::with_priority(&SURFACE_FORMAT_PRIORITY, false)
+	}
+
+	#[test]
+	fn prefers_scrgb() {
+		assert_eq!(negotiator().negotiate(&[SRGB_UNORM, SRGB, SCRGB]), SCRGB);
+	}
+
+	#[test]
+	fn prefers_hdr10_over_srgb() {
+		assert_eq!(negotiator().negotiate(&[SRGB, HDR10]), HDR10);
+	}
+
+	#[test]
+	fn prefers_srgb_over_unorm() {
+		assert_eq!(negotiator().negotiate(&[SRGB_UNORM, SRGB]), SRGB);
+	}
+
+	#[test]
+	fn falls_back_to_unorm() {
+		let supported = [(Format::R16G16B16A16Sfloat, ColorSpace::Hdr10St2084), SRGB_UNORM];
+		assert_eq!(negotiator().negotiate(&supported), SRGB_UNORM);
+	}
+
+	#[test]
+	fn force_sdr_skips_scrgb() {
+		let negotiator = SwapchainFormatNegotiator::with_priority(&SURFACE_FORMAT_PRIORITY, true);
+		assert_eq!(negotiator.negotiate(&[SCRGB, HDR10, SRGB_UNORM]), SRGB_UNORM);
+	}
+
+	#[test]
+	fn falls_back_outside_the_list() {
+		let supported = [(Format::R8G8B8A8Unorm, ColorSpace::SrgbNonLinear)];
+		assert_eq!(negotiator().negotiate(&supported), supported[0]);
+	}
+
+	#[test]
+	fn transfer_matches_the_format() {
+		assert_eq!(transfer_function(SRGB.0, SRGB.1), TRANSFER_LINEAR);
+		assert_eq!(transfer_function(SRGB_UNORM.0, SRGB_UNORM.1), TRANSFER_SRGB);
+		assert_eq!(transfer_function(SCRGB.0, SCRGB.1), TRANSFER_LINEAR);
+		assert_eq!(transfer_function(HDR10.0, HDR10.1), TRANSFER_PQ);
+	}
 }
@@ -0,0 +1,1033 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::{DescriptorSet, PipelineLayoutAbstract};
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::pipeline::blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+use crate::debug_utils::{LABEL_COLOR_COMPUTE, LABEL_COLOR_RENDER_PASS};
+use crate::error::VulkanoError;
+use crate::pipeline::{SharedComputePipeline, SharedPipeline};
+
+// Size of the simulation grid, must match N in the shaders
+pub const OCEAN_RESOLUTION: u32 = 256;
+// log2(OCEAN_RESOLUTION) butterfly passes per axis
+const FFT_PASSES: u32 = 8;
+
+const FIELD_FORMAT: Format = Format::R32G32B32A32Sfloat;
+// Sampled with linear filtering by the mesh, which 32 bit floats don't guarantee
+pub const DISPLACEMENT_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+mod spectrum_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			// h0(k) in RG, conj(h0(-k)) in BA
+			layout(set = 0, binding = 0, rgba32f) uniform writeonly image2D spectrum;
+
+			layout(push_constant) uniform PushConstants {
+				vec2 wind_direction;
+				// Meters per second
+				float wind_speed;
+				// Distance over which the wind blew, in meters
+				float fetch;
+				// Side of the simulated square, in meters
+				float patch_size;
+				float amplitude;
+			} pc;
+
+			const int N = 256;
+			const float PI = 3.14159265359;
+			const float G = 9.81;
+			// JONSWAP peak enhancement factor
+			const float GAMMA = 3.3;
+
+			float hash(uvec2 p) {
+				uint h = p.x * 1664525u + p.y * 1013904223u;
+				h ^= h >> 16;
+				h *= 0x7feb352du;
+				h ^= h >> 15;
+				h *= 0x846ca68bu;
+				h ^= h >> 16;
+				return float(h) / 4294967296.0;
+			}
+
+			// Two independent standard normal values, with the Box-Muller transform
+			vec2 gaussian(uvec2 p) {
+				float u1 = max(hash(p), 1e-6);
+				float u2 = hash(p + uvec2(7919u, 104729u));
+				return sqrt(-2.0 * log(u1)) * vec2(cos(2.0 * PI * u2), sin(2.0 * PI * u2));
+			}
+
+			float jonswap(float omega) {
+				float alpha = 0.076 * pow(pc.wind_speed * pc.wind_speed / (pc.fetch * G), 0.22);
+				float omega_peak = 22.0 * pow(G * G / (pc.wind_speed * pc.fetch), 1.0 / 3.0);
+				float sigma = omega <= omega_peak ? 0.07 : 0.09;
+				float peak = exp(-pow(omega - omega_peak, 2.0) / (2.0 * sigma * sigma * omega_peak * omega_peak));
+				return alpha * G * G / pow(omega, 5.0) * exp(-1.25 * pow(omega_peak / omega, 4.0)) * pow(GAMMA, peak);
+			}
+
+			float spectrum_amplitude(vec2 k) {
+				float k_length = length(k);
+				if (k_length < 1e-4) {
+					return 0.0;
+				}
+				float omega = sqrt(G * k_length);
+
+				// Waves travel along the wind, cos^2 spreading normalized over the half circle
+				float cos_theta = dot(k / k_length, normalize(pc.wind_direction));
+				float spreading = cos_theta > 0.0 ? 2.0 / PI * cos_theta * cos_theta : 0.0;
+
+				// From S(omega) to S(k): d(omega)/dk = G / (2 omega), over the polar area element k
+				float dk = 2.0 * PI / pc.patch_size;
+				float density = jonswap(omega) * spreading * G / (2.0 * omega) / k_length;
+				return pc.amplitude * sqrt(2.0 * density * dk * dk);
+			}
+
+			vec2 h0(ivec2 index) {
+				vec2 k = 2.0 * PI * vec2(index - N / 2) / pc.patch_size;
+				return gaussian(uvec2(index)) * spectrum_amplitude(k) / sqrt(2.0);
+			}
+
+			void main() {
+				ivec2 index = ivec2(gl_GlobalInvocationID.xy);
+				if (index.x >= N || index.y >= N) {
+					return;
+				}
+				vec2 minus = h0((N - index) % N);
+				imageStore(spectrum, index, vec4(h0(index), minus.x, -minus.y));
+			}
+		"
+	}
+}
+
+mod dispersion_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0, rgba32f) uniform readonly image2D spectrum;
+			// Five real fields packed two by two in complex numbers, which is
+			// possible because each of them transforms to a real function:
+			// (height + i dx, dz + i slope_x) and (slope_z, unused)
+			layout(set = 0, binding = 1, rgba32f) uniform writeonly image2D fields_a;
+			layout(set = 0, binding = 2, rgba32f) uniform writeonly image2D fields_b;
+
+			layout(push_constant) uniform PushConstants {
+				float time;
+				float patch_size;
+			} pc;
+
+			const int N = 256;
+			const float PI = 3.14159265359;
+			const float G = 9.81;
+
+			vec2 complex_mul(vec2 a, vec2 b) {
+				return vec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+			}
+
+			vec2 times_i(vec2 a) {
+				return vec2(-a.y, a.x);
+			}
+
+			void main() {
+				ivec2 index = ivec2(gl_GlobalInvocationID.xy);
+				if (index.x >= N || index.y >= N) {
+					return;
+				}
+				vec2 k = 2.0 * PI * vec2(index - N / 2) / pc.patch_size;
+				float k_length = length(k);
+				vec2 direction = k_length > 1e-4 ? k / k_length : vec2(0.0);
+
+				// Deep water dispersion relation
+				float omega = sqrt(G * k_length);
+				vec2 rotation = vec2(cos(omega * pc.time), sin(omega * pc.time));
+
+				vec4 h0 = imageLoad(spectrum, index);
+				vec2 h = complex_mul(h0.xy, rotation) + complex_mul(h0.zw, vec2(rotation.x, -rotation.y));
+
+				vec2 dx = -direction.x * times_i(h);
+				vec2 dz = -direction.y * times_i(h);
+				vec2 slope_x = k.x * times_i(h);
+				vec2 slope_z = k.y * times_i(h);
+
+				imageStore(fields_a, index, vec4(h + times_i(dx), dz + times_i(slope_x)));
+				imageStore(fields_b, index, vec4(slope_z, 0.0, 0.0));
+			}
+		"
+	}
+}
+
+mod fft_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			// One butterfly per invocation, N / 2 per line
+			layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0, rgba32f) uniform readonly image2D input_a;
+			layout(set = 0, binding = 1, rgba32f) uniform readonly image2D input_b;
+			layout(set = 0, binding = 2, rgba32f) uniform writeonly image2D output_a;
+			layout(set = 0, binding = 3, rgba32f) uniform writeonly image2D output_b;
+
+			layout(push_constant) uniform PushConstants {
+				// Length of the sub-transforms merged by this pass, 1 to N / 2
+				int pass_size;
+				// 1 along the rows, 0 along the columns
+				int horizontal;
+			} pc;
+
+			const int N = 256;
+			const float PI = 3.14159265359;
+
+			vec2 complex_mul(vec2 a, vec2 b) {
+				return vec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+			}
+
+			// Both complex numbers of a texel
+			vec4 twiddle(vec4 value, vec2 w) {
+				return vec4(complex_mul(value.xy, w), complex_mul(value.zw, w));
+			}
+
+			ivec2 texel(int i, int line) {
+				return pc.horizontal == 1 ? ivec2(i, line) : ivec2(line, i);
+			}
+
+			void main() {
+				int j = int(gl_GlobalInvocationID.x);
+				int line = int(gl_GlobalInvocationID.y);
+				if (j >= N / 2 || line >= N) {
+					return;
+				}
+
+				// Stockham formulation of the radix-2 Cooley-Tukey FFT: reading the
+				// inputs N / 2 apart and writing the outputs in place of the merged
+				// sub-transforms avoids the bit reversal permutation
+				int k = j & (pc.pass_size - 1);
+				float angle = PI * float(k) / float(pc.pass_size);
+				// Positive exponent, this is the inverse transform
+				vec2 w = vec2(cos(angle), sin(angle));
+
+				vec4 a0 = imageLoad(input_a, texel(j, line));
+				vec4 a1 = twiddle(imageLoad(input_a, texel(j + N / 2, line)), w);
+				vec4 b0 = imageLoad(input_b, texel(j, line));
+				vec4 b1 = twiddle(imageLoad(input_b, texel(j + N / 2, line)), w);
+
+				int destination = (j - k) * 2 + k;
+				imageStore(output_a, texel(destination, line), a0 + a1);
+				imageStore(output_a, texel(destination + pc.pass_size, line), a0 - a1);
+				imageStore(output_b, texel(destination, line), b0 + b1);
+				imageStore(output_b, texel(destination + pc.pass_size, line), b0 - b1);
+			}
+		"
+	}
+}
+
+mod resolve_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0, rgba32f) uniform readonly image2D fields_a;
+			layout(set = 0, binding = 1, rgba32f) uniform readonly image2D fields_b;
+			// Horizontal displacements in XZ, height in Y
+			layout(set = 0, binding = 2, rgba16f) uniform writeonly image2D displacement;
+			layout(set = 0, binding = 3, rgba16f) uniform writeonly image2D normals;
+
+			layout(push_constant) uniform PushConstants {
+				float choppiness;
+			} pc;
+
+			void main() {
+				ivec2 index = ivec2(gl_GlobalInvocationID.xy);
+				if (any(greaterThanEqual(index, imageSize(displacement)))) {
+					return;
+				}
+
+				// The spectrum is stored with k = 0 in the middle, which shifts the
+				// phase of every other sample by PI
+				float sign = ((index.x + index.y) & 1) == 1 ? -1.0 : 1.0;
+				vec4 a = imageLoad(fields_a, index) * sign;
+				vec4 b = imageLoad(fields_b, index) * sign;
+
+				float height = a.x;
+				vec2 horizontal = vec2(a.y, a.z) * pc.choppiness;
+				vec2 slope = vec2(a.w, b.x);
+
+				imageStore(displacement, index, vec4(horizontal.x, height, horizontal.y, 0.0));
+				imageStore(normals, index, vec4(normalize(vec3(-slope.x, 1.0, -slope.y)), 0.0));
+			}
+		"
+	}
+}
+
+mod water_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			// World XZ position of the undisplaced grid
+			layout(location = 0) in vec2 position;
+
+			layout(location = 0) out vec2 v_uv;
+			layout(location = 1) out vec3 v_to_camera;
+			layout(location = 2) flat out vec3 v_light_direction;
+
+			layout(set = 0, binding = 0) uniform sampler2D displacement;
+
+			layout(push_constant) uniform PushConstants {
+				mat4 view_projection;
+				vec4 camera_position;
+				// Towards the light
+				vec4 light_direction;
+				float patch_size;
+			} pc;
+
+			void main() {
+				v_uv = position / pc.patch_size;
+				vec3 world = vec3(position.x, 0.0, position.y) + textureLod(displacement, v_uv, 0.0).xyz;
+				v_to_camera = pc.camera_position.xyz - world;
+				v_light_direction = pc.light_direction.xyz;
+				gl_Position = pc.view_projection * vec4(world, 1.0);
+			}
+		"
+	}
+}
+
+mod water_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 v_uv;
+			layout(location = 1) in vec3 v_to_camera;
+			layout(location = 2) flat in vec3 v_light_direction;
+
+			layout(location = 0) out vec4 f_color;
+
+			layout(set = 0, binding = 1) uniform sampler2D normals;
+
+			const vec3 DEEP_COLOR = vec3(0.0, 0.05, 0.12);
+			const vec3 SKY_COLOR = vec3(0.5, 0.7, 0.9);
+			// Reflectance of water at normal incidence
+			const float F0 = 0.02;
+
+			void main() {
+				vec3 normal = normalize(texture(normals, v_uv).xyz);
+				vec3 view = normalize(v_to_camera);
+				vec3 halfway = normalize(view + v_light_direction);
+
+				float fresnel = F0 + (1.0 - F0) * pow(1.0 - max(dot(normal, view), 0.0), 5.0);
+				float specular = pow(max(dot(normal, halfway), 0.0), 256.0);
+				float diffuse = max(dot(normal, v_light_direction), 0.0) * 0.2;
+
+				vec3 color = mix(DEEP_COLOR * (0.8 + diffuse), SKY_COLOR, fresnel) + vec3(specular);
+				f_color = vec4(color, 1.0);
+			}
+		"
+	}
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct WaterVertex {
+	position: [f32; 2],
+}
+vulkano::impl_vertex!(WaterVertex, position);
+
+#[derive(Debug, Clone, Copy)]
+pub struct OceanParameters {
+	pub wind_speed: f32,
+	pub wind_direction: [f32; 2],
+	pub fetch: f32,
+	pub patch_size: f32,
+	pub amplitude: f32,
+	// Scale of the horizontal displacement, which sharpens the crests
+	pub choppiness: f32,
+}
+
+impl Default for OceanParameters {
+	fn default() -> OceanParameters {
+		OceanParameters {
+			wind_speed: 10.0,
+			wind_direction: [1.0, 0.3],
+			fetch: 100_000.0,
+			patch_size: 250.0,
+			amplitude: 1.0,
+			choppiness: 1.2,
+		}
+	}
+}
+
+fn ocean_image(
+	device: &Arc<Device>,
+	format: Format,
+	sampled: bool,
+) -> Result<Arc<StorageImage<Format>>, VulkanoError> {
+	let image = StorageImage::with_usage(
+		device.clone(),
+		ImageDimensions::Dim2d {
+			width: OCEAN_RESOLUTION,
+			height: OCEAN_RESOLUTION,
+			array_layers: 1,
+		},
+		format,
+		ImageUsage {
+			storage: true,
+			sampled,
+			..ImageUsage::none()
+		},
+		ImageCreateFlags::none(),
+		device.active_queue_families(),
+	)?;
+	Ok(image)
+}
+
+// Tessendorf's FFT ocean on a 256x256 grid. The initial spectrum is drawn
+// once from the JONSWAP model, then every frame it is advanced in time with the
+// dispersion relation and brought back to the spatial domain with 2 * 8
+// butterfly passes, giving tiling height, horizontal displacement and normal
+// maps. The water mesh is a flat grid displaced by these maps in the vertex
+// shader, covering `tiles` x `tiles` repetitions of the simulated patch.
+pub struct WaterRenderer {
+	dispersion_pipeline: SharedComputePipeline,
+	fft_pipeline: SharedComputePipeline,
+	resolve_pipeline: SharedComputePipeline,
+	draw_pipeline: SharedPipeline,
+	dispersion_set: Arc<dyn DescriptorSet + Send + Sync>,
+	// Reading the first pair of field images and writing the second, and the opposite
+	fft_sets: [Arc<dyn DescriptorSet + Send + Sync>; 2],
+	resolve_set: Arc<dyn DescriptorSet + Send + Sync>,
+	draw_set: Arc<dyn DescriptorSet + Send + Sync>,
+	vertex_buffer: Arc<CpuAccessibleBuffer<[WaterVertex]>>,
+	index_buffer: Arc<CpuAccessibleBuffer<[u32]>>,
+	displacement: Arc<StorageImage<Format>>,
+	normals: Arc<StorageImage<Format>>,
+	sampler: Arc<Sampler>,
+	parameters: OceanParameters,
+}
+
+impl WaterRenderer {
+	// The returned future computes the initial spectrum, it must be waited on
+	// before the first `update`
+	pub fn new(
+		device: Arc<Device>,
+		queue: Arc<Queue>,
+		subpass: Subpass<Arc<dyn RenderPassAbstract + Send + Sync>>,
+		parameters: OceanParameters,
+		tiles: u32,
+		grid_quads: u32,
+	) -> Result<(WaterRenderer, Box<dyn GpuFuture>), VulkanoError> {
+		let spectrum_shader = spectrum_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let dispersion_shader = dispersion_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fft_shader = fft_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let resolve_shader = resolve_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let spectrum_pipeline = Arc::new(ComputePipeline::new(
+			device.clone(),
+			&spectrum_shader.main_entry_point(),
+			&(),
+			None,
+		)?);
+		let dispersion_pipeline: SharedComputePipeline = Arc::new(ComputePipeline::new(
+			device.clone(),
+			&dispersion_shader.main_entry_point(),
+			&(),
+			None,
+		)?);
+		let fft_pipeline: SharedComputePipeline =
+			Arc::new(ComputePipeline::new(device.clone(), &fft_shader.main_entry_point(), &(), None)?);
+		let resolve_pipeline: SharedComputePipeline = Arc::new(ComputePipeline::new(
+			device.clone(),
+			&resolve_shader.main_entry_point(),
+			&(),
+			None,
+		)?);
+
+		let spectrum = ocean_image(&device, FIELD_FORMAT, false)?;
+		let fields = [
+			[ocean_image(&device, FIELD_FORMAT, false)?, ocean_image(&device, FIELD_FORMAT, false)?],
+			[ocean_image(&device, FIELD_FORMAT, false)?, ocean_image(&device, FIELD_FORMAT, false)?],
+		];
+		let displacement = ocean_image(&device, DISPLACEMENT_FORMAT, true)?;
+		let normals = ocean_image(&device, DISPLACEMENT_FORMAT, true)?;
+
+		let layout = spectrum_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let spectrum_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(spectrum.clone())?)?
+				.build()?,
+		);
+		let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family())?;
+		let groups = OCEAN_RESOLUTION / 8;
+		debug_label!(builder, "WaterRenderer spectrum", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[groups, groups, 1],
+			spectrum_pipeline,
+			spectrum_set,
+			spectrum_cs::ty::PushConstants {
+				wind_direction: parameters.wind_direction,
+				wind_speed: parameters.wind_speed,
+				fetch: parameters.fetch,
+				patch_size: parameters.patch_size,
+				amplitude: parameters.amplitude,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+		let spectrum_future = vulkano::sync::now(device.clone()).then_execute(queue, builder.build()?)?;
+
+		let layout = dispersion_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let dispersion_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(spectrum)?)?
+				.add_image(ImageView::new(fields[0][0].clone())?)?
+				.add_image(ImageView::new(fields[0][1].clone())?)?
+				.build()?,
+		);
+
+		let layout = fft_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let fft_set = |from: usize, to: usize| -> Result<Arc<dyn DescriptorSet + Send + Sync>, VulkanoError> {
+			Ok(Arc::new(
+				PersistentDescriptorSet::start(layout.clone())
+					.add_image(ImageView::new(fields[from][0].clone())?)?
+					.add_image(ImageView::new(fields[from][1].clone())?)?
+					.add_image(ImageView::new(fields[to][0].clone())?)?
+					.add_image(ImageView::new(fields[to][1].clone())?)?
+					.build()?,
+			))
+		};
+		let fft_sets = [fft_set(0, 1)?, fft_set(1, 0)?];
+
+		// An even number of passes leaves the result in the first pair
+		let layout = resolve_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let resolve_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(fields[0][0].clone())?)?
+				.add_image(ImageView::new(fields[0][1].clone())?)?
+				.add_image(ImageView::new(displacement.clone())?)?
+				.add_image(ImageView::new(normals.clone())?)?
+				.build()?,
+		);
+
+		let vs = water_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = water_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let has_depth = subpass.has_depth();
+		let builder = GraphicsPipeline::start()
+			.vertex_input_single_buffer::<WaterVertex>()
+			.vertex_shader(vs.main_entry_point(), ())
+			.triangle_list()
+			.viewports_dynamic_scissors_irrelevant(1)
+			.fragment_shader(fs.main_entry_point(), ());
+		let builder = if has_depth {
+			builder.depth_stencil_simple_depth()
+		} else {
+			builder
+		};
+		let draw_pipeline: SharedPipeline = Arc::new(builder.render_pass(subpass).build(device.clone())?);
+
+		// The maps tile, the mesh samples them past the first patch
+		let sampler = Sampler::new(
+			device.clone(),
+			Filter::Linear,
+			Filter::Linear,
+			MipmapMode::Nearest,
+			SamplerAddressMode::Repeat,
+			SamplerAddressMode::Repeat,
+			SamplerAddressMode::Repeat,
+			0.0,
+			1.0,
+			0.0,
+			0.0,
+		)?;
+		let layout = draw_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let draw_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(ImageView::new(displacement.clone())?, sampler.clone())?
+				.add_sampled_image(ImageView::new(normals.clone())?, sampler.clone())?
+				.build()?,
+		);
+
+		let (vertices, indices) = water_grid(parameters.patch_size * tiles as f32, grid_quads.max(1));
+		let vertex_buffer = CpuAccessibleBuffer::from_iter(
+			device.clone(),
+			BufferUsage::vertex_buffer(),
+			false,
+			vertices.into_iter(),
+		)?;
+		let index_buffer =
+			CpuAccessibleBuffer::from_iter(device, BufferUsage::index_buffer(), false, indices.into_iter())?;
+
+		let renderer = WaterRenderer {
+			dispersion_pipeline,
+			fft_pipeline,
+			resolve_pipeline,
+			draw_pipeline,
+			dispersion_set,
+			fft_sets,
+			resolve_set,
+			draw_set,
+			vertex_buffer,
+			index_buffer,
+			displacement,
+			normals,
+			sampler,
+			parameters,
+		};
+		Ok((renderer, spectrum_future.boxed()))
+	}
+
+	// Records the simulation of the ocean at `time` seconds, outside of any render pass
+	pub fn update(&self, builder: &mut AutoCommandBufferBuilder, time: f32) -> Result<(), VulkanoError> {
+		let groups = OCEAN_RESOLUTION / 8;
+		debug_label!(builder, "WaterRenderer dispersion", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[groups, groups, 1],
+			self.dispersion_pipeline.clone(),
+			self.dispersion_set.clone(),
+			dispersion_cs::ty::PushConstants {
+				time,
+				patch_size: self.parameters.patch_size,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+
+		// The rows then the columns, each pass merging transforms twice as long
+		let mut pass = 0;
+		for horizontal in [1, 0].iter() {
+			for stage in 0..FFT_PASSES {
+				debug_label!(builder, "WaterRenderer fft", LABEL_COLOR_COMPUTE);
+				builder.dispatch(
+					[OCEAN_RESOLUTION / 2 / 64, OCEAN_RESOLUTION, 1],
+					self.fft_pipeline.clone(),
+					self.fft_sets[pass % 2].clone(),
+					fft_cs::ty::PushConstants {
+						pass_size: 1 << stage,
+						horizontal: *horizontal,
+					},
+					vec![],
+				)?;
+				debug_label!(builder);
+				pass += 1;
+			}
+		}
+
+		debug_label!(builder, "WaterRenderer resolve", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[groups, groups, 1],
+			self.resolve_pipeline.clone(),
+			self.resolve_set.clone(),
+			resolve_cs::ty::PushConstants {
+				choppiness: self.parameters.choppiness,
+			},
+			vec![],
+		)?;
+		debug_label!(builder);
+		Ok(())
+	}
+
+	// Records the water mesh inside the current render pass, `light_direction`
+	// pointing towards the light
+	pub fn draw(
+		&self,
+		builder: &mut AutoCommandBufferBuilder,
+		dynamic_state: &DynamicState,
+		view_projection: [[f32; 4]; 4],
+		camera_position: [f32; 3],
+		light_direction: [f32; 3],
+	) -> Result<(), VulkanoError> {
+		let [x, y, z] = camera_position;
+		let [lx, ly, lz] = light_direction;
+		builder.draw_indexed(
+			self.draw_pipeline.clone(),
+			dynamic_state,
+			vec![self.vertex_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>],
+			self.index_buffer.clone(),
+			self.draw_set.clone(),
+			water_vs::ty::PushConstants {
+				view_projection,
+				camera_position: [x, y, z, 1.0],
+				light_direction: [lx, ly, lz, 0.0],
+				patch_size: self.parameters.patch_size,
+			},
+			vec![],
+		)?;
+		Ok(())
+	}
+}
+
+// Must match the caustics shaders
+pub const CAUSTICS_RESOLUTION: u32 = 512;
+pub const CAUSTICS_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+mod caustics_vs {
+	vulkano_shaders::shader! {
+		ty: "vertex",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec2 position;
+
+			// Where the refracted ray lands from the flat surface, and from the waves
+			layout(location = 0) out vec3 v_flat;
+			layout(location = 1) out vec3 v_refracted;
+			layout(location = 2) flat out vec3 v_light_color;
+
+			layout(set = 0, binding = 0) uniform sampler2D displacement;
+			layout(set = 0, binding = 1) uniform sampler2D normals;
+
+			layout(push_constant) uniform PushConstants {
+				// Towards the light
+				vec4 light_direction;
+				// Color times intensity in rgb
+				vec4 light_color;
+				// World XZ of the corner of the map, its size and the height of the floor
+				vec4 floor;
+				float patch_size;
+			} pc;
+
+			// Refractive index of the air over the one of water
+			const float ETA = 1.0 / 1.33;
+
+			vec3 land(vec3 origin, vec3 direction) {
+				return origin + direction * (pc.floor.w - origin.y) / min(direction.y, -1e-4);
+			}
+
+			void main() {
+				vec2 uv = position / pc.patch_size;
+				vec3 grid = vec3(position.x, 0.0, position.y);
+				vec3 surface = grid + textureLod(displacement, uv, 0.0).xyz;
+				vec3 normal = normalize(textureLod(normals, uv, 0.0).xyz);
+				vec3 incident = -normalize(pc.light_direction.xyz);
+
+				v_flat = land(grid, refract(incident, vec3(0.0, 1.0, 0.0), ETA));
+				v_refracted = land(surface, refract(incident, normal, ETA));
+				v_light_color = pc.light_color.rgb;
+
+				// The floor's UV space is the render target
+				vec2 floor_uv = (v_refracted.xz - pc.floor.xy) / pc.floor.z;
+				gl_Position = vec4(floor_uv * 2.0 - 1.0, 0.0, 1.0);
+			}
+		"
+	}
+}
+
+mod caustics_fs {
+	vulkano_shaders::shader! {
+		ty: "fragment",
+		src: "
+			#version 450
+
+			layout(location = 0) in vec3 v_flat;
+			layout(location = 1) in vec3 v_refracted;
+			layout(location = 2) flat in vec3 v_light_color;
+
+			layout(location = 0) out vec4 f_energy;
+
+			void main() {
+				// The light through a triangle lands on the floor spread over the
+				// refracted triangle instead of the flat one, the ratio of their areas
+				// is how much it is focused
+				float flat_area = length(dFdx(v_flat)) * length(dFdy(v_flat));
+				float refracted_area = length(dFdx(v_refracted)) * length(dFdy(v_refracted));
+				float focus = flat_area / max(refracted_area, 1e-8);
+				f_energy = vec4(v_light_color * focus, 1.0);
+			}
+		"
+	}
+}
+
+mod caustics_blend_cs {
+	vulkano_shaders::shader! {
+		ty: "compute",
+		src: "
+			#version 450
+
+			layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+			layout(set = 0, binding = 0, rgba16f) uniform readonly image2D current;
+			layout(set = 0, binding = 1, rgba16f) uniform image2D history;
+
+			layout(push_constant) uniform PushConstants {
+				// Weight of the current frame
+				float blend;
+			} pc;
+
+			void main() {
+				ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+				vec4 blended = mix(imageLoad(history, pixel), imageLoad(current, pixel), pc.blend);
+				imageStore(history, pixel, blended);
+			}
+		"
+	}
+}
+
+// Caustics of a `WaterRenderer` on a flat floor under it. The water mesh is
+// rasterized into a CAUSTICS_RESOLUTION light map laid over the floor: the
+// vertex shader refracts the light at each displaced vertex with the normal
+// map and moves it where the ray hits the floor, and the fragment shader adds
+// the light focused by the triangle, from its area on the floor against the
+// one under flat water. The maps are blended over the last frames, which hides
+// the flicker of the sub-texel triangles. The lighting pass adds the result
+// with caustics.glsl.
+pub struct CausticsPass {
+	pipeline: SharedPipeline,
+	blend_pipeline: SharedComputePipeline,
+	framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+	draw_set: Arc<dyn DescriptorSet + Send + Sync>,
+	blend_set: Arc<dyn DescriptorSet + Send + Sync>,
+	caustics: Arc<StorageImage<Format>>,
+	dynamic_state: DynamicState,
+	history_valid: bool,
+	// World XZ of the corner of the floor covered by the map, and its size
+	pub floor_origin: [f32; 2],
+	pub floor_size: f32,
+	pub floor_height: f32,
+	// Color times intensity of the directional light
+	pub light_color: [f32; 3],
+	// Weight of the new frame in the blended map
+	pub temporal_blend: f32,
+}
+
+impl CausticsPass {
+	pub fn new(device: Arc<Device>, water: &WaterRenderer) -> Result<CausticsPass, VulkanoError> {
+		let render_pass: Arc<dyn RenderPassAbstract + Send + Sync> = Arc::new(
+			vulkano::single_pass_renderpass!(
+				device.clone(),
+				attachments: {
+					energy: {
+						load: Clear,
+						store: Store,
+						format: CAUSTICS_FORMAT,
+						samples: 1,
+					}
+				},
+				pass: {
+					color: [energy],
+					depth_stencil: {}
+				}
+			)?,
+		);
+
+		let dimensions = [CAUSTICS_RESOLUTION, CAUSTICS_RESOLUTION];
+		let energy = AttachmentImage::with_usage(
+			device.clone(),
+			dimensions,
+			CAUSTICS_FORMAT,
+			ImageUsage {
+				color_attachment: true,
+				storage: true,
+				..ImageUsage::none()
+			},
+		)?;
+		let framebuffer = Arc::new(
+			Framebuffer::start(render_pass.clone())
+				.add(ImageView::new(energy.clone())?)?
+				.build()?,
+		);
+		let caustics = StorageImage::with_usage(
+			device.clone(),
+			ImageDimensions::Dim2d {
+				width: CAUSTICS_RESOLUTION,
+				height: CAUSTICS_RESOLUTION,
+				array_layers: 1,
+			},
+			CAUSTICS_FORMAT,
+			ImageUsage {
+				storage: true,
+				sampled: true,
+				..ImageUsage::none()
+			},
+			ImageCreateFlags::none(),
+			device.active_queue_families(),
+		)?;
+
+		let vs = caustics_vs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let fs = caustics_fs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		// Overlapping triangles focus more light on the same texels
+		let additive = AttachmentBlend {
+			enabled: true,
+			color_op: BlendOp::Add,
+			color_source: BlendFactor::One,
+			color_destination: BlendFactor::One,
+			alpha_op: BlendOp::Add,
+			alpha_source: BlendFactor::One,
+			alpha_destination: BlendFactor::One,
+			mask_red: true,
+			mask_green: true,
+			mask_blue: true,
+			mask_alpha: true,
+		};
+		let pipeline: SharedPipeline = Arc::new(
+			GraphicsPipeline::start()
+				.vertex_input_single_buffer::<WaterVertex>()
+				.vertex_shader(vs.main_entry_point(), ())
+				.triangle_list()
+				.viewports_dynamic_scissors_irrelevant(1)
+				.fragment_shader(fs.main_entry_point(), ())
+				.blend_collective(additive)
+				.render_pass(Subpass::from(render_pass, 0).ok_or(VulkanoError::NoSubpass)?)
+				.build(device.clone())?,
+		);
+
+		let blend_shader = caustics_blend_cs::Shader::load(device.clone()).map_err(VulkanoError::ShaderLoad)?;
+		let blend_pipeline: SharedComputePipeline =
+			Arc::new(ComputePipeline::new(device.clone(), &blend_shader.main_entry_point(), &(), None)?);
+
+		let layout = pipeline.descriptor_set_layout(0).ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let draw_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_sampled_image(ImageView::new(water.displacement.clone())?, water.sampler.clone())?
+				.add_sampled_image(ImageView::new(water.normals.clone())?, water.sampler.clone())?
+				.build()?,
+		);
+		let layout = blend_pipeline
+			.descriptor_set_layout(0)
+			.ok_or(VulkanoError::NoDescriptorSetLayout)?;
+		let blend_set = Arc::new(
+			PersistentDescriptorSet::start(layout.clone())
+				.add_image(ImageView::new(energy)?)?
+				.add_image(ImageView::new(caustics.clone())?)?
+				.build()?,
+		);
+
+		let dynamic_state = DynamicState {
+			viewports: Some(vec![Viewport {
+				origin: [0.0, 0.0],
+				dimensions: [CAUSTICS_RESOLUTION as f32, CAUSTICS_RESOLUTION as f32],
+				depth_range: 0.0..1.0,
+			}]),
+			..DynamicState::none()
+		};
+
+		Ok(CausticsPass {
+			pipeline,
+			blend_pipeline,
+			framebuffer,
+			draw_set,
+			blend_set,
+			caustics,
+			dynamic_state,
+			history_valid: false,
+			floor_origin: [0.0, 0.0],
+			floor_size: water.parameters.patch_size,
+			floor_height: -5.0,
+			light_color: [1.0, 1.0, 1.0],
+			temporal_blend: 0.3,
+		})
+	}
+
+	// The blended light map, to sample with caustics.glsl
+	pub fn caustics(&self) -> Arc<StorageImage<Format>> {
+		self.caustics.clone()
+	}
+
+	// The floor and the light packed for the uniform block of caustics.glsl
+	pub fn uniform(&self) -> [[f32; 4]; 2] {
+		let [x, z] = self.floor_origin;
+		let [r, g, b] = self.light_color;
+		[[x, z, self.floor_size, self.floor_height], [r, g, b, 0.0]]
+	}
+
+	// Records the caustics of the water once it is updated for the frame,
+	// outside of any render pass. `light_direction` points towards the light.
+	pub fn record(
+		&mut self,
+		builder: &mut AutoCommandBufferBuilder,
+		water: &WaterRenderer,
+		light_direction: [f32; 3],
+	) -> Result<(), VulkanoError> {
+		let [lx, ly, lz] = light_direction;
+		let [r, g, b] = self.light_color;
+		let [x, z] = self.floor_origin;
+		debug_label!(builder, "CausticsPass", LABEL_COLOR_RENDER_PASS);
+		builder.begin_render_pass(self.framebuffer.clone(), SubpassContents::Inline, vec![[0.0; 4].into()])?;
+		builder.draw_indexed(
+			self.pipeline.clone(),
+			&self.dynamic_state,
+			vec![water.vertex_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>],
+			water.index_buffer.clone(),
+			self.draw_set.clone(),
+			caustics_vs::ty::PushConstants {
+				light_direction: [lx, ly, lz, 0.0],
+				light_color: [r, g, b, 0.0],
+				floor: [x, z, self.floor_size, self.floor_height],
+				patch_size: water.parameters.patch_size,
+			},
+			vec![],
+		)?;
+		builder.end_render_pass()?;
+		debug_label!(builder);
+
+		// Nothing to blend with on the first frame
+		let blend = if self.history_valid { self.temporal_blend } else { 1.0 };
+		let groups = CAUSTICS_RESOLUTION / 8;
+		debug_label!(builder, "CausticsPass blend", LABEL_COLOR_COMPUTE);
+		builder.dispatch(
+			[groups, groups, 1],
+			self.blend_pipeline.clone(),
+			self.blend_set.clone(),
+			caustics_blend_cs::ty::PushConstants { blend },
+			vec![],
+		)?;
+		debug_label!(builder);
+		self.history_valid = true;
+		Ok(())
+	}
+}
+
+// Square grid with a corner at the origin, `quads` quads per side
+fn water_grid(size: f32, quads: u32) -> (Vec<WaterVertex>, Vec<u32>) {
+	let step = size / quads as f32;
+	let vertices = (0..=quads)
+		.flat_map(|z| {
+			(0..=quads).map(move |x| WaterVertex {
+				position: [x as f32 * step, z as f32 * step],
+			})
+		})
+		.collect();
+
+	let row = quads + 1;
+	let indices = (0..quads)
+		.flat_map(|z| {
+			(0..quads).flat_map(move |x| {
+				let corner = z * row + x;
+				vec![corner, corner + row, corner + 1, corner + 1, corner + row, corner + row + 1]
+			})
+		})
+		.collect();
+	(vertices, indices)
+}
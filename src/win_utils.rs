@@ -2,37 +2,148 @@ use vulkano::pipeline::viewport::Viewport;
 use vulkano::command_buffer::DynamicState;
 use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract};
 use vulkano::image::view::ImageView;
-use vulkano::image::SwapchainImage;
+use vulkano::image::{ImageAccess, SwapchainImage};
 
-use winit::window::Window;
+use winit::dpi::{LogicalSize, PhysicalSize};
+use winit::event_loop::EventLoop;
+use winit::window::{Window, WindowBuilder};
 
 use std::sync::Arc;
 
-pub fn window_size_dependent_setup(
-	images: &[Arc<SwapchainImage<Window>>],
-	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
-	dynamic_state: &mut DynamicState,
-) -> Vec<Arc<dyn FramebufferAbstract + Send + Sync>> {
-	let dimensions = images[0].dimensions();
+use crate::debug_utils::DebugNameRegistry;
+use crate::error::VulkanoError;
+use crate::transparency::OitPass;
+
+pub fn create_window(event_loop: &EventLoop<()>) -> Result<Arc<Window>, VulkanoError> {
+	Ok(Arc::new(WindowBuilder::new().build(event_loop)?))
+}
+
+// Size of the window in both units. The swapchain, the framebuffers and the
+// viewport are in physical pixels, the UI is laid out in logical points. winit
+// reports the inner size in physical pixels on every platform, the Retina and
+// Windows / X11 / Wayland scale factors only convert it to points.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowSize {
+	pub physical_size: [u32; 2],
+	pub logical_size: [f32; 2],
+	pub scale_factor: f64,
+}
+
+impl WindowSize {
+	pub fn of(window: &Window) -> WindowSize {
+		WindowSize::new(window.inner_size(), window.scale_factor())
+	}
+
+	pub fn new(physical: PhysicalSize<u32>, scale_factor: f64) -> WindowSize {
+		let logical: LogicalSize<f32> = physical.to_logical(scale_factor);
+		WindowSize {
+			physical_size: physical.into(),
+			logical_size: [logical.width, logical.height],
+			scale_factor,
+		}
+	}
+}
+
+// Framebuffers of the last swapchain images they were created for. The cached
+// framebuffers are returned as long as the images and their dimensions are the
+// same, a recreated swapchain always has new images and is rebuilt.
+pub struct FramebufferCache {
+	dimensions: [u32; 2],
+	image_keys: Vec<u64>,
+	framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+}
+
+impl Default for FramebufferCache {
+	fn default() -> FramebufferCache {
+		FramebufferCache::new()
+	}
+}
 
+impl FramebufferCache {
+	pub fn new() -> FramebufferCache {
+		FramebufferCache {
+			dimensions: [0, 0],
+			image_keys: Vec::new(),
+			framebuffers: Vec::new(),
+		}
+	}
+
+	pub fn get_or_create(
+		&mut self,
+		images: &[Arc<SwapchainImage<Arc<Window>>>],
+		render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+		debug_names: &DebugNameRegistry,
+	) -> Result<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, VulkanoError> {
+		let dimensions = SwapchainImage::dimensions(&images[0]);
+		let image_keys: Vec<u64> = images.iter().map(|image| image.inner().image.key()).collect();
+		if self.dimensions == dimensions && self.image_keys == image_keys {
+			return Ok(self.framebuffers.clone());
+		}
+
+		self.framebuffers = images
+			.iter()
+			.enumerate()
+			.map(|(i, image)| {
+				debug_names.name_indexed(image.inner().image, "swapchain_image", i);
+				let view = ImageView::new(image.clone())?;
+				let framebuffer = Framebuffer::start(render_pass.clone())
+					.add(view)?
+					.build()?;
+				debug_names.name_indexed(&FramebufferAbstract::inner(&framebuffer), "framebuffer", i);
+				Ok(Arc::new(framebuffer) as Arc<dyn FramebufferAbstract + Send + Sync>)
+			})
+			.collect::<Result<_, VulkanoError>>()?;
+		self.dimensions = dimensions;
+		self.image_keys = image_keys;
+		Ok(self.framebuffers.clone())
+	}
+
+	// Forces the next call to rebuild, needed when the render pass changes
+	pub fn invalidate(&mut self) {
+		self.image_keys.clear();
+	}
+}
+
+// The viewport follows the window size even when the framebuffers are reused,
+// `dimensions` are the physical pixels of the swapchain images
+pub fn update_viewport(dynamic_state: &mut DynamicState, dimensions: [u32; 2]) {
 	let viewport = Viewport {
 		origin: [0.0, 0.0],
 		dimensions: [dimensions[0] as f32, dimensions[1] as f32],
 		depth_range: 0.0..1.0,
 	};
 	dynamic_state.viewports = Some(vec![viewport]);
+}
+
+pub fn window_size_dependent_setup(
+	images: &[Arc<SwapchainImage<Arc<Window>>>],
+	render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+	dynamic_state: &mut DynamicState,
+	framebuffer_cache: &mut FramebufferCache,
+	debug_names: &DebugNameRegistry,
+	oit: &mut OitPass,
+) -> Result<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, VulkanoError> {
+	update_viewport(dynamic_state, SwapchainImage::dimensions(&images[0]));
+	// The accumulation images of the transparency follow the swapchain size
+	oit.resize(images, debug_names)?;
+	framebuffer_cache.get_or_create(images, render_pass, debug_names)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn retina_logical_size() {
+		let size = WindowSize::new(PhysicalSize::new(2560, 1600), 2.0);
+		assert_eq!(size.physical_size, [2560, 1600]);
+		assert_eq!(size.logical_size, [1280.0, 800.0]);
+	}
 
-	images
-		.iter()
-		.map(|image| {
-			let view = ImageView::new(image.clone()).unwrap();
-			Arc::new(
-				Framebuffer::start(render_pass.clone())
-					.add(view)
-					.unwrap()
-					.build()
-					.unwrap(),
-			) as Arc<dyn FramebufferAbstract + Send + Sync>
-		})
-		.collect::<Vec<_>>()
+	#[test]
+	fn fractional_scale_factor() {
+		let size = WindowSize::new(PhysicalSize::new(1920, 1080), 1.5);
+		assert_eq!(size.physical_size, [1920, 1080]);
+		assert_eq!(size.logical_size, [1280.0, 720.0]);
+	}
 }
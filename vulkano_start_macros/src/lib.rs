@@ -0,0 +1,225 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, DeriveInput, Error, Lit, Meta};
+
+// Generates the `build` function of a graphics pipeline from the attributes of
+// a unit struct:
+//
+//	#[derive(RenderPipeline)]
+//	#[vert = "src/shaders/sky.vert"]
+//	#[frag = "src/shaders/sky.frag"]
+//	#[blend = "alpha"]
+//	#[topology = "triangle_list"]
+//	struct SkyPipeline;
+//
+//	let pipeline = SkyPipeline::build(device.clone(), render_pass.clone())?;
+//
+// The shaders go through vulkano_shaders::shader!, so shaderc compiles them
+// with the crate and the SPIR-V is embedded in the binary, a change to the
+// GLSL rebuilds it. The paths are relative to the crate's Cargo.toml. The
+// fixed function state goes through crate::pipeline::PipelineDescriptor, the
+// other attributes being:
+//
+//	cull = "none" | "front" | "back"           (none)
+//	depth_test = true | false                 (false)
+//	wireframe = true | false                  (false)
+//	alpha_to_coverage = true | false          (false)
+//	alpha_to_one = true | false               (false)
+//	vertex = "path::to::Vertex"               (bufferless)
+//	subpass = 0                               (0)
+//
+// The struct also gets `descriptor()`, the same PipelineDescriptor, to hot
+// patch the shaders through ShaderHotPatch.
+#[proc_macro_derive(RenderPipeline, attributes(vert, frag, blend, topology, cull, depth_test, wireframe, alpha_to_coverage, alpha_to_one, vertex, subpass))]
+pub fn derive_render_pipeline(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	match expand(&input) {
+		Ok(tokens) => tokens.into(),
+		Err(error) => error.to_compile_error().into(),
+	}
+}
+
+struct Attributes {
+	vert: Option<String>,
+	frag: Option<String>,
+	blend: TokenStream2,
+	topology: TokenStream2,
+	cull: TokenStream2,
+	depth_test: bool,
+	wireframe: bool,
+	alpha_to_coverage: bool,
+	alpha_to_one: bool,
+	vertex: Option<syn::Type>,
+	subpass: u32,
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, Error> {
+	let attributes = parse_attributes(input)?;
+	let name = &input.ident;
+	let vert = attributes
+		.vert
+		.ok_or_else(|| Error::new(Span::call_site(), "RenderPipeline needs a #[vert = \"...\"] shader"))?;
+	let frag = attributes
+		.frag
+		.ok_or_else(|| Error::new(Span::call_site(), "RenderPipeline needs a #[frag = \"...\"] shader"))?;
+	let shaders = format_ident!("__{}_shaders", snake_case(&name.to_string()));
+	let vertex_input = match &attributes.vertex {
+		Some(vertex) => quote! { .vertex_input_single_buffer::<#vertex>() },
+		None => quote! { .vertex_input(::vulkano::pipeline::vertex::BufferlessDefinition {}) },
+	};
+	let (blend, topology, cull) = (&attributes.blend, &attributes.topology, &attributes.cull);
+	let (depth_test, wireframe, subpass) = (attributes.depth_test, attributes.wireframe, attributes.subpass);
+	let (alpha_to_coverage, alpha_to_one) = (attributes.alpha_to_coverage, attributes.alpha_to_one);
+
+	Ok(quote! {
+		mod #shaders {
+			pub mod vs {
+				vulkano_shaders::shader! {
+					ty: "vertex",
+					path: #vert
+				}
+			}
+
+			pub mod fs {
+				vulkano_shaders::shader! {
+					ty: "fragment",
+					path: #frag
+				}
+			}
+		}
+
+		impl #name {
+			pub fn descriptor() -> crate::pipeline::PipelineDescriptor {
+				crate::pipeline::PipelineDescriptor {
+					vertex_shader: ::std::path::PathBuf::from(#vert),
+					fragment_shader: ::std::path::PathBuf::from(#frag),
+					topology: crate::pipeline::PipelineTopology::#topology,
+					cull_mode: crate::pipeline::PipelineCullMode::#cull,
+					wireframe: #wireframe,
+					blend: crate::pipeline::PipelineBlend::#blend,
+					depth_test: #depth_test,
+					alpha_to_coverage: #alpha_to_coverage,
+					alpha_to_one: #alpha_to_one,
+				}
+			}
+
+			pub fn build(
+				device: ::std::sync::Arc<::vulkano::device::Device>,
+				render_pass: ::std::sync::Arc<dyn ::vulkano::framebuffer::RenderPassAbstract + Send + Sync>,
+			) -> ::std::result::Result<crate::pipeline::SharedPipeline, crate::error::VulkanoError> {
+				let vs = #shaders::vs::Shader::load(device.clone()).map_err(crate::error::VulkanoError::ShaderLoad)?;
+				let fs = #shaders::fs::Shader::load(device.clone()).map_err(crate::error::VulkanoError::ShaderLoad)?;
+				let subpass = ::vulkano::framebuffer::Subpass::from(render_pass, #subpass)
+					.ok_or(crate::error::VulkanoError::NoSubpass)?;
+				let builder = ::vulkano::pipeline::GraphicsPipeline::start()
+					#vertex_input
+					.vertex_shader(vs.main_entry_point(), ())
+					.fragment_shader(fs.main_entry_point(), ());
+				let pipeline = Self::descriptor().apply(builder).render_pass(subpass).build(device)?;
+				Ok(::std::sync::Arc::new(pipeline))
+			}
+		}
+	})
+}
+
+fn parse_attributes(input: &DeriveInput) -> Result<Attributes, Error> {
+	let mut attributes = Attributes {
+		vert: None,
+		frag: None,
+		blend: quote! { Opaque },
+		topology: quote! { TriangleList },
+		cull: quote! { None },
+		depth_test: false,
+		wireframe: false,
+		alpha_to_coverage: false,
+		alpha_to_one: false,
+		vertex: None,
+		subpass: 0,
+	};
+	for attribute in &input.attrs {
+		// Other attributes, doc comments or lints, may not be name = value
+		let name = match attribute.path.get_ident() {
+			Some(name) if is_pipeline_attribute(&name.to_string()) => name.to_string(),
+			_ => continue,
+		};
+		let lit = match attribute.parse_meta()? {
+			Meta::NameValue(meta) => meta.lit,
+			_ => return Err(Error::new_spanned(attribute, format!("expected #[{} = ...]", name))),
+		};
+		match (name.as_str(), &lit) {
+			("vert", Lit::Str(path)) => attributes.vert = Some(path.value()),
+			("frag", Lit::Str(path)) => attributes.frag = Some(path.value()),
+			("blend", Lit::Str(blend)) => {
+				attributes.blend = match blend.value().as_str() {
+					"opaque" => quote! { Opaque },
+					"alpha" => quote! { Alpha },
+					_ => return Err(Error::new_spanned(blend, "blend is \"opaque\" or \"alpha\"")),
+				}
+			}
+			("topology", Lit::Str(topology)) => {
+				attributes.topology = match topology.value().as_str() {
+					"point_list" => quote! { PointList },
+					"line_list" => quote! { LineList },
+					"line_strip" => quote! { LineStrip },
+					"triangle_list" => quote! { TriangleList },
+					"triangle_strip" => quote! { TriangleStrip },
+					_ => {
+						return Err(Error::new_spanned(
+							topology,
+							"topology is \"point_list\", \"line_list\", \"line_strip\", \"triangle_list\" or \"triangle_strip\"",
+						))
+					}
+				}
+			}
+			("cull", Lit::Str(cull)) => {
+				attributes.cull = match cull.value().as_str() {
+					"none" => quote! { None },
+					"front" => quote! { Front },
+					"back" => quote! { Back },
+					_ => return Err(Error::new_spanned(cull, "cull is \"none\", \"front\" or \"back\"")),
+				}
+			}
+			("depth_test", Lit::Bool(value)) => attributes.depth_test = value.value,
+			("wireframe", Lit::Bool(value)) => attributes.wireframe = value.value,
+			("alpha_to_coverage", Lit::Bool(value)) => attributes.alpha_to_coverage = value.value,
+			("alpha_to_one", Lit::Bool(value)) => attributes.alpha_to_one = value.value,
+			("vertex", Lit::Str(vertex)) => attributes.vertex = Some(vertex.parse()?),
+			("subpass", Lit::Int(index)) => attributes.subpass = index.base10_parse()?,
+			(name, lit) => return Err(Error::new_spanned(lit, format!("unexpected value for #[{}]", name))),
+		}
+	}
+	Ok(attributes)
+}
+
+fn is_pipeline_attribute(name: &str) -> bool {
+	matches!(
+		name,
+		"vert"
+			| "frag"
+			| "blend"
+			| "topology"
+			| "cull"
+			| "depth_test"
+			| "wireframe"
+			| "alpha_to_coverage"
+			| "alpha_to_one"
+			| "vertex"
+			| "subpass"
+	)
+}
+
+fn snake_case(name: &str) -> String {
+	let mut snake = String::new();
+	for (i, c) in name.chars().enumerate() {
+		if c.is_uppercase() {
+			if i > 0 {
+				snake.push('_');
+			}
+			snake.extend(c.to_lowercase());
+		} else {
+			snake.push(c);
+		}
+	}
+	snake
+}